@@ -45,6 +45,8 @@ use gdbstub::arch::Arch;
 use gdbstub_arch::aarch64::AArch64 as GdbArch;
 use hypervisor::CpuConfigAArch64;
 use hypervisor::DeviceKind;
+#[cfg(all(target_arch = "aarch64", feature = "gdb"))]
+use hypervisor::HwWatchpoint;
 use hypervisor::Hypervisor;
 use hypervisor::HypervisorCap;
 use hypervisor::ProtectionType;
@@ -239,12 +241,12 @@ pub enum Error {
     CustomPvmFwLoadFailure(arch::LoadImageError),
     #[error("vm created wrong kind of vcpu")]
     DowncastVcpu,
-    #[error("failed to enable singlestep execution: {0}")]
-    EnableSinglestep(base::Error),
     #[error("failed to finalize IRQ chip: {0}")]
     FinalizeIrqChip(base::Error),
     #[error("failed to get HW breakpoint count: {0}")]
     GetMaxHwBreakPoint(base::Error),
+    #[error("failed to get HW watchpoint count: {0}")]
+    GetMaxHwWatchPoint(base::Error),
     #[error("failed to get PSCI version: {0}")]
     GetPsciVersion(base::Error),
     #[error("failed to get serial cmdline: {0}")]
@@ -277,8 +279,8 @@ pub enum Error {
     RegisterVsock(arch::DeviceRegistrationError),
     #[error("failed to set device attr: {0}")]
     SetDeviceAttr(base::Error),
-    #[error("failed to set a hardware breakpoint: {0}")]
-    SetHwBreakpoint(base::Error),
+    #[error("failed to set the guest debug state: {0}")]
+    SetGuestDebug(base::Error),
     #[error("failed to set register: {0}")]
     SetReg(base::Error),
     #[error("failed to set up guest memory: {0}")]
@@ -796,20 +798,22 @@ impl<T: VcpuAArch64> arch::GdbOps<T> for AArch64 {
         vcpu.set_gdb_register(reg_id, data).map_err(Error::WriteReg)
     }
 
-    fn enable_singlestep(vcpu: &T) -> Result<()> {
-        const SINGLE_STEP: bool = true;
-        vcpu.set_guest_debug(&[], SINGLE_STEP)
-            .map_err(Error::EnableSinglestep)
-    }
-
     fn get_max_hw_breakpoints(vcpu: &T) -> Result<usize> {
         vcpu.get_max_hw_bps().map_err(Error::GetMaxHwBreakPoint)
     }
 
-    fn set_hw_breakpoints(vcpu: &T, breakpoints: &[GuestAddress]) -> Result<()> {
-        const SINGLE_STEP: bool = false;
-        vcpu.set_guest_debug(breakpoints, SINGLE_STEP)
-            .map_err(Error::SetHwBreakpoint)
+    fn get_max_hw_watchpoints(vcpu: &T) -> Result<usize> {
+        vcpu.get_max_hw_wps().map_err(Error::GetMaxHwWatchPoint)
+    }
+
+    fn set_guest_debug(
+        vcpu: &T,
+        breakpoints: &[GuestAddress],
+        watchpoints: &[HwWatchpoint],
+        enable_singlestep: bool,
+    ) -> Result<()> {
+        vcpu.set_guest_debug(breakpoints, watchpoints, enable_singlestep)
+            .map_err(Error::SetGuestDebug)
     }
 }
 
@@ -997,6 +1001,7 @@ mod tests {
             address_range: AddressRange::from_start_and_size(0x8080_0000, 0x1000).unwrap(),
             size: 0x1000,
             entry: GuestAddress(0x8080_0000),
+            pvh_entry: None,
         });
         let fdt_address = GuestAddress(0x1234);
         let prot = ProtectionType::Unprotected;
@@ -1034,6 +1039,7 @@ mod tests {
             address_range: AddressRange::from_start_and_size(0x8080_0000, 0x1000).unwrap(),
             size: 0x1000,
             entry: GuestAddress(0x8080_0000),
+            pvh_entry: None,
         });
         let fdt_address = GuestAddress(0x1234);
         let prot = ProtectionType::Protected;
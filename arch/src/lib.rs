@@ -75,6 +75,8 @@ use hypervisor::CpuConfigAArch64 as CpuConfigArch;
 use hypervisor::CpuConfigRiscv64 as CpuConfigArch;
 #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
 use hypervisor::CpuConfigX86_64 as CpuConfigArch;
+#[cfg(all(any(target_arch = "x86_64", target_arch = "aarch64"), feature = "gdb"))]
+use hypervisor::HwWatchpoint;
 #[cfg(any(target_arch = "arm", target_arch = "aarch64"))]
 use hypervisor::Hypervisor as HypervisorArch;
 #[cfg(target_arch = "riscv64")]
@@ -335,6 +337,7 @@ pub struct VmComponents {
     #[cfg(all(any(target_arch = "x86_64", target_arch = "aarch64"), feature = "gdb"))]
     pub gdb: Option<(u32, Tube)>, // port and control tube.
     pub host_cpu_topology: bool,
+    pub hotplug_memory_size: u64,
     pub hugepages: bool,
     pub hv_cfg: hypervisor::Config,
     pub initrd_image: Option<File>,
@@ -418,6 +421,17 @@ pub trait LinuxArch {
         hypervisor: &impl hypervisor::Hypervisor,
     ) -> std::result::Result<Vec<(GuestAddress, u64)>, Self::Error>;
 
+    /// Returns the part of the layout from `guest_memory_layout` that is reserved for hotplugged
+    /// memory, which isn't reported to the guest as RAM at boot. Must be called after
+    /// `guest_memory_layout`.
+    ///
+    /// # Arguments
+    ///
+    /// * `components` - Parts used to determine the memory layout.
+    fn hotplug_memory_region(_components: &VmComponents) -> Option<AddressRange> {
+        None
+    }
+
     /// Gets the configuration for a new `SystemAllocator` that fits the given `Vm`'s memory layout.
     ///
     /// This is the per-architecture template for constructing the `SystemAllocator`. Platform
@@ -540,14 +554,20 @@ pub trait GdbOps<T: VcpuArch> {
         data: &[u8],
     ) -> Result<(), Self::Error>;
 
-    /// Make the next vCPU's run single-step.
-    fn enable_singlestep(vcpu: &T) -> Result<(), Self::Error>;
-
     /// Get maximum number of hardware breakpoints.
     fn get_max_hw_breakpoints(vcpu: &T) -> Result<usize, Self::Error>;
 
-    /// Set hardware breakpoints at the given addresses.
-    fn set_hw_breakpoints(vcpu: &T, breakpoints: &[GuestAddress]) -> Result<(), Self::Error>;
+    /// Get maximum number of hardware watchpoints.
+    fn get_max_hw_watchpoints(vcpu: &T) -> Result<usize, Self::Error>;
+
+    /// Set the hardware breakpoints and watchpoints of the vCPU, and whether its next runs
+    /// single-step.
+    fn set_guest_debug(
+        vcpu: &T,
+        breakpoints: &[GuestAddress],
+        watchpoints: &[HwWatchpoint],
+        enable_singlestep: bool,
+    ) -> Result<(), Self::Error>;
 }
 
 /// Errors for device manager.
@@ -250,6 +250,10 @@ mod tests {
                 stdin: true,
                 out_timestamp: false,
                 debugcon_port: 0,
+                addr: None,
+                log: None,
+                log_size: None,
+                log_count: 0,
             },
         );
 
@@ -280,6 +284,10 @@ mod tests {
                 stdin: true,
                 out_timestamp: false,
                 debugcon_port: 0,
+                addr: None,
+                log: None,
+                log_size: None,
+                log_count: 0,
             },
         );
 
@@ -297,6 +305,10 @@ mod tests {
                 stdin: false,
                 out_timestamp: false,
                 debugcon_port: 0,
+                addr: None,
+                log: None,
+                log_size: None,
+                log_count: 0,
             },
         );
 
@@ -328,6 +340,10 @@ mod tests {
                 stdin: true,
                 out_timestamp: false,
                 debugcon_port: 0,
+                addr: None,
+                log: None,
+                log_size: None,
+                log_count: 0,
             },
         );
 
@@ -7,6 +7,8 @@
 use std::result::Result;
 use std::sync::Arc;
 
+use serde::Deserialize;
+use serde::Deserializer;
 use serde::Serialize;
 use serde::Serializer;
 use sync::Mutex;
@@ -41,3 +43,19 @@ where
 {
     serde::Serialize::serialize(&data[..], serializer)
 }
+
+/// Deserialize a seq of T into an array, the counterpart of `serialize_arr`.
+///
+/// Fails if the seq doesn't have exactly `SIZE` elements.
+pub fn deserialize_seq_to_arr<'de, D, T: Sized + Deserialize<'de>, const SIZE: usize>(
+    deserializer: D,
+) -> Result<[T; SIZE], D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let vals: Vec<T> = Deserialize::deserialize(deserializer)?;
+    let len = vals.len();
+    vals.try_into().map_err(|_| {
+        serde::de::Error::invalid_length(len, &format!("an array of {} elements", SIZE).as_str())
+    })
+}
@@ -7,6 +7,8 @@ use std::fs::File;
 use std::io::Stderr;
 use std::io::Stdin;
 use std::io::Stdout;
+use std::net::TcpListener;
+use std::net::TcpStream;
 use std::net::UdpSocket;
 use std::ops::Drop;
 use std::os::unix::io::AsRawFd;
@@ -202,6 +204,8 @@ macro_rules! IntoRawDescriptor {
 // relevant container type.
 AsRawDescriptor!(File);
 AsRawDescriptor!(UnlinkUnixSeqpacketListener);
+AsRawDescriptor!(TcpListener);
+AsRawDescriptor!(TcpStream);
 AsRawDescriptor!(UdpSocket);
 AsRawDescriptor!(UnixDatagram);
 AsRawDescriptor!(UnixListener);
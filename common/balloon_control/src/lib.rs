@@ -26,6 +26,20 @@ pub enum BalloonTubeCommand {
     Stats {
         id: u64,
     },
+    // Ask the guest to hint its free pages, which are then returned to the host.
+    FreePageHint,
+    // Configure the working set reporting of the guest. `bins` holds the idle ages, in
+    // milliseconds, that separate the buckets of the histogram.
+    WorkingSetConfig {
+        bins: Vec<u64>,
+        refresh_threshold: u64,
+        report_threshold: u64,
+    },
+    // Fetch the working set of the guest. The ID can be used to discard stale reports
+    // like for `Stats`.
+    WorkingSet {
+        id: u64,
+    },
 }
 
 // BalloonStats holds stats returned from the stats_queue.
@@ -45,6 +59,24 @@ pub struct BalloonStats {
     pub unevictable_memory: Option<u64>,
 }
 
+// The bounds of the number of buckets in a working set histogram.
+pub const VIRTIO_BALLOON_WS_MIN_NUM_BINS: usize = 2;
+pub const VIRTIO_BALLOON_WS_MAX_NUM_BINS: usize = 16;
+
+// WSBucket is one bucket of a working set histogram: the anonymous and file-backed memory, in
+// bytes, that has been idle for at most `age` milliseconds.
+#[derive(Default, Serialize, Deserialize, Debug, Clone, Copy)]
+pub struct WSBucket {
+    pub age: u64,
+    pub bytes: [u64; 2],
+}
+
+// BalloonWS holds the working set histogram returned from the ws_data queue.
+#[derive(Default, Serialize, Deserialize, Debug, Clone)]
+pub struct BalloonWS {
+    pub ws: Vec<WSBucket>,
+}
+
 // BalloonTubeResult are results to BalloonTubeCommand defined above.
 #[derive(Serialize, Deserialize, Debug)]
 pub enum BalloonTubeResult {
@@ -56,4 +88,9 @@ pub enum BalloonTubeResult {
     Adjusted {
         num_bytes: u64,
     },
+    WorkingSet {
+        ws: BalloonWS,
+        balloon_actual: u64,
+        id: u64,
+    },
 }
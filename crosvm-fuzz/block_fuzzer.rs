@@ -97,6 +97,8 @@ fuzz_target!(|bytes| {
         None,
         None,
         None,
+        Default::default(),
+        None,
     )
     .unwrap();
 
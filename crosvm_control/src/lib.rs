@@ -25,12 +25,14 @@ use libc::ssize_t;
 use vm_control::client::*;
 use vm_control::BalloonControlCommand;
 use vm_control::BalloonStats;
+use vm_control::BalloonWS;
 use vm_control::DiskControlCommand;
 use vm_control::UsbControlAttachedDevice;
 use vm_control::UsbControlResult;
 use vm_control::VmRequest;
 use vm_control::VmResponse;
 use vm_control::USB_CONTROL_MAX_PORTS;
+use vm_control::VIRTIO_BALLOON_WS_MAX_NUM_BINS;
 
 fn validate_socket_path(socket_path: *const c_char) -> Option<PathBuf> {
     if !socket_path.is_null() {
@@ -118,6 +120,23 @@ pub extern "C" fn crosvm_client_balloon_vms(socket_path: *const c_char, num_byte
     .unwrap_or(false)
 }
 
+/// Asks the guest of the crosvm instance whose control socket is listening on `socket_path` to
+/// hint its free pages, which are then returned to the host.
+///
+/// The function returns true on success or false if an error occured.
+#[no_mangle]
+pub extern "C" fn crosvm_client_balloon_free_page_hint(socket_path: *const c_char) -> bool {
+    catch_unwind(|| {
+        if let Some(socket_path) = validate_socket_path(socket_path) {
+            let command = BalloonControlCommand::FreePageHint;
+            vms_request(&VmRequest::BalloonCommand(command), &socket_path).is_ok()
+        } else {
+            false
+        }
+    })
+    .unwrap_or(false)
+}
+
 /// Enable vmm swap for crosvm instance whose control socket is listening on `socket_path`.
 ///
 /// The function returns true on success or false if an error occured.
@@ -439,3 +458,123 @@ pub extern "C" fn crosvm_client_balloon_stats(
     })
     .unwrap_or(false)
 }
+
+/// The maximum number of buckets in the working set histograms of `BalloonWSFfi`.
+pub const BALLOON_WS_MAX_NUM_BINS: usize = 16;
+const _: () = assert!(BALLOON_WS_MAX_NUM_BINS == VIRTIO_BALLOON_WS_MAX_NUM_BINS);
+
+/// One bucket of a working set histogram.
+#[repr(C)]
+#[derive(Copy, Clone, Default)]
+pub struct WSBucketFfi {
+    /// Idle age of the memory in the bucket, in milliseconds.
+    age: u64,
+    /// Anonymous and file-backed memory in the bucket, in bytes.
+    bytes: [u64; 2],
+}
+
+/// Working set histogram of the guest, with its first `num_bins` entries of `ws` set.
+#[repr(C)]
+pub struct BalloonWSFfi {
+    ws: [WSBucketFfi; BALLOON_WS_MAX_NUM_BINS],
+    num_bins: u8,
+    _reserved: [u8; 7],
+}
+
+impl From<&BalloonWS> for BalloonWSFfi {
+    fn from(other: &BalloonWS) -> Self {
+        let mut ws = [WSBucketFfi::default(); BALLOON_WS_MAX_NUM_BINS];
+        for (bucket, other) in ws.iter_mut().zip(other.ws.iter()) {
+            bucket.age = other.age;
+            bucket.bytes = other.bytes;
+        }
+        Self {
+            ws,
+            num_bins: other.ws.len().min(BALLOON_WS_MAX_NUM_BINS) as u8,
+            _reserved: [0; 7],
+        }
+    }
+}
+
+/// Returns the working set of the guest of the crosvm instance whose control socket is listening
+/// on `socket_path`.
+///
+/// The parameters `ws` and `actual` are optional and will only be written to if they are
+/// non-null.
+///
+/// The function returns true on success or false if an error occured.
+#[no_mangle]
+pub extern "C" fn crosvm_client_balloon_working_set(
+    socket_path: *const c_char,
+    ws: *mut BalloonWSFfi,
+    actual: *mut u64,
+) -> bool {
+    catch_unwind(|| {
+        if let Some(socket_path) = validate_socket_path(socket_path) {
+            let request = &VmRequest::BalloonCommand(BalloonControlCommand::WorkingSet);
+            if let Ok(VmResponse::BalloonWS {
+                ws: ref balloon_ws,
+                balloon_actual,
+            }) = handle_request(request, &socket_path)
+            {
+                if !ws.is_null() {
+                    unsafe {
+                        *ws = balloon_ws.into();
+                    }
+                }
+
+                if !actual.is_null() {
+                    unsafe {
+                        *actual = balloon_actual;
+                    }
+                }
+                true
+            } else {
+                false
+            }
+        } else {
+            false
+        }
+    })
+    .unwrap_or(false)
+}
+
+/// Configures the working set reporting of the guest of the crosvm instance whose control socket
+/// is listening on `socket_path`.
+///
+/// # Arguments
+///
+/// * `socket_path` - Path to the crosvm control socket
+/// * `bins` - Pointer to an array of the idle ages, in milliseconds, separating the buckets of
+///            the working set histograms
+/// * `bins_length` - Amount of entries in the array specified by `bins`
+/// * `refresh_threshold` - Age, in milliseconds, after which the guest refreshes its working set
+/// * `report_threshold` - Age, in milliseconds, after which the guest reports its working set
+///
+/// The function returns true on success or false if an error occured.
+#[no_mangle]
+pub extern "C" fn crosvm_client_balloon_working_set_config(
+    socket_path: *const c_char,
+    bins: *const u64,
+    bins_length: usize,
+    refresh_threshold: u64,
+    report_threshold: u64,
+) -> bool {
+    catch_unwind(|| {
+        if let Some(socket_path) = validate_socket_path(socket_path) {
+            if bins.is_null() || bins_length >= BALLOON_WS_MAX_NUM_BINS {
+                return false;
+            }
+            let bins = unsafe { std::slice::from_raw_parts(bins, bins_length) }.to_vec();
+            let command = BalloonControlCommand::WorkingSetConfig {
+                bins,
+                refresh_threshold,
+                report_threshold,
+            };
+            vms_request(&VmRequest::BalloonCommand(command), &socket_path).is_ok()
+        } else {
+            false
+        }
+    })
+    .unwrap_or(false)
+}
@@ -5,6 +5,7 @@
 use std::ops::Index;
 use std::vec::Vec;
 
+use anyhow::Context;
 use base::Error;
 use base::Event;
 use base::Result;
@@ -13,9 +14,12 @@ use hypervisor::IrqRoute;
 use hypervisor::IrqSource;
 use hypervisor::IrqSourceChip;
 use hypervisor::LapicState;
+use hypervisor::MPState;
 use hypervisor::PicSelect;
 use hypervisor::PicState;
 use hypervisor::PitState;
+use serde::Deserialize;
+use serde::Serialize;
 
 use crate::IrqChip;
 
@@ -58,6 +62,62 @@ pub trait IrqChipX86_64: IrqChip {
 
     /// Returns true if the PIT uses port 0x61 for the PC speaker, false if 0x61 is unused.
     fn pit_uses_speaker_port(&self) -> bool;
+
+    /// Saves the state of the PIC, IOAPIC, PIT and the local APICs of the `num_vcpus` VCPUs, to
+    /// be restored with `restore`.
+    fn snapshot(&self, num_vcpus: usize) -> anyhow::Result<serde_json::Value> {
+        let snapshot = IrqChipSnapshot {
+            pic_primary: self.get_pic_state(PicSelect::Primary)?,
+            pic_secondary: self.get_pic_state(PicSelect::Secondary)?,
+            ioapic: self.get_ioapic_state()?,
+            pit: self.get_pit()?,
+            lapics: (0..num_vcpus)
+                .map(|vcpu_id| self.get_lapic_state(vcpu_id))
+                .collect::<Result<_>>()?,
+            mp_states: (0..num_vcpus)
+                .map(|vcpu_id| self.get_mp_state(vcpu_id))
+                .collect::<Result<_>>()?,
+        };
+        serde_json::to_value(snapshot).context("failed to serialize irqchip state")
+    }
+
+    /// Restores the state saved by `snapshot`, before the VCPUs are restored.
+    fn restore(&mut self, data: serde_json::Value, num_vcpus: usize) -> anyhow::Result<()> {
+        let snapshot: IrqChipSnapshot =
+            serde_json::from_value(data).context("failed to deserialize irqchip state")?;
+        if snapshot.lapics.len() != num_vcpus || snapshot.mp_states.len() != num_vcpus {
+            anyhow::bail!(
+                "snapshot has {} vcpus but the VM has {}",
+                snapshot.lapics.len(),
+                num_vcpus
+            );
+        }
+        self.set_pic_state(PicSelect::Primary, &snapshot.pic_primary)?;
+        self.set_pic_state(PicSelect::Secondary, &snapshot.pic_secondary)?;
+        self.set_ioapic_state(&snapshot.ioapic)?;
+        self.set_pit(&snapshot.pit)?;
+        for (vcpu_id, (lapic, mp_state)) in snapshot
+            .lapics
+            .iter()
+            .zip(snapshot.mp_states.iter())
+            .enumerate()
+        {
+            self.set_lapic_state(vcpu_id, lapic)?;
+            self.set_mp_state(vcpu_id, mp_state)?;
+        }
+        Ok(())
+    }
+}
+
+/// State of the interrupt controllers saved in VM snapshots.
+#[derive(Serialize, Deserialize)]
+struct IrqChipSnapshot {
+    pic_primary: PicState,
+    pic_secondary: PicState,
+    ioapic: IoapicState,
+    pit: PitState,
+    lapics: Vec<LapicState>,
+    mp_states: Vec<MPState>,
 }
 
 /// A container for x86 IrqRoutes, grouped by GSI.
@@ -45,18 +45,30 @@ cfg_if::cfg_if! {
 
 use std::collections::HashMap;
 use std::collections::VecDeque;
-use std::fs::OpenOptions;
+#[cfg(unix)]
+use std::fs::File;
 use std::sync::Arc;
 
 use anyhow::anyhow;
 use anyhow::Context;
 use base::error;
 use base::info;
+#[cfg(unix)]
+use base::SafeDescriptor;
 use base::Tube;
 use base::TubeError;
 use cros_async::AsyncTube;
 use cros_async::Executor;
+#[cfg(unix)]
+use vm_control::migration::read_device_states;
+#[cfg(unix)]
+use vm_control::migration::write_device_states;
+use vm_control::snapshot::SnapshotReader;
+use vm_control::snapshot::SnapshotWriter;
+use vm_control::snapshot::VmSnapshot;
 use vm_control::DeviceControlCommand;
+#[cfg(unix)]
+use vm_control::MigrateControlResult;
 use vm_control::RestoreControlResult;
 use vm_control::SnapshotControlResult;
 use vm_memory::GuestMemory;
@@ -298,40 +310,14 @@ fn restore_devices(
     }
 }
 
-#[derive(serde::Serialize, serde::Deserialize)]
-struct SnapshotRoot {
-    guest_memory_metadata: serde_json::Value,
-    devices: Vec<HashMap<u32, serde_json::Value>>,
-}
-
 async fn snapshot_handler(
     path: &std::path::Path,
+    vm: VmSnapshot,
     guest_memory: &GuestMemory,
     buses: &[&Bus],
 ) -> anyhow::Result<()> {
-    let mut snapshot_root = SnapshotRoot {
-        guest_memory_metadata: serde_json::Value::Null,
-        devices: Vec::new(),
-    };
-
-    // TODO(b/268093674): Better output file format.
-    // TODO(b/268094487): If the snapshot fail, this leaves an incomplete memory snapshot at the
-    // requested path.
-
-    let mut json_file = OpenOptions::new()
-        .create(true)
-        .write(true)
-        .truncate(true)
-        .open(path)
-        .with_context(|| format!("failed to open {}", path.display()))?;
-
-    let mem_path = path.with_extension("mem");
-    let mut mem_file = OpenOptions::new()
-        .create(true)
-        .write(true)
-        .truncate(true)
-        .open(&mem_path)
-        .with_context(|| format!("failed to open {}", mem_path.display()))?;
+    let mut writer = SnapshotWriter::create(path)?;
+    let mut devices = Vec::new();
 
     for bus in buses {
         if let Err(e) = sleep_devices(bus) {
@@ -343,7 +329,7 @@ async fn snapshot_handler(
             return Err(e);
         }
     }
-    snapshot_root.guest_memory_metadata = match guest_memory.snapshot(&mut mem_file) {
+    let guest_memory_metadata = match guest_memory.snapshot(writer.memory_file()) {
         Ok(x) => x,
         Err(e) => {
             // If snapshot fails, wake devices and return error.
@@ -355,9 +341,8 @@ async fn snapshot_handler(
         }
     };
     for bus in buses {
-        if let Err(e) = snapshot_devices(bus, |id, snapshot| {
-            snapshot_root.devices.push([(id, snapshot)].into())
-        }) {
+        if let Err(e) = snapshot_devices(bus, |id, snapshot| devices.push([(id, snapshot)].into()))
+        {
             // If snapshot fails, wake devices and return error.
             error!("failed to snapshot devices: {}", e);
             for bus in buses {
@@ -370,9 +355,7 @@ async fn snapshot_handler(
         wake_devices(bus);
     }
 
-    serde_json::to_writer(&mut json_file, &snapshot_root)?;
-
-    Ok(())
+    writer.finish(vm, guest_memory_metadata, devices)
 }
 
 async fn restore_handler(
@@ -380,23 +363,13 @@ async fn restore_handler(
     guest_memory: &GuestMemory,
     buses: &[&Bus],
 ) -> anyhow::Result<()> {
-    let file = OpenOptions::new()
-        .read(true)
-        .write(false)
-        .open(path)
-        .with_context(|| format!("failed to open {}", path.display()))?;
-
-    let mem_path = path.with_extension("mem");
-    let mut mem_file = OpenOptions::new()
-        .read(true)
-        .write(false)
-        .open(&mem_path)
-        .with_context(|| format!("failed to open {}", mem_path.display()))?;
-
-    let snapshot_root: SnapshotRoot = serde_json::from_reader(file)?;
+    let mut reader = SnapshotReader::open(path)?;
 
     let mut devices_map: HashMap<u32, VecDeque<serde_json::Value>> = HashMap::new();
-    for (id, device) in snapshot_root.devices.into_iter().flatten() {
+    for (id, device) in std::mem::take(&mut reader.metadata_mut().devices)
+        .into_iter()
+        .flatten()
+    {
         devices_map.entry(id).or_default().push_back(device)
     }
     for bus in buses {
@@ -409,7 +382,7 @@ async fn restore_handler(
             return Err(e);
         }
     }
-    if let Err(e) = guest_memory.restore(snapshot_root.guest_memory_metadata, &mut mem_file) {
+    if let Err(e) = reader.read_memory(|metadata, file| guest_memory.restore(metadata, file)) {
         for bus in buses {
             wake_devices(bus);
         }
@@ -436,6 +409,70 @@ async fn restore_handler(
     Ok(())
 }
 
+// Puts the devices of all the `buses` to sleep, waking them back up on failure.
+#[cfg(unix)]
+fn sleep_buses(buses: &[&Bus]) -> anyhow::Result<()> {
+    for bus in buses {
+        if let Err(e) = sleep_devices(bus) {
+            for bus in buses {
+                wake_devices(bus);
+            }
+            return Err(e);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn send_device_states(stream: SafeDescriptor, buses: &[&Bus]) -> anyhow::Result<()> {
+    let mut devices = Vec::new();
+    for bus in buses {
+        snapshot_devices(bus, |id, snapshot| devices.push([(id, snapshot)].into()))?;
+    }
+    write_device_states(&mut File::from(stream), &devices)
+}
+
+#[cfg(unix)]
+fn receive_device_states(stream: SafeDescriptor, buses: &[&Bus]) -> anyhow::Result<()> {
+    let mut devices_map: HashMap<u32, VecDeque<serde_json::Value>> = HashMap::new();
+    for (id, device) in read_device_states(&mut File::from(stream))?
+        .into_iter()
+        .flatten()
+    {
+        devices_map.entry(id).or_default().push_back(device)
+    }
+    sleep_buses(buses)?;
+    for bus in buses {
+        if let Err(e) = restore_devices(bus, &mut devices_map) {
+            for bus in buses {
+                wake_devices(bus);
+            }
+            return Err(e);
+        }
+    }
+    for bus in buses {
+        wake_devices(bus);
+    }
+    for (key, _) in devices_map.iter().filter(|(_, v)| !v.is_empty()) {
+        info!(
+            "Unused migration data for device_id {}, device might be missing.",
+            key
+        );
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn migrate_control_result(result: anyhow::Result<()>) -> MigrateControlResult {
+    match result {
+        Ok(()) => MigrateControlResult::Ok,
+        Err(e) => {
+            error!("failed to migrate devices: {:#}", e);
+            MigrateControlResult::Failed(format!("{:#}", e))
+        }
+    }
+}
+
 async fn handle_command_tube(
     command_tube: AsyncTube,
     guest_memory: GuestMemory,
@@ -448,14 +485,19 @@ async fn handle_command_tube(
                 match command {
                     DeviceControlCommand::SnapshotDevices {
                         snapshot_path: path,
+                        vm,
                     } => {
-                        if let Err(e) =
-                            snapshot_handler(path.as_path(), &guest_memory, &[&*io_bus, &*mmio_bus])
-                                .await
+                        if let Err(e) = snapshot_handler(
+                            path.as_path(),
+                            vm,
+                            &guest_memory,
+                            &[&*io_bus, &*mmio_bus],
+                        )
+                        .await
                         {
-                            error!("failed to snapshot: {}", e);
+                            error!("failed to snapshot: {:#}", e);
                             command_tube
-                                .send(SnapshotControlResult::Failed(e.to_string()))
+                                .send(SnapshotControlResult::Failed(format!("{:#}", e)))
                                 .await
                                 .context("Failed to send response")?;
                             continue;
@@ -470,9 +512,9 @@ async fn handle_command_tube(
                             restore_handler(path.as_path(), &guest_memory, &[&*io_bus, &*mmio_bus])
                                 .await
                         {
-                            error!("failed to restore: {}", e);
+                            error!("failed to restore: {:#}", e);
                             command_tube
-                                .send(RestoreControlResult::Failed(e.to_string()))
+                                .send(RestoreControlResult::Failed(format!("{:#}", e)))
                                 .await
                                 .context("Failed to send response")?;
                             continue;
@@ -482,6 +524,39 @@ async fn handle_command_tube(
                             .await
                             .context("Failed to send response")?;
                     }
+                    #[cfg(unix)]
+                    DeviceControlCommand::SleepDevices => {
+                        let result = sleep_buses(&[&*io_bus, &*mmio_bus]);
+                        command_tube
+                            .send(migrate_control_result(result))
+                            .await
+                            .context("Failed to send response")?;
+                    }
+                    #[cfg(unix)]
+                    DeviceControlCommand::SendDeviceStates { stream } => {
+                        let result = send_device_states(stream, &[&*io_bus, &*mmio_bus]);
+                        command_tube
+                            .send(migrate_control_result(result))
+                            .await
+                            .context("Failed to send response")?;
+                    }
+                    #[cfg(unix)]
+                    DeviceControlCommand::WakeDevices => {
+                        wake_devices(&io_bus);
+                        wake_devices(&mmio_bus);
+                        command_tube
+                            .send(MigrateControlResult::Ok)
+                            .await
+                            .context("Failed to send response")?;
+                    }
+                    #[cfg(unix)]
+                    DeviceControlCommand::ReceiveDeviceStates { stream } => {
+                        let result = receive_device_states(stream, &[&*io_bus, &*mmio_bus]);
+                        command_tube
+                            .send(migrate_control_result(result))
+                            .await
+                            .context("Failed to send response")?;
+                    }
                     DeviceControlCommand::Exit => {
                         return Ok(());
                     }
@@ -9,6 +9,7 @@ use std::fs::OpenOptions;
 use std::io;
 use std::io::stdin;
 use std::io::stdout;
+use std::net::SocketAddr;
 use std::path::PathBuf;
 
 use base::error;
@@ -34,28 +35,42 @@ use crate::sys::serial_device::*;
 #[sorted]
 #[derive(ThisError, Debug)]
 pub enum Error {
+    #[error("Serial device type tcp requires an address")]
+    AddrRequired,
     #[error("Unable to clone an Event: {0}")]
     CloneEvent(base::Error),
+    #[error("Failed to start the console multiplexer: {0}")]
+    ConsoleMux(std::io::Error),
     #[error("Unable to clone file: {0}")]
     FileClone(std::io::Error),
     #[error("Unable to create file '{1}': {0}")]
     FileCreate(std::io::Error, PathBuf),
     #[error("Unable to open file '{1}': {0}")]
     FileOpen(std::io::Error, PathBuf),
+    #[error("Serial device type {0} takes its input from the console, not from input or stdin")]
+    InputConflict(SerialType),
     #[error("Serial device path '{0} is invalid")]
     InvalidPath(PathBuf),
     #[error("Invalid serial hardware: {0}")]
     InvalidSerialHardware(String),
     #[error("Invalid serial type: {0}")]
     InvalidSerialType(String),
+    #[error("Serial device type {0} doesn't support log files")]
+    LogUnsupported(SerialType),
     #[error("Serial device type file requires a path")]
     PathRequired,
+    #[error("Failed to create PTY: {0}")]
+    PtyCreate(std::io::Error),
+    #[error("Unable to link '{1}' to the PTY: {0}")]
+    PtyLink(std::io::Error, PathBuf),
     #[error("Failed to connect to socket: {0}")]
     SocketConnect(std::io::Error),
     #[error("Failed to create unbound socket: {0}")]
     SocketCreate(std::io::Error),
     #[error("Unable to open system type serial: {0}")]
     SystemTypeError(std::io::Error),
+    #[error("Unable to listen on {1}: {0}")]
+    TcpBind(std::io::Error, SocketAddr),
     #[error("Serial device type {0} not implemented")]
     Unimplemented(SerialType),
 }
@@ -74,11 +89,26 @@ pub enum SerialType {
     Stdout,
     Sink,
     Syslog,
+    #[cfg(unix)]
+    Pty,
+    #[cfg(unix)]
+    Tcp,
     #[cfg_attr(unix, serde(rename = "unix"))]
     #[cfg_attr(windows, serde(rename = "namedpipe"))]
     SystemSerialType,
 }
 
+impl SerialType {
+    /// Returns whether the stream of the device goes to a console that programs connect to.
+    fn is_console(&self) -> bool {
+        match self {
+            #[cfg(unix)]
+            SerialType::Pty | SerialType::Tcp => true,
+            _ => false,
+        }
+    }
+}
+
 impl Default for SerialType {
     fn default() -> Self {
         Self::Sink
@@ -92,6 +122,10 @@ impl Display for SerialType {
             SerialType::Stdout => "Stdout".to_string(),
             SerialType::Sink => "Sink".to_string(),
             SerialType::Syslog => "Syslog".to_string(),
+            #[cfg(unix)]
+            SerialType::Pty => "Pty".to_string(),
+            #[cfg(unix)]
+            SerialType::Tcp => "Tcp".to_string(),
             SerialType::SystemSerialType => SYSTEM_SERIAL_TYPE_NAME.to_string(),
         };
 
@@ -135,6 +169,10 @@ fn serial_parameters_default_debugcon_port() -> u16 {
     0x402
 }
 
+fn serial_parameters_default_log_count() -> u8 {
+    4
+}
+
 #[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize, FromKeyValues)]
 #[serde(deny_unknown_fields, rename_all = "kebab-case", default)]
 pub struct SerialParameters {
@@ -155,6 +193,11 @@ pub struct SerialParameters {
         default = "serial_parameters_default_debugcon_port"
     )]
     pub debugcon_port: u16,
+    pub addr: Option<SocketAddr>,
+    pub log: Option<PathBuf>,
+    pub log_size: Option<u64>,
+    #[serde(default = "serial_parameters_default_log_count")]
+    pub log_count: u8,
 }
 
 impl SerialParameters {
@@ -216,7 +259,18 @@ impl SerialParameters {
                 }
                 None => return Err(Error::PathRequired),
             },
+            #[cfg(unix)]
+            SerialType::Pty | SerialType::Tcp => {
+                if input.is_some() {
+                    return Err(Error::InputConflict(self.type_.clone()));
+                }
+                // The console multiplexer gives the input and takes the output of the device.
+                (None, None)
+            }
             SerialType::SystemSerialType => {
+                if self.log.is_some() {
+                    return Err(Error::LogUnsupported(self.type_.clone()));
+                }
                 return create_system_type_serial_device(
                     self,
                     protection_type,
@@ -226,6 +280,14 @@ impl SerialParameters {
                 );
             }
         };
+        let (input, output, sync) = if self.type_.is_console() || self.log.is_some() {
+            let (console_input, console_output) = create_console_mux(self, output, keep_rds)?;
+            let console_input = console_input.map(|i| Box::new(i) as Box<dyn SerialInput>);
+            let console_output: Box<dyn io::Write + Send> = Box::new(console_output);
+            (console_input.or(input), Some(console_output), None)
+        } else {
+            (input, output, sync)
+        };
         Ok(T::new(
             protection_type,
             evt,
@@ -265,6 +327,10 @@ mod tests {
                 stdin: false,
                 out_timestamp: false,
                 debugcon_port: 0x402,
+                addr: None,
+                log: None,
+                log_size: None,
+                log_count: 4,
             }
         );
 
@@ -283,6 +349,13 @@ mod tests {
         let opt = "type=namedpipe";
         let params = from_serial_arg(opt).unwrap();
         assert_eq!(params.type_, SerialType::SystemSerialType);
+        #[cfg(unix)]
+        {
+            let params = from_serial_arg("type=pty").unwrap();
+            assert_eq!(params.type_, SerialType::Pty);
+            let params = from_serial_arg("type=tcp").unwrap();
+            assert_eq!(params.type_, SerialType::Tcp);
+        }
         let params = from_serial_arg("type=foobar");
         assert!(params.is_err());
 
@@ -358,6 +431,18 @@ mod tests {
         let params = from_serial_arg("debugcon_port=1026").unwrap();
         assert_eq!(params.debugcon_port, 1026);
 
+        // addr parameter
+        let params = from_serial_arg("addr=127.0.0.1:4444").unwrap();
+        assert_eq!(params.addr, Some("127.0.0.1:4444".parse().unwrap()));
+        let params = from_serial_arg("addr=localhost");
+        assert!(params.is_err());
+
+        // log parameters
+        let params = from_serial_arg("log=/path/to/log,log-size=1048576,log-count=2").unwrap();
+        assert_eq!(params.log, Some("/path/to/log".into()));
+        assert_eq!(params.log_size, Some(1048576));
+        assert_eq!(params.log_count, 2);
+
         // all together
         let params = from_serial_arg("type=stdout,path=/some/path,hardware=virtio-console,num=5,earlycon,console,stdin,input=/some/input,out_timestamp,debugcon_port=12").unwrap();
         assert_eq!(
@@ -373,6 +458,10 @@ mod tests {
                 stdin: true,
                 out_timestamp: true,
                 debugcon_port: 12,
+                addr: None,
+                log: None,
+                log_size: None,
+                log_count: 4,
             }
         );
 
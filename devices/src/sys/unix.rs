@@ -3,6 +3,7 @@
 // found in the LICENSE file.
 
 mod acpi;
+mod console_mux;
 pub(crate) mod serial_device;
 
 pub(crate) use acpi::acpi_event_run;
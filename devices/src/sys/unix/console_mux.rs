@@ -0,0 +1,521 @@
+// Copyright 2023 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Console multiplexer of the serial devices, which forwards the stream of a serial device to a
+//! PTY or to the client of a TCP listener, and tees its output to a rotating log file.
+//!
+//! The multiplexer runs in a thread of the main process and talks to the device through a pair of
+//! pipes, so that the device can be sandboxed without access to the PTY, the network or the file
+//! system.
+
+use std::collections::VecDeque;
+use std::ffi::OsStr;
+use std::ffi::OsString;
+use std::fs;
+use std::fs::File;
+use std::fs::OpenOptions;
+use std::io;
+use std::io::ErrorKind;
+use std::io::Read;
+use std::io::Write;
+use std::mem::MaybeUninit;
+use std::net::TcpListener;
+use std::net::TcpStream;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::path::PathBuf;
+use std::thread;
+
+use base::add_fd_flags;
+use base::error;
+use base::info;
+use base::pipe;
+use base::AsRawDescriptor;
+use base::EventToken;
+use base::EventType;
+use base::FromRawDescriptor;
+use base::WaitContext;
+
+/// Output of the device kept while no client is connected to the console. Older output is
+/// dropped once this is exceeded.
+const PENDING_OUTPUT_MAX: usize = 64 * 1024;
+
+/// Where the multiplexer forwards the stream of the device.
+pub enum ConsoleBackend {
+    /// Master side of a PTY. The slave side is kept open, so that the output of the device is
+    /// buffered by the PTY rather than failing with EIO while no program has it open.
+    Pty { master: File, _slave: File },
+    /// A TCP listener, whose latest client is connected to the device. The output of the device
+    /// is kept while no client is connected.
+    Tcp {
+        listener: TcpListener,
+        client: Option<TcpStream>,
+    },
+    /// Output only stream, e.g. stdout, written along with the log.
+    Output(Option<Box<dyn Write + Send>>),
+}
+
+impl ConsoleBackend {
+    /// Allocates a PTY and returns it along with the path of its slave side, e.g. `/dev/pts/3`.
+    pub fn new_pty() -> io::Result<(ConsoleBackend, PathBuf)> {
+        // Safe because posix_openpt doesn't access memory and we check the result.
+        let fd = unsafe { libc::posix_openpt(libc::O_RDWR | libc::O_NOCTTY | libc::O_CLOEXEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // Safe because we own the descriptor returned by posix_openpt.
+        let master = unsafe { File::from_raw_descriptor(fd) };
+        // Safe because these functions only take the descriptor of the master, which is valid.
+        if unsafe { libc::grantpt(fd) } < 0 || unsafe { libc::unlockpt(fd) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let mut name = [0u8; 128];
+        // Safe because ptsname_r writes at most `name.len()` bytes, nul terminator included.
+        let ret =
+            unsafe { libc::ptsname_r(fd, name.as_mut_ptr() as *mut libc::c_char, name.len()) };
+        if ret != 0 {
+            return Err(io::Error::from_raw_os_error(ret));
+        }
+        let len = name.iter().position(|&c| c == 0).unwrap_or(name.len());
+        let path = PathBuf::from(OsStr::from_bytes(&name[..len]));
+
+        let slave = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(libc::O_NOCTTY)
+            .open(&path)?;
+        set_raw_mode(&slave)?;
+        add_fd_flags(master.as_raw_fd(), libc::O_NONBLOCK)?;
+        Ok((
+            ConsoleBackend::Pty {
+                master,
+                _slave: slave,
+            },
+            path,
+        ))
+    }
+
+    /// Returns whether the backend gives input to the device.
+    fn is_interactive(&self) -> bool {
+        !matches!(self, ConsoleBackend::Output(_))
+    }
+}
+
+/// Sets the terminal `tty` to raw mode, so that the stream of the device goes through unchanged
+/// until a program, e.g. `screen`, sets the mode it wants.
+fn set_raw_mode(tty: &File) -> io::Result<()> {
+    let mut termios = MaybeUninit::<libc::termios>::uninit();
+    // Safe because tcgetattr fills the termios structure and we check the result.
+    if unsafe { libc::tcgetattr(tty.as_raw_fd(), termios.as_mut_ptr()) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // Safe because tcgetattr initialized the structure.
+    let mut termios = unsafe { termios.assume_init() };
+    // Safe because cfmakeraw only modifies the given structure.
+    unsafe { libc::cfmakeraw(&mut termios) };
+    // Safe because tcsetattr only reads the termios structure and we check the result.
+    if unsafe { libc::tcsetattr(tty.as_raw_fd(), libc::TCSANOW, &termios) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Log file that is moved to `<path>.1` once it reaches `max_size` bytes, the previous
+/// `<path>.1` being moved to `<path>.2` and so on, keeping at most `count` previous files.
+pub struct RotatingLog {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    max_size: Option<u64>,
+    count: u8,
+}
+
+impl RotatingLog {
+    pub fn new(path: &Path, max_size: Option<u64>, count: u8) -> io::Result<RotatingLog> {
+        let file = OpenOptions::new().append(true).create(true).open(path)?;
+        let size = file.metadata()?.len();
+        Ok(RotatingLog {
+            path: path.to_owned(),
+            file,
+            size,
+            max_size,
+            count,
+        })
+    }
+
+    fn rotated_path(&self, index: u8) -> PathBuf {
+        let mut path = OsString::from(&self.path);
+        path.push(format!(".{}", index));
+        path.into()
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        if self.count > 0 {
+            for index in (1..self.count).rev() {
+                match fs::rename(self.rotated_path(index), self.rotated_path(index + 1)) {
+                    Err(e) if e.kind() != ErrorKind::NotFound => return Err(e),
+                    _ => {}
+                }
+            }
+            fs::rename(&self.path, self.rotated_path(1))?;
+        }
+        self.file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+}
+
+impl Write for RotatingLog {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if let Some(max_size) = self.max_size {
+            if self.size > 0 && self.size + buf.len() as u64 > max_size {
+                self.rotate()?;
+            }
+        }
+        let written = self.file.write(buf)?;
+        self.size += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+#[derive(EventToken)]
+enum Token {
+    // Output of the device.
+    Output,
+    // The TCP listener has a new client.
+    Listener,
+    // The PTY or the TCP client can be read or written.
+    Client,
+}
+
+struct ConsoleMux {
+    // Read end of the pipe written by the device.
+    output: File,
+    // Write end of the pipe read by the device.
+    input: Option<File>,
+    backend: ConsoleBackend,
+    log: Option<RotatingLog>,
+    pending: VecDeque<u8>,
+    wait_writable: bool,
+    wait_ctx: WaitContext<Token>,
+}
+
+impl ConsoleMux {
+    // Returns the descriptor of the PTY or of the connected TCP client, if any.
+    fn client(&self) -> Option<&dyn AsRawDescriptor> {
+        match &self.backend {
+            ConsoleBackend::Pty { master, .. } => Some(master),
+            ConsoleBackend::Tcp {
+                client: Some(client),
+                ..
+            } => Some(client),
+            _ => None,
+        }
+    }
+
+    fn run(&mut self) -> base::Result<()> {
+        loop {
+            let events = self.wait_ctx.wait()?;
+            for event in events.iter() {
+                match event.token {
+                    Token::Output => {
+                        if !self.forward_output()? {
+                            // The device is gone.
+                            return Ok(());
+                        }
+                    }
+                    Token::Listener => self.accept()?,
+                    Token::Client => {
+                        if event.is_readable {
+                            self.forward_input()?;
+                        }
+                        if event.is_writable {
+                            self.flush_pending()?;
+                        }
+                        if event.is_hungup {
+                            self.disconnect()?;
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    // Forwards the output of the device to the backend and the log. Returns false once the device
+    // closed its side of the pipe.
+    fn forward_output(&mut self) -> base::Result<bool> {
+        let mut buf = [0u8; 4096];
+        let len = match self.output.read(&mut buf) {
+            Ok(0) => return Ok(false),
+            Ok(len) => len,
+            Err(e) if e.kind() == ErrorKind::Interrupted => return Ok(true),
+            Err(e) => return Err(e.into()),
+        };
+        let buf = &buf[..len];
+
+        if let Some(log) = &mut self.log {
+            if let Err(e) = log.write_all(buf) {
+                error!("failed to write serial log, stopping it: {}", e);
+                self.log = None;
+            }
+        }
+
+        if let ConsoleBackend::Output(Some(output)) = &mut self.backend {
+            if let Err(e) = output.write_all(buf).and_then(|_| output.flush()) {
+                error!("failed to write serial output: {}", e);
+            }
+            return Ok(true);
+        }
+
+        self.pending.extend(buf);
+        let excess = self.pending.len().saturating_sub(PENDING_OUTPUT_MAX);
+        self.pending.drain(..excess);
+        self.flush_pending()?;
+        Ok(true)
+    }
+
+    // Writes as much pending output as the client takes without blocking, and waits for the
+    // client to be writable again if some is left.
+    fn flush_pending(&mut self) -> base::Result<()> {
+        let result = match &self.backend {
+            ConsoleBackend::Pty { master, .. } => write_pending(&mut self.pending, master),
+            ConsoleBackend::Tcp {
+                client: Some(client),
+                ..
+            } => write_pending(&mut self.pending, client),
+            _ => return Ok(()),
+        };
+        if let Err(e) = result {
+            info!("serial console client disconnected: {}", e);
+            return self.disconnect();
+        }
+
+        let wait_writable = !self.pending.is_empty();
+        if wait_writable != self.wait_writable {
+            let event_type = if wait_writable {
+                EventType::ReadWrite
+            } else {
+                EventType::Read
+            };
+            if let Some(client) = self.client() {
+                self.wait_ctx.modify(client, event_type, Token::Client)?;
+            }
+            self.wait_writable = wait_writable;
+        }
+        Ok(())
+    }
+
+    // Forwards the input of the client to the device.
+    fn forward_input(&mut self) -> base::Result<()> {
+        let mut buf = [0u8; 1024];
+        let result = match &self.backend {
+            ConsoleBackend::Pty { master, .. } => (&*master).read(&mut buf),
+            ConsoleBackend::Tcp {
+                client: Some(client),
+                ..
+            } => (&*client).read(&mut buf),
+            _ => return Ok(()),
+        };
+        match result {
+            Ok(0) => {
+                info!("serial console client disconnected");
+                self.disconnect()
+            }
+            Ok(len) => {
+                if let Some(input) = &mut self.input {
+                    input.write_all(&buf[..len])?;
+                }
+                Ok(())
+            }
+            Err(e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::Interrupted => {
+                Ok(())
+            }
+            Err(e) => {
+                info!("serial console client disconnected: {}", e);
+                self.disconnect()
+            }
+        }
+    }
+
+    fn accept(&mut self) -> base::Result<()> {
+        let listener = match &self.backend {
+            ConsoleBackend::Tcp { listener, .. } => listener,
+            _ => return Ok(()),
+        };
+        let (client, addr) = match listener.accept() {
+            Ok(connection) => connection,
+            Err(e) => {
+                error!("failed to accept serial console client: {}", e);
+                return Ok(());
+            }
+        };
+        client.set_nonblocking(true)?;
+        info!("serial console client connected from {}", addr);
+
+        // The new client replaces the previous one, if any.
+        self.disconnect()?;
+        self.wait_ctx.add(&client, Token::Client)?;
+        if let ConsoleBackend::Tcp {
+            client: current, ..
+        } = &mut self.backend
+        {
+            *current = Some(client);
+        }
+        self.flush_pending()
+    }
+
+    // Drops the TCP client. A PTY stays open since another program can open its slave side.
+    fn disconnect(&mut self) -> base::Result<()> {
+        if let ConsoleBackend::Tcp { client, .. } = &mut self.backend {
+            if let Some(client) = client.take() {
+                self.wait_ctx.delete(&client)?;
+            }
+            self.wait_writable = false;
+        }
+        Ok(())
+    }
+}
+
+// Writes the front of `pending` to `client` until it would block.
+fn write_pending<W>(pending: &mut VecDeque<u8>, mut client: W) -> io::Result<()>
+where
+    W: Write,
+{
+    while !pending.is_empty() {
+        let (front, _) = pending.as_slices();
+        match client.write(front) {
+            Ok(0) => return Err(ErrorKind::WriteZero.into()),
+            Ok(written) => {
+                pending.drain(..written);
+            }
+            Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+            Err(e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+/// Starts a thread forwarding the output of a serial device to `backend` and `log`, and the input
+/// of `backend` to the device.
+///
+/// Returns the input of the device, if `backend` gives any, and its output. The thread exits once
+/// every copy of the output is closed.
+pub fn spawn_console_mux(
+    name: &str,
+    backend: ConsoleBackend,
+    log: Option<RotatingLog>,
+) -> io::Result<(Option<File>, File)> {
+    let (output, device_output) = pipe(true)?;
+    let (device_input, input) = if backend.is_interactive() {
+        let (device_input, input) = pipe(true)?;
+        (Some(device_input), Some(input))
+    } else {
+        (None, None)
+    };
+
+    let wait_ctx = WaitContext::build_with(&[(&output, Token::Output)])?;
+    match &backend {
+        ConsoleBackend::Pty { master, .. } => wait_ctx.add(master, Token::Client)?,
+        ConsoleBackend::Tcp { listener, .. } => wait_ctx.add(listener, Token::Listener)?,
+        ConsoleBackend::Output(_) => {}
+    }
+
+    let mut mux = ConsoleMux {
+        output,
+        input,
+        backend,
+        log,
+        pending: VecDeque::new(),
+        wait_writable: false,
+        wait_ctx,
+    };
+    thread::Builder::new()
+        .name(format!("{}_console", name))
+        .spawn(move || {
+            if let Err(e) = mux.run() {
+                error!("serial console multiplexer failed: {}", e);
+            }
+        })?;
+    Ok((device_input, device_output))
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::tempdir;
+
+    use super::*;
+
+    #[test]
+    fn rotating_log() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("serial.log");
+        let mut log = RotatingLog::new(&path, Some(8), 2).unwrap();
+        for line in [b"first\n", b"secnd\n", b"third\n", b"forth\n"] {
+            log.write_all(line).unwrap();
+        }
+        assert_eq!(fs::read(&path).unwrap(), b"forth\n");
+        assert_eq!(
+            fs::read(dir.path().join("serial.log.1")).unwrap(),
+            b"third\n"
+        );
+        assert_eq!(
+            fs::read(dir.path().join("serial.log.2")).unwrap(),
+            b"secnd\n"
+        );
+        assert!(!dir.path().join("serial.log.3").exists());
+    }
+
+    #[test]
+    fn tcp_reconnect() {
+        let dir = tempdir().unwrap();
+        let log_path = dir.path().join("serial.log");
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let backend = ConsoleBackend::Tcp {
+            listener,
+            client: None,
+        };
+        let log = RotatingLog::new(&log_path, None, 0).unwrap();
+        let (device_input, mut device_output) =
+            spawn_console_mux("test", backend, Some(log)).unwrap();
+        let mut device_input = device_input.unwrap();
+
+        // Output written while no client is connected is kept for the next client.
+        device_output.write_all(b"boot\n").unwrap();
+        let mut client = TcpStream::connect(addr).unwrap();
+        let mut buf = [0u8; 5];
+        client.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"boot\n");
+
+        client.write_all(b"ls\n").unwrap();
+        let mut buf = [0u8; 3];
+        device_input.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"ls\n");
+
+        // A new client replaces the previous one, which is disconnected.
+        let mut old_client = client;
+        let mut client = TcpStream::connect(addr).unwrap();
+        old_client.read_to_end(&mut Vec::new()).unwrap();
+        device_output.write_all(b"done\n").unwrap();
+        let mut buf = [0u8; 5];
+        client.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"done\n");
+
+        // The log has the whole output, and the thread exits once the device is gone.
+        drop(device_output);
+        client.read_to_end(&mut Vec::new()).unwrap();
+        assert_eq!(fs::read(&log_path).unwrap(), b"boot\ndone\n");
+    }
+}
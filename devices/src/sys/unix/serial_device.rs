@@ -3,10 +3,14 @@
 // found in the LICENSE file.
 
 use std::borrow::Cow;
+use std::fs;
+use std::fs::File;
 use std::fs::OpenOptions;
 use std::io;
 use std::io::ErrorKind;
 use std::io::Write;
+use std::net::TcpListener;
+use std::os::unix::fs::symlink;
 use std::os::unix::net::UnixDatagram;
 use std::path::Path;
 use std::path::PathBuf;
@@ -23,9 +27,13 @@ use base::RawDescriptor;
 use base::ReadNotifier;
 use hypervisor::ProtectionType;
 
+use super::console_mux::spawn_console_mux;
+use super::console_mux::ConsoleBackend;
+use super::console_mux::RotatingLog;
 use crate::serial_device::Error;
 use crate::serial_device::SerialInput;
 use crate::serial_device::SerialParameters;
+use crate::serial_device::SerialType;
 
 pub const SYSTEM_SERIAL_TYPE_NAME: &str = "UnixSocket";
 
@@ -227,3 +235,55 @@ pub(crate) fn create_system_type_serial_device<T: SerialDevice>(
         None => Err(Error::PathRequired),
     }
 }
+
+/// Connects the device to a console multiplexer thread, which forwards its stream to the PTY or
+/// the TCP listener of `param`, or else to `output`, and tees its output to the log of `param`.
+///
+/// Returns the input, if the console gives any, and the output of the device.
+pub(crate) fn create_console_mux(
+    param: &SerialParameters,
+    output: Option<Box<dyn io::Write + Send>>,
+    keep_rds: &mut Vec<RawDescriptor>,
+) -> std::result::Result<(Option<File>, File), Error> {
+    let name = format!("{}{}", param.hardware, param.num);
+    let backend = match param.type_ {
+        SerialType::Pty => {
+            let (backend, pty_path) = ConsoleBackend::new_pty().map_err(Error::PtyCreate)?;
+            info!("{}: console on PTY {}", name, pty_path.display());
+            if let Some(path) = &param.path {
+                // Replace the link left by a previous run, but nothing else.
+                if matches!(fs::symlink_metadata(path), Ok(m) if m.file_type().is_symlink()) {
+                    fs::remove_file(path).map_err(|e| Error::PtyLink(e, path.clone()))?;
+                }
+                symlink(&pty_path, path).map_err(|e| Error::PtyLink(e, path.clone()))?;
+            }
+            backend
+        }
+        SerialType::Tcp => {
+            let addr = param.addr.ok_or(Error::AddrRequired)?;
+            let listener = TcpListener::bind(addr).map_err(|e| Error::TcpBind(e, addr))?;
+            if let Ok(addr) = listener.local_addr() {
+                info!("{}: console listening on {}", name, addr);
+            }
+            ConsoleBackend::Tcp {
+                listener,
+                client: None,
+            }
+        }
+        _ => ConsoleBackend::Output(output),
+    };
+    let log = match &param.log {
+        Some(path) => Some(
+            RotatingLog::new(path, param.log_size, param.log_count)
+                .map_err(|e| Error::FileCreate(e, path.clone()))?,
+        ),
+        None => None,
+    };
+
+    let (input, output) = spawn_console_mux(&name, backend, log).map_err(Error::ConsoleMux)?;
+    if let Some(input) = &input {
+        keep_rds.push(input.as_raw_descriptor());
+    }
+    keep_rds.push(output.as_raw_descriptor());
+    Ok((input, output))
+}
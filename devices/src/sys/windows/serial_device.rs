@@ -2,6 +2,7 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the LICENSE file.
 
+use std::fs::File;
 use std::io;
 
 use base::named_pipes;
@@ -83,3 +84,12 @@ pub(crate) fn create_system_type_serial_device<T: SerialDevice>(
         }
     }
 }
+
+/// Log files need the console multiplexer, which is only available on unix.
+pub(crate) fn create_console_mux(
+    param: &SerialParameters,
+    _output: Option<Box<dyn io::Write + Send>>,
+    _keep_rds: &mut Vec<RawDescriptor>,
+) -> std::result::Result<(Option<File>, File), Error> {
+    Err(Error::LogUnsupported(param.type_.clone()))
+}
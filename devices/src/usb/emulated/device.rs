@@ -0,0 +1,407 @@
+// Copyright 2023 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+use std::cmp::min;
+use std::mem;
+use std::sync::Arc;
+
+use base::error;
+use usb_util::ConfigDescriptor;
+use usb_util::ControlRequestDataPhaseTransferDirection;
+use usb_util::ControlRequestRecipient;
+use usb_util::Descriptor;
+use usb_util::DescriptorHeader;
+use usb_util::DescriptorType;
+use usb_util::DeviceDescriptor;
+use usb_util::EndpointDescriptor;
+use usb_util::InterfaceDescriptor;
+use usb_util::StandardControlRequest;
+use usb_util::TransferStatus;
+use usb_util::UsbRequestSetup;
+use zerocopy::AsBytes;
+
+use crate::usb::host_backend::error::*;
+use crate::usb::host_backend::host_device::ControlEndpointState;
+use crate::usb::xhci::scatter_gather_buffer::ScatterGatherBuffer;
+use crate::usb::xhci::xhci_backend_device::BackendType;
+use crate::usb::xhci::xhci_backend_device::UsbDeviceAddress;
+use crate::usb::xhci::xhci_backend_device::XhciBackendDevice;
+use crate::usb::xhci::xhci_transfer::XhciTransfer;
+use crate::usb::xhci::xhci_transfer::XhciTransferState;
+use crate::usb::xhci::xhci_transfer::XhciTransferType;
+
+/// Vendor ID of the emulated devices.
+pub const VENDOR_ID: u16 = 0x18d1;
+
+/// Language of the string descriptors, US English.
+const LANGUAGE_ID: u16 = 0x0409;
+
+/// Returns `descriptor` preceded by its standard header.
+pub fn descriptor_bytes<D: Descriptor + AsBytes>(descriptor: &D) -> Vec<u8> {
+    let header = DescriptorHeader {
+        bLength: (mem::size_of::<DescriptorHeader>() + mem::size_of::<D>()) as u8,
+        bDescriptorType: D::descriptor_type() as u8,
+    };
+    let mut bytes = header.as_bytes().to_vec();
+    bytes.extend_from_slice(descriptor.as_bytes());
+    bytes
+}
+
+/// Descriptors of an emulated device, which has a single configuration with a single interface.
+pub struct UsbDescriptors {
+    device: DeviceDescriptor,
+    // The configuration descriptor followed by the interface, class-specific and endpoint
+    // descriptors.
+    config: Vec<u8>,
+    // String descriptor `n` is `strings[n - 1]`.
+    strings: &'static [&'static str],
+}
+
+impl UsbDescriptors {
+    /// Builds the descriptors of a device with a configuration made of `interface`, the
+    /// class-specific descriptors `class` and `endpoints`.
+    pub fn new(
+        device: DeviceDescriptor,
+        interface: InterfaceDescriptor,
+        class: &[u8],
+        endpoints: &[EndpointDescriptor],
+        strings: &'static [&'static str],
+    ) -> UsbDescriptors {
+        let mut body = descriptor_bytes(&interface);
+        body.extend_from_slice(class);
+        for endpoint in endpoints {
+            body.extend(descriptor_bytes(endpoint));
+        }
+        let config_descriptor = ConfigDescriptor {
+            wTotalLength: (mem::size_of::<DescriptorHeader>()
+                + mem::size_of::<ConfigDescriptor>()
+                + body.len()) as u16,
+            bNumInterfaces: 1,
+            bConfigurationValue: 1,
+            iConfiguration: 0,
+            // Bus powered, bit 7 must be set.
+            bmAttributes: 0x80,
+            // 100 mA, in units of 2 mA.
+            bMaxPower: 50,
+        };
+        let mut config = descriptor_bytes(&config_descriptor);
+        config.extend(body);
+        UsbDescriptors {
+            device,
+            config,
+            strings,
+        }
+    }
+
+    fn string(&self, index: u8) -> Option<Vec<u8>> {
+        // String descriptor 0 lists the supported languages.
+        let chars: Vec<u16> = match index {
+            0 => vec![LANGUAGE_ID],
+            _ => self
+                .strings
+                .get(index as usize - 1)?
+                .encode_utf16()
+                .collect(),
+        };
+        let mut bytes = vec![
+            (mem::size_of::<DescriptorHeader>() + chars.len() * 2) as u8,
+            DescriptorType::String as u8,
+        ];
+        for c in chars {
+            bytes.extend_from_slice(&c.to_le_bytes());
+        }
+        Some(bytes)
+    }
+}
+
+/// The function of an emulated device, which the guest drives through the endpoints of its
+/// interface and through class-specific control requests.
+pub trait UsbFunction: Send {
+    /// Returns the descriptors of the device.
+    fn descriptors(&self) -> &UsbDescriptors;
+    /// Handles a control request that isn't a standard request to the device. `data` is what the
+    /// host sent with the request. Returns the data to send back to the host, or None if the
+    /// request isn't supported.
+    fn control_request(&mut self, setup: &UsbRequestSetup, data: &[u8]) -> Option<Vec<u8>>;
+    /// Handles a transfer to one of the endpoints of the interface.
+    fn handle_transfer(&mut self, transfer: XhciTransfer) -> Result<()>;
+    /// Puts the function back in the state it has when the device is plugged in.
+    fn reset(&mut self);
+}
+
+/// A USB device emulated by crosvm. It handles the standard requests on the default control
+/// endpoint and leaves the rest to its `UsbFunction`.
+pub struct EmulatedDevice<F: UsbFunction> {
+    function: F,
+    ctl_ep_state: ControlEndpointState,
+    control_request_setup: UsbRequestSetup,
+    executed: bool,
+    configuration: u8,
+}
+
+impl<F: UsbFunction> EmulatedDevice<F> {
+    pub fn new(function: F) -> EmulatedDevice<F> {
+        EmulatedDevice {
+            function,
+            ctl_ep_state: ControlEndpointState::SetupStage,
+            control_request_setup: UsbRequestSetup::new(0, 0, 0, 0, 0),
+            executed: false,
+            configuration: 0,
+        }
+    }
+
+    fn get_descriptor(&self, value: u16) -> Option<Vec<u8>> {
+        let descriptors = self.function.descriptors();
+        let descriptor_type = (value >> 8) as u8;
+        let index = value as u8;
+        if descriptor_type == DescriptorType::Device as u8 {
+            Some(descriptor_bytes(&descriptors.device))
+        } else if descriptor_type == DescriptorType::Configuration as u8 && index == 0 {
+            Some(descriptors.config.clone())
+        } else if descriptor_type == DescriptorType::String as u8 {
+            descriptors.string(index)
+        } else {
+            None
+        }
+    }
+
+    fn control_request(&mut self, setup: &UsbRequestSetup, data: &[u8]) -> Option<Vec<u8>> {
+        let recipient = setup.get_recipient();
+        match (setup.get_standard_request(), recipient) {
+            (Some(StandardControlRequest::GetDescriptor), ControlRequestRecipient::Device) => {
+                self.get_descriptor(setup.value)
+            }
+            (Some(StandardControlRequest::SetAddress), ControlRequestRecipient::Device) => {
+                // The address is assigned by the Address Device command of the xHCI controller.
+                Some(Vec::new())
+            }
+            (Some(StandardControlRequest::SetConfiguration), ControlRequestRecipient::Device) => {
+                self.configuration = setup.value as u8;
+                Some(Vec::new())
+            }
+            (Some(StandardControlRequest::GetConfiguration), ControlRequestRecipient::Device) => {
+                Some(vec![self.configuration])
+            }
+            (Some(StandardControlRequest::GetStatus), _) => Some(vec![0, 0]),
+            (Some(StandardControlRequest::GetInterface), ControlRequestRecipient::Interface) => {
+                Some(vec![0])
+            }
+            (Some(StandardControlRequest::SetInterface), ControlRequestRecipient::Interface)
+            | (Some(StandardControlRequest::ClearFeature), _)
+            | (Some(StandardControlRequest::SetFeature), _) => Some(Vec::new()),
+            _ => self.function.control_request(setup, data),
+        }
+    }
+
+    fn execute_control_transfer(
+        &mut self,
+        xhci_transfer: XhciTransfer,
+        buffer: Option<ScatterGatherBuffer>,
+    ) -> Result<()> {
+        let setup = self.control_request_setup;
+        let direction = setup.get_direction();
+        let mut data = Vec::new();
+        if direction == ControlRequestDataPhaseTransferDirection::HostToDevice {
+            if let Some(buffer) = &buffer {
+                data = vec![0; buffer.len().map_err(Error::BufferLen)?];
+                buffer.read(&mut data).map_err(Error::ReadBuffer)?;
+            }
+        }
+
+        let (status, bytes_transferred) = match self.control_request(&setup, &data) {
+            Some(response) => match (direction, &buffer) {
+                (ControlRequestDataPhaseTransferDirection::DeviceToHost, Some(buffer)) => {
+                    let len = min(response.len(), setup.length as usize);
+                    let written = buffer.write(&response[..len]).map_err(Error::WriteBuffer)?;
+                    (TransferStatus::Completed, written as u32)
+                }
+                _ => (TransferStatus::Completed, data.len() as u32),
+            },
+            None => {
+                usb_debug!("unsupported control request {:?}", setup);
+                (TransferStatus::Error, 0)
+            }
+        };
+        xhci_transfer
+            .on_transfer_complete(&status, bytes_transferred)
+            .map_err(Error::TransferComplete)
+    }
+
+    fn handle_control_transfer(&mut self, xhci_transfer: XhciTransfer) -> Result<()> {
+        let transfer_type = xhci_transfer
+            .get_transfer_type()
+            .map_err(Error::GetXhciTransferType)?;
+        match transfer_type {
+            XhciTransferType::SetupStage(setup) => {
+                if self.ctl_ep_state != ControlEndpointState::SetupStage {
+                    error!("Control endpoint is in an inconsistant state");
+                    return Ok(());
+                }
+                usb_debug!("setup stage setup buffer: {:?}", setup);
+                self.control_request_setup = setup;
+                xhci_transfer
+                    .on_transfer_complete(&TransferStatus::Completed, 0)
+                    .map_err(Error::TransferComplete)?;
+                self.ctl_ep_state = ControlEndpointState::DataStage;
+            }
+            XhciTransferType::DataStage(buffer) => {
+                if self.ctl_ep_state != ControlEndpointState::DataStage {
+                    error!("Control endpoint is in an inconsistant state");
+                    return Ok(());
+                }
+                self.execute_control_transfer(xhci_transfer, Some(buffer))?;
+                self.executed = true;
+                self.ctl_ep_state = ControlEndpointState::StatusStage;
+            }
+            XhciTransferType::StatusStage => {
+                if self.ctl_ep_state == ControlEndpointState::SetupStage {
+                    error!("Control endpoint is in an inconsistant state");
+                    return Ok(());
+                }
+                if self.executed {
+                    xhci_transfer
+                        .on_transfer_complete(&TransferStatus::Completed, 0)
+                        .map_err(Error::TransferComplete)?;
+                } else {
+                    self.execute_control_transfer(xhci_transfer, None)?;
+                }
+                self.executed = false;
+                self.ctl_ep_state = ControlEndpointState::SetupStage;
+            }
+            _ => {
+                error!(
+                    "Non control {} transfer sent to control endpoint.",
+                    transfer_type,
+                );
+                xhci_transfer
+                    .on_transfer_complete(&TransferStatus::Completed, 0)
+                    .map_err(Error::TransferComplete)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<F: UsbFunction> XhciBackendDevice for EmulatedDevice<F> {
+    fn get_backend_type(&self) -> BackendType {
+        BackendType::Usb2
+    }
+
+    fn get_vid(&self) -> u16 {
+        self.function.descriptors().device.idVendor
+    }
+
+    fn get_pid(&self) -> u16 {
+        self.function.descriptors().device.idProduct
+    }
+
+    fn submit_transfer(&mut self, transfer: XhciTransfer) -> Result<()> {
+        if transfer.get_endpoint_number() == 0 {
+            self.handle_control_transfer(transfer)
+        } else {
+            self.function.handle_transfer(transfer)
+        }
+    }
+
+    fn set_address(&mut self, _address: UsbDeviceAddress) {}
+
+    fn reset(&mut self) -> Result<()> {
+        usb_debug!("resetting emulated device");
+        self.ctl_ep_state = ControlEndpointState::SetupStage;
+        self.executed = false;
+        self.configuration = 0;
+        self.function.reset();
+        Ok(())
+    }
+}
+
+/// A transfer to an IN endpoint that waits until the device has something to send, e.g. the next
+/// input event for the interrupt endpoint of a HID device. The transfer is completed as cancelled
+/// if the guest stops the endpoint or if it is dropped before being completed.
+pub struct PendingTransfer {
+    transfer: Arc<XhciTransfer>,
+    buffer: ScatterGatherBuffer,
+}
+
+impl PendingTransfer {
+    /// Holds `transfer` until it is completed. Returns None if the guest already cancelled it.
+    pub fn new(
+        transfer: XhciTransfer,
+        buffer: ScatterGatherBuffer,
+    ) -> Result<Option<PendingTransfer>> {
+        let transfer = Arc::new(transfer);
+        let cancelled = {
+            let mut state = transfer.state().lock();
+            match mem::replace(&mut *state, XhciTransferState::Cancelled) {
+                XhciTransferState::Created => {
+                    // The callback holds a reference to the transfer, which is dropped with the
+                    // callback once the transfer is completed or cancelled.
+                    let cancelled_transfer = transfer.clone();
+                    let cancel_callback = Box::new(move || {
+                        if let Err(e) =
+                            cancelled_transfer.on_transfer_complete(&TransferStatus::Cancelled, 0)
+                        {
+                            error!("failed to cancel pending transfer: {}", e);
+                        }
+                    });
+                    *state = XhciTransferState::Submitted { cancel_callback };
+                    false
+                }
+                XhciTransferState::Cancelled => true,
+                _ => {
+                    error!("xhci trasfer state is invalid");
+                    return Err(Error::BadXhciTransferState);
+                }
+            }
+        };
+        if cancelled {
+            transfer
+                .on_transfer_complete(&TransferStatus::Cancelled, 0)
+                .map_err(Error::TransferComplete)?;
+            return Ok(None);
+        }
+        Ok(Some(PendingTransfer { transfer, buffer }))
+    }
+
+    /// Returns the number of bytes that the transfer can hold.
+    pub fn len(&self) -> Result<usize> {
+        self.buffer.len().map_err(Error::BufferLen)
+    }
+
+    /// Completes the transfer with `data`. Returns false, without sending anything, if the guest
+    /// cancelled the transfer meanwhile.
+    pub fn complete(self, data: &[u8]) -> Result<bool> {
+        {
+            let mut state = self.transfer.state().lock();
+            match mem::replace(&mut *state, XhciTransferState::Completed) {
+                XhciTransferState::Submitted { .. } => {}
+                previous => {
+                    *state = previous;
+                    return Ok(false);
+                }
+            }
+        }
+        let bytes_transferred = self.buffer.write(data).map_err(Error::WriteBuffer)?;
+        self.transfer
+            .on_transfer_complete(&TransferStatus::Completed, bytes_transferred as u32)
+            .map_err(Error::TransferComplete)?;
+        Ok(true)
+    }
+}
+
+impl Drop for PendingTransfer {
+    fn drop(&mut self) {
+        let mut state = self.transfer.state().lock();
+        if let XhciTransferState::Submitted { .. } = *state {
+            *state = XhciTransferState::Cancelled;
+            drop(state);
+            if let Err(e) = self
+                .transfer
+                .on_transfer_complete(&TransferStatus::Cancelled, 0)
+            {
+                error!("failed to cancel pending transfer: {}", e);
+            }
+        }
+    }
+}
@@ -0,0 +1,603 @@
+// Copyright 2023 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! USB HID keyboard and tablet, which send the guest the input events injected through the
+//! control socket.
+
+use std::collections::VecDeque;
+use std::sync::Arc;
+
+use base::error;
+use linux_input_sys::ABS_X;
+use linux_input_sys::ABS_Y;
+use linux_input_sys::BTN_TOUCH;
+use linux_input_sys::EV_ABS;
+use linux_input_sys::EV_KEY;
+use linux_input_sys::EV_REL;
+use linux_input_sys::EV_SYN;
+use linux_input_sys::REL_WHEEL;
+use linux_input_sys::SYN_REPORT;
+use sync::Mutex;
+use usb_util::ControlRequestRecipient;
+use usb_util::ControlRequestType;
+use usb_util::DeviceDescriptor;
+use usb_util::EndpointDescriptor;
+use usb_util::InterfaceDescriptor;
+use usb_util::StandardControlRequest;
+use usb_util::TransferStatus;
+use usb_util::UsbRequestSetup;
+use vm_control::input::InputEvent;
+use vm_control::input::BTN_LEFT;
+use vm_control::input::BTN_MIDDLE;
+use vm_control::input::BTN_RIGHT;
+
+use super::device::PendingTransfer;
+use super::device::UsbDescriptors;
+use super::device::UsbFunction;
+use super::device::VENDOR_ID;
+use crate::usb::host_backend::error::*;
+use crate::usb::xhci::xhci_transfer::TransferDirection;
+use crate::usb::xhci::xhci_transfer::XhciTransfer;
+use crate::usb::xhci::xhci_transfer::XhciTransferType;
+
+const KEYBOARD_PRODUCT_ID: u16 = 0x5f02;
+const TABLET_PRODUCT_ID: u16 = 0x5f03;
+
+const HID_CLASS: u8 = 0x03;
+const BOOT_INTERFACE_SUBCLASS: u8 = 0x01;
+const KEYBOARD_PROTOCOL: u8 = 0x01;
+
+// Class descriptor types, HID 1.11 section 7.1.
+const HID_DESCRIPTOR_TYPE: u8 = 0x21;
+const REPORT_DESCRIPTOR_TYPE: u8 = 0x22;
+
+// Class requests, HID 1.11 section 7.2.
+const GET_REPORT: u8 = 0x01;
+const GET_IDLE: u8 = 0x02;
+const GET_PROTOCOL: u8 = 0x03;
+const SET_REPORT: u8 = 0x09;
+const SET_IDLE: u8 = 0x0a;
+const SET_PROTOCOL: u8 = 0x0b;
+
+const REPORT_PROTOCOL: u8 = 1;
+
+/// Reports beyond this number are dropped, oldest first, while the guest doesn't read them.
+const MAX_QUEUED_REPORTS: usize = 64;
+
+/// Report descriptor of the boot keyboard, HID 1.11 appendix B.1.
+const KEYBOARD_REPORT_DESCRIPTOR: &[u8] = &[
+    0x05, 0x01, // Usage Page (Generic Desktop)
+    0x09, 0x06, // Usage (Keyboard)
+    0xa1, 0x01, // Collection (Application)
+    0x75, 0x01, //   Report Size (1)
+    0x95, 0x08, //   Report Count (8)
+    0x05, 0x07, //   Usage Page (Key Codes)
+    0x19, 0xe0, //   Usage Minimum (224)
+    0x29, 0xe7, //   Usage Maximum (231)
+    0x15, 0x00, //   Logical Minimum (0)
+    0x25, 0x01, //   Logical Maximum (1)
+    0x81, 0x02, //   Input (Data, Variable, Absolute): modifier keys
+    0x95, 0x01, //   Report Count (1)
+    0x75, 0x08, //   Report Size (8)
+    0x81, 0x01, //   Input (Constant): reserved byte
+    0x95, 0x05, //   Report Count (5)
+    0x75, 0x01, //   Report Size (1)
+    0x05, 0x08, //   Usage Page (LEDs)
+    0x19, 0x01, //   Usage Minimum (1)
+    0x29, 0x05, //   Usage Maximum (5)
+    0x91, 0x02, //   Output (Data, Variable, Absolute): LEDs
+    0x95, 0x01, //   Report Count (1)
+    0x75, 0x03, //   Report Size (3)
+    0x91, 0x01, //   Output (Constant): padding
+    0x95, 0x06, //   Report Count (6)
+    0x75, 0x08, //   Report Size (8)
+    0x15, 0x00, //   Logical Minimum (0)
+    0x26, 0xff, 0x00, //   Logical Maximum (255)
+    0x05, 0x07, //   Usage Page (Key Codes)
+    0x19, 0x00, //   Usage Minimum (0)
+    0x29, 0xff, //   Usage Maximum (255)
+    0x81, 0x00, //   Input (Data, Array): keys
+    0xc0, // End Collection
+];
+
+/// Report descriptor of the tablet: 3 buttons, absolute X and Y from 0 to 0x7fff, and a wheel.
+const TABLET_REPORT_DESCRIPTOR: &[u8] = &[
+    0x05, 0x01, // Usage Page (Generic Desktop)
+    0x09, 0x02, // Usage (Mouse)
+    0xa1, 0x01, // Collection (Application)
+    0x09, 0x01, //   Usage (Pointer)
+    0xa1, 0x00, //   Collection (Physical)
+    0x05, 0x09, //     Usage Page (Buttons)
+    0x19, 0x01, //     Usage Minimum (1)
+    0x29, 0x03, //     Usage Maximum (3)
+    0x15, 0x00, //     Logical Minimum (0)
+    0x25, 0x01, //     Logical Maximum (1)
+    0x95, 0x03, //     Report Count (3)
+    0x75, 0x01, //     Report Size (1)
+    0x81, 0x02, //     Input (Data, Variable, Absolute): buttons
+    0x95, 0x01, //     Report Count (1)
+    0x75, 0x05, //     Report Size (5)
+    0x81, 0x01, //     Input (Constant): padding
+    0x05, 0x01, //     Usage Page (Generic Desktop)
+    0x09, 0x30, //     Usage (X)
+    0x09, 0x31, //     Usage (Y)
+    0x15, 0x00, //     Logical Minimum (0)
+    0x26, 0xff, 0x7f, //     Logical Maximum (0x7fff)
+    0x35, 0x00, //     Physical Minimum (0)
+    0x46, 0xff, 0x7f, //     Physical Maximum (0x7fff)
+    0x75, 0x10, //     Report Size (16)
+    0x95, 0x02, //     Report Count (2)
+    0x81, 0x02, //     Input (Data, Variable, Absolute): X, Y
+    0x05, 0x01, //     Usage Page (Generic Desktop)
+    0x09, 0x38, //     Usage (Wheel)
+    0x15, 0x81, //     Logical Minimum (-127)
+    0x25, 0x7f, //     Logical Maximum (127)
+    0x35, 0x00, //     Physical Minimum (0)
+    0x45, 0x00, //     Physical Maximum (0)
+    0x75, 0x08, //     Report Size (8)
+    0x95, 0x01, //     Report Count (1)
+    0x81, 0x06, //     Input (Data, Variable, Relative): wheel
+    0xc0, //   End Collection
+    0xc0, // End Collection
+];
+
+/// Largest coordinate of the tablet.
+const TABLET_MAX_COORDINATE: u32 = 0x7fff;
+
+/// Linux key codes of the HID keyboard usages, from usage 0 to the keypad equal sign (0x67).
+#[rustfmt::skip]
+const USAGE_KEY_CODES: [u16; 0x68] = [
+    0, 0, 0, 0, 30, 48, 46, 32, 18, 33, 34, 35, 23, 36, 37, 38,
+    50, 49, 24, 25, 16, 19, 31, 20, 22, 47, 17, 45, 21, 44, 2, 3,
+    4, 5, 6, 7, 8, 9, 10, 11, 28, 1, 14, 15, 57, 12, 13, 26,
+    27, 43, 43, 39, 40, 41, 51, 52, 53, 58, 59, 60, 61, 62, 63, 64,
+    65, 66, 67, 68, 87, 88, 99, 70, 119, 110, 102, 104, 111, 107, 109, 106,
+    105, 108, 103, 69, 98, 55, 74, 78, 96, 79, 80, 81, 75, 76, 77, 71,
+    72, 73, 82, 83, 86, 127, 116, 117,
+];
+
+/// Linux key codes of the modifier keys, from usage 0xe0 to 0xe7.
+const MODIFIER_KEY_CODES: [u16; 8] = [29, 42, 56, 125, 97, 54, 100, 126];
+
+/// Linux key codes of the media keys and their usages.
+const MEDIA_KEYS: [(u16, u8); 3] = [(113, 0x7f), (115, 0x80), (114, 0x81)];
+
+/// Returns the HID usage of the key with the Linux key code `code`.
+fn key_usage(code: u16) -> Option<u8> {
+    if code == 0 {
+        return None;
+    }
+    if let Some(i) = MODIFIER_KEY_CODES.iter().position(|&c| c == code) {
+        return Some(0xe0 + i as u8);
+    }
+    if let Some(&(_, usage)) = MEDIA_KEYS.iter().find(|(c, _)| *c == code) {
+        return Some(usage);
+    }
+    USAGE_KEY_CODES
+        .iter()
+        .position(|&c| c == code)
+        .map(|usage| usage as u8)
+}
+
+/// Keyboard using the boot protocol report: the modifier keys, a reserved byte and up to 6 other
+/// keys held down.
+#[derive(Default)]
+struct Keyboard {
+    modifiers: u8,
+    keys: Vec<u8>,
+}
+
+impl Keyboard {
+    fn input(&mut self, event: &InputEvent) {
+        if event.type_ != EV_KEY {
+            return;
+        }
+        let usage = match key_usage(event.code) {
+            Some(usage) => usage,
+            None => return,
+        };
+        if usage >= 0xe0 {
+            let bit = 1 << (usage - 0xe0);
+            if event.value != 0 {
+                self.modifiers |= bit;
+            } else {
+                self.modifiers &= !bit;
+            }
+        } else if event.value == 0 {
+            self.keys.retain(|&k| k != usage);
+        } else if !self.keys.contains(&usage) {
+            // Auto-repeat events, with a value of 2, are ignored since the guest repeats keys
+            // itself.
+            self.keys.push(usage);
+        }
+    }
+
+    fn report(&self) -> Vec<u8> {
+        let mut report = vec![self.modifiers, 0];
+        if self.keys.len() > 6 {
+            // ErrorRollOver in every key slot when too many keys are held down.
+            report.extend_from_slice(&[0x01; 6]);
+        } else {
+            report.extend_from_slice(&self.keys);
+            report.resize(8, 0);
+        }
+        report
+    }
+}
+
+/// Absolute pointing device, whose input events have coordinates from 0 to `width` - 1 and
+/// `height` - 1.
+struct Tablet {
+    width: u32,
+    height: u32,
+    buttons: u8,
+    x: u16,
+    y: u16,
+    wheel: i8,
+}
+
+impl Tablet {
+    fn scale(value: i32, size: u32) -> u16 {
+        let max = size.saturating_sub(1).max(1);
+        let value = value.clamp(0, max as i32) as u32;
+        (value as u64 * TABLET_MAX_COORDINATE as u64 / max as u64) as u16
+    }
+
+    fn input(&mut self, event: &InputEvent) {
+        match (event.type_, event.code) {
+            (EV_ABS, ABS_X) => self.x = Self::scale(event.value, self.width),
+            (EV_ABS, ABS_Y) => self.y = Self::scale(event.value, self.height),
+            (EV_KEY, code) => {
+                let bit = match code {
+                    // Touches are clicks, as on the virtio tablet.
+                    BTN_LEFT | BTN_TOUCH => 1 << 0,
+                    BTN_RIGHT => 1 << 1,
+                    BTN_MIDDLE => 1 << 2,
+                    _ => return,
+                };
+                if event.value != 0 {
+                    self.buttons |= bit;
+                } else {
+                    self.buttons &= !bit;
+                }
+            }
+            (EV_REL, REL_WHEEL) => {
+                self.wheel = (self.wheel as i32 + event.value).clamp(-127, 127) as i8;
+            }
+            _ => {}
+        }
+    }
+
+    fn report(&self) -> Vec<u8> {
+        let mut report = vec![self.buttons];
+        report.extend_from_slice(&self.x.to_le_bytes());
+        report.extend_from_slice(&self.y.to_le_bytes());
+        report.push(self.wheel as u8);
+        report
+    }
+}
+
+enum HidKind {
+    Keyboard(Keyboard),
+    Tablet(Tablet),
+}
+
+impl HidKind {
+    fn report(&self) -> Vec<u8> {
+        match self {
+            HidKind::Keyboard(keyboard) => keyboard.report(),
+            HidKind::Tablet(tablet) => tablet.report(),
+        }
+    }
+}
+
+/// State shared by a `HidDevice` and its `HidInput`.
+struct HidState {
+    kind: HidKind,
+    // Reports not read by the guest yet.
+    reports: VecDeque<Vec<u8>>,
+    // Transfer of the interrupt endpoint waiting for the next report.
+    pending: Option<PendingTransfer>,
+    idle: u8,
+    protocol: u8,
+}
+
+impl HidState {
+    fn input(&mut self, events: &[InputEvent]) {
+        for event in events {
+            if event.type_ == EV_SYN && event.code == SYN_REPORT {
+                if self.reports.len() == MAX_QUEUED_REPORTS {
+                    self.reports.pop_front();
+                }
+                self.reports.push_back(self.kind.report());
+                if let HidKind::Tablet(tablet) = &mut self.kind {
+                    tablet.wheel = 0;
+                }
+                continue;
+            }
+            match &mut self.kind {
+                HidKind::Keyboard(keyboard) => keyboard.input(event),
+                HidKind::Tablet(tablet) => tablet.input(event),
+            }
+        }
+    }
+
+    // Sends the next report to the guest if it is waiting for one.
+    fn send_reports(&mut self) -> Result<()> {
+        while let Some(report) = self.reports.front() {
+            let pending = match self.pending.take() {
+                Some(pending) => pending,
+                None => break,
+            };
+            if pending.complete(report)? {
+                self.reports.pop_front();
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Handle to send input events to a `HidDevice`.
+#[derive(Clone)]
+pub struct HidInput {
+    state: Arc<Mutex<HidState>>,
+}
+
+impl HidInput {
+    /// Sends `events` to the guest, one report for each `SYN_REPORT` event.
+    pub fn inject(&self, events: &[InputEvent]) -> Result<()> {
+        let mut state = self.state.lock();
+        state.input(events);
+        state.send_reports()
+    }
+}
+
+/// The function of a HID device, with a single interrupt IN endpoint.
+pub struct HidDevice {
+    descriptors: UsbDescriptors,
+    report_descriptor: &'static [u8],
+    state: Arc<Mutex<HidState>>,
+}
+
+impl HidDevice {
+    fn new(
+        product_id: u16,
+        product: &'static [&'static str],
+        protocol: (u8, u8),
+        report_descriptor: &'static [u8],
+        report_len: u16,
+        kind: HidKind,
+    ) -> (HidDevice, HidInput) {
+        let (interface_subclass, interface_protocol) = protocol;
+        let device = DeviceDescriptor {
+            bcdUSB: 0x0200,
+            bMaxPacketSize0: 64,
+            idVendor: VENDOR_ID,
+            idProduct: product_id,
+            bcdDevice: 0x0100,
+            iManufacturer: 1,
+            iProduct: 2,
+            bNumConfigurations: 1,
+            ..Default::default()
+        };
+        let interface = InterfaceDescriptor {
+            bNumEndpoints: 1,
+            bInterfaceClass: HID_CLASS,
+            bInterfaceSubClass: interface_subclass,
+            bInterfaceProtocol: interface_protocol,
+            ..Default::default()
+        };
+        let endpoint = EndpointDescriptor {
+            bEndpointAddress: 0x81,
+            // Interrupt.
+            bmAttributes: 0x03,
+            wMaxPacketSize: report_len,
+            // Milliseconds.
+            bInterval: 10,
+        };
+        let state = Arc::new(Mutex::new(HidState {
+            kind,
+            reports: VecDeque::new(),
+            pending: None,
+            idle: 0,
+            protocol: REPORT_PROTOCOL,
+        }));
+        let hid = HidDevice {
+            descriptors: UsbDescriptors::new(
+                device,
+                interface,
+                &hid_descriptor(report_descriptor),
+                &[endpoint],
+                product,
+            ),
+            report_descriptor,
+            state: state.clone(),
+        };
+        (hid, HidInput { state })
+    }
+
+    /// Creates a keyboard using the boot protocol, to which the keys of the input events are sent
+    /// with a US layout.
+    pub fn keyboard() -> (HidDevice, HidInput) {
+        HidDevice::new(
+            KEYBOARD_PRODUCT_ID,
+            &["crosvm", "USB keyboard"],
+            (BOOT_INTERFACE_SUBCLASS, KEYBOARD_PROTOCOL),
+            KEYBOARD_REPORT_DESCRIPTOR,
+            8,
+            HidKind::Keyboard(Keyboard::default()),
+        )
+    }
+
+    /// Creates a tablet, whose input events have coordinates from 0 to `width` - 1 and
+    /// `height` - 1.
+    pub fn tablet(width: u32, height: u32) -> (HidDevice, HidInput) {
+        HidDevice::new(
+            TABLET_PRODUCT_ID,
+            &["crosvm", "USB tablet"],
+            (0, 0),
+            TABLET_REPORT_DESCRIPTOR,
+            6,
+            HidKind::Tablet(Tablet {
+                width,
+                height,
+                buttons: 0,
+                x: 0,
+                y: 0,
+                wheel: 0,
+            }),
+        )
+    }
+}
+
+/// Returns the HID descriptor listing `report_descriptor`, HID 1.11 section 6.2.1.
+fn hid_descriptor(report_descriptor: &[u8]) -> Vec<u8> {
+    let mut descriptor = vec![
+        9,
+        HID_DESCRIPTOR_TYPE,
+        // bcdHID 1.11.
+        0x11,
+        0x01,
+        // Not localized.
+        0,
+        // One report descriptor.
+        1,
+        REPORT_DESCRIPTOR_TYPE,
+    ];
+    descriptor.extend_from_slice(&(report_descriptor.len() as u16).to_le_bytes());
+    descriptor
+}
+
+impl UsbFunction for HidDevice {
+    fn descriptors(&self) -> &UsbDescriptors {
+        &self.descriptors
+    }
+
+    fn control_request(&mut self, setup: &UsbRequestSetup, _data: &[u8]) -> Option<Vec<u8>> {
+        if setup.get_recipient() != ControlRequestRecipient::Interface {
+            return None;
+        }
+        if setup.get_standard_request() == Some(StandardControlRequest::GetDescriptor) {
+            return match (setup.value >> 8) as u8 {
+                HID_DESCRIPTOR_TYPE => Some(hid_descriptor(self.report_descriptor)),
+                REPORT_DESCRIPTOR_TYPE => Some(self.report_descriptor.to_vec()),
+                _ => None,
+            };
+        }
+        if setup.get_type() != ControlRequestType::Class {
+            return None;
+        }
+        let mut state = self.state.lock();
+        match setup.request {
+            GET_REPORT => Some(state.kind.report()),
+            GET_IDLE => Some(vec![state.idle]),
+            GET_PROTOCOL => Some(vec![state.protocol]),
+            // The keyboard LEDs aren't shown anywhere.
+            SET_REPORT => Some(Vec::new()),
+            SET_IDLE => {
+                // Reports are only sent when the input changes, whatever the idle rate.
+                state.idle = (setup.value >> 8) as u8;
+                Some(Vec::new())
+            }
+            SET_PROTOCOL => {
+                // The boot and report protocols use the same reports.
+                state.protocol = setup.value as u8;
+                Some(Vec::new())
+            }
+            _ => None,
+        }
+    }
+
+    fn handle_transfer(&mut self, transfer: XhciTransfer) -> Result<()> {
+        let buffer = match transfer
+            .get_transfer_type()
+            .map_err(Error::GetXhciTransferType)?
+        {
+            XhciTransferType::Normal(buffer)
+                if transfer.get_transfer_dir() == TransferDirection::In =>
+            {
+                buffer
+            }
+            _ => {
+                error!("unhandled xhci transfer type by usb hid device");
+                return transfer
+                    .on_transfer_complete(&TransferStatus::Error, 0)
+                    .map_err(Error::TransferComplete);
+            }
+        };
+        let mut state = self.state.lock();
+        if let Some(pending) = PendingTransfer::new(transfer, buffer)? {
+            state.pending = Some(pending);
+            state.send_reports()?;
+        }
+        Ok(())
+    }
+
+    fn reset(&mut self) {
+        let mut state = self.state.lock();
+        state.reports.clear();
+        state.pending = None;
+        state.idle = 0;
+        state.protocol = REPORT_PROTOCOL;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use vm_control::input::key_chord_events;
+    use vm_control::input::text_events;
+
+    use super::*;
+
+    fn reports(hid: &HidInput) -> Vec<Vec<u8>> {
+        hid.state.lock().reports.drain(..).collect()
+    }
+
+    #[test]
+    fn keyboard_reports() {
+        let (_, input) = HidDevice::keyboard();
+        input.inject(&text_events("A").unwrap()).unwrap();
+        assert_eq!(
+            reports(&input),
+            vec![
+                // Left shift, then shift and a.
+                vec![0x02, 0, 0, 0, 0, 0, 0, 0],
+                vec![0x02, 0, 0x04, 0, 0, 0, 0, 0],
+                vec![0x02, 0, 0, 0, 0, 0, 0, 0],
+                vec![0, 0, 0, 0, 0, 0, 0, 0],
+            ]
+        );
+
+        // Enter, then more keys than a boot keyboard report holds.
+        input.inject(&key_chord_events(&[28])).unwrap();
+        assert_eq!(reports(&input)[0], vec![0, 0, 0x28, 0, 0, 0, 0, 0]);
+        input
+            .inject(&key_chord_events(&[2, 3, 4, 5, 6, 7, 8]))
+            .unwrap();
+        assert_eq!(reports(&input)[6], vec![0, 0, 1, 1, 1, 1, 1, 1]);
+    }
+
+    #[test]
+    fn tablet_reports() {
+        let (_, input) = HidDevice::tablet(1025, 769);
+        input
+            .inject(&[
+                InputEvent::absolute(ABS_X, 512),
+                InputEvent::absolute(ABS_Y, 2000),
+                InputEvent::key(BTN_TOUCH, true),
+                InputEvent::relative(REL_WHEEL, -1),
+                InputEvent::syn(),
+                InputEvent::key(BTN_TOUCH, false),
+                InputEvent::syn(),
+            ])
+            .unwrap();
+        assert_eq!(
+            reports(&input),
+            vec![
+                vec![1, 0xff, 0x3f, 0xff, 0x7f, 0xff],
+                vec![0, 0xff, 0x3f, 0xff, 0x7f, 0],
+            ]
+        );
+    }
+}
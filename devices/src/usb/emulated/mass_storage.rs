@@ -0,0 +1,720 @@
+// Copyright 2023 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! USB mass storage device using the bulk-only transport, which exposes a disk image to the guest
+//! as a SCSI direct-access block device.
+
+use std::cmp::min;
+use std::io;
+
+use base::error;
+use data_model::VolatileSlice;
+use disk::DiskFile;
+use usb_util::ControlRequestRecipient;
+use usb_util::ControlRequestType;
+use usb_util::DeviceDescriptor;
+use usb_util::EndpointDescriptor;
+use usb_util::InterfaceDescriptor;
+use usb_util::TransferStatus;
+use usb_util::UsbRequestSetup;
+
+use super::device::PendingTransfer;
+use super::device::UsbDescriptors;
+use super::device::UsbFunction;
+use super::device::VENDOR_ID;
+use crate::usb::host_backend::error::*;
+use crate::usb::xhci::xhci_transfer::TransferDirection;
+use crate::usb::xhci::xhci_transfer::XhciTransfer;
+use crate::usb::xhci::xhci_transfer::XhciTransferType;
+
+const PRODUCT_ID: u16 = 0x5f01;
+
+const MASS_STORAGE_CLASS: u8 = 0x08;
+const SCSI_SUBCLASS: u8 = 0x06;
+const BULK_ONLY_PROTOCOL: u8 = 0x50;
+
+// Class requests, bulk-only transport 1.0 section 3.
+const BULK_ONLY_RESET: u8 = 0xff;
+const GET_MAX_LUN: u8 = 0xfe;
+
+// Command and status wrappers, bulk-only transport 1.0 section 5.
+const CBW_SIGNATURE: u32 = 0x43425355;
+const CBW_LEN: usize = 31;
+const CBW_DATA_IN: u8 = 0x80;
+const CSW_SIGNATURE: u32 = 0x53425355;
+const CSW_PASSED: u8 = 0;
+const CSW_FAILED: u8 = 1;
+const CSW_PHASE_ERROR: u8 = 2;
+
+// SCSI operation codes, SPC-4 and SBC-3.
+const TEST_UNIT_READY: u8 = 0x00;
+const REQUEST_SENSE: u8 = 0x03;
+const INQUIRY: u8 = 0x12;
+const MODE_SENSE_6: u8 = 0x1a;
+const START_STOP_UNIT: u8 = 0x1b;
+const PREVENT_ALLOW_MEDIUM_REMOVAL: u8 = 0x1e;
+const READ_FORMAT_CAPACITIES: u8 = 0x23;
+const READ_CAPACITY_10: u8 = 0x25;
+const READ_10: u8 = 0x28;
+const WRITE_10: u8 = 0x2a;
+const VERIFY_10: u8 = 0x2f;
+const SYNCHRONIZE_CACHE_10: u8 = 0x35;
+const MODE_SENSE_10: u8 = 0x5a;
+const READ_16: u8 = 0x88;
+const WRITE_16: u8 = 0x8a;
+const SERVICE_ACTION_IN_16: u8 = 0x9e;
+const READ_CAPACITY_16: u8 = 0x10;
+const READ_12: u8 = 0xa8;
+const WRITE_12: u8 = 0xaa;
+
+// Sense keys and additional sense codes.
+const NO_SENSE: u8 = 0x00;
+const MEDIUM_ERROR: u8 = 0x03;
+const ILLEGAL_REQUEST: u8 = 0x05;
+const DATA_PROTECT: u8 = 0x07;
+const WRITE_ERROR: u8 = 0x0c;
+const UNRECOVERED_READ_ERROR: u8 = 0x11;
+const INVALID_COMMAND_OPERATION_CODE: u8 = 0x20;
+const LBA_OUT_OF_RANGE: u8 = 0x21;
+const INVALID_FIELD_IN_CDB: u8 = 0x24;
+const LOGICAL_UNIT_NOT_SUPPORTED: u8 = 0x25;
+const WRITE_PROTECTED: u8 = 0x27;
+
+const BLOCK_SIZE: u64 = 512;
+
+/// Data of the data phase sent to the host.
+enum DataIn {
+    Buffer(Vec<u8>),
+    Disk { offset: u64, remaining: usize },
+}
+
+enum Phase {
+    /// Waiting for a command block wrapper.
+    Command,
+    /// Sending data to the host.
+    DataIn(DataIn),
+    /// Receiving `remaining` bytes from the host, the first `disk_remaining` of which are written
+    /// to the disk at `offset`. The rest is discarded.
+    DataOut {
+        offset: u64,
+        disk_remaining: usize,
+        remaining: usize,
+    },
+    /// Waiting to send the command status wrapper.
+    Status,
+}
+
+/// What a SCSI command transfers.
+enum Response {
+    None,
+    Data(Vec<u8>),
+    Read { offset: u64, len: usize },
+    Write { offset: u64, len: usize },
+}
+
+/// Error of a SCSI command, as reported by REQUEST SENSE.
+struct Sense {
+    key: u8,
+    asc: u8,
+}
+
+impl Sense {
+    fn illegal_request(asc: u8) -> Sense {
+        Sense {
+            key: ILLEGAL_REQUEST,
+            asc,
+        }
+    }
+}
+
+/// Bulk-only transport state machine and SCSI target, independent from the xHCI transfers.
+struct BulkOnly {
+    disk: Box<dyn DiskFile>,
+    read_only: bool,
+    blocks: u64,
+    phase: Phase,
+    // Tag of the current command, echoed in its status wrapper.
+    tag: u32,
+    // Length of the data phase the host expects, and number of bytes processed so far.
+    expected: usize,
+    transferred: usize,
+    status: u8,
+    sense: Sense,
+}
+
+fn be16(bytes: &[u8]) -> usize {
+    u16::from_be_bytes([bytes[0], bytes[1]]) as usize
+}
+
+fn be32(bytes: &[u8]) -> u64 {
+    u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as u64
+}
+
+fn be64(bytes: &[u8]) -> u64 {
+    (be32(bytes) << 32) | be32(&bytes[4..])
+}
+
+fn truncated(mut data: Vec<u8>, allocation_len: usize) -> Response {
+    data.truncate(allocation_len);
+    Response::Data(data)
+}
+
+impl BulkOnly {
+    fn new(disk: Box<dyn DiskFile>, read_only: bool) -> io::Result<BulkOnly> {
+        let blocks = disk.get_len()? / BLOCK_SIZE;
+        Ok(BulkOnly {
+            disk,
+            read_only,
+            blocks,
+            phase: Phase::Command,
+            tag: 0,
+            expected: 0,
+            transferred: 0,
+            status: CSW_PASSED,
+            sense: Sense {
+                key: NO_SENSE,
+                asc: 0,
+            },
+        })
+    }
+
+    fn reset(&mut self) {
+        self.phase = Phase::Command;
+    }
+
+    /// Returns true if the next IN transfer can be completed right away.
+    fn in_ready(&self) -> bool {
+        matches!(self.phase, Phase::DataIn(_) | Phase::Status)
+    }
+
+    /// Handles the data of an OUT transfer, either a command or the data of a write.
+    fn out_transfer(&mut self, data: &[u8]) {
+        match self.phase {
+            Phase::Command => self.command_block(data),
+            Phase::DataOut {
+                offset,
+                disk_remaining,
+                remaining,
+            } => {
+                let len = min(data.len(), remaining);
+                let disk_len = min(len, disk_remaining);
+                let mut disk_data = data[..disk_len].to_vec();
+                if let Err(e) = self
+                    .disk
+                    .write_all_at_volatile(VolatileSlice::new(&mut disk_data), offset)
+                {
+                    error!("failed to write the usb mass storage disk: {}", e);
+                    self.fail(MEDIUM_ERROR, WRITE_ERROR);
+                    // Discard the rest of the data.
+                    self.phase = Phase::DataOut {
+                        offset,
+                        disk_remaining: 0,
+                        remaining: remaining - len,
+                    };
+                } else {
+                    self.transferred += disk_len;
+                    self.phase = Phase::DataOut {
+                        offset: offset + disk_len as u64,
+                        disk_remaining: disk_remaining - disk_len,
+                        remaining: remaining - len,
+                    };
+                }
+                if remaining == len {
+                    self.phase = Phase::Status;
+                }
+            }
+            _ => error!("unexpected usb mass storage OUT transfer"),
+        }
+    }
+
+    /// Returns the data of an IN transfer of up to `max_len` bytes, either data of the current
+    /// command or its status.
+    fn in_transfer(&mut self, max_len: usize) -> Vec<u8> {
+        match &mut self.phase {
+            Phase::DataIn(DataIn::Buffer(buffer)) => {
+                let len = min(buffer.len(), max_len);
+                let data: Vec<u8> = buffer.drain(..len).collect();
+                self.transferred += len;
+                // A short transfer ends the data phase.
+                if buffer.is_empty() {
+                    self.phase = Phase::Status;
+                }
+                data
+            }
+            Phase::DataIn(DataIn::Disk { offset, remaining }) => {
+                let len = min(*remaining, max_len);
+                let mut data = vec![0; len];
+                let offset = *offset;
+                let remaining = *remaining - len;
+                match self
+                    .disk
+                    .read_exact_at_volatile(VolatileSlice::new(&mut data), offset)
+                {
+                    Ok(()) => {
+                        self.transferred += len;
+                        self.phase = if remaining == 0 {
+                            Phase::Status
+                        } else {
+                            Phase::DataIn(DataIn::Disk {
+                                offset: offset + len as u64,
+                                remaining,
+                            })
+                        };
+                        data
+                    }
+                    Err(e) => {
+                        error!("failed to read the usb mass storage disk: {}", e);
+                        self.fail(MEDIUM_ERROR, UNRECOVERED_READ_ERROR);
+                        self.phase = Phase::Status;
+                        Vec::new()
+                    }
+                }
+            }
+            Phase::Status => {
+                self.phase = Phase::Command;
+                let residue = self.expected.saturating_sub(self.transferred) as u32;
+                let mut csw = Vec::with_capacity(13);
+                csw.extend_from_slice(&CSW_SIGNATURE.to_le_bytes());
+                csw.extend_from_slice(&self.tag.to_le_bytes());
+                csw.extend_from_slice(&residue.to_le_bytes());
+                csw.push(self.status);
+                csw
+            }
+            _ => {
+                error!("unexpected usb mass storage IN transfer");
+                Vec::new()
+            }
+        }
+    }
+
+    fn fail(&mut self, key: u8, asc: u8) {
+        self.status = CSW_FAILED;
+        self.sense = Sense { key, asc };
+    }
+
+    fn command_block(&mut self, cbw: &[u8]) {
+        if cbw.len() != CBW_LEN
+            || u32::from_le_bytes([cbw[0], cbw[1], cbw[2], cbw[3]]) != CBW_SIGNATURE
+        {
+            // The host resets the device when the status of the command doesn't come.
+            error!("invalid usb mass storage command block wrapper");
+            return;
+        }
+        self.tag = u32::from_le_bytes([cbw[4], cbw[5], cbw[6], cbw[7]]);
+        self.expected = u32::from_le_bytes([cbw[8], cbw[9], cbw[10], cbw[11]]) as usize;
+        self.transferred = 0;
+        self.status = CSW_PASSED;
+        let data_in = cbw[12] & CBW_DATA_IN != 0;
+        let lun = cbw[13] & 0x0f;
+        let cdb_len = min(cbw[14] as usize & 0x1f, 16);
+        let mut cdb = [0u8; 16];
+        cdb[..cdb_len].copy_from_slice(&cbw[15..15 + cdb_len]);
+
+        let response = if lun != 0 {
+            Err(Sense::illegal_request(LOGICAL_UNIT_NOT_SUPPORTED))
+        } else {
+            self.scsi_command(&cdb)
+        };
+        let response = response.unwrap_or_else(|sense| {
+            self.fail(sense.key, sense.asc);
+            Response::None
+        });
+
+        self.phase = match response {
+            Response::Data(mut data) if data_in => {
+                data.truncate(self.expected);
+                Phase::DataIn(DataIn::Buffer(data))
+            }
+            Response::Read { offset, len } if data_in => Phase::DataIn(DataIn::Disk {
+                offset,
+                remaining: min(len, self.expected),
+            }),
+            Response::Write { offset, len } if !data_in => Phase::DataOut {
+                offset,
+                disk_remaining: min(len, self.expected),
+                remaining: self.expected,
+            },
+            Response::None => Phase::Status,
+            _ => {
+                // The direction of the data doesn't match the command.
+                self.status = CSW_PHASE_ERROR;
+                Phase::Status
+            }
+        };
+        if self.expected > 0 {
+            if let Phase::Status = self.phase {
+                // Pad the data phase expected by the host with a short packet, or discard the
+                // data it sends.
+                self.phase = if data_in {
+                    Phase::DataIn(DataIn::Buffer(Vec::new()))
+                } else {
+                    Phase::DataOut {
+                        offset: 0,
+                        disk_remaining: 0,
+                        remaining: self.expected,
+                    }
+                };
+            }
+        }
+    }
+
+    fn block_range(&self, lba: u64, blocks: u64) -> std::result::Result<(u64, usize), Sense> {
+        match lba.checked_add(blocks) {
+            Some(end) if end <= self.blocks => {
+                Ok((lba * BLOCK_SIZE, (blocks * BLOCK_SIZE) as usize))
+            }
+            _ => Err(Sense::illegal_request(LBA_OUT_OF_RANGE)),
+        }
+    }
+
+    fn read(&self, lba: u64, blocks: u64) -> std::result::Result<Response, Sense> {
+        let (offset, len) = self.block_range(lba, blocks)?;
+        Ok(Response::Read { offset, len })
+    }
+
+    fn write(&self, lba: u64, blocks: u64) -> std::result::Result<Response, Sense> {
+        if self.read_only {
+            return Err(Sense {
+                key: DATA_PROTECT,
+                asc: WRITE_PROTECTED,
+            });
+        }
+        let (offset, len) = self.block_range(lba, blocks)?;
+        Ok(Response::Write { offset, len })
+    }
+
+    fn scsi_command(&mut self, cdb: &[u8; 16]) -> std::result::Result<Response, Sense> {
+        if cdb[0] != REQUEST_SENSE {
+            self.sense = Sense {
+                key: NO_SENSE,
+                asc: 0,
+            };
+        }
+        let last_lba = self.blocks.saturating_sub(1);
+        let write_protect = if self.read_only { 0x80 } else { 0 };
+        match cdb[0] {
+            TEST_UNIT_READY | START_STOP_UNIT | PREVENT_ALLOW_MEDIUM_REMOVAL | VERIFY_10 => {
+                Ok(Response::None)
+            }
+            SYNCHRONIZE_CACHE_10 => {
+                if let Err(e) = self.disk.fsync() {
+                    error!("failed to sync the usb mass storage disk: {}", e);
+                    return Err(Sense {
+                        key: MEDIUM_ERROR,
+                        asc: WRITE_ERROR,
+                    });
+                }
+                Ok(Response::None)
+            }
+            REQUEST_SENSE => {
+                let mut sense = vec![0; 18];
+                // Current error, fixed format.
+                sense[0] = 0x70;
+                sense[2] = self.sense.key;
+                sense[7] = 10;
+                sense[12] = self.sense.asc;
+                Ok(truncated(sense, cdb[4] as usize))
+            }
+            INQUIRY => {
+                if cdb[1] & 0x01 != 0 {
+                    // Vital product data pages aren't supported.
+                    return Err(Sense::illegal_request(INVALID_FIELD_IN_CDB));
+                }
+                // Direct access block device, removable, SPC-3.
+                let mut inquiry = vec![0x00, 0x80, 0x05, 0x02, 31, 0, 0, 0];
+                inquiry.extend_from_slice(b"crosvm  ");
+                inquiry.extend_from_slice(b"Mass Storage    ");
+                inquiry.extend_from_slice(b"1.0 ");
+                Ok(truncated(inquiry, be16(&cdb[3..])))
+            }
+            MODE_SENSE_6 => Ok(truncated(
+                // No mode pages nor block descriptors.
+                vec![3, 0, write_protect, 0],
+                cdb[4] as usize,
+            )),
+            MODE_SENSE_10 => Ok(truncated(
+                vec![0, 6, 0, write_protect, 0, 0, 0, 0],
+                be16(&cdb[7..]),
+            )),
+            READ_FORMAT_CAPACITIES => {
+                let mut capacities = vec![0, 0, 0, 8];
+                capacities
+                    .extend_from_slice(&(min(self.blocks, u32::MAX as u64) as u32).to_be_bytes());
+                // Formatted media, followed by the block length.
+                capacities.push(0x02);
+                capacities.extend_from_slice(&(BLOCK_SIZE as u32).to_be_bytes()[1..]);
+                Ok(truncated(capacities, be16(&cdb[7..])))
+            }
+            READ_CAPACITY_10 => {
+                // The host uses READ CAPACITY (16) when the last block doesn't fit.
+                let mut capacity = min(last_lba, u32::MAX as u64).to_be_bytes()[4..].to_vec();
+                capacity.extend_from_slice(&(BLOCK_SIZE as u32).to_be_bytes());
+                Ok(Response::Data(capacity))
+            }
+            SERVICE_ACTION_IN_16 if cdb[1] & 0x1f == READ_CAPACITY_16 => {
+                let mut capacity = last_lba.to_be_bytes().to_vec();
+                capacity.extend_from_slice(&(BLOCK_SIZE as u32).to_be_bytes());
+                capacity.resize(32, 0);
+                Ok(truncated(capacity, be32(&cdb[10..]) as usize))
+            }
+            SERVICE_ACTION_IN_16 => Err(Sense::illegal_request(INVALID_FIELD_IN_CDB)),
+            READ_10 => self.read(be32(&cdb[2..]), be16(&cdb[7..]) as u64),
+            READ_12 => self.read(be32(&cdb[2..]), be32(&cdb[6..])),
+            READ_16 => self.read(be64(&cdb[2..]), be32(&cdb[10..])),
+            WRITE_10 => self.write(be32(&cdb[2..]), be16(&cdb[7..]) as u64),
+            WRITE_12 => self.write(be32(&cdb[2..]), be32(&cdb[6..])),
+            WRITE_16 => self.write(be64(&cdb[2..]), be32(&cdb[10..])),
+            opcode => {
+                usb_debug!("unsupported scsi command {:#x}", opcode);
+                Err(Sense::illegal_request(INVALID_COMMAND_OPERATION_CODE))
+            }
+        }
+    }
+}
+
+/// The function of a mass storage device, with a bulk IN and a bulk OUT endpoint.
+pub struct MassStorage {
+    descriptors: UsbDescriptors,
+    bulk_only: BulkOnly,
+    // IN transfer that came before the device had anything to send.
+    pending: Option<PendingTransfer>,
+}
+
+impl MassStorage {
+    /// Creates a mass storage device backed by `disk`, which the guest can't write if `read_only`
+    /// is set.
+    pub fn new(disk: Box<dyn DiskFile>, read_only: bool) -> io::Result<MassStorage> {
+        let device = DeviceDescriptor {
+            bcdUSB: 0x0200,
+            bMaxPacketSize0: 64,
+            idVendor: VENDOR_ID,
+            idProduct: PRODUCT_ID,
+            bcdDevice: 0x0100,
+            iManufacturer: 1,
+            iProduct: 2,
+            // The bulk-only transport requires a serial number.
+            iSerialNumber: 3,
+            bNumConfigurations: 1,
+            ..Default::default()
+        };
+        let interface = InterfaceDescriptor {
+            bNumEndpoints: 2,
+            bInterfaceClass: MASS_STORAGE_CLASS,
+            bInterfaceSubClass: SCSI_SUBCLASS,
+            bInterfaceProtocol: BULK_ONLY_PROTOCOL,
+            ..Default::default()
+        };
+        let endpoints = [
+            EndpointDescriptor {
+                bEndpointAddress: 0x81,
+                // Bulk.
+                bmAttributes: 0x02,
+                wMaxPacketSize: 64,
+                bInterval: 0,
+            },
+            EndpointDescriptor {
+                bEndpointAddress: 0x02,
+                bmAttributes: 0x02,
+                wMaxPacketSize: 64,
+                bInterval: 0,
+            },
+        ];
+        Ok(MassStorage {
+            descriptors: UsbDescriptors::new(
+                device,
+                interface,
+                &[],
+                &endpoints,
+                &["crosvm", "USB mass storage", "000000000001"],
+            ),
+            bulk_only: BulkOnly::new(disk, read_only)?,
+            pending: None,
+        })
+    }
+
+    // Completes the pending IN transfer if the device has something to send.
+    fn send_pending(&mut self) -> Result<()> {
+        if !self.bulk_only.in_ready() {
+            return Ok(());
+        }
+        if let Some(pending) = self.pending.take() {
+            let data = self.bulk_only.in_transfer(pending.len()?);
+            if !pending.complete(&data)? {
+                error!("usb mass storage transfer cancelled while sending it");
+            }
+        }
+        Ok(())
+    }
+}
+
+impl UsbFunction for MassStorage {
+    fn descriptors(&self) -> &UsbDescriptors {
+        &self.descriptors
+    }
+
+    fn control_request(&mut self, setup: &UsbRequestSetup, _data: &[u8]) -> Option<Vec<u8>> {
+        if setup.get_type() != ControlRequestType::Class
+            || setup.get_recipient() != ControlRequestRecipient::Interface
+        {
+            return None;
+        }
+        match setup.request {
+            BULK_ONLY_RESET => {
+                self.bulk_only.reset();
+                self.pending = None;
+                Some(Vec::new())
+            }
+            // A single logical unit.
+            GET_MAX_LUN => Some(vec![0]),
+            _ => None,
+        }
+    }
+
+    fn handle_transfer(&mut self, transfer: XhciTransfer) -> Result<()> {
+        let buffer = match transfer
+            .get_transfer_type()
+            .map_err(Error::GetXhciTransferType)?
+        {
+            XhciTransferType::Normal(buffer) => buffer,
+            _ => {
+                error!("unhandled xhci transfer type by usb mass storage device");
+                return transfer
+                    .on_transfer_complete(&TransferStatus::Error, 0)
+                    .map_err(Error::TransferComplete);
+            }
+        };
+        match transfer.get_transfer_dir() {
+            TransferDirection::Out => {
+                let mut data = vec![0; buffer.len().map_err(Error::BufferLen)?];
+                buffer.read(&mut data).map_err(Error::ReadBuffer)?;
+                self.bulk_only.out_transfer(&data);
+                transfer
+                    .on_transfer_complete(&TransferStatus::Completed, data.len() as u32)
+                    .map_err(Error::TransferComplete)?;
+            }
+            _ => {
+                if let Some(pending) = PendingTransfer::new(transfer, buffer)? {
+                    self.pending = Some(pending);
+                }
+            }
+        }
+        self.send_pending()
+    }
+
+    fn reset(&mut self) {
+        self.bulk_only.reset();
+        self.pending = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use base::FileReadWriteAtVolatile;
+    use tempfile::tempfile;
+
+    use super::*;
+
+    const DISK_BLOCKS: u64 = 16;
+
+    fn bulk_only(read_only: bool) -> BulkOnly {
+        let mut disk = Box::new(tempfile().unwrap());
+        let mut data: Vec<u8> = (0..DISK_BLOCKS * BLOCK_SIZE).map(|i| i as u8).collect();
+        disk.write_all_at_volatile(VolatileSlice::new(&mut data), 0)
+            .unwrap();
+        BulkOnly::new(disk, read_only).unwrap()
+    }
+
+    fn cbw(tag: u32, expected: u32, data_in: bool, cdb: &[u8]) -> Vec<u8> {
+        let mut cbw = Vec::new();
+        cbw.extend_from_slice(&CBW_SIGNATURE.to_le_bytes());
+        cbw.extend_from_slice(&tag.to_le_bytes());
+        cbw.extend_from_slice(&expected.to_le_bytes());
+        cbw.push(if data_in { CBW_DATA_IN } else { 0 });
+        cbw.push(0);
+        cbw.push(cdb.len() as u8);
+        cbw.extend_from_slice(cdb);
+        cbw.resize(CBW_LEN, 0);
+        cbw
+    }
+
+    fn assert_csw(bulk_only: &mut BulkOnly, tag: u32, residue: u32, status: u8) {
+        assert!(bulk_only.in_ready());
+        let csw = bulk_only.in_transfer(13);
+        assert_eq!(&csw[..4], &CSW_SIGNATURE.to_le_bytes());
+        assert_eq!(&csw[4..8], &tag.to_le_bytes());
+        assert_eq!(&csw[8..12], &residue.to_le_bytes());
+        assert_eq!(csw[12], status);
+        assert!(!bulk_only.in_ready());
+    }
+
+    #[test]
+    fn read_capacity() {
+        let mut bulk_only = bulk_only(false);
+        bulk_only.out_transfer(&cbw(
+            1,
+            8,
+            true,
+            &[READ_CAPACITY_10, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+        ));
+        assert_eq!(
+            bulk_only.in_transfer(8),
+            vec![0, 0, 0, DISK_BLOCKS as u8 - 1, 0, 0, 2, 0]
+        );
+        assert_csw(&mut bulk_only, 1, 0, CSW_PASSED);
+    }
+
+    #[test]
+    fn read_write() {
+        let mut bulk_only = bulk_only(false);
+
+        // Read blocks 2 and 3 in two transfers.
+        bulk_only.out_transfer(&cbw(1, 1024, true, &[READ_10, 0, 0, 0, 0, 2, 0, 0, 2, 0]));
+        let mut data = bulk_only.in_transfer(512);
+        data.extend(bulk_only.in_transfer(512));
+        let expected: Vec<u8> = (1024..2048).map(|i| i as u8).collect();
+        assert_eq!(data, expected);
+        assert_csw(&mut bulk_only, 1, 0, CSW_PASSED);
+
+        // Write block 15 and read it back.
+        bulk_only.out_transfer(&cbw(2, 512, false, &[WRITE_10, 0, 0, 0, 0, 15, 0, 0, 1, 0]));
+        assert!(!bulk_only.in_ready());
+        bulk_only.out_transfer(&[0xaa; 512]);
+        assert_csw(&mut bulk_only, 2, 0, CSW_PASSED);
+        bulk_only.out_transfer(&cbw(3, 512, true, &[READ_10, 0, 0, 0, 0, 15, 0, 0, 1, 0]));
+        assert_eq!(bulk_only.in_transfer(512), vec![0xaa; 512]);
+        assert_csw(&mut bulk_only, 3, 0, CSW_PASSED);
+
+        // Flush the write to the disk image.
+        bulk_only.out_transfer(&cbw(
+            4,
+            0,
+            false,
+            &[SYNCHRONIZE_CACHE_10, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+        ));
+        assert_csw(&mut bulk_only, 4, 0, CSW_PASSED);
+    }
+
+    #[test]
+    fn errors() {
+        let mut bulk_only = bulk_only(true);
+
+        // Writes to a read-only disk fail with their data discarded.
+        bulk_only.out_transfer(&cbw(1, 512, false, &[WRITE_10, 0, 0, 0, 0, 0, 0, 0, 1, 0]));
+        bulk_only.out_transfer(&[0; 512]);
+        assert_csw(&mut bulk_only, 1, 512, CSW_FAILED);
+        bulk_only.out_transfer(&cbw(2, 18, true, &[REQUEST_SENSE, 0, 0, 0, 18, 0]));
+        let sense = bulk_only.in_transfer(18);
+        assert_eq!((sense[2], sense[12]), (DATA_PROTECT, WRITE_PROTECTED));
+        assert_csw(&mut bulk_only, 2, 0, CSW_PASSED);
+
+        // Reads past the end of the disk fail with an empty data phase.
+        bulk_only.out_transfer(&cbw(3, 1024, true, &[READ_10, 0, 0, 0, 0, 15, 0, 0, 2, 0]));
+        assert!(bulk_only.in_transfer(1024).is_empty());
+        assert_csw(&mut bulk_only, 3, 1024, CSW_FAILED);
+        bulk_only.out_transfer(&cbw(4, 18, true, &[REQUEST_SENSE, 0, 0, 0, 18, 0]));
+        let sense = bulk_only.in_transfer(18);
+        assert_eq!((sense[2], sense[12]), (ILLEGAL_REQUEST, LBA_OUT_OF_RANGE));
+        assert_csw(&mut bulk_only, 4, 0, CSW_PASSED);
+    }
+}
@@ -0,0 +1,16 @@
+// Copyright 2023 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! USB devices emulated by crosvm, which are plugged in the xHCI controller like the host devices
+//! passed through to the guest.
+
+mod device;
+mod hid;
+mod mass_storage;
+
+pub use self::device::EmulatedDevice;
+pub use self::device::UsbFunction;
+pub use self::hid::HidDevice;
+pub use self::hid::HidInput;
+pub use self::mass_storage::MassStorage;
@@ -2,6 +2,8 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the LICENSE file.
 
+use std::io;
+
 use base::TubeError;
 use remain::sorted;
 use thiserror::Error;
@@ -65,6 +67,16 @@ pub enum Error {
     StartAsyncJobQueue(UtilsError),
     #[error("xhci transfer completed: {0}")]
     TransferComplete(XhciTransferError),
+    #[error("invalid usbip reply")]
+    UsbipBadReply,
+    #[error("usbip bus id {0} is too long")]
+    UsbipBusIdTooLong(String),
+    #[error("usbip server refused to export the device, status {0}")]
+    UsbipImportRefused(u32),
+    #[error("failed to read from the usbip server: {0}")]
+    UsbipRead(io::Error),
+    #[error("failed to write to the usbip server: {0}")]
+    UsbipWrite(io::Error),
     #[error("failed to write buffer: {0}")]
     WriteBuffer(BufferError),
     #[error("failed to write control tube: {0}")]
@@ -5,6 +5,7 @@
 use std::collections::HashMap;
 use std::fs::File;
 use std::mem;
+use std::path::Path;
 use std::sync::Arc;
 use std::time::Duration;
 
@@ -16,6 +17,8 @@ use base::RawDescriptor;
 use base::Tube;
 use sync::Mutex;
 use usb_util::Device;
+use vm_control::input::InputEvent;
+use vm_control::EmulatedUsbDevice;
 use vm_control::UsbControlAttachedDevice;
 use vm_control::UsbControlCommand;
 use vm_control::UsbControlResult;
@@ -23,7 +26,14 @@ use vm_control::USB_CONTROL_MAX_PORTS;
 
 use super::error::*;
 use super::host_device::HostDevice;
+use crate::usb::emulated::EmulatedDevice;
+use crate::usb::emulated::HidDevice;
+use crate::usb::emulated::HidInput;
+use crate::usb::emulated::MassStorage;
+use crate::usb::usbip::UsbipConnection;
+use crate::usb::usbip::UsbipDevice;
 use crate::usb::xhci::usb_hub::UsbHub;
+use crate::usb::xhci::xhci_backend_device::XhciBackendDevice;
 use crate::usb::xhci::xhci_backend_device_provider::XhciBackendDeviceProvider;
 use crate::utils::AsyncJobQueue;
 use crate::utils::EventHandler;
@@ -33,7 +43,7 @@ use crate::utils::FailHandle;
 const SOCKET_TIMEOUT_MS: u64 = 2000;
 
 /// Host backend device provider is a xhci backend device provider that would provide pass through
-/// devices.
+/// devices, and the devices emulated by crosvm.
 pub enum HostBackendDeviceProvider {
     // The provider is created but not yet started.
     Created { control_tube: Mutex<Tube> },
@@ -134,6 +144,11 @@ pub struct ProviderInner {
 
     // Map of USB hub port number to per-device context.
     devices: Mutex<HashMap<u8, HostDeviceContext>>,
+    // Map of USB hub port number to the input of the emulated HID device on the port.
+    hid_inputs: Mutex<HashMap<u8, HidInput>>,
+    // Map of USB hub port number to the handler of the replies of the USB/IP server exporting the
+    // device on the port.
+    usbip_devices: Mutex<HashMap<u8, Arc<UsbipEventHandler>>>,
 }
 
 struct HostDeviceContext {
@@ -156,6 +171,8 @@ impl ProviderInner {
             control_tube,
             usb_hub,
             devices: Mutex::new(HashMap::new()),
+            hid_inputs: Mutex::new(HashMap::new()),
+            usbip_devices: Mutex::new(HashMap::new()),
         }
     }
 
@@ -221,9 +238,126 @@ impl ProviderInner {
         }
     }
 
+    /// Creates a USB device emulated by crosvm and plugs it in the hub.
+    fn handle_attach_emulated_device(&self, device: EmulatedUsbDevice) -> UsbControlResult {
+        let (backend, hid_input): (Box<dyn XhciBackendDevice>, _) = match device {
+            EmulatedUsbDevice::MassStorage { file, read_only } => {
+                let disk = match disk::create_disk_file(
+                    file,
+                    false,
+                    disk::MAX_NESTING_DEPTH,
+                    Path::new(""),
+                ) {
+                    Ok(disk) => disk,
+                    Err(e) => {
+                        error!("failed to open USB mass storage disk image: {}", e);
+                        return UsbControlResult::FailedToOpenDevice;
+                    }
+                };
+                match MassStorage::new(disk, read_only) {
+                    Ok(storage) => (Box::new(EmulatedDevice::new(storage)), None),
+                    Err(e) => {
+                        error!("failed to create USB mass storage device: {}", e);
+                        return UsbControlResult::FailedToOpenDevice;
+                    }
+                }
+            }
+            EmulatedUsbDevice::Keyboard => {
+                let (hid, input) = HidDevice::keyboard();
+                (Box::new(EmulatedDevice::new(hid)), Some(input))
+            }
+            EmulatedUsbDevice::Tablet { width, height } => {
+                let (hid, input) = HidDevice::tablet(width, height);
+                (Box::new(EmulatedDevice::new(hid)), Some(input))
+            }
+        };
+
+        match self.usb_hub.connect_backend(backend) {
+            Ok(port) => {
+                if let Some(input) = hid_input {
+                    self.hid_inputs.lock().insert(port, input);
+                }
+                UsbControlResult::Ok { port }
+            }
+            Err(e) => {
+                error!("failed to connect device to hub: {}", e);
+                UsbControlResult::NoAvailablePort
+            }
+        }
+    }
+
+    /// Imports the device `busid` of the USB/IP server connected to `socket` and plugs it in the
+    /// hub.
+    fn handle_attach_usbip_device(&self, socket: File, busid: &str) -> UsbControlResult {
+        let (device, connection) = match UsbipDevice::import(socket, busid) {
+            Ok(imported) => imported,
+            Err(e @ Error::UsbipImportRefused(_)) | Err(e @ Error::UsbipBusIdTooLong(_)) => {
+                error!("failed to import usbip device {}: {}", busid, e);
+                return UsbControlResult::NoSuchDevice;
+            }
+            Err(e) => {
+                error!("failed to import usbip device {}: {}", busid, e);
+                return UsbControlResult::FailedToOpenDevice;
+            }
+        };
+
+        let port = match self.usb_hub.connect_backend(Box::new(device)) {
+            Ok(port) => port,
+            Err(e) => {
+                error!("failed to connect device to hub: {}", e);
+                return UsbControlResult::NoAvailablePort;
+            }
+        };
+
+        let event_handler = Arc::new(UsbipEventHandler {
+            connection,
+            usb_hub: self.usb_hub.clone(),
+            port,
+        });
+        let handler: Arc<dyn EventHandler> = event_handler.clone();
+        if let Err(e) = self.event_loop.add_event(
+            event_handler.connection.socket(),
+            EventType::Read,
+            Arc::downgrade(&handler),
+        ) {
+            error!("failed to add usbip socket to event handler: {}", e);
+            let _ = self.usb_hub.disconnect_port(port);
+            return UsbControlResult::FailedToOpenDevice;
+        }
+        self.usbip_devices.lock().insert(port, event_handler);
+        UsbControlResult::Ok { port }
+    }
+
+    fn handle_inject_input(&self, port: u8, events: &[InputEvent]) -> UsbControlResult {
+        let input = match self.hid_inputs.lock().get(&port) {
+            Some(input) => input.clone(),
+            None => return UsbControlResult::NoSuchDevice,
+        };
+        match input.inject(events) {
+            Ok(()) => UsbControlResult::Ok { port },
+            Err(e) => {
+                error!(
+                    "failed to inject input to the USB device on port {}: {}",
+                    port, e
+                );
+                self.fail_handle.fail();
+                UsbControlResult::FailedToInjectInput
+            }
+        }
+    }
+
     fn handle_detach_device(&self, port: u8) -> UsbControlResult {
         match self.usb_hub.disconnect_port(port) {
             Ok(()) => {
+                self.hid_inputs.lock().remove(&port);
+                if let Some(event_handler) = self.usbip_devices.lock().remove(&port) {
+                    if let Err(e) = self
+                        .event_loop
+                        .remove_event_for_descriptor(event_handler.connection.socket())
+                    {
+                        error!("failed to remove usbip socket from event loop: {}", e);
+                    }
+                }
                 if let Some(device_ctx) = self.devices.lock().remove(&port) {
                     let _ = device_ctx.event_handler.on_event();
                     let device = device_ctx.device.lock();
@@ -271,8 +405,17 @@ impl ProviderInner {
         let cmd = tube.recv().map_err(Error::ReadControlTube)?;
         let result = match cmd {
             UsbControlCommand::AttachDevice { file } => self.handle_attach_device(file),
+            UsbControlCommand::AttachEmulatedDevice { device } => {
+                self.handle_attach_emulated_device(device)
+            }
+            UsbControlCommand::AttachUsbipDevice { socket, busid } => {
+                self.handle_attach_usbip_device(socket, &busid)
+            }
             UsbControlCommand::DetachDevice { port } => self.handle_detach_device(port),
             UsbControlCommand::ListDevice { ports } => self.handle_list_devices(ports),
+            UsbControlCommand::InjectInput { port, events } => {
+                self.handle_inject_input(port, &events)
+            }
         };
         tube.send(&result).map_err(Error::WriteControlTube)?;
         Ok(())
@@ -298,3 +441,22 @@ impl EventHandler for UsbUtilEventHandler {
             .context("UsbUtilEventHandler poll_transfers failed")
     }
 }
+
+struct UsbipEventHandler {
+    connection: Arc<UsbipConnection>,
+    usb_hub: Arc<UsbHub>,
+    port: u8,
+}
+
+impl EventHandler for UsbipEventHandler {
+    fn on_event(&self) -> anyhow::Result<()> {
+        if let Err(e) = self.connection.read_reply() {
+            // The device is unplugged when the connection to the server is broken. Returning an
+            // error removes the socket from the event loop.
+            error!("usbip device on port {} disconnected: {}", self.port, e);
+            let _ = self.usb_hub.disconnect_port(self.port);
+            return Err(e).context("UsbipEventHandler read_reply failed");
+        }
+        Ok(())
+    }
+}
@@ -4,5 +4,7 @@
 
 #[macro_use]
 mod log;
+pub mod emulated;
 pub mod host_backend;
+pub mod usbip;
 pub mod xhci;
@@ -0,0 +1,12 @@
+// Copyright 2023 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Client side of the USB/IP protocol, which plugs a device exported by a USB/IP server in the
+//! xHCI controller.
+
+mod protocol;
+mod usbip_device;
+
+pub use self::usbip_device::UsbipConnection;
+pub use self::usbip_device::UsbipDevice;
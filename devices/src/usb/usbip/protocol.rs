@@ -0,0 +1,285 @@
+// Copyright 2023 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Messages of the USB/IP protocol, as described in `Documentation/usb/usbip_protocol.rst` of the
+//! Linux kernel. All the fields are big endian, except the setup packets of control transfers.
+
+use std::io::Read;
+use std::io::Write;
+
+use crate::usb::host_backend::error::*;
+
+const USBIP_VERSION: u16 = 0x0111;
+
+// Operations exchanged before a device is imported.
+const OP_REQ_IMPORT: u16 = 0x8003;
+const OP_REP_IMPORT: u16 = 0x0003;
+const OP_HEADER_LEN: usize = 8;
+const ST_OK: u32 = 0;
+const BUSID_LEN: usize = 32;
+// Length of the `usbip_usb_device` structure of OP_REP_IMPORT.
+const USB_DEVICE_LEN: usize = 312;
+
+// Commands and replies exchanged once the device is imported.
+const USBIP_CMD_SUBMIT: u32 = 0x0001;
+const USBIP_CMD_UNLINK: u32 = 0x0002;
+const USBIP_RET_SUBMIT: u32 = 0x0003;
+const USBIP_RET_UNLINK: u32 = 0x0004;
+const USBIP_DIR_OUT: u32 = 0;
+const USBIP_DIR_IN: u32 = 1;
+// URB_DIR_IN of the transfer flags, which the Linux client also sets.
+const URB_DIR_IN: u32 = 0x0200;
+
+/// Length of the header of the commands and replies, the data of the transfers comes after it.
+pub const HEADER_LEN: usize = 48;
+
+/// `enum usb_device_speed` of the Linux kernel.
+const USB_SPEED_SUPER: u32 = 5;
+const USB_SPEED_HIGH: u32 = 3;
+
+/// A device exported by the server and imported by crosvm.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ImportedDevice {
+    pub busnum: u32,
+    pub devnum: u32,
+    pub speed: u32,
+    pub vendor_id: u16,
+    pub product_id: u16,
+}
+
+impl ImportedDevice {
+    /// Returns the id of the device in the commands sent to the server.
+    pub fn devid(&self) -> u32 {
+        (self.busnum << 16) | self.devnum
+    }
+
+    pub fn is_super_speed(&self) -> bool {
+        self.speed >= USB_SPEED_SUPER
+    }
+
+    /// Returns the `interval` of a transfer to an interrupt endpoint of the device, from the
+    /// `bInterval` of its descriptor.
+    pub fn interval(&self, b_interval: u8) -> u32 {
+        if self.speed >= USB_SPEED_HIGH {
+            // In microframes, as a power of 2.
+            1 << (b_interval.clamp(1, 16) - 1)
+        } else {
+            // In frames.
+            b_interval.max(1) as u32
+        }
+    }
+}
+
+fn be16(bytes: &[u8]) -> u16 {
+    u16::from_be_bytes([bytes[0], bytes[1]])
+}
+
+fn be32(bytes: &[u8]) -> u32 {
+    u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+}
+
+/// Asks the server on the other end of `stream` to export the device `busid`. Once it succeeds,
+/// `stream` carries the commands and replies of the transfers to the device.
+pub fn import_device<S: Read + Write>(stream: &mut S, busid: &str) -> Result<ImportedDevice> {
+    // The bus id is a nul-terminated string.
+    if busid.len() >= BUSID_LEN {
+        return Err(Error::UsbipBusIdTooLong(busid.to_owned()));
+    }
+    let mut request = Vec::with_capacity(OP_HEADER_LEN + BUSID_LEN);
+    request.extend_from_slice(&USBIP_VERSION.to_be_bytes());
+    request.extend_from_slice(&OP_REQ_IMPORT.to_be_bytes());
+    request.extend_from_slice(&ST_OK.to_be_bytes());
+    request.extend_from_slice(busid.as_bytes());
+    request.resize(OP_HEADER_LEN + BUSID_LEN, 0);
+    stream.write_all(&request).map_err(Error::UsbipWrite)?;
+
+    let mut header = [0u8; OP_HEADER_LEN];
+    stream.read_exact(&mut header).map_err(Error::UsbipRead)?;
+    if be16(&header[2..]) != OP_REP_IMPORT {
+        return Err(Error::UsbipBadReply);
+    }
+    // The description of the device only follows when the import succeeds.
+    let status = be32(&header[4..]);
+    if status != ST_OK {
+        return Err(Error::UsbipImportRefused(status));
+    }
+    let mut device = [0u8; USB_DEVICE_LEN];
+    stream.read_exact(&mut device).map_err(Error::UsbipRead)?;
+    // Skip the sysfs path and the bus id of the device.
+    let device = &device[256 + BUSID_LEN..];
+    Ok(ImportedDevice {
+        busnum: be32(&device[0..]),
+        devnum: be32(&device[4..]),
+        speed: be32(&device[8..]),
+        vendor_id: be16(&device[12..]),
+        product_id: be16(&device[14..]),
+    })
+}
+
+/// USBIP_CMD_SUBMIT, which submits a transfer of `transfer_buffer_length` bytes. The data of
+/// OUT transfers follows it.
+pub struct CmdSubmit {
+    pub seqnum: u32,
+    pub devid: u32,
+    pub ep: u8,
+    pub dir_in: bool,
+    pub transfer_buffer_length: u32,
+    pub interval: u32,
+    /// Setup packet of control transfers, as sent on the bus.
+    pub setup: [u8; 8],
+}
+
+impl CmdSubmit {
+    pub fn to_bytes(&self) -> [u8; HEADER_LEN] {
+        let (direction, transfer_flags) = if self.dir_in {
+            (USBIP_DIR_IN, URB_DIR_IN)
+        } else {
+            (USBIP_DIR_OUT, 0)
+        };
+        let mut bytes = [0u8; HEADER_LEN];
+        bytes[0..4].copy_from_slice(&USBIP_CMD_SUBMIT.to_be_bytes());
+        bytes[4..8].copy_from_slice(&self.seqnum.to_be_bytes());
+        bytes[8..12].copy_from_slice(&self.devid.to_be_bytes());
+        bytes[12..16].copy_from_slice(&direction.to_be_bytes());
+        bytes[16..20].copy_from_slice(&(self.ep as u32).to_be_bytes());
+        bytes[20..24].copy_from_slice(&transfer_flags.to_be_bytes());
+        bytes[24..28].copy_from_slice(&self.transfer_buffer_length.to_be_bytes());
+        // start_frame and number_of_packets are only used by isochronous transfers.
+        bytes[36..40].copy_from_slice(&self.interval.to_be_bytes());
+        bytes[40..48].copy_from_slice(&self.setup);
+        bytes
+    }
+}
+
+/// Returns USBIP_CMD_UNLINK, which cancels the transfer submitted with `unlink_seqnum`.
+pub fn cmd_unlink(seqnum: u32, devid: u32, unlink_seqnum: u32) -> [u8; HEADER_LEN] {
+    let mut bytes = [0u8; HEADER_LEN];
+    bytes[0..4].copy_from_slice(&USBIP_CMD_UNLINK.to_be_bytes());
+    bytes[4..8].copy_from_slice(&seqnum.to_be_bytes());
+    bytes[8..12].copy_from_slice(&devid.to_be_bytes());
+    bytes[20..24].copy_from_slice(&unlink_seqnum.to_be_bytes());
+    bytes
+}
+
+/// A reply of the server, with the sequence number of the command it answers. `status` is 0 or a
+/// negated errno value.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Reply {
+    /// USBIP_RET_SUBMIT, followed by `actual_length` bytes of data for IN transfers.
+    Submit {
+        seqnum: u32,
+        status: i32,
+        actual_length: u32,
+    },
+    /// USBIP_RET_UNLINK.
+    Unlink { seqnum: u32, status: i32 },
+}
+
+impl Reply {
+    pub fn from_bytes(bytes: &[u8; HEADER_LEN]) -> Result<Reply> {
+        let seqnum = be32(&bytes[4..]);
+        let status = be32(&bytes[20..]) as i32;
+        match be32(&bytes[0..]) {
+            USBIP_RET_SUBMIT => Ok(Reply::Submit {
+                seqnum,
+                status,
+                actual_length: be32(&bytes[24..]),
+            }),
+            USBIP_RET_UNLINK => Ok(Reply::Unlink { seqnum, status }),
+            _ => Err(Error::UsbipBadReply),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::net::UnixStream;
+    use std::thread;
+
+    use super::*;
+
+    #[test]
+    fn import() {
+        let (mut client, mut server) = UnixStream::pair().unwrap();
+        let server = thread::spawn(move || {
+            let mut request = [0u8; OP_HEADER_LEN + BUSID_LEN];
+            server.read_exact(&mut request).unwrap();
+            assert_eq!(&request[..4], &[0x01, 0x11, 0x80, 0x03]);
+            assert_eq!(&request[8..13], b"1-2.3");
+            assert!(request[13..].iter().all(|&b| b == 0));
+
+            let mut reply = vec![0x01, 0x11, 0x00, 0x03, 0, 0, 0, 0];
+            let mut device = vec![0u8; USB_DEVICE_LEN];
+            device[288..304].copy_from_slice(&[
+                0, 0, 0, 1, // busnum
+                0, 0, 0, 5, // devnum
+                0, 0, 0, 3, // speed
+                0x18, 0xd1, 0x4e, 0xe7, // idVendor, idProduct
+            ]);
+            reply.extend(device);
+            server.write_all(&reply).unwrap();
+        });
+
+        let device = import_device(&mut client, "1-2.3").unwrap();
+        server.join().unwrap();
+        assert_eq!(
+            device,
+            ImportedDevice {
+                busnum: 1,
+                devnum: 5,
+                speed: USB_SPEED_HIGH,
+                vendor_id: 0x18d1,
+                product_id: 0x4ee7,
+            }
+        );
+        assert_eq!(device.devid(), 0x10005);
+        assert_eq!(device.interval(4), 8);
+    }
+
+    #[test]
+    fn import_refused() {
+        let (mut client, mut server) = UnixStream::pair().unwrap();
+        server
+            .write_all(&[0x01, 0x11, 0x00, 0x03, 0, 0, 0, 1])
+            .unwrap();
+        assert!(matches!(
+            import_device(&mut client, "1-1"),
+            Err(Error::UsbipImportRefused(1))
+        ));
+    }
+
+    #[test]
+    fn messages() {
+        let submit = CmdSubmit {
+            seqnum: 7,
+            devid: 0x10002,
+            ep: 0,
+            dir_in: true,
+            transfer_buffer_length: 18,
+            interval: 0,
+            setup: [0x80, 0x06, 0x00, 0x01, 0x00, 0x00, 0x12, 0x00],
+        };
+        let bytes = submit.to_bytes();
+        assert_eq!(
+            &bytes[..20],
+            &[0, 0, 0, 1, 0, 0, 0, 7, 0, 1, 0, 2, 0, 0, 0, 1, 0, 0, 0, 0]
+        );
+        assert_eq!(&bytes[20..28], &[0, 0, 0x02, 0, 0, 0, 0, 18]);
+        assert_eq!(&bytes[40..], &submit.setup);
+
+        let mut reply = [0u8; HEADER_LEN];
+        reply[3] = 3;
+        reply[7] = 7;
+        reply[20..24].copy_from_slice(&(-32i32).to_be_bytes());
+        reply[27] = 18;
+        assert_eq!(
+            Reply::from_bytes(&reply).unwrap(),
+            Reply::Submit {
+                seqnum: 7,
+                status: -32,
+                actual_length: 18,
+            }
+        );
+    }
+}
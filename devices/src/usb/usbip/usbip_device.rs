@@ -0,0 +1,535 @@
+// Copyright 2023 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+use std::cmp::min;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Read;
+use std::io::Write;
+use std::mem;
+use std::sync::Arc;
+use std::sync::Weak;
+
+use base::error;
+use sync::Mutex;
+use usb_util::ControlRequestDataPhaseTransferDirection;
+use usb_util::ControlRequestRecipient;
+use usb_util::DescriptorType;
+use usb_util::StandardControlRequest;
+use usb_util::TransferStatus;
+use usb_util::UsbRequestSetup;
+use zerocopy::AsBytes;
+
+use super::protocol::cmd_unlink;
+use super::protocol::import_device;
+use super::protocol::CmdSubmit;
+use super::protocol::ImportedDevice;
+use super::protocol::Reply;
+use super::protocol::HEADER_LEN;
+use crate::usb::host_backend::error::*;
+use crate::usb::host_backend::host_device::ControlEndpointState;
+use crate::usb::xhci::scatter_gather_buffer::ScatterGatherBuffer;
+use crate::usb::xhci::xhci_backend_device::BackendType;
+use crate::usb::xhci::xhci_backend_device::UsbDeviceAddress;
+use crate::usb::xhci::xhci_backend_device::XhciBackendDevice;
+use crate::usb::xhci::xhci_transfer::TransferDirection;
+use crate::usb::xhci::xhci_transfer::XhciTransfer;
+use crate::usb::xhci::xhci_transfer::XhciTransferState;
+use crate::usb::xhci::xhci_transfer::XhciTransferType;
+
+/// SetFeature(PORT_RESET) sent to the port of the device, which the server handles by resetting
+/// the device.
+const PORT_RESET_SETUP: [u8; 8] = [0x23, 0x03, 0x04, 0x00, 0x00, 0x00, 0x00, 0x00];
+
+/// Type of the endpoint descriptors, which are not part of `DescriptorType`.
+const ENDPOINT_DESCRIPTOR_TYPE: u8 = 5;
+
+/// A request submitted to the server, waiting for its reply.
+struct Urb {
+    // None for the requests that crosvm makes on its own.
+    transfer: Option<XhciTransfer>,
+    // The buffer that receives the data of IN transfers.
+    buffer: Option<ScatterGatherBuffer>,
+    dir_in: bool,
+    length: u32,
+    // Whether the data is the configuration descriptor, from which the intervals of the interrupt
+    // endpoints are learned.
+    config_descriptor: bool,
+}
+
+/// What to submit to an endpoint of the device.
+struct Request {
+    ep: u8,
+    dir_in: bool,
+    length: u32,
+    setup: [u8; 8],
+    // The data of OUT transfers.
+    data: Vec<u8>,
+    buffer: Option<ScatterGatherBuffer>,
+    config_descriptor: bool,
+}
+
+/// The connection to the server that exports a device. Transfers are written to it from the xHCI
+/// controller, while the replies are read from the event loop of the USB backends.
+pub struct UsbipConnection {
+    device: ImportedDevice,
+    reader: File,
+    writer: Mutex<File>,
+    next_seqnum: Mutex<u32>,
+    // Map of the sequence number of a submit command to its request.
+    urbs: Mutex<HashMap<u32, Urb>>,
+    // Map of the sequence number of an unlink command to the command that it cancels.
+    unlinks: Mutex<HashMap<u32, u32>>,
+    // Map of an endpoint address to the interval of its transfers.
+    intervals: Mutex<HashMap<u8, u32>>,
+}
+
+impl UsbipConnection {
+    /// Returns the socket connected to the server, which is readable when a reply is available.
+    pub fn socket(&self) -> &File {
+        &self.reader
+    }
+
+    fn next_seqnum(&self) -> u32 {
+        let mut next_seqnum = self.next_seqnum.lock();
+        let seqnum = *next_seqnum;
+        // Sequence number 0 is never used.
+        *next_seqnum = next_seqnum.wrapping_add(1).max(1);
+        seqnum
+    }
+
+    fn write(&self, bytes: &[&[u8]]) -> Result<()> {
+        let mut writer = self.writer.lock();
+        for bytes in bytes {
+            writer.write_all(bytes).map_err(Error::UsbipWrite)?;
+        }
+        Ok(())
+    }
+
+    /// Sends `request` to the server. `transfer` is completed once the server replies.
+    fn submit(self: &Arc<Self>, transfer: Option<XhciTransfer>, request: Request) -> Result<()> {
+        let seqnum = self.next_seqnum();
+        if let Some(transfer) = &transfer {
+            let state = transfer.state().clone();
+            let mut state = state.lock();
+            match mem::replace(&mut *state, XhciTransferState::Cancelled) {
+                XhciTransferState::Created => {
+                    let connection = Arc::downgrade(self);
+                    let cancel_callback = Box::new(move || Self::cancel(connection, seqnum));
+                    *state = XhciTransferState::Submitted { cancel_callback };
+                }
+                XhciTransferState::Cancelled => {
+                    drop(state);
+                    return transfer
+                        .on_transfer_complete(&TransferStatus::Cancelled, 0)
+                        .map_err(Error::TransferComplete);
+                }
+                _ => {
+                    error!("xhci trasfer state is invalid");
+                    return Err(Error::BadXhciTransferState);
+                }
+            }
+        }
+
+        self.urbs.lock().insert(
+            seqnum,
+            Urb {
+                transfer,
+                buffer: request.buffer,
+                dir_in: request.dir_in,
+                length: request.length,
+                config_descriptor: request.config_descriptor,
+            },
+        );
+        let interval = if request.ep == 0 {
+            0
+        } else {
+            let address = if request.dir_in {
+                request.ep | 0x80
+            } else {
+                request.ep
+            };
+            self.intervals.lock().get(&address).copied().unwrap_or(1)
+        };
+        let command = CmdSubmit {
+            seqnum,
+            devid: self.device.devid(),
+            ep: request.ep,
+            dir_in: request.dir_in,
+            transfer_buffer_length: request.length,
+            interval,
+            setup: request.setup,
+        }
+        .to_bytes();
+        if let Err(e) = self.write(&[&command, &request.data]) {
+            // The reader finds out that the connection is broken and unplugs the device.
+            error!("failed to submit usbip transfer: {}", e);
+            if let Some(urb) = self.urbs.lock().remove(&seqnum) {
+                Self::complete(urb, TransferStatus::Error, 0, &[])?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Asks the server to cancel the command `seqnum`. Its transfer is completed once the server
+    /// replies.
+    fn cancel(connection: Weak<UsbipConnection>, seqnum: u32) {
+        let connection = match connection.upgrade() {
+            Some(connection) => connection,
+            None => return,
+        };
+        let unlink_seqnum = connection.next_seqnum();
+        connection.unlinks.lock().insert(unlink_seqnum, seqnum);
+        let command = cmd_unlink(unlink_seqnum, connection.device.devid(), seqnum);
+        if let Err(e) = connection.write(&[&command]) {
+            error!("failed to cancel usbip transfer: {}", e);
+        }
+    }
+
+    /// Completes the transfer of `urb` with the `actual_length` bytes that the device transferred,
+    /// `data` being what the server sent back for IN transfers.
+    fn complete(urb: Urb, status: TransferStatus, actual_length: u32, data: &[u8]) -> Result<()> {
+        let transfer = match urb.transfer {
+            Some(transfer) => transfer,
+            None => return Ok(()),
+        };
+        let status = {
+            let mut state = transfer.state().lock();
+            match mem::replace(&mut *state, XhciTransferState::Completed) {
+                XhciTransferState::Submitted { .. } => status,
+                XhciTransferState::Cancelling | XhciTransferState::Cancelled => {
+                    *state = XhciTransferState::Cancelled;
+                    TransferStatus::Cancelled
+                }
+                _ => {
+                    error!("xhci trasfer state is invalid");
+                    return Err(Error::BadXhciTransferState);
+                }
+            }
+        };
+        let mut bytes_transferred = min(actual_length, urb.length);
+        if let (TransferStatus::Completed, Some(buffer)) = (&status, &urb.buffer) {
+            bytes_transferred = buffer.write(data).map_err(Error::WriteBuffer)? as u32;
+        }
+        transfer
+            .on_transfer_complete(&status, bytes_transferred)
+            .map_err(Error::TransferComplete)
+    }
+
+    /// Learns the intervals of the interrupt endpoints from a configuration descriptor.
+    fn parse_config_descriptor(&self, data: &[u8]) {
+        let mut intervals = self.intervals.lock();
+        let mut offset = 0;
+        while let Some(&[length, descriptor_type]) = data.get(offset..offset + 2) {
+            if length < 2 {
+                break;
+            }
+            if descriptor_type == ENDPOINT_DESCRIPTOR_TYPE {
+                if let Some(endpoint) = data.get(offset..offset + 7) {
+                    intervals.insert(endpoint[2], self.device.interval(endpoint[6]));
+                }
+            }
+            offset += length as usize;
+        }
+    }
+
+    /// Reads a reply of the server and completes the transfer that it answers. Fails if the
+    /// connection is broken, in which case the pending transfers are completed with an error.
+    pub fn read_reply(&self) -> Result<()> {
+        let result = self.read_reply_helper();
+        if result.is_err() {
+            let urbs: Vec<Urb> = self.urbs.lock().drain().map(|(_, urb)| urb).collect();
+            for urb in urbs {
+                if let Err(e) = Self::complete(urb, TransferStatus::Error, 0, &[]) {
+                    error!("failed to complete usbip transfer: {}", e);
+                }
+            }
+        }
+        result
+    }
+
+    fn read_reply_helper(&self) -> Result<()> {
+        let mut header = [0u8; HEADER_LEN];
+        (&self.reader)
+            .read_exact(&mut header)
+            .map_err(Error::UsbipRead)?;
+        match Reply::from_bytes(&header)? {
+            Reply::Submit {
+                seqnum,
+                status,
+                actual_length,
+            } => {
+                let urb = self
+                    .urbs
+                    .lock()
+                    .remove(&seqnum)
+                    .ok_or(Error::UsbipBadReply)?;
+                let mut data = Vec::new();
+                if urb.dir_in {
+                    if actual_length > urb.length {
+                        return Err(Error::UsbipBadReply);
+                    }
+                    data.resize(actual_length as usize, 0);
+                    (&self.reader)
+                        .read_exact(&mut data)
+                        .map_err(Error::UsbipRead)?;
+                }
+                if urb.config_descriptor && status == 0 {
+                    self.parse_config_descriptor(&data);
+                }
+                let status = match -status {
+                    0 => TransferStatus::Completed,
+                    libc::ECONNRESET | libc::ENOENT => TransferStatus::Cancelled,
+                    libc::ENODEV | libc::ESHUTDOWN => TransferStatus::NoDevice,
+                    _ => {
+                        usb_debug!("usbip transfer {} failed with status {}", seqnum, status);
+                        TransferStatus::Error
+                    }
+                };
+                Self::complete(urb, status, actual_length, &data)
+            }
+            Reply::Unlink { seqnum, status } => {
+                let unlinked = self
+                    .unlinks
+                    .lock()
+                    .remove(&seqnum)
+                    .ok_or(Error::UsbipBadReply)?;
+                // A status of 0 means that the command already completed, and that its reply was
+                // sent before this one.
+                if status == 0 {
+                    return Ok(());
+                }
+                match self.urbs.lock().remove(&unlinked) {
+                    Some(urb) => Self::complete(urb, TransferStatus::Cancelled, 0, &[]),
+                    None => Ok(()),
+                }
+            }
+        }
+    }
+}
+
+/// A device exported by a USB/IP server, such as `usbipd` on another machine. The transfers of the
+/// guest are forwarded to the server, which submits them to the device.
+pub struct UsbipDevice {
+    connection: Arc<UsbipConnection>,
+    ctl_ep_state: ControlEndpointState,
+    control_request_setup: UsbRequestSetup,
+    executed: bool,
+}
+
+impl UsbipDevice {
+    /// Imports the device `busid` of the server connected to `socket`. The returned connection
+    /// must be polled with `UsbipConnection::read_reply` for the transfers to complete.
+    pub fn import(mut socket: File, busid: &str) -> Result<(UsbipDevice, Arc<UsbipConnection>)> {
+        let device = import_device(&mut socket, busid)?;
+        let writer = socket.try_clone().map_err(Error::UsbipWrite)?;
+        let connection = Arc::new(UsbipConnection {
+            device,
+            reader: socket,
+            writer: Mutex::new(writer),
+            next_seqnum: Mutex::new(1),
+            urbs: Mutex::new(HashMap::new()),
+            unlinks: Mutex::new(HashMap::new()),
+            intervals: Mutex::new(HashMap::new()),
+        });
+        let usbip_device = UsbipDevice {
+            connection: connection.clone(),
+            ctl_ep_state: ControlEndpointState::SetupStage,
+            control_request_setup: UsbRequestSetup::new(0, 0, 0, 0, 0),
+            executed: false,
+        };
+        Ok((usbip_device, connection))
+    }
+
+    fn execute_control_transfer(
+        &mut self,
+        xhci_transfer: XhciTransfer,
+        buffer: Option<ScatterGatherBuffer>,
+    ) -> Result<()> {
+        let setup = self.control_request_setup;
+        let recipient = setup.get_recipient();
+        let standard_request = setup.get_standard_request();
+        if standard_request == Some(StandardControlRequest::SetAddress)
+            && recipient == ControlRequestRecipient::Device
+        {
+            // The address is assigned by the Address Device command of the xHCI controller.
+            usb_debug!("usbip device handling set address");
+            return xhci_transfer
+                .on_transfer_complete(&TransferStatus::Completed, 0)
+                .map_err(Error::TransferComplete);
+        }
+
+        let dir_in =
+            setup.get_direction() == ControlRequestDataPhaseTransferDirection::DeviceToHost;
+        let mut data = Vec::new();
+        if !dir_in {
+            if let Some(buffer) = &buffer {
+                let len = min(
+                    buffer.len().map_err(Error::BufferLen)?,
+                    setup.length as usize,
+                );
+                data = vec![0; len];
+                buffer.read(&mut data).map_err(Error::ReadBuffer)?;
+            }
+        }
+        let config_descriptor = standard_request == Some(StandardControlRequest::GetDescriptor)
+            && recipient == ControlRequestRecipient::Device
+            && (setup.value >> 8) as u8 == DescriptorType::Configuration as u8;
+        let mut setup_bytes = [0u8; 8];
+        setup_bytes.copy_from_slice(setup.as_bytes());
+        let request = Request {
+            ep: 0,
+            dir_in,
+            length: if dir_in {
+                setup.length as u32
+            } else {
+                data.len() as u32
+            },
+            setup: setup_bytes,
+            data,
+            buffer: if dir_in { buffer } else { None },
+            config_descriptor,
+        };
+        self.connection.submit(Some(xhci_transfer), request)
+    }
+
+    fn handle_control_transfer(&mut self, xhci_transfer: XhciTransfer) -> Result<()> {
+        let transfer_type = xhci_transfer
+            .get_transfer_type()
+            .map_err(Error::GetXhciTransferType)?;
+        match transfer_type {
+            XhciTransferType::SetupStage(setup) => {
+                if self.ctl_ep_state != ControlEndpointState::SetupStage {
+                    error!("Control endpoint is in an inconsistant state");
+                    return Ok(());
+                }
+                usb_debug!("setup stage setup buffer: {:?}", setup);
+                self.control_request_setup = setup;
+                xhci_transfer
+                    .on_transfer_complete(&TransferStatus::Completed, 0)
+                    .map_err(Error::TransferComplete)?;
+                self.ctl_ep_state = ControlEndpointState::DataStage;
+            }
+            XhciTransferType::DataStage(buffer) => {
+                if self.ctl_ep_state != ControlEndpointState::DataStage {
+                    error!("Control endpoint is in an inconsistant state");
+                    return Ok(());
+                }
+                self.execute_control_transfer(xhci_transfer, Some(buffer))?;
+                self.executed = true;
+                self.ctl_ep_state = ControlEndpointState::StatusStage;
+            }
+            XhciTransferType::StatusStage => {
+                if self.ctl_ep_state == ControlEndpointState::SetupStage {
+                    error!("Control endpoint is in an inconsistant state");
+                    return Ok(());
+                }
+                if self.executed {
+                    xhci_transfer
+                        .on_transfer_complete(&TransferStatus::Completed, 0)
+                        .map_err(Error::TransferComplete)?;
+                } else {
+                    self.execute_control_transfer(xhci_transfer, None)?;
+                }
+                self.executed = false;
+                self.ctl_ep_state = ControlEndpointState::SetupStage;
+            }
+            _ => {
+                error!(
+                    "Non control {} transfer sent to control endpoint.",
+                    transfer_type,
+                );
+                xhci_transfer
+                    .on_transfer_complete(&TransferStatus::Completed, 0)
+                    .map_err(Error::TransferComplete)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn handle_transfer(&mut self, xhci_transfer: XhciTransfer) -> Result<()> {
+        let transfer_type = xhci_transfer
+            .get_transfer_type()
+            .map_err(Error::GetXhciTransferType)?;
+        let buffer = match transfer_type {
+            XhciTransferType::Normal(buffer) => buffer,
+            XhciTransferType::Noop => {
+                return xhci_transfer
+                    .on_transfer_complete(&TransferStatus::Completed, 0)
+                    .map_err(Error::TransferComplete);
+            }
+            _ => {
+                // Isochronous transfers are not supported.
+                error!(
+                    "unsupported {} transfer sent to usbip device",
+                    transfer_type
+                );
+                return xhci_transfer
+                    .on_transfer_complete(&TransferStatus::Error, 0)
+                    .map_err(Error::TransferComplete);
+            }
+        };
+        let length = buffer.len().map_err(Error::BufferLen)?;
+        let dir_in = xhci_transfer.get_transfer_dir() == TransferDirection::In;
+        let mut data = Vec::new();
+        if !dir_in {
+            data = vec![0; length];
+            buffer.read(&mut data).map_err(Error::ReadBuffer)?;
+        }
+        let request = Request {
+            ep: xhci_transfer.get_endpoint_number(),
+            dir_in,
+            length: length as u32,
+            setup: [0; 8],
+            data,
+            buffer: if dir_in { Some(buffer) } else { None },
+            config_descriptor: false,
+        };
+        self.connection.submit(Some(xhci_transfer), request)
+    }
+}
+
+impl XhciBackendDevice for UsbipDevice {
+    fn get_backend_type(&self) -> BackendType {
+        if self.connection.device.is_super_speed() {
+            BackendType::Usb3
+        } else {
+            BackendType::Usb2
+        }
+    }
+
+    fn get_vid(&self) -> u16 {
+        self.connection.device.vendor_id
+    }
+
+    fn get_pid(&self) -> u16 {
+        self.connection.device.product_id
+    }
+
+    fn submit_transfer(&mut self, transfer: XhciTransfer) -> Result<()> {
+        if transfer.get_endpoint_number() == 0 {
+            self.handle_control_transfer(transfer)
+        } else {
+            self.handle_transfer(transfer)
+        }
+    }
+
+    fn set_address(&mut self, _address: UsbDeviceAddress) {}
+
+    fn reset(&mut self) -> Result<()> {
+        usb_debug!("resetting usbip device");
+        self.ctl_ep_state = ControlEndpointState::SetupStage;
+        self.executed = false;
+        let request = Request {
+            ep: 0,
+            dir_in: false,
+            length: 0,
+            setup: PORT_RESET_SETUP,
+            data: Vec::new(),
+            buffer: None,
+            config_descriptor: false,
+        };
+        self.connection.submit(None, request)
+    }
+}
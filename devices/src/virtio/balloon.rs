@@ -12,6 +12,9 @@ use anyhow::Context;
 use balloon_control::BalloonStats;
 use balloon_control::BalloonTubeCommand;
 use balloon_control::BalloonTubeResult;
+use balloon_control::BalloonWS;
+use balloon_control::WSBucket;
+use balloon_control::VIRTIO_BALLOON_WS_MAX_NUM_BINS;
 use base::error;
 use base::warn;
 use base::AsRawDescriptor;
@@ -19,6 +22,7 @@ use base::Event;
 use base::RawDescriptor;
 use base::Tube;
 use cros_async::block_on;
+use cros_async::select4;
 use cros_async::select8;
 use cros_async::sync::Mutex as AsyncMutex;
 use cros_async::AsyncTube;
@@ -47,6 +51,7 @@ use super::Queue;
 use super::Reader;
 use super::SignalableInterrupt;
 use super::VirtioDevice;
+use super::Writer;
 use crate::Suspendable;
 use crate::UnpinRequest;
 use crate::UnpinResponse;
@@ -72,9 +77,10 @@ pub enum BalloonError {
 }
 pub type Result<T> = std::result::Result<T, BalloonError>;
 
-// Balloon implements four virt IO queues: Inflate, Deflate, Stats, Event.
+// Balloon implements up to eight virt IO queues: Inflate, Deflate, Stats, FreePage, Reporting,
+// Event, WsData and WsOp.
 const QUEUE_SIZE: u16 = 128;
-const QUEUE_SIZES: &[u16] = &[QUEUE_SIZE, QUEUE_SIZE, QUEUE_SIZE, QUEUE_SIZE];
+const QUEUE_SIZES: &[u16] = &[QUEUE_SIZE; 8];
 
 const VIRTIO_BALLOON_PFN_SHIFT: u32 = 12;
 const VIRTIO_BALLOON_PF_SIZE: u64 = 1 << VIRTIO_BALLOON_PFN_SHIFT;
@@ -83,20 +89,32 @@ const VIRTIO_BALLOON_PF_SIZE: u64 = 1 << VIRTIO_BALLOON_PFN_SHIFT;
 const VIRTIO_BALLOON_F_MUST_TELL_HOST: u32 = 0; // Tell before reclaiming pages
 const VIRTIO_BALLOON_F_STATS_VQ: u32 = 1; // Stats reporting enabled
 const VIRTIO_BALLOON_F_DEFLATE_ON_OOM: u32 = 2; // Deflate balloon on OOM
+const VIRTIO_BALLOON_F_FREE_PAGE_HINT: u32 = 3; // Free page hinting virtqueue
 const VIRTIO_BALLOON_F_PAGE_REPORTING: u32 = 5; // Page reporting virtqueue
 
 #[derive(Copy, Clone)]
 #[repr(u32)]
 // Balloon virtqueues
 pub enum BalloonFeatures {
+    // Free page hinting enabled
+    FreePageHint = VIRTIO_BALLOON_F_FREE_PAGE_HINT,
     // Page Reporting enabled
     PageReporting = VIRTIO_BALLOON_F_PAGE_REPORTING,
+    // Working set reporting enabled
+    WSReporting = VIRTIO_BALLOON_F_WS_REPORTING,
 }
 
 // These feature bits are part of the proposal:
 //  https://lists.oasis-open.org/archives/virtio-comment/202201/msg00139.html
 const VIRTIO_BALLOON_F_RESPONSIVE_DEVICE: u32 = 6; // Device actively watching guest memory
 const VIRTIO_BALLOON_F_EVENTS_VQ: u32 = 7; // Event vq is enabled
+                                           // This feature bit is part of the working set reporting proposal, which adds the ws_num_bins
+                                           // config field and the ws_data and ws_op queues.
+const VIRTIO_BALLOON_F_WS_REPORTING: u32 = 8; // Working set reporting vqs are enabled
+
+// The free page hint command ids with a special meaning. Any other id starts a hinting run.
+const VIRTIO_BALLOON_CMD_ID_STOP: u32 = 0;
+const VIRTIO_BALLOON_CMD_ID_DONE: u32 = 1;
 
 // virtio_balloon_config is the balloon device configuration space defined by the virtio spec.
 #[derive(Copy, Clone, Debug, Default)]
@@ -104,6 +122,10 @@ const VIRTIO_BALLOON_F_EVENTS_VQ: u32 = 7; // Event vq is enabled
 struct virtio_balloon_config {
     num_pages: Le32,
     actual: Le32,
+    free_page_hint_cmd_id: Le32,
+    poison_val: Le32,
+    ws_num_bins: u8,
+    _reserved: [u8; 3],
 }
 
 // Safe because it only has data and has no implicit padding.
@@ -118,6 +140,10 @@ struct BalloonState {
     // is set by an Adjust command that has allow_failure set, and is cleared when the
     // Adjusted success/failure response is sent.
     failable_update: bool,
+    // The id of the current free page hinting run, as exposed in the config space.
+    free_page_hint_cmd_id: u32,
+    // The id of the working set request waiting for a report from the guest.
+    ws_request_id: Option<u64>,
 }
 
 // The constants defining stats types in virtio_baloon_stat
@@ -177,6 +203,46 @@ struct virtio_balloon_event_header {
 // Safe because it only has data and has no implicit padding.
 unsafe impl DataInit for virtio_balloon_event_header {}
 
+// virtio_balloon_ws is one bucket of the working set histogram read from the ws_data queue.
+#[derive(Copy, Clone, Default)]
+#[repr(C)]
+struct virtio_balloon_ws {
+    tag: Le16,
+    node_id: Le16,
+    _reserved: [u8; 4],
+    idle_age_ms: Le64,
+    // Anonymous and file-backed memory.
+    memory_size_bytes: [Le64; 2],
+}
+
+// Safe because it only has data and has no implicit padding.
+unsafe impl DataInit for virtio_balloon_ws {}
+
+const VIRTIO_BALLOON_WS_OP_REQUEST: u16 = 1;
+const VIRTIO_BALLOON_WS_OP_CONFIG: u16 = 2;
+
+// virtio_balloon_op is the header of the operations written to the ws_op queue.
+#[derive(Copy, Clone, Default)]
+#[repr(C)]
+struct virtio_balloon_op {
+    type_: Le16,
+}
+
+// Safe because it only has data and has no implicit padding.
+unsafe impl DataInit for virtio_balloon_op {}
+
+// Working set operations that the device sends to the driver.
+enum WSOp {
+    // Ask for a fresh working set report.
+    Request,
+    // Set the bins of the histogram and when the guest refreshes and reports it.
+    Config {
+        bins: Vec<u64>,
+        refresh_threshold: u64,
+        report_threshold: u64,
+    },
+}
+
 fn invoke_desc_handler<F>(ranges: Vec<(u64, u64)>, desc_handler: &mut F)
 where
     F: FnMut(GuestAddress, u64),
@@ -356,6 +422,65 @@ async fn handle_reporting_queue<F>(
     }
 }
 
+// Async task that handles the free page hinting queue. The driver sends the id of the hinting
+// run it is reporting for, then the free pages as writable buffers, and finally the STOP id,
+// after which it waits for the DONE id before reusing the hinted pages.
+async fn handle_free_page_queue<F>(
+    mem: &GuestMemory,
+    mut queue: Queue,
+    mut queue_event: EventAsync,
+    release_memory_tube: &Option<Tube>,
+    state: Arc<AsyncMutex<BalloonState>>,
+    interrupt: Interrupt,
+    mut desc_handler: F,
+) where
+    F: FnMut(GuestAddress, u64),
+{
+    // Whether the driver is hinting pages for the current hinting run.
+    let mut hinting = false;
+    loop {
+        let avail_desc = match queue.next_async(mem, &mut queue_event).await {
+            Err(e) => {
+                error!("Failed to read descriptor {}", e);
+                return;
+            }
+            Ok(d) => d,
+        };
+        let index = avail_desc.index;
+        if avail_desc.is_write_only() {
+            // Hints for a stale run may be for pages the guest is using again.
+            if hinting {
+                if let Err(e) =
+                    handle_reported_buffer(release_memory_tube, avail_desc, &mut desc_handler)
+                {
+                    error!("balloon: failed to process hinted buffer: {}", e);
+                }
+            }
+        } else {
+            match Reader::new(mem.clone(), avail_desc) {
+                Ok(mut r) => match r.read_obj::<Le32>() {
+                    Ok(cmd_id) => {
+                        let cmd_id = cmd_id.to_native();
+                        let mut state = state.lock().await;
+                        if cmd_id == VIRTIO_BALLOON_CMD_ID_STOP {
+                            hinting = false;
+                            // Let the driver give the hinted pages back to the guest.
+                            state.free_page_hint_cmd_id = VIRTIO_BALLOON_CMD_ID_DONE;
+                            interrupt.signal_config_changed();
+                        } else {
+                            hinting = cmd_id == state.free_page_hint_cmd_id;
+                        }
+                    }
+                    Err(e) => error!("failed to read free page hint command id: {}", e),
+                },
+                Err(e) => error!("balloon: failed to CREATE Reader: {}", e),
+            }
+        }
+        queue.add_used(mem, index, 0);
+        queue.trigger_interrupt(mem, &interrupt);
+    }
+}
+
 fn parse_balloon_stats(reader: &mut Reader) -> BalloonStats {
     let mut stats: BalloonStats = Default::default();
     for res in reader.iter::<BalloonStat>() {
@@ -436,6 +561,136 @@ async fn handle_stats_queue(
     }
 }
 
+fn parse_balloon_ws(reader: &mut Reader) -> BalloonWS {
+    let mut ws = BalloonWS::default();
+    for res in reader.iter::<virtio_balloon_ws>() {
+        match res {
+            Ok(bucket) => {
+                if ws.ws.len() == VIRTIO_BALLOON_WS_MAX_NUM_BINS {
+                    warn!("working set report has too many bins");
+                    break;
+                }
+                ws.ws.push(WSBucket {
+                    age: bucket.idle_age_ms.to_native(),
+                    bytes: [
+                        bucket.memory_size_bytes[0].to_native(),
+                        bucket.memory_size_bytes[1].to_native(),
+                    ],
+                });
+            }
+            Err(e) => {
+                error!("error while reading working set: {}", e);
+                break;
+            }
+        }
+    }
+    ws
+}
+
+// Async task that handles the ws_data queue. The guest sends working set reports on its own
+// whenever they pass the report threshold and in response to requests; only the reports that a
+// request is waiting for are sent to the command tube.
+async fn handle_ws_data_queue(
+    mem: &GuestMemory,
+    mut queue: Queue,
+    mut queue_event: EventAsync,
+    command_tube: &AsyncTube,
+    state: Arc<AsyncMutex<BalloonState>>,
+    interrupt: Interrupt,
+) -> Result<()> {
+    loop {
+        let avail_desc = queue
+            .next_async(mem, &mut queue_event)
+            .await
+            .map_err(BalloonError::AsyncAwait)?;
+        let index = avail_desc.index;
+        match Reader::new(mem.clone(), avail_desc) {
+            Ok(mut r) => {
+                let ws = parse_balloon_ws(&mut r);
+                let mut state = state.lock().await;
+                if let Some(id) = state.ws_request_id.take() {
+                    let result = BalloonTubeResult::WorkingSet {
+                        ws,
+                        balloon_actual: (state.actual_pages as u64) << VIRTIO_BALLOON_PFN_SHIFT,
+                        id,
+                    };
+                    command_tube
+                        .send(result)
+                        .await
+                        .map_err(BalloonError::SendResponse)?;
+                }
+            }
+            Err(e) => error!("balloon: failed to CREATE Reader: {}", e),
+        };
+
+        queue.add_used(mem, index, 0);
+        queue.trigger_interrupt(mem, &interrupt);
+    }
+}
+
+fn write_ws_op(writer: &mut Writer, op: WSOp) -> std::io::Result<()> {
+    match op {
+        WSOp::Request => writer.write_obj(virtio_balloon_op {
+            type_: VIRTIO_BALLOON_WS_OP_REQUEST.into(),
+        }),
+        WSOp::Config {
+            bins,
+            refresh_threshold,
+            report_threshold,
+        } => {
+            writer.write_obj(virtio_balloon_op {
+                type_: VIRTIO_BALLOON_WS_OP_CONFIG.into(),
+            })?;
+            for bin in bins {
+                writer.write_obj(Le64::from(bin))?;
+            }
+            writer.write_obj(Le64::from(refresh_threshold))?;
+            writer.write_obj(Le64::from(report_threshold))
+        }
+    }
+}
+
+// Async task that handles the ws_op queue. The guest keeps writable buffers queued, which are
+// filled with the operations received from the command tube.
+async fn handle_ws_op_queue(
+    mem: &GuestMemory,
+    mut queue: Queue,
+    mut queue_event: EventAsync,
+    mut ws_op_rx: mpsc::Receiver<WSOp>,
+    interrupt: Interrupt,
+) -> Result<()> {
+    loop {
+        let op = match ws_op_rx.next().await {
+            Some(op) => op,
+            None => {
+                error!("working set op signal tube was closed");
+                return Ok(());
+            }
+        };
+        let avail_desc = queue
+            .next_async(mem, &mut queue_event)
+            .await
+            .map_err(BalloonError::AsyncAwait)?;
+        let index = avail_desc.index;
+        let len = match Writer::new(mem.clone(), avail_desc) {
+            Ok(mut w) => match write_ws_op(&mut w, op) {
+                Ok(()) => w.bytes_written() as u32,
+                Err(e) => {
+                    error!("failed to write working set op: {}", e);
+                    0
+                }
+            },
+            Err(e) => {
+                error!("balloon: failed to CREATE Writer: {}", e);
+                0
+            }
+        };
+
+        queue.add_used(mem, index, len);
+        queue.trigger_interrupt(mem, &interrupt);
+    }
+}
+
 async fn handle_event(
     state: Arc<AsyncMutex<BalloonState>>,
     interrupt: Interrupt,
@@ -502,7 +757,9 @@ async fn handle_command_tube(
     interrupt: Interrupt,
     state: Arc<AsyncMutex<BalloonState>>,
     mut stats_tx: mpsc::Sender<u64>,
+    mut ws_op_tx: mpsc::Sender<WSOp>,
 ) -> Result<()> {
+    let mut free_page_hint_cmd_id = VIRTIO_BALLOON_CMD_ID_DONE;
     loop {
         match command_tube.next().await {
             Ok(command) => match command {
@@ -530,6 +787,33 @@ async fn handle_command_tube(
                         error!("failed to signal the stat handler: {}", e);
                     }
                 }
+                BalloonTubeCommand::FreePageHint => {
+                    free_page_hint_cmd_id = free_page_hint_cmd_id
+                        .wrapping_add(1)
+                        .max(VIRTIO_BALLOON_CMD_ID_DONE + 1);
+                    state.lock().await.free_page_hint_cmd_id = free_page_hint_cmd_id;
+                    interrupt.signal_config_changed();
+                }
+                BalloonTubeCommand::WorkingSetConfig {
+                    bins,
+                    refresh_threshold,
+                    report_threshold,
+                } => {
+                    let op = WSOp::Config {
+                        bins,
+                        refresh_threshold,
+                        report_threshold,
+                    };
+                    if let Err(e) = ws_op_tx.try_send(op) {
+                        error!("failed to signal the working set op handler: {}", e);
+                    }
+                }
+                BalloonTubeCommand::WorkingSet { id } => {
+                    state.lock().await.ws_request_id = Some(id);
+                    if let Err(e) = ws_op_tx.try_send(WSOp::Request) {
+                        error!("failed to signal the working set op handler: {}", e);
+                    }
+                }
             },
             Err(e) => {
                 return Err(BalloonError::ReceivingCommand(e));
@@ -544,8 +828,11 @@ fn run_worker(
     inflate_queue: (Queue, Event),
     deflate_queue: (Queue, Event),
     stats_queue: Option<(Queue, Event)>,
+    free_page_queue: Option<(Queue, Event)>,
     reporting_queue: Option<(Queue, Event)>,
     events_queue: Option<(Queue, Event)>,
+    ws_data_queue: Option<(Queue, Event)>,
+    ws_op_queue: Option<(Queue, Event)>,
     command_tube: Tube,
     #[cfg(windows)] dynamic_mapping_tube: Tube,
     release_memory_tube: Option<Tube>,
@@ -617,6 +904,33 @@ fn run_worker(
         };
         pin_mut!(stats);
 
+        // The next queue is used for free page hints if VIRTIO_BALLOON_F_FREE_PAGE_HINT is
+        // negotiated.
+        let free_page = if let Some((free_page_queue, free_page_queue_evt)) = free_page_queue {
+            handle_free_page_queue(
+                &mem,
+                free_page_queue,
+                EventAsync::new(free_page_queue_evt, &ex).expect("failed to create async event"),
+                &release_memory_tube,
+                state.clone(),
+                interrupt.clone(),
+                |guest_address, len| {
+                    sys::free_memory(
+                        &guest_address,
+                        len,
+                        #[cfg(windows)]
+                        &dynamic_mapping_tube,
+                        #[cfg(unix)]
+                        &mem,
+                    )
+                },
+            )
+            .left_future()
+        } else {
+            std::future::pending().right_future()
+        };
+        pin_mut!(free_page);
+
         // The next queue is used for reporting messages
         let reporting = if let Some((reporting_queue, reporting_queue_evt)) = reporting_queue {
             handle_reporting_queue(
@@ -642,9 +956,45 @@ fn run_worker(
         };
         pin_mut!(reporting);
 
+        // The last two queues are used for working set reports and operations if
+        // VIRTIO_BALLOON_F_WS_REPORTING is negotiated.
+        let (ws_op_tx, ws_op_rx) = mpsc::channel::<WSOp>(1);
+        let ws_data = if let Some((ws_data_queue, ws_data_queue_evt)) = ws_data_queue {
+            handle_ws_data_queue(
+                &mem,
+                ws_data_queue,
+                EventAsync::new(ws_data_queue_evt, &ex).expect("failed to create async event"),
+                &command_tube,
+                state.clone(),
+                interrupt.clone(),
+            )
+            .left_future()
+        } else {
+            std::future::pending().right_future()
+        };
+        pin_mut!(ws_data);
+        let ws_op = if let Some((ws_op_queue, ws_op_queue_evt)) = ws_op_queue {
+            handle_ws_op_queue(
+                &mem,
+                ws_op_queue,
+                EventAsync::new(ws_op_queue_evt, &ex).expect("failed to create async event"),
+                ws_op_rx,
+                interrupt.clone(),
+            )
+            .left_future()
+        } else {
+            std::future::pending().right_future()
+        };
+        pin_mut!(ws_op);
+
         // Future to handle command messages that resize the balloon.
-        let command =
-            handle_command_tube(&command_tube, interrupt.clone(), state.clone(), stats_tx);
+        let command = handle_command_tube(
+            &command_tube,
+            interrupt.clone(),
+            state.clone(),
+            stats_tx,
+            ws_op_tx,
+        );
         pin_mut!(command);
 
         // Process any requests to resample the irq value.
@@ -671,9 +1021,20 @@ fn run_worker(
         };
         pin_mut!(events);
 
+        // The optional queues that don't fit in the select below.
+        let extra_queues = select4(free_page, events, ws_data, ws_op);
+        pin_mut!(extra_queues);
+
         if let Err(e) = ex
             .run_until(select8(
-                inflate, deflate, stats, reporting, command, resample, kill, events,
+                inflate,
+                deflate,
+                stats,
+                reporting,
+                command,
+                resample,
+                kill,
+                extra_queues,
             ))
             .map(|_| ())
         {
@@ -693,6 +1054,7 @@ pub struct Balloon {
     state: Arc<AsyncMutex<BalloonState>>,
     features: u64,
     acked_features: u64,
+    ws_num_bins: u8,
     kill_evt: Option<Event>,
     worker_thread: Option<thread::JoinHandle<Option<Tube>>>,
 }
@@ -712,6 +1074,8 @@ impl Balloon {
     /// by CoIOMMU to host, the release_memory_tube will be used to send the inflate
     /// ranges to CoIOMMU with UnpinRequest/UnpinResponse messages, so that The
     /// memory in the inflate range can be unpinned first.
+    /// `ws_num_bins` is the number of buckets in the working set histograms reported by the
+    /// guest when working set reporting is enabled.
     pub fn new(
         base_features: u64,
         command_tube: Tube,
@@ -720,6 +1084,7 @@ impl Balloon {
         init_balloon_size: u64,
         mode: BalloonMode,
         enabled_features: u64,
+        ws_num_bins: u8,
     ) -> Result<Balloon> {
         let features = base_features
             | 1 << VIRTIO_BALLOON_F_MUST_TELL_HOST
@@ -741,11 +1106,14 @@ impl Balloon {
                 num_pages: (init_balloon_size >> VIRTIO_BALLOON_PFN_SHIFT) as u32,
                 actual_pages: 0,
                 failable_update: false,
+                free_page_hint_cmd_id: VIRTIO_BALLOON_CMD_ID_DONE,
+                ws_request_id: None,
             })),
             kill_evt: None,
             worker_thread: None,
             features,
             acked_features: 0,
+            ws_num_bins,
         })
     }
 
@@ -754,15 +1122,26 @@ impl Balloon {
         virtio_balloon_config {
             num_pages: state.num_pages.into(),
             actual: state.actual_pages.into(),
+            free_page_hint_cmd_id: state.free_page_hint_cmd_id.into(),
+            poison_val: 0.into(),
+            ws_num_bins: self.ws_num_bins,
+            _reserved: [0; 3],
         }
     }
 
     fn num_expected_queues(acked_features: u64) -> usize {
         // mandatory inflate and deflate queues plus any optional ack'ed queues
         let queue_bits = (1 << VIRTIO_BALLOON_F_STATS_VQ)
+            | (1 << VIRTIO_BALLOON_F_FREE_PAGE_HINT)
             | (1 << VIRTIO_BALLOON_F_EVENTS_VQ)
             | (1 << VIRTIO_BALLOON_F_PAGE_REPORTING);
-        2 + (acked_features & queue_bits as u64).count_ones() as usize
+        // working set reporting adds both the ws_data and ws_op queues
+        let ws_queues = if acked_features & (1 << VIRTIO_BALLOON_F_WS_REPORTING) != 0 {
+            2
+        } else {
+            0
+        };
+        2 + (acked_features & queue_bits as u64).count_ones() as usize + ws_queues
     }
 }
 
@@ -844,6 +1223,11 @@ impl VirtioDevice for Balloon {
         } else {
             None
         };
+        let free_page_queue = if self.acked_features & (1 << VIRTIO_BALLOON_F_FREE_PAGE_HINT) != 0 {
+            Some(queues.remove(0))
+        } else {
+            None
+        };
         let reporting_queue = if self.acked_features & (1 << VIRTIO_BALLOON_F_PAGE_REPORTING) != 0 {
             Some(queues.remove(0))
         } else {
@@ -854,6 +1238,12 @@ impl VirtioDevice for Balloon {
         } else {
             None
         };
+        let (ws_data_queue, ws_op_queue) =
+            if self.acked_features & (1 << VIRTIO_BALLOON_F_WS_REPORTING) != 0 {
+                (Some(queues.remove(0)), Some(queues.remove(0)))
+            } else {
+                (None, None)
+            };
 
         let (self_kill_evt, kill_evt) = Event::new()
             .and_then(|e| Ok((e.try_clone()?, e)))
@@ -883,8 +1273,11 @@ impl VirtioDevice for Balloon {
                     inflate_queue,
                     deflate_queue,
                     stats_queue,
+                    free_page_queue,
                     reporting_queue,
                     events_queue,
+                    ws_data_queue,
+                    ws_op_queue,
                     command_tube,
                     #[cfg(windows)]
                     mapping_tube,
@@ -992,5 +1385,52 @@ mod tests {
                 VIRTIO_BALLOON_F_PAGE_REPORTING
             ]))
         );
+        assert_eq!(
+            8,
+            Balloon::num_expected_queues(to_feature_bits(&[
+                VIRTIO_BALLOON_F_STATS_VQ,
+                VIRTIO_BALLOON_F_FREE_PAGE_HINT,
+                VIRTIO_BALLOON_F_EVENTS_VQ,
+                VIRTIO_BALLOON_F_PAGE_REPORTING,
+                VIRTIO_BALLOON_F_WS_REPORTING
+            ]))
+        );
+    }
+
+    #[test]
+    fn ws_parsing() {
+        let memory = GuestMemory::new(&[(GuestAddress(0x0), 0x10000)]).unwrap();
+        for (i, age) in [1000u64, 5000].iter().enumerate() {
+            let bucket = virtio_balloon_ws {
+                idle_age_ms: (*age).into(),
+                memory_size_bytes: [(i as u64 + 1).into(), (i as u64 + 10).into()],
+                ..Default::default()
+            };
+            memory
+                .write_all_at_addr(
+                    bucket.as_slice(),
+                    GuestAddress(0x100 + (i * std::mem::size_of::<virtio_balloon_ws>()) as u64),
+                )
+                .unwrap();
+        }
+
+        let chain = create_descriptor_chain(
+            &memory,
+            GuestAddress(0x0),
+            GuestAddress(0x100),
+            vec![(
+                DescriptorType::Readable,
+                2 * std::mem::size_of::<virtio_balloon_ws>() as u32,
+            )],
+            0,
+        )
+        .expect("create_descriptor_chain failed");
+
+        let ws = parse_balloon_ws(&mut Reader::new(memory, chain).unwrap());
+        assert_eq!(ws.ws.len(), 2);
+        assert_eq!(ws.ws[0].age, 1000);
+        assert_eq!(ws.ws[0].bytes, [1, 10]);
+        assert_eq!(ws.ws[1].age, 5000);
+        assert_eq!(ws.ws[1].bytes, [2, 11]);
     }
 }
@@ -3,6 +3,7 @@
 // found in the LICENSE file.
 
 use std::cell::RefCell;
+use std::fs::File;
 use std::io;
 use std::io::Write;
 use std::mem::size_of;
@@ -49,16 +50,25 @@ use futures::stream::StreamExt;
 use remain::sorted;
 use sync::Mutex;
 use thiserror::Error as ThisError;
+use virtio_sys::virtio_config::VIRTIO_F_RING_PACKED;
 use vm_control::DiskControlCommand;
 use vm_control::DiskControlResult;
+use vm_control::DiskThrottleConfig;
 use vm_memory::GuestMemory;
 
 use crate::virtio::async_utils;
 use crate::virtio::block::sys::*;
+use crate::virtio::block::zoned::ZoneError;
+use crate::virtio::block::DiskThrottle;
+use crate::virtio::block::ZonedDisk;
+use crate::virtio::block::ZonedOption;
 use crate::virtio::copy_config;
 use crate::virtio::device_constants::block::virtio_blk_config;
 use crate::virtio::device_constants::block::virtio_blk_discard_write_zeroes;
 use crate::virtio::device_constants::block::virtio_blk_req_header;
+use crate::virtio::device_constants::block::virtio_blk_zone_descriptor;
+use crate::virtio::device_constants::block::virtio_blk_zone_report;
+use crate::virtio::device_constants::block::virtio_blk_zoned_characteristics;
 use crate::virtio::device_constants::block::VIRTIO_BLK_DISCARD_WRITE_ZEROES_FLAG_UNMAP;
 use crate::virtio::device_constants::block::VIRTIO_BLK_F_BLK_SIZE;
 use crate::virtio::device_constants::block::VIRTIO_BLK_F_DISCARD;
@@ -67,6 +77,7 @@ use crate::virtio::device_constants::block::VIRTIO_BLK_F_MQ;
 use crate::virtio::device_constants::block::VIRTIO_BLK_F_RO;
 use crate::virtio::device_constants::block::VIRTIO_BLK_F_SEG_MAX;
 use crate::virtio::device_constants::block::VIRTIO_BLK_F_WRITE_ZEROES;
+use crate::virtio::device_constants::block::VIRTIO_BLK_F_ZONED;
 use crate::virtio::device_constants::block::VIRTIO_BLK_S_IOERR;
 use crate::virtio::device_constants::block::VIRTIO_BLK_S_OK;
 use crate::virtio::device_constants::block::VIRTIO_BLK_S_UNSUPP;
@@ -76,6 +87,14 @@ use crate::virtio::device_constants::block::VIRTIO_BLK_T_GET_ID;
 use crate::virtio::device_constants::block::VIRTIO_BLK_T_IN;
 use crate::virtio::device_constants::block::VIRTIO_BLK_T_OUT;
 use crate::virtio::device_constants::block::VIRTIO_BLK_T_WRITE_ZEROES;
+use crate::virtio::device_constants::block::VIRTIO_BLK_T_ZONE_APPEND;
+use crate::virtio::device_constants::block::VIRTIO_BLK_T_ZONE_CLOSE;
+use crate::virtio::device_constants::block::VIRTIO_BLK_T_ZONE_FINISH;
+use crate::virtio::device_constants::block::VIRTIO_BLK_T_ZONE_OPEN;
+use crate::virtio::device_constants::block::VIRTIO_BLK_T_ZONE_REPORT;
+use crate::virtio::device_constants::block::VIRTIO_BLK_T_ZONE_RESET;
+use crate::virtio::device_constants::block::VIRTIO_BLK_T_ZONE_RESET_ALL;
+use crate::virtio::device_constants::block::VIRTIO_BLK_Z_HM;
 use crate::virtio::vhost::user::device::VhostBackendReqConnectionState;
 use crate::virtio::DescriptorChain;
 use crate::virtio::DescriptorError;
@@ -103,6 +122,11 @@ const MAX_WRITE_ZEROES_SEG: u32 = 32;
 // but this should probably be based on cluster size for qcow.
 const DISCARD_SECTOR_ALIGNMENT: u32 = 128;
 
+// Returns the number of sectors needed to hold `len` bytes.
+fn sectors_of(len: usize) -> u64 {
+    (len as u64 + SECTOR_SIZE - 1) >> SECTOR_SHIFT
+}
+
 #[sorted]
 #[derive(ThisError, Debug)]
 pub enum ExecuteError {
@@ -137,6 +161,8 @@ pub enum ExecuteError {
     ReceivingCommand(TubeError),
     #[error("failed to send command response: {0}")]
     SendingResponse(TubeError),
+    #[error("failed to wait for the rate limit: {0}")]
+    Throttle(cros_async::Error),
     #[error("couldn't reset the timer: {0}")]
     TimerReset(base::Error),
     #[error("unsupported ({0})")]
@@ -149,6 +175,10 @@ pub enum ExecuteError {
     },
     #[error("failed to write request status: {0}")]
     WriteStatus(io::Error),
+    #[error("failed to write zone information: {0}")]
+    WriteZoneInfo(io::Error),
+    #[error("zoned request failed: {0}")]
+    Zone(ZoneError),
 }
 
 impl ExecuteError {
@@ -165,10 +195,13 @@ impl ExecuteError {
             ExecuteError::ReadOnly { .. } => VIRTIO_BLK_S_IOERR,
             ExecuteError::ReceivingCommand(_) => VIRTIO_BLK_S_IOERR,
             ExecuteError::SendingResponse(_) => VIRTIO_BLK_S_IOERR,
+            ExecuteError::Throttle(_) => VIRTIO_BLK_S_IOERR,
             ExecuteError::TimerReset(_) => VIRTIO_BLK_S_IOERR,
             ExecuteError::WriteIo { .. } => VIRTIO_BLK_S_IOERR,
             ExecuteError::WriteStatus(_) => VIRTIO_BLK_S_IOERR,
+            ExecuteError::WriteZoneInfo(_) => VIRTIO_BLK_S_IOERR,
             ExecuteError::Unsupported(_) => VIRTIO_BLK_S_UNSUPP,
+            ExecuteError::Zone(e) => e.status(),
         }
     }
 }
@@ -205,6 +238,8 @@ pub struct DiskState {
     pub read_only: bool,
     pub sparse: bool,
     pub id: Option<BlockId>,
+    pub throttle: RefCell<DiskThrottle>,
+    pub zones: Option<Arc<Mutex<ZonedDisk>>>,
 }
 
 impl DiskState {
@@ -215,6 +250,8 @@ impl DiskState {
         read_only: bool,
         sparse: bool,
         id: Option<BlockId>,
+        throttle: DiskThrottle,
+        zones: Option<Arc<Mutex<ZonedDisk>>>,
     ) -> DiskState {
         DiskState {
             disk_image,
@@ -222,11 +259,14 @@ impl DiskState {
             read_only,
             sparse,
             id,
+            throttle: RefCell::new(throttle),
+            zones,
         }
     }
 }
 
 async fn process_one_request(
+    ex: &Executor,
     avail_desc: DescriptorChain,
     disk_state: Rc<AsyncMutex<DiskState>>,
     flush_timer: Rc<RefCell<TimerAsync>>,
@@ -247,6 +287,7 @@ async fn process_one_request(
     let mut status_writer = writer.split_at(status_offset);
 
     let status = match BlockAsync::execute_request(
+        ex,
         &mut reader,
         &mut writer,
         disk_state,
@@ -272,6 +313,7 @@ async fn process_one_request(
 
 /// Process one descriptor chain asynchronously.
 pub async fn process_one_chain<I: SignalableInterrupt>(
+    ex: &Executor,
     queue: Rc<RefCell<Queue>>,
     avail_desc: DescriptorChain,
     disk_state: Rc<AsyncMutex<DiskState>>,
@@ -281,16 +323,22 @@ pub async fn process_one_chain<I: SignalableInterrupt>(
     flush_timer_armed: Rc<RefCell<bool>>,
 ) {
     let descriptor_index = avail_desc.index;
-    let len =
-        match process_one_request(avail_desc, disk_state, flush_timer, flush_timer_armed, &mem)
-            .await
-        {
-            Ok(len) => len,
-            Err(e) => {
-                error!("block: failed to handle request: {}", e);
-                0
-            }
-        };
+    let len = match process_one_request(
+        ex,
+        avail_desc,
+        disk_state,
+        flush_timer,
+        flush_timer_armed,
+        &mem,
+    )
+    .await
+    {
+        Ok(len) => len,
+        Err(e) => {
+            error!("block: failed to handle request: {}", e);
+            0
+        }
+    };
 
     let mut queue = queue.borrow_mut();
     queue.add_used(&mem, descriptor_index, len as u32);
@@ -323,8 +371,10 @@ pub async fn handle_queue<I: SignalableInterrupt + 'static>(
             let flush_timer = Rc::clone(&flush_timer);
             let flush_timer_armed = Rc::clone(&flush_timer_armed);
 
+            let chain_ex = ex.clone();
             ex.spawn_local(async move {
                 process_one_chain(
+                    &chain_ex,
                     queue,
                     descriptor_chain,
                     disk_state,
@@ -342,12 +392,14 @@ pub async fn handle_queue<I: SignalableInterrupt + 'static>(
 
 /// handles the disk control requests from the vhost user backend control server.
 pub async fn handle_vhost_user_command_tube(
+    ex: Executor,
     command_tube: AsyncTube,
     backend_req_connection: Arc<Mutex<VhostBackendReqConnectionState>>,
     disk_state: Rc<AsyncMutex<DiskState>>,
 ) -> Result<(), ExecuteError> {
     // Process the commands.
     handle_command_tube(
+        &ex,
         &Some(command_tube),
         ConfigChangeSignal::VhostUserBackendRequest(backend_req_connection),
         Rc::clone(&disk_state),
@@ -361,6 +413,7 @@ enum ConfigChangeSignal {
 }
 
 async fn handle_command_tube(
+    ex: &Executor,
     command_tube: &Option<AsyncTube>,
     signal: ConfigChangeSignal,
     disk_state: Rc<AsyncMutex<DiskState>>,
@@ -375,10 +428,35 @@ async fn handle_command_tube(
     loop {
         match command_tube.next().await {
             Ok(command) => {
+                // Only a resize changes the device config seen by the guest.
+                let config_changed = matches!(command, DiskControlCommand::Resize { .. });
                 let resp = match command {
                     DiskControlCommand::Resize { new_size } => {
                         resize(Rc::clone(&disk_state), new_size).await
                     }
+                    DiskControlCommand::Snapshot { name } => {
+                        snapshot(Rc::clone(&disk_state), name).await
+                    }
+                    DiskControlCommand::DeleteSnapshot { name } => {
+                        delete_snapshot(Rc::clone(&disk_state), name).await
+                    }
+                    DiskControlCommand::Throttle { limits } => {
+                        throttle(Rc::clone(&disk_state), limits).await
+                    }
+                    DiskControlCommand::ExternalSnapshot {
+                        overlay,
+                        backing_file,
+                        backing_file_path,
+                    } => {
+                        external_snapshot(
+                            ex,
+                            Rc::clone(&disk_state),
+                            overlay,
+                            backing_file,
+                            backing_file_path,
+                        )
+                        .await
+                    }
                 };
 
                 let resp_clone = resp.clone();
@@ -386,7 +464,7 @@ async fn handle_command_tube(
                     .send(resp_clone)
                     .await
                     .map_err(ExecuteError::SendingResponse)?;
-                if let DiskControlResult::Ok = resp {
+                if config_changed && matches!(resp, DiskControlResult::Ok) {
                     match &signal {
                         ConfigChangeSignal::Interrupt(interrupt) => {
                             interrupt.signal_config_changed();
@@ -421,6 +499,11 @@ async fn resize(disk_state: Rc<AsyncMutex<DiskState>>, new_size: u64) -> DiskCon
         return DiskControlResult::Err(SysError::new(libc::EROFS));
     }
 
+    if disk_state.zones.is_some() {
+        error!("Attempted to resize zoned block device");
+        return DiskControlResult::Err(SysError::new(libc::ENOTSUP));
+    }
+
     info!("Resizing block device to {} bytes", new_size);
 
     if let Err(e) = disk_state.disk_image.set_len(new_size) {
@@ -442,6 +525,121 @@ async fn resize(disk_state: Rc<AsyncMutex<DiskState>>, new_size: u64) -> DiskCon
     DiskControlResult::Ok
 }
 
+async fn snapshot(disk_state: Rc<AsyncMutex<DiskState>>, name: String) -> DiskControlResult {
+    // Hold the state lock so no requests are processed while the snapshot is taken.
+    let disk_state = disk_state.lock().await;
+
+    if disk_state.read_only {
+        error!("Attempted to snapshot read-only block device");
+        return DiskControlResult::Err(SysError::new(libc::EROFS));
+    }
+
+    info!("Creating block device snapshot {}", name);
+
+    match disk_state.disk_image.create_internal_snapshot(&name).await {
+        Ok(()) => DiskControlResult::Ok,
+        Err(disk::Error::UnsupportedOperation) => {
+            error!("Block device doesn't support internal snapshots");
+            DiskControlResult::Err(SysError::new(libc::ENOTSUP))
+        }
+        Err(e) => {
+            error!("Creating snapshot {} failed! {}", name, e);
+            DiskControlResult::Err(SysError::new(libc::EIO))
+        }
+    }
+}
+
+async fn delete_snapshot(disk_state: Rc<AsyncMutex<DiskState>>, name: String) -> DiskControlResult {
+    let disk_state = disk_state.lock().await;
+
+    if disk_state.read_only {
+        error!("Attempted to delete a snapshot of read-only block device");
+        return DiskControlResult::Err(SysError::new(libc::EROFS));
+    }
+
+    info!("Deleting block device snapshot {}", name);
+
+    match disk_state.disk_image.delete_internal_snapshot(&name).await {
+        Ok(()) => DiskControlResult::Ok,
+        Err(disk::Error::UnsupportedOperation) => {
+            error!("Block device doesn't support internal snapshots");
+            DiskControlResult::Err(SysError::new(libc::ENOTSUP))
+        }
+        Err(e) => {
+            error!("Deleting snapshot {} failed! {}", name, e);
+            DiskControlResult::Err(SysError::new(libc::EIO))
+        }
+    }
+}
+
+async fn external_snapshot(
+    ex: &Executor,
+    disk_state: Rc<AsyncMutex<DiskState>>,
+    overlay: File,
+    backing_file: File,
+    backing_file_path: String,
+) -> DiskControlResult {
+    // Hold the state lock so no requests are processed while the disk is switched.
+    let mut disk_state = disk_state.lock().await;
+
+    if disk_state.read_only {
+        error!("Attempted to switch read-only block device to an overlay");
+        return DiskControlResult::Err(SysError::new(libc::EROFS));
+    }
+
+    // Completed writes must reach the current image before it becomes the backing file.
+    if let Err(e) = disk_state.disk_image.fsync().await {
+        error!("Flushing disk before switching to an overlay failed! {}", e);
+        return DiskControlResult::Err(SysError::new(libc::EIO));
+    }
+
+    info!(
+        "Switching block device to a new overlay on top of {}",
+        backing_file_path
+    );
+
+    match disk::switch_to_overlay(
+        &mut disk_state.disk_image,
+        overlay,
+        &backing_file,
+        &backing_file_path,
+        ex,
+    ) {
+        Ok(()) => DiskControlResult::Ok,
+        Err(disk::Error::BackingFileMismatch) => {
+            error!(
+                "{} is not the current image of the block device",
+                backing_file_path
+            );
+            DiskControlResult::Err(SysError::new(libc::EINVAL))
+        }
+        Err(disk::Error::RelativeBackingFile(_)) => {
+            error!("{} is not an absolute path", backing_file_path);
+            DiskControlResult::Err(SysError::new(libc::EINVAL))
+        }
+        Err(disk::Error::UnsupportedOperation) => {
+            error!("Block device doesn't support overlays");
+            DiskControlResult::Err(SysError::new(libc::ENOTSUP))
+        }
+        Err(e) => {
+            error!("Switching to overlay failed! {}", e);
+            DiskControlResult::Err(SysError::new(libc::EIO))
+        }
+    }
+}
+
+async fn throttle(
+    disk_state: Rc<AsyncMutex<DiskState>>,
+    limits: DiskThrottleConfig,
+) -> DiskControlResult {
+    let disk_state = disk_state.lock().await;
+
+    info!("Setting block device rate limits to {:?}", limits);
+
+    *disk_state.throttle.borrow_mut() = DiskThrottle::new(&limits);
+    DiskControlResult::Ok
+}
+
 /// Periodically flushes the disk when the given timer fires.
 pub async fn flush_disk(
     disk_state: Rc<AsyncMutex<DiskState>>,
@@ -493,6 +691,7 @@ fn run_worker(
 
     // Handles control requests.
     let control = handle_command_tube(
+        &ex,
         control_tube,
         ConfigChangeSignal::Interrupt(interrupt.clone()),
         disk_state.clone(),
@@ -566,8 +765,11 @@ pub struct BlockAsync {
     pub(crate) control_tube: Option<Tube>,
     pub(crate) queue_sizes: Vec<u16>,
     pub(crate) executor_kind: ExecutorKind,
+    pub(crate) throttle: DiskThrottleConfig,
+    pub(crate) zones: Option<Arc<Mutex<ZonedDisk>>>,
     kill_evt: Option<Event>,
-    worker_thread: Option<thread::JoinHandle<(Box<dyn DiskFile>, Option<Tube>)>>,
+    worker_thread:
+        Option<thread::JoinHandle<(Box<dyn DiskFile>, Option<Tube>, DiskThrottleConfig)>>,
 }
 
 impl BlockAsync {
@@ -583,6 +785,8 @@ impl BlockAsync {
         queue_size: Option<u16>,
         executor_kind: Option<ExecutorKind>,
         num_queues: Option<u16>,
+        throttle: DiskThrottleConfig,
+        zoned: Option<ZonedOption>,
     ) -> SysResult<BlockAsync> {
         if block_size % SECTOR_SIZE as u32 != 0 {
             error!(
@@ -612,8 +816,27 @@ impl BlockAsync {
         }
         let queue_sizes = vec![q_size; num_queues as usize];
 
-        let avail_features =
-            Self::build_avail_features(base_features, read_only, sparse, multi_queue);
+        // Zoned host devices are always exposed with their own zones.
+        let zones = match host_zoned_disk(&*disk_image, disk_size)? {
+            Some(host_zones) => {
+                if zoned.is_some() {
+                    error!("A zone layout can't be emulated on a zoned host device.");
+                    return Err(SysError::new(libc::EINVAL));
+                }
+                Some(host_zones)
+            }
+            None => zoned
+                .map(|zoned| ZonedDisk::new_emulated(&zoned, disk_size, block_size))
+                .transpose()?,
+        };
+
+        let avail_features = Self::build_avail_features(
+            base_features,
+            read_only,
+            sparse,
+            multi_queue,
+            zones.is_some(),
+        );
 
         let seg_max = get_seg_max(q_size);
         let executor_kind = executor_kind.unwrap_or_default();
@@ -632,6 +855,8 @@ impl BlockAsync {
             worker_thread: None,
             control_tube,
             executor_kind,
+            throttle,
+            zones: zones.map(|z| Arc::new(Mutex::new(z))),
         })
     }
 
@@ -641,22 +866,30 @@ impl BlockAsync {
         read_only: bool,
         sparse: bool,
         multi_queue: bool,
+        zoned: bool,
     ) -> u64 {
         let mut avail_features = base_features;
         if read_only {
             avail_features |= 1 << VIRTIO_BLK_F_RO;
         } else {
-            if sparse {
+            // Discards and zeroes could be written anywhere, which zoned devices don't allow.
+            if sparse && !zoned {
                 avail_features |= 1 << VIRTIO_BLK_F_DISCARD;
             }
             avail_features |= 1 << VIRTIO_BLK_F_FLUSH;
-            avail_features |= 1 << VIRTIO_BLK_F_WRITE_ZEROES;
+            if !zoned {
+                avail_features |= 1 << VIRTIO_BLK_F_WRITE_ZEROES;
+            }
+        }
+        if zoned {
+            avail_features |= 1 << VIRTIO_BLK_F_ZONED;
         }
         avail_features |= 1 << VIRTIO_BLK_F_SEG_MAX;
         avail_features |= 1 << VIRTIO_BLK_F_BLK_SIZE;
         if multi_queue {
             avail_features |= 1 << VIRTIO_BLK_F_MQ;
         }
+        avail_features |= 1 << VIRTIO_F_RING_PACKED;
         avail_features
     }
 
@@ -665,21 +898,47 @@ impl BlockAsync {
     // It is up to the caller to convert the result of this function into a status byte
     // and write it to the expected location in guest memory.
     async fn execute_request(
+        ex: &Executor,
         reader: &mut Reader,
         writer: &mut Writer,
         disk_state: Rc<AsyncMutex<DiskState>>,
         flush_timer: Rc<RefCell<TimerAsync>>,
         flush_timer_armed: Rc<RefCell<bool>>,
     ) -> result::Result<(), ExecuteError> {
-        // Acquire immutable access to disk_state to prevent the disk from being resized.
-        let disk_state = disk_state.read_lock().await;
-
         let req_header: virtio_blk_req_header = reader.read_obj().map_err(ExecuteError::Read)?;
 
         let req_type = req_header.req_type.to_native();
         let sector = req_header.sector.to_native();
 
-        if disk_state.read_only && req_type != VIRTIO_BLK_T_IN && req_type != VIRTIO_BLK_T_GET_ID {
+        // Delay reads and writes that exceed the rate limits. The state isn't locked while waiting
+        // so that control commands can still be processed.
+        let throttled = match req_type {
+            VIRTIO_BLK_T_IN => Some((false, writer.available_bytes())),
+            VIRTIO_BLK_T_OUT | VIRTIO_BLK_T_ZONE_APPEND => Some((true, reader.available_bytes())),
+            _ => None,
+        };
+        if let Some((write, data_len)) = throttled {
+            let delay = disk_state
+                .read_lock()
+                .await
+                .throttle
+                .borrow_mut()
+                .request_delay(write, data_len as u64);
+            if !delay.is_zero() {
+                TimerAsync::sleep(ex, delay)
+                    .await
+                    .map_err(ExecuteError::Throttle)?;
+            }
+        }
+
+        // Acquire immutable access to disk_state to prevent the disk from being resized.
+        let disk_state = disk_state.read_lock().await;
+
+        if disk_state.read_only
+            && req_type != VIRTIO_BLK_T_IN
+            && req_type != VIRTIO_BLK_T_GET_ID
+            && req_type != VIRTIO_BLK_T_ZONE_REPORT
+        {
             return Err(ExecuteError::ReadOnly {
                 request_type: req_type,
             });
@@ -702,6 +961,34 @@ impl BlockAsync {
             }
         }
 
+        // Start the timer that flushes written data if it isn't already running.
+        fn arm_flush_timer(
+            flush_timer: &RefCell<TimerAsync>,
+            flush_timer_armed: &RefCell<bool>,
+        ) -> result::Result<(), ExecuteError> {
+            if !*flush_timer_armed.borrow() {
+                *flush_timer_armed.borrow_mut() = true;
+
+                let flush_delay = Duration::from_secs(60);
+                flush_timer
+                    .borrow_mut()
+                    .reset(flush_delay, None)
+                    .map_err(ExecuteError::TimerReset)?;
+            }
+            Ok(())
+        }
+
+        // Returns the zones of the disk, which only zoned devices support requests for.
+        fn zones(
+            disk_state: &DiskState,
+            req_type: u32,
+        ) -> result::Result<&Mutex<ZonedDisk>, ExecuteError> {
+            disk_state
+                .zones
+                .as_deref()
+                .ok_or(ExecuteError::Unsupported(req_type))
+        }
+
         let disk_size = disk_state.disk_size.load(Ordering::Relaxed);
         match req_type {
             VIRTIO_BLK_T_IN => {
@@ -732,24 +1019,109 @@ impl BlockAsync {
                     .checked_shl(u32::from(SECTOR_SHIFT))
                     .ok_or(ExecuteError::OutOfRange)?;
                 check_range(offset, data_len as u64, disk_size)?;
+                let zone_write = match &disk_state.zones {
+                    Some(zones) => zones
+                        .lock()
+                        .write(sector, sectors_of(data_len))
+                        .map_err(ExecuteError::Zone)?,
+                    None => None,
+                };
                 let disk_image = &disk_state.disk_image;
-                reader
+                let result = reader
                     .read_exact_to_at_fut(&**disk_image, data_len, offset)
-                    .await
-                    .map_err(|desc_error| ExecuteError::WriteIo {
-                        length: data_len,
-                        sector,
-                        desc_error,
-                    })?;
-
-                if !*flush_timer_armed.borrow() {
-                    *flush_timer_armed.borrow_mut() = true;
+                    .await;
+                // The write pointer only moves past the sectors that were written.
+                let completed = match (&disk_state.zones, zone_write) {
+                    (Some(zones), Some(zone_write)) => {
+                        zones.lock().complete(zone_write, result.is_ok())
+                    }
+                    _ => Ok(()),
+                };
+                result.map_err(|desc_error| ExecuteError::WriteIo {
+                    length: data_len,
+                    sector,
+                    desc_error,
+                })?;
+                completed.map_err(ExecuteError::Zone)?;
+
+                arm_flush_timer(&flush_timer, &flush_timer_armed)?;
+            }
+            VIRTIO_BLK_T_ZONE_APPEND => {
+                let zones = zones(&disk_state, req_type)?;
+                let data_len = reader.available_bytes();
+                let (append_sector, zone_write) = zones
+                    .lock()
+                    .append(sector, sectors_of(data_len))
+                    .map_err(ExecuteError::Zone)?;
+                let offset = append_sector << SECTOR_SHIFT;
+                let disk_image = &disk_state.disk_image;
+                let result = reader
+                    .read_exact_to_at_fut(&**disk_image, data_len, offset)
+                    .await;
+                let completed = zones.lock().complete(zone_write, result.is_ok());
+                result.map_err(|desc_error| ExecuteError::WriteIo {
+                    length: data_len,
+                    sector: append_sector,
+                    desc_error,
+                })?;
+                completed.map_err(ExecuteError::Zone)?;
+                // The sector the data was written to precedes the status byte.
+                writer
+                    .write_obj(Le64::from(append_sector))
+                    .map_err(ExecuteError::WriteZoneInfo)?;
 
-                    let flush_delay = Duration::from_secs(60);
-                    flush_timer
-                        .borrow_mut()
-                        .reset(flush_delay, None)
-                        .map_err(ExecuteError::TimerReset)?;
+                arm_flush_timer(&flush_timer, &flush_timer_armed)?;
+            }
+            VIRTIO_BLK_T_ZONE_REPORT => {
+                let zones = zones(&disk_state, req_type)?;
+                let max_zones = writer
+                    .available_bytes()
+                    .saturating_sub(size_of::<virtio_blk_zone_report>())
+                    / size_of::<virtio_blk_zone_descriptor>();
+                let descriptors = zones.lock().report(sector, max_zones);
+                writer
+                    .write_obj(virtio_blk_zone_report {
+                        nr_zones: Le64::from(descriptors.len() as u64),
+                        reserved: [0; 56],
+                    })
+                    .map_err(ExecuteError::WriteZoneInfo)?;
+                for descriptor in descriptors {
+                    writer
+                        .write_obj(descriptor)
+                        .map_err(ExecuteError::WriteZoneInfo)?;
+                }
+            }
+            VIRTIO_BLK_T_ZONE_OPEN | VIRTIO_BLK_T_ZONE_CLOSE | VIRTIO_BLK_T_ZONE_FINISH => {
+                let mut zones = zones(&disk_state, req_type)?.lock();
+                match req_type {
+                    VIRTIO_BLK_T_ZONE_OPEN => zones.open(sector),
+                    VIRTIO_BLK_T_ZONE_CLOSE => zones.close(sector),
+                    _ => zones.finish(sector),
+                }
+                .map_err(ExecuteError::Zone)?;
+            }
+            VIRTIO_BLK_T_ZONE_RESET | VIRTIO_BLK_T_ZONE_RESET_ALL => {
+                let reset = {
+                    let mut zones = zones(&disk_state, req_type)?.lock();
+                    if req_type == VIRTIO_BLK_T_ZONE_RESET {
+                        zones.reset(sector).map(|r| r.into_iter().collect())
+                    } else {
+                        zones.reset_all()
+                    }
+                }
+                .map_err(ExecuteError::Zone)?;
+                // Emulated zones read back as zeroes once they are reset.
+                for (sector, num_sectors) in reset {
+                    disk_state
+                        .disk_image
+                        .write_zeroes_at(sector << SECTOR_SHIFT, num_sectors << SECTOR_SHIFT)
+                        .await
+                        .map_err(|e| ExecuteError::DiscardWriteZeroes {
+                            ioerr: Some(e),
+                            sector,
+                            num_sectors: num_sectors as u32,
+                            flags: 0,
+                        })?;
                 }
             }
             VIRTIO_BLK_T_DISCARD | VIRTIO_BLK_T_WRITE_ZEROES => {
@@ -813,6 +1185,9 @@ impl BlockAsync {
                     .fsync()
                     .await
                     .map_err(ExecuteError::Flush)?;
+                if let Some(zones) = &disk_state.zones {
+                    zones.lock().sync().map_err(ExecuteError::Zone)?;
+                }
             }
             VIRTIO_BLK_T_GET_ID => {
                 if let Some(id) = disk_state.id {
@@ -832,7 +1207,20 @@ impl BlockAsync {
         seg_max: u32,
         block_size: u32,
         num_queues: u16,
+        zones: Option<&ZonedDisk>,
     ) -> virtio_blk_config {
+        let zoned = zones.map_or_else(Default::default, |zones| {
+            let zone_sectors = Le32::from(zones.zone_sectors() as u32);
+            virtio_blk_zoned_characteristics {
+                zone_sectors,
+                max_open_zones: Le32::from(zones.max_open_zones()),
+                max_active_zones: Le32::from(zones.max_active_zones()),
+                max_append_sectors: zone_sectors,
+                write_granularity: Le32::from(block_size),
+                model: VIRTIO_BLK_Z_HM,
+                unused2: [0; 3],
+            }
+        });
         virtio_blk_config {
             // If the image is not a multiple of the sector size, the tail bits are not exposed.
             capacity: Le64::from(disk_size >> SECTOR_SHIFT),
@@ -845,6 +1233,7 @@ impl BlockAsync {
             write_zeroes_may_unmap: 1,
             max_discard_seg: Le32::from(MAX_DISCARD_SEG),
             max_write_zeroes_seg: Le32::from(MAX_WRITE_ZEROES_SEG),
+            zoned,
             ..Default::default()
         }
     }
@@ -875,6 +1264,10 @@ impl VirtioDevice for BlockAsync {
             keep_rds.push(control_tube.as_raw_descriptor());
         }
 
+        if let Some(zones) = &self.zones {
+            keep_rds.extend(zones.lock().as_raw_descriptors());
+        }
+
         keep_rds
     }
 
@@ -893,11 +1286,13 @@ impl VirtioDevice for BlockAsync {
     fn read_config(&self, offset: u64, data: &mut [u8]) {
         let config_space = {
             let disk_size = self.disk_size.load(Ordering::Acquire);
+            let zones = self.zones.as_ref().map(|z| z.lock());
             Self::build_config_space(
                 disk_size,
                 self.seg_max,
                 self.block_size,
                 self.queue_sizes.len() as u16,
+                zones.as_deref(),
             )
         };
         copy_config(data, 0, config_space.as_slice(), offset);
@@ -919,6 +1314,8 @@ impl VirtioDevice for BlockAsync {
         let disk_size = self.disk_size.clone();
         let id = self.id.take();
         let executor_kind = self.executor_kind;
+        let throttle = DiskThrottle::new(&self.throttle);
+        let zones = self.zones.clone();
         let disk_image = self.disk_image.take().context("missing disk image")?;
         let control_tube = self.control_tube.take();
         let worker_thread = thread::Builder::new()
@@ -939,6 +1336,8 @@ impl VirtioDevice for BlockAsync {
                     read_only,
                     sparse,
                     id,
+                    throttle: RefCell::new(throttle),
+                    zones,
                 }));
                 if let Err(err_string) = run_worker(
                     ex,
@@ -956,9 +1355,11 @@ impl VirtioDevice for BlockAsync {
                     Ok(d) => d.into_inner(),
                     Err(_) => panic!("too many refs to the disk"),
                 };
+                // The limits may have been changed through the control tube.
                 (
                     disk_state.disk_image.into_inner(),
                     async_control.map(|c| c.into()),
+                    disk_state.throttle.into_inner().config(),
                 )
             })
             .context("failed to spawn virtio_blk worker")?;
@@ -981,9 +1382,10 @@ impl VirtioDevice for BlockAsync {
                     error!("{}: failed to get back resources", self.debug_label());
                     return false;
                 }
-                Ok((disk_image, control_tube)) => {
+                Ok((disk_image, control_tube, throttle)) => {
                     self.disk_image = Some(disk_image);
                     self.control_tube = control_tube;
+                    self.throttle = throttle;
                     return true;
                 }
             }
@@ -1012,6 +1414,10 @@ mod tests {
     use crate::virtio::base_features;
     use crate::virtio::descriptor_utils::create_descriptor_chain;
     use crate::virtio::descriptor_utils::DescriptorType;
+    use crate::virtio::device_constants::block::VIRTIO_BLK_S_ZONE_INVALID_CMD;
+    use crate::virtio::device_constants::block::VIRTIO_BLK_S_ZONE_UNALIGNED_WP;
+    use crate::virtio::device_constants::block::VIRTIO_BLK_ZS_FULL;
+    use crate::virtio::device_constants::block::VIRTIO_BLK_ZS_IOPEN;
 
     #[test]
     fn read_size() {
@@ -1033,6 +1439,8 @@ mod tests {
             None,
             None,
             None,
+            Default::default(),
+            None,
         )
         .unwrap();
         let mut num_sectors = [0u8; 4];
@@ -1065,6 +1473,8 @@ mod tests {
             None,
             None,
             None,
+            Default::default(),
+            None,
         )
         .unwrap();
         let mut blk_size = [0u8; 4];
@@ -1094,12 +1504,15 @@ mod tests {
                 None,
                 None,
                 None,
+                Default::default(),
+                None,
             )
             .unwrap();
             // writable device should set VIRTIO_BLK_F_FLUSH + VIRTIO_BLK_F_DISCARD
             // + VIRTIO_BLK_F_WRITE_ZEROES + VIRTIO_F_VERSION_1 + VIRTIO_BLK_F_BLK_SIZE
             // + VIRTIO_BLK_F_SEG_MAX + VIRTIO_BLK_F_MQ + VIRTIO_RING_F_EVENT_IDX
-            assert_eq!(0x120007244, b.features());
+            // + VIRTIO_F_RING_PACKED
+            assert_eq!(0x520007244, b.features());
         }
 
         // read-write block device, non-sparse
@@ -1117,12 +1530,14 @@ mod tests {
                 None,
                 None,
                 None,
+                Default::default(),
+                None,
             )
             .unwrap();
             // writable device should set VIRTIO_F_FLUSH + VIRTIO_BLK_F_RO
             // + VIRTIO_F_VERSION_1 + VIRTIO_BLK_F_BLK_SIZE + VIRTIO_BLK_F_SEG_MAX
-            // + VIRTIO_BLK_F_MQ + VIRTIO_RING_F_EVENT_IDX
-            assert_eq!(0x120005244, b.features());
+            // + VIRTIO_BLK_F_MQ + VIRTIO_RING_F_EVENT_IDX + VIRTIO_F_RING_PACKED
+            assert_eq!(0x520005244, b.features());
         }
 
         // read-only block device
@@ -1140,12 +1555,45 @@ mod tests {
                 None,
                 None,
                 None,
+                Default::default(),
+                None,
             )
             .unwrap();
             // read-only device should set VIRTIO_BLK_F_RO
             // + VIRTIO_F_VERSION_1 + VIRTIO_BLK_F_BLK_SIZE + VIRTIO_BLK_F_SEG_MAX
-            // + VIRTIO_BLK_F_MQ + VIRTIO_RING_F_EVENT_IDX
-            assert_eq!(0x120001064, b.features());
+            // + VIRTIO_BLK_F_MQ + VIRTIO_RING_F_EVENT_IDX + VIRTIO_F_RING_PACKED
+            assert_eq!(0x520001064, b.features());
+        }
+
+        // zoned block device
+        {
+            let f = File::create(&path).unwrap();
+            f.set_len(0x10_0000).unwrap();
+            let features = base_features(ProtectionType::Unprotected);
+            let b = BlockAsync::new(
+                features,
+                Box::new(f),
+                false,
+                true,
+                512,
+                None,
+                None,
+                None,
+                None,
+                None,
+                Default::default(),
+                Some(ZonedOption {
+                    zone_size: 0x1_0000,
+                    state_file: Some(tempdir.path().join("zones")),
+                    ..Default::default()
+                }),
+            )
+            .unwrap();
+            // zoned device should set VIRTIO_BLK_F_ZONED + VIRTIO_BLK_F_FLUSH
+            // + VIRTIO_F_VERSION_1 + VIRTIO_BLK_F_BLK_SIZE + VIRTIO_BLK_F_SEG_MAX
+            // + VIRTIO_BLK_F_MQ + VIRTIO_RING_F_EVENT_IDX + VIRTIO_F_RING_PACKED, but not
+            // discard or write zeroes.
+            assert_eq!(0x520021244, b.features());
         }
     }
 
@@ -1169,6 +1617,8 @@ mod tests {
             None,
             None,
             None,
+            Default::default(),
+            None,
         )
         .unwrap();
         assert_eq!(
@@ -1189,6 +1639,8 @@ mod tests {
             Some(128),
             None,
             Some(1),
+            Default::default(),
+            None,
         )
         .unwrap();
         assert_eq!([128; 1], b.queue_max_sizes());
@@ -1254,9 +1706,18 @@ mod tests {
             read_only: false,
             sparse: true,
             id: None,
+            throttle: RefCell::new(DiskThrottle::new(&Default::default())),
+            zones: None,
         }));
 
-        let fut = process_one_request(avail_desc, disk_state, flush_timer, flush_timer_armed, &mem);
+        let fut = process_one_request(
+            &ex,
+            avail_desc,
+            disk_state,
+            flush_timer,
+            flush_timer_armed,
+            &mem,
+        );
 
         ex.run_until(fut)
             .expect("running executor failed")
@@ -1323,9 +1784,18 @@ mod tests {
             read_only: false,
             sparse: true,
             id: None,
+            throttle: RefCell::new(DiskThrottle::new(&Default::default())),
+            zones: None,
         }));
 
-        let fut = process_one_request(avail_desc, disk_state, flush_timer, flush_timer_armed, &mem);
+        let fut = process_one_request(
+            &ex,
+            avail_desc,
+            disk_state,
+            flush_timer,
+            flush_timer_armed,
+            &mem,
+        );
 
         ex.run_until(fut)
             .expect("running executor failed")
@@ -1394,9 +1864,18 @@ mod tests {
             read_only: false,
             sparse: true,
             id: Some(*id),
+            throttle: RefCell::new(DiskThrottle::new(&Default::default())),
+            zones: None,
         }));
 
-        let fut = process_one_request(avail_desc, disk_state, flush_timer, flush_timer_armed, &mem);
+        let fut = process_one_request(
+            &ex,
+            avail_desc,
+            disk_state,
+            flush_timer,
+            flush_timer_armed,
+            &mem,
+        );
 
         ex.run_until(fut)
             .expect("running executor failed")
@@ -1410,4 +1889,144 @@ mod tests {
         let returned_id = mem.read_obj_from_addr::<[u8; 20]>(id_offset).unwrap();
         assert_eq!(returned_id, *id);
     }
+
+    #[test]
+    fn zoned_requests() {
+        let ex = Executor::new().expect("creating an executor failed");
+
+        let tempdir = TempDir::new().unwrap();
+        let mut path = tempdir.path().to_owned();
+        path.push("disk_image");
+        let f = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .open(&path)
+            .unwrap();
+        // Four zones of 8 sectors.
+        let disk_size = 0x4000;
+        f.set_len(disk_size).unwrap();
+        let af = SingleFileDisk::new(f, &ex).expect("Failed to create SFD");
+        let zoned = ZonedOption {
+            zone_size: 0x1000,
+            state_file: Some(tempdir.path().join("zones")),
+            ..Default::default()
+        };
+        let zones = ZonedDisk::new_emulated(&zoned, disk_size, 512).unwrap();
+
+        let mem = GuestMemory::new(&[(GuestAddress(0u64), 4 * 1024 * 1024)])
+            .expect("Creating guest memory failed.");
+
+        let timer = Timer::new().expect("Failed to create a timer");
+        let flush_timer = Rc::new(RefCell::new(
+            TimerAsync::new(timer, &ex).expect("Failed to create an async timer"),
+        ));
+        let flush_timer_armed = Rc::new(RefCell::new(false));
+
+        let disk_state = Rc::new(AsyncMutex::new(DiskState {
+            disk_image: Box::new(af),
+            disk_size: Arc::new(AtomicU64::new(disk_size)),
+            read_only: false,
+            sparse: true,
+            id: None,
+            throttle: RefCell::new(DiskThrottle::new(&Default::default())),
+            zones: Some(Arc::new(Mutex::new(zones))),
+        }));
+
+        // Runs a request with `out_len` bytes of data and room for `in_len` bytes of results, and
+        // returns its status.
+        let run_request = |req_type: u32, sector: u64, out_len: u32, in_len: u32| {
+            let req_hdr = virtio_blk_req_header {
+                req_type: Le32::from(req_type),
+                reserved: Le32::from(0),
+                sector: Le64::from(sector),
+            };
+            mem.write_obj_at_addr(req_hdr, GuestAddress(0x1000))
+                .expect("writing req failed");
+            let mut descriptors = vec![(DescriptorType::Readable, size_of_val(&req_hdr) as u32)];
+            if out_len != 0 {
+                descriptors.push((DescriptorType::Readable, out_len));
+            }
+            if in_len != 0 {
+                descriptors.push((DescriptorType::Writable, in_len));
+            }
+            descriptors.push((DescriptorType::Writable, 1));
+            let avail_desc = create_descriptor_chain(
+                &mem,
+                GuestAddress(0x100),  // Place descriptor chain at 0x100.
+                GuestAddress(0x1000), // Describe buffer at 0x1000.
+                descriptors,
+                0,
+            )
+            .expect("create_descriptor_chain failed");
+
+            let fut = process_one_request(
+                &ex,
+                avail_desc,
+                Rc::clone(&disk_state),
+                Rc::clone(&flush_timer),
+                Rc::clone(&flush_timer_armed),
+                &mem,
+            );
+            ex.run_until(fut)
+                .expect("running executor failed")
+                .expect("execute failed");
+
+            let status_offset =
+                GuestAddress(0x1000 + (size_of_val(&req_hdr) as u32 + out_len + in_len) as u64);
+            mem.read_obj_from_addr::<u8>(status_offset).unwrap()
+        };
+        let in_offset = |out_len: u32| {
+            GuestAddress(0x1000 + (size_of::<virtio_blk_req_header>() as u32 + out_len) as u64)
+        };
+
+        // Appends return where the data was written.
+        assert_eq!(
+            run_request(VIRTIO_BLK_T_ZONE_APPEND, 8, 512, 8),
+            VIRTIO_BLK_S_OK
+        );
+        assert_eq!(mem.read_obj_from_addr::<u64>(in_offset(512)).unwrap(), 8);
+        assert_eq!(
+            run_request(VIRTIO_BLK_T_ZONE_APPEND, 8, 1024, 8),
+            VIRTIO_BLK_S_OK
+        );
+        assert_eq!(mem.read_obj_from_addr::<u64>(in_offset(1024)).unwrap(), 9);
+
+        // Writes must be at the write pointer.
+        assert_eq!(
+            run_request(VIRTIO_BLK_T_OUT, 8, 512, 0),
+            VIRTIO_BLK_S_ZONE_UNALIGNED_WP
+        );
+        assert_eq!(run_request(VIRTIO_BLK_T_OUT, 11, 512, 0), VIRTIO_BLK_S_OK);
+        assert_eq!(
+            run_request(VIRTIO_BLK_T_ZONE_RESET, 9, 0, 0),
+            VIRTIO_BLK_S_ZONE_INVALID_CMD
+        );
+        assert_eq!(
+            run_request(VIRTIO_BLK_T_ZONE_FINISH, 16, 0, 0),
+            VIRTIO_BLK_S_OK
+        );
+
+        // Report the two zones starting at the second one.
+        assert_eq!(
+            run_request(VIRTIO_BLK_T_ZONE_REPORT, 8, 0, 64 * 3),
+            VIRTIO_BLK_S_OK
+        );
+        let report: virtio_blk_zone_report = mem.read_obj_from_addr(in_offset(0)).unwrap();
+        assert_eq!(report.nr_zones.to_native(), 2);
+        let zone: virtio_blk_zone_descriptor = mem.read_obj_from_addr(in_offset(64)).unwrap();
+        assert_eq!(zone.z_start.to_native(), 8);
+        assert_eq!(zone.z_wp.to_native(), 12);
+        assert_eq!(zone.z_state, VIRTIO_BLK_ZS_IOPEN);
+        let zone: virtio_blk_zone_descriptor = mem.read_obj_from_addr(in_offset(128)).unwrap();
+        assert_eq!(zone.z_start.to_native(), 16);
+        assert_eq!(zone.z_wp.to_native(), 24);
+        assert_eq!(zone.z_state, VIRTIO_BLK_ZS_FULL);
+
+        assert_eq!(
+            run_request(VIRTIO_BLK_T_ZONE_RESET_ALL, 0, 0, 0),
+            VIRTIO_BLK_S_OK
+        );
+        assert_eq!(run_request(VIRTIO_BLK_T_OUT, 8, 512, 0), VIRTIO_BLK_S_OK);
+    }
 }
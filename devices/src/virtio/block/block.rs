@@ -10,6 +10,9 @@ use cros_async::ExecutorKind;
 use serde::Deserialize;
 use serde::Deserializer;
 use serde::Serialize;
+use vm_control::DiskThrottleConfig;
+
+use crate::virtio::block::zoned::ZonedOption;
 
 fn block_option_sparse_default() -> bool {
     true
@@ -80,6 +83,12 @@ pub struct DiskOption {
     /// precedence over the async executor kind specified by the subcommand's option.
     /// If None, the default or the specified by the subcommand's option would be used.
     pub async_executor: Option<ExecutorKind>,
+    #[serde(default)]
+    /// Limits on the rate of read and write requests to the disk.
+    pub throttle: DiskThrottleConfig,
+    #[serde(default)]
+    /// Exposes the disk as a host-managed zoned device with this zone layout.
+    pub zoned: Option<ZonedOption>,
 }
 
 #[cfg(test)]
@@ -119,6 +128,8 @@ mod tests {
                 #[cfg(windows)]
                 io_concurrency: NonZeroU32::new(1).unwrap(),
                 async_executor: None,
+                throttle: Default::default(),
+                zoned: None,
             }
         );
 
@@ -137,6 +148,8 @@ mod tests {
                 #[cfg(windows)]
                 io_concurrency: NonZeroU32::new(1).unwrap(),
                 async_executor: None,
+                throttle: Default::default(),
+                zoned: None,
             }
         );
 
@@ -155,6 +168,8 @@ mod tests {
                 #[cfg(windows)]
                 io_concurrency: NonZeroU32::new(1).unwrap(),
                 async_executor: None,
+                throttle: Default::default(),
+                zoned: None,
             }
         );
 
@@ -173,6 +188,8 @@ mod tests {
                 #[cfg(windows)]
                 io_concurrency: NonZeroU32::new(1).unwrap(),
                 async_executor: None,
+                throttle: Default::default(),
+                zoned: None,
             }
         );
 
@@ -191,6 +208,8 @@ mod tests {
                 #[cfg(windows)]
                 io_concurrency: NonZeroU32::new(1).unwrap(),
                 async_executor: None,
+                throttle: Default::default(),
+                zoned: None,
             }
         );
         let params = from_block_arg("/some/path.img,sparse=false").unwrap();
@@ -207,6 +226,8 @@ mod tests {
                 #[cfg(windows)]
                 io_concurrency: NonZeroU32::new(1).unwrap(),
                 async_executor: None,
+                throttle: Default::default(),
+                zoned: None,
             }
         );
 
@@ -225,6 +246,8 @@ mod tests {
                 #[cfg(windows)]
                 io_concurrency: NonZeroU32::new(1).unwrap(),
                 async_executor: None,
+                throttle: Default::default(),
+                zoned: None,
             }
         );
 
@@ -243,6 +266,8 @@ mod tests {
                 #[cfg(windows)]
                 io_concurrency: NonZeroU32::new(1).unwrap(),
                 async_executor: None,
+                throttle: Default::default(),
+                zoned: None,
             }
         );
 
@@ -261,6 +286,8 @@ mod tests {
                 #[cfg(windows)]
                 io_concurrency: NonZeroU32::new(1).unwrap(),
                 async_executor: None,
+                throttle: Default::default(),
+                zoned: None,
             }
         );
 
@@ -277,6 +304,8 @@ mod tests {
                 block_size: 128,
                 id: None,
                 async_executor: None,
+                throttle: Default::default(),
+                zoned: None,
                 #[cfg(windows)]
                 io_concurrency: NonZeroU32::new(1).unwrap(),
             }
@@ -298,6 +327,8 @@ mod tests {
                     id: None,
                     io_concurrency: NonZeroU32::new(4).unwrap(),
                     async_executor: None,
+                    throttle: Default::default(),
+                    zoned: None,
                 }
             );
         }
@@ -317,6 +348,8 @@ mod tests {
                 #[cfg(windows)]
                 io_concurrency: NonZeroU32::new(1).unwrap(),
                 async_executor: None,
+                throttle: Default::default(),
+                zoned: None,
             }
         );
         let err = from_block_arg("/some/path.img,id=DISK_ID_IS_WAY_TOO_LONG").unwrap_err();
@@ -348,8 +381,69 @@ mod tests {
                 #[cfg(windows)]
                 io_concurrency: NonZeroU32::new(1).unwrap(),
                 async_executor: Some(ex_kind),
+                throttle: Default::default(),
+                zoned: None,
+            }
+        );
+
+        // throttle
+        let params = from_block_arg(
+            "/some/path.img,throttle=[read-iops=100,write-bps=1048576,write-bps-burst=4096]",
+        )
+        .unwrap();
+        assert_eq!(
+            params,
+            DiskOption {
+                path: "/some/path.img".into(),
+                read_only: false,
+                root: false,
+                sparse: true,
+                direct: false,
+                block_size: 512,
+                id: None,
+                #[cfg(windows)]
+                io_concurrency: NonZeroU32::new(1).unwrap(),
+                async_executor: None,
+                throttle: DiskThrottleConfig {
+                    read_iops: Some(100),
+                    write_bps: Some(1048576),
+                    write_bps_burst: Some(4096),
+                    ..Default::default()
+                },
+                zoned: None,
+            }
+        );
+
+        // zoned
+        let params = from_block_arg(
+            "/some/path.img,zoned=[zone-size=67108864,conventional-zones=2,max-open-zones=8,state-file=/some/path.zones]",
+        )
+        .unwrap();
+        assert_eq!(
+            params,
+            DiskOption {
+                path: "/some/path.img".into(),
+                read_only: false,
+                root: false,
+                sparse: true,
+                direct: false,
+                block_size: 512,
+                id: None,
+                #[cfg(windows)]
+                io_concurrency: NonZeroU32::new(1).unwrap(),
+                async_executor: None,
+                throttle: Default::default(),
+                zoned: Some(ZonedOption {
+                    zone_size: 64 << 20,
+                    conventional_zones: 2,
+                    max_open_zones: 8,
+                    max_active_zones: 0,
+                    state_file: Some("/some/path.zones".into()),
+                }),
             }
         );
+        let params = from_block_arg("/some/path.img,zoned=[]").unwrap();
+        assert_eq!(params.zoned, Some(ZonedOption::default()));
 
         // All together
         let params = from_block_arg(&format!(
@@ -370,6 +464,8 @@ mod tests {
                 #[cfg(windows)]
                 io_concurrency: NonZeroU32::new(1).unwrap(),
                 async_executor: Some(ex_kind),
+                throttle: Default::default(),
+                zoned: None,
             }
         );
     }
@@ -5,6 +5,11 @@
 pub mod asynchronous;
 pub mod block;
 pub(crate) mod sys;
+pub mod throttle;
+pub mod zoned;
 
 pub use asynchronous::BlockAsync;
 pub use asynchronous::DiskState;
+pub use throttle::DiskThrottle;
+pub use zoned::ZonedDisk;
+pub use zoned::ZonedOption;
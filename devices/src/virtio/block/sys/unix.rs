@@ -4,18 +4,87 @@
 
 use std::cmp::max;
 use std::cmp::min;
+use std::fs;
 use std::fs::File;
 use std::fs::OpenOptions;
+use std::io;
+use std::mem::size_of;
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::fs::MetadataExt;
 use std::os::unix::prelude::OpenOptionsExt;
 
+use anyhow::bail;
 use anyhow::Context;
+use base::clone_descriptor;
 use base::flock;
+use base::ioctl_ior_nr;
+use base::ioctl_iow_nr;
+use base::ioctl_iowr_nr;
+use base::ioctl_with_mut_ptr;
+use base::ioctl_with_mut_ref;
+use base::ioctl_with_ref;
 use base::iov_max;
 use base::open_file;
+use base::Descriptor;
 use base::FlockOperation;
+use base::FromRawDescriptor;
+use base::Result as SysResult;
+use data_model::DataInit;
 use disk::DiskFile;
 
 use crate::virtio::block::block::DiskOption;
+use crate::virtio::block::zoned::HostZoneOp;
+use crate::virtio::block::zoned::Zone;
+use crate::virtio::block::zoned::ZonedDisk;
+
+const BLK_IOCTL_TYPE: u32 = 0x12;
+
+// Header of the BLKREPORTZONE argument, followed by `nr_zones` `blk_zone` entries.
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C)]
+struct blk_zone_report {
+    sector: u64,
+    nr_zones: u32,
+    flags: u32,
+}
+
+// Safe because it only has data and has no implicit padding.
+unsafe impl DataInit for blk_zone_report {}
+
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C)]
+struct blk_zone {
+    start: u64,
+    len: u64,
+    wp: u64,
+    zone_type: u8,
+    cond: u8,
+    non_seq: u8,
+    reset: u8,
+    resv: [u8; 4],
+    capacity: u64,
+    reserved: [u8; 24],
+}
+
+// Safe because it only has data and has no implicit padding.
+unsafe impl DataInit for blk_zone {}
+
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C)]
+struct blk_zone_range {
+    sector: u64,
+    nr_sectors: u64,
+}
+
+ioctl_iowr_nr!(BLKREPORTZONE, BLK_IOCTL_TYPE, 130, blk_zone_report);
+ioctl_iow_nr!(BLKRESETZONE, BLK_IOCTL_TYPE, 131, blk_zone_range);
+ioctl_ior_nr!(BLKGETZONESZ, BLK_IOCTL_TYPE, 132, u32);
+ioctl_iow_nr!(BLKOPENZONE, BLK_IOCTL_TYPE, 134, blk_zone_range);
+ioctl_iow_nr!(BLKCLOSEZONE, BLK_IOCTL_TYPE, 135, blk_zone_range);
+ioctl_iow_nr!(BLKFINISHZONE, BLK_IOCTL_TYPE, 136, blk_zone_range);
+
+// Number of zones read from the host device with each BLKREPORTZONE.
+const REPORT_ZONES_BATCH: usize = 128;
 
 pub fn get_seg_max(queue_size: u16) -> u32 {
     let seg_max = min(max(iov_max(), 1), u32::max_value() as usize) as u32;
@@ -29,6 +98,45 @@ pub fn get_seg_max(queue_size: u16) -> u32 {
 impl DiskOption {
     /// Open the specified disk file.
     pub fn open(&self) -> anyhow::Result<Box<dyn DiskFile>> {
+        let raw_image = self.open_image()?;
+        // Lock the disk image to prevent other crosvm instances from using it.
+        flock(&raw_image, self.lock_operation(), true)
+            .with_context(|| format!("failed to lock disk image {}", self.path.display()))?;
+
+        disk::create_disk_file(raw_image, self.sparse, disk::MAX_NESTING_DEPTH, &self.path)
+            .context("create_disk_file failed")
+    }
+
+    /// Open the specified disk file without locking it, for a VM migrated from another crosvm
+    /// instance that still uses the disk. Only raw images are supported since the metadata of the
+    /// other formats is read on open while the other instance may still change it. The disk must
+    /// be locked with `lock` before the VM runs.
+    pub fn open_unlocked(&self) -> anyhow::Result<Box<dyn DiskFile>> {
+        let raw_image = self.open_image()?;
+        let image_type = disk::detect_image_type(&raw_image).context("detect_image_type failed")?;
+        if image_type != disk::ImageType::Raw {
+            bail!(
+                "disk image {} of type {:?} can't be opened unlocked",
+                self.path.display(),
+                image_type
+            );
+        }
+
+        disk::create_disk_file(raw_image, self.sparse, disk::MAX_NESTING_DEPTH, &self.path)
+            .context("create_disk_file failed")
+    }
+
+    /// Locks the disk image like `open` does, waiting for the other crosvm instances to release
+    /// it. The lock is held until the returned file is closed.
+    pub fn lock(&self) -> anyhow::Result<File> {
+        let image = File::open(&self.path)
+            .with_context(|| format!("failed to open disk image {}", self.path.display()))?;
+        flock(&image, self.lock_operation(), false)
+            .with_context(|| format!("failed to lock disk image {}", self.path.display()))?;
+        Ok(image)
+    }
+
+    fn open_image(&self) -> anyhow::Result<File> {
         let mut options = OpenOptions::new();
         options.read(true).write(!self.read_only);
 
@@ -36,18 +144,114 @@ impl DiskOption {
             options.custom_flags(libc::O_DIRECT);
         }
 
-        let raw_image: File = open_file(&self.path, &options)
-            .with_context(|| format!("failed to load disk image {}", self.path.display()))?;
-        // Lock the disk image to prevent other crosvm instances from using it.
-        let lock_op = if self.read_only {
+        open_file(&self.path, &options)
+            .with_context(|| format!("failed to load disk image {}", self.path.display()))
+    }
+
+    fn lock_operation(&self) -> FlockOperation {
+        if self.read_only {
             FlockOperation::LockShared
         } else {
             FlockOperation::LockExclusive
+        }
+    }
+}
+
+// Reads a zone limit of the host block device from sysfs, 0 meaning no limit.
+fn host_zone_limit(rdev: u64, name: &str) -> u32 {
+    // Safe because these only decode the device number.
+    let (major, minor) = unsafe { (libc::major(rdev), libc::minor(rdev)) };
+    let path = format!("/sys/dev/block/{}:{}/queue/{}", major, minor, name);
+    fs::read_to_string(path)
+        .ok()
+        .and_then(|s| s.trim().parse().ok())
+        .unwrap_or(0)
+}
+
+/// Returns the zones of `disk_image` if it is a zoned block device of the host, `None` otherwise.
+pub fn host_zoned_disk(disk_image: &dyn DiskFile, disk_size: u64) -> SysResult<Option<ZonedDisk>> {
+    let descriptors = disk_image.as_raw_descriptors();
+    let &[descriptor] = &descriptors[..] else {
+        return Ok(None);
+    };
+    // Safe because the duplicated descriptor is owned by nothing else.
+    let host = unsafe { File::from_raw_descriptor(clone_descriptor(&Descriptor(descriptor))?) };
+    let metadata = host.metadata()?;
+    if !metadata.file_type().is_block_device() {
+        return Ok(None);
+    }
+
+    let mut zone_sectors: u32 = 0;
+    // Safe because the kernel only writes a u32 to `zone_sectors`.
+    let ret = unsafe { ioctl_with_mut_ref(&host, BLKGETZONESZ(), &mut zone_sectors) };
+    // Kernels without zoned device support don't know the ioctl.
+    if ret < 0 || zone_sectors == 0 {
+        return Ok(None);
+    }
+
+    let disk_sectors = disk_size >> 9;
+    let mut zones = Vec::new();
+    let mut buf =
+        vec![0u8; size_of::<blk_zone_report>() + REPORT_ZONES_BATCH * size_of::<blk_zone>()];
+    let mut sector = 0;
+    while sector < disk_sectors {
+        let header = blk_zone_report {
+            sector,
+            nr_zones: REPORT_ZONES_BATCH as u32,
+            flags: 0,
         };
-        flock(&raw_image, lock_op, true)
-            .with_context(|| format!("failed to lock disk image {}", self.path.display()))?;
+        buf[..size_of::<blk_zone_report>()].copy_from_slice(header.as_slice());
+        // Safe because `buf` has room for the header and `nr_zones` zones.
+        let ret = unsafe { ioctl_with_mut_ptr(&host, BLKREPORTZONE(), buf.as_mut_ptr()) };
+        if ret < 0 {
+            return Err(base::Error::last());
+        }
+        let header = blk_zone_report::from_slice(&buf[..size_of::<blk_zone_report>()]).unwrap();
+        if header.nr_zones == 0 {
+            break;
+        }
+        for i in 0..header.nr_zones as usize {
+            let offset = size_of::<blk_zone_report>() + i * size_of::<blk_zone>();
+            let zone = blk_zone::from_slice(&buf[offset..offset + size_of::<blk_zone>()]).unwrap();
+            // Linux reports the same zone types and conditions as virtio-blk.
+            zones.push(Zone {
+                start: zone.start,
+                len: zone.len,
+                capacity: zone.capacity,
+                write_pointer: zone.wp,
+                zone_type: zone.zone_type,
+                state: zone.cond,
+            });
+            sector = zone.start + zone.len;
+        }
+    }
 
-        disk::create_disk_file(raw_image, self.sparse, disk::MAX_NESTING_DEPTH, &self.path)
-            .context("create_disk_file failed")
+    let rdev = metadata.rdev();
+    let max_open_zones = host_zone_limit(rdev, "max_open_zones");
+    let max_active_zones = host_zone_limit(rdev, "max_active_zones");
+    Ok(Some(ZonedDisk::new_host(
+        host,
+        u64::from(zone_sectors),
+        zones,
+        max_open_zones,
+        max_active_zones,
+    )))
+}
+
+/// Sends a zone management request for the `nr_sectors` starting at `sector` to a zoned host
+/// device.
+pub fn host_zone_op(host: &File, op: HostZoneOp, sector: u64, nr_sectors: u64) -> io::Result<()> {
+    let range = blk_zone_range { sector, nr_sectors };
+    let nr = match op {
+        HostZoneOp::Open => BLKOPENZONE(),
+        HostZoneOp::Close => BLKCLOSEZONE(),
+        HostZoneOp::Finish => BLKFINISHZONE(),
+        HostZoneOp::Reset => BLKRESETZONE(),
+    };
+    // Safe because the kernel only reads `range`.
+    let ret = unsafe { ioctl_with_ref(host, nr, &range) };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
     }
+    Ok(())
 }
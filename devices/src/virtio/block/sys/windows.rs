@@ -2,14 +2,20 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the LICENSE file.
 
+use std::fs::File;
 use std::fs::OpenOptions;
+use std::io;
 use std::os::windows::fs::OpenOptionsExt;
 
 use anyhow::Context;
+use base::Result as SysResult;
+use disk::DiskFile;
 use winapi::um::winnt::FILE_SHARE_READ;
 use winapi::um::winnt::FILE_SHARE_WRITE;
 
 use crate::virtio::block::block::DiskOption;
+use crate::virtio::block::zoned::HostZoneOp;
+use crate::virtio::block::zoned::ZonedDisk;
 
 pub fn get_seg_max(_queue_size: u16) -> u32 {
     // Allow a single segment per request, since vectored I/O is not implemented for Windows yet.
@@ -32,3 +38,20 @@ impl DiskOption {
         )?)
     }
 }
+
+/// Zoned host devices aren't supported on Windows.
+pub fn host_zoned_disk(
+    _disk_image: &dyn DiskFile,
+    _disk_size: u64,
+) -> SysResult<Option<ZonedDisk>> {
+    Ok(None)
+}
+
+pub fn host_zone_op(
+    _host: &File,
+    _op: HostZoneOp,
+    _sector: u64,
+    _nr_sectors: u64,
+) -> io::Result<()> {
+    Err(io::Error::from(io::ErrorKind::Unsupported))
+}
@@ -0,0 +1,175 @@
+// Copyright 2023 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Token bucket rate limiting of block device requests.
+
+use std::cmp::max;
+use std::time::Duration;
+use std::time::Instant;
+
+use vm_control::DiskThrottleConfig;
+
+// A bucket that fills with `rate` tokens per second, up to `capacity` tokens. Requests take tokens
+// out of the bucket even if that leaves it in debt, and then wait until the debt is paid back.
+#[derive(Debug)]
+struct TokenBucket {
+    rate: f64,
+    capacity: f64,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    // Returns a full bucket, or `None` if `rate` doesn't limit anything.
+    fn new(rate: Option<u64>, burst: Option<u64>, now: Instant) -> Option<TokenBucket> {
+        let rate = rate.filter(|r| *r != 0)? as f64;
+        // By default one second's worth of requests can be made at once.
+        let capacity = burst.map_or(rate, |b| b as f64);
+        Some(TokenBucket {
+            rate,
+            capacity,
+            tokens: capacity,
+            last_refill: now,
+        })
+    }
+
+    // Takes `count` tokens out of the bucket and returns how long to wait before they are paid for.
+    fn consume(&mut self, count: u64, now: Instant) -> Duration {
+        let elapsed = now
+            .saturating_duration_since(self.last_refill)
+            .as_secs_f64();
+        self.last_refill = max(self.last_refill, now);
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.capacity);
+        self.tokens -= count as f64;
+        if self.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-self.tokens / self.rate)
+        }
+    }
+}
+
+/// Rate limits for the read and write requests of a block device.
+#[derive(Debug)]
+pub struct DiskThrottle {
+    config: DiskThrottleConfig,
+    read_ops: Option<TokenBucket>,
+    read_bytes: Option<TokenBucket>,
+    write_ops: Option<TokenBucket>,
+    write_bytes: Option<TokenBucket>,
+}
+
+impl DiskThrottle {
+    /// Creates a throttle enforcing the limits in `config`, starting with full bursts available.
+    pub fn new(config: &DiskThrottleConfig) -> DiskThrottle {
+        Self::new_at(config, Instant::now())
+    }
+
+    fn new_at(config: &DiskThrottleConfig, now: Instant) -> DiskThrottle {
+        DiskThrottle {
+            config: *config,
+            read_ops: TokenBucket::new(config.read_iops, config.read_iops_burst, now),
+            read_bytes: TokenBucket::new(config.read_bps, config.read_bps_burst, now),
+            write_ops: TokenBucket::new(config.write_iops, config.write_iops_burst, now),
+            write_bytes: TokenBucket::new(config.write_bps, config.write_bps_burst, now),
+        }
+    }
+
+    /// Returns the limits enforced by the throttle.
+    pub fn config(&self) -> DiskThrottleConfig {
+        self.config
+    }
+
+    /// Accounts for a read or write request of `len` bytes and returns how long it must be delayed
+    /// to stay within the limits.
+    pub fn request_delay(&mut self, write: bool, len: u64) -> Duration {
+        self.request_delay_at(write, len, Instant::now())
+    }
+
+    fn request_delay_at(&mut self, write: bool, len: u64, now: Instant) -> Duration {
+        let (ops, bytes) = if write {
+            (&mut self.write_ops, &mut self.write_bytes)
+        } else {
+            (&mut self.read_ops, &mut self.read_bytes)
+        };
+        let ops_delay = ops.as_mut().map_or(Duration::ZERO, |b| b.consume(1, now));
+        let bytes_delay = bytes
+            .as_mut()
+            .map_or(Duration::ZERO, |b| b.consume(len, now));
+        max(ops_delay, bytes_delay)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unlimited() {
+        let now = Instant::now();
+        let mut throttle = DiskThrottle::new_at(&DiskThrottleConfig::default(), now);
+        for _ in 0..1000 {
+            assert_eq!(
+                throttle.request_delay_at(false, 1 << 20, now),
+                Duration::ZERO
+            );
+            assert_eq!(
+                throttle.request_delay_at(true, 1 << 20, now),
+                Duration::ZERO
+            );
+        }
+    }
+
+    #[test]
+    fn iops_burst() {
+        let now = Instant::now();
+        let config = DiskThrottleConfig {
+            read_iops: Some(10),
+            read_iops_burst: Some(20),
+            ..Default::default()
+        };
+        let mut throttle = DiskThrottle::new_at(&config, now);
+        for _ in 0..20 {
+            assert_eq!(throttle.request_delay_at(false, 512, now), Duration::ZERO);
+        }
+        // The burst is used up, further requests are spaced by the rate.
+        assert_eq!(
+            throttle.request_delay_at(false, 512, now),
+            Duration::from_millis(100)
+        );
+        assert_eq!(
+            throttle.request_delay_at(false, 512, now),
+            Duration::from_millis(200)
+        );
+        // Writes aren't limited.
+        assert_eq!(throttle.request_delay_at(true, 512, now), Duration::ZERO);
+
+        // After the debt is paid back the bucket refills.
+        let later = now + Duration::from_secs(1);
+        assert_eq!(throttle.request_delay_at(false, 512, later), Duration::ZERO);
+    }
+
+    #[test]
+    fn bandwidth() {
+        let now = Instant::now();
+        let config = DiskThrottleConfig {
+            write_bps: Some(1 << 20),
+            ..Default::default()
+        };
+        let mut throttle = DiskThrottle::new_at(&config, now);
+        // A request larger than the burst waits for the missing bytes.
+        assert_eq!(
+            throttle.request_delay_at(true, 2 << 20, now),
+            Duration::from_secs(1)
+        );
+        assert_eq!(
+            throttle.request_delay_at(true, 1 << 19, now),
+            Duration::from_millis(1500)
+        );
+        assert_eq!(
+            throttle.request_delay_at(false, 2 << 20, now),
+            Duration::ZERO
+        );
+    }
+}
@@ -0,0 +1,866 @@
+// Copyright 2023 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Zone state tracking for host-managed zoned block devices.
+//!
+//! The zones are either emulated on top of a regular disk image, or mirror the zones of a zoned
+//! block device on the host, in which case zone management requests are also forwarded to the
+//! host device. The write pointers of emulated zones are saved in a state file next to the disk
+//! image, so that the zones are found as the guest left them when crosvm is restarted. Like on a
+//! device that was powered off, zones that were open are closed.
+
+use std::fs::File;
+use std::fs::OpenOptions;
+use std::io;
+use std::path::PathBuf;
+
+use base::error;
+use base::AsRawDescriptor;
+use base::Error as SysError;
+use base::FileReadWriteAtVolatile;
+use base::RawDescriptor;
+use base::Result as SysResult;
+use data_model::Le64;
+use data_model::VolatileSlice;
+use remain::sorted;
+use serde::Deserialize;
+use serde::Serialize;
+use thiserror::Error as ThisError;
+
+use crate::virtio::block::sys::host_zone_op;
+use crate::virtio::device_constants::block::virtio_blk_zone_descriptor;
+use crate::virtio::device_constants::block::VIRTIO_BLK_S_IOERR;
+use crate::virtio::device_constants::block::VIRTIO_BLK_S_ZONE_ACTIVE_RESOURCE;
+use crate::virtio::device_constants::block::VIRTIO_BLK_S_ZONE_INVALID_CMD;
+use crate::virtio::device_constants::block::VIRTIO_BLK_S_ZONE_OPEN_RESOURCE;
+use crate::virtio::device_constants::block::VIRTIO_BLK_S_ZONE_UNALIGNED_WP;
+use crate::virtio::device_constants::block::VIRTIO_BLK_ZS_CLOSED;
+use crate::virtio::device_constants::block::VIRTIO_BLK_ZS_EMPTY;
+use crate::virtio::device_constants::block::VIRTIO_BLK_ZS_EOPEN;
+use crate::virtio::device_constants::block::VIRTIO_BLK_ZS_FULL;
+use crate::virtio::device_constants::block::VIRTIO_BLK_ZS_IOPEN;
+use crate::virtio::device_constants::block::VIRTIO_BLK_ZS_NOT_WP;
+use crate::virtio::device_constants::block::VIRTIO_BLK_ZT_CONV;
+use crate::virtio::device_constants::block::VIRTIO_BLK_ZT_SWR;
+
+const SECTOR_SHIFT: u8 = 9;
+
+// Size of the record of each zone in the state file, which holds its write pointer.
+const ZONE_RECORD_SIZE: usize = 8;
+
+fn zoned_option_zone_size_default() -> u64 {
+    256 << 20
+}
+
+/// Layout of the zones emulated on a regular disk image.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct ZonedOption {
+    /// Size of each zone in bytes. Must be a power of two and a multiple of the block size.
+    #[serde(default = "zoned_option_zone_size_default")]
+    pub zone_size: u64,
+    /// Number of conventional zones at the start of the disk, which can be written in any order.
+    #[serde(default)]
+    pub conventional_zones: u32,
+    /// Maximum number of zones that can be open at the same time, or 0 for no limit.
+    #[serde(default)]
+    pub max_open_zones: u32,
+    /// Maximum number of zones that can be open or closed at the same time, or 0 for no limit.
+    #[serde(default)]
+    pub max_active_zones: u32,
+    /// File keeping the write pointers of the zones across restarts. It is created if it doesn't
+    /// exist and must otherwise have been created for the same layout.
+    #[serde(default)]
+    pub state_file: Option<PathBuf>,
+}
+
+impl Default for ZonedOption {
+    fn default() -> Self {
+        ZonedOption {
+            zone_size: zoned_option_zone_size_default(),
+            conventional_zones: 0,
+            max_open_zones: 0,
+            max_active_zones: 0,
+            state_file: None,
+        }
+    }
+}
+
+/// Errors of zoned requests, which are reported to the driver with the zone specific statuses.
+#[sorted]
+#[derive(ThisError, Debug)]
+pub enum ZoneError {
+    #[error("too many active zones")]
+    ActiveResources,
+    #[error("zone management of the host device failed: {0}")]
+    Host(io::Error),
+    #[error("invalid zone command")]
+    InvalidCommand,
+    #[error("too many open zones")]
+    OpenResources,
+    #[error("failed to save the zone state: {0}")]
+    SaveState(io::Error),
+    #[error("write is not at the write pointer")]
+    UnalignedWritePointer,
+}
+
+impl ZoneError {
+    pub(crate) fn status(&self) -> u8 {
+        match self {
+            ZoneError::ActiveResources => VIRTIO_BLK_S_ZONE_ACTIVE_RESOURCE,
+            ZoneError::Host(_) => VIRTIO_BLK_S_IOERR,
+            ZoneError::InvalidCommand => VIRTIO_BLK_S_ZONE_INVALID_CMD,
+            ZoneError::OpenResources => VIRTIO_BLK_S_ZONE_OPEN_RESOURCE,
+            ZoneError::SaveState(_) => VIRTIO_BLK_S_IOERR,
+            ZoneError::UnalignedWritePointer => VIRTIO_BLK_S_ZONE_UNALIGNED_WP,
+        }
+    }
+}
+
+/// Zone management operations forwarded to a zoned host device.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HostZoneOp {
+    Open,
+    Close,
+    Finish,
+    Reset,
+}
+
+/// One zone of the disk. All fields except the type and state are in 512 byte sectors.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Zone {
+    pub start: u64,
+    pub len: u64,
+    pub capacity: u64,
+    pub write_pointer: u64,
+    pub zone_type: u8,
+    pub state: u8,
+}
+
+impl Zone {
+    fn is_sequential(&self) -> bool {
+        self.zone_type != VIRTIO_BLK_ZT_CONV
+    }
+
+    fn is_open(&self) -> bool {
+        self.state == VIRTIO_BLK_ZS_IOPEN || self.state == VIRTIO_BLK_ZS_EOPEN
+    }
+
+    fn is_active(&self) -> bool {
+        self.is_open() || self.state == VIRTIO_BLK_ZS_CLOSED
+    }
+
+    fn end(&self) -> u64 {
+        self.start + self.capacity
+    }
+}
+
+/// A write to a sequential zone whose sectors were reserved at the write pointer. It must be
+/// passed to `ZonedDisk::complete` once the data has been written or failed to be.
+#[must_use]
+pub struct ZoneWrite {
+    index: usize,
+    // The zone before and right after the sectors were reserved.
+    previous: Zone,
+    reserved: Zone,
+}
+
+/// The zones of a host-managed zoned disk and the limits on how many can be in use at once.
+pub struct ZonedDisk {
+    zone_sectors: u64,
+    zones: Vec<Zone>,
+    max_open_zones: u32,
+    max_active_zones: u32,
+    host: Option<File>,
+    // State file of emulated zones.
+    state: Option<File>,
+}
+
+impl ZonedDisk {
+    /// Emulates the zone layout of `option` on a disk of `disk_size` bytes. The disk is split in
+    /// zones of the same size, except for the last one which may be smaller.
+    pub fn new_emulated(option: &ZonedOption, disk_size: u64, block_size: u32) -> SysResult<Self> {
+        let zone_size = option.zone_size;
+        if !zone_size.is_power_of_two() || zone_size % u64::from(block_size) != 0 {
+            error!(
+                "Zone size {} is not a power of 2 multiple of the block size {}.",
+                zone_size, block_size,
+            );
+            return Err(SysError::new(libc::EINVAL));
+        }
+        let zone_sectors = zone_size >> SECTOR_SHIFT;
+        if zone_sectors > u64::from(u32::MAX) {
+            error!("Zone size {} is too large.", zone_size);
+            return Err(SysError::new(libc::EINVAL));
+        }
+        let disk_sectors = disk_size >> SECTOR_SHIFT;
+        let nr_zones = (disk_sectors + zone_sectors - 1) / zone_sectors;
+        if u64::from(option.conventional_zones) >= nr_zones {
+            error!(
+                "Disk of {} bytes has no room for sequential zones after {} conventional zones.",
+                disk_size, option.conventional_zones,
+            );
+            return Err(SysError::new(libc::EINVAL));
+        }
+        let zones = (0..nr_zones)
+            .map(|i| {
+                let start = i * zone_sectors;
+                let len = std::cmp::min(zone_sectors, disk_sectors - start);
+                let conventional = i < u64::from(option.conventional_zones);
+                Zone {
+                    start,
+                    len,
+                    capacity: len,
+                    write_pointer: start,
+                    zone_type: if conventional {
+                        VIRTIO_BLK_ZT_CONV
+                    } else {
+                        VIRTIO_BLK_ZT_SWR
+                    },
+                    state: if conventional {
+                        VIRTIO_BLK_ZS_NOT_WP
+                    } else {
+                        VIRTIO_BLK_ZS_EMPTY
+                    },
+                }
+            })
+            .collect();
+        let state_file = match &option.state_file {
+            Some(path) => path,
+            None => {
+                error!("Emulated zones need a state file to keep their write pointers.");
+                return Err(SysError::new(libc::EINVAL));
+            }
+        };
+        let state = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .open(state_file)
+            .map_err(|e| {
+                error!(
+                    "Failed to open zone state file {}: {}",
+                    state_file.display(),
+                    e
+                );
+                SysError::from(e)
+            })?;
+        let mut disk = ZonedDisk {
+            zone_sectors,
+            zones,
+            max_open_zones: option.max_open_zones,
+            max_active_zones: option.max_active_zones,
+            host: None,
+            state: None,
+        };
+        disk.load_state(state)?;
+        Ok(disk)
+    }
+
+    // Restores the write pointers of the emulated zones from `state`, or saves the initial ones if
+    // the file is empty.
+    fn load_state(&mut self, mut state: File) -> SysResult<()> {
+        let mut records = vec![0u8; self.zones.len() * ZONE_RECORD_SIZE];
+        let file_len = state.metadata()?.len();
+        if file_len == 0 {
+            for (zone, record) in self
+                .zones
+                .iter()
+                .zip(records.chunks_exact_mut(ZONE_RECORD_SIZE))
+            {
+                record.copy_from_slice(&zone.write_pointer.to_le_bytes());
+            }
+            state.write_all_at_volatile(VolatileSlice::new(&mut records), 0)?;
+            self.state = Some(state);
+            return Ok(());
+        }
+        if file_len != records.len() as u64 {
+            error!(
+                "Zone state file doesn't match the layout of {} zones.",
+                self.zones.len()
+            );
+            return Err(SysError::new(libc::EINVAL));
+        }
+        state.read_exact_at_volatile(VolatileSlice::new(&mut records), 0)?;
+        for (zone, record) in self
+            .zones
+            .iter_mut()
+            .zip(records.chunks_exact(ZONE_RECORD_SIZE))
+            .filter(|(zone, _)| zone.is_sequential())
+        {
+            let mut write_pointer = [0u8; ZONE_RECORD_SIZE];
+            write_pointer.copy_from_slice(record);
+            let write_pointer = u64::from_le_bytes(write_pointer);
+            if write_pointer < zone.start || write_pointer > zone.end() {
+                error!(
+                    "Invalid write pointer {} of the zone at sector {} in the zone state file.",
+                    write_pointer, zone.start
+                );
+                return Err(SysError::new(libc::EINVAL));
+            }
+            zone.write_pointer = write_pointer;
+            zone.state = if write_pointer == zone.start {
+                VIRTIO_BLK_ZS_EMPTY
+            } else if write_pointer == zone.end() {
+                VIRTIO_BLK_ZS_FULL
+            } else {
+                VIRTIO_BLK_ZS_CLOSED
+            };
+        }
+        self.state = Some(state);
+        Ok(())
+    }
+
+    /// Mirrors the zones of a zoned host device. Zone management requests are also sent to
+    /// `host`.
+    pub fn new_host(
+        host: File,
+        zone_sectors: u64,
+        zones: Vec<Zone>,
+        max_open_zones: u32,
+        max_active_zones: u32,
+    ) -> Self {
+        ZonedDisk {
+            zone_sectors,
+            zones,
+            max_open_zones,
+            max_active_zones,
+            host: Some(host),
+            state: None,
+        }
+    }
+
+    /// Returns the size of the zones in sectors.
+    pub fn zone_sectors(&self) -> u64 {
+        self.zone_sectors
+    }
+
+    /// Returns the maximum number of open zones, 0 if there is no limit.
+    pub fn max_open_zones(&self) -> u32 {
+        self.max_open_zones
+    }
+
+    /// Returns the maximum number of active zones, 0 if there is no limit.
+    pub fn max_active_zones(&self) -> u32 {
+        self.max_active_zones
+    }
+
+    /// Returns the descriptors of the host device or of the state file of emulated zones.
+    pub fn as_raw_descriptors(&self) -> Vec<RawDescriptor> {
+        self.host
+            .iter()
+            .chain(self.state.iter())
+            .map(|f| f.as_raw_descriptor())
+            .collect()
+    }
+
+    /// Flushes the state file of emulated zones.
+    pub fn sync(&self) -> Result<(), ZoneError> {
+        match &self.state {
+            Some(state) => state.sync_data().map_err(ZoneError::SaveState),
+            None => Ok(()),
+        }
+    }
+
+    // Saves the write pointer of the emulated zone at `index` in the state file.
+    fn save_zone(&mut self, index: usize) -> Result<(), ZoneError> {
+        if let Some(state) = &mut self.state {
+            let mut record = self.zones[index].write_pointer.to_le_bytes();
+            state
+                .write_all_at_volatile(
+                    VolatileSlice::new(&mut record),
+                    (index * ZONE_RECORD_SIZE) as u64,
+                )
+                .map_err(ZoneError::SaveState)?;
+        }
+        Ok(())
+    }
+
+    // Returns the index of the zone containing `sector`.
+    fn zone_index(&self, sector: u64) -> Result<usize, ZoneError> {
+        let index = (sector / self.zone_sectors) as usize;
+        if index < self.zones.len() {
+            Ok(index)
+        } else {
+            Err(ZoneError::InvalidCommand)
+        }
+    }
+
+    // Returns the index of the sequential zone starting at `sector`.
+    fn sequential_zone_index(&self, sector: u64) -> Result<usize, ZoneError> {
+        let index = self.zone_index(sector)?;
+        let zone = &self.zones[index];
+        if zone.start != sector || !zone.is_sequential() {
+            return Err(ZoneError::InvalidCommand);
+        }
+        Ok(index)
+    }
+
+    /// Returns the descriptors of at most `max_zones` zones, starting with the one containing
+    /// `sector`.
+    pub(crate) fn report(&self, sector: u64, max_zones: usize) -> Vec<virtio_blk_zone_descriptor> {
+        let first = (sector / self.zone_sectors) as usize;
+        self.zones
+            .iter()
+            .skip(first)
+            .take(max_zones)
+            .map(|zone| virtio_blk_zone_descriptor {
+                z_cap: Le64::from(zone.capacity),
+                z_start: Le64::from(zone.start),
+                z_wp: Le64::from(zone.write_pointer),
+                z_type: zone.zone_type,
+                z_state: zone.state,
+                reserved: [0; 38],
+            })
+            .collect()
+    }
+
+    /// Checks that `num_sectors` can be written at `sector` and reserves them at the write pointer
+    /// of the written sequential zone, if any.
+    pub fn write(&mut self, sector: u64, num_sectors: u64) -> Result<Option<ZoneWrite>, ZoneError> {
+        let index = self.zone_index(sector)?;
+        if !self.zones[index].is_sequential() {
+            // Writes to conventional zones can't spill into a sequential zone.
+            let last = self.zone_index(sector + num_sectors.saturating_sub(1))?;
+            if self.zones[index..=last].iter().any(Zone::is_sequential) {
+                return Err(ZoneError::InvalidCommand);
+            }
+            return Ok(None);
+        }
+        if sector != self.zones[index].write_pointer {
+            return Err(ZoneError::UnalignedWritePointer);
+        }
+        self.advance(index, num_sectors).map(Some)
+    }
+
+    /// Reserves `num_sectors` at the write pointer of the zone starting at `sector` and returns
+    /// the sector they should be written to.
+    pub fn append(&mut self, sector: u64, num_sectors: u64) -> Result<(u64, ZoneWrite), ZoneError> {
+        let index = self.sequential_zone_index(sector)?;
+        let write_pointer = self.zones[index].write_pointer;
+        let write = self.advance(index, num_sectors)?;
+        Ok((write_pointer, write))
+    }
+
+    /// Completes a write reserved by `write` or `append`. The write pointer is saved if the data
+    /// was written, and otherwise moved back unless the zone changed since the write was reserved,
+    /// e.g. because more sectors were reserved after it.
+    pub fn complete(&mut self, write: ZoneWrite, written: bool) -> Result<(), ZoneError> {
+        let zone = &mut self.zones[write.index];
+        if written {
+            // The write pointer of a zone with later writes in flight is saved once they complete.
+            if zone.write_pointer != write.reserved.write_pointer {
+                return Ok(());
+            }
+        } else {
+            if *zone != write.reserved {
+                return Ok(());
+            }
+            *zone = write.previous;
+        }
+        self.save_zone(write.index)
+    }
+
+    // Moves the write pointer of a sequential zone, opening it implicitly if needed.
+    fn advance(&mut self, index: usize, num_sectors: u64) -> Result<ZoneWrite, ZoneError> {
+        let previous = self.zones[index];
+        if previous.write_pointer + num_sectors > previous.end() {
+            return Err(ZoneError::InvalidCommand);
+        }
+        match previous.state {
+            VIRTIO_BLK_ZS_EMPTY | VIRTIO_BLK_ZS_CLOSED => {
+                self.reserve_resources(index)?;
+                self.zones[index].state = VIRTIO_BLK_ZS_IOPEN;
+            }
+            VIRTIO_BLK_ZS_IOPEN | VIRTIO_BLK_ZS_EOPEN => {}
+            _ => return Err(ZoneError::InvalidCommand),
+        }
+        let zone = &mut self.zones[index];
+        zone.write_pointer += num_sectors;
+        if zone.write_pointer == zone.end() {
+            zone.state = VIRTIO_BLK_ZS_FULL;
+        }
+        Ok(ZoneWrite {
+            index,
+            previous,
+            reserved: *zone,
+        })
+    }
+
+    // Checks that the empty or closed zone at `index` can be opened, closing an implicitly open
+    // zone if that is needed to stay within the open zone limit.
+    fn reserve_resources(&mut self, index: usize) -> Result<(), ZoneError> {
+        if self.zones[index].state == VIRTIO_BLK_ZS_EMPTY {
+            self.reserve_active_resource()?;
+        }
+        let open_zones = self.zones.iter().filter(|z| z.is_open()).count();
+        if self.max_open_zones != 0 && open_zones >= self.max_open_zones as usize {
+            let implicitly_open = self
+                .zones
+                .iter()
+                .position(|z| z.state == VIRTIO_BLK_ZS_IOPEN)
+                .ok_or(ZoneError::OpenResources)?;
+            self.set_closed(implicitly_open);
+        }
+        Ok(())
+    }
+
+    fn reserve_active_resource(&self) -> Result<(), ZoneError> {
+        let active_zones = self.zones.iter().filter(|z| z.is_active()).count();
+        if self.max_active_zones != 0 && active_zones >= self.max_active_zones as usize {
+            return Err(ZoneError::ActiveResources);
+        }
+        Ok(())
+    }
+
+    fn set_closed(&mut self, index: usize) {
+        let zone = &mut self.zones[index];
+        zone.state = if zone.write_pointer == zone.start {
+            VIRTIO_BLK_ZS_EMPTY
+        } else {
+            VIRTIO_BLK_ZS_CLOSED
+        };
+    }
+
+    fn host_op(&self, op: HostZoneOp, index: usize) -> Result<(), ZoneError> {
+        if let Some(host) = &self.host {
+            let zone = &self.zones[index];
+            host_zone_op(host, op, zone.start, zone.len).map_err(ZoneError::Host)?;
+        }
+        Ok(())
+    }
+
+    /// Explicitly opens the zone starting at `sector`.
+    pub fn open(&mut self, sector: u64) -> Result<(), ZoneError> {
+        let index = self.sequential_zone_index(sector)?;
+        match self.zones[index].state {
+            VIRTIO_BLK_ZS_EOPEN | VIRTIO_BLK_ZS_FULL => return Ok(()),
+            VIRTIO_BLK_ZS_IOPEN => {}
+            VIRTIO_BLK_ZS_EMPTY | VIRTIO_BLK_ZS_CLOSED => self.reserve_resources(index)?,
+            _ => return Err(ZoneError::InvalidCommand),
+        }
+        self.host_op(HostZoneOp::Open, index)?;
+        self.zones[index].state = VIRTIO_BLK_ZS_EOPEN;
+        Ok(())
+    }
+
+    /// Closes the zone starting at `sector`.
+    pub fn close(&mut self, sector: u64) -> Result<(), ZoneError> {
+        let index = self.sequential_zone_index(sector)?;
+        match self.zones[index].state {
+            VIRTIO_BLK_ZS_IOPEN | VIRTIO_BLK_ZS_EOPEN => {}
+            VIRTIO_BLK_ZS_EMPTY | VIRTIO_BLK_ZS_CLOSED | VIRTIO_BLK_ZS_FULL => return Ok(()),
+            _ => return Err(ZoneError::InvalidCommand),
+        }
+        self.host_op(HostZoneOp::Close, index)?;
+        self.set_closed(index);
+        Ok(())
+    }
+
+    /// Moves the write pointer of the zone starting at `sector` to the end of the zone.
+    pub fn finish(&mut self, sector: u64) -> Result<(), ZoneError> {
+        let index = self.sequential_zone_index(sector)?;
+        match self.zones[index].state {
+            VIRTIO_BLK_ZS_FULL => return Ok(()),
+            VIRTIO_BLK_ZS_EMPTY => self.reserve_active_resource()?,
+            VIRTIO_BLK_ZS_IOPEN | VIRTIO_BLK_ZS_EOPEN | VIRTIO_BLK_ZS_CLOSED => {}
+            _ => return Err(ZoneError::InvalidCommand),
+        }
+        self.host_op(HostZoneOp::Finish, index)?;
+        let zone = &mut self.zones[index];
+        zone.write_pointer = zone.end();
+        zone.state = VIRTIO_BLK_ZS_FULL;
+        self.save_zone(index)
+    }
+
+    /// Moves the write pointer of the zone starting at `sector` back to its start. Returns the
+    /// range of sectors that held data, which emulated disks should discard.
+    pub fn reset(&mut self, sector: u64) -> Result<Option<(u64, u64)>, ZoneError> {
+        let index = self.sequential_zone_index(sector)?;
+        self.reset_zone(index)
+    }
+
+    /// Resets all the sequential zones. Returns the ranges of sectors that held data, which
+    /// emulated disks should discard.
+    pub fn reset_all(&mut self) -> Result<Vec<(u64, u64)>, ZoneError> {
+        let mut discarded = Vec::new();
+        for index in 0..self.zones.len() {
+            let zone = &self.zones[index];
+            if zone.is_sequential() && zone.write_pointer != zone.start {
+                discarded.extend(self.reset_zone(index)?);
+            }
+        }
+        Ok(discarded)
+    }
+
+    fn reset_zone(&mut self, index: usize) -> Result<Option<(u64, u64)>, ZoneError> {
+        match self.zones[index].state {
+            VIRTIO_BLK_ZS_EMPTY | VIRTIO_BLK_ZS_IOPEN | VIRTIO_BLK_ZS_EOPEN
+            | VIRTIO_BLK_ZS_CLOSED | VIRTIO_BLK_ZS_FULL => {}
+            _ => return Err(ZoneError::InvalidCommand),
+        }
+        self.host_op(HostZoneOp::Reset, index)?;
+        let emulated = self.host.is_none();
+        let zone = &mut self.zones[index];
+        let written = zone.write_pointer - zone.start;
+        let start = zone.start;
+        zone.write_pointer = start;
+        zone.state = VIRTIO_BLK_ZS_EMPTY;
+        self.save_zone(index)?;
+        Ok(if emulated && written != 0 {
+            Some((start, written))
+        } else {
+            None
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use tempfile::TempDir;
+
+    use super::*;
+
+    const MIB: u64 = 1 << 20;
+
+    fn emulated_with_state(
+        state_file: &Path,
+        conventional_zones: u32,
+        max_open_zones: u32,
+        max_active_zones: u32,
+    ) -> SysResult<ZonedDisk> {
+        let option = ZonedOption {
+            zone_size: MIB,
+            conventional_zones,
+            max_open_zones,
+            max_active_zones,
+            state_file: Some(state_file.to_path_buf()),
+        };
+        // Four and a half zones.
+        ZonedDisk::new_emulated(&option, 4 * MIB + MIB / 2, 512)
+    }
+
+    fn emulated(conventional_zones: u32, max_open_zones: u32, max_active_zones: u32) -> ZonedDisk {
+        // The disk keeps the state file open once the directory is removed.
+        let dir = TempDir::new().unwrap();
+        emulated_with_state(
+            &dir.path().join("zones"),
+            conventional_zones,
+            max_open_zones,
+            max_active_zones,
+        )
+        .unwrap()
+    }
+
+    fn complete_write(
+        disk: &mut ZonedDisk,
+        sector: u64,
+        num_sectors: u64,
+    ) -> Result<(), ZoneError> {
+        if let Some(write) = disk.write(sector, num_sectors)? {
+            disk.complete(write, true)?;
+        }
+        Ok(())
+    }
+
+    fn complete_append(
+        disk: &mut ZonedDisk,
+        sector: u64,
+        num_sectors: u64,
+    ) -> Result<u64, ZoneError> {
+        let (append_sector, write) = disk.append(sector, num_sectors)?;
+        disk.complete(write, true)?;
+        Ok(append_sector)
+    }
+
+    fn state(disk: &ZonedDisk, index: usize) -> (u8, u64) {
+        let zone = &disk.zones[index];
+        (zone.state, zone.write_pointer)
+    }
+
+    #[test]
+    fn layout() {
+        let disk = emulated(1, 0, 0);
+        let report = disk.report(0, 16);
+        assert_eq!(report.len(), 5);
+        assert_eq!(report[0].z_type, VIRTIO_BLK_ZT_CONV);
+        assert_eq!(report[0].z_state, VIRTIO_BLK_ZS_NOT_WP);
+        assert_eq!(report[1].z_type, VIRTIO_BLK_ZT_SWR);
+        assert_eq!(report[1].z_state, VIRTIO_BLK_ZS_EMPTY);
+        assert_eq!(report[1].z_start.to_native(), 2048);
+        // The last zone is smaller.
+        assert_eq!(report[4].z_start.to_native(), 8192);
+        assert_eq!(report[4].z_cap.to_native(), 1024);
+
+        let report = disk.report(4096 + 7, 2);
+        assert_eq!(report.len(), 2);
+        assert_eq!(report[0].z_start.to_native(), 4096);
+
+        let dir = TempDir::new().unwrap();
+        let state_file = Some(dir.path().join("zones"));
+        assert!(ZonedDisk::new_emulated(
+            &ZonedOption {
+                zone_size: 3 * MIB,
+                state_file: state_file.clone(),
+                ..Default::default()
+            },
+            16 * MIB,
+            512
+        )
+        .is_err());
+        assert!(ZonedDisk::new_emulated(
+            &ZonedOption {
+                zone_size: MIB,
+                conventional_zones: 4,
+                state_file,
+                ..Default::default()
+            },
+            4 * MIB,
+            512
+        )
+        .is_err());
+        // Emulated zones can't be used without a state file.
+        assert!(ZonedDisk::new_emulated(&ZonedOption::default(), 16 * MIB, 512).is_err());
+    }
+
+    #[test]
+    fn sequential_write() {
+        let mut disk = emulated(1, 0, 0);
+        // Conventional zones can be written anywhere, but not across into a sequential zone.
+        complete_write(&mut disk, 100, 8).unwrap();
+        complete_write(&mut disk, 0, 8).unwrap();
+        assert!(matches!(
+            complete_write(&mut disk, 2040, 16),
+            Err(ZoneError::InvalidCommand)
+        ));
+
+        assert!(matches!(
+            complete_write(&mut disk, 2056, 8),
+            Err(ZoneError::UnalignedWritePointer)
+        ));
+        complete_write(&mut disk, 2048, 8).unwrap();
+        assert_eq!(state(&disk, 1), (VIRTIO_BLK_ZS_IOPEN, 2056));
+        complete_write(&mut disk, 2056, 2040).unwrap();
+        assert_eq!(state(&disk, 1), (VIRTIO_BLK_ZS_FULL, 4096));
+        assert!(matches!(
+            complete_write(&mut disk, 4096 - 8, 8),
+            Err(ZoneError::UnalignedWritePointer)
+        ));
+
+        // Writes can't go past the end of the zone.
+        assert!(matches!(
+            complete_write(&mut disk, 4096, 2056),
+            Err(ZoneError::InvalidCommand)
+        ));
+        assert_eq!(state(&disk, 2), (VIRTIO_BLK_ZS_EMPTY, 4096));
+    }
+
+    #[test]
+    fn append() {
+        let mut disk = emulated(1, 0, 0);
+        assert_eq!(complete_append(&mut disk, 2048, 8).unwrap(), 2048);
+        assert_eq!(complete_append(&mut disk, 2048, 16).unwrap(), 2056);
+        assert_eq!(state(&disk, 1), (VIRTIO_BLK_ZS_IOPEN, 2072));
+        // Appends must name the start of a sequential zone.
+        assert!(matches!(
+            complete_append(&mut disk, 0, 8),
+            Err(ZoneError::InvalidCommand)
+        ));
+        assert!(matches!(
+            complete_append(&mut disk, 2056, 8),
+            Err(ZoneError::InvalidCommand)
+        ));
+    }
+
+    #[test]
+    fn open_limit() {
+        let mut disk = emulated(0, 2, 3);
+        disk.open(0).unwrap();
+        complete_write(&mut disk, 2048, 8).unwrap();
+        // The implicitly open zone is closed to make room for another one.
+        disk.open(4096).unwrap();
+        assert_eq!(state(&disk, 1), (VIRTIO_BLK_ZS_CLOSED, 2056));
+        assert_eq!(state(&disk, 2), (VIRTIO_BLK_ZS_EOPEN, 4096));
+        // Explicitly open zones are never closed by the device.
+        assert!(matches!(
+            complete_write(&mut disk, 2056, 8),
+            Err(ZoneError::OpenResources)
+        ));
+        disk.close(0).unwrap();
+        assert_eq!(state(&disk, 0), (VIRTIO_BLK_ZS_EMPTY, 0));
+        complete_write(&mut disk, 2056, 8).unwrap();
+        assert_eq!(state(&disk, 1), (VIRTIO_BLK_ZS_IOPEN, 2064));
+
+        // Zones 0 and 1 end up closed and zone 2 is open, so no more zones can be active.
+        complete_write(&mut disk, 0, 8).unwrap();
+        disk.close(0).unwrap();
+        assert_eq!(state(&disk, 0), (VIRTIO_BLK_ZS_CLOSED, 8));
+        assert!(matches!(disk.open(6144), Err(ZoneError::ActiveResources)));
+        disk.finish(0).unwrap();
+        assert_eq!(state(&disk, 0), (VIRTIO_BLK_ZS_FULL, 2048));
+        disk.close(4096).unwrap();
+        disk.open(6144).unwrap();
+    }
+
+    #[test]
+    fn reset() {
+        let mut disk = emulated(1, 0, 0);
+        assert_eq!(disk.reset(2048).unwrap(), None);
+        complete_write(&mut disk, 2048, 8).unwrap();
+        disk.finish(4096).unwrap();
+        assert_eq!(disk.reset(2048).unwrap(), Some((2048, 8)));
+        assert_eq!(state(&disk, 1), (VIRTIO_BLK_ZS_EMPTY, 2048));
+        assert!(matches!(disk.reset(0), Err(ZoneError::InvalidCommand)));
+
+        complete_write(&mut disk, 2048, 16).unwrap();
+        assert_eq!(disk.reset_all().unwrap(), vec![(2048, 16), (4096, 2048)]);
+        assert_eq!(state(&disk, 2), (VIRTIO_BLK_ZS_EMPTY, 4096));
+    }
+
+    #[test]
+    fn failed_write() {
+        let mut disk = emulated(1, 0, 0);
+        let write = disk.write(2048, 8).unwrap().unwrap();
+        disk.complete(write, false).unwrap();
+        assert_eq!(state(&disk, 1), (VIRTIO_BLK_ZS_EMPTY, 2048));
+
+        // A failed append isn't given back once later sectors were reserved.
+        let (_, first) = disk.append(2048, 8).unwrap();
+        let (second_sector, second) = disk.append(2048, 8).unwrap();
+        assert_eq!(second_sector, 2056);
+        disk.complete(first, false).unwrap();
+        assert_eq!(state(&disk, 1), (VIRTIO_BLK_ZS_IOPEN, 2064));
+        disk.complete(second, false).unwrap();
+        assert_eq!(state(&disk, 1), (VIRTIO_BLK_ZS_IOPEN, 2056));
+    }
+
+    #[test]
+    fn saved_state() {
+        let dir = TempDir::new().unwrap();
+        let state_file = dir.path().join("zones");
+        let mut disk = emulated_with_state(&state_file, 1, 0, 0).unwrap();
+        complete_write(&mut disk, 2048, 8).unwrap();
+        disk.finish(4096).unwrap();
+        complete_write(&mut disk, 6144, 8).unwrap();
+        // Writes in flight aren't saved.
+        let _write = disk.write(6152, 8).unwrap().unwrap();
+        drop(disk);
+
+        let disk = emulated_with_state(&state_file, 1, 0, 0).unwrap();
+        assert_eq!(state(&disk, 1), (VIRTIO_BLK_ZS_CLOSED, 2056));
+        assert_eq!(state(&disk, 2), (VIRTIO_BLK_ZS_FULL, 6144));
+        assert_eq!(state(&disk, 3), (VIRTIO_BLK_ZS_CLOSED, 6152));
+        assert_eq!(state(&disk, 4), (VIRTIO_BLK_ZS_EMPTY, 8192));
+
+        // The state file must match the layout.
+        let option = ZonedOption {
+            zone_size: 2 * MIB,
+            state_file: Some(state_file),
+            ..Default::default()
+        };
+        assert!(ZonedDisk::new_emulated(&option, 4 * MIB + MIB / 2, 512).is_err());
+    }
+}
@@ -21,10 +21,21 @@ pub mod block {
     pub const VIRTIO_BLK_T_GET_ID: u32 = 8;
     pub const VIRTIO_BLK_T_DISCARD: u32 = 11;
     pub const VIRTIO_BLK_T_WRITE_ZEROES: u32 = 13;
+    pub const VIRTIO_BLK_T_ZONE_APPEND: u32 = 15;
+    pub const VIRTIO_BLK_T_ZONE_REPORT: u32 = 16;
+    pub const VIRTIO_BLK_T_ZONE_OPEN: u32 = 18;
+    pub const VIRTIO_BLK_T_ZONE_CLOSE: u32 = 20;
+    pub const VIRTIO_BLK_T_ZONE_FINISH: u32 = 22;
+    pub const VIRTIO_BLK_T_ZONE_RESET: u32 = 24;
+    pub const VIRTIO_BLK_T_ZONE_RESET_ALL: u32 = 26;
 
     pub const VIRTIO_BLK_S_OK: u8 = 0;
     pub const VIRTIO_BLK_S_IOERR: u8 = 1;
     pub const VIRTIO_BLK_S_UNSUPP: u8 = 2;
+    pub const VIRTIO_BLK_S_ZONE_INVALID_CMD: u8 = 3;
+    pub const VIRTIO_BLK_S_ZONE_UNALIGNED_WP: u8 = 4;
+    pub const VIRTIO_BLK_S_ZONE_OPEN_RESOURCE: u8 = 5;
+    pub const VIRTIO_BLK_S_ZONE_ACTIVE_RESOURCE: u8 = 6;
 
     pub const VIRTIO_BLK_F_SEG_MAX: u32 = 2;
     pub const VIRTIO_BLK_F_RO: u32 = 5;
@@ -33,6 +44,24 @@ pub mod block {
     pub const VIRTIO_BLK_F_MQ: u32 = 12;
     pub const VIRTIO_BLK_F_DISCARD: u32 = 13;
     pub const VIRTIO_BLK_F_WRITE_ZEROES: u32 = 14;
+    pub const VIRTIO_BLK_F_ZONED: u32 = 17;
+
+    pub const VIRTIO_BLK_Z_NONE: u8 = 0;
+    pub const VIRTIO_BLK_Z_HM: u8 = 1;
+    pub const VIRTIO_BLK_Z_HA: u8 = 2;
+
+    pub const VIRTIO_BLK_ZT_CONV: u8 = 1;
+    pub const VIRTIO_BLK_ZT_SWR: u8 = 2;
+    pub const VIRTIO_BLK_ZT_SWP: u8 = 3;
+
+    pub const VIRTIO_BLK_ZS_NOT_WP: u8 = 0;
+    pub const VIRTIO_BLK_ZS_EMPTY: u8 = 1;
+    pub const VIRTIO_BLK_ZS_IOPEN: u8 = 2;
+    pub const VIRTIO_BLK_ZS_EOPEN: u8 = 3;
+    pub const VIRTIO_BLK_ZS_CLOSED: u8 = 4;
+    pub const VIRTIO_BLK_ZS_RDONLY: u8 = 13;
+    pub const VIRTIO_BLK_ZS_FULL: u8 = 14;
+    pub const VIRTIO_BLK_ZS_OFFLINE: u8 = 15;
 
     #[derive(Copy, Clone, Debug, Default)]
     #[repr(C)]
@@ -57,6 +86,21 @@ pub mod block {
     // Safe because it only has data and has no implicit padding.
     unsafe impl DataInit for virtio_blk_topology {}
 
+    #[derive(Copy, Clone, Debug, Default)]
+    #[repr(C)]
+    pub struct virtio_blk_zoned_characteristics {
+        pub zone_sectors: Le32,
+        pub max_open_zones: Le32,
+        pub max_active_zones: Le32,
+        pub max_append_sectors: Le32,
+        pub write_granularity: Le32,
+        pub model: u8,
+        pub unused2: [u8; 3],
+    }
+
+    // Safe because it only has data and has no implicit padding.
+    unsafe impl DataInit for virtio_blk_zoned_characteristics {}
+
     #[derive(Copy, Clone, Debug, Default)]
     #[repr(C, packed)]
     pub struct virtio_blk_config {
@@ -76,6 +120,10 @@ pub mod block {
         pub max_write_zeroes_seg: Le32,
         pub write_zeroes_may_unmap: u8,
         pub unused1: [u8; 3],
+        pub max_secure_erase_sectors: Le32,
+        pub max_secure_erase_seg: Le32,
+        pub secure_erase_sector_alignment: Le32,
+        pub zoned: virtio_blk_zoned_characteristics,
     }
 
     // Safe because it only has data and has no implicit padding.
@@ -104,6 +152,30 @@ pub mod block {
 
     // Safe because it only has data and has no implicit padding.
     unsafe impl DataInit for virtio_blk_discard_write_zeroes {}
+
+    #[derive(Copy, Clone, Debug)]
+    #[repr(C)]
+    pub(crate) struct virtio_blk_zone_report {
+        pub nr_zones: Le64,
+        pub reserved: [u8; 56],
+    }
+
+    // Safe because it only has data and has no implicit padding.
+    unsafe impl DataInit for virtio_blk_zone_report {}
+
+    #[derive(Copy, Clone, Debug)]
+    #[repr(C)]
+    pub(crate) struct virtio_blk_zone_descriptor {
+        pub z_cap: Le64,
+        pub z_start: Le64,
+        pub z_wp: Le64,
+        pub z_type: u8,
+        pub z_state: u8,
+        pub reserved: [u8; 38],
+    }
+
+    // Safe because it only has data and has no implicit padding.
+    unsafe impl DataInit for virtio_blk_zone_descriptor {}
 }
 
 pub mod fs {
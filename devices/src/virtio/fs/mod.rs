@@ -48,6 +48,7 @@ use crate::Suspendable;
 mod caps;
 mod multikey;
 pub mod passthrough;
+pub mod policy;
 mod read_dir;
 mod worker;
 
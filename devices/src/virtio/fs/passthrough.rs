@@ -8,6 +8,8 @@ use std::collections::btree_map;
 use std::collections::BTreeMap;
 use std::ffi::CStr;
 use std::ffi::CString;
+use std::ffi::OsStr;
+use std::ffi::OsString;
 use std::fs::File;
 use std::io;
 use std::mem;
@@ -15,6 +17,9 @@ use std::mem::size_of;
 use std::mem::MaybeUninit;
 use std::os::raw::c_int;
 use std::os::raw::c_long;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::ffi::OsStringExt;
+use std::path::PathBuf;
 use std::ptr::addr_of;
 use std::ptr::addr_of_mut;
 use std::str::FromStr;
@@ -40,6 +45,7 @@ use data_model::DataInit;
 use fuse::filesystem::Context;
 use fuse::filesystem::DirectoryIterator;
 use fuse::filesystem::Entry;
+use fuse::filesystem::FileLock;
 use fuse::filesystem::FileSystem;
 use fuse::filesystem::FsOptions;
 use fuse::filesystem::GetxattrReply;
@@ -52,6 +58,7 @@ use fuse::filesystem::SetattrValid;
 use fuse::filesystem::ZeroCopyReader;
 use fuse::filesystem::ZeroCopyWriter;
 use fuse::filesystem::ROOT_ID;
+use fuse::sys::LK_FLOCK;
 use fuse::sys::WRITE_KILL_PRIV;
 use fuse::Mapper;
 #[cfg(feature = "arc_quota")]
@@ -77,6 +84,7 @@ use crate::virtio::fs::caps::Caps;
 use crate::virtio::fs::caps::Set as CapSet;
 use crate::virtio::fs::caps::Value as CapValue;
 use crate::virtio::fs::multikey::MultikeyBTreeMap;
+use crate::virtio::fs::policy::AccessPolicy;
 use crate::virtio::fs::read_dir::ReadDir;
 
 const EMPTY_CSTR: &[u8] = b"\0";
@@ -217,6 +225,9 @@ struct InodeData {
     file: Mutex<(File, libc::c_int)>,
     refcount: AtomicU64,
     filetype: FileType,
+    // Open file descriptions holding the POSIX locks of each lock owner on the file, so that the
+    // locks of different owners conflict and closing the file releases those of one owner only.
+    posix_locks: Mutex<BTreeMap<u64, File>>,
 }
 
 impl AsRawDescriptor for InodeData {
@@ -384,6 +395,41 @@ fn ebadf() -> io::Error {
     io::Error::from_raw_os_error(libc::EBADF)
 }
 
+fn einval() -> io::Error {
+    io::Error::from_raw_os_error(libc::EINVAL)
+}
+
+// Converts a FUSE lock, whose end is inclusive and is `i64::MAX` for locks extending to the end of
+// the file, into the equivalent `flock64`.
+fn to_flock(lock: &FileLock) -> io::Result<libc::flock64> {
+    let len = if lock.end >= i64::MAX as u64 {
+        0
+    } else {
+        lock.end.checked_sub(lock.start).ok_or_else(einval)? as i64 + 1
+    };
+    Ok(libc::flock64 {
+        l_type: lock.type_ as libc::c_short,
+        l_whence: libc::SEEK_SET as libc::c_short,
+        l_start: i64::try_from(lock.start).map_err(|_| einval())?,
+        l_len: len,
+        l_pid: 0,
+    })
+}
+
+fn from_flock(flock: &libc::flock64) -> FileLock {
+    FileLock {
+        start: flock.l_start as u64,
+        end: if flock.l_len == 0 {
+            i64::MAX as u64
+        } else {
+            (flock.l_start + flock.l_len - 1) as u64
+        },
+        type_: flock.l_type as u32,
+        // OFD locks are not owned by a process.
+        pid: 0,
+    }
+}
+
 fn stat<F: AsRawDescriptor + ?Sized>(f: &F) -> io::Result<libc::stat64> {
     let mut st = MaybeUninit::<libc::stat64>::zeroed();
 
@@ -538,6 +584,23 @@ pub struct Config {
     ///
     /// The default value for this option is `true`.
     pub posix_acl: bool,
+
+    /// Forward POSIX and flock locks to the host.
+    ///
+    /// Locks taken in the guest are then also seen by the processes of the host that use the same
+    /// files. Blocking lock requests (`F_SETLKW` and `flock` without `LOCK_NB`) fail with
+    /// `EOPNOTSUPP` since waiting for the lock would stall the file system. Otherwise, locks are
+    /// only handled by the guest kernel. Locks are not forwarded when `cache_policy` allows opens
+    /// without a message to the file system.
+    ///
+    /// The default value for this option is `false`.
+    pub forward_locks: bool,
+
+    /// Restrictions on the guest access to the shared directory. See the documentation of
+    /// `AccessPolicy` for more details.
+    ///
+    /// The default value for this option is `None`.
+    pub access_policy: Option<AccessPolicy>,
 }
 
 impl Default for Config {
@@ -553,6 +616,8 @@ impl Default for Config {
             privileged_quota_uids: Default::default(),
             use_dax: false,
             posix_acl: true,
+            forward_locks: false,
+            access_policy: None,
         }
     }
 }
@@ -591,6 +656,10 @@ pub struct PassthroughFs {
     // Whether zero message opendir is supported by the kernel driver.
     zero_message_opendir: AtomicBool,
 
+    // Number of bytes written by the guest since the file system was created, for enforcing
+    // `AccessPolicy::max_write_bytes`.
+    written_bytes: AtomicU64,
+
     // Used to communicate with other processes using D-Bus.
     #[cfg(feature = "arc_quota")]
     dbus_connection: Option<Mutex<dbus::blocking::Connection>>,
@@ -646,6 +715,7 @@ impl PassthroughFs {
             writeback: AtomicBool::new(false),
             zero_message_open: AtomicBool::new(false),
             zero_message_opendir: AtomicBool::new(false),
+            written_bytes: AtomicU64::new(0),
 
             #[cfg(feature = "arc_quota")]
             dbus_connection,
@@ -706,6 +776,110 @@ impl PassthroughFs {
             .ok_or_else(ebadf)
     }
 
+    // Returns the path of `inode` in the shared directory by reading the `self/fd/{}` symlink. The
+    // path is relative to the root of the process, so it is only the path in the shared directory
+    // when the shared directory is the root, as it is in the pivot_root jail of the vhost-user
+    // device. Fails with `EACCES` if the inode has no such path.
+    fn inode_path(&self, inode: &InodeData) -> io::Result<PathBuf> {
+        let pathname = CString::new(format!("self/fd/{}", inode.as_raw_descriptor()))
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+        let mut buf = vec![0u8; libc::PATH_MAX as usize];
+        // Safe because the kernel will only write up to `buf.len()` bytes to `buf` and we check
+        // the return value.
+        let len = syscall!(unsafe {
+            libc::readlinkat(
+                self.proc.as_raw_descriptor(),
+                pathname.as_ptr(),
+                buf.as_mut_ptr() as *mut libc::c_char,
+                buf.len(),
+            )
+        })?;
+        buf.truncate(len as usize);
+        // Descriptors that don't refer to a path, e.g. anonymous inodes, have a name that doesn't
+        // start with `/`, which no policy path could match.
+        if buf.first() != Some(&b'/') {
+            return Err(io::Error::from_raw_os_error(libc::EACCES));
+        }
+
+        Ok(PathBuf::from(OsString::from_vec(buf)))
+    }
+
+    // Returns true if the access policy hides the entry `name` of the directory `parent`.
+    fn is_hidden(&self, parent: &InodeData, name: &CStr) -> io::Result<bool> {
+        let policy = match &self.cfg.access_policy {
+            Some(policy) if !policy.hidden.is_empty() => policy,
+            _ => return Ok(false),
+        };
+        let name = OsStr::from_bytes(name.to_bytes());
+        if name == "." || name == ".." {
+            return Ok(false);
+        }
+        Ok(policy.is_hidden(&self.inode_path(parent)?.join(name)))
+    }
+
+    // Checks that the access policy allows the guest to modify `inode`, or to create or remove
+    // the entry `name` in `inode` if `name` is set.
+    fn check_writable(&self, inode: &InodeData, name: Option<&CStr>) -> io::Result<()> {
+        let policy = match &self.cfg.access_policy {
+            Some(policy) if policy.restricts_paths() => policy,
+            _ => return Ok(()),
+        };
+        let mut path = self.inode_path(inode)?;
+        if let Some(name) = name {
+            if self.is_hidden(inode, name)? {
+                return Err(io::Error::from_raw_os_error(libc::EACCES));
+            }
+            path.push(OsStr::from_bytes(name.to_bytes()));
+        }
+        if policy.is_read_only(&path) {
+            return Err(io::Error::from_raw_os_error(libc::EROFS));
+        }
+        Ok(())
+    }
+
+    // Runs `write`, which writes up to `size` bytes, and accounts for the bytes that it wrote.
+    // Fails with `ENOSPC` without running `write` if it could go over the limit of the access
+    // policy.
+    fn limit_write<F>(&self, size: u64, write: F) -> io::Result<usize>
+    where
+        F: FnOnce() -> io::Result<usize>,
+    {
+        let max = match self
+            .cfg
+            .access_policy
+            .as_ref()
+            .and_then(|p| p.max_write_bytes)
+        {
+            Some(max) => max,
+            None => return write(),
+        };
+
+        // Reserve `size` bytes up front so that concurrent writes can't go over the limit.
+        self.written_bytes
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |written| {
+                written.checked_add(size).filter(|&total| total <= max)
+            })
+            .map_err(|_| io::Error::from_raw_os_error(libc::ENOSPC))?;
+        let res = write();
+        let written = res.as_ref().map_or(0, |&n| n as u64);
+        self.written_bytes
+            .fetch_sub(size.saturating_sub(written), Ordering::Relaxed);
+        res
+    }
+
+    // Opens a new file description of `inode` for the POSIX locks of an owner. Write locks need it
+    // to be open for writing and read locks for reading, so it is open for both if possible, and
+    // like `handle` otherwise.
+    fn open_lock_file(&self, inode: &InodeData, handle: &HandleData) -> io::Result<File> {
+        self.open_inode(inode, libc::O_RDWR).or_else(|_| {
+            // Safe because this doesn't modify any memory and we check the return value.
+            let flags =
+                syscall!(unsafe { libc::fcntl(handle.as_raw_descriptor(), libc::F_GETFL) })?;
+            self.open_inode(inode, flags & libc::O_ACCMODE)
+        })
+    }
+
     fn open_fd(&self, fd: RawDescriptor, flags: i32) -> io::Result<File> {
         let pathname = CString::new(format!("self/fd/{}", fd))
             .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
@@ -779,6 +953,7 @@ impl PassthroughFs {
                     file: Mutex::new((f, open_flags)),
                     refcount: AtomicU64::new(1),
                     filetype: st.st_mode.into(),
+                    posix_locks: Mutex::new(BTreeMap::new()),
                 }),
             );
 
@@ -815,6 +990,10 @@ impl PassthroughFs {
     }
 
     fn do_lookup(&self, parent: &InodeData, name: &CStr) -> io::Result<Entry> {
+        if self.is_hidden(parent, name)? {
+            return Err(io::Error::from_raw_os_error(libc::ENOENT));
+        }
+
         let st = statat(parent, name)?;
 
         let altkey = InodeAltKey {
@@ -1521,6 +1700,7 @@ impl FileSystem for PassthroughFs {
                 file: Mutex::new((f, flags)),
                 refcount: AtomicU64::new(2),
                 filetype: st.st_mode.into(),
+                posix_locks: Mutex::new(BTreeMap::new()),
             }),
         );
 
@@ -1536,7 +1716,13 @@ impl FileSystem for PassthroughFs {
             self.writeback.store(true, Ordering::Relaxed);
         }
         if self.cfg.cache_policy == CachePolicy::Always {
-            if capable.contains(FsOptions::ZERO_MESSAGE_OPEN) {
+            // The access policy is checked when files are opened.
+            let restricts_paths = self
+                .cfg
+                .access_policy
+                .as_ref()
+                .map_or(false, |p| p.restricts_paths());
+            if capable.contains(FsOptions::ZERO_MESSAGE_OPEN) && !restricts_paths {
                 opts |= FsOptions::ZERO_MESSAGE_OPEN;
                 self.zero_message_open.store(true, Ordering::Relaxed);
             }
@@ -1545,6 +1731,11 @@ impl FileSystem for PassthroughFs {
                 self.zero_message_opendir.store(true, Ordering::Relaxed);
             }
         }
+        // Locks are held by the file descriptors of the handles, so they can only be forwarded when
+        // every open gets its own handle.
+        if self.cfg.forward_locks && !self.zero_message_open.load(Ordering::Relaxed) {
+            opts |= capable & (FsOptions::POSIX_LOCKS | FsOptions::FLOCK_LOCKS);
+        }
         Ok(opts)
     }
 
@@ -1626,6 +1817,7 @@ impl FileSystem for PassthroughFs {
         umask: u32,
     ) -> io::Result<Entry> {
         let data = self.find_inode(parent)?;
+        self.check_writable(&data, Some(name))?;
 
         let (_uid, _gid) = set_creds(ctx.uid, ctx.gid)?;
         {
@@ -1640,6 +1832,7 @@ impl FileSystem for PassthroughFs {
 
     fn rmdir(&self, _ctx: Context, parent: Inode, name: &CStr) -> io::Result<()> {
         let data = self.find_inode(parent)?;
+        self.check_writable(&data, Some(name))?;
         self.do_unlink(&data, name, libc::AT_REMOVEDIR)
     }
 
@@ -1651,17 +1844,39 @@ impl FileSystem for PassthroughFs {
         size: u32,
         offset: u64,
     ) -> io::Result<Self::DirIter> {
-        let buf = vec![0; size as usize].into_boxed_slice();
+        let data = self.find_inode(inode)?;
+        let hidden = match &self.cfg.access_policy {
+            Some(policy) if !policy.hidden.is_empty() => Some((policy, self.inode_path(&data)?)),
+            _ => None,
+        };
 
-        if self.zero_message_opendir.load(Ordering::Relaxed) {
-            let data = self.find_inode(inode)?;
-            ReadDir::new(&*data, offset as libc::off64_t, buf)
-        } else {
-            let data = self.find_handle(handle, inode)?;
+        let mut offset = offset as libc::off64_t;
+        loop {
+            let buf = vec![0; size as usize].into_boxed_slice();
+            let mut read_dir = if self.zero_message_opendir.load(Ordering::Relaxed) {
+                ReadDir::new(&*data, offset, buf)?
+            } else {
+                let handle_data = self.find_handle(handle, inode)?;
+
+                let dir = handle_data.file.lock();
 
-            let dir = data.file.lock();
+                ReadDir::new(&*dir, offset, buf)?
+            };
 
-            ReadDir::new(&*dir, offset as libc::off64_t, buf)
+            let (policy, dir_path) = match &hidden {
+                Some(hidden) => hidden,
+                None => return Ok(read_dir),
+            };
+            let next_offset = read_dir.retain(|name| {
+                let name = OsStr::from_bytes(name.to_bytes());
+                name == "." || name == ".." || !policy.is_hidden(&dir_path.join(name))
+            });
+            // Only return an empty buffer at the end of the directory, as the guest would
+            // otherwise stop reading it.
+            match next_offset {
+                Some(next_offset) if read_dir.remaining() == 0 => offset = next_offset,
+                _ => return Ok(read_dir),
+            }
         }
     }
 
@@ -1674,6 +1889,10 @@ impl FileSystem for PassthroughFs {
         if self.zero_message_open.load(Ordering::Relaxed) {
             Err(io::Error::from_raw_os_error(libc::ENOSYS))
         } else {
+            if flags as i32 & (libc::O_ACCMODE | libc::O_TRUNC) != libc::O_RDONLY {
+                let data = self.find_inode(inode)?;
+                self.check_writable(&data, None)?;
+            }
             self.do_open(inode, flags)
         }
     }
@@ -1703,6 +1922,7 @@ impl FileSystem for PassthroughFs {
         umask: u32,
     ) -> io::Result<Entry> {
         let data = self.find_inode(parent)?;
+        self.check_writable(&data, None)?;
 
         let (_uid, _gid) = set_creds(ctx.uid, ctx.gid)?;
 
@@ -1742,6 +1962,7 @@ impl FileSystem for PassthroughFs {
         umask: u32,
     ) -> io::Result<(Entry, Option<Handle>, OpenOptions)> {
         let data = self.find_inode(parent)?;
+        self.check_writable(&data, Some(name))?;
 
         let (_uid, _gid) = set_creds(ctx.uid, ctx.gid)?;
 
@@ -1784,6 +2005,7 @@ impl FileSystem for PassthroughFs {
 
     fn unlink(&self, _ctx: Context, parent: Inode, name: &CStr) -> io::Result<()> {
         let data = self.find_inode(parent)?;
+        self.check_writable(&data, Some(name))?;
         self.do_unlink(&data, name, 0)
     }
 
@@ -1863,12 +2085,14 @@ impl FileSystem for PassthroughFs {
                 _ => panic!("Unexpected flags: {:#x}", flags),
             }
 
-            r.read_to(&mut file.0, size as usize, offset)
+            self.limit_write(size.into(), || {
+                r.read_to(&mut file.0, size as usize, offset)
+            })
         } else {
             let data = self.find_handle(handle, inode)?;
 
             let mut f = data.file.lock();
-            r.read_to(&mut f, size as usize, offset)
+            self.limit_write(size.into(), || r.read_to(&mut f, size as usize, offset))
         }
     }
 
@@ -1891,6 +2115,7 @@ impl FileSystem for PassthroughFs {
         valid: SetattrValid,
     ) -> io::Result<(libc::stat64, Duration)> {
         let inode_data = self.find_inode(inode)?;
+        self.check_writable(&inode_data, None)?;
 
         enum Data {
             Handle(Arc<HandleData>, RawDescriptor),
@@ -2013,6 +2238,8 @@ impl FileSystem for PassthroughFs {
     ) -> io::Result<()> {
         let old_inode = self.find_inode(olddir)?;
         let new_inode = self.find_inode(newdir)?;
+        self.check_writable(&old_inode, Some(oldname))?;
+        self.check_writable(&new_inode, Some(newname))?;
 
         // Safe because this doesn't modify any memory and we check the return value.
         // TODO: Switch to libc::renameat2 once https://github.com/rust-lang/libc/pull/1508 lands
@@ -2040,6 +2267,7 @@ impl FileSystem for PassthroughFs {
         umask: u32,
     ) -> io::Result<Entry> {
         let data = self.find_inode(parent)?;
+        self.check_writable(&data, Some(name))?;
 
         let (_uid, _gid) = set_creds(ctx.uid, ctx.gid)?;
 
@@ -2069,6 +2297,9 @@ impl FileSystem for PassthroughFs {
     ) -> io::Result<Entry> {
         let data = self.find_inode(inode)?;
         let new_inode = self.find_inode(newparent)?;
+        // The new link mustn't give write access to a file of a read-only subtree.
+        self.check_writable(&data, None)?;
+        self.check_writable(&new_inode, Some(newname))?;
 
         let path = CString::new(format!("self/fd/{}", data.as_raw_descriptor()))
             .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
@@ -2095,6 +2326,7 @@ impl FileSystem for PassthroughFs {
         name: &CStr,
     ) -> io::Result<Entry> {
         let data = self.find_inode(parent)?;
+        self.check_writable(&data, Some(name))?;
 
         let (_uid, _gid) = set_creds(ctx.uid, ctx.gid)?;
 
@@ -2133,12 +2365,19 @@ impl FileSystem for PassthroughFs {
         _ctx: Context,
         inode: Inode,
         handle: Handle,
-        _lock_owner: u64,
+        lock_owner: u64,
     ) -> io::Result<()> {
         let data: Arc<dyn AsRawDescriptor> = if self.zero_message_open.load(Ordering::Relaxed) {
             self.find_inode(inode)?
         } else {
-            self.find_handle(handle, inode)?
+            let data = self.find_handle(handle, inode)?;
+            // Closing a file releases all the POSIX locks of its owner on it, which are dropped
+            // along with their open file description.
+            self.find_inode(inode)?
+                .posix_locks
+                .lock()
+                .remove(&lock_owner);
+            data
         };
 
         // Since this method is called whenever an fd is closed in the client, we can emulate that
@@ -2247,6 +2486,7 @@ impl FileSystem for PassthroughFs {
         }
 
         let data = self.find_inode(inode)?;
+        self.check_writable(&data, None)?;
         let name = self.rewrite_xattr_name(name);
         let file = data.file.lock();
         let o_path_file = (file.1 & libc::O_PATH) != 0;
@@ -2367,6 +2607,7 @@ impl FileSystem for PassthroughFs {
         }
 
         let data = self.find_inode(inode)?;
+        self.check_writable(&data, None)?;
         let name = self.rewrite_xattr_name(name);
 
         let file = data.file.lock();
@@ -2400,6 +2641,8 @@ impl FileSystem for PassthroughFs {
         offset: u64,
         length: u64,
     ) -> io::Result<()> {
+        self.check_writable(&*self.find_inode(inode)?, None)?;
+
         let data: Arc<dyn AsRawDescriptor> = if self.zero_message_open.load(Ordering::Relaxed) {
             let data = self.find_inode(inode)?;
 
@@ -2427,14 +2670,17 @@ impl FileSystem for PassthroughFs {
         };
 
         let fd = data.as_raw_descriptor();
-        // Safe because this doesn't modify any memory and we check the return value.
-        syscall!(unsafe {
-            libc::fallocate64(
-                fd,
-                mode as libc::c_int,
-                offset as libc::off64_t,
-                length as libc::off64_t,
-            )
+        self.limit_write(length, || {
+            // Safe because this doesn't modify any memory and we check the return value.
+            syscall!(unsafe {
+                libc::fallocate64(
+                    fd,
+                    mode as libc::c_int,
+                    offset as libc::off64_t,
+                    length as libc::off64_t,
+                )
+            })?;
+            Ok(length as usize)
         })?;
 
         Ok(())
@@ -2462,6 +2708,11 @@ impl FileSystem for PassthroughFs {
         const ENABLE_VERITY: u32 = FS_IOC_ENABLE_VERITY() as u32;
         const MEASURE_VERITY: u32 = FS_IOC_MEASURE_VERITY() as u32;
 
+        if matches!(cmd, SET_FSXATTR | SET_FLAGS32 | SET_FLAGS64 | ENABLE_VERITY) {
+            let data = self.find_inode(inode)?;
+            self.check_writable(&data, None)?;
+        }
+
         match cmd {
             GET_ENCRYPTION_POLICY_EX => self.get_encryption_policy_ex(inode, handle, r),
             GET_FSXATTR => {
@@ -2540,17 +2791,122 @@ impl FileSystem for PassthroughFs {
         let src = src_data.as_raw_descriptor();
         let dst = dst_data.as_raw_descriptor();
 
-        Ok(syscall!(unsafe {
-            libc::syscall(
-                libc::SYS_copy_file_range,
-                src,
-                &offset_src,
-                dst,
-                &offset_dst,
-                length,
-                flags,
-            )
-        })? as usize)
+        self.limit_write(length, || {
+            Ok(syscall!(unsafe {
+                libc::syscall(
+                    libc::SYS_copy_file_range,
+                    src,
+                    &offset_src,
+                    dst,
+                    &offset_dst,
+                    length,
+                    flags,
+                )
+            })? as usize)
+        })
+    }
+
+    fn getlk(
+        &self,
+        _ctx: Context,
+        inode: Inode,
+        handle: Handle,
+        owner: u64,
+        lock: FileLock,
+        _flags: u32,
+    ) -> io::Result<FileLock> {
+        let data = self.find_handle(handle, inode)?;
+        let inode_data = self.find_inode(inode)?;
+        let posix_locks = inode_data.posix_locks.lock();
+        // The locks of `owner` itself don't conflict with `lock`.
+        let fd = match posix_locks.get(&owner) {
+            Some(file) => file.as_raw_descriptor(),
+            None => data.as_raw_descriptor(),
+        };
+
+        let mut flock = to_flock(&lock)?;
+        // Safe because the kernel will only write data in `flock` and we check the return value.
+        syscall!(unsafe { libc::fcntl(fd, libc::F_OFD_GETLK, &mut flock) })?;
+        Ok(from_flock(&flock))
+    }
+
+    fn setlk(
+        &self,
+        _ctx: Context,
+        inode: Inode,
+        handle: Handle,
+        owner: u64,
+        lock: FileLock,
+        flags: u32,
+    ) -> io::Result<()> {
+        let data = self.find_handle(handle, inode)?;
+
+        if flags & LK_FLOCK != 0 {
+            let operation = match lock.type_ as c_int {
+                libc::F_RDLCK => libc::LOCK_SH,
+                libc::F_WRLCK => libc::LOCK_EX,
+                libc::F_UNLCK => libc::LOCK_UN,
+                _ => return Err(einval()),
+            };
+            // Safe because this doesn't modify any memory and we check the return value.
+            syscall!(unsafe { libc::flock(data.as_raw_descriptor(), operation | libc::LOCK_NB) })?;
+            return Ok(());
+        }
+
+        let flock = to_flock(&lock)?;
+        let inode_data = self.find_inode(inode)?;
+        let mut posix_locks = inode_data.posix_locks.lock();
+        let file = match posix_locks.entry(owner) {
+            btree_map::Entry::Occupied(entry) => entry.into_mut(),
+            // An owner without locks has nothing to unlock.
+            btree_map::Entry::Vacant(_) if lock.type_ == libc::F_UNLCK as u32 => return Ok(()),
+            btree_map::Entry::Vacant(entry) => {
+                entry.insert(self.open_lock_file(&inode_data, &data)?)
+            }
+        };
+        // Safe because this only reads `flock` and we check the return value.
+        syscall!(unsafe { libc::fcntl(file.as_raw_descriptor(), libc::F_OFD_SETLK, &flock) })?;
+        Ok(())
+    }
+
+    fn setlkw(
+        &self,
+        _ctx: Context,
+        _inode: Inode,
+        _handle: Handle,
+        _owner: u64,
+        _lock: FileLock,
+        _flags: u32,
+    ) -> io::Result<()> {
+        // Waiting for the lock would block the queue worker, which then couldn't process the
+        // request releasing the conflicting lock.
+        Err(io::Error::from_raw_os_error(libc::EOPNOTSUPP))
+    }
+
+    fn lseek(
+        &self,
+        _ctx: Context,
+        inode: Inode,
+        handle: Handle,
+        offset: u64,
+        whence: u32,
+    ) -> io::Result<u64> {
+        let data: Arc<dyn AsRawDescriptor> = if self.zero_message_open.load(Ordering::Relaxed) {
+            self.find_inode(inode)?
+        } else {
+            self.find_handle(handle, inode)?
+        };
+
+        let whence = whence as c_int;
+        if whence != libc::SEEK_DATA && whence != libc::SEEK_HOLE {
+            return Err(einval());
+        }
+        let offset = i64::try_from(offset).map_err(|_| einval())?;
+
+        // Safe because this doesn't modify any memory and we check the return value. Reads and
+        // writes use explicit offsets so moving the file position doesn't affect them.
+        let offset = syscall!(unsafe { libc::lseek64(data.as_raw_descriptor(), offset, whence) })?;
+        Ok(offset as u64)
     }
 
     fn set_up_mapping<M: Mapper>(
@@ -2579,6 +2935,18 @@ impl FileSystem for PassthroughFs {
 
         let data = self.find_inode(inode)?;
 
+        if write {
+            self.check_writable(&data, None)?;
+            // Writes to shared mappings can't be accounted for.
+            if let Some(AccessPolicy {
+                max_write_bytes: Some(_),
+                ..
+            }) = self.cfg.access_policy
+            {
+                return Err(io::Error::from_raw_os_error(libc::EPERM));
+            }
+        }
+
         if self.zero_message_open.load(Ordering::Relaxed) {
             let mut file = data.file.lock();
             let mut open_flags = file.1;
@@ -2653,6 +3021,48 @@ mod tests {
         );
     }
 
+    #[test]
+    fn flock_conversion() {
+        let lock = FileLock {
+            start: 10,
+            end: 19,
+            type_: libc::F_WRLCK as u32,
+            pid: 1234,
+        };
+        let flock = to_flock(&lock).unwrap();
+        assert_eq!(flock.l_start, 10);
+        assert_eq!(flock.l_len, 10);
+        assert_eq!(flock.l_type, libc::F_WRLCK as libc::c_short);
+        assert_eq!(flock.l_pid, 0);
+        let back = from_flock(&flock);
+        assert_eq!((back.start, back.end, back.type_), (10, 19, lock.type_));
+
+        // A lock to the end of the file.
+        let lock = FileLock {
+            start: 4096,
+            end: i64::MAX as u64,
+            type_: libc::F_RDLCK as u32,
+            pid: 0,
+        };
+        let flock = to_flock(&lock).unwrap();
+        assert_eq!(flock.l_len, 0);
+        assert_eq!(from_flock(&flock).end, i64::MAX as u64);
+
+        // Invalid ranges.
+        let lock = FileLock {
+            start: 20,
+            end: 10,
+            ..Default::default()
+        };
+        assert!(to_flock(&lock).is_err());
+        let lock = FileLock {
+            start: u64::MAX - 1,
+            end: u64::MAX,
+            ..Default::default()
+        };
+        assert!(to_flock(&lock).is_err());
+    }
+
     #[test]
     fn strip_xattr_names() {
         let only_nuls = b"\0\0\0\0\0";
@@ -2691,4 +3101,123 @@ mod tests {
         strip_xattr_prefix(&mut actual);
         assert_eq!(&actual[..], b"security.sehash");
     }
+
+    #[test]
+    fn forward_locks() {
+        let locks = FsOptions::POSIX_LOCKS | FsOptions::FLOCK_LOCKS;
+
+        let fs = PassthroughFs::new(Default::default()).unwrap();
+        assert!(!fs.init(locks).unwrap().intersects(locks));
+
+        let cfg = Config {
+            forward_locks: true,
+            ..Default::default()
+        };
+        let fs = PassthroughFs::new(cfg).unwrap();
+        assert!(fs.init(locks).unwrap().contains(locks));
+    }
+
+    #[test]
+    fn posix_locks() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("file");
+        File::create(&path).unwrap();
+
+        let fs = PassthroughFs::new(Default::default()).unwrap();
+        fs.init(FsOptions::empty()).unwrap();
+        let ctx = Context {
+            uid: 0,
+            gid: 0,
+            pid: 0,
+        };
+        let inode = path
+            .components()
+            .skip(1)
+            .fold(ROOT_ID, |parent, component| {
+                let name = CString::new(component.as_os_str().as_bytes()).unwrap();
+                fs.lookup(ctx, parent, &name).unwrap().inode
+            });
+        let handle1 = fs.open(ctx, inode, libc::O_RDWR as u32).unwrap().0.unwrap();
+        let handle2 = fs
+            .open(ctx, inode, libc::O_RDONLY as u32)
+            .unwrap()
+            .0
+            .unwrap();
+        let lock = |type_: c_int| FileLock {
+            start: 0,
+            end: i64::MAX as u64,
+            type_: type_ as u32,
+            pid: 0,
+        };
+        let errno = |res: io::Result<()>| res.unwrap_err().raw_os_error();
+
+        // The locks of different owners conflict, whichever handle they go through.
+        fs.setlk(ctx, inode, handle1, 1, lock(libc::F_WRLCK), 0)
+            .unwrap();
+        assert_eq!(
+            errno(fs.setlk(ctx, inode, handle1, 2, lock(libc::F_RDLCK), 0)),
+            Some(libc::EAGAIN)
+        );
+        let conflict = fs
+            .getlk(ctx, inode, handle2, 2, lock(libc::F_RDLCK), 0)
+            .unwrap();
+        assert_eq!(conflict.type_, libc::F_WRLCK as u32);
+        let conflict = fs
+            .getlk(ctx, inode, handle2, 1, lock(libc::F_RDLCK), 0)
+            .unwrap();
+        assert_eq!(conflict.type_, libc::F_UNLCK as u32);
+        assert_eq!(
+            errno(fs.setlkw(ctx, inode, handle2, 2, lock(libc::F_RDLCK), 0)),
+            Some(libc::EOPNOTSUPP)
+        );
+
+        // Closing the file only releases the locks of the owner closing it.
+        fs.flush(ctx, inode, handle2, 2).unwrap();
+        assert_eq!(
+            errno(fs.setlk(ctx, inode, handle2, 2, lock(libc::F_RDLCK), 0)),
+            Some(libc::EAGAIN)
+        );
+        fs.flush(ctx, inode, handle2, 1).unwrap();
+        fs.setlk(ctx, inode, handle2, 2, lock(libc::F_RDLCK), 0)
+            .unwrap();
+    }
+
+    #[test]
+    fn fallocate_write_limit() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("file");
+        File::create(&path).unwrap();
+
+        let cfg = Config {
+            access_policy: Some(AccessPolicy {
+                max_write_bytes: Some(8192),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let fs = PassthroughFs::new(cfg).unwrap();
+        fs.init(FsOptions::empty()).unwrap();
+        let ctx = Context {
+            uid: 0,
+            gid: 0,
+            pid: 0,
+        };
+        let inode = path
+            .components()
+            .skip(1)
+            .fold(ROOT_ID, |parent, component| {
+                let name = CString::new(component.as_os_str().as_bytes()).unwrap();
+                fs.lookup(ctx, parent, &name).unwrap().inode
+            });
+        let handle = fs.open(ctx, inode, libc::O_RDWR as u32).unwrap().0.unwrap();
+
+        fs.fallocate(ctx, inode, handle, 0, 0, 8192).unwrap();
+        assert_eq!(
+            fs.fallocate(ctx, inode, handle, 0, 8192, 1)
+                .unwrap_err()
+                .raw_os_error(),
+            Some(libc::ENOSPC)
+        );
+        assert_eq!(path.metadata().unwrap().len(), 8192);
+    }
 }
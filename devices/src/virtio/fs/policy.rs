@@ -0,0 +1,224 @@
+// Copyright 2023 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Access policy restricting what the guest can see and modify in a shared directory.
+
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Component;
+use std::path::Path;
+use std::path::PathBuf;
+
+use anyhow::Context;
+use serde::Deserialize;
+use serde::Serialize;
+
+/// Restrictions on the guest access to a shared directory, in addition to the permissions of the
+/// host files. All the paths are relative to the root of the shared directory, which the device
+/// must serve as the root of its process, e.g. from a pivot_root jail, for them to match.
+///
+/// The policy is usually loaded from a JSON file such as:
+///
+/// ```json
+/// {
+///     "read_only": ["/src", "/.git"],
+///     "hidden": ["**/.ssh", "*.pem", "/secrets/*"],
+///     "max_write_bytes": 1073741824
+/// }
+/// ```
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AccessPolicy {
+    /// Subtrees that the guest can read but not modify.
+    #[serde(default)]
+    pub read_only: Vec<PathBuf>,
+    /// Glob patterns of the paths hidden from the guest. `*` and `?` match any characters but
+    /// `/`, and `**` matches any characters. Patterns without a `/` match file names at any depth.
+    #[serde(default)]
+    pub hidden: Vec<String>,
+    /// Maximum number of bytes that the guest can write to files of the shared directory while the
+    /// device runs. This is a budget for the session rather than a quota on the disk usage: it
+    /// counts the bytes of every write, fallocate and copy, including overwrites, and starts over
+    /// when the device is restarted.
+    #[serde(default)]
+    pub max_write_bytes: Option<u64>,
+}
+
+impl AccessPolicy {
+    /// Loads the policy from the JSON file at `path`.
+    pub fn from_file(path: &Path) -> anyhow::Result<AccessPolicy> {
+        let file = File::open(path)
+            .with_context(|| format!("failed to open access policy {}", path.display()))?;
+        let policy: AccessPolicy = serde_json::from_reader(BufReader::new(file))
+            .with_context(|| format!("failed to parse access policy {}", path.display()))?;
+        Ok(policy)
+    }
+
+    /// Returns true if the policy restricts which paths the guest can see or modify.
+    pub fn restricts_paths(&self) -> bool {
+        !self.read_only.is_empty() || !self.hidden.is_empty()
+    }
+
+    /// Returns true if `path` is in a read-only subtree.
+    pub fn is_read_only(&self, path: &Path) -> bool {
+        let path = normalize(path);
+        self.read_only
+            .iter()
+            .any(|subtree| path.starts_with(normalize(subtree)))
+    }
+
+    /// Returns true if `path` matches one of the hidden patterns.
+    pub fn is_hidden(&self, path: &Path) -> bool {
+        let path = normalize(path);
+        let path = match path.to_str() {
+            Some(path) => path,
+            // Patterns are UTF-8 so they can't match a path that isn't.
+            None => return false,
+        };
+        let name = path.rsplit('/').next().unwrap_or(path);
+        self.hidden.iter().any(|pattern| {
+            if pattern.contains('/') {
+                glob_match(pattern.trim_start_matches('/').as_bytes(), path.as_bytes())
+            } else {
+                glob_match(pattern.as_bytes(), name.as_bytes())
+            }
+        })
+    }
+}
+
+// Returns `path` without its root and `.` components, so that "/a/./b" and "a/b" are equal.
+fn normalize(path: &Path) -> PathBuf {
+    path.components()
+        .filter(|c| matches!(c, Component::Normal(_) | Component::ParentDir))
+        .collect()
+}
+
+// Matches `text` against the glob `pattern`, where `*` and `?` don't match `/` but `**` does.
+// A `**/` at the start of a path component also matches no directory at all.
+//
+// The text comes from the guest, so this doesn't backtrack recursively: after a mismatch, only the
+// last `*` is extended, or the last `**` once the `*` reaches a `/`, which takes O(n * m) time.
+fn glob_match(pattern: &[u8], text: &[u8]) -> bool {
+    let (mut p, mut t) = (0, 0);
+    // The position in the pattern after the last `*`, and the end of the text it matches.
+    let mut star: Option<(usize, usize)> = None;
+    // The same for the last `**`, and whether it is followed by a `/`.
+    let mut double_star: Option<(usize, usize, bool)> = None;
+    loop {
+        if pattern.get(p) == Some(&b'*') {
+            if pattern.get(p + 1) == Some(&b'*') {
+                // "**/" starting a path component only matches whole directories, possibly none.
+                let slash = pattern.get(p + 2) == Some(&b'/') && (p == 0 || pattern[p - 1] == b'/');
+                p += if slash { 3 } else { 2 };
+                double_star = Some((p, t, slash));
+                star = None;
+            } else {
+                p += 1;
+                star = Some((p, t));
+            }
+            continue;
+        }
+        if p == pattern.len() && t == text.len() {
+            return true;
+        }
+        if let (Some(&pc), Some(&tc)) = (pattern.get(p), text.get(t)) {
+            if pc == tc || (pc == b'?' && tc != b'/') {
+                p += 1;
+                t += 1;
+                continue;
+            }
+        }
+        if let Some((star_p, star_t)) = star {
+            if matches!(text.get(star_t), Some(&c) if c != b'/') {
+                star = Some((star_p, star_t + 1));
+                p = star_p;
+                t = star_t + 1;
+                continue;
+            }
+        }
+        if let Some((star_p, star_t, slash)) = double_star {
+            let next_t = if slash {
+                text[star_t..]
+                    .iter()
+                    .position(|&c| c == b'/')
+                    .map(|i| star_t + i + 1)
+            } else if star_t < text.len() {
+                Some(star_t + 1)
+            } else {
+                None
+            };
+            if let Some(next_t) = next_t {
+                double_star = Some((star_p, next_t, slash));
+                star = None;
+                p = star_p;
+                t = next_t;
+                continue;
+            }
+        }
+        return false;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn globs() {
+        assert!(glob_match(b"*.pem", b"key.pem"));
+        assert!(!glob_match(b"*.pem", b"dir/key.pem"));
+        assert!(glob_match(b"secrets/*", b"secrets/a"));
+        assert!(!glob_match(b"secrets/*", b"secrets/a/b"));
+        assert!(glob_match(b"**/.ssh", b".ssh"));
+        assert!(glob_match(b"**/.ssh", b"home/user/.ssh"));
+        assert!(!glob_match(b"**/.ssh", b"home/user/.sshd"));
+        assert!(!glob_match(b"**/.ssh", b"home/x.ssh"));
+        assert!(glob_match(b"a/**", b"a/b/c"));
+        assert!(glob_match(b"file?.txt", b"file1.txt"));
+        assert!(!glob_match(b"file?.txt", b"file10.txt"));
+        assert!(glob_match(b"**/*.pem", b"a/b/key.pem"));
+        assert!(!glob_match(b"**/*.pem", b"a/b/key.pem/c"));
+        assert!(glob_match(b"a/**/b/*", b"a/x/b/y/b/z"));
+        assert!(!glob_match(b"a/**/b/*", b"a/x/b/y/z"));
+        assert!(glob_match(b"**", b""));
+        assert!(glob_match(b"*a*b", b"xaab"));
+    }
+
+    #[test]
+    fn globs_pathological() {
+        // Exponential with backtracking at every star.
+        let name = vec![b'a'; 4096];
+        assert!(!glob_match(b"*a*a*a*a*a*a*a*a*a*a*b", &name));
+        assert!(!glob_match(b"**a**a**a**a**a**a**a**a**b", &name));
+    }
+
+    #[test]
+    fn policy() {
+        let policy: AccessPolicy = serde_json::from_str(
+            r#"{
+                "read_only": ["/src", "data/ro"],
+                "hidden": ["*.pem", "/secrets/**", "**/.git/config"]
+            }"#,
+        )
+        .unwrap();
+
+        assert!(policy.restricts_paths());
+        assert_eq!(policy.max_write_bytes, None);
+
+        assert!(policy.is_read_only(Path::new("/src")));
+        assert!(policy.is_read_only(Path::new("/src/main.rs")));
+        assert!(policy.is_read_only(Path::new("/data/./ro/file")));
+        assert!(!policy.is_read_only(Path::new("/srcs")));
+        assert!(!policy.is_read_only(Path::new("/data")));
+
+        assert!(policy.is_hidden(Path::new("/key.pem")));
+        assert!(policy.is_hidden(Path::new("/a/b/key.pem")));
+        assert!(policy.is_hidden(Path::new("/secrets/token")));
+        assert!(policy.is_hidden(Path::new("/repo/.git/config")));
+        assert!(!policy.is_hidden(Path::new("/secrets")));
+        assert!(!policy.is_hidden(Path::new("/repo/.git/HEAD")));
+
+        assert!(serde_json::from_str::<AccessPolicy>(r#"{"readonly": []}"#).is_err());
+    }
+}
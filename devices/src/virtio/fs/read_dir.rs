@@ -57,6 +57,29 @@ impl<P: DerefMut<Target = [u8]>> ReadDir<P> {
             end: res as usize,
         })
     }
+
+    /// Removes the entries whose name doesn't satisfy `f` from the internal buffer. Returns the
+    /// offset of the entry following the last one that was read, or `None` if there were no
+    /// entries left.
+    pub fn retain<F: FnMut(&CStr) -> bool>(&mut self, mut f: F) -> Option<libc::off64_t> {
+        let mut next_offset = None;
+        let mut read = self.current;
+        let mut write = self.current;
+        while read < self.end {
+            let dirent64 = LinuxDirent64::read_from_prefix(&self.buf[read..])
+                .expect("unable to get LinuxDirent64 from slice");
+            let reclen = dirent64.d_reclen as usize;
+            let name = strip_padding(&self.buf[read + size_of::<LinuxDirent64>()..read + reclen]);
+            if f(name) {
+                self.buf.copy_within(read..read + reclen, write);
+                write += reclen;
+            }
+            next_offset = Some(dirent64.d_off);
+            read += reclen;
+        }
+        self.end = write;
+        next_offset
+    }
 }
 
 impl<P> ReadDir<P> {
@@ -4,6 +4,7 @@
 
 mod edid;
 mod parameters;
+mod png;
 mod protocol;
 mod virtio_gpu;
 
@@ -11,6 +12,8 @@ use std::cell::RefCell;
 use std::collections::BTreeMap;
 use std::collections::VecDeque;
 use std::io::Read;
+#[cfg(unix)]
+use std::net::TcpListener;
 use std::path::PathBuf;
 use std::rc::Rc;
 use std::sync::atomic::AtomicBool;
@@ -116,6 +119,26 @@ pub struct VirtioScanoutBlobData {
     pub offsets: [u32; 4],
 }
 
+/// The area of a resource that is shown on a scanout.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct VirtioScanoutRect {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl From<virtio_gpu_rect> for VirtioScanoutRect {
+    fn from(r: virtio_gpu_rect) -> Self {
+        VirtioScanoutRect {
+            x: r.x.to_native(),
+            y: r.y.to_native(),
+            width: r.width.to_native(),
+            height: r.height.to_native(),
+        }
+    }
+}
+
 #[derive(PartialEq, Eq, PartialOrd, Ord)]
 enum VirtioGpuRing {
     Global,
@@ -387,6 +410,7 @@ impl Frontend {
             GpuCommand::SetScanout(info) => self.virtio_gpu.set_scanout(
                 info.scanout_id.to_native(),
                 info.resource_id.to_native(),
+                info.r.into(),
                 None,
             ),
             GpuCommand::ResourceFlush(info) => {
@@ -609,7 +633,7 @@ impl Frontend {
                 };
 
                 self.virtio_gpu
-                    .set_scanout(scanout_id, resource_id, Some(scanout))
+                    .set_scanout(scanout_id, resource_id, info.r.into(), Some(scanout))
             }
             GpuCommand::ResourceMapBlob(info) => {
                 let resource_id = info.resource_id.to_native();
@@ -1018,6 +1042,9 @@ pub enum DisplayBackend {
     #[cfg(unix)]
     /// Open a connection to the X server at the given display if given.
     X(Option<String>),
+    #[cfg(unix)]
+    /// Serve the display to the VNC clients connecting to the given nonblocking listener.
+    Vnc(Arc<TcpListener>),
     /// Emulate a display without actually displaying it.
     Stub,
     #[cfg(windows)]
@@ -1035,6 +1062,8 @@ impl DisplayBackend {
             DisplayBackend::Wayland(path) => GpuDisplay::open_wayland(path.as_ref()),
             #[cfg(unix)]
             DisplayBackend::X(display) => GpuDisplay::open_x(display.as_ref()),
+            #[cfg(unix)]
+            DisplayBackend::Vnc(listener) => GpuDisplay::open_vnc(listener.try_clone()?),
             DisplayBackend::Stub => GpuDisplay::open_stub(),
             #[cfg(windows)]
             DisplayBackend::WinApi(display_properties) => match wndproc_thread.take() {
@@ -1303,6 +1332,13 @@ impl VirtioDevice for Gpu {
             resource_bridges.append_raw_descriptors(&mut keep_rds);
         }
 
+        #[cfg(unix)]
+        for display_backend in &self.display_backends {
+            if let DisplayBackend::Vnc(listener) = display_backend {
+                keep_rds.push(listener.as_raw_descriptor());
+            }
+        }
+
         keep_rds
     }
 
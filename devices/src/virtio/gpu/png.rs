@@ -0,0 +1,124 @@
+// Copyright 2023 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Minimal PNG encoder for the screenshots of the displays.
+
+use std::io;
+use std::io::Write;
+
+use flate2::write::ZlibEncoder;
+use flate2::Compression;
+use flate2::Crc;
+
+const PNG_SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1a, b'\n'];
+const BIT_DEPTH: u8 = 8;
+const COLOR_TYPE_RGB: u8 = 2;
+const FILTER_NONE: u8 = 0;
+
+fn write_chunk<W: Write>(w: &mut W, chunk_type: &[u8; 4], data: &[u8]) -> io::Result<()> {
+    let mut crc = Crc::new();
+    crc.update(chunk_type);
+    crc.update(data);
+    w.write_all(&(data.len() as u32).to_be_bytes())?;
+    w.write_all(chunk_type)?;
+    w.write_all(data)?;
+    w.write_all(&crc.sum().to_be_bytes())
+}
+
+/// Writes the `width`x`height` image made of the 4 byte pixels of `pixels`, whose rows are `stride`
+/// bytes apart, to `w` as an opaque RGB PNG image. `rgb_offsets` are the offsets of the red, green
+/// and blue bytes in each pixel.
+pub fn write_png<W: Write>(
+    mut w: W,
+    width: u32,
+    height: u32,
+    stride: usize,
+    rgb_offsets: [usize; 3],
+    pixels: &[u8],
+) -> io::Result<()> {
+    if rgb_offsets.iter().any(|&offset| offset >= 4) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "color outside of the pixels",
+        ));
+    }
+    let row_len = width as usize * 4;
+    if stride < row_len || pixels.len() < stride * (height as usize).saturating_sub(1) + row_len {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "image larger than its pixels",
+        ));
+    }
+
+    w.write_all(&PNG_SIGNATURE)?;
+
+    let mut header = Vec::with_capacity(13);
+    header.extend_from_slice(&width.to_be_bytes());
+    header.extend_from_slice(&height.to_be_bytes());
+    // Default compression, filter and interlace methods.
+    header.extend_from_slice(&[BIT_DEPTH, COLOR_TYPE_RGB, 0, 0, 0]);
+    write_chunk(&mut w, b"IHDR", &header)?;
+
+    let mut encoder = ZlibEncoder::new(Vec::new(), Compression::fast());
+    let mut row = Vec::with_capacity(1 + width as usize * 3);
+    for y in 0..height as usize {
+        row.clear();
+        row.push(FILTER_NONE);
+        for pixel in pixels[y * stride..y * stride + row_len].chunks_exact(4) {
+            row.extend(rgb_offsets.iter().map(|&offset| pixel[offset]));
+        }
+        encoder.write_all(&row)?;
+    }
+    write_chunk(&mut w, b"IDAT", &encoder.finish()?)?;
+
+    write_chunk(&mut w, b"IEND", &[])?;
+    w.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use flate2::read::ZlibDecoder;
+
+    use super::*;
+
+    #[test]
+    fn encode_png() {
+        // 2x2 image with a row padding of 4 bytes.
+        let pixels = [
+            0x10, 0x20, 0x30, 0xff, 0x40, 0x50, 0x60, 0xff, 0, 0, 0, 0, //
+            0x70, 0x80, 0x90, 0xff, 0xa0, 0xb0, 0xc0, 0xff, 0, 0, 0, 0,
+        ];
+        let mut png = Vec::new();
+        write_png(&mut png, 2, 2, 12, [2, 1, 0], &pixels).unwrap();
+
+        assert_eq!(png[..8], PNG_SIGNATURE);
+        // IHDR
+        assert_eq!(png[8..16], [0, 0, 0, 13, b'I', b'H', b'D', b'R']);
+        assert_eq!(png[16..29], [0, 0, 0, 2, 0, 0, 0, 2, 8, 2, 0, 0, 0]);
+        let mut crc = Crc::new();
+        crc.update(&png[12..29]);
+        assert_eq!(png[29..33], crc.sum().to_be_bytes());
+        // IDAT
+        let idat_len = u32::from_be_bytes(png[33..37].try_into().unwrap()) as usize;
+        assert_eq!(png[37..41], *b"IDAT");
+        let mut data = Vec::new();
+        ZlibDecoder::new(&png[41..41 + idat_len])
+            .read_to_end(&mut data)
+            .unwrap();
+        assert_eq!(
+            data,
+            [0, 0x30, 0x20, 0x10, 0x60, 0x50, 0x40, 0, 0x90, 0x80, 0x70, 0xc0, 0xb0, 0xa0]
+        );
+        // IEND
+        assert_eq!(
+            png[45 + idat_len..],
+            [0, 0, 0, 0, b'I', b'E', b'N', b'D', 0xae, 0x42, 0x60, 0x82]
+        );
+
+        assert!(write_png(&mut Vec::new(), 4, 2, 12, [2, 1, 0], &pixels).is_err());
+        assert!(write_png(&mut Vec::new(), 2, 2, 12, [1, 2, 4], &pixels).is_err());
+    }
+}
@@ -5,6 +5,8 @@
 use std::cell::RefCell;
 use std::collections::BTreeMap as Map;
 use std::collections::BTreeSet as Set;
+use std::fs::File;
+use std::io::BufWriter;
 use std::num::NonZeroU32;
 use std::rc::Rc;
 use std::result::Result;
@@ -13,11 +15,14 @@ use std::sync::atomic::Ordering;
 use std::sync::Arc;
 
 use base::error;
+use base::FromRawDescriptor;
+use base::IntoRawDescriptor;
 use base::Protection;
 use base::SafeDescriptor;
 use data_model::VolatileSlice;
 use gpu_display::*;
 use libc::c_void;
+use rutabaga_gfx::DrmFormat;
 use rutabaga_gfx::ResourceCreate3D;
 use rutabaga_gfx::ResourceCreateBlob;
 use rutabaga_gfx::Rutabaga;
@@ -40,13 +45,23 @@ use vm_memory::udmabuf::UdmabufDriverTrait;
 use vm_memory::GuestAddress;
 use vm_memory::GuestMemory;
 
+use super::png;
 use super::protocol::GpuResponse;
 use super::protocol::GpuResponse::*;
 use super::protocol::GpuResponsePlaneInfo;
 use super::protocol::VirtioGpuResult;
 use super::protocol::VIRTIO_GPU_BLOB_FLAG_CREATE_GUEST_HANDLE;
 use super::protocol::VIRTIO_GPU_BLOB_MEM_HOST3D;
+use super::protocol::VIRTIO_GPU_FORMAT_A8B8G8R8_UNORM;
+use super::protocol::VIRTIO_GPU_FORMAT_A8R8G8B8_UNORM;
+use super::protocol::VIRTIO_GPU_FORMAT_B8G8R8A8_UNORM;
+use super::protocol::VIRTIO_GPU_FORMAT_B8G8R8X8_UNORM;
+use super::protocol::VIRTIO_GPU_FORMAT_R8G8B8A8_UNORM;
+use super::protocol::VIRTIO_GPU_FORMAT_R8G8B8X8_UNORM;
+use super::protocol::VIRTIO_GPU_FORMAT_X8B8G8R8_UNORM;
+use super::protocol::VIRTIO_GPU_FORMAT_X8R8G8B8_UNORM;
 use super::VirtioScanoutBlobData;
+use super::VirtioScanoutRect;
 use crate::virtio::gpu::edid::DisplayInfo;
 use crate::virtio::gpu::edid::EdidBytes;
 use crate::virtio::gpu::GpuDisplayParameters;
@@ -62,6 +77,8 @@ struct VirtioGpuResource {
     width: u32,
     height: u32,
     size: u64,
+    // The virtio-gpu format of resources that aren't blobs.
+    format: Option<u32>,
     shmem_offset: Option<u64>,
     scanout_data: Option<VirtioScanoutBlobData>,
     display_import: Option<u32>,
@@ -77,6 +94,7 @@ impl VirtioGpuResource {
             width,
             height,
             size,
+            format: None,
             shmem_offset: None,
             scanout_data: None,
             display_import: None,
@@ -97,6 +115,8 @@ struct VirtioGpuScanout {
     display_params: Option<GpuDisplayParameters>,
     // If this scanout is a cursor scanout, the scanout that this is cursor is overlayed onto.
     parent_surface_id: Option<u32>,
+    // If this scanout is a primary scanout, the area of the resource it shows.
+    rect: Option<VirtioScanoutRect>,
 }
 
 impl VirtioGpuScanout {
@@ -111,6 +131,7 @@ impl VirtioGpuScanout {
             surface_id: None,
             resource_id: None,
             parent_surface_id: None,
+            rect: None,
         }
     }
 
@@ -126,6 +147,7 @@ impl VirtioGpuScanout {
             surface_id: None,
             resource_id: None,
             parent_surface_id: None,
+            rect: None,
         }
     }
 
@@ -317,6 +339,17 @@ fn sglist_to_rutabaga_iovecs(
     Ok(rutabaga_iovecs)
 }
 
+/// Returns the offsets of the red, green and blue bytes in the pixels of the virtio-gpu `format`.
+fn rgb_offsets(format: u32) -> Option<[usize; 3]> {
+    match format {
+        VIRTIO_GPU_FORMAT_B8G8R8A8_UNORM | VIRTIO_GPU_FORMAT_B8G8R8X8_UNORM => Some([2, 1, 0]),
+        VIRTIO_GPU_FORMAT_A8R8G8B8_UNORM | VIRTIO_GPU_FORMAT_X8R8G8B8_UNORM => Some([1, 2, 3]),
+        VIRTIO_GPU_FORMAT_R8G8B8A8_UNORM | VIRTIO_GPU_FORMAT_R8G8B8X8_UNORM => Some([0, 1, 2]),
+        VIRTIO_GPU_FORMAT_A8B8G8R8_UNORM | VIRTIO_GPU_FORMAT_X8B8G8R8_UNORM => Some([3, 2, 1]),
+        _ => None,
+    }
+}
+
 pub enum ProcessDisplayResult {
     Success,
     CloseRequested,
@@ -494,12 +527,105 @@ impl VirtioGpu {
             })
     }
 
+    /// Writes the resource currently shown on the specified display to `file` as a PNG image.
+    fn screenshot(&mut self, display_id: u32, file: SafeDescriptor) -> GpuControlResult {
+        let scanout = match self.scanouts.get(&display_id) {
+            Some(scanout) => scanout,
+            None => return GpuControlResult::NoSuchDisplay { display_id },
+        };
+        let resource = match scanout
+            .resource_id
+            .and_then(|resource_id| self.resources.get(&resource_id.get()))
+        {
+            Some(resource) => resource,
+            None => return GpuControlResult::NoScanoutResource { display_id },
+        };
+
+        let (format, resource_width, resource_height) = match resource.scanout_data {
+            Some(data) => {
+                // The backend reads the blob with its own layout, which must be the one the guest
+                // scans out.
+                match self.rutabaga.query(resource.resource_id) {
+                    Ok(query)
+                        if query.strides[0] == data.strides[0]
+                            && query.offsets[0] == data.offsets[0] => {}
+                    _ => {
+                        return GpuControlResult::ScreenshotFailed(
+                            "unsupported scanout layout".to_string(),
+                        )
+                    }
+                }
+                let format = if data.drm_format == DrmFormat::new(b'X', b'R', b'2', b'4')
+                    || data.drm_format == DrmFormat::new(b'A', b'R', b'2', b'4')
+                {
+                    Some(VIRTIO_GPU_FORMAT_B8G8R8X8_UNORM)
+                } else {
+                    None
+                };
+                (format, data.width, data.height)
+            }
+            None => (resource.format, resource.width, resource.height),
+        };
+        let rgb_offsets = match format.and_then(rgb_offsets) {
+            Some(rgb_offsets) => rgb_offsets,
+            None => {
+                return GpuControlResult::ScreenshotFailed("unsupported scanout format".to_string())
+            }
+        };
+
+        let rect = scanout.rect.unwrap_or_default();
+        let in_resource = |offset: u32, len: u32, resource_len: u32| {
+            len != 0 && matches!(offset.checked_add(len), Some(end) if end <= resource_len)
+        };
+        if !in_resource(rect.x, rect.width, resource_width)
+            || !in_resource(rect.y, rect.height, resource_height)
+        {
+            return GpuControlResult::ScreenshotFailed(
+                "scanout outside of its resource".to_string(),
+            );
+        }
+        let stride = match rect.width.checked_mul(4) {
+            Some(stride) => stride,
+            None => return GpuControlResult::ScreenshotFailed("scanout too large".to_string()),
+        };
+
+        let mut pixels = vec![0u8; stride as usize * rect.height as usize];
+        let mut transfer = Transfer3D::new_2d(rect.x, rect.y, rect.width, rect.height);
+        transfer.stride = stride;
+        if let Err(e) = self.rutabaga.transfer_read(
+            0,
+            resource.resource_id,
+            transfer,
+            Some(VolatileSlice::new(&mut pixels)),
+        ) {
+            return GpuControlResult::ScreenshotFailed(e.to_string());
+        }
+
+        // Safe because the descriptor is owned and not used anywhere else.
+        let file = unsafe { File::from_raw_descriptor(file.into_raw_descriptor()) };
+        match png::write_png(
+            BufWriter::new(file),
+            rect.width,
+            rect.height,
+            stride as usize,
+            rgb_offsets,
+            &pixels,
+        ) {
+            Ok(()) => GpuControlResult::ScreenshotSaved {
+                width: rect.width,
+                height: rect.height,
+            },
+            Err(e) => GpuControlResult::ScreenshotFailed(e.to_string()),
+        }
+    }
+
     /// Performs the given command to interact with or modify the device.
     pub fn process_gpu_control_command(&mut self, cmd: GpuControlCommand) -> GpuControlResult {
         match cmd {
             GpuControlCommand::AddDisplays { displays } => self.add_displays(displays),
             GpuControlCommand::ListDisplays => self.list_displays(),
             GpuControlCommand::RemoveDisplays { display_ids } => self.remove_displays(display_ids),
+            GpuControlCommand::Screenshot { display_id, fd } => self.screenshot(display_id, fd),
         }
     }
 
@@ -534,9 +660,14 @@ impl VirtioGpu {
         &mut self,
         scanout_id: u32,
         resource_id: u32,
+        rect: VirtioScanoutRect,
         scanout_data: Option<VirtioScanoutBlobData>,
     ) -> VirtioGpuResult {
-        self.update_scanout_resource(SurfaceType::Scanout, scanout_id, scanout_data, resource_id)
+        self.update_scanout_resource(SurfaceType::Scanout, scanout_id, scanout_data, resource_id)?;
+        if let Some(scanout) = self.scanouts.get_mut(&scanout_id) {
+            scanout.rect = Some(rect);
+        }
+        Ok(OkNoData)
     }
 
     /// If the resource is the scanout resource, flush it to the display.
@@ -709,12 +840,13 @@ impl VirtioGpu {
         self.rutabaga
             .resource_create_3d(resource_id, resource_create_3d)?;
 
-        let resource = VirtioGpuResource::new(
+        let mut resource = VirtioGpuResource::new(
             resource_id,
             resource_create_3d.width,
             resource_create_3d.height,
             0,
         );
+        resource.format = Some(resource_create_3d.format);
 
         // Rely on rutabaga to check for duplicate resource ids.
         self.resources.insert(resource_id, resource);
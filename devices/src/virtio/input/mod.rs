@@ -9,8 +9,10 @@ mod evdev;
 mod event_source;
 
 use std::collections::BTreeMap;
+use std::collections::VecDeque;
 use std::io::Read;
 use std::io::Write;
+use std::sync::Arc;
 use std::thread;
 
 use anyhow::anyhow;
@@ -18,9 +20,13 @@ use anyhow::Context;
 use base::error;
 use base::warn;
 use base::AsRawDescriptor;
+use base::Error as SysError;
 use base::Event;
 use base::EventToken;
 use base::RawDescriptor;
+use base::ReadNotifier;
+use base::Tube;
+use base::TubeError;
 use base::WaitContext;
 use data_model::DataInit;
 use data_model::Le16;
@@ -28,7 +34,10 @@ use data_model::Le32;
 use linux_input_sys::virtio_input_event;
 use linux_input_sys::InputEventDecoder;
 use remain::sorted;
+use sync::Mutex;
 use thiserror::Error;
+use vm_control::input::InputControlCommand;
+use vm_control::input::InputControlResult;
 use vm_memory::GuestMemory;
 
 use self::constants::*;
@@ -87,9 +96,15 @@ pub enum InputError {
     // Detected error on guest side
     #[error("detected error on guest side: {0}")]
     GuestError(String),
+    /// Failed to receive a command from the control tube
+    #[error("failed to read from the control tube: {0}")]
+    ReadControlTube(TubeError),
     // Error while reading from virtqueue
     #[error("failed to read from virtqueue: {0}")]
     ReadQueue(std::io::Error),
+    /// Failed to send the result of a command to the control tube
+    #[error("failed to write to the control tube: {0}")]
+    WriteControlTube(TubeError),
     // Error while writing to virtqueue
     #[error("failed to write to virtqueue: {0}")]
     WriteQueue(std::io::Error),
@@ -348,9 +363,93 @@ impl VirtioInputConfig {
     }
 }
 
+// Events injected by the host through the control tube, until the worker of the device takes them.
+#[derive(Default)]
+struct InjectedEvents {
+    events: VecDeque<virtio_input_event>,
+    // Signaled when events are added. Only set while the device is activated.
+    available_evt: Option<Event>,
+}
+
+// Handles a command from the host on `tube`. Returns false if the tube was closed.
+fn handle_control_tube(tube: &Tube, injected_events: &Mutex<InjectedEvents>) -> Result<bool> {
+    let command = match tube.recv::<InputControlCommand>() {
+        Ok(command) => command,
+        Err(TubeError::Disconnected) => return Ok(false),
+        Err(e) => return Err(InputError::ReadControlTube(e)),
+    };
+
+    let result = match command {
+        InputControlCommand::InjectEvents { events } => {
+            let mut injected_events = injected_events.lock();
+            let InjectedEvents {
+                events: queued_events,
+                available_evt,
+            } = &mut *injected_events;
+            // Without a driver, there is nobody to receive the events.
+            match available_evt {
+                Some(available_evt) => {
+                    queued_events.extend(events.into_iter().map(virtio_input_event::from));
+                    match available_evt.signal() {
+                        Ok(()) => InputControlResult::Ok,
+                        Err(e) => InputControlResult::Err(e),
+                    }
+                }
+                None => InputControlResult::Err(SysError::new(libc::EAGAIN)),
+            }
+        }
+    };
+    tube.send(&result).map_err(InputError::WriteControlTube)?;
+    Ok(true)
+}
+
+// Answers the commands of the host on `tube` until `kill_evt` is signaled or the tube is closed,
+// whether or not the device is activated.
+fn run_control(tube: Tube, injected_events: Arc<Mutex<InjectedEvents>>, kill_evt: Event) {
+    #[derive(EventToken)]
+    enum Token {
+        ControlTube,
+        Kill,
+    }
+    let wait_ctx: WaitContext<Token> = match WaitContext::build_with(&[
+        (tube.get_read_notifier(), Token::ControlTube),
+        (&kill_evt, Token::Kill),
+    ]) {
+        Ok(wait_ctx) => wait_ctx,
+        Err(e) => {
+            error!("failed creating WaitContext: {}", e);
+            return;
+        }
+    };
+
+    'wait: loop {
+        let wait_events = match wait_ctx.wait() {
+            Ok(wait_events) => wait_events,
+            Err(e) => {
+                error!("failed polling for events: {}", e);
+                break;
+            }
+        };
+
+        for wait_event in wait_events.iter().filter(|e| e.is_readable) {
+            match wait_event.token {
+                Token::ControlTube => match handle_control_tube(&tube, &injected_events) {
+                    Ok(true) => {}
+                    Ok(false) => break 'wait,
+                    Err(e) => error!("failed handling control tube command: {}", e),
+                },
+                Token::Kill => break 'wait,
+            }
+        }
+    }
+}
+
 struct Worker<T: EventSource> {
     interrupt: Interrupt,
     event_source: T,
+    // Events received from the control thread, sent to the guest after those of `event_source`.
+    injected_events: VecDeque<virtio_input_event>,
+    shared_injected_events: Arc<Mutex<InjectedEvents>>,
     event_queue: Queue,
     status_queue: Queue,
     guest_memory: GuestMemory,
@@ -360,13 +459,17 @@ impl<T: EventSource> Worker<T> {
     // Fills a virtqueue with events from the source.  Returns the number of bytes written.
     fn fill_event_virtqueue(
         event_source: &mut T,
+        injected_events: &mut VecDeque<virtio_input_event>,
         avail_desc: DescriptorChain,
         mem: &GuestMemory,
     ) -> Result<usize> {
         let mut writer = Writer::new(mem.clone(), avail_desc).map_err(InputError::Descriptor)?;
 
         while writer.available_bytes() >= virtio_input_event::SIZE {
-            if let Some(evt) = event_source.pop_available_event() {
+            if let Some(evt) = event_source
+                .pop_available_event()
+                .or_else(|| injected_events.pop_front())
+            {
                 writer.write_obj(evt).map_err(InputError::WriteQueue)?;
             } else {
                 break;
@@ -381,7 +484,7 @@ impl<T: EventSource> Worker<T> {
         let mut needs_interrupt = false;
 
         // Only consume from the queue iterator if we know we have events to send
-        while self.event_source.available_events_count() > 0 {
+        while self.event_source.available_events_count() > 0 || !self.injected_events.is_empty() {
             match self.event_queue.pop(&self.guest_memory) {
                 None => {
                     break;
@@ -391,6 +494,7 @@ impl<T: EventSource> Worker<T> {
 
                     let bytes_written = match Worker::fill_event_virtqueue(
                         &mut self.event_source,
+                        &mut self.injected_events,
                         avail_desc,
                         &self.guest_memory,
                     ) {
@@ -456,7 +560,13 @@ impl<T: EventSource> Worker<T> {
 
     // Allow error! and early return anywhere in function
     #[allow(clippy::needless_return)]
-    fn run(&mut self, event_queue_evt: Event, status_queue_evt: Event, kill_evt: Event) {
+    fn run(
+        &mut self,
+        event_queue_evt: Event,
+        status_queue_evt: Event,
+        injected_events_evt: Event,
+        kill_evt: Event,
+    ) {
         if let Err(e) = self.event_source.init() {
             error!("failed initializing event source: {}", e);
             return;
@@ -467,6 +577,7 @@ impl<T: EventSource> Worker<T> {
             EventQAvailable,
             StatusQAvailable,
             InputEventsAvailable,
+            InjectedEventsAvailable,
             InterruptResample,
             Kill,
         }
@@ -474,6 +585,7 @@ impl<T: EventSource> Worker<T> {
             (&event_queue_evt, Token::EventQAvailable),
             (&status_queue_evt, Token::StatusQAvailable),
             (&self.event_source, Token::InputEventsAvailable),
+            (&injected_events_evt, Token::InjectedEventsAvailable),
             (&kill_evt, Token::Kill),
         ]) {
             Ok(wait_ctx) => wait_ctx,
@@ -525,6 +637,15 @@ impl<T: EventSource> Worker<T> {
                         Err(e) => error!("error receiving events: {}", e),
                         Ok(_cnt) => needs_interrupt |= self.send_events(),
                     },
+                    Token::InjectedEventsAvailable => {
+                        if let Err(e) = injected_events_evt.wait() {
+                            error!("failed reading injected events Event: {}", e);
+                            break 'wait;
+                        }
+                        self.injected_events
+                            .extend(self.shared_injected_events.lock().events.drain(..));
+                        needs_interrupt |= self.send_events();
+                    }
                     Token::InterruptResample => {
                         self.interrupt.interrupt_resample();
                     }
@@ -554,6 +675,10 @@ pub struct Input<T: EventSource> {
     worker_thread: Option<thread::JoinHandle<Worker<T>>>,
     config: VirtioInputConfig,
     source: Option<T>,
+    control_tube: Option<Tube>,
+    injected_events: Arc<Mutex<InjectedEvents>>,
+    control_kill_evt: Option<Event>,
+    control_thread: Option<thread::JoinHandle<()>>,
     virtio_features: u64,
 }
 
@@ -567,6 +692,14 @@ impl<T: EventSource> Drop for Input<T> {
         if let Some(worker_thread) = self.worker_thread.take() {
             let _ = worker_thread.join();
         }
+
+        if let Some(control_kill_evt) = self.control_kill_evt.take() {
+            let _ = control_kill_evt.signal();
+        }
+
+        if let Some(control_thread) = self.control_thread.take() {
+            let _ = control_thread.join();
+        }
     }
 }
 
@@ -575,10 +708,14 @@ where
     T: 'static + EventSource + Send,
 {
     fn keep_rds(&self) -> Vec<RawDescriptor> {
+        let mut keep_rds = Vec::new();
         if let Some(source) = &self.source {
-            return vec![source.as_raw_descriptor()];
+            keep_rds.push(source.as_raw_descriptor());
         }
-        Vec::new()
+        if let Some(control_tube) = &self.control_tube {
+            keep_rds.push(control_tube.as_raw_descriptor());
+        }
+        keep_rds
     }
 
     fn device_type(&self) -> DeviceType {
@@ -589,6 +726,34 @@ where
         QUEUE_SIZES
     }
 
+    fn on_device_sandboxed(&mut self) {
+        // Commands are answered by a thread of their own, so that the host gets a reply even
+        // before the driver activates the device. Threads must be created after sandboxing.
+        let control_tube = match self.control_tube.take() {
+            Some(control_tube) => control_tube,
+            None => return,
+        };
+        let (control_kill_evt, kill_evt) = match Event::new().and_then(|e| Ok((e.try_clone()?, e)))
+        {
+            Ok(evts) => evts,
+            Err(e) => {
+                error!("failed to create kill Event pair: {}", e);
+                return;
+            }
+        };
+        let injected_events = Arc::clone(&self.injected_events);
+        match thread::Builder::new()
+            .name("v_input_control".to_string())
+            .spawn(move || run_control(control_tube, injected_events, kill_evt))
+        {
+            Ok(control_thread) => {
+                self.control_kill_evt = Some(control_kill_evt);
+                self.control_thread = Some(control_thread);
+            }
+            Err(e) => error!("failed to spawn virtio_input control thread: {}", e),
+        }
+    }
+
     fn read_config(&self, offset: u64, data: &mut [u8]) {
         self.config.read(offset as usize, data);
     }
@@ -624,17 +789,29 @@ where
             .source
             .take()
             .context("tried to activate device without a source for events")?;
+        let (available_evt, injected_events_evt) = Event::new()
+            .and_then(|e| Ok((e.try_clone()?, e)))
+            .context("failed to create injected events Event pair")?;
+        self.injected_events.lock().available_evt = Some(available_evt);
+        let shared_injected_events = Arc::clone(&self.injected_events);
         let worker_thread = thread::Builder::new()
             .name("v_input".to_string())
             .spawn(move || {
                 let mut worker = Worker {
                     interrupt,
                     event_source: source,
+                    injected_events: VecDeque::new(),
+                    shared_injected_events,
                     event_queue,
                     status_queue,
                     guest_memory: mem,
                 };
-                worker.run(event_queue_evt, status_queue_evt, kill_evt);
+                worker.run(
+                    event_queue_evt,
+                    status_queue_evt,
+                    injected_events_evt,
+                    kill_evt,
+                );
                 worker
             })
             .context("failed to spawn virtio_input worker")?;
@@ -660,6 +837,7 @@ where
                 }
                 Ok(worker) => {
                     self.source = Some(worker.event_source);
+                    *self.injected_events.lock() = InjectedEvents::default();
                     return true;
                 }
             }
@@ -671,7 +849,11 @@ where
 impl<T> Suspendable for Input<T> where T: 'static + EventSource + Send {}
 
 /// Creates a new virtio input device from an event device node
-pub fn new_evdev<T>(source: T, virtio_features: u64) -> Result<Input<EvdevEventSource<T>>>
+pub fn new_evdev<T>(
+    source: T,
+    control_tube: Option<Tube>,
+    virtio_features: u64,
+) -> Result<Input<EvdevEventSource<T>>>
 where
     T: Read + Write + AsRawDescriptor,
 {
@@ -680,6 +862,10 @@ where
         worker_thread: None,
         config: VirtioInputConfig::from_evdev(&source)?,
         source: Some(EvdevEventSource::new(source)),
+        control_tube,
+        injected_events: Default::default(),
+        control_kill_evt: None,
+        control_thread: None,
         virtio_features,
     })
 }
@@ -690,6 +876,7 @@ pub fn new_single_touch<T>(
     source: T,
     width: u32,
     height: u32,
+    control_tube: Option<Tube>,
     virtio_features: u64,
 ) -> Result<Input<SocketEventSource<T>>>
 where
@@ -700,6 +887,10 @@ where
         worker_thread: None,
         config: defaults::new_single_touch_config(idx, width, height),
         source: Some(SocketEventSource::new(source)),
+        control_tube,
+        injected_events: Default::default(),
+        control_kill_evt: None,
+        control_thread: None,
         virtio_features,
     })
 }
@@ -710,6 +901,7 @@ pub fn new_multi_touch<T>(
     source: T,
     width: u32,
     height: u32,
+    control_tube: Option<Tube>,
     virtio_features: u64,
 ) -> Result<Input<SocketEventSource<T>>>
 where
@@ -720,6 +912,10 @@ where
         worker_thread: None,
         config: defaults::new_multi_touch_config(idx, width, height),
         source: Some(SocketEventSource::new(source)),
+        control_tube,
+        injected_events: Default::default(),
+        control_kill_evt: None,
+        control_thread: None,
         virtio_features,
     })
 }
@@ -731,6 +927,7 @@ pub fn new_trackpad<T>(
     source: T,
     width: u32,
     height: u32,
+    control_tube: Option<Tube>,
     virtio_features: u64,
 ) -> Result<Input<SocketEventSource<T>>>
 where
@@ -741,6 +938,10 @@ where
         worker_thread: None,
         config: defaults::new_trackpad_config(idx, width, height),
         source: Some(SocketEventSource::new(source)),
+        control_tube,
+        injected_events: Default::default(),
+        control_kill_evt: None,
+        control_thread: None,
         virtio_features,
     })
 }
@@ -749,6 +950,7 @@ where
 pub fn new_mouse<T>(
     idx: u32,
     source: T,
+    control_tube: Option<Tube>,
     virtio_features: u64,
 ) -> Result<Input<SocketEventSource<T>>>
 where
@@ -759,6 +961,10 @@ where
         worker_thread: None,
         config: defaults::new_mouse_config(idx),
         source: Some(SocketEventSource::new(source)),
+        control_tube,
+        injected_events: Default::default(),
+        control_kill_evt: None,
+        control_thread: None,
         virtio_features,
     })
 }
@@ -767,6 +973,7 @@ where
 pub fn new_keyboard<T>(
     idx: u32,
     source: T,
+    control_tube: Option<Tube>,
     virtio_features: u64,
 ) -> Result<Input<SocketEventSource<T>>>
 where
@@ -777,6 +984,10 @@ where
         worker_thread: None,
         config: defaults::new_keyboard_config(idx),
         source: Some(SocketEventSource::new(source)),
+        control_tube,
+        injected_events: Default::default(),
+        control_kill_evt: None,
+        control_thread: None,
         virtio_features,
     })
 }
@@ -785,6 +996,7 @@ where
 pub fn new_switches<T>(
     idx: u32,
     source: T,
+    control_tube: Option<Tube>,
     virtio_features: u64,
 ) -> Result<Input<SocketEventSource<T>>>
 where
@@ -795,6 +1007,10 @@ where
         worker_thread: None,
         config: defaults::new_switches_config(idx),
         source: Some(SocketEventSource::new(source)),
+        control_tube,
+        injected_events: Default::default(),
+        control_kill_evt: None,
+        control_thread: None,
         virtio_features,
     })
 }
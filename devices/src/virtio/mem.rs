@@ -0,0 +1,629 @@
+// Copyright 2023 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! virtio-mem device, which lets the guest plug and unplug the blocks of a memory region reserved
+//! for memory hotplug until the amount of plugged memory matches the size requested by the host.
+
+use std::io;
+use std::sync::Arc;
+use std::thread;
+
+use anyhow::anyhow;
+use anyhow::Context;
+use base::error;
+use base::warn;
+use base::AsRawDescriptor;
+use base::Error as SysError;
+use base::Event;
+use base::RawDescriptor;
+use base::Tube;
+use cros_async::select4;
+use cros_async::AsyncTube;
+use cros_async::EventAsync;
+use cros_async::Executor;
+use data_model::DataInit;
+use data_model::Le16;
+use data_model::Le64;
+use futures::pin_mut;
+use remain::sorted;
+use resources::AddressRange;
+use sync::Mutex;
+use thiserror::Error;
+use vm_control::MemControlCommand;
+use vm_control::MemControlResult;
+use vm_control::MemStats;
+use vm_memory::GuestAddress;
+use vm_memory::GuestMemory;
+
+use super::async_utils;
+use super::copy_config;
+use super::DescriptorChain;
+use super::DescriptorError;
+use super::DeviceType;
+use super::Interrupt;
+use super::Queue;
+use super::Reader;
+use super::SignalableInterrupt;
+use super::VirtioDevice;
+use super::Writer;
+use crate::Suspendable;
+
+const QUEUE_SIZE: u16 = 128;
+const QUEUE_SIZES: &[u16] = &[QUEUE_SIZE];
+
+/// Granularity at which the guest plugs and unplugs memory.
+pub const VIRTIO_MEM_BLOCK_SIZE: u64 = 2 << 20;
+
+const VIRTIO_MEM_REQ_PLUG: u16 = 0;
+const VIRTIO_MEM_REQ_UNPLUG: u16 = 1;
+const VIRTIO_MEM_REQ_UNPLUG_ALL: u16 = 2;
+const VIRTIO_MEM_REQ_STATE: u16 = 3;
+
+const VIRTIO_MEM_RESP_ACK: u16 = 0;
+const VIRTIO_MEM_RESP_NACK: u16 = 1;
+const VIRTIO_MEM_RESP_ERROR: u16 = 3;
+
+const VIRTIO_MEM_STATE_PLUGGED: u16 = 0;
+const VIRTIO_MEM_STATE_UNPLUGGED: u16 = 1;
+const VIRTIO_MEM_STATE_MIXED: u16 = 2;
+
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C)]
+struct virtio_mem_config {
+    block_size: Le64,
+    node_id: Le16,
+    padding: [u8; 6],
+    addr: Le64,
+    region_size: Le64,
+    usable_region_size: Le64,
+    plugged_size: Le64,
+    requested_size: Le64,
+}
+
+// Safe because it only has data and has no implicit padding.
+unsafe impl DataInit for virtio_mem_config {}
+
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C)]
+struct virtio_mem_req {
+    type_: Le16,
+    padding: [Le16; 3],
+    addr: Le64,
+    nb_blocks: Le16,
+    padding_1: [Le16; 3],
+}
+
+// Safe because it only has data and has no implicit padding.
+unsafe impl DataInit for virtio_mem_req {}
+
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C)]
+struct virtio_mem_resp {
+    type_: Le16,
+    padding: [Le16; 3],
+    state: Le16,
+}
+
+// Safe because it only has data and has no implicit padding.
+unsafe impl DataInit for virtio_mem_resp {}
+
+#[sorted]
+#[derive(Error, Debug)]
+enum Error {
+    /// Invalid virtio descriptor chain.
+    #[error("virtio descriptor error: {0}")]
+    Descriptor(DescriptorError),
+    /// Failed to read from virtqueue.
+    #[error("failed to read from virtqueue: {0}")]
+    ReadQueue(io::Error),
+    /// Failed to write to virtqueue.
+    #[error("failed to write to virtqueue: {0}")]
+    WriteQueue(io::Error),
+}
+
+type Result<T> = ::std::result::Result<T, Error>;
+
+/// Plugged blocks of the hotplug region, shared between the device and its worker.
+struct MemState {
+    region: AddressRange,
+    plugged: Vec<bool>,
+    plugged_size: u64,
+    requested_size: u64,
+}
+
+impl MemState {
+    fn new(region: AddressRange) -> anyhow::Result<MemState> {
+        let region_size = match region.len() {
+            Some(size) if size != 0 => size,
+            _ => return Err(anyhow!("invalid hotplug memory region {:?}", region)),
+        };
+        if region.start % VIRTIO_MEM_BLOCK_SIZE != 0 || region_size % VIRTIO_MEM_BLOCK_SIZE != 0 {
+            return Err(anyhow!(
+                "hotplug memory region {:?} not aligned to {} bytes blocks",
+                region,
+                VIRTIO_MEM_BLOCK_SIZE
+            ));
+        }
+        let nb_blocks = usize::try_from(region_size / VIRTIO_MEM_BLOCK_SIZE)
+            .context("hotplug memory region too large")?;
+
+        Ok(MemState {
+            region,
+            plugged: vec![false; nb_blocks],
+            plugged_size: 0,
+            requested_size: 0,
+        })
+    }
+
+    fn region_size(&self) -> u64 {
+        self.plugged.len() as u64 * VIRTIO_MEM_BLOCK_SIZE
+    }
+
+    /// Returns the indices of the `nb_blocks` blocks starting at `addr`, if they are all in the
+    /// region.
+    fn blocks(&self, addr: u64, nb_blocks: u16) -> Option<std::ops::Range<usize>> {
+        let offset = addr.checked_sub(self.region.start)?;
+        if offset % VIRTIO_MEM_BLOCK_SIZE != 0 || nb_blocks == 0 {
+            return None;
+        }
+        let first = (offset / VIRTIO_MEM_BLOCK_SIZE) as usize;
+        let last = first.checked_add(nb_blocks as usize)?;
+        if last > self.plugged.len() {
+            return None;
+        }
+        Some(first..last)
+    }
+
+    /// Discards the contents of `blocks` and marks them as unplugged.
+    fn unplug(&mut self, mem: &GuestMemory, blocks: std::ops::Range<usize>) -> bool {
+        let addr = GuestAddress(self.region.start + blocks.start as u64 * VIRTIO_MEM_BLOCK_SIZE);
+        let size = blocks.len() as u64 * VIRTIO_MEM_BLOCK_SIZE;
+        if let Err(e) = mem.remove_range(addr, size) {
+            error!("failed to discard unplugged memory at {}: {}", addr, e);
+            return false;
+        }
+        for plugged in &mut self.plugged[blocks] {
+            if *plugged {
+                *plugged = false;
+                self.plugged_size -= VIRTIO_MEM_BLOCK_SIZE;
+            }
+        }
+        true
+    }
+
+    /// Executes `request` and returns the response type and the state of the requested blocks.
+    fn execute(&mut self, mem: &GuestMemory, request: &virtio_mem_req) -> (u16, u16) {
+        let addr = request.addr.to_native();
+        let nb_blocks = request.nb_blocks.to_native();
+        match request.type_.to_native() {
+            VIRTIO_MEM_REQ_PLUG => {
+                let blocks = match self.blocks(addr, nb_blocks) {
+                    Some(blocks) => blocks,
+                    None => return (VIRTIO_MEM_RESP_ERROR, 0),
+                };
+                if self.plugged[blocks.clone()].iter().any(|&plugged| plugged) {
+                    return (VIRTIO_MEM_RESP_ERROR, 0);
+                }
+                let size = blocks.len() as u64 * VIRTIO_MEM_BLOCK_SIZE;
+                if self.plugged_size + size > self.requested_size {
+                    return (VIRTIO_MEM_RESP_NACK, 0);
+                }
+                self.plugged[blocks].fill(true);
+                self.plugged_size += size;
+                (VIRTIO_MEM_RESP_ACK, 0)
+            }
+            VIRTIO_MEM_REQ_UNPLUG => {
+                let blocks = match self.blocks(addr, nb_blocks) {
+                    Some(blocks) => blocks,
+                    None => return (VIRTIO_MEM_RESP_ERROR, 0),
+                };
+                if !self.plugged[blocks.clone()].iter().all(|&plugged| plugged) {
+                    return (VIRTIO_MEM_RESP_ERROR, 0);
+                }
+                if !self.unplug(mem, blocks) {
+                    return (VIRTIO_MEM_RESP_NACK, 0);
+                }
+                (VIRTIO_MEM_RESP_ACK, 0)
+            }
+            VIRTIO_MEM_REQ_UNPLUG_ALL => {
+                if !self.unplug(mem, 0..self.plugged.len()) {
+                    return (VIRTIO_MEM_RESP_NACK, 0);
+                }
+                (VIRTIO_MEM_RESP_ACK, 0)
+            }
+            VIRTIO_MEM_REQ_STATE => {
+                let blocks = match self.blocks(addr, nb_blocks) {
+                    Some(blocks) => blocks,
+                    None => return (VIRTIO_MEM_RESP_ERROR, 0),
+                };
+                let blocks = &self.plugged[blocks];
+                let state = if blocks.iter().all(|&plugged| plugged) {
+                    VIRTIO_MEM_STATE_PLUGGED
+                } else if blocks.iter().all(|&plugged| !plugged) {
+                    VIRTIO_MEM_STATE_UNPLUGGED
+                } else {
+                    VIRTIO_MEM_STATE_MIXED
+                };
+                (VIRTIO_MEM_RESP_ACK, state)
+            }
+            type_ => {
+                warn!("unknown virtio-mem request type: {}", type_);
+                (VIRTIO_MEM_RESP_ERROR, 0)
+            }
+        }
+    }
+
+    fn stats(&self) -> MemStats {
+        MemStats {
+            region_size: self.region_size(),
+            block_size: VIRTIO_MEM_BLOCK_SIZE,
+            plugged_size: self.plugged_size,
+            requested_size: self.requested_size,
+        }
+    }
+}
+
+fn handle_request(
+    mem: &GuestMemory,
+    avail_desc: DescriptorChain,
+    state: &Mutex<MemState>,
+) -> Result<usize> {
+    let mut reader = Reader::new(mem.clone(), avail_desc.clone()).map_err(Error::Descriptor)?;
+    let mut writer = Writer::new(mem.clone(), avail_desc).map_err(Error::Descriptor)?;
+
+    let request: virtio_mem_req = reader.read_obj().map_err(Error::ReadQueue)?;
+    let (type_, mem_state) = state.lock().execute(mem, &request);
+
+    let response = virtio_mem_resp {
+        type_: type_.into(),
+        state: mem_state.into(),
+        ..Default::default()
+    };
+    writer.write_obj(response).map_err(Error::WriteQueue)?;
+
+    Ok(writer.bytes_written())
+}
+
+async fn handle_queue(
+    mem: &GuestMemory,
+    mut queue: Queue,
+    mut queue_event: EventAsync,
+    interrupt: Interrupt,
+    state: &Mutex<MemState>,
+) {
+    loop {
+        let avail_desc = match queue.next_async(mem, &mut queue_event).await {
+            Err(e) => {
+                error!("Failed to read descriptor {}", e);
+                return;
+            }
+            Ok(d) => d,
+        };
+        let index = avail_desc.index;
+        let written = match handle_request(mem, avail_desc, state) {
+            Ok(n) => n,
+            Err(e) => {
+                error!("mem: failed to handle request: {}", e);
+                0
+            }
+        };
+        queue.add_used(mem, index, written as u32);
+        queue.trigger_interrupt(mem, &interrupt);
+    }
+}
+
+// Async task that handles the command socket, on which the host changes the requested size of
+// the plugged memory or queries the state of the device.
+async fn handle_command_tube(
+    command_tube: &AsyncTube,
+    interrupt: Interrupt,
+    state: &Mutex<MemState>,
+) {
+    loop {
+        let command = match command_tube.next::<MemControlCommand>().await {
+            Ok(command) => command,
+            Err(e) => {
+                error!("failed to read mem command: {}", e);
+                return;
+            }
+        };
+        let result = match command {
+            MemControlCommand::Resize { size } => {
+                let mut state = state.lock();
+                let size = size - size % VIRTIO_MEM_BLOCK_SIZE;
+                if size > state.region_size() {
+                    MemControlResult::Err(SysError::new(libc::EINVAL))
+                } else {
+                    state.requested_size = size;
+                    interrupt.signal_config_changed();
+                    MemControlResult::Ok
+                }
+            }
+            MemControlCommand::Stats => MemControlResult::Stats(state.lock().stats()),
+        };
+        if let Err(e) = command_tube.send(result).await {
+            error!("failed to send mem command result: {}", e);
+        }
+    }
+}
+
+fn run_worker(
+    queue_evt: Event,
+    queue: Queue,
+    command_tube: Tube,
+    interrupt: Interrupt,
+    kill_evt: Event,
+    mem: GuestMemory,
+    state: Arc<Mutex<MemState>>,
+) -> (Tube, GuestMemory) {
+    let ex = Executor::new().unwrap();
+
+    let queue_evt = EventAsync::new(queue_evt, &ex).expect("failed to set up the queue event");
+    let command_tube = AsyncTube::new(&ex, command_tube).expect("failed to set up the mem tube");
+
+    // We need a block to release all references to command_tube at the end before returning it.
+    {
+        // Process requests from the virtio queue.
+        let queue_fut = handle_queue(&mem, queue, queue_evt, interrupt.clone(), &state);
+        pin_mut!(queue_fut);
+
+        // Process requests from the host.
+        let command = handle_command_tube(&command_tube, interrupt.clone(), &state);
+        pin_mut!(command);
+
+        // Process any requests to resample the irq value.
+        let resample = async_utils::handle_irq_resample(&ex, interrupt);
+        pin_mut!(resample);
+
+        // Exit if the kill event is triggered.
+        let kill = async_utils::await_and_exit(&ex, kill_evt);
+        pin_mut!(kill);
+
+        if let Err(e) = ex.run_until(select4(queue_fut, command, resample, kill)) {
+            error!("error happened in executor: {}", e);
+        }
+    }
+
+    (command_tube.into(), mem)
+}
+
+/// Virtio device that plugs memory in blocks of the hotplug region at the request of the guest.
+pub struct Mem {
+    base_features: u64,
+    command_tube: Option<Tube>,
+    state: Arc<Mutex<MemState>>,
+    kill_event: Option<Event>,
+    worker_thread: Option<thread::JoinHandle<(Tube, GuestMemory)>>,
+}
+
+impl Mem {
+    /// Creates a virtio-mem device managing the hotplug memory `region`, whose size is
+    /// changed with the requests received on `command_tube`.
+    pub fn new(
+        base_features: u64,
+        region: AddressRange,
+        command_tube: Tube,
+    ) -> anyhow::Result<Mem> {
+        Ok(Mem {
+            base_features,
+            command_tube: Some(command_tube),
+            state: Arc::new(Mutex::new(MemState::new(region)?)),
+            kill_event: None,
+            worker_thread: None,
+        })
+    }
+}
+
+impl Drop for Mem {
+    fn drop(&mut self) {
+        if let Some(kill_evt) = self.kill_event.take() {
+            // Ignore the result because there is nothing we can do about it.
+            let _ = kill_evt.signal();
+        }
+
+        if let Some(worker_thread) = self.worker_thread.take() {
+            let _ = worker_thread.join();
+        }
+    }
+}
+
+impl VirtioDevice for Mem {
+    fn keep_rds(&self) -> Vec<RawDescriptor> {
+        let mut keep_rds = Vec::new();
+        if let Some(command_tube) = &self.command_tube {
+            keep_rds.push(command_tube.as_raw_descriptor());
+        }
+        keep_rds
+    }
+
+    fn device_type(&self) -> DeviceType {
+        DeviceType::Mem
+    }
+
+    fn queue_max_sizes(&self) -> &[u16] {
+        QUEUE_SIZES
+    }
+
+    fn features(&self) -> u64 {
+        self.base_features
+    }
+
+    fn read_config(&self, offset: u64, data: &mut [u8]) {
+        let state = self.state.lock();
+        let region_size = state.region_size();
+        let config = virtio_mem_config {
+            block_size: VIRTIO_MEM_BLOCK_SIZE.into(),
+            addr: state.region.start.into(),
+            region_size: region_size.into(),
+            usable_region_size: region_size.into(),
+            plugged_size: state.plugged_size.into(),
+            requested_size: state.requested_size.into(),
+            ..Default::default()
+        };
+        copy_config(data, 0, config.as_slice(), offset);
+    }
+
+    fn activate(
+        &mut self,
+        memory: GuestMemory,
+        interrupt: Interrupt,
+        mut queues: Vec<(Queue, Event)>,
+    ) -> anyhow::Result<()> {
+        if queues.len() != 1 {
+            return Err(anyhow!("expected 1 queue, got {}", queues.len()));
+        }
+
+        let (queue, queue_event) = queues.remove(0);
+
+        let command_tube = self
+            .command_tube
+            .take()
+            .context("missing mem command tube")?;
+
+        let (self_kill_event, kill_event) = Event::new()
+            .and_then(|e| Ok((e.try_clone()?, e)))
+            .context("failed creating kill Event pair")?;
+        self.kill_event = Some(self_kill_event);
+
+        let state = self.state.clone();
+        let worker_thread = thread::Builder::new()
+            .name("v_mem".to_string())
+            .spawn(move || {
+                run_worker(
+                    queue_event,
+                    queue,
+                    command_tube,
+                    interrupt,
+                    kill_event,
+                    memory,
+                    state,
+                )
+            })
+            .context("failed to spawn virtio_mem worker")?;
+        self.worker_thread = Some(worker_thread);
+        Ok(())
+    }
+
+    fn reset(&mut self) -> bool {
+        if let Some(kill_evt) = self.kill_event.take() {
+            if kill_evt.signal().is_err() {
+                error!("{}: failed to notify the kill event", self.debug_label());
+                return false;
+            }
+        }
+
+        if let Some(worker_thread) = self.worker_thread.take() {
+            match worker_thread.join() {
+                Err(_) => {
+                    error!("{}: failed to get back resources", self.debug_label());
+                    return false;
+                }
+                Ok((command_tube, mem)) => {
+                    self.command_tube = Some(command_tube);
+                    // The guest starts over with no plugged memory, so discard the blocks it
+                    // plugged until now.
+                    let mut state = self.state.lock();
+                    let nb_blocks = state.plugged.len();
+                    return state.unplug(&mem, 0..nb_blocks);
+                }
+            }
+        }
+        false
+    }
+}
+
+impl Suspendable for Mem {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const REGION_START: u64 = 0x1_0000_0000;
+
+    fn request(type_: u16, block: u64, nb_blocks: u16) -> virtio_mem_req {
+        virtio_mem_req {
+            type_: type_.into(),
+            addr: (REGION_START + block * VIRTIO_MEM_BLOCK_SIZE).into(),
+            nb_blocks: nb_blocks.into(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn plug_unplug() {
+        let region =
+            AddressRange::from_start_and_size(REGION_START, 4 * VIRTIO_MEM_BLOCK_SIZE).unwrap();
+        let mem =
+            GuestMemory::new(&[(GuestAddress(REGION_START), 4 * VIRTIO_MEM_BLOCK_SIZE)]).unwrap();
+        let mut state = MemState::new(region).unwrap();
+        state.requested_size = 3 * VIRTIO_MEM_BLOCK_SIZE;
+
+        let plug = request(VIRTIO_MEM_REQ_PLUG, 1, 2);
+        assert_eq!(state.execute(&mem, &plug), (VIRTIO_MEM_RESP_ACK, 0));
+        assert_eq!(state.plugged_size, 2 * VIRTIO_MEM_BLOCK_SIZE);
+        // Blocks already plugged.
+        assert_eq!(state.execute(&mem, &plug), (VIRTIO_MEM_RESP_ERROR, 0));
+        // More than the requested size.
+        let plug = request(VIRTIO_MEM_REQ_PLUG, 0, 1);
+        let plug_more = request(VIRTIO_MEM_REQ_PLUG, 3, 1);
+        assert_eq!(state.execute(&mem, &plug), (VIRTIO_MEM_RESP_ACK, 0));
+        assert_eq!(state.execute(&mem, &plug_more), (VIRTIO_MEM_RESP_NACK, 0));
+        // Outside of the region.
+        let plug = request(VIRTIO_MEM_REQ_PLUG, 4, 1);
+        assert_eq!(state.execute(&mem, &plug), (VIRTIO_MEM_RESP_ERROR, 0));
+
+        let query = request(VIRTIO_MEM_REQ_STATE, 0, 3);
+        assert_eq!(
+            state.execute(&mem, &query),
+            (VIRTIO_MEM_RESP_ACK, VIRTIO_MEM_STATE_PLUGGED)
+        );
+        let query = request(VIRTIO_MEM_REQ_STATE, 2, 2);
+        assert_eq!(
+            state.execute(&mem, &query),
+            (VIRTIO_MEM_RESP_ACK, VIRTIO_MEM_STATE_MIXED)
+        );
+
+        let unplug = request(VIRTIO_MEM_REQ_UNPLUG, 2, 1);
+        assert_eq!(state.execute(&mem, &unplug), (VIRTIO_MEM_RESP_ACK, 0));
+        assert_eq!(state.plugged_size, 2 * VIRTIO_MEM_BLOCK_SIZE);
+        // Blocks not plugged.
+        assert_eq!(state.execute(&mem, &unplug), (VIRTIO_MEM_RESP_ERROR, 0));
+        let query = request(VIRTIO_MEM_REQ_STATE, 2, 2);
+        assert_eq!(
+            state.execute(&mem, &query),
+            (VIRTIO_MEM_RESP_ACK, VIRTIO_MEM_STATE_UNPLUGGED)
+        );
+
+        let unplug_all = request(VIRTIO_MEM_REQ_UNPLUG_ALL, 0, 0);
+        assert_eq!(state.execute(&mem, &unplug_all), (VIRTIO_MEM_RESP_ACK, 0));
+        assert_eq!(state.plugged_size, 0);
+    }
+
+    #[test]
+    fn unaligned_request() {
+        let region =
+            AddressRange::from_start_and_size(REGION_START, 4 * VIRTIO_MEM_BLOCK_SIZE).unwrap();
+        let mem =
+            GuestMemory::new(&[(GuestAddress(REGION_START), 4 * VIRTIO_MEM_BLOCK_SIZE)]).unwrap();
+        let mut state = MemState::new(region).unwrap();
+        state.requested_size = 4 * VIRTIO_MEM_BLOCK_SIZE;
+
+        let mut plug = request(VIRTIO_MEM_REQ_PLUG, 0, 1);
+        plug.addr = (REGION_START + 4096).into();
+        assert_eq!(state.execute(&mem, &plug), (VIRTIO_MEM_RESP_ERROR, 0));
+        let plug = request(VIRTIO_MEM_REQ_PLUG, 0, 0);
+        assert_eq!(state.execute(&mem, &plug), (VIRTIO_MEM_RESP_ERROR, 0));
+        assert_eq!(state.plugged_size, 0);
+    }
+    #[test]
+    fn invalid_region() {
+        let region =
+            AddressRange::from_start_and_size(REGION_START, VIRTIO_MEM_BLOCK_SIZE + 4096).unwrap();
+        assert!(MemState::new(region).is_err());
+        let region =
+            AddressRange::from_start_and_size(REGION_START + 4096, VIRTIO_MEM_BLOCK_SIZE).unwrap();
+        assert!(MemState::new(region).is_err());
+        assert!(MemState::new(AddressRange::empty()).is_err());
+    }
+}
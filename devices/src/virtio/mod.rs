@@ -35,6 +35,7 @@ pub mod resource_bridge;
 #[cfg(feature = "audio")]
 pub mod snd;
 pub mod vhost;
+pub mod vsock;
 
 #[cfg(feature = "balloon")]
 pub use self::balloon::*;
@@ -56,8 +57,10 @@ pub use self::video::*;
 pub use self::virtio_device::*;
 pub use self::virtio_mmio_device::*;
 pub use self::virtio_pci_device::*;
+pub use self::vsock::*;
 cfg_if::cfg_if! {
     if #[cfg(unix)] {
+        mod mem;
         mod p9;
         mod pmem;
 
@@ -66,6 +69,7 @@ cfg_if::cfg_if! {
         pub mod net;
 
         pub use self::iommu::sys::unix::vfio_wrapper;
+        pub use self::mem::*;
         pub use self::net::*;
         pub use self::p9::*;
         pub use self::pmem::*;
@@ -74,7 +78,6 @@ cfg_if::cfg_if! {
         pub use self::wl::*;
 
     } else if #[cfg(windows)] {
-        mod vsock;
         #[cfg(feature = "slirp")]
         pub mod net;
 
@@ -82,7 +85,6 @@ cfg_if::cfg_if! {
         pub use self::net::*;
         #[cfg(feature = "slirp")]
         pub use self::sys::windows::NetExt;
-        pub use self::vsock::*;
     } else {
         compile_error!("Unsupported platform");
     }
@@ -123,6 +125,7 @@ pub enum DeviceType {
     Iommu = virtio_ids::VIRTIO_ID_IOMMU,
     Sound = virtio_ids::VIRTIO_ID_SOUND,
     Fs = virtio_ids::VIRTIO_ID_FS,
+    Mem = virtio_ids::VIRTIO_ID_MEM,
     Pmem = virtio_ids::VIRTIO_ID_PMEM,
     Mac80211HwSim = virtio_ids::VIRTIO_ID_MAC80211_HWSIM,
     VideoEnc = virtio_ids::VIRTIO_ID_VIDEO_ENCODER,
@@ -155,6 +158,7 @@ impl std::fmt::Display for DeviceType {
             DeviceType::VhostUser => write!(f, "vhost-user"),
             DeviceType::Sound => write!(f, "snd"),
             DeviceType::Fs => write!(f, "fs"),
+            DeviceType::Mem => write!(f, "mem"),
             DeviceType::Pmem => write!(f, "pmem"),
             DeviceType::Wl => write!(f, "wl"),
             DeviceType::Tpm => write!(f, "tpm"),
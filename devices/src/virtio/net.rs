@@ -3,11 +3,13 @@
 // found in the LICENSE file.
 
 use std::io;
+use std::io::Read;
 use std::io::Write;
 use std::mem;
 use std::net::Ipv4Addr;
 use std::os::raw::c_uint;
 use std::str::FromStr;
+use std::sync::Arc;
 use std::thread;
 
 use anyhow::anyhow;
@@ -22,25 +24,47 @@ use base::Event;
 use base::EventToken;
 use base::RawDescriptor;
 use base::ReadNotifier;
+use base::Tube;
+use base::TubeError;
 use base::WaitContext;
 use data_model::DataInit;
 use data_model::Le16;
+use data_model::Le32;
 use data_model::Le64;
+#[cfg(unix)]
+use net_util::sys::unix::HostForward;
 use net_util::Error as TapError;
 use net_util::MacAddress;
 use net_util::TapT;
 use remain::sorted;
 use serde::Deserialize;
 use serde::Serialize;
+use sync::Mutex;
 use thiserror::Error as ThisError;
+use virtio_sys::virtio_config::VIRTIO_F_RING_PACKED;
 use virtio_sys::virtio_net;
 use virtio_sys::virtio_net::virtio_net_hdr_v1;
+use virtio_sys::virtio_net::VIRTIO_NET_CTRL_ANNOUNCE;
+use virtio_sys::virtio_net::VIRTIO_NET_CTRL_ANNOUNCE_ACK;
 use virtio_sys::virtio_net::VIRTIO_NET_CTRL_GUEST_OFFLOADS;
 use virtio_sys::virtio_net::VIRTIO_NET_CTRL_GUEST_OFFLOADS_SET;
+use virtio_sys::virtio_net::VIRTIO_NET_CTRL_MAC;
+use virtio_sys::virtio_net::VIRTIO_NET_CTRL_MAC_ADDR_SET;
+use virtio_sys::virtio_net::VIRTIO_NET_CTRL_MAC_TABLE_SET;
 use virtio_sys::virtio_net::VIRTIO_NET_CTRL_MQ;
 use virtio_sys::virtio_net::VIRTIO_NET_CTRL_MQ_VQ_PAIRS_SET;
+use virtio_sys::virtio_net::VIRTIO_NET_CTRL_RX;
+use virtio_sys::virtio_net::VIRTIO_NET_CTRL_RX_ALLMULTI;
+use virtio_sys::virtio_net::VIRTIO_NET_CTRL_RX_PROMISC;
+use virtio_sys::virtio_net::VIRTIO_NET_CTRL_VLAN;
+use virtio_sys::virtio_net::VIRTIO_NET_CTRL_VLAN_ADD;
+use virtio_sys::virtio_net::VIRTIO_NET_CTRL_VLAN_DEL;
 use virtio_sys::virtio_net::VIRTIO_NET_ERR;
 use virtio_sys::virtio_net::VIRTIO_NET_OK;
+use virtio_sys::virtio_net::VIRTIO_NET_S_ANNOUNCE;
+use virtio_sys::virtio_net::VIRTIO_NET_S_LINK_UP;
+use vm_control::NetControlCommand;
+use vm_control::NetControlResult;
 use vm_memory::GuestMemory;
 
 use super::copy_config;
@@ -54,10 +78,13 @@ use super::VirtioDevice;
 use super::Writer;
 use crate::Suspendable;
 
+mod filter;
+
+use filter::RxFilter;
+
 /// The maximum buffer size when segmentation offload is enabled. This
 /// includes the 12-byte virtio net header.
 /// http://docs.oasis-open.org/virtio/virtio/v1.0/virtio-v1.0.html#x1-1740003
-#[cfg(windows)]
 pub(crate) const MAX_BUFFER_SIZE: usize = 65562;
 const QUEUE_SIZE: u16 = 256;
 
@@ -85,6 +112,9 @@ pub enum NetError {
     /// Removing the tap descriptor from the event context failed.
     #[error("failed to remove tap trigger from event context: {0}")]
     EventRemoveTap(SysError),
+    /// Error receiving a command from the control tube.
+    #[error("failed to receive control tube command: {0}")]
+    ReadControlTube(TubeError),
     /// Error reading data from control queue.
     #[error("failed to read control message data: {0}")]
     ReadCtrlData(io::Error),
@@ -139,6 +169,9 @@ pub enum NetError {
     #[cfg(unix)]
     #[error("failed to write to guest buffer: {0}")]
     WriteBuffer(io::Error),
+    /// Error sending a response to the control tube.
+    #[error("failed to send control tube response: {0}")]
+    WriteControlTube(TubeError),
 }
 
 #[derive(Serialize, Deserialize, PartialEq, Eq, Debug)]
@@ -160,6 +193,26 @@ pub enum NetParametersMode {
         netmask: Ipv4Addr,
         mac: MacAddress,
     },
+    /// User-mode network stack, which needs no TAP device or privileges.
+    #[cfg(unix)]
+    #[serde(rename_all = "kebab-case")]
+    User {
+        #[serde(deserialize_with = "deserialize_user")]
+        user: bool,
+        mac: Option<MacAddress>,
+        #[serde(default)]
+        host_fwd: Vec<HostForward>,
+    },
+}
+
+// Only accepts `user` or `user=true`, so that `user=false` isn't silently taken as user-mode
+// networking.
+#[cfg(unix)]
+fn deserialize_user<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<bool, D::Error> {
+    match bool::deserialize(deserializer)? {
+        true => Ok(true),
+        false => Err(serde::de::Error::custom("`user` cannot be false")),
+    }
 }
 
 #[derive(Serialize, Deserialize, PartialEq, Eq, Debug)]
@@ -222,6 +275,158 @@ pub struct VirtioNetConfig {
 // Safe because it only has data and has no implicit padding.
 unsafe impl DataInit for VirtioNetConfig {}
 
+/// State of a network device that is shared by its queue pairs, and changed by the driver through
+/// the control queue or by the host.
+pub struct NetState {
+    pub(crate) rx_filter: RxFilter,
+    status: u16,
+    // The interrupt and the acked features of the driver while the device is activated.
+    driver: Option<(Interrupt, u64)>,
+}
+
+impl NetState {
+    /// Creates the state of a device with the MAC address `mac`, and its link up.
+    pub fn new(mac: Option<[u8; 6]>) -> NetState {
+        NetState {
+            rx_filter: RxFilter::new(mac),
+            status: VIRTIO_NET_S_LINK_UP as u16,
+            driver: None,
+        }
+    }
+
+    /// Returns the value of the status field of the config space.
+    pub fn status(&self) -> u16 {
+        self.status
+    }
+
+    /// Returns whether frames can be sent and received.
+    pub fn link_up(&self) -> bool {
+        self.status & VIRTIO_NET_S_LINK_UP as u16 != 0
+    }
+
+    /// Brings the link up or down. If `announce` is true, a link that comes up asks the driver to
+    /// announce its presence on the network. Returns whether the status changed.
+    pub fn set_link_up(&mut self, up: bool, announce: bool) -> bool {
+        let old_status = self.status;
+        if up {
+            self.status |= VIRTIO_NET_S_LINK_UP as u16;
+            if announce && old_status & VIRTIO_NET_S_LINK_UP as u16 == 0 {
+                self.status |= VIRTIO_NET_S_ANNOUNCE as u16;
+            }
+        } else {
+            self.status &= !(VIRTIO_NET_S_LINK_UP | VIRTIO_NET_S_ANNOUNCE) as u16;
+        }
+        self.status != old_status
+    }
+
+    /// Brings the link up or down on behalf of the host, and notifies the driver of an activated
+    /// device if the status changed.
+    pub fn set_host_link_up(&mut self, up: bool) {
+        let acked_features = self.driver.as_ref().map_or(0, |(_, features)| *features);
+        let announce = acked_features & 1 << virtio_net::VIRTIO_NET_F_GUEST_ANNOUNCE != 0;
+        if self.set_link_up(up, announce)
+            && acked_features & 1 << virtio_net::VIRTIO_NET_F_STATUS != 0
+        {
+            if let Some((interrupt, _)) = &self.driver {
+                interrupt.signal_config_changed();
+            }
+        }
+    }
+
+    /// Returns whether received frames must be checked with `accepts_frame()` before they are
+    /// passed to the driver.
+    pub fn filters_rx(&self) -> bool {
+        !self.link_up() || self.rx_filter.is_active()
+    }
+
+    /// Returns whether the received frame `buf`, starting with a virtio-net header, should be
+    /// passed to the driver.
+    pub fn accepts_frame(&self, buf: &[u8]) -> bool {
+        let hdr_len = mem::size_of::<virtio_net_hdr_v1>();
+        self.link_up()
+            && self
+                .rx_filter
+                .accepts(buf.get(hdr_len..).unwrap_or_default())
+    }
+
+    // Restores the state configured by the driver to its defaults, keeping the link state that is
+    // controlled by the host.
+    fn reset(&mut self, mac: Option<[u8; 6]>) {
+        self.rx_filter = RxFilter::new(mac);
+        self.status &= !(VIRTIO_NET_S_ANNOUNCE as u16);
+        self.driver = None;
+    }
+}
+
+// Reads the MAC addresses of a `virtio_net_ctrl_mac` table. Returns `None` if the table is larger
+// than the message.
+fn read_mac_table(reader: &mut Reader) -> Result<Option<Vec<[u8; 6]>>, NetError> {
+    let entries: Le32 = reader.read_obj().map_err(NetError::ReadCtrlData)?;
+    let entries = entries.to_native() as usize;
+    if entries.saturating_mul(6) > reader.available_bytes() {
+        return Ok(None);
+    }
+    let mut macs = vec![[0u8; 6]; entries];
+    for mac in macs.iter_mut() {
+        reader.read_exact(mac).map_err(NetError::ReadCtrlData)?;
+    }
+    Ok(Some(macs))
+}
+
+// Applies a `VIRTIO_NET_CTRL_RX`, `VIRTIO_NET_CTRL_MAC` or `VIRTIO_NET_CTRL_VLAN` command to
+// `rx_filter`. Returns false if the command isn't valid.
+fn process_rx_filter_ctrl(
+    ctrl_hdr: &virtio_net_ctrl_hdr,
+    reader: &mut Reader,
+    acked_features: u64,
+    rx_filter: &mut RxFilter,
+) -> Result<bool, NetError> {
+    let has_feature = |feature: u32| acked_features & (1 << feature) != 0;
+    match (ctrl_hdr.class as c_uint, ctrl_hdr.cmd as c_uint) {
+        (VIRTIO_NET_CTRL_RX, cmd) if has_feature(virtio_net::VIRTIO_NET_F_CTRL_RX) => {
+            let on: u8 = reader.read_obj().map_err(NetError::ReadCtrlData)?;
+            match cmd {
+                VIRTIO_NET_CTRL_RX_PROMISC => rx_filter.set_promisc(on != 0),
+                VIRTIO_NET_CTRL_RX_ALLMULTI => rx_filter.set_allmulti(on != 0),
+                _ => return Ok(false),
+            }
+        }
+        (VIRTIO_NET_CTRL_MAC, VIRTIO_NET_CTRL_MAC_TABLE_SET)
+            if has_feature(virtio_net::VIRTIO_NET_F_CTRL_RX) =>
+        {
+            let uni_table = match read_mac_table(reader)? {
+                Some(table) => table,
+                None => return Ok(false),
+            };
+            let multi_table = match read_mac_table(reader)? {
+                Some(table) => table,
+                None => return Ok(false),
+            };
+            rx_filter.set_mac_tables(uni_table, multi_table);
+        }
+        (VIRTIO_NET_CTRL_MAC, VIRTIO_NET_CTRL_MAC_ADDR_SET)
+            if has_feature(virtio_net::VIRTIO_NET_F_CTRL_MAC_ADDR) =>
+        {
+            let mut mac = [0u8; 6];
+            reader
+                .read_exact(&mut mac)
+                .map_err(NetError::ReadCtrlData)?;
+            rx_filter.set_mac(mac);
+        }
+        (VIRTIO_NET_CTRL_VLAN, cmd) if has_feature(virtio_net::VIRTIO_NET_F_CTRL_VLAN) => {
+            let vid: Le16 = reader.read_obj().map_err(NetError::ReadCtrlData)?;
+            let valid = match cmd {
+                VIRTIO_NET_CTRL_VLAN_ADD => rx_filter.add_vlan(vid.to_native()),
+                VIRTIO_NET_CTRL_VLAN_DEL => rx_filter.del_vlan(vid.to_native()),
+                _ => false,
+            };
+            return Ok(valid);
+        }
+        _ => return Ok(false),
+    }
+    Ok(true)
+}
+
 pub fn process_ctrl<I: SignalableInterrupt, T: TapT>(
     interrupt: &I,
     ctrl_queue: &mut Queue,
@@ -229,6 +434,7 @@ pub fn process_ctrl<I: SignalableInterrupt, T: TapT>(
     tap: &mut T,
     acked_features: u64,
     vq_pairs: u16,
+    state: &Mutex<NetState>,
 ) -> Result<(), NetError> {
     while let Some(desc_chain) = ctrl_queue.pop(mem) {
         let index = desc_chain.index;
@@ -283,6 +489,36 @@ pub fn process_ctrl<I: SignalableInterrupt, T: TapT>(
                     writer.write_all(&[ack]).map_err(NetError::WriteAck)?;
                 }
             }
+            VIRTIO_NET_CTRL_RX | VIRTIO_NET_CTRL_MAC | VIRTIO_NET_CTRL_VLAN => {
+                let valid = process_rx_filter_ctrl(
+                    &ctrl_hdr,
+                    &mut reader,
+                    acked_features,
+                    &mut state.lock().rx_filter,
+                )?;
+                if !valid {
+                    error!(
+                        "invalid rx filter cmd: class {}, cmd {}",
+                        ctrl_hdr.class, ctrl_hdr.cmd
+                    );
+                    write_error()?;
+                    continue;
+                }
+                let ack = VIRTIO_NET_OK as u8;
+                writer.write_all(&[ack]).map_err(NetError::WriteAck)?;
+            }
+            VIRTIO_NET_CTRL_ANNOUNCE => {
+                if ctrl_hdr.cmd != VIRTIO_NET_CTRL_ANNOUNCE_ACK as u8
+                    || acked_features & 1 << virtio_net::VIRTIO_NET_F_GUEST_ANNOUNCE == 0
+                {
+                    error!("invalid cmd for VIRTIO_NET_CTRL_ANNOUNCE: {}", ctrl_hdr.cmd);
+                    write_error()?;
+                    continue;
+                }
+                state.lock().status &= !(VIRTIO_NET_S_ANNOUNCE as u16);
+                let ack = VIRTIO_NET_OK as u8;
+                writer.write_all(&[ack]).map_err(NetError::WriteAck)?;
+            }
             _ => warn!(
                 "unimplemented class for VIRTIO_NET_CTRL_GUEST_OFFLOADS: {}",
                 ctrl_hdr.class
@@ -319,6 +555,7 @@ pub(super) struct Worker<T: TapT> {
     pub(super) tx_queue: Queue,
     pub(super) ctrl_queue: Option<Queue>,
     pub(super) tap: T,
+    pub(super) state: Arc<Mutex<NetState>>,
     #[cfg(windows)]
     pub(super) overlapped_wrapper: OverlappedWrapper,
     #[cfg(windows)]
@@ -343,6 +580,7 @@ where
             &mut self.tx_queue,
             &self.mem,
             &mut self.tap,
+            Some(&self.state),
         )
     }
 
@@ -359,6 +597,7 @@ where
             &mut self.tap,
             self.acked_features,
             self.vq_pairs,
+            &self.state,
         )
     }
 
@@ -452,6 +691,52 @@ where
     }
 }
 
+// Handles the commands of the host on `control_tube` until `kill_evt` is signaled or the tube is
+// closed. Commands only change the shared `state`, so they are answered whether or not the device
+// is activated.
+fn run_control(
+    control_tube: &Tube,
+    state: &Mutex<NetState>,
+    kill_evt: &Event,
+) -> Result<(), NetError> {
+    #[derive(EventToken)]
+    enum Token {
+        ControlTube,
+        Kill,
+    }
+
+    let wait_ctx: WaitContext<Token> = WaitContext::build_with(&[
+        (control_tube.get_read_notifier(), Token::ControlTube),
+        (kill_evt, Token::Kill),
+    ])
+    .map_err(NetError::CreateWaitContext)?;
+
+    loop {
+        let events = wait_ctx.wait().map_err(NetError::WaitError)?;
+        for event in events.iter().filter(|e| e.is_readable) {
+            match event.token {
+                Token::ControlTube => {
+                    let command = match control_tube.recv::<NetControlCommand>() {
+                        Ok(command) => command,
+                        Err(TubeError::Disconnected) => return Ok(()),
+                        Err(e) => return Err(NetError::ReadControlTube(e)),
+                    };
+                    let result = match command {
+                        NetControlCommand::SetLinkState { up } => {
+                            state.lock().set_host_link_up(up);
+                            NetControlResult::Ok
+                        }
+                    };
+                    control_tube
+                        .send(&result)
+                        .map_err(NetError::WriteControlTube)?;
+                }
+                Token::Kill => return Ok(()),
+            }
+        }
+    }
+}
+
 pub fn build_config(vq_pairs: u16, mtu: u16, mac: Option<[u8; 6]>) -> VirtioNetConfig {
     VirtioNetConfig {
         max_vq_pairs: Le16::from(vq_pairs),
@@ -466,6 +751,11 @@ pub fn build_config(vq_pairs: u16, mtu: u16, mac: Option<[u8; 6]>) -> VirtioNetC
 
 pub struct Net<T: TapT + ReadNotifier> {
     guest_mac: Option<[u8; 6]>,
+    state: Arc<Mutex<NetState>>,
+    control_tube: Option<Tube>,
+    control_worker_kill_evt: Option<Event>,
+    control_kill_evt: Event,
+    control_thread: Option<thread::JoinHandle<()>>,
     queue_sizes: Box<[u16]>,
     workers_kill_evt: Vec<Event>,
     kill_evts: Vec<Event>,
@@ -483,12 +773,13 @@ where
     T: TapT + ReadNotifier,
 {
     /// Creates a new virtio network device from a tap device that has already been
-    /// configured.
+    /// configured. The link state of the device can be changed through `control_tube`.
     pub fn new(
         base_features: u64,
         tap: T,
         vq_pairs: u16,
         mac_addr: Option<MacAddress>,
+        control_tube: Option<Tube>,
     ) -> Result<Net<T>, NetError> {
         let taps = tap.into_mq_taps(vq_pairs).map_err(NetError::TapOpen)?;
 
@@ -516,7 +807,13 @@ where
             | 1 << virtio_net::VIRTIO_NET_F_GUEST_UFO
             | 1 << virtio_net::VIRTIO_NET_F_HOST_TSO4
             | 1 << virtio_net::VIRTIO_NET_F_HOST_UFO
-            | 1 << virtio_net::VIRTIO_NET_F_MTU;
+            | 1 << virtio_net::VIRTIO_NET_F_MTU
+            | 1 << virtio_net::VIRTIO_NET_F_STATUS
+            | 1 << virtio_net::VIRTIO_NET_F_CTRL_RX
+            | 1 << virtio_net::VIRTIO_NET_F_CTRL_VLAN
+            | 1 << virtio_net::VIRTIO_NET_F_CTRL_MAC_ADDR
+            | 1 << virtio_net::VIRTIO_NET_F_GUEST_ANNOUNCE
+            | 1 << VIRTIO_F_RING_PACKED;
 
         if vq_pairs > 1 {
             avail_features |= 1 << virtio_net::VIRTIO_NET_F_MQ;
@@ -531,6 +828,7 @@ where
             avail_features,
             mtu,
             mac_addr,
+            control_tube,
             #[cfg(windows)]
             None,
         )
@@ -541,6 +839,7 @@ where
         avail_features: u64,
         mtu: u16,
         mac_addr: Option<MacAddress>,
+        control_tube: Option<Tube>,
         #[cfg(windows)] slirp_kill_evt: Option<Event>,
     ) -> Result<Self, NetError> {
         let mut kill_evts: Vec<Event> = Vec::new();
@@ -551,9 +850,19 @@ where
             kill_evts.push(kill_evt);
             workers_kill_evt.push(worker_kill_evt);
         }
+        let control_kill_evt = Event::new().map_err(NetError::CreateKillEvent)?;
+        let control_worker_kill_evt = control_kill_evt
+            .try_clone()
+            .map_err(NetError::CloneKillEvent)?;
 
+        let guest_mac = mac_addr.map(|mac| mac.octets());
         Ok(Self {
-            guest_mac: mac_addr.map(|mac| mac.octets()),
+            guest_mac,
+            state: Arc::new(Mutex::new(NetState::new(guest_mac))),
+            control_tube,
+            control_worker_kill_evt: Some(control_worker_kill_evt),
+            control_kill_evt,
+            control_thread: None,
             queue_sizes: vec![QUEUE_SIZE; (taps.len() * 2 + 1) as usize].into_boxed_slice(),
             workers_kill_evt,
             kill_evts,
@@ -633,6 +942,11 @@ where
         for _ in 0..len {
             let _ = self.worker_threads.remove(0).join();
         }
+
+        if let Some(control_thread) = self.control_thread.take() {
+            let _ = self.control_kill_evt.signal();
+            let _ = control_thread.join();
+        }
     }
 }
 
@@ -653,6 +967,13 @@ where
         for kill_evt in &self.kill_evts {
             keep_rds.push(kill_evt.as_raw_descriptor());
         }
+        if let Some(control_tube) = &self.control_tube {
+            keep_rds.push(control_tube.as_raw_descriptor());
+        }
+        if let Some(control_worker_kill_evt) = &self.control_worker_kill_evt {
+            keep_rds.push(control_worker_kill_evt.as_raw_descriptor());
+        }
+        keep_rds.push(self.control_kill_evt.as_raw_descriptor());
 
         keep_rds
     }
@@ -693,9 +1014,34 @@ where
         }
     }
 
+    fn on_device_sandboxed(&mut self) {
+        // The link state is changed from a thread of its own, so that the host gets an answer
+        // even before the driver activates the device. Threads must be created after sandboxing.
+        let (control_tube, kill_evt) = match (
+            self.control_tube.take(),
+            self.control_worker_kill_evt.take(),
+        ) {
+            (Some(control_tube), Some(kill_evt)) => (control_tube, kill_evt),
+            _ => return,
+        };
+        let state = Arc::clone(&self.state);
+        match thread::Builder::new()
+            .name("v_net_control".to_string())
+            .spawn(move || {
+                if let Err(e) = run_control(&control_tube, &state, &kill_evt) {
+                    error!("net control thread exited with error: {}", e);
+                }
+            }) {
+            Ok(control_thread) => self.control_thread = Some(control_thread),
+            Err(e) => error!("failed to spawn virtio_net control thread: {}", e),
+        }
+    }
+
     fn read_config(&self, offset: u64, data: &mut [u8]) {
         let vq_pairs = self.queue_sizes.len() / 2;
-        let config_space = build_config(vq_pairs as u16, self.mtu, self.guest_mac);
+        let state = self.state.lock();
+        let mut config_space = build_config(vq_pairs as u16, self.mtu, state.rx_filter.mac());
+        config_space.status = Le16::from(state.status());
         copy_config(data, 0, config_space.as_slice(), offset);
     }
 
@@ -728,6 +1074,13 @@ where
                 self.workers_kill_evt.len()
             ));
         }
+        {
+            let mut state = self.state.lock();
+            state.rx_filter.set_vlan_filtering(
+                self.acked_features & 1 << virtio_net::VIRTIO_NET_F_CTRL_VLAN != 0,
+            );
+            state.driver = Some((interrupt.clone(), self.acked_features));
+        }
         for i in 0..vq_pairs {
             let tap = self.taps.remove(0);
             let acked_features = self.acked_features;
@@ -737,12 +1090,14 @@ where
             // Queues alternate between rx0, tx0, rx1, tx1, ..., rxN, txN, ctrl.
             let (rx_queue, rx_queue_evt) = queues.remove(0);
             let (tx_queue, tx_queue_evt) = queues.remove(0);
+            // The first worker also handles the control queue.
             let (ctrl_queue, ctrl_queue_evt) = if i == 0 {
                 let (queue, evt) = queues.remove(queues.len() - 1);
                 (Some(queue), Some(evt))
             } else {
                 (None, None)
             };
+            let state = Arc::clone(&self.state);
             let pairs = vq_pairs as u16;
             #[cfg(windows)]
             let overlapped_wrapper = OverlappedWrapper::new(true).unwrap();
@@ -757,6 +1112,7 @@ where
                             tx_queue,
                             ctrl_queue,
                             tap,
+                            state,
                             #[cfg(windows)]
                             overlapped_wrapper,
                             acked_features,
@@ -808,6 +1164,7 @@ where
                 }
             }
         }
+        self.state.lock().reset(self.guest_mac);
 
         true
     }
@@ -978,4 +1335,44 @@ mod tests {
         // invalid parameter
         assert!(from_net_arg("tap-name=tap,foomatic=true").is_err());
     }
+
+    #[cfg(unix)]
+    #[test]
+    fn params_from_key_values_user() {
+        let params = from_net_arg("user").unwrap();
+        assert_eq!(
+            params,
+            NetParameters {
+                vhost_net: false,
+                mode: NetParametersMode::User {
+                    user: true,
+                    mac: None,
+                    host_fwd: Vec::new(),
+                }
+            }
+        );
+
+        let params = from_net_arg(
+            "user,mac=\"3d:70:eb:61:1a:91\",host-fwd=[tcp:2222:22,udp:0.0.0.0:5353:53]",
+        )
+        .unwrap();
+        assert_eq!(
+            params,
+            NetParameters {
+                vhost_net: false,
+                mode: NetParametersMode::User {
+                    user: true,
+                    mac: Some(MacAddress::from_str("3d:70:eb:61:1a:91").unwrap()),
+                    host_fwd: vec![
+                        HostForward::from_str("tcp:2222:22").unwrap(),
+                        HostForward::from_str("udp:0.0.0.0:5353:53").unwrap(),
+                    ],
+                }
+            }
+        );
+
+        assert!(from_net_arg("user=false").is_err());
+        assert!(from_net_arg("user,host-fwd=[sctp:1:2]").is_err());
+        assert!(from_net_arg("user,tap-name=tap").is_err());
+    }
 }
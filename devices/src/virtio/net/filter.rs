@@ -0,0 +1,236 @@
+// Copyright 2023 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Filtering of the frames received by a virtio-net device, as configured by the driver through
+//! the control queue.
+
+// Number of addresses in each MAC table above which all the frames of that kind are accepted.
+const MAX_MAC_TABLE_ENTRIES: usize = 64;
+pub const MAX_VLAN_ID: u16 = 4095;
+const ETH_P_8021Q: u16 = 0x8100;
+const BROADCAST_MAC: [u8; 6] = [0xff; 6];
+
+/// Decides which received frames are passed to the driver.
+///
+/// Filtering only starts once the driver turns promiscuous mode off. Until then, all frames are
+/// received like by a device without `VIRTIO_NET_F_CTRL_RX`.
+#[derive(Debug)]
+pub struct RxFilter {
+    mac: Option<[u8; 6]>,
+    promisc: bool,
+    allmulti: bool,
+    uni_table: Vec<[u8; 6]>,
+    uni_overflow: bool,
+    multi_table: Vec<[u8; 6]>,
+    multi_overflow: bool,
+    vlan_filtering: bool,
+    vlans: Vec<u64>,
+}
+
+impl RxFilter {
+    /// Creates a filter for a device with the MAC address `mac`, if it has one.
+    pub fn new(mac: Option<[u8; 6]>) -> RxFilter {
+        RxFilter {
+            mac,
+            promisc: true,
+            allmulti: false,
+            uni_table: Vec::new(),
+            uni_overflow: false,
+            multi_table: Vec::new(),
+            multi_overflow: false,
+            vlan_filtering: false,
+            vlans: vec![0; (MAX_VLAN_ID as usize + 1) / 64],
+        }
+    }
+
+    /// Returns the MAC address of the device.
+    pub fn mac(&self) -> Option<[u8; 6]> {
+        self.mac
+    }
+
+    /// Changes the MAC address of the device.
+    pub fn set_mac(&mut self, mac: [u8; 6]) {
+        self.mac = Some(mac);
+    }
+
+    pub fn set_promisc(&mut self, promisc: bool) {
+        self.promisc = promisc;
+    }
+
+    pub fn set_allmulti(&mut self, allmulti: bool) {
+        self.allmulti = allmulti;
+    }
+
+    /// Replaces the tables of additional unicast and multicast addresses to receive frames for.
+    pub fn set_mac_tables(&mut self, uni_table: Vec<[u8; 6]>, multi_table: Vec<[u8; 6]>) {
+        self.uni_overflow = uni_table.len() > MAX_MAC_TABLE_ENTRIES;
+        self.uni_table = if self.uni_overflow {
+            Vec::new()
+        } else {
+            uni_table
+        };
+        self.multi_overflow = multi_table.len() > MAX_MAC_TABLE_ENTRIES;
+        self.multi_table = if self.multi_overflow {
+            Vec::new()
+        } else {
+            multi_table
+        };
+    }
+
+    /// Enables dropping of VLAN tagged frames for VLANs that weren't added, which is only done
+    /// once the driver accepted `VIRTIO_NET_F_CTRL_VLAN`.
+    pub fn set_vlan_filtering(&mut self, vlan_filtering: bool) {
+        self.vlan_filtering = vlan_filtering;
+    }
+
+    /// Starts receiving the frames tagged with `vid`. Returns false if `vid` is invalid.
+    pub fn add_vlan(&mut self, vid: u16) -> bool {
+        if vid > MAX_VLAN_ID {
+            return false;
+        }
+        self.vlans[vid as usize / 64] |= 1 << (vid % 64);
+        true
+    }
+
+    /// Stops receiving the frames tagged with `vid`. Returns false if `vid` is invalid.
+    pub fn del_vlan(&mut self, vid: u16) -> bool {
+        if vid > MAX_VLAN_ID {
+            return false;
+        }
+        self.vlans[vid as usize / 64] &= !(1 << (vid % 64));
+        true
+    }
+
+    /// Returns whether received frames need to be checked with `accepts()`, as opposed to all
+    /// being accepted.
+    pub fn is_active(&self) -> bool {
+        !self.promisc
+    }
+
+    /// Returns whether the Ethernet frame `frame` should be passed to the driver.
+    pub fn accepts(&self, frame: &[u8]) -> bool {
+        if self.promisc {
+            return true;
+        }
+        // Leave runt frames for the driver to drop.
+        if frame.len() < 14 {
+            return true;
+        }
+
+        if self.vlan_filtering
+            && frame.len() >= 16
+            && u16::from_be_bytes([frame[12], frame[13]]) == ETH_P_8021Q
+        {
+            let vid = u16::from_be_bytes([frame[14], frame[15]]) & MAX_VLAN_ID;
+            if self.vlans[vid as usize / 64] & (1 << (vid % 64)) == 0 {
+                return false;
+            }
+        }
+
+        let dest = &frame[0..6];
+        if dest[0] & 1 != 0 {
+            dest == BROADCAST_MAC
+                || self.allmulti
+                || self.multi_overflow
+                || self.multi_table.iter().any(|mac| mac == dest)
+        } else {
+            // Without a known address of its own, the device can't tell which frames are meant
+            // for it.
+            self.mac.map_or(true, |mac| mac == dest)
+                || self.uni_overflow
+                || self.uni_table.iter().any(|mac| mac == dest)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAC: [u8; 6] = [0x52, 0x54, 0x00, 0x12, 0x34, 0x56];
+    const OTHER_MAC: [u8; 6] = [0x52, 0x54, 0x00, 0x65, 0x43, 0x21];
+    const MULTICAST_MAC: [u8; 6] = [0x01, 0x00, 0x5e, 0x00, 0x00, 0x01];
+
+    fn frame(dest: [u8; 6], vid: Option<u16>) -> Vec<u8> {
+        let mut frame = dest.to_vec();
+        frame.extend_from_slice(&OTHER_MAC);
+        if let Some(vid) = vid {
+            frame.extend_from_slice(&ETH_P_8021Q.to_be_bytes());
+            frame.extend_from_slice(&vid.to_be_bytes());
+        }
+        // IPv4 ethertype and some payload.
+        frame.extend_from_slice(&[0x08, 0x00]);
+        frame.extend_from_slice(&[0u8; 46]);
+        frame
+    }
+
+    #[test]
+    fn promisc_by_default() {
+        let filter = RxFilter::new(Some(MAC));
+        assert!(!filter.is_active());
+        assert!(filter.accepts(&frame(OTHER_MAC, None)));
+        assert!(filter.accepts(&frame(MULTICAST_MAC, Some(10))));
+    }
+
+    #[test]
+    fn unicast() {
+        let mut filter = RxFilter::new(Some(MAC));
+        filter.set_promisc(false);
+        assert!(filter.is_active());
+        assert!(filter.accepts(&frame(MAC, None)));
+        assert!(!filter.accepts(&frame(OTHER_MAC, None)));
+
+        filter.set_mac_tables(vec![OTHER_MAC], Vec::new());
+        assert!(filter.accepts(&frame(OTHER_MAC, None)));
+
+        filter.set_mac(OTHER_MAC);
+        filter.set_mac_tables(Vec::new(), Vec::new());
+        assert!(!filter.accepts(&frame(MAC, None)));
+        assert!(filter.accepts(&frame(OTHER_MAC, None)));
+
+        // Too many addresses to keep track of.
+        filter.set_mac_tables(vec![[0x02, 0, 0, 0, 0, 0]; 65], Vec::new());
+        assert!(filter.accepts(&frame(MAC, None)));
+    }
+
+    #[test]
+    fn multicast() {
+        let mut filter = RxFilter::new(Some(MAC));
+        filter.set_promisc(false);
+        assert!(filter.accepts(&frame(BROADCAST_MAC, None)));
+        assert!(!filter.accepts(&frame(MULTICAST_MAC, None)));
+
+        filter.set_mac_tables(Vec::new(), vec![MULTICAST_MAC]);
+        assert!(filter.accepts(&frame(MULTICAST_MAC, None)));
+
+        filter.set_mac_tables(Vec::new(), Vec::new());
+        filter.set_allmulti(true);
+        assert!(filter.accepts(&frame(MULTICAST_MAC, None)));
+        // Unicast frames are still filtered.
+        assert!(!filter.accepts(&frame(OTHER_MAC, None)));
+    }
+
+    #[test]
+    fn vlan() {
+        let mut filter = RxFilter::new(Some(MAC));
+        filter.set_promisc(false);
+        // VLANs aren't filtered before the driver accepts the feature.
+        assert!(filter.accepts(&frame(MAC, Some(10))));
+
+        filter.set_vlan_filtering(true);
+        assert!(!filter.accepts(&frame(MAC, Some(10))));
+        assert!(filter.accepts(&frame(MAC, None)));
+
+        assert!(filter.add_vlan(10));
+        assert!(filter.accepts(&frame(MAC, Some(10))));
+        // The priority bits aren't part of the VLAN ID.
+        assert!(filter.accepts(&frame(MAC, Some(0xe000 | 10))));
+        assert!(!filter.accepts(&frame(MAC, Some(11))));
+
+        assert!(filter.del_vlan(10));
+        assert!(!filter.accepts(&frame(MAC, Some(10))));
+
+        assert!(!filter.add_vlan(MAX_VLAN_ID + 1));
+    }
+}
@@ -22,7 +22,14 @@ use data_model::Le64;
 use smallvec::smallvec;
 use smallvec::SmallVec;
 use sync::Mutex;
+use virtio_sys::virtio_config::VIRTIO_F_RING_PACKED;
 use virtio_sys::virtio_ring::VIRTIO_RING_F_EVENT_IDX;
+use virtio_sys::virtio_ring::VRING_PACKED_DESC_F_AVAIL;
+use virtio_sys::virtio_ring::VRING_PACKED_DESC_F_USED;
+use virtio_sys::virtio_ring::VRING_PACKED_EVENT_FLAG_DESC;
+use virtio_sys::virtio_ring::VRING_PACKED_EVENT_FLAG_DISABLE;
+use virtio_sys::virtio_ring::VRING_PACKED_EVENT_FLAG_ENABLE;
+use virtio_sys::virtio_ring::VRING_PACKED_EVENT_F_WRAP_CTR;
 use vm_memory::GuestAddress;
 use vm_memory::GuestMemory;
 use zerocopy::FromBytes;
@@ -83,8 +90,10 @@ pub struct DescriptorChain {
     desc_table: GuestAddress,
     queue_size: u16,
     ttl: u16, // used to prevent infinite chain cycles
+    packed: bool,
 
-    /// Index into the descriptor table
+    /// Index into the descriptor table. For the head of a chain taken from a packed virtqueue,
+    /// this is the buffer ID to pass back to `Queue::add_used` instead.
     pub index: u16,
 
     /// Guest physical address of device specific data, or IO virtual address
@@ -127,6 +136,18 @@ pub struct Desc {
 // Safe because it only has data and has no implicit padding.
 unsafe impl DataInit for Desc {}
 
+/// A descriptor of a packed virtqueue, which is also used to return buffers to the driver.
+#[derive(Copy, Clone, Debug, FromBytes)]
+#[repr(C)]
+pub struct PackedDesc {
+    pub addr: Le64,
+    pub len: Le32,
+    pub id: Le16,
+    pub flags: Le16,
+}
+// Safe because it only has data and has no implicit padding.
+unsafe impl DataInit for PackedDesc {}
+
 impl DescriptorChain {
     pub(crate) fn checked_new(
         mem: &GuestMemory,
@@ -136,6 +157,30 @@ impl DescriptorChain {
         required_flags: u16,
         iommu: Option<Arc<Mutex<IpcMemoryMapper>>>,
         exported_desc_table: Option<ExportedRegion>,
+    ) -> Result<DescriptorChain> {
+        Self::checked_new_with_format(
+            mem,
+            desc_table,
+            queue_size,
+            index,
+            required_flags,
+            iommu,
+            exported_desc_table,
+            false,
+        )
+    }
+
+    // Reads the descriptor at `index` of a split descriptor table, or of a packed descriptor ring
+    // if `packed` is true. The descriptors of a packed chain follow each other in the ring.
+    fn checked_new_with_format(
+        mem: &GuestMemory,
+        desc_table: GuestAddress,
+        queue_size: u16,
+        index: u16,
+        required_flags: u16,
+        iommu: Option<Arc<Mutex<IpcMemoryMapper>>>,
+        exported_desc_table: Option<ExportedRegion>,
+        packed: bool,
     ) -> Result<DescriptorChain> {
         if index >= queue_size {
             bail!("index ({}) >= queue_size ({})", index, queue_size);
@@ -144,11 +189,28 @@ impl DescriptorChain {
         let desc_head = desc_table
             .checked_add((index as u64) * 16)
             .context("integer overflow")?;
-        let desc: Desc = read_obj_from_addr_wrapper(mem, &exported_desc_table, desc_head)
-            .with_context(|| format!("failed to read desc {:x}", desc_head.offset()))?;
+        let (addr, len, flags, next) = if packed {
+            let desc: PackedDesc = read_obj_from_addr_wrapper(mem, &exported_desc_table, desc_head)
+                .with_context(|| format!("failed to read desc {:x}", desc_head.offset()))?;
+            let next = if index + 1 == queue_size {
+                0
+            } else {
+                index + 1
+            };
+            (desc.addr, desc.len, desc.flags.to_native(), next)
+        } else {
+            let desc: Desc = read_obj_from_addr_wrapper(mem, &exported_desc_table, desc_head)
+                .with_context(|| format!("failed to read desc {:x}", desc_head.offset()))?;
+            (
+                desc.addr,
+                desc.len,
+                desc.flags.to_native(),
+                desc.next.to_native(),
+            )
+        };
 
-        let addr = GuestAddress(desc.addr.into());
-        let len = desc.len.to_native();
+        let addr = GuestAddress(addr.into());
+        let len = len.to_native();
         let (regions, exported_region) = if let Some(iommu) = &iommu {
             if exported_desc_table.is_none() {
                 bail!("missing exported descriptor table");
@@ -187,11 +249,12 @@ impl DescriptorChain {
             desc_table,
             queue_size,
             ttl: queue_size,
+            packed,
             index,
             addr,
             len,
-            flags: desc.flags.into(),
-            next: desc.next.into(),
+            flags,
+            next,
             iommu,
             regions,
             exported_region,
@@ -254,7 +317,7 @@ impl DescriptorChain {
             // Once we see a write-only descriptor, all subsequent descriptors must be write-only.
             let required_flags = self.flags & VIRTQ_DESC_F_WRITE;
             let iommu = self.iommu.as_ref().map(Arc::clone);
-            match DescriptorChain::checked_new(
+            match DescriptorChain::checked_new_with_format(
                 &self.mem,
                 self.desc_table,
                 self.queue_size,
@@ -262,6 +325,7 @@ impl DescriptorChain {
                 required_flags,
                 iommu,
                 self.exported_desc_table.clone(),
+                self.packed,
             ) {
                 Ok(mut c) => {
                     c.ttl = self.ttl - 1;
@@ -305,9 +369,9 @@ pub struct Queue {
     /// The maximal size in elements offered by the device
     max_size: u16,
 
-    /// The queue size in elements the driver selected. This is always guaranteed to be a power of
-    /// two less than or equal to `max_size`, as required for split virtqueues. These invariants are
-    /// enforced by `set_size()`.
+    /// The queue size in elements the driver selected. This is always guaranteed to be less than or
+    /// equal to `max_size`, and a power of two as required for split virtqueues. These invariants
+    /// are enforced by `set_size()`.
     size: u16,
 
     /// Inidcates if the queue is finished with configuration
@@ -332,6 +396,18 @@ pub struct Queue {
     features: u64,
     last_used: Wrapping<u16>,
 
+    // Wrap counters of a packed virtqueue, toggled each time `next_avail` or `next_used` wraps
+    // around the ring.
+    avail_wrap_counter: bool,
+    used_wrap_counter: bool,
+
+    // Number of descriptors of each buffer popped from a packed virtqueue, indexed by buffer ID.
+    // The used ring position skips over that many descriptors when the buffer is returned.
+    packed_chain_lens: Vec<u16>,
+
+    // Position in the ring after the chain returned by the last `peek()` of a packed virtqueue.
+    packed_peeked: Option<(u16, bool)>,
+
     iommu: Option<Arc<Mutex<IpcMemoryMapper>>>,
 
     // When |iommu| is present, |desc_table| and the rings are IOVAs rather than real
@@ -375,6 +451,10 @@ impl Queue {
             next_used: Wrapping(0),
             features: 0,
             last_used: Wrapping(0),
+            avail_wrap_counter: true,
+            used_wrap_counter: true,
+            packed_chain_lens: Vec::new(),
+            packed_peeked: None,
             iommu: None,
             exported_desc_table: None,
             exported_avail_ring: None,
@@ -405,7 +485,8 @@ impl Queue {
             return;
         }
 
-        if val > self.max_size || !val.is_power_of_two() {
+        // Packed virtqueues may have any size.
+        if val == 0 || val > self.max_size || (!self.packed() && !val.is_power_of_two()) {
             warn!(
                 "ignoring invalid queue_size {} (max_size {})",
                 val, self.max_size,
@@ -477,6 +558,10 @@ impl Queue {
             next_used: self.next_used,
             features: self.features,
             last_used: self.last_used,
+            avail_wrap_counter: self.avail_wrap_counter,
+            used_wrap_counter: self.used_wrap_counter,
+            packed_chain_lens: Vec::new(),
+            packed_peeked: None,
             iommu: self.iommu.as_ref().map(Arc::clone),
             exported_desc_table: self.exported_desc_table.clone(),
             exported_avail_ring: self.exported_avail_ring.clone(),
@@ -506,6 +591,10 @@ impl Queue {
         self.next_used = Wrapping(0);
         self.features = 0;
         self.last_used = Wrapping(0);
+        self.avail_wrap_counter = true;
+        self.used_wrap_counter = true;
+        self.packed_chain_lens.clear();
+        self.packed_peeked = None;
         self.exported_desc_table = None;
         self.exported_avail_ring = None;
         self.exported_used_ring = None;
@@ -518,10 +607,28 @@ impl Queue {
         self.next_avail = Wrapping(0);
         self.next_used = Wrapping(0);
         self.last_used = Wrapping(0);
+        self.avail_wrap_counter = true;
+        self.used_wrap_counter = true;
+        self.packed_chain_lens.iter_mut().for_each(|len| *len = 0);
+        self.packed_peeked = None;
+    }
+
+    /// Returns whether the driver accepted `VIRTIO_F_RING_PACKED`, so the queue uses the packed
+    /// virtqueue layout instead of the split one.
+    pub fn packed(&self) -> bool {
+        self.features & (1u64 << VIRTIO_F_RING_PACKED) != 0
     }
 
     fn ring_sizes(&self) -> Vec<(GuestAddress, usize)> {
         let queue_size = self.size as usize;
+        if self.packed() {
+            // The driver and device areas only hold the event suppression structures.
+            return vec![
+                (self.desc_table, 16 * queue_size),
+                (self.avail_ring, 4),
+                (self.used_ring, 4),
+            ];
+        }
         vec![
             (self.desc_table, 16 * queue_size),
             (self.avail_ring, 6 + 2 * queue_size),
@@ -648,6 +755,10 @@ impl Queue {
             return None;
         }
 
+        if self.packed() {
+            return self.peek_packed(mem);
+        }
+
         let avail_index = self.get_avail_index(mem);
         if self.next_avail == avail_index {
             return None;
@@ -685,6 +796,14 @@ impl Queue {
     /// Remove the first available descriptor chain from the queue.
     /// This function should only be called immediately following `peek`.
     pub fn pop_peeked(&mut self, mem: &GuestMemory) {
+        if self.packed() {
+            if let Some((next_avail, wrap_counter)) = self.packed_peeked.take() {
+                self.next_avail = Wrapping(next_avail);
+                self.avail_wrap_counter = wrap_counter;
+            }
+            return;
+        }
+
         self.next_avail += Wrapping(1);
         if self.features & ((1u64) << VIRTIO_RING_F_EVENT_IDX) != 0 {
             self.set_avail_event(mem, self.next_avail);
@@ -731,6 +850,11 @@ impl Queue {
             return;
         }
 
+        if self.packed() {
+            self.add_used_packed(mem, desc_index, len);
+            return;
+        }
+
         let used_ring = self.used_ring;
         let next_used = self.wrap_queue_index(self.next_used) as usize;
         let used_elem = used_ring.unchecked_add((4 + next_used * 8) as u64);
@@ -872,6 +996,9 @@ impl Queue {
     ///                              └──────────────────────────────────┘
     /// ```
     fn queue_wants_interrupt(&self, mem: &GuestMemory) -> bool {
+        if self.packed() {
+            return self.packed_wants_interrupt(mem);
+        }
         if self.features & ((1u64) << VIRTIO_RING_F_EVENT_IDX) != 0 {
             let used_event = self.get_used_event(mem);
             self.next_used - used_event - Wrapping(1) < self.next_used - self.last_used
@@ -889,7 +1016,7 @@ impl Queue {
         interrupt: &I,
     ) -> bool {
         if self.queue_wants_interrupt(mem) {
-            self.last_used = self.next_used;
+            self.last_used = self.used_position();
             interrupt.signal_used_queue(self.vector);
             true
         } else {
@@ -905,6 +1032,202 @@ impl Queue {
     pub fn set_iommu(&mut self, iommu: Arc<Mutex<IpcMemoryMapper>>) {
         self.iommu = Some(iommu);
     }
+
+    // Returns the position in the ring of `index`, counting the laps of a packed virtqueue modulo
+    // two with `wrap_counter`, so that distances between positions can be computed like the
+    // distances between split virtqueue indices.
+    fn packed_position(&self, index: u16, wrap_counter: bool) -> Wrapping<u16> {
+        if wrap_counter {
+            Wrapping(index)
+        } else {
+            // Can't overflow as packed virtqueues have at most 32768 entries.
+            Wrapping(index + self.size)
+        }
+    }
+
+    // Returns the position compared against `last_used` to decide whether to send interrupts.
+    fn used_position(&self) -> Wrapping<u16> {
+        if self.packed() {
+            self.packed_position(self.next_used.0, self.used_wrap_counter)
+        } else {
+            self.next_used
+        }
+    }
+
+    // Returns the index and wrap counter of the ring entry following `index`.
+    fn next_packed_index(&self, index: u16, wrap_counter: bool) -> (u16, bool) {
+        if index + 1 == self.size {
+            (0, !wrap_counter)
+        } else {
+            (index + 1, wrap_counter)
+        }
+    }
+
+    // Reads the flags of the packed descriptor at `index`.
+    fn packed_desc_flags(&self, mem: &GuestMemory, index: u16) -> u16 {
+        let flags_addr = self.desc_table.unchecked_add(u64::from(index) * 16 + 14);
+        let flags: Le16 =
+            read_obj_from_addr_wrapper(mem, &self.exported_desc_table, flags_addr).unwrap();
+        flags.to_native()
+    }
+
+    // Implements `peek()` for packed virtqueues.
+    //
+    // A descriptor is available when its AVAIL flag matches the driver's wrap counter and its USED
+    // flag doesn't. The buffer ID of a chain is the one of its last descriptor.
+    fn peek_packed(&mut self, mem: &GuestMemory) -> Option<DescriptorChain> {
+        fence(Ordering::SeqCst);
+
+        let head = self.next_avail.0;
+        let flags = self.packed_desc_flags(mem, head);
+        let avail = flags & (1 << VRING_PACKED_DESC_F_AVAIL) != 0;
+        let used = flags & (1 << VRING_PACKED_DESC_F_USED) != 0;
+        if avail != self.avail_wrap_counter || used == self.avail_wrap_counter {
+            return None;
+        }
+
+        // This fence ensures that the descriptors are read only after checking that the head of
+        // the chain is available.
+        fence(Ordering::SeqCst);
+
+        let mut index = head;
+        let mut wrap_counter = self.avail_wrap_counter;
+        let mut count = 1;
+        let mut flags = flags;
+        while flags & VIRTQ_DESC_F_NEXT != 0 {
+            if count == self.size {
+                error!("packed descriptor chain is longer than the queue");
+                return None;
+            }
+            (index, wrap_counter) = self.next_packed_index(index, wrap_counter);
+            flags = self.packed_desc_flags(mem, index);
+            count += 1;
+        }
+        let id_addr = self.desc_table.unchecked_add(u64::from(index) * 16 + 12);
+        let id: Le16 = read_obj_from_addr_wrapper(mem, &self.exported_desc_table, id_addr).unwrap();
+        let id = id.to_native();
+        if id >= self.size {
+            error!("packed descriptor chain has invalid buffer id {}", id);
+            return None;
+        }
+        if self.packed_chain_lens.len() != self.size as usize {
+            self.packed_chain_lens = vec![0; self.size as usize];
+        }
+
+        let iommu = self.iommu.as_ref().map(Arc::clone);
+        let mut chain = DescriptorChain::checked_new_with_format(
+            mem,
+            self.desc_table,
+            self.size,
+            head,
+            0,
+            iommu,
+            self.exported_desc_table.clone(),
+            true,
+        )
+        .map_err(|e| {
+            error!("{:#}", e);
+            e
+        })
+        .ok()?;
+        chain.index = id;
+
+        self.packed_chain_lens[id as usize] = count;
+        self.packed_peeked = Some(self.next_packed_index(index, wrap_counter));
+        Some(chain)
+    }
+
+    // Implements `add_used()` for packed virtqueues by writing a used descriptor for buffer `id`
+    // and skipping over the descriptors of its chain.
+    fn add_used_packed(&mut self, mem: &GuestMemory, id: u16, len: u32) {
+        let desc_addr = self
+            .desc_table
+            .unchecked_add(u64::from(self.next_used.0) * 16);
+        let flags: u16 = if self.used_wrap_counter {
+            1 << VRING_PACKED_DESC_F_AVAIL | 1 << VRING_PACKED_DESC_F_USED
+        } else {
+            0
+        };
+
+        // These writes can't fail as we are guaranteed to be within the descriptor ring.
+        write_obj_at_addr_wrapper(
+            mem,
+            &self.exported_desc_table,
+            Le32::from(len),
+            desc_addr.unchecked_add(8),
+        )
+        .unwrap();
+        write_obj_at_addr_wrapper(
+            mem,
+            &self.exported_desc_table,
+            Le16::from(id),
+            desc_addr.unchecked_add(12),
+        )
+        .unwrap();
+
+        // The driver must see the ID and length before the flags making the descriptor used.
+        fence(Ordering::SeqCst);
+        write_obj_at_addr_wrapper(
+            mem,
+            &self.exported_desc_table,
+            Le16::from(flags),
+            desc_addr.unchecked_add(14),
+        )
+        .unwrap();
+
+        let count = self
+            .packed_chain_lens
+            .get_mut(id as usize)
+            .map_or(0, |len| std::mem::replace(len, 0))
+            .max(1);
+        let next_used = u32::from(self.next_used.0) + u32::from(count);
+        let size = u32::from(self.size);
+        if next_used >= size {
+            self.next_used = Wrapping((next_used - size) as u16);
+            self.used_wrap_counter = !self.used_wrap_counter;
+        } else {
+            self.next_used = Wrapping(next_used as u16);
+        }
+    }
+
+    // Implements `queue_wants_interrupt()` for packed virtqueues, using the driver event
+    // suppression structure in the driver area.
+    fn packed_wants_interrupt(&self, mem: &GuestMemory) -> bool {
+        fence(Ordering::SeqCst);
+
+        let off_wrap: Le16 =
+            read_obj_from_addr_wrapper(mem, &self.exported_avail_ring, self.avail_ring).unwrap();
+        let flags: Le16 = read_obj_from_addr_wrapper(
+            mem,
+            &self.exported_avail_ring,
+            self.avail_ring.unchecked_add(2),
+        )
+        .unwrap();
+
+        match u32::from(flags.to_native() & 0x3) {
+            VRING_PACKED_EVENT_FLAG_ENABLE => true,
+            VRING_PACKED_EVENT_FLAG_DISABLE => false,
+            VRING_PACKED_EVENT_FLAG_DESC
+                if self.features & (1u64 << VIRTIO_RING_F_EVENT_IDX) != 0 =>
+            {
+                let off_wrap = off_wrap.to_native();
+                let off = off_wrap & 0x7fff;
+                if off >= self.size {
+                    return true;
+                }
+                let wrap_counter = off_wrap & (1 << VRING_PACKED_EVENT_F_WRAP_CTR) != 0;
+                let used_event = u32::from(self.packed_position(off, wrap_counter).0);
+                let next_used = u32::from(self.used_position().0);
+                let last_used = u32::from(self.last_used.0);
+                // Positions wrap around after two laps of the ring rather than at `u16::MAX`, but
+                // the inequality is the same as for split virtqueues.
+                let ring_len = 2 * u32::from(self.size);
+                let distance = |a: u32, b: u32| (a + 2 * ring_len - b) % ring_len;
+                distance(next_used, used_event + 1) < distance(next_used, last_used)
+            }
+            _ => true,
+        }
+    }
 }
 
 #[cfg(test)]
@@ -1165,4 +1488,189 @@ mod tests {
         // should inject interrupt again.
         assert_eq!(queue.trigger_interrupt(&mem, &interrupt), true);
     }
+
+    fn setup_packed_vq(queue: &mut Queue, mem: &GuestMemory) {
+        // Descriptors, driver and device event suppression structures all start zeroed, so no
+        // descriptor is available.
+        mem.write_all_at_addr(&[0u8; 0x600], GuestAddress(DESC_OFFSET))
+            .unwrap();
+
+        queue.desc_table = GuestAddress(DESC_OFFSET);
+        queue.avail_ring = GuestAddress(AVAIL_OFFSET);
+        queue.used_ring = GuestAddress(USED_OFFSET);
+        queue.ack_features((1u64) << VIRTIO_RING_F_EVENT_IDX | (1u64) << VIRTIO_F_RING_PACKED);
+        queue.set_ready(true);
+    }
+
+    // The driver side of a packed virtqueue.
+    struct PackedDriver {
+        next: u16,
+        wrap_counter: bool,
+    }
+
+    impl PackedDriver {
+        fn new() -> PackedDriver {
+            PackedDriver {
+                next: 0,
+                wrap_counter: true,
+            }
+        }
+
+        // Makes a buffer of `count` descriptors available, and returns the index of its head.
+        fn add_buffer(&mut self, mem: &GuestMemory, id: u16, count: u16) -> u16 {
+            let head = self.next;
+            for i in 0..count {
+                let mut flags: u16 = if self.wrap_counter {
+                    1 << VRING_PACKED_DESC_F_AVAIL
+                } else {
+                    1 << VRING_PACKED_DESC_F_USED
+                };
+                if i + 1 < count {
+                    flags |= VIRTQ_DESC_F_NEXT;
+                }
+                let desc = PackedDesc {
+                    addr: Le64::from(BUFFER_OFFSET + u64::from(i) * u64::from(BUFFER_LEN)),
+                    len: Le32::from(BUFFER_LEN),
+                    id: Le16::from(id),
+                    flags: Le16::from(flags),
+                };
+                mem.write_obj_at_addr(desc, GuestAddress(DESC_OFFSET + u64::from(self.next) * 16))
+                    .unwrap();
+                self.next += 1;
+                if self.next == QUEUE_SIZE as u16 {
+                    self.next = 0;
+                    self.wrap_counter = !self.wrap_counter;
+                }
+            }
+            head
+        }
+    }
+
+    // Writes the driver event suppression structure of a packed virtqueue.
+    fn set_driver_event(mem: &GuestMemory, off_wrap: u16, flags: u32) {
+        mem.write_obj_at_addr(Le16::from(off_wrap), GuestAddress(AVAIL_OFFSET))
+            .unwrap();
+        mem.write_obj_at_addr(Le16::from(flags as u16), GuestAddress(AVAIL_OFFSET + 2))
+            .unwrap();
+    }
+
+    #[test]
+    fn packed_queue_pop_add_used() {
+        let mut queue = Queue::new(QUEUE_SIZE.try_into().unwrap());
+        let mem = GuestMemory::new(&[(GuestAddress(0), GUEST_MEMORY_SIZE)]).unwrap();
+        setup_packed_vq(&mut queue, &mem);
+        let mut driver = PackedDriver::new();
+
+        assert!(queue.peek(&mem).is_none());
+
+        // Chains of 3 descriptors don't divide the ring evenly, so some of them wrap around its
+        // end, and the wrap counters flip several times.
+        let mut used_wrap_counter = true;
+        for i in 0..20u16 {
+            let id = i % QUEUE_SIZE as u16;
+            let head = driver.add_buffer(&mem, id, 3);
+
+            let chain = queue.pop(&mem).expect("no buffer available");
+            assert_eq!(chain.index, id);
+            assert_eq!(chain.addr, GuestAddress(BUFFER_OFFSET));
+            let descs: Vec<DescriptorChain> = chain.into_iter().collect();
+            assert_eq!(descs.len(), 3);
+            assert_eq!(
+                descs[2].addr,
+                GuestAddress(BUFFER_OFFSET + 2 * u64::from(BUFFER_LEN))
+            );
+            assert!(queue.pop(&mem).is_none());
+
+            queue.add_used(&mem, id, 0x100 + u32::from(i));
+            let used: PackedDesc = mem
+                .read_obj_from_addr(GuestAddress(DESC_OFFSET + u64::from(head) * 16))
+                .unwrap();
+            assert_eq!(used.id.to_native(), id);
+            assert_eq!(used.len.to_native(), 0x100 + u32::from(i));
+            let wrap_flags = 1 << VRING_PACKED_DESC_F_AVAIL | 1 << VRING_PACKED_DESC_F_USED;
+            let used_flags = if used_wrap_counter { wrap_flags } else { 0 };
+            assert_eq!(used.flags.to_native() & wrap_flags, used_flags);
+            if head + 3 >= QUEUE_SIZE as u16 {
+                used_wrap_counter = !used_wrap_counter;
+            }
+        }
+
+        // The device caught up with the driver.
+        assert_eq!(queue.next_avail.0, driver.next);
+        assert_eq!(queue.next_used.0, driver.next);
+        assert!(queue.peek(&mem).is_none());
+    }
+
+    #[test]
+    fn packed_queue_out_of_order() {
+        let mut queue = Queue::new(QUEUE_SIZE.try_into().unwrap());
+        let mem = GuestMemory::new(&[(GuestAddress(0), GUEST_MEMORY_SIZE)]).unwrap();
+        setup_packed_vq(&mut queue, &mem);
+        let mut driver = PackedDriver::new();
+
+        driver.add_buffer(&mem, 7, 2);
+        driver.add_buffer(&mem, 3, 1);
+        let first = queue.pop(&mem).unwrap();
+        let second = queue.pop(&mem).unwrap();
+        assert_eq!(first.index, 7);
+        assert_eq!(second.index, 3);
+
+        // Used descriptors are written in completion order, each skipping over the descriptors of
+        // the buffer being returned.
+        queue.add_used(&mem, second.index, 1);
+        assert_eq!(queue.next_used.0, 1);
+        queue.add_used(&mem, first.index, 2);
+        assert_eq!(queue.next_used.0, 3);
+        let used: PackedDesc = mem.read_obj_from_addr(GuestAddress(DESC_OFFSET)).unwrap();
+        assert_eq!(used.id.to_native(), 3);
+        let used: PackedDesc = mem
+            .read_obj_from_addr(GuestAddress(DESC_OFFSET + 16))
+            .unwrap();
+        assert_eq!(used.id.to_native(), 7);
+    }
+
+    #[test]
+    fn packed_queue_event_suppression() {
+        let mut queue = Queue::new(QUEUE_SIZE.try_into().unwrap());
+        let mem = GuestMemory::new(&[(GuestAddress(0), GUEST_MEMORY_SIZE)]).unwrap();
+        setup_packed_vq(&mut queue, &mem);
+        let mut driver = PackedDriver::new();
+        let interrupt = Interrupt::new(IrqLevelEvent::new().unwrap(), None, 10);
+
+        let mut use_buffers = |queue: &mut Queue, count: u16| {
+            for _ in 0..count {
+                driver.add_buffer(&mem, 0, 1);
+                let chain = queue.pop(&mem).unwrap();
+                queue.add_used(&mem, chain.index, BUFFER_LEN);
+            }
+        };
+
+        // Interrupts are enabled by default.
+        use_buffers(&mut queue, 1);
+        assert_eq!(queue.trigger_interrupt(&mem, &interrupt), true);
+
+        set_driver_event(&mem, 0, VRING_PACKED_EVENT_FLAG_DISABLE);
+        use_buffers(&mut queue, 1);
+        assert_eq!(queue.trigger_interrupt(&mem, &interrupt), false);
+
+        // The driver wants an interrupt once the descriptor at index 4 of the first lap is used.
+        set_driver_event(&mem, 1 << 15 | 4, VRING_PACKED_EVENT_FLAG_DESC);
+        use_buffers(&mut queue, 2);
+        assert_eq!(queue.trigger_interrupt(&mem, &interrupt), false);
+        use_buffers(&mut queue, 1);
+        assert_eq!(queue.trigger_interrupt(&mem, &interrupt), true);
+        assert_eq!(queue.trigger_interrupt(&mem, &interrupt), false);
+
+        // Then once the descriptor at index 1 of the second lap is used.
+        set_driver_event(&mem, 1, VRING_PACKED_EVENT_FLAG_DESC);
+        use_buffers(&mut queue, 12);
+        assert_eq!(queue.next_used.0, 1);
+        assert_eq!(queue.trigger_interrupt(&mem, &interrupt), false);
+        use_buffers(&mut queue, 1);
+        assert_eq!(queue.trigger_interrupt(&mem, &interrupt), true);
+
+        set_driver_event(&mem, 0, VRING_PACKED_EVENT_FLAG_ENABLE);
+        use_buffers(&mut queue, 1);
+        assert_eq!(queue.trigger_interrupt(&mem, &interrupt), true);
+    }
 }
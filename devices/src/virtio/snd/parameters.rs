@@ -3,6 +3,8 @@
 // found in the LICENSE file.
 
 use std::num::ParseIntError;
+#[cfg(unix)]
+use std::path::PathBuf;
 use std::str::ParseBoolError;
 
 #[cfg(all(unix, feature = "audio_cras"))]
@@ -67,6 +69,12 @@ pub struct Parameters {
     pub client_type: CrasClientType,
     #[cfg(all(unix, feature = "audio_cras"))]
     pub socket_type: CrasSocketType,
+    /// Directory where the `file` backend records each playback stream to `stream<N>.wav`.
+    #[cfg(unix)]
+    pub playback_path: Option<PathBuf>,
+    /// WAV or raw file played to the capture streams by the `file` backend.
+    #[cfg(unix)]
+    pub capture_path: Option<PathBuf>,
 }
 
 impl Default for Parameters {
@@ -82,6 +90,10 @@ impl Default for Parameters {
             client_type: CrasClientType::CRAS_CLIENT_TYPE_CROSVM,
             #[cfg(all(unix, feature = "audio_cras"))]
             socket_type: CrasSocketType::Unified,
+            #[cfg(unix)]
+            playback_path: None,
+            #[cfg(unix)]
+            capture_path: None,
         }
     }
 }
@@ -227,4 +239,19 @@ mod tests {
             CrasSocketType::Unified,
         );
     }
+
+    #[test]
+    #[cfg(unix)]
+    fn file_parameters_fromstr() {
+        let params: Parameters = serde_keyvalue::from_key_values(
+            "backend=file,playback_path=/tmp/out,capture_path=/tmp/in.wav",
+        )
+        .expect("parse should have succeded");
+        assert_eq!(
+            params.backend,
+            StreamSourceBackend::Sys(SysStreamSourceBackend::FILE)
+        );
+        assert_eq!(params.playback_path, Some(PathBuf::from("/tmp/out")));
+        assert_eq!(params.capture_path, Some(PathBuf::from("/tmp/in.wav")));
+    }
 }
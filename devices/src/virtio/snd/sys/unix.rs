@@ -2,7 +2,12 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the LICENSE file.
 
+mod file;
+#[cfg(feature = "audio_pulse")]
+mod pulse;
+
 use async_trait::async_trait;
+use audio_streams::AsyncBufferCommit;
 use audio_streams::AsyncPlaybackBufferStream;
 use audio_streams::StreamSource;
 use audio_streams::StreamSourceGenerator;
@@ -14,6 +19,9 @@ use futures::channel::mpsc::UnboundedSender;
 #[cfg(feature = "audio_cras")]
 use libcras::CrasStreamSourceGenerator;
 
+use self::file::FileStreamSourceGenerator;
+#[cfg(feature = "audio_pulse")]
+use self::pulse::PulseStreamSourceGenerator;
 use crate::virtio::common_backend::PcmResponse;
 use crate::virtio::snd::common_backend::async_funcs::PlaybackBufferWriter;
 use crate::virtio::snd::common_backend::stream_info::StreamInfo;
@@ -42,6 +50,9 @@ pub(crate) struct SysAsyncStreamObjects {
 pub enum StreamSourceBackend {
     #[cfg(feature = "audio_cras")]
     CRAS,
+    FILE,
+    #[cfg(feature = "audio_pulse")]
+    PULSE,
 }
 
 impl TryFrom<&str> for StreamSourceBackend {
@@ -51,6 +62,9 @@ impl TryFrom<&str> for StreamSourceBackend {
         match s {
             #[cfg(feature = "audio_cras")]
             "cras" => Ok(StreamSourceBackend::CRAS),
+            "file" => Ok(StreamSourceBackend::FILE),
+            #[cfg(feature = "audio_pulse")]
+            "pulse" => Ok(StreamSourceBackend::PULSE),
             _ => Err(ParametersError::InvalidBackend),
         }
     }
@@ -72,7 +86,32 @@ pub(crate) fn create_cras_stream_source_generators(
     generators
 }
 
-#[allow(unused_variables)]
+pub(crate) fn create_file_stream_source_generators(
+    params: &Parameters,
+    snd_data: &SndData,
+) -> Vec<Box<dyn StreamSourceGenerator>> {
+    (0..snd_data.pcm_info_len())
+        .map(|index| -> Box<dyn StreamSourceGenerator> {
+            Box::new(FileStreamSourceGenerator::new(
+                index,
+                params.playback_path.as_ref(),
+                params.capture_path.as_ref(),
+            ))
+        })
+        .collect()
+}
+
+#[cfg(feature = "audio_pulse")]
+pub(crate) fn create_pulse_stream_source_generators(
+    snd_data: &SndData,
+) -> Vec<Box<dyn StreamSourceGenerator>> {
+    (0..snd_data.pcm_info_len())
+        .map(|index| -> Box<dyn StreamSourceGenerator> {
+            Box::new(PulseStreamSourceGenerator::new(index))
+        })
+        .collect()
+}
+
 pub(crate) fn create_stream_source_generators(
     backend: StreamSourceBackend,
     params: &Parameters,
@@ -81,6 +120,21 @@ pub(crate) fn create_stream_source_generators(
     match backend {
         #[cfg(feature = "audio_cras")]
         StreamSourceBackend::CRAS => create_cras_stream_source_generators(params, snd_data),
+        StreamSourceBackend::FILE => create_file_stream_source_generators(params, snd_data),
+        #[cfg(feature = "audio_pulse")]
+        StreamSourceBackend::PULSE => create_pulse_stream_source_generators(snd_data),
+    }
+}
+
+/// Records the number of frames committed by the device to the buffer of a stream.
+struct FrameCommit {
+    frames: usize,
+}
+
+#[async_trait(?Send)]
+impl AsyncBufferCommit for FrameCommit {
+    async fn commit(&mut self, nframes: usize) {
+        self.frames = nframes;
     }
 }
 
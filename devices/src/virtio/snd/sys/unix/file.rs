@@ -0,0 +1,569 @@
+// Copyright 2023 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Backend recording playback streams to WAV files and playing capture streams from a file, so
+//! that the audio of a guest can be checked deterministically.
+//!
+//! Each playback stream is written to `stream<N>.wav` in the playback directory, where `N` is the
+//! index of the stream, and the file is rewritten each time the guest prepares the stream.
+//! Capture streams read from the start of the capture file, either a WAV file whose format must
+//! match the one of the stream, or raw samples in the format of the stream, followed by silence.
+//! Both directions are consumed in real time, like they would be by a sound card.
+
+use std::fs::File;
+use std::io;
+use std::io::BufReader;
+use std::io::Read;
+use std::io::Seek;
+use std::io::SeekFrom;
+use std::io::Write;
+use std::os::unix::fs::FileExt;
+use std::path::PathBuf;
+use std::time::Duration;
+use std::time::Instant;
+
+use async_trait::async_trait;
+use audio_streams::capture::AsyncCaptureBuffer;
+use audio_streams::capture::AsyncCaptureBufferStream;
+use audio_streams::AsyncPlaybackBuffer;
+use audio_streams::AsyncPlaybackBufferStream;
+use audio_streams::AudioStreamsExecutor;
+use audio_streams::BoxError;
+use audio_streams::NoopStreamControl;
+use audio_streams::PlaybackBufferStream;
+use audio_streams::SampleFormat;
+use audio_streams::StreamControl;
+use audio_streams::StreamEffect;
+use audio_streams::StreamSource;
+use audio_streams::StreamSourceGenerator;
+use base::error;
+use remain::sorted;
+use thiserror::Error as ThisError;
+
+use super::FrameCommit;
+
+const WAVE_FORMAT_PCM: u16 = 1;
+const WAVE_FORMAT_EXTENSIBLE: u16 = 0xfffe;
+const WAV_HEADER_LEN: usize = 44;
+const WAV_EXTENSIBLE_HEADER_LEN: usize = 68;
+// GUID of the PCM subformat of `WAVE_FORMAT_EXTENSIBLE`.
+const KSDATAFORMAT_SUBTYPE_PCM: [u8; 16] = [
+    0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x10, 0x00, 0x80, 0x00, 0x00, 0xaa, 0x00, 0x38, 0x9b, 0x71,
+];
+
+#[sorted]
+#[derive(ThisError, Debug)]
+pub enum Error {
+    #[error("capture file format doesn't match the stream: {0}")]
+    FormatMismatch(String),
+    #[error("invalid WAV file: {0}")]
+    InvalidWav(&'static str),
+    #[error("failed to open {0}: {1}")]
+    Open(PathBuf, io::Error),
+    #[error("failed to read capture file: {0}")]
+    Read(io::Error),
+    #[error("synchronous streams are not supported")]
+    SyncUnsupported,
+}
+
+/// Format of the samples of a stream, as described by a WAV file.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct WavFormat {
+    channels: u16,
+    frame_rate: u32,
+    container_bits: u16,
+    valid_bits: u16,
+}
+
+impl WavFormat {
+    fn new(num_channels: usize, format: SampleFormat, frame_rate: u32) -> Self {
+        let container_bits = (format.sample_bytes() * 8) as u16;
+        WavFormat {
+            channels: num_channels as u16,
+            frame_rate,
+            container_bits,
+            valid_bits: if format == SampleFormat::S24LE {
+                24
+            } else {
+                container_bits
+            },
+        }
+    }
+
+    fn frame_bytes(&self) -> u32 {
+        u32::from(self.channels) * u32::from(self.container_bits / 8)
+    }
+
+    /// Returns the header of a WAV file with `data_len` bytes of samples.
+    fn header(&self, data_len: u32) -> Vec<u8> {
+        // Samples with padding bits can only be described by the extensible format.
+        let extensible = self.valid_bits != self.container_bits;
+        let header_len = if extensible {
+            WAV_EXTENSIBLE_HEADER_LEN
+        } else {
+            WAV_HEADER_LEN
+        };
+        let mut header = Vec::with_capacity(header_len);
+        header.extend_from_slice(b"RIFF");
+        header.extend_from_slice(&(header_len as u32 - 8 + data_len).to_le_bytes());
+        header.extend_from_slice(b"WAVE");
+        header.extend_from_slice(b"fmt ");
+        let (fmt_len, format_tag) = if extensible {
+            (40u32, WAVE_FORMAT_EXTENSIBLE)
+        } else {
+            (16, WAVE_FORMAT_PCM)
+        };
+        header.extend_from_slice(&fmt_len.to_le_bytes());
+        header.extend_from_slice(&format_tag.to_le_bytes());
+        header.extend_from_slice(&self.channels.to_le_bytes());
+        header.extend_from_slice(&self.frame_rate.to_le_bytes());
+        header.extend_from_slice(&(self.frame_rate * self.frame_bytes()).to_le_bytes());
+        header.extend_from_slice(&(self.frame_bytes() as u16).to_le_bytes());
+        header.extend_from_slice(&self.container_bits.to_le_bytes());
+        if extensible {
+            header.extend_from_slice(&22u16.to_le_bytes());
+            header.extend_from_slice(&self.valid_bits.to_le_bytes());
+            // Channel mask, left unspecified.
+            header.extend_from_slice(&0u32.to_le_bytes());
+            header.extend_from_slice(&KSDATAFORMAT_SUBTYPE_PCM);
+        }
+        header.extend_from_slice(b"data");
+        header.extend_from_slice(&data_len.to_le_bytes());
+        header
+    }
+}
+
+/// Writes samples to a WAV file, keeping its header up to date so that the file is valid even if
+/// crosvm doesn't exit cleanly.
+struct WavWriter {
+    file: File,
+    format: WavFormat,
+    header_len: u64,
+    data_len: u32,
+}
+
+impl WavWriter {
+    fn create(path: PathBuf, format: WavFormat) -> Result<WavWriter, Error> {
+        let mut file = File::create(&path).map_err(|e| Error::Open(path, e))?;
+        let header = format.header(0);
+        // The file was just created, so this can only fail if the disk is full, which will be
+        // reported by the writes of the samples.
+        let _ = file.write_all(&header);
+        Ok(WavWriter {
+            file,
+            format,
+            header_len: header.len() as u64,
+            data_len: 0,
+        })
+    }
+
+    fn write(&mut self, samples: &[u8]) -> io::Result<()> {
+        self.file
+            .write_all_at(samples, self.header_len + u64::from(self.data_len))?;
+        self.data_len = self.data_len.saturating_add(samples.len() as u32);
+        self.file
+            .write_all_at(&self.format.header(self.data_len), 0)
+    }
+}
+
+/// Parses the header of the WAV file read by `reader`, leaving it at the start of the samples.
+/// Returns the format of the samples and their length.
+fn read_wav_header<R: Read + Seek>(reader: &mut R) -> Result<(WavFormat, u64), Error> {
+    let mut riff = [0u8; 12];
+    reader.read_exact(&mut riff).map_err(Error::Read)?;
+    if &riff[0..4] != b"RIFF" || &riff[8..12] != b"WAVE" {
+        return Err(Error::InvalidWav("not a RIFF WAVE file"));
+    }
+    let mut format = None;
+    loop {
+        let mut chunk_header = [0u8; 8];
+        if let Err(e) = reader.read_exact(&mut chunk_header) {
+            return Err(match e.kind() {
+                io::ErrorKind::UnexpectedEof => Error::InvalidWav("no data chunk"),
+                _ => Error::Read(e),
+            });
+        }
+        let chunk_len = u32::from_le_bytes(chunk_header[4..8].try_into().unwrap());
+        match &chunk_header[0..4] {
+            b"fmt " => {
+                if chunk_len < 16 {
+                    return Err(Error::InvalidWav("fmt chunk too short"));
+                }
+                let mut fmt = vec![0u8; chunk_len as usize];
+                reader.read_exact(&mut fmt).map_err(Error::Read)?;
+                let u16_at = |i: usize| u16::from_le_bytes([fmt[i], fmt[i + 1]]);
+                let format_tag = u16_at(0);
+                let container_bits = u16_at(14);
+                let valid_bits = match format_tag {
+                    WAVE_FORMAT_PCM => container_bits,
+                    WAVE_FORMAT_EXTENSIBLE
+                        if chunk_len >= 40 && fmt[24..40] == KSDATAFORMAT_SUBTYPE_PCM =>
+                    {
+                        u16_at(18)
+                    }
+                    _ => return Err(Error::InvalidWav("samples are not PCM")),
+                };
+                format = Some(WavFormat {
+                    channels: u16_at(2),
+                    frame_rate: u32::from_le_bytes(fmt[4..8].try_into().unwrap()),
+                    container_bits,
+                    valid_bits,
+                });
+            }
+            b"data" => {
+                let format = format.ok_or(Error::InvalidWav("data chunk before fmt chunk"))?;
+                return Ok((format, u64::from(chunk_len)));
+            }
+            _ => {
+                reader
+                    .seek(SeekFrom::Current(i64::from(chunk_len)))
+                    .map_err(Error::Read)?;
+            }
+        }
+        // Chunks are padded to an even length.
+        if chunk_len % 2 == 1 {
+            reader.seek(SeekFrom::Current(1)).map_err(Error::Read)?;
+        }
+    }
+}
+
+/// Opens the capture file at `path` for a stream of format `format`, returning the reader of
+/// its samples.
+fn open_capture_file(path: &PathBuf, format: WavFormat) -> Result<Box<dyn Read + Send>, Error> {
+    let file = File::open(path).map_err(|e| Error::Open(path.clone(), e))?;
+    let mut reader = BufReader::new(file);
+    let mut magic = [0u8; 4];
+    let is_wav = match reader.read_exact(&mut magic) {
+        Ok(()) => &magic == b"RIFF",
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => false,
+        Err(e) => return Err(Error::Read(e)),
+    };
+    reader.seek(SeekFrom::Start(0)).map_err(Error::Read)?;
+    if !is_wav {
+        return Ok(Box::new(reader));
+    }
+    let (file_format, data_len) = read_wav_header(&mut reader)?;
+    if file_format != format {
+        return Err(Error::FormatMismatch(format!(
+            "file has {} channels at {} Hz with {} bit samples, stream has {} channels at {} Hz \
+             with {} bit samples",
+            file_format.channels,
+            file_format.frame_rate,
+            file_format.valid_bits,
+            format.channels,
+            format.frame_rate,
+            format.valid_bits
+        )));
+    }
+    Ok(Box::new(reader.take(data_len)))
+}
+
+/// Paces a stream so that it consumes or produces one buffer per period.
+struct Pacer {
+    interval: Duration,
+    next_buffer: Duration,
+    start_time: Option<Instant>,
+}
+
+impl Pacer {
+    fn new(frame_rate: u32, buffer_size: usize) -> Self {
+        let interval = Duration::from_micros(buffer_size as u64 * 1_000_000 / frame_rate as u64);
+        Pacer {
+            interval,
+            next_buffer: interval,
+            start_time: None,
+        }
+    }
+
+    /// Waits until the next buffer is due.
+    async fn wait(&mut self, ex: &dyn AudioStreamsExecutor) -> Result<(), BoxError> {
+        if let Some(start_time) = self.start_time {
+            let elapsed = start_time.elapsed();
+            if elapsed < self.next_buffer {
+                ex.delay(self.next_buffer - elapsed).await?;
+            }
+            self.next_buffer += self.interval;
+        } else {
+            self.start_time = Some(Instant::now());
+            self.next_buffer = self.interval;
+        }
+        Ok(())
+    }
+}
+
+/// Playback stream writing the samples of the guest to a WAV file, if any.
+struct FilePlaybackStream {
+    buffer: Vec<u8>,
+    frame_size: usize,
+    pacer: Pacer,
+    committed: FrameCommit,
+    writer: Option<WavWriter>,
+}
+
+impl FilePlaybackStream {
+    // Writes the frames committed by the guest to the last buffer.
+    fn flush(&mut self) {
+        let len = self.committed.frames * self.frame_size;
+        self.committed.frames = 0;
+        if let Some(writer) = &mut self.writer {
+            if let Err(e) = writer.write(&self.buffer[..len]) {
+                error!("snd: failed to write playback file, stop recording: {}", e);
+                self.writer = None;
+            }
+        }
+    }
+}
+
+impl Drop for FilePlaybackStream {
+    fn drop(&mut self) {
+        self.flush();
+    }
+}
+
+#[async_trait(?Send)]
+impl AsyncPlaybackBufferStream for FilePlaybackStream {
+    async fn next_playback_buffer<'a>(
+        &'a mut self,
+        ex: &dyn AudioStreamsExecutor,
+    ) -> Result<AsyncPlaybackBuffer<'a>, BoxError> {
+        self.flush();
+        self.pacer.wait(ex).await?;
+        Ok(AsyncPlaybackBuffer::new(
+            self.frame_size,
+            &mut self.buffer,
+            &mut self.committed,
+        )?)
+    }
+}
+
+/// Capture stream reading the samples sent to the guest from a file, if any.
+struct FileCaptureStream {
+    buffer: Vec<u8>,
+    frame_size: usize,
+    pacer: Pacer,
+    committed: FrameCommit,
+    reader: Option<Box<dyn Read + Send>>,
+    silence: u8,
+}
+
+impl FileCaptureStream {
+    // Fills the buffer with the next samples of the file, padded with silence.
+    fn fill_buffer(&mut self) {
+        let mut len = 0;
+        if let Some(reader) = &mut self.reader {
+            while len < self.buffer.len() {
+                match reader.read(&mut self.buffer[len..]) {
+                    Ok(0) => break,
+                    Ok(n) => len += n,
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                    Err(e) => {
+                        error!("snd: failed to read capture file: {}", e);
+                        break;
+                    }
+                }
+            }
+            if len < self.buffer.len() {
+                self.reader = None;
+            }
+        }
+        // Drop any incomplete frame at the end of the file.
+        len -= len % self.frame_size;
+        self.buffer[len..].fill(self.silence);
+    }
+}
+
+#[async_trait(?Send)]
+impl AsyncCaptureBufferStream for FileCaptureStream {
+    async fn next_capture_buffer<'a>(
+        &'a mut self,
+        ex: &dyn AudioStreamsExecutor,
+    ) -> Result<AsyncCaptureBuffer<'a>, BoxError> {
+        self.pacer.wait(ex).await?;
+        self.fill_buffer();
+        Ok(AsyncCaptureBuffer::new(
+            self.frame_size,
+            &mut self.buffer,
+            &mut self.committed,
+        )?)
+    }
+}
+
+/// Source of the streams of the file backend.
+pub(crate) struct FileStreamSource {
+    playback_path: Option<PathBuf>,
+    capture_path: Option<PathBuf>,
+}
+
+#[async_trait(?Send)]
+impl StreamSource for FileStreamSource {
+    #[allow(clippy::type_complexity)]
+    fn new_playback_stream(
+        &mut self,
+        _num_channels: usize,
+        _format: SampleFormat,
+        _frame_rate: u32,
+        _buffer_size: usize,
+    ) -> Result<(Box<dyn StreamControl>, Box<dyn PlaybackBufferStream>), BoxError> {
+        Err(Box::new(Error::SyncUnsupported))
+    }
+
+    #[allow(clippy::type_complexity)]
+    fn new_async_playback_stream(
+        &mut self,
+        num_channels: usize,
+        format: SampleFormat,
+        frame_rate: u32,
+        buffer_size: usize,
+        _ex: &dyn AudioStreamsExecutor,
+    ) -> Result<(Box<dyn StreamControl>, Box<dyn AsyncPlaybackBufferStream>), BoxError> {
+        let frame_size = format.sample_bytes() * num_channels;
+        let writer = match &self.playback_path {
+            Some(path) => Some(WavWriter::create(
+                path.clone(),
+                WavFormat::new(num_channels, format, frame_rate),
+            )?),
+            None => None,
+        };
+        Ok((
+            Box::new(NoopStreamControl::new()),
+            Box::new(FilePlaybackStream {
+                buffer: vec![0; buffer_size * frame_size],
+                frame_size,
+                pacer: Pacer::new(frame_rate, buffer_size),
+                committed: FrameCommit { frames: 0 },
+                writer,
+            }),
+        ))
+    }
+
+    #[allow(clippy::type_complexity)]
+    fn new_async_capture_stream(
+        &mut self,
+        num_channels: usize,
+        format: SampleFormat,
+        frame_rate: u32,
+        buffer_size: usize,
+        _effects: &[StreamEffect],
+        _ex: &dyn AudioStreamsExecutor,
+    ) -> Result<(Box<dyn StreamControl>, Box<dyn AsyncCaptureBufferStream>), BoxError> {
+        let frame_size = format.sample_bytes() * num_channels;
+        let reader = match &self.capture_path {
+            Some(path) => Some(open_capture_file(
+                path,
+                WavFormat::new(num_channels, format, frame_rate),
+            )?),
+            None => None,
+        };
+        Ok((
+            Box::new(NoopStreamControl::new()),
+            Box::new(FileCaptureStream {
+                buffer: vec![0; buffer_size * frame_size],
+                frame_size,
+                pacer: Pacer::new(frame_rate, buffer_size),
+                committed: FrameCommit { frames: 0 },
+                reader,
+                // Unsigned samples are silent at half of their range.
+                silence: if format == SampleFormat::U8 { 0x80 } else { 0 },
+            }),
+        ))
+    }
+}
+
+/// Generates the `FileStreamSource` of a single stream.
+pub(crate) struct FileStreamSourceGenerator {
+    playback_path: Option<PathBuf>,
+    capture_path: Option<PathBuf>,
+}
+
+impl FileStreamSourceGenerator {
+    /// Creates the generator of stream `index`, which records to `playback_dir` and plays
+    /// `capture_path`.
+    pub(crate) fn new(
+        index: usize,
+        playback_dir: Option<&PathBuf>,
+        capture_path: Option<&PathBuf>,
+    ) -> Self {
+        FileStreamSourceGenerator {
+            playback_path: playback_dir.map(|dir| dir.join(format!("stream{}.wav", index))),
+            capture_path: capture_path.cloned(),
+        }
+    }
+}
+
+impl StreamSourceGenerator for FileStreamSourceGenerator {
+    fn generate(&self) -> Result<Box<dyn StreamSource>, BoxError> {
+        Ok(Box::new(FileStreamSource {
+            playback_path: self.playback_path.clone(),
+            capture_path: self.capture_path.clone(),
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    #[test]
+    fn wav_header_roundtrip() {
+        for format in [SampleFormat::U8, SampleFormat::S16LE, SampleFormat::S24LE] {
+            let wav_format = WavFormat::new(2, format, 48000);
+            let mut wav = wav_format.header(8);
+            wav.extend_from_slice(&[1; 8]);
+            let mut reader = Cursor::new(wav);
+            assert_eq!(read_wav_header(&mut reader).unwrap(), (wav_format, 8));
+            let mut data = Vec::new();
+            reader.read_to_end(&mut data).unwrap();
+            assert_eq!(data, [1; 8]);
+        }
+    }
+
+    #[test]
+    fn wav_writer() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("stream0.wav");
+        let format = WavFormat::new(1, SampleFormat::S16LE, 8000);
+        let mut writer = WavWriter::create(path.clone(), format).unwrap();
+        writer.write(&[1, 2]).unwrap();
+        writer.write(&[3, 4]).unwrap();
+
+        let mut reader = BufReader::new(File::open(&path).unwrap());
+        assert_eq!(read_wav_header(&mut reader).unwrap(), (format, 4));
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data).unwrap();
+        assert_eq!(data, [1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn capture_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let format = WavFormat::new(2, SampleFormat::S16LE, 48000);
+
+        let wav_path = dir.path().join("capture.wav");
+        let mut wav = format.header(4);
+        wav.extend_from_slice(&[5, 6, 7, 8]);
+        std::fs::write(&wav_path, &wav).unwrap();
+        let mut data = Vec::new();
+        open_capture_file(&wav_path, format)
+            .unwrap()
+            .read_to_end(&mut data)
+            .unwrap();
+        assert_eq!(data, [5, 6, 7, 8]);
+        assert!(matches!(
+            open_capture_file(&wav_path, WavFormat::new(1, SampleFormat::S16LE, 48000)),
+            Err(Error::FormatMismatch(_))
+        ));
+
+        // Files that aren't WAV files are read as raw samples.
+        let raw_path = dir.path().join("capture.raw");
+        std::fs::write(&raw_path, [1, 2, 3]).unwrap();
+        let mut data = Vec::new();
+        open_capture_file(&raw_path, format)
+            .unwrap()
+            .read_to_end(&mut data)
+            .unwrap();
+        assert_eq!(data, [1, 2, 3]);
+    }
+}
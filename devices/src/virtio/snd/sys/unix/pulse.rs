@@ -0,0 +1,430 @@
+// Copyright 2023 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Backend playing and capturing audio through a PulseAudio server, including the one provided by
+//! PipeWire with pipewire-pulse.
+//!
+//! Each stream is a connection of the simple API of libpulse, whose blocking calls are made by a
+//! thread of the stream. The thread exchanges the samples with the device through a socket pair
+//! with small buffers, so that the server paces the guest.
+
+use std::ffi::CStr;
+use std::ffi::CString;
+use std::io;
+use std::io::Read;
+use std::io::Write;
+use std::os::raw::c_int;
+use std::os::raw::c_void;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::UnixStream;
+use std::ptr::null;
+use std::thread;
+
+use async_trait::async_trait;
+use audio_streams::capture::AsyncCaptureBuffer;
+use audio_streams::capture::AsyncCaptureBufferStream;
+use audio_streams::AsyncPlaybackBuffer;
+use audio_streams::AsyncPlaybackBufferStream;
+use audio_streams::AsyncStream;
+use audio_streams::AudioStreamsExecutor;
+use audio_streams::BoxError;
+use audio_streams::NoopStreamControl;
+use audio_streams::PlaybackBufferStream;
+use audio_streams::SampleFormat;
+use audio_streams::StreamControl;
+use audio_streams::StreamEffect;
+use audio_streams::StreamSource;
+use audio_streams::StreamSourceGenerator;
+use base::error;
+use remain::sorted;
+use thiserror::Error as ThisError;
+
+use super::FrameCommit;
+
+#[sorted]
+#[derive(ThisError, Debug)]
+pub enum Error {
+    #[error("failed to connect to the PulseAudio server: {0}")]
+    Connect(String),
+    #[error("failed to create the socket of the stream: {0}")]
+    CreateSocket(io::Error),
+    #[error("failed to spawn the thread of the stream: {0}")]
+    SpawnThread(io::Error),
+    #[error("PulseAudio stream ended")]
+    StreamEnded,
+    #[error("synchronous streams are not supported")]
+    SyncUnsupported,
+}
+
+fn pa_error_string(error: c_int) -> String {
+    // Safe because pa_strerror returns a static string, or null for unknown errors.
+    let message = unsafe { pulse_sys::pa_strerror(error) };
+    if message.is_null() {
+        format!("error {}", error)
+    } else {
+        // Safe because the string is a valid NUL-terminated static string.
+        unsafe { CStr::from_ptr(message) }
+            .to_string_lossy()
+            .into_owned()
+    }
+}
+
+/// Connection of the simple API of libpulse.
+struct PaSimple(*mut pulse_sys::pa_simple);
+
+// Safe because the connection is only used by a single thread at a time.
+unsafe impl Send for PaSimple {}
+
+impl PaSimple {
+    fn new(
+        dir: c_int,
+        stream_name: &str,
+        num_channels: usize,
+        format: SampleFormat,
+        frame_rate: u32,
+        period_bytes: usize,
+    ) -> Result<PaSimple, Error> {
+        let spec = pulse_sys::pa_sample_spec {
+            format: match format {
+                SampleFormat::U8 => pulse_sys::PA_SAMPLE_U8,
+                SampleFormat::S16LE => pulse_sys::PA_SAMPLE_S16LE,
+                SampleFormat::S24LE => pulse_sys::PA_SAMPLE_S24_32LE,
+                SampleFormat::S32LE => pulse_sys::PA_SAMPLE_S32LE,
+            },
+            rate: frame_rate,
+            channels: num_channels as u8,
+        };
+        // Keep the latency of the server to about two periods, and let it pick the rest.
+        let attr = pulse_sys::pa_buffer_attr {
+            maxlength: u32::MAX,
+            tlength: (period_bytes * 2) as u32,
+            prebuf: u32::MAX,
+            minreq: u32::MAX,
+            fragsize: period_bytes as u32,
+        };
+        let name = CString::new("crosvm").unwrap();
+        let stream_name = CString::new(stream_name).unwrap();
+        let mut error: c_int = 0;
+        // Safe because all the pointers are valid for the duration of the call, and the result is
+        // checked.
+        let s = unsafe {
+            pulse_sys::pa_simple_new(
+                null(),
+                name.as_ptr(),
+                dir,
+                null(),
+                stream_name.as_ptr(),
+                &spec,
+                null(),
+                &attr,
+                &mut error,
+            )
+        };
+        if s.is_null() {
+            return Err(Error::Connect(pa_error_string(error)));
+        }
+        Ok(PaSimple(s))
+    }
+
+    fn write(&self, data: &[u8]) -> Result<(), String> {
+        let mut error: c_int = 0;
+        // Safe because the connection is valid and the data is valid for reads of its length.
+        let ret = unsafe {
+            pulse_sys::pa_simple_write(
+                self.0,
+                data.as_ptr() as *const c_void,
+                data.len(),
+                &mut error,
+            )
+        };
+        if ret < 0 {
+            return Err(pa_error_string(error));
+        }
+        Ok(())
+    }
+
+    fn read(&self, data: &mut [u8]) -> Result<(), String> {
+        let mut error: c_int = 0;
+        // Safe because the connection is valid and the data is valid for writes of its length.
+        let ret = unsafe {
+            pulse_sys::pa_simple_read(
+                self.0,
+                data.as_mut_ptr() as *mut c_void,
+                data.len(),
+                &mut error,
+            )
+        };
+        if ret < 0 {
+            return Err(pa_error_string(error));
+        }
+        Ok(())
+    }
+}
+
+impl Drop for PaSimple {
+    fn drop(&mut self) {
+        // Safe because the connection is valid and not used after this.
+        unsafe { pulse_sys::pa_simple_free(self.0) };
+    }
+}
+
+/// Returns the sockets connecting a stream to its thread, buffering about `period_bytes`.
+fn stream_sockets(period_bytes: usize) -> io::Result<(UnixStream, UnixStream)> {
+    let (device, thread) = UnixStream::pair()?;
+    let size = period_bytes as c_int;
+    for socket in [&device, &thread] {
+        // Safe because the socket is valid and the option is a valid c_int.
+        let ret = unsafe {
+            libc::setsockopt(
+                socket.as_raw_fd(),
+                libc::SOL_SOCKET,
+                libc::SO_SNDBUF,
+                &size as *const c_int as *const c_void,
+                std::mem::size_of::<c_int>() as libc::socklen_t,
+            )
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok((device, thread))
+}
+
+// Plays the samples received from `socket` until the stream is dropped.
+fn playback_thread(pa: PaSimple, mut socket: UnixStream, frame_size: usize, period_bytes: usize) {
+    let mut buffer = vec![0u8; period_bytes];
+    // Bytes of an incomplete frame at the start of the buffer.
+    let mut pending = 0;
+    loop {
+        let len = match socket.read(&mut buffer[pending..]) {
+            Ok(0) => break,
+            Ok(n) => pending + n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => {
+                error!("snd: failed to receive playback samples: {}", e);
+                break;
+            }
+        };
+        let complete = len - len % frame_size;
+        if let Err(e) = pa.write(&buffer[..complete]) {
+            error!("snd: failed to play samples: {}", e);
+            break;
+        }
+        buffer.copy_within(complete..len, 0);
+        pending = len - complete;
+    }
+}
+
+// Sends the captured samples to `socket` until the stream is dropped.
+fn capture_thread(pa: PaSimple, mut socket: UnixStream, period_bytes: usize) {
+    let mut buffer = vec![0u8; period_bytes];
+    loop {
+        if let Err(e) = pa.read(&mut buffer) {
+            error!("snd: failed to capture samples: {}", e);
+            break;
+        }
+        // Fails once the stream is dropped.
+        if socket.write_all(&buffer).is_err() {
+            break;
+        }
+    }
+}
+
+struct PulsePlaybackStream {
+    buffer: Vec<u8>,
+    frame_size: usize,
+    committed: FrameCommit,
+    socket: AsyncStream,
+}
+
+#[async_trait(?Send)]
+impl AsyncPlaybackBufferStream for PulsePlaybackStream {
+    async fn next_playback_buffer<'a>(
+        &'a mut self,
+        _ex: &dyn AudioStreamsExecutor,
+    ) -> Result<AsyncPlaybackBuffer<'a>, BoxError> {
+        // Send the frames committed to the last buffer, which waits for the server to need them.
+        let mut data = self.buffer[..self.committed.frames * self.frame_size].to_vec();
+        self.committed.frames = 0;
+        while !data.is_empty() {
+            let (written, mut rest) = self.socket.write_from_vec(None, data).await?;
+            if written == 0 {
+                return Err(Box::new(Error::StreamEnded));
+            }
+            rest.drain(..written);
+            data = rest;
+        }
+        Ok(AsyncPlaybackBuffer::new(
+            self.frame_size,
+            &mut self.buffer,
+            &mut self.committed,
+        )?)
+    }
+}
+
+struct PulseCaptureStream {
+    buffer: Vec<u8>,
+    frame_size: usize,
+    committed: FrameCommit,
+    socket: AsyncStream,
+}
+
+#[async_trait(?Send)]
+impl AsyncCaptureBufferStream for PulseCaptureStream {
+    async fn next_capture_buffer<'a>(
+        &'a mut self,
+        _ex: &dyn AudioStreamsExecutor,
+    ) -> Result<AsyncCaptureBuffer<'a>, BoxError> {
+        let mut filled = 0;
+        while filled < self.buffer.len() {
+            let (read, data) = self
+                .socket
+                .read_to_vec(None, vec![0u8; self.buffer.len() - filled])
+                .await?;
+            if read == 0 {
+                return Err(Box::new(Error::StreamEnded));
+            }
+            self.buffer[filled..filled + read].copy_from_slice(&data[..read]);
+            filled += read;
+        }
+        Ok(AsyncCaptureBuffer::new(
+            self.frame_size,
+            &mut self.buffer,
+            &mut self.committed,
+        )?)
+    }
+}
+
+/// Source of the streams of the PulseAudio backend.
+pub(crate) struct PulseStreamSource {
+    stream_name: String,
+}
+
+impl PulseStreamSource {
+    // Connects a stream to the server and starts its thread, returning the socket of the device.
+    fn connect(
+        &self,
+        dir: c_int,
+        num_channels: usize,
+        format: SampleFormat,
+        frame_rate: u32,
+        period_bytes: usize,
+        ex: &dyn AudioStreamsExecutor,
+    ) -> Result<AsyncStream, BoxError> {
+        let frame_size = format.sample_bytes() * num_channels;
+        let pa = PaSimple::new(
+            dir,
+            &self.stream_name,
+            num_channels,
+            format,
+            frame_rate,
+            period_bytes,
+        )?;
+        let (device_socket, thread_socket) =
+            stream_sockets(period_bytes).map_err(Error::CreateSocket)?;
+        let builder = thread::Builder::new().name(format!("pulse {}", self.stream_name));
+        if dir == pulse_sys::PA_STREAM_PLAYBACK {
+            builder.spawn(move || playback_thread(pa, thread_socket, frame_size, period_bytes))
+        } else {
+            builder.spawn(move || capture_thread(pa, thread_socket, period_bytes))
+        }
+        .map_err(Error::SpawnThread)?;
+        Ok(ex.async_unix_stream(device_socket)?)
+    }
+}
+
+#[async_trait(?Send)]
+impl StreamSource for PulseStreamSource {
+    #[allow(clippy::type_complexity)]
+    fn new_playback_stream(
+        &mut self,
+        _num_channels: usize,
+        _format: SampleFormat,
+        _frame_rate: u32,
+        _buffer_size: usize,
+    ) -> Result<(Box<dyn StreamControl>, Box<dyn PlaybackBufferStream>), BoxError> {
+        Err(Box::new(Error::SyncUnsupported))
+    }
+
+    #[allow(clippy::type_complexity)]
+    fn new_async_playback_stream(
+        &mut self,
+        num_channels: usize,
+        format: SampleFormat,
+        frame_rate: u32,
+        buffer_size: usize,
+        ex: &dyn AudioStreamsExecutor,
+    ) -> Result<(Box<dyn StreamControl>, Box<dyn AsyncPlaybackBufferStream>), BoxError> {
+        let frame_size = format.sample_bytes() * num_channels;
+        let socket = self.connect(
+            pulse_sys::PA_STREAM_PLAYBACK,
+            num_channels,
+            format,
+            frame_rate,
+            buffer_size * frame_size,
+            ex,
+        )?;
+        Ok((
+            Box::new(NoopStreamControl::new()),
+            Box::new(PulsePlaybackStream {
+                buffer: vec![0; buffer_size * frame_size],
+                frame_size,
+                committed: FrameCommit { frames: 0 },
+                socket,
+            }),
+        ))
+    }
+
+    #[allow(clippy::type_complexity)]
+    fn new_async_capture_stream(
+        &mut self,
+        num_channels: usize,
+        format: SampleFormat,
+        frame_rate: u32,
+        buffer_size: usize,
+        _effects: &[StreamEffect],
+        ex: &dyn AudioStreamsExecutor,
+    ) -> Result<(Box<dyn StreamControl>, Box<dyn AsyncCaptureBufferStream>), BoxError> {
+        let frame_size = format.sample_bytes() * num_channels;
+        let socket = self.connect(
+            pulse_sys::PA_STREAM_RECORD,
+            num_channels,
+            format,
+            frame_rate,
+            buffer_size * frame_size,
+            ex,
+        )?;
+        Ok((
+            Box::new(NoopStreamControl::new()),
+            Box::new(PulseCaptureStream {
+                buffer: vec![0; buffer_size * frame_size],
+                frame_size,
+                committed: FrameCommit { frames: 0 },
+                socket,
+            }),
+        ))
+    }
+}
+
+/// Generates the `PulseStreamSource` of a single stream.
+pub(crate) struct PulseStreamSourceGenerator {
+    stream_name: String,
+}
+
+impl PulseStreamSourceGenerator {
+    /// Creates the generator of stream `index`.
+    pub(crate) fn new(index: usize) -> Self {
+        PulseStreamSourceGenerator {
+            stream_name: format!("stream {}", index),
+        }
+    }
+}
+
+impl StreamSourceGenerator for PulseStreamSourceGenerator {
+    fn generate(&self) -> Result<Box<dyn StreamSource>, BoxError> {
+        Ok(Box::new(PulseStreamSource {
+            stream_name: self.stream_name.clone(),
+        }))
+    }
+}
@@ -3,6 +3,7 @@
 // found in the LICENSE file.
 
 use std::io;
+use std::io::Write;
 use std::result;
 
 use base::error;
@@ -11,24 +12,47 @@ use base::EventType;
 use base::ReadNotifier;
 use base::WaitContext;
 use net_util::TapT;
+use sync::Mutex;
 use vm_memory::GuestMemory;
 
 use super::super::super::net::NetError;
+use super::super::super::net::NetState;
 use super::super::super::net::Token;
 use super::super::super::net::Worker;
+use super::super::super::net::MAX_BUFFER_SIZE;
 use super::super::super::Queue;
 use super::super::super::Reader;
 use super::super::super::SignalableInterrupt;
 use super::super::super::Writer;
 
+// Reads the next frame from `tap` into `frame_buf`, skipping the frames that `state` rejects.
+// Returns the length of the frame.
+fn read_filtered_frame<T: TapT>(
+    tap: &mut T,
+    state: &Mutex<NetState>,
+    frame_buf: &mut Vec<u8>,
+) -> io::Result<usize> {
+    frame_buf.resize(MAX_BUFFER_SIZE, 0);
+    loop {
+        let len = tap.read(frame_buf)?;
+        if state.lock().accepts_frame(&frame_buf[..len]) {
+            return Ok(len);
+        }
+    }
+}
+
+/// Receives frames from `tap` into `rx_queue`. If `state` is given, the frames it doesn't accept
+/// are dropped.
 pub fn process_rx<I: SignalableInterrupt, T: TapT>(
     interrupt: &I,
     rx_queue: &mut Queue,
     mem: &GuestMemory,
     mut tap: &mut T,
+    state: Option<&Mutex<NetState>>,
 ) -> result::Result<(), NetError> {
     let mut needs_interrupt = false;
     let mut exhausted_queue = false;
+    let mut frame_buf = Vec::new();
 
     // Read as many frames as possible.
     loop {
@@ -43,7 +67,16 @@ pub fn process_rx<I: SignalableInterrupt, T: TapT>(
         let index = desc_chain.index;
         let bytes_written = match Writer::new(mem.clone(), desc_chain) {
             Ok(mut writer) => {
-                match writer.write_from(&mut tap, writer.available_bytes()) {
+                // Frames are copied straight from the tap to the guest, unless they may have to
+                // be dropped.
+                let res = match state.filter(|s| s.lock().filters_rx()) {
+                    Some(state) => read_filtered_frame(tap, state, &mut frame_buf)
+                        .and_then(|len| writer.write_all(&frame_buf[..len])),
+                    None => writer
+                        .write_from(&mut tap, writer.available_bytes())
+                        .map(|_| ()),
+                };
+                match res {
                     Ok(_) => {}
                     Err(ref e) if e.kind() == io::ErrorKind::WriteZero => {
                         warn!("net: rx: buffer is too small to hold frame");
@@ -85,14 +118,21 @@ pub fn process_rx<I: SignalableInterrupt, T: TapT>(
     }
 }
 
+/// Sends the frames of `tx_queue` to `tap`. If `state` is given, frames are dropped while the link
+/// is down.
 pub fn process_tx<I: SignalableInterrupt, T: TapT>(
     interrupt: &I,
     tx_queue: &mut Queue,
     mem: &GuestMemory,
     mut tap: &mut T,
+    state: Option<&Mutex<NetState>>,
 ) {
     while let Some(desc_chain) = tx_queue.pop(mem) {
         let index = desc_chain.index;
+        if state.map_or(false, |s| !s.lock().link_up()) {
+            tx_queue.add_used(mem, index, 0);
+            continue;
+        }
 
         match Reader::new(mem.clone(), desc_chain) {
             Ok(mut reader) => {
@@ -157,6 +197,7 @@ where
             &mut self.rx_queue,
             &self.mem,
             &mut self.tap,
+            Some(&self.state),
         )
     }
 }
@@ -15,12 +15,14 @@ use base::ReadNotifier;
 use base::WaitContext;
 use libc::EEXIST;
 use net_util::TapT;
+use sync::Mutex;
 use virtio_sys::virtio_net;
 use vm_memory::GuestMemory;
 
 use super::super::super::base_features;
 use super::super::super::net::Net;
 use super::super::super::net::NetError;
+use super::super::super::net::NetState;
 use super::super::super::net::Token;
 use super::super::super::net::Worker;
 use super::super::super::net::MAX_BUFFER_SIZE;
@@ -152,6 +154,7 @@ pub fn process_tx<I: SignalableInterrupt, T: TapT>(
     tx_queue: &mut Queue,
     mem: &GuestMemory,
     tap: &mut T,
+    state: Option<&Mutex<NetState>>,
 ) {
     // Reads up to `buf.len()` bytes or until there is no more data in `r`, whichever
     // is smaller.
@@ -170,6 +173,10 @@ pub fn process_tx<I: SignalableInterrupt, T: TapT>(
 
     while let Some(desc_chain) = tx_queue.pop(mem) {
         let index = desc_chain.index;
+        if state.map_or(false, |s| !s.lock().link_up()) {
+            tx_queue.add_used(mem, index, 0);
+            continue;
+        }
 
         match Reader::new(mem.clone(), desc_chain) {
             Ok(reader) => {
@@ -225,6 +232,7 @@ where
             avail_features,
             1500,
             None,
+            None,
             Some(slirp_kill_evt),
         )
     }
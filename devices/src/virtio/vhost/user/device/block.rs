@@ -37,6 +37,8 @@ use crate::virtio::block::asynchronous::handle_queue;
 use crate::virtio::block::asynchronous::handle_vhost_user_command_tube;
 use crate::virtio::block::asynchronous::BlockAsync;
 use crate::virtio::block::DiskState;
+use crate::virtio::block::DiskThrottle;
+use crate::virtio::block::ZonedDisk;
 use crate::virtio::copy_config;
 use crate::virtio::vhost::user::device::handler::sys::Doorbell;
 use crate::virtio::vhost::user::device::handler::VhostBackendReqConnection;
@@ -50,6 +52,7 @@ struct BlockBackend {
     ex: Executor,
     disk_state: Rc<AsyncMutex<DiskState>>,
     disk_size: Arc<AtomicU64>,
+    zones: Option<Arc<Mutex<ZonedDisk>>>,
     block_size: u32,
     seg_max: u32,
     avail_features: u64,
@@ -85,6 +88,8 @@ impl VhostUserDevice for BlockAsync {
             self.read_only,
             self.sparse,
             self.id,
+            DiskThrottle::new(&self.throttle),
+            self.zones.clone(),
         )));
 
         let timer = Timer::new().context("Failed to create a timer")?;
@@ -116,6 +121,7 @@ impl VhostUserDevice for BlockAsync {
         if let Some(control_tube) = self.control_tube.take() {
             let async_tube = AsyncTube::new(ex, control_tube)?;
             ex.spawn_local(handle_vhost_user_command_tube(
+                ex.clone(),
                 async_tube,
                 Arc::clone(&backend_req_conn),
                 Rc::clone(&disk_state),
@@ -127,6 +133,7 @@ impl VhostUserDevice for BlockAsync {
             ex: ex.clone(),
             disk_state,
             disk_size: Arc::clone(&self.disk_size),
+            zones: self.zones.clone(),
             block_size: self.block_size,
             seg_max: self.seg_max,
             avail_features,
@@ -189,7 +196,14 @@ impl VhostUserBackend for BlockBackend {
     fn read_config(&self, offset: u64, data: &mut [u8]) {
         let config_space = {
             let disk_size = self.disk_size.load(Ordering::Relaxed);
-            BlockAsync::build_config_space(disk_size, self.seg_max, self.block_size, NUM_QUEUES)
+            let zones = self.zones.as_ref().map(|z| z.lock());
+            BlockAsync::build_config_space(
+                disk_size,
+                self.seg_max,
+                self.block_size,
+                NUM_QUEUES,
+                zones.as_deref(),
+            )
         };
         copy_config(data, 0, config_space.as_slice(), offset);
     }
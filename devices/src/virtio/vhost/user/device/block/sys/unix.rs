@@ -52,6 +52,8 @@ pub fn start_device(opts: Options) -> anyhow::Result<()> {
         block_size: 512,
         id: None,
         async_executor: None,
+        throttle: Default::default(),
+        zoned: None,
     };
 
     let block = Box::new(BlockAsync::new(
@@ -65,6 +67,8 @@ pub fn start_device(opts: Options) -> anyhow::Result<()> {
         None,
         None,
         None,
+        disk.throttle,
+        disk.zoned,
     )?);
 
     let listener = VhostUserListener::new_from_socket_or_vfio(
@@ -81,6 +81,8 @@ pub fn start_device(opts: Options) -> anyhow::Result<()> {
         None,
         None,
         None,
+        disk_option.throttle,
+        disk_option.zoned,
     )?)
     .into_backend(&ex)?;
 
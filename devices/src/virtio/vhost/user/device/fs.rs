@@ -35,6 +35,7 @@ use vmm_vhost::message::VhostUserVirtioFeatures;
 use crate::virtio;
 use crate::virtio::copy_config;
 use crate::virtio::device_constants::fs::FS_MAX_TAG_LEN;
+use crate::virtio::fs::passthrough;
 use crate::virtio::fs::passthrough::PassthroughFs;
 use crate::virtio::fs::process_fs_queue;
 use crate::virtio::vhost::user::device::handler::sys::Doorbell;
@@ -77,7 +78,7 @@ struct FsBackend {
 }
 
 impl FsBackend {
-    pub fn new(ex: &Executor, tag: &str) -> anyhow::Result<Self> {
+    pub fn new(ex: &Executor, tag: &str, cfg: passthrough::Config) -> anyhow::Result<Self> {
         if tag.len() > FS_MAX_TAG_LEN {
             bail!(
                 "fs tag is too long: {} (max supported: {})",
@@ -91,8 +92,7 @@ impl FsBackend {
         let avail_features = virtio::base_features(ProtectionType::Unprotected)
             | VhostUserVirtioFeatures::PROTOCOL_FEATURES.bits();
 
-        // Use default passthroughfs config
-        let fs = PassthroughFs::new(Default::default())?;
+        let fs = PassthroughFs::new(cfg)?;
 
         let mut keep_rds: Vec<RawDescriptor> = [0, 1, 2].to_vec();
         keep_rds.append(&mut fs.keep_rds());
@@ -234,4 +234,7 @@ pub struct Options {
     #[argh(option, arg_name = "GIDMAP")]
     /// gid map to use
     gid_map: Option<String>,
+    #[argh(option, arg_name = "PATH")]
+    /// path to a JSON file with the access policy of the shared directory
+    policy: Option<PathBuf>,
 }
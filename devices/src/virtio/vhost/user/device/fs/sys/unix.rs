@@ -13,6 +13,8 @@ use base::RawDescriptor;
 use cros_async::Executor;
 use minijail::Minijail;
 
+use crate::virtio::fs::passthrough;
+use crate::virtio::fs::policy::AccessPolicy;
 use crate::virtio::vhost::user::device::fs::FsBackend;
 use crate::virtio::vhost::user::device::fs::Options;
 use crate::virtio::vhost::user::device::handler::VhostUserBackend;
@@ -91,7 +93,16 @@ fn jail_and_fork(
 /// Returns an error if the given `args` is invalid or the device fails to run.
 pub fn start_device(opts: Options) -> anyhow::Result<()> {
     let ex = Executor::new().context("Failed to create executor")?;
-    let fs_device = Box::new(FsBackend::new(&ex, &opts.tag)?);
+    let access_policy = opts
+        .policy
+        .as_deref()
+        .map(AccessPolicy::from_file)
+        .transpose()?;
+    let cfg = passthrough::Config {
+        access_policy,
+        ..Default::default()
+    };
+    let fs_device = Box::new(FsBackend::new(&ex, &opts.tag, cfg)?);
 
     let mut keep_rds = fs_device.keep_rds.clone();
     let listener = VhostUserListener::new_from_socket_or_vfio(
@@ -68,6 +68,7 @@ use base::Protection;
 use base::SafeDescriptor;
 use base::SharedMemory;
 use sys::Doorbell;
+use virtio_sys::virtio_config::VIRTIO_F_RING_PACKED;
 use vm_control::VmMemorySource;
 use vm_memory::GuestAddress;
 use vm_memory::GuestMemory;
@@ -102,6 +103,10 @@ use crate::virtio::SignalableInterrupt;
 /// Largest valid number of entries in a virtqueue.
 const MAX_VRING_LEN: u16 = 32768;
 
+// The vring base exchanged with the frontend has no room for the wrap counters of packed
+// virtqueues, so they can't be handed over between backends.
+const UNSUPPORTED_FEATURES: u64 = 1 << VIRTIO_F_RING_PACKED;
+
 /// An event to deliver an interrupt to the guest.
 ///
 /// Unlike `devices::Interrupt`, this doesn't support interrupt status and signal resampling.
@@ -418,7 +423,7 @@ impl VhostUserSlaveReqHandlerMut for DeviceRequestHandler {
     }
 
     fn get_features(&mut self) -> VhostResult<u64> {
-        let features = self.backend.features();
+        let features = self.backend.features() & !UNSUPPORTED_FEATURES;
         Ok(features)
     }
 
@@ -427,7 +432,7 @@ impl VhostUserSlaveReqHandlerMut for DeviceRequestHandler {
             return Err(VhostError::InvalidOperation);
         }
 
-        if (features & !(self.backend.features())) != 0 {
+        if (features & !(self.backend.features() & !UNSUPPORTED_FEATURES)) != 0 {
             return Err(VhostError::InvalidParam);
         }
 
@@ -16,6 +16,7 @@ use data_model::DataInit;
 use futures::future::AbortHandle;
 use net_util::TapT;
 use once_cell::sync::OnceCell;
+use sync::Mutex;
 pub use sys::start_device as run_net_device;
 pub use sys::Options;
 use vm_memory::GuestMemory;
@@ -26,6 +27,7 @@ use crate::virtio::net::build_config;
 use crate::virtio::net::process_ctrl;
 use crate::virtio::net::process_tx;
 use crate::virtio::net::virtio_features_to_tap_offload;
+use crate::virtio::net::NetState;
 use crate::virtio::vhost::user::device::handler::sys::Doorbell;
 use crate::virtio::vhost::user::device::handler::VhostUserBackend;
 
@@ -50,7 +52,7 @@ async fn run_tx_queue<T: TapT>(
             break;
         }
 
-        process_tx(&doorbell, &mut queue, &mem, &mut tap);
+        process_tx(&doorbell, &mut queue, &mem, &mut tap, None);
     }
 }
 
@@ -63,6 +65,8 @@ async fn run_ctrl_queue<T: TapT>(
     acked_features: u64,
     vq_pairs: u16,
 ) {
+    // None of the features configuring this state are offered to vhost-user frontends.
+    let state = Mutex::new(NetState::new(None));
     loop {
         if let Err(e) = kick_evt.next_val().await {
             error!("Failed to read kick event for tx queue: {}", e);
@@ -76,6 +80,7 @@ async fn run_ctrl_queue<T: TapT>(
             &mut tap,
             acked_features,
             vq_pairs,
+            &state,
         ) {
             error!("Failed to process ctrl queue: {}", e);
             break;
@@ -146,7 +146,7 @@ async fn run_rx_queue<T: TapT>(
             error!("Failed to wait for tap device to become readable: {}", e);
             break;
         }
-        match process_rx(&doorbell, &mut queue, &mem, tap.as_source_mut()) {
+        match process_rx(&doorbell, &mut queue, &mem, tap.as_source_mut(), None) {
             Ok(()) => {}
             Err(NetError::RxDescriptorsExhausted) => {
                 if let Err(e) = kick_evt.next_val().await {
@@ -281,9 +281,11 @@ pub struct Options {
     /// comma separated key=value pairs for setting up cras snd devices.
     /// Possible key values:
     /// capture - Enable audio capture. Default to false.
-    /// backend - Which backend to use for vhost-snd (null|cras).
+    /// backend - Which backend to use for vhost-snd (null|file|cras|pulse).
     /// client_type - Set specific client type for cras backend.
     /// socket_type - Set socket type for cras backend.
+    /// playback_path - Directory where the file backend records the playback streams.
+    /// capture_path - WAV or raw file played to the capture streams by the file backend.
     /// num_output_devices - Set number of output PCM devices.
     /// num_input_devices - Set number of input PCM devices.
     /// num_output_streams - Set number of output PCM streams per device.
@@ -4,15 +4,13 @@
 
 //! This module implements the virtio vsock device.
 //!
-//! Currently, this is only implemented for Windows.
-//! For Linux, please use the vhost-vsock device, which delegates the vsock
-//! implementation to the kernel.
-
-#![cfg(windows)]
+//! On Windows, the host side of the connections are named pipes. On Linux, they are Unix domain
+//! sockets; the vhost-vsock device, which delegates the vsock implementation to the kernel, can
+//! be used instead when the `vhost_vsock` module is available.
 
 pub mod protocol;
-pub mod vsock;
+mod sys;
 
 pub(crate) use protocol::*;
-pub use vsock::Vsock;
-pub use vsock::VsockError;
+pub use sys::Vsock;
+pub use sys::VsockError;
@@ -63,3 +63,7 @@ pub mod vsock_op {
     /* Request the peer to send the credit info to us */
     pub const VIRTIO_VSOCK_OP_CREDIT_REQUEST: u16 = 7;
 }
+
+/* Flags of VIRTIO_VSOCK_OP_SHUTDOWN packets */
+pub const VIRTIO_VSOCK_SHUTDOWN_F_RECEIVE: u32 = 1;
+pub const VIRTIO_VSOCK_SHUTDOWN_F_SEND: u32 = 2;
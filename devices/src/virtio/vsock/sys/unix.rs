@@ -0,0 +1,1268 @@
+// Copyright 2023 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Userspace vsock device whose host side is made of Unix domain sockets.
+//!
+//! The device listens on a Unix socket at a path given by the user. To connect to a port of the
+//! guest, a host process connects to that socket and sends `CONNECT <port>\n`. Once the guest
+//! accepts the connection, the device answers `OK <host port>\n` and the socket carries the data
+//! of the connection from then on; if the guest refuses it, the socket is closed. When the guest
+//! connects to port N of the host, the device connects to the Unix socket at `<path>_N`.
+
+use std::collections::HashMap;
+use std::collections::VecDeque;
+use std::ffi::OsString;
+use std::fmt;
+use std::fmt::Display;
+use std::fs;
+use std::io;
+use std::io::Read;
+use std::io::Write;
+use std::mem;
+use std::net::Shutdown;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::io::FromRawFd;
+use std::os::unix::net::UnixListener;
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use std::path::PathBuf;
+use std::result;
+use std::thread;
+
+use anyhow::anyhow;
+use anyhow::Context;
+use base::error;
+use base::info;
+use base::warn;
+use base::AsRawDescriptor;
+use base::Error as SysError;
+use base::Event;
+use base::EventToken;
+use base::EventType;
+use base::RawDescriptor;
+use base::WaitContext;
+use data_model::DataInit;
+use data_model::Le64;
+use remain::sorted;
+use thiserror::Error as ThisError;
+use vm_memory::GuestMemory;
+
+use crate::virtio::copy_config;
+use crate::virtio::device_constants::vsock::NUM_QUEUES;
+use crate::virtio::device_constants::vsock::QUEUE_SIZES;
+use crate::virtio::virtio_vsock_config;
+use crate::virtio::virtio_vsock_hdr;
+use crate::virtio::vsock_op;
+use crate::virtio::DeviceType;
+use crate::virtio::Interrupt;
+use crate::virtio::Queue;
+use crate::virtio::Reader;
+use crate::virtio::SignalableInterrupt;
+use crate::virtio::VirtioDevice;
+use crate::virtio::Writer;
+use crate::virtio::TYPE_STREAM_SOCKET;
+use crate::virtio::VIRTIO_VSOCK_SHUTDOWN_F_RECEIVE;
+use crate::virtio::VIRTIO_VSOCK_SHUTDOWN_F_SEND;
+use crate::Suspendable;
+
+#[sorted]
+#[derive(ThisError, Debug)]
+pub enum VsockError {
+    #[error("failed to bind the vsock socket {0}: {1}")]
+    Bind(PathBuf, io::Error),
+    #[error("failed to create WaitContext: {0}")]
+    CreateWaitContext(SysError),
+    #[error("failed to remove stale vsock socket {0}: {1}")]
+    RemoveStaleSocket(PathBuf, io::Error),
+    #[error("failed to set the vsock socket non-blocking: {0}")]
+    SetNonBlocking(io::Error),
+}
+pub type Result<T> = result::Result<T, VsockError>;
+
+/// CID of the host, the peer of every connection of the guest.
+const HOST_CID: u64 = 2;
+
+const HEADER_SIZE: usize = std::mem::size_of::<virtio_vsock_hdr>();
+
+/// Largest amount of data read from a host socket at once, which matches the size of the rx
+/// buffers of the Linux driver.
+const READ_BUF_SIZE: usize = 4096;
+
+/// Data of the guest that is buffered for each connection until the host socket takes it, which
+/// is the `buf_alloc` advertised to the guest.
+const BUF_ALLOC: u32 = 256 * 1024;
+
+/// Amount of data written to a host socket after which the guest is told, so that it doesn't run
+/// out of credit.
+const CREDIT_UPDATE_THRESHOLD: u32 = BUF_ALLOC / 2;
+
+/// Host sockets aren't read while this many packets wait for room in the rx queue.
+const MAX_RX_BACKLOG: usize = 64;
+
+/// Host ports of the connections initiated by the host are allocated from this one upwards.
+const FIRST_HOST_PORT: u32 = 1 << 30;
+
+/// Longest `CONNECT` line accepted from the host.
+const MAX_CONNECT_LINE: usize = 32;
+
+/// Virtio device exposing Unix domain sockets of the host as vsock connections.
+pub struct Vsock {
+    guest_cid: u64,
+    uds_path: PathBuf,
+    listener: Option<UnixListener>,
+    features: u64,
+    kill_evt: Option<Event>,
+    worker_thread: Option<thread::JoinHandle<()>>,
+}
+
+impl Vsock {
+    /// Creates the device for a guest with the context ID `guest_cid`, listening for the
+    /// connections of the host at `uds_path`.
+    pub fn new(guest_cid: u64, uds_path: &Path, base_features: u64) -> Result<Vsock> {
+        // A socket left behind by a previous instance would make binding fail.
+        if let Ok(metadata) = fs::symlink_metadata(uds_path) {
+            if metadata.file_type().is_socket() {
+                fs::remove_file(uds_path)
+                    .map_err(|e| VsockError::RemoveStaleSocket(uds_path.to_owned(), e))?;
+            }
+        }
+        let listener =
+            UnixListener::bind(uds_path).map_err(|e| VsockError::Bind(uds_path.to_owned(), e))?;
+        listener
+            .set_nonblocking(true)
+            .map_err(VsockError::SetNonBlocking)?;
+
+        Ok(Vsock {
+            guest_cid,
+            uds_path: uds_path.to_owned(),
+            listener: Some(listener),
+            features: base_features,
+            kill_evt: None,
+            worker_thread: None,
+        })
+    }
+
+    fn get_config(&self) -> virtio_vsock_config {
+        virtio_vsock_config {
+            guest_cid: Le64::from(self.guest_cid),
+        }
+    }
+}
+
+impl Drop for Vsock {
+    fn drop(&mut self) {
+        if let Some(kill_evt) = self.kill_evt.take() {
+            // Ignore the result because there is nothing we can do about it.
+            let _ = kill_evt.signal();
+        }
+
+        if let Some(worker_thread) = self.worker_thread.take() {
+            let _ = worker_thread.join();
+        }
+    }
+}
+
+impl VirtioDevice for Vsock {
+    fn keep_rds(&self) -> Vec<RawDescriptor> {
+        self.listener
+            .iter()
+            .map(|listener| listener.as_raw_descriptor())
+            .collect()
+    }
+
+    fn read_config(&self, offset: u64, data: &mut [u8]) {
+        copy_config(data, 0, self.get_config().as_slice(), offset);
+    }
+
+    fn device_type(&self) -> DeviceType {
+        DeviceType::Vsock
+    }
+
+    fn queue_max_sizes(&self) -> &[u16] {
+        QUEUE_SIZES
+    }
+
+    fn features(&self) -> u64 {
+        self.features
+    }
+
+    fn ack_features(&mut self, value: u64) {
+        self.features &= value;
+    }
+
+    fn activate(
+        &mut self,
+        mem: GuestMemory,
+        interrupt: Interrupt,
+        mut queues: Vec<(Queue, Event)>,
+    ) -> anyhow::Result<()> {
+        if queues.len() != NUM_QUEUES {
+            return Err(anyhow!(
+                "Failed to activate vsock device. queues.len(): {} != {}",
+                queues.len(),
+                NUM_QUEUES,
+            ));
+        }
+        let listener = self
+            .listener
+            .take()
+            .context("vsock device was already activated")?;
+
+        let (rx_queue, rx_queue_evt) = queues.remove(0);
+        let (tx_queue, tx_queue_evt) = queues.remove(0);
+        // The event queue is only used to report transport resets after a migration, which never
+        // happen here.
+        let (_event_queue, event_queue_evt) = queues.remove(0);
+
+        let (self_kill_evt, kill_evt) = Event::new()
+            .and_then(|e| Ok((e.try_clone()?, e)))
+            .context("failed to create kill Event pair")?;
+        self.kill_evt = Some(self_kill_evt);
+        let guest_cid = self.guest_cid;
+        let uds_path = self.uds_path.clone();
+        let worker_thread = thread::Builder::new()
+            .name("userspace_virtio_vsock".to_string())
+            .spawn(move || {
+                let mut worker = match Worker::new(
+                    mem, interrupt, guest_cid, uds_path, listener, rx_queue, tx_queue,
+                ) {
+                    Ok(worker) => worker,
+                    Err(e) => {
+                        error!("failed to create userspace vsock worker: {}", e);
+                        return;
+                    }
+                };
+                if let Err(e) = worker.run(rx_queue_evt, tx_queue_evt, event_queue_evt, kill_evt) {
+                    error!("userspace vsock worker thread exited with error: {}", e);
+                }
+            })
+            .context("failed to spawn virtio-vsock worker")?;
+        self.worker_thread = Some(worker_thread);
+        Ok(())
+    }
+}
+
+impl Suspendable for Vsock {}
+
+#[derive(PartialEq, Eq, Hash, Clone, Copy, Debug)]
+struct PortPair {
+    host: u32,
+    guest: u32,
+}
+
+impl Display for PortPair {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "(host port: {}, guest port: {})", self.host, self.guest)
+    }
+}
+
+impl PortPair {
+    fn from_tx_header(header: &virtio_vsock_hdr) -> PortPair {
+        PortPair {
+            host: { header.dst_port }.to_native(),
+            guest: { header.src_port }.to_native(),
+        }
+    }
+}
+
+/// Connects a non-blocking socket to the Unix socket at `path`. Unlike `UnixStream::connect`, this
+/// never waits for the listener: the connection fails with `WouldBlock` if its backlog is full.
+fn connect_nonblocking(path: &Path) -> io::Result<UnixStream> {
+    let mut addr = libc::sockaddr_un {
+        sun_family: libc::AF_UNIX as libc::sa_family_t,
+        sun_path: [0; 108],
+    };
+    let bytes = path.as_os_str().as_bytes();
+    // The path must be nul-terminated.
+    if bytes.len() >= addr.sun_path.len() {
+        return Err(io::Error::from(io::ErrorKind::InvalidInput));
+    }
+    for (dst, src) in addr.sun_path.iter_mut().zip(bytes) {
+        *dst = *src as libc::c_char;
+    }
+
+    // Safe because socket doesn't access memory and we check the result.
+    let fd = unsafe {
+        libc::socket(
+            libc::AF_UNIX,
+            libc::SOCK_STREAM | libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC,
+            0,
+        )
+    };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // Safe because we own the descriptor returned by socket.
+    let stream = unsafe { UnixStream::from_raw_fd(fd) };
+    // Safe because connect only reads the address, whose size is given, and we check the result.
+    let ret = unsafe {
+        libc::connect(
+            fd,
+            &addr as *const libc::sockaddr_un as *const libc::sockaddr,
+            mem::size_of::<libc::sockaddr_un>() as libc::socklen_t,
+        )
+    };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(stream)
+}
+
+/// Parses the line sent by a host process to connect to a guest port, without its newline.
+fn parse_connect_line(line: &[u8]) -> Option<u32> {
+    let line = std::str::from_utf8(line).ok()?;
+    line.strip_prefix("CONNECT ")?.trim_end().parse().ok()
+}
+
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+enum ConnectionState {
+    /// The host initiated the connection, and the guest hasn't answered yet.
+    Connecting,
+    Established,
+}
+
+struct VsockConnection {
+    id: u64,
+    stream: UnixStream,
+    state: ConnectionState,
+
+    // Data of the guest that the host socket didn't take yet.
+    pending_tx: VecDeque<u8>,
+    // Total free-running count of bytes written to the host socket.
+    fwd_cnt: u32,
+    // `fwd_cnt` as last sent to the guest.
+    sent_fwd_cnt: u32,
+
+    // Total free-running count of bytes sent to the guest.
+    tx_cnt: u32,
+    // Peer (driver) total rx buffer allocated.
+    peer_buf_alloc: u32,
+    // Peer (driver) total free-running count of received bytes.
+    peer_fwd_cnt: u32,
+    // Whether we asked the guest for a credit update since it last ran out of buffer space.
+    credit_requested: bool,
+
+    // The host socket reached the end of its data.
+    host_eof: bool,
+    // The guest won't receive or send any more data.
+    guest_shutdown_recv: bool,
+    guest_shutdown_send: bool,
+
+    // The events the stream is registered for in the WaitContext, if it is.
+    registered: Option<EventType>,
+    // The host socket was hung up, so the stream can't be left registered without events.
+    hungup: bool,
+}
+
+impl VsockConnection {
+    fn new(id: u64, stream: UnixStream, state: ConnectionState) -> VsockConnection {
+        VsockConnection {
+            id,
+            stream,
+            state,
+            pending_tx: VecDeque::new(),
+            fwd_cnt: 0,
+            sent_fwd_cnt: 0,
+            tx_cnt: 0,
+            peer_buf_alloc: 0,
+            peer_fwd_cnt: 0,
+            credit_requested: false,
+            host_eof: false,
+            guest_shutdown_recv: false,
+            guest_shutdown_send: false,
+            registered: None,
+            hungup: false,
+        }
+    }
+
+    /// Free space in the rx buffer of the guest for this connection.
+    fn peer_free(&self) -> u32 {
+        self.peer_buf_alloc
+            .wrapping_sub(self.tx_cnt.wrapping_sub(self.peer_fwd_cnt))
+    }
+
+    fn update_peer_credit(&mut self, header: &virtio_vsock_hdr) {
+        self.peer_buf_alloc = { header.buf_alloc }.to_native();
+        self.peer_fwd_cnt = { header.fwd_cnt }.to_native();
+        if self.peer_free() > 0 {
+            self.credit_requested = false;
+        }
+    }
+
+    fn wants_read(&self) -> bool {
+        self.state == ConnectionState::Established
+            && !self.host_eof
+            && !self.guest_shutdown_recv
+            && self.peer_free() > 0
+    }
+
+    /// Whether both directions of the connection are shut down.
+    fn is_closed(&self) -> bool {
+        (self.host_eof || self.guest_shutdown_recv)
+            && self.guest_shutdown_send
+            && self.pending_tx.is_empty()
+    }
+
+    /// Builds the header of a packet of this connection, telling the guest about our buffer.
+    fn header(&mut self, guest_cid: u64, port: PortPair, op: u16) -> virtio_vsock_hdr {
+        self.sent_fwd_cnt = self.fwd_cnt;
+        packet_header(guest_cid, port, op, BUF_ALLOC, self.fwd_cnt)
+    }
+
+    /// Writes as much of the pending data of the guest as the host socket takes.
+    fn flush(&mut self) -> io::Result<()> {
+        while !self.pending_tx.is_empty() {
+            let (data, _) = self.pending_tx.as_slices();
+            match self.stream.write(data) {
+                Ok(0) => return Err(io::Error::from(io::ErrorKind::WriteZero)),
+                Ok(len) => {
+                    self.pending_tx.drain(..len);
+                    self.fwd_cnt = self.fwd_cnt.wrapping_add(len as u32);
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        if self.pending_tx.is_empty() && self.guest_shutdown_send {
+            // The host sees the end of the data once everything was written.
+            let _ = self.stream.shutdown(Shutdown::Write);
+        }
+        Ok(())
+    }
+}
+
+fn packet_header(
+    guest_cid: u64,
+    port: PortPair,
+    op: u16,
+    buf_alloc: u32,
+    fwd_cnt: u32,
+) -> virtio_vsock_hdr {
+    virtio_vsock_hdr {
+        src_cid: HOST_CID.into(),
+        dst_cid: guest_cid.into(),
+        src_port: port.host.into(),
+        dst_port: port.guest.into(),
+        r#type: TYPE_STREAM_SOCKET.into(),
+        op: op.into(),
+        buf_alloc: buf_alloc.into(),
+        fwd_cnt: fwd_cnt.into(),
+        ..Default::default()
+    }
+}
+
+/// A connection of a host process, waiting for its `CONNECT` line.
+struct PendingConnection {
+    stream: UnixStream,
+    line: Vec<u8>,
+}
+
+#[derive(EventToken)]
+enum Token {
+    RxQueue,
+    TxQueue,
+    EventQueue,
+    InterruptResample,
+    Kill,
+    Listener,
+    Pending { id: u64 },
+    Connection { id: u64 },
+}
+
+struct Worker {
+    mem: GuestMemory,
+    interrupt: Interrupt,
+    guest_cid: u64,
+    uds_path: PathBuf,
+    listener: UnixListener,
+    rx_queue: Queue,
+    tx_queue: Queue,
+    wait_ctx: WaitContext<Token>,
+    // Packets waiting for buffers in the rx queue.
+    rx_backlog: VecDeque<(virtio_vsock_hdr, Vec<u8>)>,
+    connections: HashMap<PortPair, VsockConnection>,
+    // Ports of the connections, by the id of their token.
+    connection_ports: HashMap<u64, PortPair>,
+    pending: HashMap<u64, PendingConnection>,
+    next_id: u64,
+    next_host_port: u32,
+}
+
+impl Worker {
+    fn new(
+        mem: GuestMemory,
+        interrupt: Interrupt,
+        guest_cid: u64,
+        uds_path: PathBuf,
+        listener: UnixListener,
+        rx_queue: Queue,
+        tx_queue: Queue,
+    ) -> Result<Worker> {
+        Ok(Worker {
+            mem,
+            interrupt,
+            guest_cid,
+            uds_path,
+            listener,
+            rx_queue,
+            tx_queue,
+            wait_ctx: WaitContext::new().map_err(VsockError::CreateWaitContext)?,
+            rx_backlog: VecDeque::new(),
+            connections: HashMap::new(),
+            connection_ports: HashMap::new(),
+            pending: HashMap::new(),
+            next_id: 0,
+            next_host_port: FIRST_HOST_PORT,
+        })
+    }
+
+    fn next_id(&mut self) -> u64 {
+        self.next_id += 1;
+        self.next_id
+    }
+
+    fn queue_rx(&mut self, header: virtio_vsock_hdr, data: Vec<u8>) {
+        self.rx_backlog.push_back((header, data));
+    }
+
+    /// Resets the connection of a packet of the guest, whether it exists or not.
+    fn reset(&mut self, port: PortPair) {
+        self.remove_connection(port);
+        let header = packet_header(self.guest_cid, port, vsock_op::VIRTIO_VSOCK_OP_RST, 0, 0);
+        self.queue_rx(header, Vec::new());
+    }
+
+    fn remove_connection(&mut self, port: PortPair) {
+        if let Some(connection) = self.connections.remove(&port) {
+            if connection.registered.is_some() {
+                let _ = self.wait_ctx.delete(&connection.stream);
+            }
+            self.connection_ports.remove(&connection.id);
+        }
+    }
+
+    fn add_connection(&mut self, port: PortPair, connection: VsockConnection) {
+        self.connection_ports.insert(connection.id, port);
+        self.connections.insert(port, connection);
+    }
+
+    /// Writes the packets of the backlog to the rx queue, as long as it has buffers.
+    fn process_rx_queue(&mut self) -> bool {
+        let mut needs_interrupt = false;
+        while let Some((header, data)) = self.rx_backlog.front_mut() {
+            let avail_desc = match self.rx_queue.pop(&self.mem) {
+                Some(d) => d,
+                None => break,
+            };
+            let index = avail_desc.index;
+            let mut writer = match Writer::new(self.mem.clone(), avail_desc) {
+                Ok(w) => w,
+                Err(e) => {
+                    error!("vsock: failed to create Writer: {}", e);
+                    self.rx_queue.add_used(&self.mem, index, 0);
+                    needs_interrupt = true;
+                    continue;
+                }
+            };
+            // Packets with more data than the buffer can hold are split.
+            let len = data
+                .len()
+                .min(writer.available_bytes().saturating_sub(HEADER_SIZE));
+            header.len = (len as u32).into();
+            let result = writer
+                .write_all(header.as_slice())
+                .and_then(|()| writer.write_all(&data[..len]));
+            if let Err(e) = result {
+                error!("vsock: failed to write packet to the rx queue: {}", e);
+            }
+            if len == data.len() {
+                self.rx_backlog.pop_front();
+            } else {
+                data.drain(..len);
+            }
+            self.rx_queue
+                .add_used(&self.mem, index, writer.bytes_written() as u32);
+            needs_interrupt = true;
+        }
+        needs_interrupt
+    }
+
+    /// Handles the packets of the guest in the tx queue.
+    fn process_tx_queue(&mut self) -> bool {
+        let mut needs_interrupt = false;
+        while let Some(avail_desc) = self.tx_queue.pop(&self.mem) {
+            let index = avail_desc.index;
+            let packet = Reader::new(self.mem.clone(), avail_desc)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+                .and_then(|mut reader| {
+                    let header = reader.read_obj::<virtio_vsock_hdr>()?;
+                    // The guest can't send more than the buffer of a connection takes, nor more
+                    // than its descriptors hold.
+                    let len = { header.len }.to_native() as usize;
+                    if len > BUF_ALLOC as usize || len > reader.available_bytes() {
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            format!("invalid packet length {}", len),
+                        ));
+                    }
+                    let mut data = vec![0u8; len];
+                    reader.read_exact(&mut data)?;
+                    Ok((header, data))
+                });
+            self.tx_queue.add_used(&self.mem, index, 0);
+            needs_interrupt = true;
+            match packet {
+                Ok((header, data)) => self.handle_tx_packet(header, data),
+                Err(e) => error!("vsock: failed to read packet from the tx queue: {}", e),
+            }
+        }
+        needs_interrupt
+    }
+
+    fn handle_tx_packet(&mut self, header: virtio_vsock_hdr, data: Vec<u8>) {
+        let port = PortPair::from_tx_header(&header);
+        let src_cid = { header.src_cid }.to_native();
+        let dst_cid = { header.dst_cid }.to_native();
+        if src_cid != self.guest_cid || dst_cid != HOST_CID {
+            warn!(
+                "vsock: dropping packet from CID {} to CID {}",
+                src_cid, dst_cid
+            );
+            return;
+        }
+        if { header.r#type }.to_native() != TYPE_STREAM_SOCKET {
+            self.reset(port);
+            return;
+        }
+
+        let op = { header.op }.to_native();
+        if op == vsock_op::VIRTIO_VSOCK_OP_REQUEST {
+            self.handle_connection_request(port, &header);
+            return;
+        }
+        let connection = match self.connections.get_mut(&port) {
+            Some(c) => c,
+            None => {
+                if op != vsock_op::VIRTIO_VSOCK_OP_RST {
+                    self.reset(port);
+                }
+                return;
+            }
+        };
+        connection.update_peer_credit(&header);
+
+        match op {
+            vsock_op::VIRTIO_VSOCK_OP_RESPONSE => {
+                if connection.state != ConnectionState::Connecting {
+                    self.reset(port);
+                    return;
+                }
+                connection.state = ConnectionState::Established;
+                let reply = format!("OK {}\n", port.host);
+                // The socket has just been read from and is otherwise idle, so the line fits in
+                // its buffer.
+                if let Err(e) = connection.stream.write_all(reply.as_bytes()) {
+                    warn!("vsock: port {}: host socket closed on connect: {}", port, e);
+                    self.reset(port);
+                }
+            }
+            vsock_op::VIRTIO_VSOCK_OP_RST => {
+                if connection.state == ConnectionState::Connecting {
+                    info!("vsock: guest refused connection on port {}", port);
+                }
+                self.remove_connection(port);
+            }
+            vsock_op::VIRTIO_VSOCK_OP_SHUTDOWN => {
+                let flags = { header.flags }.to_native();
+                connection.guest_shutdown_recv |= flags & VIRTIO_VSOCK_SHUTDOWN_F_RECEIVE != 0;
+                connection.guest_shutdown_send |= flags & VIRTIO_VSOCK_SHUTDOWN_F_SEND != 0;
+                if let Err(e) = connection.flush() {
+                    warn!(
+                        "vsock: port {}: failed to write to host socket: {}",
+                        port, e
+                    );
+                    self.reset(port);
+                }
+            }
+            vsock_op::VIRTIO_VSOCK_OP_RW => {
+                if connection.state != ConnectionState::Established
+                    || connection.guest_shutdown_send
+                {
+                    self.reset(port);
+                    return;
+                }
+                if connection.pending_tx.len() + data.len() > BUF_ALLOC as usize {
+                    error!("vsock: port {}: guest exceeded its credit", port);
+                    self.reset(port);
+                    return;
+                }
+                connection.pending_tx.extend(data);
+                if let Err(e) = connection.flush() {
+                    warn!(
+                        "vsock: port {}: failed to write to host socket: {}",
+                        port, e
+                    );
+                    self.reset(port);
+                    return;
+                }
+                if connection.fwd_cnt.wrapping_sub(connection.sent_fwd_cnt)
+                    >= CREDIT_UPDATE_THRESHOLD
+                {
+                    let header = connection.header(
+                        self.guest_cid,
+                        port,
+                        vsock_op::VIRTIO_VSOCK_OP_CREDIT_UPDATE,
+                    );
+                    self.queue_rx(header, Vec::new());
+                }
+            }
+            vsock_op::VIRTIO_VSOCK_OP_CREDIT_UPDATE => {
+                // The credit of the peer was updated above.
+            }
+            vsock_op::VIRTIO_VSOCK_OP_CREDIT_REQUEST => {
+                let header = connection.header(
+                    self.guest_cid,
+                    port,
+                    vsock_op::VIRTIO_VSOCK_OP_CREDIT_UPDATE,
+                );
+                self.queue_rx(header, Vec::new());
+            }
+            _ => {
+                warn!(
+                    "vsock: port {}: unknown operation {}, dropping packet",
+                    port, op
+                );
+            }
+        }
+        self.check_closed(port);
+    }
+
+    /// Connects the guest to the host socket of the port it requested.
+    fn handle_connection_request(&mut self, port: PortPair, header: &virtio_vsock_hdr) {
+        if self.connections.contains_key(&port) {
+            warn!(
+                "vsock: connection request on already connected port {}",
+                port
+            );
+            return;
+        }
+        let mut path = OsString::from(self.uds_path.as_os_str());
+        path.push(format!("_{}", port.host));
+        let stream = match connect_nonblocking(Path::new(&path)) {
+            Ok(s) => s,
+            Err(e) => {
+                info!(
+                    "vsock: no host socket at {:?} for port {}, not connecting (err: {})",
+                    path, port, e
+                );
+                self.reset(port);
+                return;
+            }
+        };
+        let mut connection =
+            VsockConnection::new(self.next_id(), stream, ConnectionState::Established);
+        connection.update_peer_credit(header);
+        let response = connection.header(self.guest_cid, port, vsock_op::VIRTIO_VSOCK_OP_RESPONSE);
+        self.add_connection(port, connection);
+        self.queue_rx(response, Vec::new());
+    }
+
+    /// Accepts the connections of host processes.
+    fn handle_listener(&mut self) {
+        loop {
+            let stream = match self.listener.accept() {
+                Ok((stream, _)) => stream,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => {
+                    error!("vsock: failed to accept host connection: {}", e);
+                    break;
+                }
+            };
+            if let Err(e) = stream.set_nonblocking(true) {
+                error!("vsock: failed to set host socket non-blocking: {}", e);
+                continue;
+            }
+            let id = self.next_id();
+            if let Err(e) = self.wait_ctx.add(&stream, Token::Pending { id }) {
+                error!("vsock: failed to wait on host socket: {}", e);
+                continue;
+            }
+            self.pending.insert(
+                id,
+                PendingConnection {
+                    stream,
+                    line: Vec::new(),
+                },
+            );
+        }
+    }
+
+    /// Reads the `CONNECT` line of a host process, and forwards the connection to the guest once
+    /// complete.
+    fn handle_pending(&mut self, id: u64) {
+        let pending = match self.pending.get_mut(&id) {
+            Some(p) => p,
+            None => return,
+        };
+        let mut buf = [0u8; MAX_CONNECT_LINE];
+        let result = loop {
+            // Read one byte at a time so that no data following the line is consumed.
+            match pending.stream.read(&mut buf[..1]) {
+                Ok(0) => break Err(()),
+                Ok(_) if buf[0] == b'\n' => break parse_connect_line(&pending.line).ok_or(()),
+                Ok(_) if pending.line.len() < MAX_CONNECT_LINE => pending.line.push(buf[0]),
+                Ok(_) => break Err(()),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(_) => break Err(()),
+            }
+        };
+
+        let pending = self.pending.remove(&id).unwrap();
+        let guest_port = match result {
+            Ok(port) => port,
+            Err(()) => {
+                warn!(
+                    "vsock: dropping host connection with invalid request {:?}",
+                    String::from_utf8_lossy(&pending.line)
+                );
+                let _ = self.wait_ctx.delete(&pending.stream);
+                return;
+            }
+        };
+        // The stream isn't polled until the guest accepts the connection.
+        if let Err(e) =
+            self.wait_ctx
+                .modify(&pending.stream, EventType::None, Token::Connection { id })
+        {
+            error!("vsock: failed to wait on host socket: {}", e);
+            let _ = self.wait_ctx.delete(&pending.stream);
+            return;
+        }
+
+        let port = loop {
+            let port = PortPair {
+                host: self.next_host_port,
+                guest: guest_port,
+            };
+            self.next_host_port = self
+                .next_host_port
+                .checked_add(1)
+                .unwrap_or(FIRST_HOST_PORT);
+            if !self.connections.contains_key(&port) {
+                break port;
+            }
+        };
+        let mut connection = VsockConnection::new(id, pending.stream, ConnectionState::Connecting);
+        connection.registered = Some(EventType::None);
+        let request = connection.header(self.guest_cid, port, vsock_op::VIRTIO_VSOCK_OP_REQUEST);
+        self.add_connection(port, connection);
+        self.queue_rx(request, Vec::new());
+    }
+
+    /// Forwards the data of a host socket to the guest, and the pending data of the guest to the
+    /// host socket.
+    fn handle_connection(&mut self, id: u64, readable: bool, writable: bool, hungup: bool) {
+        let port = match self.connection_ports.get(&id) {
+            Some(p) => *p,
+            None => return,
+        };
+        let connection = self.connections.get_mut(&port).unwrap();
+        if hungup {
+            connection.hungup = true;
+            if connection.state == ConnectionState::Connecting {
+                // The host process gave up before the guest answered.
+                self.reset(port);
+                return;
+            }
+        }
+
+        if (writable || hungup) && !connection.pending_tx.is_empty() {
+            let sent_fwd_cnt = connection.sent_fwd_cnt;
+            if let Err(e) = connection.flush() {
+                warn!(
+                    "vsock: port {}: failed to write to host socket: {}",
+                    port, e
+                );
+                self.reset(port);
+                return;
+            }
+            if connection.fwd_cnt != sent_fwd_cnt {
+                let header = connection.header(
+                    self.guest_cid,
+                    port,
+                    vsock_op::VIRTIO_VSOCK_OP_CREDIT_UPDATE,
+                );
+                self.queue_rx(header, Vec::new());
+            }
+        }
+
+        let connection = self.connections.get_mut(&port).unwrap();
+        if (readable || hungup) && connection.wants_read() {
+            let mut data = vec![0u8; READ_BUF_SIZE.min(connection.peer_free() as usize)];
+            match connection.stream.read(&mut data) {
+                Ok(0) => {
+                    connection.host_eof = true;
+                    let mut header =
+                        connection.header(self.guest_cid, port, vsock_op::VIRTIO_VSOCK_OP_SHUTDOWN);
+                    header.flags = VIRTIO_VSOCK_SHUTDOWN_F_SEND.into();
+                    self.queue_rx(header, Vec::new());
+                }
+                Ok(len) => {
+                    data.truncate(len);
+                    connection.tx_cnt = connection.tx_cnt.wrapping_add(len as u32);
+                    let header =
+                        connection.header(self.guest_cid, port, vsock_op::VIRTIO_VSOCK_OP_RW);
+                    self.queue_rx(header, data);
+                }
+                Err(e)
+                    if e.kind() == io::ErrorKind::WouldBlock
+                        || e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => {
+                    warn!(
+                        "vsock: port {}: failed to read from host socket: {}",
+                        port, e
+                    );
+                    self.reset(port);
+                    return;
+                }
+            }
+        }
+        self.check_closed(port);
+    }
+
+    /// Resets the connection once both of its directions are shut down.
+    fn check_closed(&mut self, port: PortPair) {
+        if self
+            .connections
+            .get(&port)
+            .map_or(false, |connection| connection.is_closed())
+        {
+            self.reset(port);
+        }
+    }
+
+    /// Updates the events the host sockets are waited for, which depend on the credit of the
+    /// guest and on the backlog of the rx queue.
+    fn update_wait_events(&mut self) {
+        let backlog_full = self.rx_backlog.len() >= MAX_RX_BACKLOG;
+        for (port, connection) in self.connections.iter_mut() {
+            let read = connection.wants_read() && !backlog_full;
+            let write = !connection.pending_tx.is_empty();
+            let events = match (read, write) {
+                (false, false) => EventType::None,
+                (true, false) => EventType::Read,
+                (false, true) => EventType::Write,
+                (true, true) => EventType::ReadWrite,
+            };
+            // A hung up socket always triggers, so it can't stay registered without events.
+            let wanted = if events == EventType::None && connection.hungup {
+                None
+            } else {
+                Some(events)
+            };
+            if wanted == connection.registered {
+                continue;
+            }
+            let token = Token::Connection { id: connection.id };
+            let result = match (connection.registered, wanted) {
+                (None, Some(events)) => {
+                    self.wait_ctx
+                        .add_for_event(&connection.stream, events, token)
+                }
+                (Some(_), Some(events)) => self.wait_ctx.modify(&connection.stream, events, token),
+                (Some(_), None) => self.wait_ctx.delete(&connection.stream),
+                (None, None) => Ok(()),
+            };
+            match result {
+                Ok(()) => connection.registered = wanted,
+                Err(e) => error!("vsock: port {}: failed to wait on host socket: {}", port, e),
+            }
+        }
+    }
+
+    /// Asks the guest for a credit update on the connections that have data to send to it but no
+    /// space left in its buffer.
+    fn request_credit(&mut self) {
+        for (port, connection) in self.connections.iter_mut() {
+            if connection.state == ConnectionState::Established
+                && !connection.host_eof
+                && !connection.guest_shutdown_recv
+                && connection.peer_free() == 0
+                && !connection.credit_requested
+            {
+                connection.credit_requested = true;
+                let header = connection.header(
+                    self.guest_cid,
+                    *port,
+                    vsock_op::VIRTIO_VSOCK_OP_CREDIT_REQUEST,
+                );
+                self.rx_backlog.push_back((header, Vec::new()));
+            }
+        }
+    }
+
+    fn run(
+        &mut self,
+        rx_queue_evt: Event,
+        tx_queue_evt: Event,
+        event_queue_evt: Event,
+        kill_evt: Event,
+    ) -> anyhow::Result<()> {
+        self.wait_ctx
+            .add_many(&[
+                (&rx_queue_evt, Token::RxQueue),
+                (&tx_queue_evt, Token::TxQueue),
+                (&event_queue_evt, Token::EventQueue),
+                (&kill_evt, Token::Kill),
+                (&self.listener, Token::Listener),
+            ])
+            .context("failed to add events to WaitContext")?;
+        if let Some(resample_evt) = self.interrupt.get_resample_evt() {
+            self.wait_ctx
+                .add(resample_evt, Token::InterruptResample)
+                .context("failed to add resample event to WaitContext")?;
+        }
+
+        loop {
+            let events = self.wait_ctx.wait().context("failed to wait for events")?;
+            let mut needs_interrupt = false;
+            for event in events.iter() {
+                match event.token {
+                    Token::RxQueue => {
+                        rx_queue_evt
+                            .wait()
+                            .context("failed to read rx queue Event")?;
+                    }
+                    Token::TxQueue => {
+                        tx_queue_evt
+                            .wait()
+                            .context("failed to read tx queue Event")?;
+                        needs_interrupt |= self.process_tx_queue();
+                    }
+                    Token::EventQueue => {
+                        event_queue_evt
+                            .wait()
+                            .context("failed to read event queue Event")?;
+                    }
+                    Token::InterruptResample => {
+                        self.interrupt.interrupt_resample();
+                    }
+                    Token::Kill => return Ok(()),
+                    Token::Listener => self.handle_listener(),
+                    Token::Pending { id } => self.handle_pending(id),
+                    Token::Connection { id } => self.handle_connection(
+                        id,
+                        event.is_readable,
+                        event.is_writable,
+                        event.is_hungup,
+                    ),
+                }
+            }
+            self.request_credit();
+            if needs_interrupt {
+                self.tx_queue.trigger_interrupt(&self.mem, &self.interrupt);
+            }
+            if self.process_rx_queue() {
+                self.rx_queue.trigger_interrupt(&self.mem, &self.interrupt);
+            }
+            self.update_wait_events();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use vm_memory::GuestAddress;
+
+    use super::*;
+    use crate::IrqLevelEvent;
+
+    const GUEST_CID: u64 = 3;
+
+    fn worker(uds_path: &Path) -> Worker {
+        let listener = Vsock::new(GUEST_CID, uds_path, 0)
+            .unwrap()
+            .listener
+            .take()
+            .unwrap();
+        Worker::new(
+            GuestMemory::new(&[(GuestAddress(0), 0x10000)]).unwrap(),
+            Interrupt::new(IrqLevelEvent::new().unwrap(), None, 0),
+            GUEST_CID,
+            uds_path.to_owned(),
+            listener,
+            Queue::new(16),
+            Queue::new(16),
+        )
+        .unwrap()
+    }
+
+    fn guest_packet(port: PortPair, op: u16, len: u32) -> virtio_vsock_hdr {
+        virtio_vsock_hdr {
+            src_cid: GUEST_CID.into(),
+            dst_cid: HOST_CID.into(),
+            src_port: port.guest.into(),
+            dst_port: port.host.into(),
+            len: len.into(),
+            r#type: TYPE_STREAM_SOCKET.into(),
+            op: op.into(),
+            buf_alloc: 4096.into(),
+            ..Default::default()
+        }
+    }
+
+    fn next_rx(worker: &mut Worker) -> (u16, Vec<u8>) {
+        let (header, data) = worker.rx_backlog.pop_front().unwrap();
+        ({ header.op }.to_native(), data)
+    }
+
+    fn connection_id(worker: &Worker, port: PortPair) -> u64 {
+        worker.connections[&port].id
+    }
+
+    #[test]
+    fn connect_line() {
+        assert_eq!(parse_connect_line(b"CONNECT 1234"), Some(1234));
+        assert_eq!(parse_connect_line(b"CONNECT 52\r"), Some(52));
+        assert_eq!(parse_connect_line(b"CONNECT"), None);
+        assert_eq!(parse_connect_line(b"CONNECT -1"), None);
+        assert_eq!(parse_connect_line(b"connect 1234"), None);
+    }
+
+    #[test]
+    fn peer_credit() {
+        let (stream, _peer) = UnixStream::pair().unwrap();
+        let mut connection = VsockConnection::new(1, stream, ConnectionState::Established);
+        let mut header = virtio_vsock_hdr {
+            buf_alloc: 1024.into(),
+            fwd_cnt: u32::MAX.into(),
+            ..Default::default()
+        };
+        connection.tx_cnt = 1023;
+        connection.update_peer_credit(&header);
+        // The counters are free-running and wrap around.
+        assert_eq!(connection.peer_free(), 0);
+        assert!(!connection.wants_read());
+
+        header.fwd_cnt = 1023.into();
+        connection.update_peer_credit(&header);
+        assert_eq!(connection.peer_free(), 1024);
+        assert!(connection.wants_read());
+    }
+
+    #[test]
+    fn guest_connection() {
+        let dir = tempfile::tempdir().unwrap();
+        let uds_path = dir.path().join("vsock");
+        let mut worker = worker(&uds_path);
+        let port = PortPair {
+            host: 1234,
+            guest: 5000,
+        };
+
+        // Nothing listens on the port yet.
+        worker.handle_tx_packet(
+            guest_packet(port, vsock_op::VIRTIO_VSOCK_OP_REQUEST, 0),
+            Vec::new(),
+        );
+        assert_eq!(next_rx(&mut worker).0, vsock_op::VIRTIO_VSOCK_OP_RST);
+
+        let host_listener = UnixListener::bind(dir.path().join("vsock_1234")).unwrap();
+        worker.handle_tx_packet(
+            guest_packet(port, vsock_op::VIRTIO_VSOCK_OP_REQUEST, 0),
+            Vec::new(),
+        );
+        assert_eq!(next_rx(&mut worker).0, vsock_op::VIRTIO_VSOCK_OP_RESPONSE);
+        let (mut host, _) = host_listener.accept().unwrap();
+
+        worker.handle_tx_packet(
+            guest_packet(port, vsock_op::VIRTIO_VSOCK_OP_RW, 5),
+            b"hello".to_vec(),
+        );
+        let mut buf = [0u8; 5];
+        host.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"hello");
+
+        host.write_all(b"world").unwrap();
+        let id = connection_id(&worker, port);
+        worker.handle_connection(id, true, false, false);
+        assert_eq!(
+            next_rx(&mut worker),
+            (vsock_op::VIRTIO_VSOCK_OP_RW, b"world".to_vec())
+        );
+
+        // The host closing its socket shuts down the connection once the guest is done too.
+        drop(host);
+        worker.handle_connection(id, true, false, true);
+        assert_eq!(next_rx(&mut worker).0, vsock_op::VIRTIO_VSOCK_OP_SHUTDOWN);
+        let mut shutdown = guest_packet(port, vsock_op::VIRTIO_VSOCK_OP_SHUTDOWN, 0);
+        shutdown.flags = (VIRTIO_VSOCK_SHUTDOWN_F_RECEIVE | VIRTIO_VSOCK_SHUTDOWN_F_SEND).into();
+        worker.handle_tx_packet(shutdown, Vec::new());
+        assert_eq!(next_rx(&mut worker).0, vsock_op::VIRTIO_VSOCK_OP_RST);
+        assert!(worker.connections.is_empty());
+        assert!(worker.rx_backlog.is_empty());
+    }
+
+    #[test]
+    fn host_connection() {
+        let dir = tempfile::tempdir().unwrap();
+        let uds_path = dir.path().join("vsock");
+        let mut worker = worker(&uds_path);
+
+        let mut host = UnixStream::connect(&uds_path).unwrap();
+        host.write_all(b"CONNECT 52\n").unwrap();
+        worker.handle_listener();
+        let id = *worker.pending.keys().next().unwrap();
+        worker.handle_pending(id);
+        let (header, _) = worker.rx_backlog.pop_front().unwrap();
+        assert_eq!({ header.op }.to_native(), vsock_op::VIRTIO_VSOCK_OP_REQUEST);
+        assert_eq!({ header.dst_port }.to_native(), 52);
+        let port = PortPair {
+            host: { header.src_port }.to_native(),
+            guest: 52,
+        };
+
+        worker.handle_tx_packet(
+            guest_packet(port, vsock_op::VIRTIO_VSOCK_OP_RESPONSE, 0),
+            Vec::new(),
+        );
+        let mut reply = vec![0u8; format!("OK {}\n", port.host).len()];
+        host.read_exact(&mut reply).unwrap();
+        assert_eq!(reply, format!("OK {}\n", port.host).into_bytes());
+
+        host.write_all(b"ping").unwrap();
+        worker.handle_connection(id, true, false, false);
+        assert_eq!(
+            next_rx(&mut worker),
+            (vsock_op::VIRTIO_VSOCK_OP_RW, b"ping".to_vec())
+        );
+
+        // The guest resetting the connection closes the host socket.
+        worker.handle_tx_packet(
+            guest_packet(port, vsock_op::VIRTIO_VSOCK_OP_RST, 0),
+            Vec::new(),
+        );
+        assert_eq!(host.read(&mut reply).unwrap(), 0);
+        assert!(worker.connections.is_empty());
+    }
+
+    #[test]
+    fn host_connection_refused() {
+        let dir = tempfile::tempdir().unwrap();
+        let uds_path = dir.path().join("vsock");
+        let mut worker = worker(&uds_path);
+
+        let mut host = UnixStream::connect(&uds_path).unwrap();
+        host.write_all(b"HELLO\n").unwrap();
+        worker.handle_listener();
+        let id = *worker.pending.keys().next().unwrap();
+        worker.handle_pending(id);
+        assert!(worker.pending.is_empty());
+        assert!(worker.rx_backlog.is_empty());
+        assert_eq!(host.read(&mut [0u8; 1]).unwrap(), 0);
+    }
+}
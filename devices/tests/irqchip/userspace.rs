@@ -38,6 +38,7 @@ use hypervisor::DebugRegs;
 use hypervisor::DeliveryMode;
 use hypervisor::DestinationMode;
 use hypervisor::Fpu;
+use hypervisor::HwWatchpoint;
 use hypervisor::HypervHypercall;
 use hypervisor::IoParams;
 use hypervisor::IoapicRedirectionTableEntry;
@@ -51,9 +52,11 @@ use hypervisor::Regs;
 use hypervisor::Sregs;
 use hypervisor::TriggerMode;
 use hypervisor::Vcpu;
+use hypervisor::VcpuEvents;
 use hypervisor::VcpuExit;
 use hypervisor::VcpuRunHandle;
 use hypervisor::VcpuX86_64;
+use hypervisor::Xsave;
 use resources::AddressRange;
 use resources::SystemAllocator;
 use resources::SystemAllocatorConfig;
@@ -751,6 +754,18 @@ impl VcpuX86_64 for FakeVcpu {
     fn set_xcrs(&self, _xcrs: &[Register]) -> Result<()> {
         unimplemented!()
     }
+    fn get_xsave(&self) -> Result<Xsave> {
+        unimplemented!()
+    }
+    fn set_xsave(&self, _xsave: &Xsave) -> Result<()> {
+        unimplemented!()
+    }
+    fn get_vcpu_events(&self) -> Result<VcpuEvents> {
+        unimplemented!()
+    }
+    fn set_vcpu_events(&self, _events: &VcpuEvents) -> Result<()> {
+        unimplemented!()
+    }
     fn get_msrs(&self, _msrs: &mut Vec<Register>) -> Result<()> {
         unimplemented!()
     }
@@ -766,7 +781,12 @@ impl VcpuX86_64 for FakeVcpu {
     fn get_hyperv_cpuid(&self) -> Result<CpuId> {
         unimplemented!()
     }
-    fn set_guest_debug(&self, _addrs: &[GuestAddress], _enable_singlestep: bool) -> Result<()> {
+    fn set_guest_debug(
+        &self,
+        _addrs: &[GuestAddress],
+        _watchpoints: &[HwWatchpoint],
+        _enable_singlestep: bool,
+    ) -> Result<()> {
         unimplemented!()
     }
     fn get_tsc_offset(&self) -> Result<u64> {
@@ -12,7 +12,9 @@ use std::path::Path;
 use devices::virtio::fs::passthrough::Config;
 use devices::virtio::fs::passthrough::Inode;
 use devices::virtio::fs::passthrough::PassthroughFs;
+use devices::virtio::fs::policy::AccessPolicy;
 use fuse::filesystem::Context;
+use fuse::filesystem::DirectoryIterator;
 use fuse::filesystem::FileSystem;
 use fuse::filesystem::FsOptions;
 use tempfile::TempDir;
@@ -106,3 +108,61 @@ fn test_lookup_ascii_casefold() {
 
     assert_eq!(lookup(&fs, &temp_dir.path().join("nonexistent-file")), None);
 }
+
+#[test]
+fn test_access_policy() {
+    let temp_dir = TempDir::new().unwrap();
+    create_test_data(
+        &temp_dir,
+        &["ro", "rw"],
+        &["ro/a.txt", "rw/b.txt", "rw/key.pem"],
+    );
+
+    let cfg = Config {
+        access_policy: Some(AccessPolicy {
+            read_only: vec![temp_dir.path().join("ro")],
+            hidden: vec!["*.pem".to_string()],
+            max_write_bytes: None,
+        }),
+        ..Default::default()
+    };
+    let fs = PassthroughFs::new(cfg).unwrap();
+
+    let capable = FsOptions::empty();
+    fs.init(capable).unwrap();
+
+    let ctx = Context {
+        uid: 0,
+        gid: 0,
+        pid: 0,
+    };
+
+    // Hidden files can't be looked up, listed, or created.
+    assert_eq!(lookup(&fs, &temp_dir.path().join("rw/key.pem")), None);
+    let rw = lookup(&fs, &temp_dir.path().join("rw")).expect("rw must be found");
+    let (handle, _) = fs.opendir(ctx, rw, libc::O_RDONLY as u32).unwrap();
+    let mut dir = fs.readdir(ctx, rw, handle.unwrap(), 4096, 0).unwrap();
+    let mut names = Vec::new();
+    while let Some(entry) = dir.next() {
+        names.push(entry.name.to_str().unwrap().to_string());
+    }
+    names.sort();
+    assert_eq!(names, [".", "..", "b.txt"]);
+    let name = CString::new("other.pem").unwrap();
+    let err = fs.mkdir(ctx, rw, &name, 0o755, 0).err();
+    assert_eq!(err.and_then(|e| e.raw_os_error()), Some(libc::EACCES));
+
+    // Read-only subtrees can't be modified.
+    let ro = lookup(&fs, &temp_dir.path().join("ro")).expect("ro must be found");
+    let name = CString::new("a.txt").unwrap();
+    let err = fs.unlink(ctx, ro, &name).unwrap_err();
+    assert_eq!(err.raw_os_error(), Some(libc::EROFS));
+    let a = lookup(&fs, &temp_dir.path().join("ro/a.txt")).expect("a.txt must be found");
+    let err = fs.open(ctx, a, libc::O_RDWR as u32).unwrap_err();
+    assert_eq!(err.raw_os_error(), Some(libc::EROFS));
+    fs.open(ctx, a, libc::O_RDONLY as u32).unwrap();
+
+    // The rest of the directory can still be modified.
+    let name = CString::new("c").unwrap();
+    fs.mkdir(ctx, rw, &name, 0o755, 0).unwrap();
+}
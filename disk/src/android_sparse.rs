@@ -16,9 +16,11 @@ use std::sync::Arc;
 
 use async_trait::async_trait;
 use base::AsRawDescriptor;
+use base::AsRawDescriptors;
 use base::FileAllocate;
 use base::FileReadWriteAtVolatile;
 use base::FileSetLen;
+use base::FileSync;
 use base::RawDescriptor;
 use cros_async::BackingMemory;
 use cros_async::Executor;
@@ -238,6 +240,13 @@ impl DiskGetLen for AndroidSparse {
     }
 }
 
+impl FileSync for AndroidSparse {
+    fn fsync(&mut self) -> io::Result<()> {
+        // Android sparse images are read-only, there is nothing to sync.
+        Ok(())
+    }
+}
+
 impl FileSetLen for AndroidSparse {
     fn set_len(&self, _len: u64) -> io::Result<()> {
         Err(io::Error::new(
@@ -325,6 +334,12 @@ impl ToAsyncDisk for AndroidSparse {
     }
 }
 
+impl AsRawDescriptors for AsyncAndroidSparse {
+    fn as_raw_descriptors(&self) -> Vec<RawDescriptor> {
+        vec![self.inner.as_source().as_raw_descriptor()]
+    }
+}
+
 impl DiskGetLen for AsyncAndroidSparse {
     fn get_len(&self) -> io::Result<u64> {
         Ok(self.total_size)
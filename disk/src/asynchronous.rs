@@ -25,6 +25,7 @@ use crate::AsyncDisk;
 use crate::DiskFile;
 use crate::DiskGetLen;
 use crate::Error;
+use crate::InternalSnapshot;
 use crate::Result;
 
 /// Async wrapper around a non-async `DiskFile` using a `BlockingPool`.
@@ -44,6 +45,13 @@ impl<T: DiskFile + Send> AsyncDiskFileWrapper<T> {
             inner: Arc::new(Mutex::new(disk_file)),
         }
     }
+
+    /// Returns a shared handle to the wrapped disk. The handle must be dropped before `into_inner`
+    /// is called.
+    #[allow(dead_code)] // Only used if the qcow feature is enabled
+    pub fn inner(&self) -> Arc<Mutex<T>> {
+        self.inner.clone()
+    }
 }
 
 impl<T: DiskFile + Send> DiskGetLen for AsyncDiskFileWrapper<T> {
@@ -78,6 +86,7 @@ impl<
             + FileAllocate
             + FileSetLen
             + FileSync
+            + InternalSnapshot
             + PunchHole
             + WriteZeroesAt,
     > AsyncDisk for AsyncDiskFileWrapper<T>
@@ -179,4 +188,26 @@ impl<
             })
             .await
     }
+
+    async fn create_internal_snapshot(&self, name: &str) -> Result<()> {
+        let inner_clone = self.inner.clone();
+        let name = name.to_string();
+        self.blocking_pool
+            .spawn(move || {
+                let mut disk_file = inner_clone.lock();
+                disk_file.create_internal_snapshot(&name)
+            })
+            .await
+    }
+
+    async fn delete_internal_snapshot(&self, name: &str) -> Result<()> {
+        let inner_clone = self.inner.clone();
+        let name = name.to_string();
+        self.blocking_pool
+            .spawn(move || {
+                let mut disk_file = inner_clone.lock();
+                disk_file.delete_internal_snapshot(&name)
+            })
+            .await
+    }
 }
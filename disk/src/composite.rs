@@ -27,6 +27,7 @@ use base::AsRawDescriptors;
 use base::FileAllocate;
 use base::FileReadWriteAtVolatile;
 use base::FileSetLen;
+use base::FileSync;
 use base::RawDescriptor;
 use crc32fast::Hasher;
 use cros_async::BackingMemory;
@@ -353,6 +354,18 @@ impl FileReadWriteAtVolatile for CompositeDiskFile {
     }
 }
 
+impl FileSync for CompositeDiskFile {
+    fn fsync(&mut self) -> io::Result<()> {
+        for disk in self.component_disks.iter_mut() {
+            if disk.needs_fsync {
+                disk.file.fsync()?;
+                disk.needs_fsync = false;
+            }
+        }
+        Ok(())
+    }
+}
+
 impl AsRawDescriptors for CompositeDiskFile {
     fn as_raw_descriptors(&self) -> Vec<RawDescriptor> {
         self.component_disks
@@ -373,6 +386,15 @@ pub struct AsyncCompositeDiskFile {
     component_disks: Vec<AsyncComponentDiskPart>,
 }
 
+impl AsRawDescriptors for AsyncCompositeDiskFile {
+    fn as_raw_descriptors(&self) -> Vec<RawDescriptor> {
+        self.component_disks
+            .iter()
+            .flat_map(|d| d.file.as_raw_descriptors())
+            .collect()
+    }
+}
+
 impl DiskGetLen for AsyncCompositeDiskFile {
     fn get_len(&self) -> io::Result<u64> {
         Ok(self.length())
@@ -17,10 +17,13 @@ use std::sync::Arc;
 use async_trait::async_trait;
 use base::get_filesystem_type;
 use base::info;
+use base::AsRawDescriptor;
 use base::AsRawDescriptors;
 use base::FileAllocate;
 use base::FileReadWriteAtVolatile;
 use base::FileSetLen;
+use base::FileSync;
+use base::RawDescriptor;
 use cros_async::AllocateMode;
 use cros_async::BackingMemory;
 use cros_async::Executor;
@@ -33,8 +36,12 @@ pub(crate) use asynchronous::AsyncDiskFileWrapper;
 #[cfg(feature = "qcow")]
 mod qcow;
 #[cfg(feature = "qcow")]
+pub use qcow::CompressionType;
+#[cfg(feature = "qcow")]
 pub use qcow::QcowFile;
 #[cfg(feature = "qcow")]
+pub use qcow::QcowSnapshot;
+#[cfg(feature = "qcow")]
 pub use qcow::QCOW_MAGIC;
 mod sys;
 
@@ -71,8 +78,12 @@ pub const MAX_NESTING_DEPTH: u32 = 10;
 
 #[derive(ThisError, Debug)]
 pub enum Error {
+    #[error("the backing file is not the current disk image")]
+    BackingFileMismatch,
     #[error("failed to create block device: {0}")]
     BlockDeviceNew(base::Error),
+    #[error("failed to check the backing file: {0}")]
+    CheckingFile(io::Error),
     #[error("requested file conversion not supported")]
     ConversionNotSupported,
     #[cfg(feature = "android-sparse")]
@@ -87,6 +98,8 @@ pub enum Error {
     Fallocate(cros_async::AsyncError),
     #[error("failure with fsync: {0}")]
     Fsync(cros_async::AsyncError),
+    #[error("failed to get the disk size: {0}")]
+    GetLen(io::Error),
     #[error("failure with fsync: {0}")]
     IoFsync(io::Error),
     #[error("checking host fs type: {0}")]
@@ -104,6 +117,8 @@ pub enum Error {
     ReadingHeader(io::Error),
     #[error("failed to read to memory: {0}")]
     ReadToMem(cros_async::AsyncError),
+    #[error("backing file path {0} is not absolute")]
+    RelativeBackingFile(String),
     #[error("failed to seek file: {0}")]
     SeekingFile(io::Error),
     #[error("failed to set file size: {0}")]
@@ -149,13 +164,21 @@ impl DiskGetLen for File {
 
 /// The prerequisites necessary to support a block device.
 pub trait DiskFile:
-    FileSetLen + DiskGetLen + FileReadWriteAtVolatile + ToAsyncDisk + Send + AsRawDescriptors + Debug
+    FileSetLen
+    + DiskGetLen
+    + FileReadWriteAtVolatile
+    + FileSync
+    + ToAsyncDisk
+    + Send
+    + AsRawDescriptors
+    + Debug
 {
 }
 impl<
         D: FileSetLen
             + DiskGetLen
             + FileReadWriteAtVolatile
+            + FileSync
             + ToAsyncDisk
             + Send
             + AsRawDescriptors
@@ -164,6 +187,15 @@ impl<
 {
 }
 
+/// A disk image that can store snapshots of its contents within the image itself.
+pub trait InternalSnapshot {
+    /// Saves the current contents of the disk as a snapshot named `name`.
+    fn create_internal_snapshot(&mut self, name: &str) -> Result<()>;
+
+    /// Deletes the snapshot with the given name or ID.
+    fn delete_internal_snapshot(&mut self, name: &str) -> Result<()>;
+}
+
 /// A `DiskFile` that can be converted for asychronous access.
 pub trait ToAsyncDisk: AsRawDescriptors + DiskGetLen + Send {
     /// Convert a boxed self in to a box-wrapped implementaiton of AsyncDisk.
@@ -299,9 +331,57 @@ pub fn create_disk_file(
     })
 }
 
+/// Switches `disk` over to a new qcow2 image created in `overlay`, whose backing file is the
+/// current image of `disk`, found at `backing_file_name` and opened as `backing_file`. From then on
+/// writes go to the overlay and the previous image is only read from. Fails if `backing_file` isn't
+/// the current image of `disk` or if `backing_file_name` isn't an absolute path. On failure `disk` is
+/// left unchanged.
+#[cfg(feature = "qcow")]
+pub fn switch_to_overlay(
+    disk: &mut Box<dyn AsyncDisk>,
+    overlay: File,
+    backing_file: &File,
+    backing_file_name: &str,
+    ex: &Executor,
+) -> Result<()> {
+    // The overlay records the path of the backing file, which must lead to the image it replaces
+    // whatever directory the overlay is opened from.
+    if !Path::new(backing_file_name).is_absolute() {
+        return Err(Error::RelativeBackingFile(backing_file_name.to_string()));
+    }
+    match disk.as_raw_descriptors().first() {
+        Some(&image) if sys::is_same_file(backing_file, image)? => (),
+        _ => return Err(Error::BackingFileMismatch),
+    }
+
+    let size = disk.get_len().map_err(Error::GetLen)?;
+    let overlay =
+        QcowFile::new_overlay(overlay, size, backing_file_name).map_err(Error::QcowError)?;
+
+    let overlay_disk = AsyncDiskFileWrapper::new(overlay, ex);
+    let overlay = overlay_disk.inner();
+    let previous_disk = std::mem::replace(disk, Box::new(overlay_disk));
+    overlay
+        .lock()
+        .set_backing_file(Some(previous_disk.into_inner()));
+    Ok(())
+}
+
+/// Overlays are only supported for qcow2 images.
+#[cfg(not(feature = "qcow"))]
+pub fn switch_to_overlay(
+    _disk: &mut Box<dyn AsyncDisk>,
+    _overlay: File,
+    _backing_file: &File,
+    _backing_file_name: &str,
+    _ex: &Executor,
+) -> Result<()> {
+    Err(Error::UnsupportedOperation)
+}
+
 /// An asynchronously accessible disk.
 #[async_trait(?Send)]
-pub trait AsyncDisk: DiskGetLen + FileSetLen + FileAllocate {
+pub trait AsyncDisk: DiskGetLen + FileSetLen + FileAllocate + AsRawDescriptors {
     /// Returns the inner file consuming self.
     fn into_inner(self: Box<Self>) -> Box<dyn DiskFile>;
 
@@ -331,6 +411,16 @@ pub trait AsyncDisk: DiskGetLen + FileSetLen + FileAllocate {
     /// Writes up to `length` bytes of zeroes to the stream, returning how many bytes were written.
     async fn write_zeroes_at(&self, file_offset: u64, length: u64) -> Result<()>;
 
+    /// Saves the current contents of the disk as a snapshot named `name` within the disk image.
+    async fn create_internal_snapshot(&self, _name: &str) -> Result<()> {
+        Err(Error::UnsupportedOperation)
+    }
+
+    /// Deletes the snapshot with the given name or ID from the disk image.
+    async fn delete_internal_snapshot(&self, _name: &str) -> Result<()> {
+        Err(Error::UnsupportedOperation)
+    }
+
     /// Reads from the file at 'file_offset' into `buf`.
     ///
     /// Less efficient than `read_to_mem` because of extra copies and allocations.
@@ -391,6 +481,12 @@ impl FileSetLen for SingleFileDisk {
     }
 }
 
+impl AsRawDescriptors for SingleFileDisk {
+    fn as_raw_descriptors(&self) -> Vec<RawDescriptor> {
+        vec![self.inner.as_source().as_raw_descriptor()]
+    }
+}
+
 impl FileAllocate for SingleFileDisk {
     fn allocate(&mut self, offset: u64, len: u64) -> io::Result<()> {
         self.inner.as_source_mut().allocate(offset, len)
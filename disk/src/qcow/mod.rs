@@ -4,6 +4,7 @@
 
 mod qcow_raw_file;
 mod refcount;
+mod snapshot;
 mod vec_cache;
 
 use std::cmp::max;
@@ -18,6 +19,9 @@ use std::io::Write;
 use std::mem::size_of;
 use std::path::Path;
 use std::str;
+use std::str::FromStr;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
 
 use base::error;
 use base::open_file;
@@ -33,15 +37,21 @@ use base::WriteZeroesAt;
 use cros_async::Executor;
 use data_model::VolatileMemory;
 use data_model::VolatileSlice;
+use flate2::Compress;
+use flate2::Compression;
+use flate2::Decompress;
+use flate2::FlushCompress;
+use flate2::FlushDecompress;
+use flate2::Status;
 use libc::EINVAL;
 use libc::ENOSPC;
-use libc::ENOTSUP;
 use remain::sorted;
 use thiserror::Error;
 
 use crate::create_disk_file;
 use crate::qcow::qcow_raw_file::QcowRawFile;
 use crate::qcow::refcount::RefCount;
+pub use crate::qcow::snapshot::QcowSnapshot;
 use crate::qcow::vec_cache::CacheMap;
 use crate::qcow::vec_cache::Cacheable;
 use crate::qcow::vec_cache::VecCache;
@@ -49,19 +59,24 @@ use crate::AsyncDisk;
 use crate::AsyncDiskFileWrapper;
 use crate::DiskFile;
 use crate::DiskGetLen;
+use crate::InternalSnapshot;
 use crate::ToAsyncDisk;
 
 #[sorted]
 #[derive(Error, Debug)]
 pub enum Error {
+    #[error("failed to apply snapshot: {0}")]
+    ApplyingSnapshot(io::Error),
     #[error("backing file io error: {0}")]
     BackingFileIo(io::Error),
     #[error("backing file open error: {0}")]
     BackingFileOpen(Box<crate::Error>),
     #[error("backing file name is too long: {0} bytes over")]
     BackingFileTooLong(usize),
-    #[error("compressed blocks not supported")]
-    CompressedBlocksNotSupported,
+    #[error("failed to create snapshot: {0}")]
+    CreatingSnapshot(io::Error),
+    #[error("failed to delete snapshot: {0}")]
+    DeletingSnapshot(io::Error),
     #[error("failed to evict cache: {0}")]
     EvictingCache(io::Error),
     #[error("file larger than max of {}: {0}", MAX_QCOW_FILE_SIZE)]
@@ -90,6 +105,8 @@ pub enum Error {
     InvalidRefcountTableOffset,
     #[error("invalid refcount table size: {0}")]
     InvalidRefcountTableSize(u64),
+    #[error("invalid snapshot name: {0:?}")]
+    InvalidSnapshotName(String),
     #[error("no free clusters")]
     NoFreeClusters,
     #[error("no refcount clusters")]
@@ -106,6 +123,10 @@ pub enum Error {
     ReadingRefCountBlock(refcount::Error),
     #[error("failed to read ref counts: {0}")]
     ReadingRefCounts(io::Error),
+    #[error("failed to read snapshot table: {0}")]
+    ReadingSnapshotTable(snapshot::Error),
+    #[error("failed to read source image: {0}")]
+    ReadingSourceImage(io::Error),
     #[error("failed to rebuild ref counts: {0}")]
     RebuildingRefCounts(io::Error),
     #[error("refcount table offset past file end")]
@@ -118,14 +139,26 @@ pub enum Error {
     SettingRefcountRefcount(io::Error),
     #[error("size too small for number of clusters")]
     SizeTooSmallForNumberOfClusters,
+    #[error("snapshot disk size {0} doesn't match the size of the disk")]
+    SnapshotDiskSizeMismatch(u64),
+    #[error("snapshot {0:?} already exists")]
+    SnapshotExists(String),
+    #[error("snapshot {0:?} not found")]
+    SnapshotNotFound(String),
     #[error("l1 entry table too large: {0}")]
     TooManyL1Entries(u64),
     #[error("ref count table too large: {0}")]
     TooManyRefcounts(u64),
+    #[error("too many snapshots")]
+    TooManySnapshots,
+    #[error("unsupported compression type: {0}")]
+    UnsupportedCompressionType(u8),
     #[error("unsupported refcount order")]
     UnsupportedRefcountOrder,
     #[error("unsupported version: {0}")]
     UnsupportedVersion(u32),
+    #[error("failed to write compressed image: {0}")]
+    WritingCompressedImage(io::Error),
     #[error("failed to write header: {0}")]
     WritingHeader(io::Error),
 }
@@ -159,6 +192,18 @@ const L2_TABLE_OFFSET_MASK: u64 = 0x00ff_ffff_ffff_fe00;
 const COMPRESSED_FLAG: u64 = 1 << 62;
 const CLUSTER_USED_FLAG: u64 = 1 << 63;
 const COMPATIBLE_FEATURES_LAZY_REFCOUNTS: u64 = 1 << 0;
+const INCOMPATIBLE_FEATURES_COMPRESSION_TYPE: u64 = 1 << 3;
+
+// Values of the compression type header field.
+const COMPRESSION_TYPE_DEFLATE: u8 = 0;
+const COMPRESSION_TYPE_ZSTD: u8 = 1;
+// Compressed cluster descriptors count the size of the compressed data in 512 byte sectors.
+const COMPRESSED_SECTOR_SIZE: u64 = 512;
+// qemu limits the deflate window to 4k so that its decompressor can stream clusters.
+const DEFLATE_WINDOW_BITS: u8 = 12;
+
+// Offset in the header of the snapshot count, which is followed by the snapshot table offset.
+const NB_SNAPSHOTS_OFFSET: u64 = 60;
 
 // The format supports a "header extension area", that crosvm does not use.
 const QCOW_EMPTY_HEADER_EXTENSION_SIZE: u32 = 8;
@@ -194,11 +239,22 @@ pub struct QcowHeader {
     pub autoclear_features: u64,
     pub refcount_order: u32,
     pub header_size: u32,
+    // Only present if header_size is larger than the v3 bare header.
+    pub compression_type: u8,
 
     // Post-header entries
     pub backing_file_path: Option<String>,
 }
 
+// Reads the next u8 from the file.
+fn read_u8_from_file(mut f: &File) -> Result<u8> {
+    let mut value = [0u8; 1];
+    (&mut f)
+        .read_exact(&mut value)
+        .map_err(Error::ReadingHeader)?;
+    Ok(value[0])
+}
+
 // Reads the next u16 from the file.
 fn read_u16_from_file(mut f: &File) -> Result<u16> {
     let mut value = [0u8; 2];
@@ -255,8 +311,12 @@ impl QcowHeader {
             autoclear_features: read_u64_from_file(f)?,
             refcount_order: read_u32_from_file(f)?,
             header_size: read_u32_from_file(f)?,
+            compression_type: COMPRESSION_TYPE_DEFLATE,
             backing_file_path: None,
         };
+        if header.header_size > V3_BARE_HEADER_SIZE {
+            header.compression_type = read_u8_from_file(f)?;
+        }
         if header.backing_file_size > MAX_BACKING_FILE_SIZE {
             return Err(Error::BackingFileTooLong(header.backing_file_size as usize));
         }
@@ -328,6 +388,7 @@ impl QcowHeader {
             autoclear_features: 0,
             refcount_order: DEFAULT_REFCOUNT_ORDER,
             header_size: V3_BARE_HEADER_SIZE,
+            compression_type: COMPRESSION_TYPE_DEFLATE,
             backing_file_path: backing_file.map(String::from),
         })
     }
@@ -364,6 +425,14 @@ impl QcowHeader {
         write_u64_to_file(file, self.autoclear_features)?;
         write_u32_to_file(file, self.refcount_order)?;
         write_u32_to_file(file, self.header_size)?;
+        if self.header_size > V3_BARE_HEADER_SIZE {
+            // The compression type is followed by padding up to the end of the header.
+            let mut additional_fields =
+                vec![0u8; (self.header_size - V3_BARE_HEADER_SIZE) as usize];
+            additional_fields[0] = self.compression_type;
+            file.write_all(&additional_fields)
+                .map_err(Error::WritingHeader)?;
+        }
         write_u32_to_file(file, 0)?; // header extension type: end of header extension area
         write_u32_to_file(file, 0)?; // length of header extension data: 0
         if let Some(backing_file_path) = self.backing_file_path.as_ref() {
@@ -393,6 +462,185 @@ fn max_refcount_clusters(refcount_order: u32, cluster_size: u32, num_clusters: u
     for_data + for_refcounts
 }
 
+/// The algorithm used to compress clusters of a qcow2 image.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CompressionType {
+    Deflate,
+    Zstd,
+}
+
+impl CompressionType {
+    fn from_header_value(value: u8) -> Result<CompressionType> {
+        match value {
+            COMPRESSION_TYPE_DEFLATE => Ok(CompressionType::Deflate),
+            COMPRESSION_TYPE_ZSTD => Ok(CompressionType::Zstd),
+            v => Err(Error::UnsupportedCompressionType(v)),
+        }
+    }
+
+    fn header_value(self) -> u8 {
+        match self {
+            CompressionType::Deflate => COMPRESSION_TYPE_DEFLATE,
+            CompressionType::Zstd => COMPRESSION_TYPE_ZSTD,
+        }
+    }
+}
+
+impl FromStr for CompressionType {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "deflate" => Ok(CompressionType::Deflate),
+            "zstd" => Ok(CompressionType::Zstd),
+            _ => Err(format!(
+                "invalid compression type '{}', expected 'deflate' or 'zstd'",
+                s
+            )),
+        }
+    }
+}
+
+// Compresses a cluster's worth of `data`. Returns None if compression doesn't make it smaller.
+fn compress_cluster_data(
+    compression_type: CompressionType,
+    data: &[u8],
+) -> io::Result<Option<Vec<u8>>> {
+    match compression_type {
+        CompressionType::Deflate => {
+            // Limiting the output buffer to less than the input size makes deflate stop early
+            // for data that doesn't compress.
+            let mut compressed = Vec::with_capacity(data.len() - 1);
+            let mut compress =
+                Compress::new_with_window_bits(Compression::default(), false, DEFLATE_WINDOW_BITS);
+            let status = compress
+                .compress_vec(data, &mut compressed, FlushCompress::Finish)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            if status != Status::StreamEnd {
+                return Ok(None);
+            }
+            Ok(Some(compressed))
+        }
+        CompressionType::Zstd => {
+            let compressed = zstd::bulk::compress(data, zstd::DEFAULT_COMPRESSION_LEVEL)?;
+            if compressed.len() >= data.len() {
+                return Ok(None);
+            }
+            Ok(Some(compressed))
+        }
+    }
+}
+
+// Decompresses `compressed` into a buffer of `cluster_size` bytes. `compressed` may contain
+// trailing bytes after the end of the compressed stream.
+fn decompress_cluster_data(
+    compression_type: CompressionType,
+    compressed: &[u8],
+    cluster_size: usize,
+) -> io::Result<Vec<u8>> {
+    let mut data = vec![0u8; cluster_size];
+    match compression_type {
+        CompressionType::Deflate => {
+            let mut decompress = Decompress::new(false);
+            decompress
+                .decompress(compressed, &mut data, FlushDecompress::Finish)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            if decompress.total_out() != cluster_size as u64 {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "compressed cluster is truncated",
+                ));
+            }
+        }
+        CompressionType::Zstd => {
+            let mut decoder = zstd::stream::read::Decoder::with_buffer(compressed)?.single_frame();
+            decoder.read_exact(&mut data)?;
+        }
+    }
+    Ok(data)
+}
+
+// Returns the file offset and the maximum length of the data of the compressed cluster described
+// by `l2_entry`. The length is rounded up to the end of the last 512 byte sector.
+fn compressed_cluster_range(l2_entry: u64, cluster_bits: u32) -> (u64, u64) {
+    let size_shift = 62 - (cluster_bits - 8);
+    let size_mask = (0x01u64 << (cluster_bits - 8)) - 1;
+    let offset = l2_entry & ((0x01u64 << size_shift) - 1);
+    let sectors = ((l2_entry >> size_shift) & size_mask) + 1;
+    (
+        offset,
+        sectors * COMPRESSED_SECTOR_SIZE - offset % COMPRESSED_SECTOR_SIZE,
+    )
+}
+
+// Builds the L2 entry for a compressed cluster of `len` bytes stored at file `offset`.
+fn compressed_cluster_descriptor(offset: u64, len: u64, cluster_bits: u32) -> u64 {
+    let size_shift = 62 - (cluster_bits - 8);
+    let additional_sectors =
+        (offset + len - 1) / COMPRESSED_SECTOR_SIZE - offset / COMPRESSED_SECTOR_SIZE;
+    COMPRESSED_FLAG | (additional_sectors << size_shift) | offset
+}
+
+// Returns the addresses of the host clusters holding the data of the compressed cluster described
+// by `l2_entry`. Each compressed cluster holds one reference to each of these clusters.
+fn compressed_host_clusters(l2_entry: u64, cluster_bits: u32) -> impl Iterator<Item = u64> {
+    let (offset, len) = compressed_cluster_range(l2_entry, cluster_bits);
+    let cluster_mask = (0x01u64 << cluster_bits) - 1;
+    let first = offset & !cluster_mask;
+    let last = (offset + len - 1) & !cluster_mask;
+    (first..=last).step_by(0x01usize << cluster_bits)
+}
+
+// Writes an L2 table to `offset` in the file. Standard cluster entries are flagged as used unless
+// `refcounts` is given and shows that the cluster is shared with a snapshot. Compressed cluster
+// descriptors are written unchanged.
+fn write_l2_table(
+    raw_file: &mut QcowRawFile,
+    mut refcounts: Option<&mut RefCount>,
+    offset: u64,
+    table: &[u64],
+) -> io::Result<()> {
+    let mut entries = Vec::with_capacity(table.len());
+    for entry in table {
+        let used = if *entry == 0 || *entry & COMPRESSED_FLAG != 0 {
+            false
+        } else if let Some(refcounts) = refcounts.as_deref_mut() {
+            refcounts
+                .get_cluster_refcount(raw_file, *entry)
+                .map_err(|_| std::io::Error::from_raw_os_error(EINVAL))?
+                == 1
+        } else {
+            true
+        };
+        entries.push(if used {
+            *entry | CLUSTER_USED_FLAG
+        } else {
+            *entry
+        });
+    }
+    raw_file.write_pointer_table(offset, &entries, 0)
+}
+
+// Where the data for a guest cluster is stored in the qcow file.
+enum ClusterLocation {
+    // No data has been written to the cluster in this file.
+    Unallocated,
+    // The cluster is stored uncompressed; holds the file offset of the requested address.
+    Standard(u64),
+    // The cluster is stored compressed; holds the L2 entry describing the compressed data.
+    Compressed(u64),
+}
+
+// The source of the data for a range passed to the `read_cb` callback.
+enum ReadSource<'a> {
+    // Read from the given file at the given offset.
+    File(&'a mut dyn DiskFile, u64),
+    // Copy from a decompressed cluster.
+    Buffer(&'a [u8]),
+    // The range reads as zeros.
+    Zeros,
+}
+
 /// Represents a qcow2 file. This is a sparse file format maintained by the qemu project.
 /// Full documentation of the format can be found in the qemu repository.
 ///
@@ -424,11 +672,44 @@ pub struct QcowFile {
     // removal of references to them have been synced to disk.
     avail_clusters: Vec<u64>,
     backing_file: Option<Box<dyn DiskFile>>,
+    compression_type: CompressionType,
+    // The L2 entry and data of the most recently decompressed cluster.
+    decompressed_cluster: Option<(u64, Vec<u8>)>,
+    // File offset of the free space after the last written compressed cluster, if it shares a
+    // host cluster with other compressed clusters.
+    compressed_tail: Option<u64>,
+    snapshots: Vec<QcowSnapshot>,
 }
 
 impl QcowFile {
     /// Creates a QcowFile from `file`. File must be a valid qcow2 image.
-    pub fn from(mut file: File, max_nesting_depth: u32) -> Result<QcowFile> {
+    pub fn from(file: File, max_nesting_depth: u32) -> Result<QcowFile> {
+        let mut qcow = QcowFile::from_without_backing_file(file)?;
+
+        if let Some(backing_file_path) = qcow.header.backing_file_path.as_ref() {
+            let path = backing_file_path.clone();
+            let backing_raw_file = open_file(
+                Path::new(&path),
+                OpenOptions::new().read(true), // TODO(b/190435784): Add support for O_DIRECT.
+            )
+            .map_err(|e| Error::BackingFileIo(e.into()))?;
+            // is_sparse_file is false because qcow is internally sparse and we don't need file
+            // system sparseness on top of that.
+            let backing_file = create_disk_file(
+                backing_raw_file,
+                /* is_sparse_file= */ false,
+                max_nesting_depth,
+                Path::new(&path),
+            )
+            .map_err(|e| Error::BackingFileOpen(Box::new(e)))?;
+            qcow.backing_file = Some(backing_file);
+        }
+
+        Ok(qcow)
+    }
+
+    // Reads and validates the image in `file`, leaving its backing file unopened.
+    fn from_without_backing_file(mut file: File) -> Result<QcowFile> {
         let header = QcowHeader::new(&mut file)?;
 
         // Only v3 files are supported.
@@ -447,32 +728,18 @@ impl QcowFile {
         }
         let cluster_size = 0x01u64 << cluster_bits;
 
+        let compression_type = CompressionType::from_header_value(header.compression_type)?;
+        if compression_type != CompressionType::Deflate
+            && (header.incompatible_features & INCOMPATIBLE_FEATURES_COMPRESSION_TYPE) == 0
+        {
+            return Err(Error::UnsupportedCompressionType(header.compression_type));
+        }
+
         // Limit the total size of the disk.
         if header.size > MAX_QCOW_FILE_SIZE {
             return Err(Error::FileTooBig(header.size));
         }
 
-        let backing_file = if let Some(backing_file_path) = header.backing_file_path.as_ref() {
-            let path = backing_file_path.clone();
-            let backing_raw_file = open_file(
-                Path::new(&path),
-                OpenOptions::new().read(true), // TODO(b/190435784): Add support for O_DIRECT.
-            )
-            .map_err(|e| Error::BackingFileIo(e.into()))?;
-            // is_sparse_file is false because qcow is internally sparse and we don't need file
-            // system sparseness on top of that.
-            let backing_file = create_disk_file(
-                backing_raw_file,
-                /* is_sparse_file= */ false,
-                max_nesting_depth,
-                Path::new(&path),
-            )
-            .map_err(|e| Error::BackingFileOpen(Box::new(e)))?;
-            Some(backing_file)
-        } else {
-            None
-        };
-
         // Only support two byte refcounts.
         let refcount_bits: u64 = 0x01u64
             .checked_shl(header.refcount_order)
@@ -495,6 +762,20 @@ impl QcowFile {
             return Err(Error::RefcountTableOffEnd);
         }
 
+        let snapshots = snapshot::read_snapshot_table(
+            &mut file,
+            header.snapshots_offset,
+            header.nb_snapshots,
+            header.size,
+        )
+        .map_err(Error::ReadingSnapshotTable)?;
+        for snapshot in &snapshots {
+            offset_is_cluster_boundary(snapshot.l1_table_offset, header.cluster_bits)?;
+            if u64::from(snapshot.l1_size) > MAX_RAM_POINTER_TABLE_SIZE {
+                return Err(Error::InvalidL1TableSize(snapshot.l1_size));
+            }
+        }
+
         // The first cluster should always have a non-zero refcount, so if it is 0,
         // this is an old file with broken refcounts, which requires a rebuild.
         let mut refcount_rebuild_required = true;
@@ -552,10 +833,16 @@ impl QcowFile {
             return Err(Error::TooManyRefcounts(refcount_clusters));
         }
         let refcount_block_entries = cluster_size / refcount_bytes;
+        // Snapshots can grow the file past what is needed for the virtual size, make all of the
+        // space in the refcount table available.
+        let refcount_table_entries = max(
+            refcount_clusters,
+            u64::from(header.refcount_table_clusters) * cluster_size / size_of::<u64>() as u64,
+        );
         let refcounts = RefCount::new(
             &mut raw_file,
             header.refcount_table_offset,
-            refcount_clusters,
+            refcount_table_entries,
             refcount_block_entries,
             cluster_size,
         )
@@ -573,7 +860,11 @@ impl QcowFile {
             current_offset: 0,
             unref_clusters: Vec::new(),
             avail_clusters: Vec::new(),
-            backing_file,
+            backing_file: None,
+            compression_type,
+            decompressed_cluster: None,
+            compressed_tail: None,
+            snapshots,
         };
 
         // Check that the L1 and refcount tables fit in a 64bit address space.
@@ -594,7 +885,14 @@ impl QcowFile {
     /// Creates a new QcowFile at the given path.
     pub fn new(file: File, virtual_size: u64) -> Result<QcowFile> {
         let header = QcowHeader::create_for_size_and_path(virtual_size, None)?;
-        QcowFile::new_from_header(file, header, 1)
+        QcowFile::new_from_header(file, header)
+    }
+
+    /// Creates a new QcowFile that names `backing_file_name` as its backing file without opening
+    /// it. The backing disk must be attached with `set_backing_file` before the file is used.
+    pub fn new_overlay(file: File, virtual_size: u64, backing_file_name: &str) -> Result<QcowFile> {
+        let header = QcowHeader::create_for_size_and_path(virtual_size, Some(backing_file_name))?;
+        QcowFile::new_from_header(file, header)
     }
 
     /// Creates a new QcowFile at the given path.
@@ -619,21 +917,57 @@ impl QcowFile {
         )
         .map_err(|e| Error::BackingFileOpen(Box::new(e)))?;
         let size = backing_file.get_len().map_err(Error::BackingFileIo)?;
-        let header = QcowHeader::create_for_size_and_path(size, Some(backing_file_name))?;
-        let mut result = QcowFile::new_from_header(file, header, backing_file_max_nesting_depth)?;
+        let mut result = QcowFile::new_overlay(file, size, backing_file_name)?;
         result.backing_file = Some(backing_file);
         Ok(result)
     }
 
-    fn new_from_header(
-        mut file: File,
-        header: QcowHeader,
-        max_nesting_depth: u32,
+    /// Creates a new QcowFile holding a compressed copy of the contents of `source`. Clusters that
+    /// read as zeros are left unallocated and clusters that don't shrink when compressed are
+    /// stored uncompressed. The new file doesn't reference any backing file.
+    pub fn new_compressed_from(
+        file: File,
+        source: &mut dyn DiskFile,
+        compression_type: CompressionType,
     ) -> Result<QcowFile> {
+        let size = source.get_len().map_err(Error::ReadingSourceImage)?;
+        let mut header = QcowHeader::create_for_size_and_path(size, None)?;
+        if compression_type != CompressionType::Deflate {
+            header.incompatible_features |= INCOMPATIBLE_FEATURES_COMPRESSION_TYPE;
+            header.compression_type = compression_type.header_value();
+            // The compression type field is padded to keep the header a multiple of 8 bytes.
+            header.header_size = V3_BARE_HEADER_SIZE + 8;
+        }
+        let mut qcow = QcowFile::new_from_header(file, header)?;
+
+        let cluster_size = qcow.raw_file.cluster_size();
+        let mut cluster_data = vec![0u8; cluster_size as usize];
+        let mut address = 0;
+        while address < size {
+            // The last cluster may extend past the end of the source, pad it with zeros.
+            let count = min(cluster_size, size - address) as usize;
+            cluster_data.fill(0);
+            source
+                .read_exact_at_volatile(VolatileSlice::new(&mut cluster_data[..count]), address)
+                .map_err(Error::ReadingSourceImage)?;
+            if cluster_data.iter().any(|b| *b != 0) {
+                qcow.write_compressed_cluster(address, &cluster_data)
+                    .map_err(Error::WritingCompressedImage)?;
+            }
+            address += cluster_size;
+        }
+        qcow.fsync().map_err(Error::WritingCompressedImage)?;
+
+        Ok(qcow)
+    }
+
+    // Writes `header` to `file` and opens the result. Any backing file named in `header` is left
+    // unopened.
+    fn new_from_header(mut file: File, header: QcowHeader) -> Result<QcowFile> {
         file.seek(SeekFrom::Start(0)).map_err(Error::SeekingFile)?;
         header.write_to(&mut file)?;
 
-        let mut qcow = Self::from(file, max_nesting_depth)?;
+        let mut qcow = Self::from_without_backing_file(file)?;
 
         // Set the refcount for each refcount table cluster.
         let cluster_size = 0x01u64 << qcow.header.cluster_bits;
@@ -657,6 +991,271 @@ impl QcowFile {
         self.backing_file = backing;
     }
 
+    /// Returns the internal snapshots stored in the image.
+    pub fn snapshots(&self) -> &[QcowSnapshot] {
+        &self.snapshots
+    }
+
+    /// Saves the current contents of the disk as an internal snapshot named `name`. The snapshot
+    /// shares all of its clusters with the disk, clusters are copied when the disk modifies them.
+    pub fn create_snapshot(&mut self, name: &str) -> Result<()> {
+        if name.is_empty() || name.len() > u16::MAX as usize {
+            return Err(Error::InvalidSnapshotName(name.to_string()));
+        }
+        if self.find_snapshot(name).is_some() {
+            return Err(Error::SnapshotExists(name.to_string()));
+        }
+        if self.snapshots.len() >= snapshot::MAX_SNAPSHOTS {
+            return Err(Error::TooManySnapshots);
+        }
+        // Like qemu, number snapshots in the order they are created.
+        let id = self
+            .snapshots
+            .iter()
+            .filter_map(|snapshot| snapshot.id.parse::<u64>().ok())
+            .max()
+            .unwrap_or(0)
+            + 1;
+        self.add_snapshot(id.to_string(), name.to_string())
+            .map_err(Error::CreatingSnapshot)
+    }
+
+    /// Reverts the contents of the disk to those saved in the snapshot with the given name or ID.
+    /// The snapshot is kept and can be applied again.
+    pub fn apply_snapshot(&mut self, name: &str) -> Result<()> {
+        let index = self
+            .find_snapshot(name)
+            .ok_or_else(|| Error::SnapshotNotFound(name.to_string()))?;
+        let snapshot = self.snapshots[index].clone();
+        if snapshot.disk_size != self.virtual_size() {
+            return Err(Error::SnapshotDiskSizeMismatch(snapshot.disk_size));
+        }
+        self.replace_l1_table(&snapshot)
+            .map_err(Error::ApplyingSnapshot)
+    }
+
+    /// Deletes the snapshot with the given name or ID, freeing the clusters only it references.
+    pub fn delete_snapshot(&mut self, name: &str) -> Result<()> {
+        let index = self
+            .find_snapshot(name)
+            .ok_or_else(|| Error::SnapshotNotFound(name.to_string()))?;
+        self.remove_snapshot(index).map_err(Error::DeletingSnapshot)
+    }
+
+    // Returns the index of the snapshot with the given name, or failing that, ID.
+    fn find_snapshot(&self, name: &str) -> Option<usize> {
+        self.snapshots
+            .iter()
+            .position(|snapshot| snapshot.name == name)
+            .or_else(|| {
+                self.snapshots
+                    .iter()
+                    .position(|snapshot| snapshot.id == name)
+            })
+    }
+
+    // Copies the active L1 table and adds a snapshot referencing the copy.
+    fn add_snapshot(&mut self, id: String, name: String) -> std::io::Result<()> {
+        // Write out all cached tables so that the L2 tables referenced from the copy are current.
+        self.sync_caches()?;
+
+        let l1_table = self.l1_table.get_values().to_vec();
+        let l1_table_offset =
+            self.append_table_clusters((l1_table.len() * size_of::<u64>()) as u64)?;
+        self.raw_file
+            .write_pointer_table(l1_table_offset, &l1_table, 0)?;
+        self.update_l1_refcounts(&l1_table, 1)?;
+        // The clusters are now shared, make sure they aren't flagged as used by the active table.
+        self.write_l2_flags(&l1_table)?;
+
+        let date = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let mut snapshots = self.snapshots.clone();
+        snapshots.push(QcowSnapshot::new(
+            id,
+            name,
+            date.as_secs() as u32,
+            date.subsec_nanos(),
+            self.virtual_size(),
+            l1_table_offset,
+            l1_table.len() as u32,
+        ));
+        self.write_snapshot_table(snapshots)
+    }
+
+    // Replaces the active L1 table with the L1 table of `snapshot`.
+    fn replace_l1_table(&mut self, snapshot: &QcowSnapshot) -> std::io::Result<()> {
+        self.sync_caches()?;
+
+        let mut l1_table = self.raw_file.read_pointer_table(
+            snapshot.l1_table_offset,
+            u64::from(snapshot.l1_size),
+            Some(L1_TABLE_OFFSET_MASK),
+        )?;
+        l1_table.resize(self.l1_table.len(), 0);
+        // Take the new references before dropping the old ones so that clusters referenced by
+        // both tables are never freed. All the tables are shared with the snapshot.
+        self.update_l1_refcounts(&l1_table, 1)?;
+        self.write_l2_flags(&l1_table)?;
+        self.sync_caches()?;
+
+        let old_l1_table = self.l1_table.get_values().to_vec();
+        self.raw_file
+            .write_pointer_table(self.header.l1_table_offset, &l1_table, 0)?;
+        self.raw_file.file_mut().sync_data()?;
+        // The cached tables were all written by the sync above and belong to the old L1 table.
+        self.l2_cache.clear();
+        self.l1_table = VecCache::from_vec(l1_table);
+        self.decompressed_cluster = None;
+        self.compressed_tail = None;
+
+        self.update_l1_refcounts(&old_l1_table, -1)?;
+        self.sync_caches()
+    }
+
+    // Removes the snapshot at `index` and drops its references.
+    fn remove_snapshot(&mut self, index: usize) -> std::io::Result<()> {
+        self.sync_caches()?;
+
+        let snapshot = self.snapshots[index].clone();
+        let l1_table = self.raw_file.read_pointer_table(
+            snapshot.l1_table_offset,
+            u64::from(snapshot.l1_size),
+            Some(L1_TABLE_OFFSET_MASK),
+        )?;
+        let mut snapshots = self.snapshots.clone();
+        snapshots.remove(index);
+        self.write_snapshot_table(snapshots)?;
+
+        // The snapshot is no longer reachable from the header, free what only it used.
+        self.update_l1_refcounts(&l1_table, -1)?;
+        self.free_table_clusters(
+            snapshot.l1_table_offset,
+            u64::from(snapshot.l1_size) * size_of::<u64>() as u64,
+        )?;
+        // Clusters that were only shared with this snapshot are now used by the active table alone.
+        let active_l1_table = self.l1_table.get_values().to_vec();
+        self.write_l2_flags(&active_l1_table)?;
+        self.decompressed_cluster = None;
+        self.compressed_tail = None;
+        self.sync_caches()
+    }
+
+    // Adds `addend` to the refcounts of the L2 tables referenced by `l1_table` and of the clusters
+    // referenced by those tables. This takes or drops the references held by an L1 table.
+    fn update_l1_refcounts(&mut self, l1_table: &[u64], addend: i32) -> std::io::Result<()> {
+        for &l2_addr in l1_table.iter().filter(|addr| **addr != 0) {
+            for l2_entry in Self::read_l2_cluster(&mut self.raw_file, l2_addr)? {
+                if l2_entry & COMPRESSED_FLAG != 0 {
+                    for host_cluster in compressed_host_clusters(l2_entry, self.header.cluster_bits)
+                    {
+                        self.add_cluster_refcount(host_cluster, addend)?;
+                    }
+                } else if l2_entry != 0 {
+                    self.add_cluster_refcount(l2_entry, addend)?;
+                }
+            }
+            self.add_cluster_refcount(l2_addr, addend)?;
+        }
+        Ok(())
+    }
+
+    // Rewrites the L2 tables referenced by `l1_table` so their used flags match the refcounts.
+    fn write_l2_flags(&mut self, l1_table: &[u64]) -> std::io::Result<()> {
+        for &l2_addr in l1_table.iter().filter(|addr| **addr != 0) {
+            let l2_table = Self::read_l2_cluster(&mut self.raw_file, l2_addr)?;
+            write_l2_table(
+                &mut self.raw_file,
+                Some(&mut self.refcounts),
+                l2_addr,
+                &l2_table,
+            )?;
+        }
+        Ok(())
+    }
+
+    // Adds `addend` to the refcount of the cluster at `address`.
+    fn add_cluster_refcount(&mut self, address: u64, addend: i32) -> std::io::Result<()> {
+        let refcount = i32::from(self.cluster_refcount(address)?) + addend;
+        let refcount =
+            u16::try_from(refcount).map_err(|_| std::io::Error::from_raw_os_error(EINVAL))?;
+        let mut newly_unref = self.set_cluster_refcount(address, refcount)?;
+        self.unref_clusters.append(&mut newly_unref);
+        if refcount == 0 {
+            self.unref_clusters.push(address);
+        }
+        Ok(())
+    }
+
+    // Allocates contiguous clusters at the end of the file to hold a table of `size` bytes and
+    // returns the offset of the first one.
+    fn append_table_clusters(&mut self, size: u64) -> std::io::Result<u64> {
+        let cluster_size = self.raw_file.cluster_size();
+        let max_valid_cluster_offset = self.refcounts.max_valid_cluster_offset();
+        let mut clusters = Vec::new();
+        for _ in 0..max(div_round_up_u64(size, cluster_size), 1) {
+            match self.raw_file.add_cluster_end(max_valid_cluster_offset)? {
+                Some(cluster) => clusters.push(cluster),
+                None => {
+                    error!("No free clusters in append_table_clusters()");
+                    return Err(std::io::Error::from_raw_os_error(ENOSPC));
+                }
+            }
+        }
+        // Setting refcounts can allocate clusters, only do it once the table has been allocated.
+        for cluster in &clusters {
+            let mut newly_unref = self.set_cluster_refcount(*cluster, 1)?;
+            self.unref_clusters.append(&mut newly_unref);
+        }
+        Ok(clusters[0])
+    }
+
+    // Drops the references to the clusters holding the table of `size` bytes at `offset`.
+    fn free_table_clusters(&mut self, offset: u64, size: u64) -> std::io::Result<()> {
+        let cluster_size = self.raw_file.cluster_size();
+        for i in 0..div_round_up_u64(size, cluster_size) {
+            self.add_cluster_refcount(offset + i * cluster_size, -1)?;
+        }
+        Ok(())
+    }
+
+    // Writes `snapshots` to new clusters as the snapshot table, points the header at it and then
+    // frees the clusters of the previous table.
+    fn write_snapshot_table(&mut self, snapshots: Vec<QcowSnapshot>) -> std::io::Result<()> {
+        let old_offset = self.header.snapshots_offset;
+        let old_size = snapshot::snapshot_table_bytes(&self.snapshots).len() as u64;
+
+        let mut table = snapshot::snapshot_table_bytes(&snapshots);
+        let offset = if table.is_empty() {
+            0
+        } else {
+            let offset = self.append_table_clusters(table.len() as u64)?;
+            self.raw_file
+                .file_mut()
+                .write_all_at_volatile(VolatileSlice::new(&mut table), offset)?;
+            offset
+        };
+        // Everything the table references must be on disk before the header points to it.
+        self.sync_caches()?;
+
+        let mut header_fields = Vec::new();
+        header_fields.extend_from_slice(&(snapshots.len() as u32).to_be_bytes());
+        header_fields.extend_from_slice(&offset.to_be_bytes());
+        self.raw_file
+            .file_mut()
+            .write_all_at_volatile(VolatileSlice::new(&mut header_fields), NB_SNAPSHOTS_OFFSET)?;
+        self.raw_file.file_mut().sync_data()?;
+        self.header.nb_snapshots = snapshots.len() as u32;
+        self.header.snapshots_offset = offset;
+        self.snapshots = snapshots;
+
+        if old_offset != 0 {
+            self.free_table_clusters(old_offset, old_size)?;
+        }
+        Ok(())
+    }
+
     /// Returns the first cluster in the file with a 0 refcount. Used for testing.
     pub fn first_zero_refcount(&mut self) -> Result<Option<u64>> {
         let file_size = self
@@ -734,21 +1333,20 @@ impl QcowFile {
             Ok(())
         }
 
-        // Traverse the L1 and L2 tables to find all reachable data clusters.
+        // Traverse the L1 table at `l1_table_offset` and its L2 tables to find all reachable data
+        // clusters.
         fn set_data_refcounts(
             refcounts: &mut [u16],
-            header: QcowHeader,
+            l1_table_offset: u64,
+            l1_size: u32,
+            cluster_bits: u32,
             cluster_size: u64,
             raw_file: &mut QcowRawFile,
         ) -> Result<()> {
             let l1_table = raw_file
-                .read_pointer_table(
-                    header.l1_table_offset,
-                    header.l1_size as u64,
-                    Some(L1_TABLE_OFFSET_MASK),
-                )
+                .read_pointer_table(l1_table_offset, l1_size as u64, Some(L1_TABLE_OFFSET_MASK))
                 .map_err(Error::ReadingPointers)?;
-            for l1_index in 0..header.l1_size as usize {
+            for l1_index in 0..l1_size as usize {
                 let l2_addr_disk = *l1_table.get(l1_index).ok_or(Error::InvalidIndex)?;
                 if l2_addr_disk != 0 {
                     // Add a reference to the L2 table cluster itself.
@@ -759,12 +1357,16 @@ impl QcowFile {
                         .read_pointer_table(
                             l2_addr_disk,
                             cluster_size / size_of::<u64>() as u64,
-                            Some(L2_TABLE_OFFSET_MASK),
+                            None,
                         )
                         .map_err(Error::ReadingPointers)?;
-                    for data_cluster_addr in l2_table {
-                        if data_cluster_addr != 0 {
-                            add_ref(refcounts, cluster_size, data_cluster_addr)?;
+                    for l2_entry in l2_table {
+                        if l2_entry & COMPRESSED_FLAG != 0 {
+                            for host_cluster in compressed_host_clusters(l2_entry, cluster_bits) {
+                                add_ref(refcounts, cluster_size, host_cluster)?;
+                            }
+                        } else if l2_entry & L2_TABLE_OFFSET_MASK != 0 {
+                            add_ref(refcounts, cluster_size, l2_entry & L2_TABLE_OFFSET_MASK)?;
                         }
                     }
                 }
@@ -773,6 +1375,44 @@ impl QcowFile {
             Ok(())
         }
 
+        // Add references to the snapshot table, the L1 tables of the snapshots and the clusters
+        // they reference.
+        fn set_snapshot_refcounts(
+            refcounts: &mut [u16],
+            header: QcowHeader,
+            snapshots: &[QcowSnapshot],
+            cluster_size: u64,
+            raw_file: &mut QcowRawFile,
+        ) -> Result<()> {
+            let table_size = snapshot::snapshot_table_bytes(snapshots).len() as u64;
+            for i in 0..div_round_up_u64(table_size, cluster_size) {
+                add_ref(
+                    refcounts,
+                    cluster_size,
+                    header.snapshots_offset + i * cluster_size,
+                )?;
+            }
+            for snapshot in snapshots {
+                let l1_table_size = u64::from(snapshot.l1_size) * size_of::<u64>() as u64;
+                for i in 0..div_round_up_u64(l1_table_size, cluster_size) {
+                    add_ref(
+                        refcounts,
+                        cluster_size,
+                        snapshot.l1_table_offset + i * cluster_size,
+                    )?;
+                }
+                set_data_refcounts(
+                    refcounts,
+                    snapshot.l1_table_offset,
+                    snapshot.l1_size,
+                    header.cluster_bits,
+                    cluster_size,
+                    raw_file,
+                )?;
+            }
+            Ok(())
+        }
+
         // Add references to the top-level refcount table clusters.
         fn set_refcount_table_refcounts(
             refcounts: &mut [u16],
@@ -895,7 +1535,22 @@ impl QcowFile {
         let l2_clusters = div_round_up_u64(data_clusters, pointers_per_cluster);
         let l1_clusters = div_round_up_u64(l2_clusters, cluster_size);
         let header_clusters = div_round_up_u64(size_of::<QcowHeader>() as u64, cluster_size);
-        let max_clusters = data_clusters + l2_clusters + l1_clusters + header_clusters;
+        let snapshots = snapshot::read_snapshot_table(
+            raw_file.file_mut(),
+            header.snapshots_offset,
+            header.nb_snapshots,
+            header.size,
+        )
+        .map_err(Error::ReadingSnapshotTable)?;
+        let snapshot_table_clusters = div_round_up_u64(
+            snapshot::snapshot_table_bytes(&snapshots).len() as u64,
+            cluster_size,
+        );
+        // Each snapshot can hold its own copy of all of the data and tables.
+        let copies = 1 + snapshots.len() as u64;
+        let max_clusters = (data_clusters + l2_clusters + l1_clusters) * copies
+            + header_clusters
+            + snapshot_table_clusters;
         let mut max_valid_cluster_index = max_clusters;
         let refblock_clusters = div_round_up_u64(max_valid_cluster_index, refcount_block_entries);
         let reftable_clusters = div_round_up_u64(refblock_clusters, pointers_per_cluster);
@@ -923,7 +1578,21 @@ impl QcowFile {
         // Find all references clusters and rebuild refcounts.
         set_header_refcount(&mut refcounts, cluster_size)?;
         set_l1_refcounts(&mut refcounts, header.clone(), cluster_size)?;
-        set_data_refcounts(&mut refcounts, header.clone(), cluster_size, raw_file)?;
+        set_data_refcounts(
+            &mut refcounts,
+            header.l1_table_offset,
+            header.l1_size,
+            header.cluster_bits,
+            cluster_size,
+            raw_file,
+        )?;
+        set_snapshot_refcounts(
+            &mut refcounts,
+            header.clone(),
+            &snapshots,
+            cluster_size,
+            raw_file,
+        )?;
         set_refcount_table_refcounts(&mut refcounts, header.clone(), cluster_size)?;
 
         // Allocate clusters to store the new reference count blocks.
@@ -980,9 +1649,20 @@ impl QcowFile {
         (address / self.raw_file.cluster_size()) % self.l2_entries
     }
 
-    // Gets the offset of the given guest address in the host file. If L1, L2, or data clusters have
-    // yet to be allocated, return None.
-    fn file_offset_read(&mut self, address: u64) -> std::io::Result<Option<u64>> {
+    // Adds the L2 table for `l1_index` to the cache, writing out an evicted table if needed.
+    fn cache_l2_table(&mut self, l1_index: usize, table: VecCache<u64>) -> std::io::Result<()> {
+        let l1_table = &self.l1_table;
+        let raw_file = &mut self.raw_file;
+        // Clusters can only be shared while the image has snapshots.
+        let refcounts = (!self.snapshots.is_empty()).then_some(&mut self.refcounts);
+        self.l2_cache.insert(l1_index, table, |index, evicted| {
+            write_l2_table(raw_file, refcounts, l1_table[index], evicted.get_values())
+        })
+    }
+
+    // Gets the location of the given guest address in the host file. If L1, L2, or data clusters
+    // have yet to be allocated, return `ClusterLocation::Unallocated`.
+    fn cluster_location(&mut self, address: u64) -> std::io::Result<ClusterLocation> {
         if address >= self.virtual_size() as u64 {
             return Err(std::io::Error::from_raw_os_error(EINVAL));
         }
@@ -995,7 +1675,7 @@ impl QcowFile {
 
         if l2_addr_disk == 0 {
             // Reading from an unallocated cluster will return zeros.
-            return Ok(None);
+            return Ok(ClusterLocation::Unallocated);
         }
 
         let l2_index = self.l2_table_index(address) as usize;
@@ -1005,27 +1685,89 @@ impl QcowFile {
             let table =
                 VecCache::from_vec(Self::read_l2_cluster(&mut self.raw_file, l2_addr_disk)?);
 
-            let l1_table = &self.l1_table;
-            let raw_file = &mut self.raw_file;
-            self.l2_cache.insert(l1_index, table, |index, evicted| {
-                raw_file.write_pointer_table(
-                    l1_table[index],
-                    evicted.get_values(),
-                    CLUSTER_USED_FLAG,
-                )
-            })?;
+            self.cache_l2_table(l1_index, table)?;
         };
 
         let cluster_addr = self.l2_cache.get(&l1_index).unwrap()[l2_index];
         if cluster_addr == 0 {
-            return Ok(None);
+            return Ok(ClusterLocation::Unallocated);
         }
-        Ok(Some(cluster_addr + self.raw_file.cluster_offset(address)))
+        if cluster_addr & COMPRESSED_FLAG != 0 {
+            return Ok(ClusterLocation::Compressed(cluster_addr));
+        }
+        Ok(ClusterLocation::Standard(
+            cluster_addr + self.raw_file.cluster_offset(address),
+        ))
     }
 
     // Gets the offset of the given guest address in the host file. If L1, L2, or data clusters need
-    // to be allocated, they will be.
+    // to be allocated, they will be. Compressed clusters are copied to a newly allocated cluster.
     fn file_offset_write(&mut self, address: u64) -> std::io::Result<u64> {
+        let mut set_refcounts = Vec::new();
+        let (l1_index, l2_index) = self.cache_l2_table_for_write(address, &mut set_refcounts)?;
+
+        let virtual_size = self.virtual_size();
+        let cluster_addr = match self.l2_cache.get(&l1_index).unwrap()[l2_index] {
+            0 => {
+                let initial_data = if let Some(backing) = self.backing_file.as_mut() {
+                    let cluster_size = self.raw_file.cluster_size();
+                    let cluster_begin = address - (address % cluster_size);
+                    let mut cluster_data = vec![0u8; cluster_size as usize];
+                    // The last cluster may extend past the end of the disk, only read the part of
+                    // it that exists in the backing file.
+                    let count = min(cluster_size, virtual_size - cluster_begin) as usize;
+                    let volatile_slice = VolatileSlice::new(&mut cluster_data[..count]);
+                    backing.read_exact_at_volatile(volatile_slice, cluster_begin)?;
+                    Some(cluster_data)
+                } else {
+                    None
+                };
+                // Need to allocate a data cluster
+                let cluster_addr = self.append_data_cluster(initial_data)?;
+                self.update_cluster_addr(l1_index, l2_index, cluster_addr, &mut set_refcounts)?;
+                cluster_addr
+            }
+            l2_entry if l2_entry & COMPRESSED_FLAG != 0 => {
+                let initial_data = self.decompress_cluster(l2_entry)?.to_vec();
+                let cluster_addr = self.append_data_cluster(Some(initial_data))?;
+                self.update_cluster_addr(l1_index, l2_index, cluster_addr, &mut set_refcounts)?;
+                self.unref_compressed_cluster(l2_entry)?;
+                cluster_addr
+            }
+            a if self.snapshots.is_empty() => a,
+            a => {
+                // Clusters shared with a snapshot are copied before they are modified.
+                let refcount = self.cluster_refcount(a)?;
+                if refcount > 1 {
+                    let mut initial_data = vec![0u8; self.raw_file.cluster_size() as usize];
+                    self.raw_file
+                        .file_mut()
+                        .read_exact_at_volatile(VolatileSlice::new(&mut initial_data), a)?;
+                    let cluster_addr = self.append_data_cluster(Some(initial_data))?;
+                    self.update_cluster_addr(l1_index, l2_index, cluster_addr, &mut set_refcounts)?;
+                    set_refcounts.push((a, refcount - 1));
+                    cluster_addr
+                } else {
+                    a
+                }
+            }
+        };
+
+        for (addr, count) in set_refcounts {
+            let mut newly_unref = self.set_cluster_refcount(addr, count)?;
+            self.unref_clusters.append(&mut newly_unref);
+        }
+
+        Ok(cluster_addr + self.raw_file.cluster_offset(address))
+    }
+
+    // Makes sure the L2 table covering the given guest address is in the cache, allocating a new
+    // table if there isn't one yet. Returns the L1 and L2 indices of the address.
+    fn cache_l2_table_for_write(
+        &mut self,
+        address: u64,
+        set_refcounts: &mut Vec<(u64, u16)>,
+    ) -> std::io::Result<(usize, usize)> {
         if address >= self.virtual_size() as u64 {
             return Err(std::io::Error::from_raw_os_error(EINVAL));
         }
@@ -1037,8 +1779,6 @@ impl QcowFile {
             .ok_or_else(|| std::io::Error::from_raw_os_error(EINVAL))?;
         let l2_index = self.l2_table_index(address) as usize;
 
-        let mut set_refcounts = Vec::new();
-
         if !self.l2_cache.contains_key(&l1_index) {
             // Not in the cache.
             let l2_table = if l2_addr_disk == 0 {
@@ -1052,43 +1792,110 @@ impl QcowFile {
             } else {
                 VecCache::from_vec(Self::read_l2_cluster(&mut self.raw_file, l2_addr_disk)?)
             };
-            let l1_table = &self.l1_table;
-            let raw_file = &mut self.raw_file;
-            self.l2_cache.insert(l1_index, l2_table, |index, evicted| {
-                raw_file.write_pointer_table(
-                    l1_table[index],
-                    evicted.get_values(),
-                    CLUSTER_USED_FLAG,
-                )
-            })?;
+            self.cache_l2_table(l1_index, l2_table)?;
         }
 
-        let cluster_addr = match self.l2_cache.get(&l1_index).unwrap()[l2_index] {
-            0 => {
-                let initial_data = if let Some(backing) = self.backing_file.as_mut() {
-                    let cluster_size = self.raw_file.cluster_size();
-                    let cluster_begin = address - (address % cluster_size);
-                    let mut cluster_data = vec![0u8; cluster_size as usize];
-                    let volatile_slice = VolatileSlice::new(&mut cluster_data);
-                    backing.read_exact_at_volatile(volatile_slice, cluster_begin)?;
-                    Some(cluster_data)
-                } else {
-                    None
-                };
-                // Need to allocate a data cluster
-                let cluster_addr = self.append_data_cluster(initial_data)?;
-                self.update_cluster_addr(l1_index, l2_index, cluster_addr, &mut set_refcounts)?;
-                cluster_addr
+        Ok((l1_index, l2_index))
+    }
+
+    // Writes the full cluster `data` to the unallocated guest cluster at `address`. The data is
+    // compressed if that makes it smaller, otherwise it is written as a standard cluster.
+    fn write_compressed_cluster(&mut self, address: u64, data: &[u8]) -> std::io::Result<()> {
+        let mut compressed = match compress_cluster_data(self.compression_type, data)? {
+            Some(compressed) => compressed,
+            None => {
+                let count = self.limit_range_file(address, data.len());
+                let mut data = data[..count].to_vec();
+                return self.write_all_at_volatile(VolatileSlice::new(&mut data), address);
+            }
+        };
+
+        let mut set_refcounts = Vec::new();
+        let (l1_index, l2_index) = self.cache_l2_table_for_write(address, &mut set_refcounts)?;
+        if self.l2_cache.get(&l1_index).unwrap()[l2_index] != 0 {
+            return Err(std::io::Error::from_raw_os_error(EINVAL));
+        }
+
+        // Pack compressed clusters together, starting a new host cluster when the data doesn't
+        // fit in the remaining space of the current one.
+        let cluster_size = self.raw_file.cluster_size();
+        let len = compressed.len() as u64;
+        let data_offset = match self.compressed_tail {
+            Some(tail) if self.raw_file.cluster_offset(tail) + len <= cluster_size => {
+                let host_cluster = tail - self.raw_file.cluster_offset(tail);
+                let refcount = self
+                    .refcounts
+                    .get_cluster_refcount(&mut self.raw_file, host_cluster)
+                    .map_err(|_| std::io::Error::from_raw_os_error(EINVAL))?;
+                set_refcounts.push((host_cluster, refcount + 1));
+                tail
             }
-            a => a,
+            _ => self.append_data_cluster(None)?,
         };
+        self.raw_file
+            .file_mut()
+            .write_all_at_volatile(VolatileSlice::new(&mut compressed), data_offset)?;
+        self.compressed_tail =
+            Some(data_offset + len).filter(|tail| self.raw_file.cluster_offset(*tail) != 0);
+
+        let l2_entry = compressed_cluster_descriptor(data_offset, len, self.header.cluster_bits);
+        self.update_cluster_addr(l1_index, l2_index, l2_entry, &mut set_refcounts)?;
 
         for (addr, count) in set_refcounts {
             let mut newly_unref = self.set_cluster_refcount(addr, count)?;
             self.unref_clusters.append(&mut newly_unref);
         }
+        Ok(())
+    }
 
-        Ok(cluster_addr + self.raw_file.cluster_offset(address))
+    // Reads and decompresses the compressed cluster described by `l2_entry`. The last decompressed
+    // cluster is kept so that sequential reads within it don't decompress it again.
+    fn decompress_cluster(&mut self, l2_entry: u64) -> std::io::Result<&[u8]> {
+        if self.decompressed_cluster.as_ref().map(|(entry, _)| *entry) != Some(l2_entry) {
+            let (offset, len) = compressed_cluster_range(l2_entry, self.header.cluster_bits);
+            let file_size = self.raw_file.file().metadata()?.len();
+            // The data of the last compressed cluster may end before the end of its last sector.
+            let len = min(len, file_size.saturating_sub(offset));
+            let mut compressed = vec![0u8; len as usize];
+            self.raw_file
+                .file_mut()
+                .read_exact_at_volatile(VolatileSlice::new(&mut compressed), offset)?;
+            let data = decompress_cluster_data(
+                self.compression_type,
+                &compressed,
+                self.raw_file.cluster_size() as usize,
+            )?;
+            self.decompressed_cluster = Some((l2_entry, data));
+        }
+        // The cache was filled above if it didn't already hold this cluster.
+        Ok(&self.decompressed_cluster.as_ref().unwrap().1)
+    }
+
+    // Drops the references the compressed cluster described by `l2_entry` holds on the host
+    // clusters containing its data, freeing any that are no longer used.
+    fn unref_compressed_cluster(&mut self, l2_entry: u64) -> std::io::Result<()> {
+        let cluster_mask = self.raw_file.cluster_size() - 1;
+        for host_cluster in compressed_host_clusters(l2_entry, self.header.cluster_bits) {
+            let refcount = self
+                .refcounts
+                .get_cluster_refcount(&mut self.raw_file, host_cluster)
+                .map_err(|_| std::io::Error::from_raw_os_error(EINVAL))?;
+            if refcount == 0 {
+                return Err(std::io::Error::from_raw_os_error(EINVAL));
+            }
+            let mut newly_unref = self.set_cluster_refcount(host_cluster, refcount - 1)?;
+            self.unref_clusters.append(&mut newly_unref);
+            if refcount == 1 {
+                self.unref_clusters.push(host_cluster);
+                if self.compressed_tail.map(|tail| tail & !cluster_mask) == Some(host_cluster) {
+                    self.compressed_tail = None;
+                }
+            }
+        }
+        if self.decompressed_cluster.as_ref().map(|(entry, _)| *entry) == Some(l2_entry) {
+            self.decompressed_cluster = None;
+        }
+        Ok(())
     }
 
     // Updates the l1 and l2 tables to point to the new `cluster_addr`.
@@ -1106,8 +1913,12 @@ impl QcowFile {
             // The index must be valid from when it was insterted.
             let addr = self.l1_table[l1_index];
             if addr != 0 {
-                self.unref_clusters.push(addr);
-                set_refcounts.push((addr, 0));
+                // A table shared with a snapshot is kept for the snapshot.
+                let refcount = self.cluster_refcount(addr)?.saturating_sub(1);
+                if refcount == 0 {
+                    self.unref_clusters.push(addr);
+                }
+                set_refcounts.push((addr, refcount));
             }
 
             // Allocate a new cluster to store the L2 table and update the L1 table to point
@@ -1124,6 +1935,33 @@ impl QcowFile {
         Ok(())
     }
 
+    // Removes the mapping of the cluster at `l2_index` from the cached L2 table for `l1_index`. A
+    // table shared with a snapshot is moved to a new cluster first so the snapshot is unchanged.
+    fn clear_cluster_addr(&mut self, l1_index: usize, l2_index: usize) -> io::Result<()> {
+        // unwrap is safe as the caller made sure the table is in the cache.
+        let shared = !self.snapshots.is_empty()
+            && !self.l2_cache.get(&l1_index).unwrap().dirty()
+            && self.cluster_refcount(self.l1_table[l1_index])? > 1;
+        if shared {
+            let mut set_refcounts = Vec::new();
+            self.update_cluster_addr(l1_index, l2_index, 0, &mut set_refcounts)?;
+            for (addr, count) in set_refcounts {
+                let mut newly_unref = self.set_cluster_refcount(addr, count)?;
+                self.unref_clusters.append(&mut newly_unref);
+            }
+        } else {
+            self.l2_cache.get_mut(&l1_index).unwrap()[l2_index] = 0;
+        }
+        Ok(())
+    }
+
+    // Gets the refcount of the cluster at `address`.
+    fn cluster_refcount(&mut self, address: u64) -> io::Result<u16> {
+        self.refcounts
+            .get_cluster_refcount(&mut self.raw_file, address)
+            .map_err(|_| std::io::Error::from_raw_os_error(EINVAL))
+    }
+
     // Allocate a new cluster and return its offset within the raw file.
     fn get_new_cluster(&mut self, initial_data: Option<Vec<u8>>) -> std::io::Result<u64> {
         // First use a pre allocated cluster if one is available.
@@ -1182,15 +2020,7 @@ impl QcowFile {
             // Not in the cache.
             let table =
                 VecCache::from_vec(Self::read_l2_cluster(&mut self.raw_file, l2_addr_disk)?);
-            let l1_table = &self.l1_table;
-            let raw_file = &mut self.raw_file;
-            self.l2_cache.insert(l1_index, table, |index, evicted| {
-                raw_file.write_pointer_table(
-                    l1_table[index],
-                    evicted.get_values(),
-                    CLUSTER_USED_FLAG,
-                )
-            })?;
+            self.cache_l2_table(l1_index, table)?;
         }
 
         let cluster_addr = self.l2_cache.get(&l1_index).unwrap()[l2_index];
@@ -1199,6 +2029,12 @@ impl QcowFile {
             return Ok(());
         }
 
+        if cluster_addr & COMPRESSED_FLAG != 0 {
+            self.unref_compressed_cluster(cluster_addr)?;
+            self.clear_cluster_addr(l1_index, l2_index)?;
+            return Ok(());
+        }
+
         // Decrement the refcount.
         let refcount = self
             .refcounts
@@ -1213,8 +2049,7 @@ impl QcowFile {
         self.unref_clusters.append(&mut newly_unref);
 
         // Rewrite the L2 entry to remove the cluster mapping.
-        // unwrap is safe as we just checked/inserted this entry.
-        self.l2_cache.get_mut(&l1_index).unwrap()[l2_index] = 0;
+        self.clear_cluster_addr(l1_index, l2_index)?;
 
         if new_refcount == 0 {
             let cluster_size = self.raw_file.cluster_size();
@@ -1252,9 +2087,17 @@ impl QcowFile {
                     // show through.
                     Some(self.file_offset_write(curr_addr)?)
                 } else {
-                    // Any space in unallocated clusters can be left alone, since
-                    // unallocated clusters already read back as zeroes.
-                    self.file_offset_read(curr_addr)?
+                    match self.cluster_location(curr_addr)? {
+                        // Any space in unallocated clusters can be left alone, since
+                        // unallocated clusters already read back as zeroes.
+                        ClusterLocation::Unallocated => None,
+                        ClusterLocation::Standard(offset) if self.snapshots.is_empty() => {
+                            Some(offset)
+                        }
+                        // Compressed clusters and clusters that may be shared with a snapshot
+                        // can't be modified in place.
+                        _ => Some(self.file_offset_write(curr_addr)?),
+                    }
                 };
                 if let Some(offset) = offset {
                     // Partial cluster - zero it out.
@@ -1269,16 +2112,19 @@ impl QcowFile {
         Ok(())
     }
 
-    // Reads an L2 cluster from the disk, returning an error if the file can't be read or if any
-    // cluster is compressed.
+    // Reads an L2 cluster from the disk, returning an error if the file can't be read. Standard
+    // cluster entries are reduced to their offset, compressed cluster descriptors are kept whole.
     fn read_l2_cluster(raw_file: &mut QcowRawFile, cluster_addr: u64) -> std::io::Result<Vec<u64>> {
         let file_values = raw_file.read_pointer_cluster(cluster_addr, None)?;
-        if file_values.iter().any(|entry| entry & COMPRESSED_FLAG != 0) {
-            return Err(std::io::Error::from_raw_os_error(ENOTSUP));
-        }
         Ok(file_values
             .iter()
-            .map(|entry| *entry & L2_TABLE_OFFSET_MASK)
+            .map(|entry| {
+                if entry & COMPRESSED_FLAG != 0 {
+                    *entry & !CLUSTER_USED_FLAG
+                } else {
+                    *entry & L2_TABLE_OFFSET_MASK
+                }
+            })
             .collect())
     }
 
@@ -1345,11 +2191,8 @@ impl QcowFile {
             // The index must be valid from when we insterted it.
             let addr = self.l1_table[*l1_index];
             if addr != 0 {
-                self.raw_file.write_pointer_table(
-                    addr,
-                    l2_table.get_values(),
-                    CLUSTER_USED_FLAG,
-                )?;
+                let refcounts = (!self.snapshots.is_empty()).then_some(&mut self.refcounts);
+                write_l2_table(&mut self.raw_file, refcounts, addr, l2_table.get_values())?;
             } else {
                 return Err(std::io::Error::from_raw_os_error(EINVAL));
             }
@@ -1380,27 +2223,43 @@ impl QcowFile {
     }
 
     // Reads `count` bytes starting at `address`, calling `cb` repeatedly with the data source,
-    // number of bytes read so far, offset to read from, and number of bytes to read from the file
-    // in that invocation. If None is given to `cb` in place of the backing file, the `cb` should
-    // infer zeros would have been read.
+    // number of bytes read so far, and number of bytes to read from the source in that invocation.
     fn read_cb<F>(&mut self, address: u64, count: usize, mut cb: F) -> std::io::Result<usize>
     where
-        F: FnMut(Option<&mut dyn DiskFile>, usize, u64, usize) -> std::io::Result<()>,
+        F: FnMut(ReadSource, usize, usize) -> std::io::Result<()>,
     {
         let read_count: usize = self.limit_range_file(address, count);
 
         let mut nread: usize = 0;
         while nread < read_count {
             let curr_addr = address + nread as u64;
-            let file_offset = self.file_offset_read(curr_addr)?;
+            let location = self.cluster_location(curr_addr)?;
             let count = self.limit_range_cluster(curr_addr, read_count - nread);
 
-            if let Some(offset) = file_offset {
-                cb(Some(self.raw_file.file_mut()), nread, offset, count)?;
-            } else if let Some(backing) = self.backing_file.as_mut() {
-                cb(Some(backing.as_mut()), nread, curr_addr, count)?;
-            } else {
-                cb(None, nread, 0, count)?;
+            match location {
+                ClusterLocation::Standard(offset) => {
+                    cb(
+                        ReadSource::File(self.raw_file.file_mut(), offset),
+                        nread,
+                        count,
+                    )?;
+                }
+                ClusterLocation::Compressed(l2_entry) => {
+                    let cluster_offset = self.raw_file.cluster_offset(curr_addr) as usize;
+                    let data = self.decompress_cluster(l2_entry)?;
+                    cb(
+                        ReadSource::Buffer(&data[cluster_offset..cluster_offset + count]),
+                        nread,
+                        count,
+                    )?;
+                }
+                ClusterLocation::Unallocated => {
+                    if let Some(backing) = self.backing_file.as_mut() {
+                        cb(ReadSource::File(backing.as_mut(), curr_addr), nread, count)?;
+                    } else {
+                        cb(ReadSource::Zeros, nread, count)?;
+                    }
+                }
             }
 
             nread += count;
@@ -1451,20 +2310,11 @@ impl Read for QcowFile {
     fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
         let len = buf.len();
         let slice = VolatileSlice::new(buf);
-        let read_count = self.read_cb(
-            self.current_offset,
-            len,
-            |file, already_read, offset, count| {
+        let read_count =
+            self.read_cb(self.current_offset, len, |source, already_read, count| {
                 let sub_slice = slice.get_slice(already_read, count).unwrap();
-                match file {
-                    Some(f) => f.read_exact_at_volatile(sub_slice, offset),
-                    None => {
-                        sub_slice.write_bytes(0);
-                        Ok(())
-                    }
-                }
-            },
-        )?;
+                read_from_source(source, sub_slice)
+            })?;
         self.current_offset += read_count as u64;
         Ok(read_count)
     }
@@ -1523,15 +2373,9 @@ impl Write for QcowFile {
 
 impl FileReadWriteAtVolatile for QcowFile {
     fn read_at_volatile(&mut self, slice: VolatileSlice, offset: u64) -> io::Result<usize> {
-        self.read_cb(offset, slice.size(), |file, read, offset, count| {
+        self.read_cb(offset, slice.size(), |source, read, count| {
             let sub_slice = slice.get_slice(read, count).unwrap();
-            match file {
-                Some(f) => f.read_exact_at_volatile(sub_slice, offset),
-                None => {
-                    sub_slice.write_bytes(0);
-                    Ok(())
-                }
-            }
+            read_from_source(source, sub_slice)
         })
     }
 
@@ -1600,12 +2444,37 @@ impl WriteZeroesAt for QcowFile {
     }
 }
 
+impl InternalSnapshot for QcowFile {
+    fn create_internal_snapshot(&mut self, name: &str) -> crate::Result<()> {
+        self.create_snapshot(name).map_err(crate::Error::QcowError)
+    }
+
+    fn delete_internal_snapshot(&mut self, name: &str) -> crate::Result<()> {
+        self.delete_snapshot(name).map_err(crate::Error::QcowError)
+    }
+}
+
 impl ToAsyncDisk for QcowFile {
     fn to_async_disk(self: Box<Self>, ex: &Executor) -> crate::Result<Box<dyn AsyncDisk>> {
         Ok(Box::new(AsyncDiskFileWrapper::new(*self, ex)))
     }
 }
 
+// Fills `slice` with the data from `source`.
+fn read_from_source(source: ReadSource, slice: VolatileSlice) -> io::Result<()> {
+    match source {
+        ReadSource::File(file, offset) => file.read_exact_at_volatile(slice, offset),
+        ReadSource::Buffer(data) => {
+            slice.copy_from(data);
+            Ok(())
+        }
+        ReadSource::Zeros => {
+            slice.write_bytes(0);
+            Ok(())
+        }
+    }
+}
+
 // Returns an Error if the given offset doesn't align to a cluster boundary.
 fn offset_is_cluster_boundary(offset: u64, cluster_bits: u32) -> Result<()> {
     if offset & ((0x01 << cluster_bits) - 1) != 0 {
@@ -2561,4 +3430,367 @@ mod tests {
             }
         });
     }
+
+    // Creates a raw source image of `size` bytes with a mix of compressible, incompressible and
+    // zero clusters.
+    fn compression_source(size: usize) -> (File, Vec<u8>) {
+        let cluster_size = 0x01usize << DEFAULT_CLUSTER_BITS;
+        let mut data = vec![0u8; size];
+        let mut state: u32 = 0x1234_5678;
+        for (i, b) in data.iter_mut().enumerate() {
+            *b = match (i / cluster_size) % 3 {
+                0 => (i % 7) as u8,
+                1 => {
+                    state = state.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                    (state >> 16) as u8
+                }
+                _ => 0,
+            };
+        }
+        let mut source = tempfile().unwrap();
+        source.write_all(&data).unwrap();
+        (source, data)
+    }
+
+    fn compressed_write_read(compression_type: CompressionType) {
+        // Not a multiple of the cluster size, so the last cluster is partial.
+        let size = 0x01 << 20 | 0x1234;
+        let (mut source, data) = compression_source(size);
+        let mut qcow_file =
+            QcowFile::new_compressed_from(tempfile().unwrap(), &mut source, compression_type)
+                .expect("Failed to create compressed qcow file.");
+        assert_eq!(qcow_file.get_len().unwrap(), size as u64);
+
+        let mut readback = vec![0u8; size];
+        read_exact_at(&mut qcow_file, &mut readback, 0).unwrap();
+        assert!(readback == data);
+
+        // Reopen the file to check the compressed clusters and header survive.
+        let file = qcow_file.raw_file.file().try_clone().unwrap();
+        drop(qcow_file);
+        let mut qcow_file = QcowFile::from(file, MAX_NESTING_DEPTH).unwrap();
+        assert_eq!(qcow_file.compression_type, compression_type);
+        let mut readback = vec![0u8; size];
+        read_exact_at(&mut qcow_file, &mut readback, 0).unwrap();
+        assert!(readback == data);
+    }
+
+    #[test]
+    fn compressed_write_read_deflate() {
+        compressed_write_read(CompressionType::Deflate);
+    }
+
+    #[test]
+    fn compressed_write_read_zstd() {
+        compressed_write_read(CompressionType::Zstd);
+    }
+
+    #[test]
+    fn compressed_is_smaller() {
+        let size = 0x01 << 22;
+        let (mut source, _data) = compression_source(size);
+        let qcow_file = QcowFile::new_compressed_from(
+            tempfile().unwrap(),
+            &mut source,
+            CompressionType::Deflate,
+        )
+        .unwrap();
+        let plain_file = QcowFile::new(tempfile().unwrap(), size as u64).unwrap();
+        let mut plain_file = plain_file;
+        let mut source_data = vec![0u8; size];
+        source.seek(SeekFrom::Start(0)).unwrap();
+        source.read_exact(&mut source_data).unwrap();
+        write_all_at(&mut plain_file, &source_data, 0).unwrap();
+        plain_file.fsync().unwrap();
+
+        let compressed_len = qcow_file.raw_file.file().metadata().unwrap().len();
+        let plain_len = plain_file.raw_file.file().metadata().unwrap().len();
+        assert!(compressed_len < plain_len);
+    }
+
+    #[test]
+    fn compressed_cluster_copy_on_write() {
+        let cluster_size = 0x01usize << DEFAULT_CLUSTER_BITS;
+        let size = cluster_size * 8;
+        let (mut source, mut data) = compression_source(size);
+        let mut qcow_file = QcowFile::new_compressed_from(
+            tempfile().unwrap(),
+            &mut source,
+            CompressionType::Deflate,
+        )
+        .unwrap();
+
+        // Overwrite part of the compressed clusters 0 and 3 and zero part of cluster 6.
+        let update = vec![0xa5u8; 0x100];
+        write_all_at(&mut qcow_file, &update, 0x80).unwrap();
+        data[0x80..0x180].copy_from_slice(&update);
+        write_all_at(&mut qcow_file, &update, (3 * cluster_size + 0x40) as u64).unwrap();
+        data[3 * cluster_size + 0x40..3 * cluster_size + 0x140].copy_from_slice(&update);
+        qcow_file
+            .write_zeroes_all_at((6 * cluster_size + 0x10) as u64, 0x20)
+            .unwrap();
+        data[6 * cluster_size + 0x10..6 * cluster_size + 0x30].fill(0);
+        // Drop cluster 1 completely.
+        qcow_file
+            .punch_hole(cluster_size as u64, cluster_size as u64)
+            .unwrap();
+        data[cluster_size..2 * cluster_size].fill(0);
+
+        let mut readback = vec![0u8; size];
+        read_exact_at(&mut qcow_file, &mut readback, 0).unwrap();
+        assert!(readback == data);
+
+        // The refcounts of the shared compressed clusters must still be consistent.
+        qcow_file.fsync().unwrap();
+        let file = qcow_file.raw_file.file().try_clone().unwrap();
+        drop(qcow_file);
+        let mut qcow_file = QcowFile::from(file, MAX_NESTING_DEPTH).unwrap();
+        let mut readback = vec![0u8; size];
+        read_exact_at(&mut qcow_file, &mut readback, 0).unwrap();
+        assert!(readback == data);
+    }
+
+    #[test]
+    fn compressed_rebuild_refcounts() {
+        let cluster_size = 0x01u64 << DEFAULT_CLUSTER_BITS;
+        let size = 0x01 << 20;
+        let (mut source, data) = compression_source(size);
+        let mut qcow_file = QcowFile::new_compressed_from(
+            tempfile().unwrap(),
+            &mut source,
+            CompressionType::Deflate,
+        )
+        .unwrap();
+
+        // Count the compressed clusters stored in each host cluster.
+        let mut expected_refcounts = std::collections::BTreeMap::new();
+        for address in (0..size as u64).step_by(cluster_size as usize) {
+            if let ClusterLocation::Compressed(l2_entry) =
+                qcow_file.cluster_location(address).unwrap()
+            {
+                for host_cluster in compressed_host_clusters(l2_entry, DEFAULT_CLUSTER_BITS) {
+                    *expected_refcounts.entry(host_cluster).or_insert(0u16) += 1;
+                }
+            }
+        }
+        assert!(expected_refcounts.values().any(|refcount| *refcount > 1));
+
+        let mut file = qcow_file.raw_file.file().try_clone().unwrap();
+        drop(qcow_file);
+        let header = QcowHeader::new(&mut file).unwrap();
+        let mut raw_file = QcowRawFile::from(file, cluster_size).unwrap();
+        QcowFile::rebuild_refcounts(&mut raw_file, header).expect("Failed to rebuild refcounts.");
+
+        let mut qcow_file = QcowFile::from(raw_file.file().try_clone().unwrap(), 1).unwrap();
+        for (host_cluster, refcount) in expected_refcounts {
+            assert_eq!(
+                qcow_file
+                    .refcounts
+                    .get_cluster_refcount(&mut qcow_file.raw_file, host_cluster)
+                    .unwrap(),
+                refcount
+            );
+        }
+        let mut readback = vec![0u8; size];
+        read_exact_at(&mut qcow_file, &mut readback, 0).unwrap();
+        assert!(readback == data);
+    }
+
+    #[test]
+    fn compressed_descriptor_round_trip() {
+        let cluster_bits = DEFAULT_CLUSTER_BITS;
+        let descriptor = compressed_cluster_descriptor(0x3_0123, 0x2345, cluster_bits);
+        assert_ne!(descriptor & COMPRESSED_FLAG, 0);
+        let (offset, len) = compressed_cluster_range(descriptor, cluster_bits);
+        assert_eq!(offset, 0x3_0123);
+        assert!((0x2345..0x2345 + COMPRESSED_SECTOR_SIZE).contains(&len));
+        assert_eq!(
+            compressed_host_clusters(descriptor, cluster_bits).collect::<Vec<_>>(),
+            vec![0x3_0000]
+        );
+    }
+
+    #[test]
+    fn invalid_compression_type() {
+        let mut header = QcowHeader::create_for_size_and_path(0x10_0000, None).unwrap();
+        header.header_size = V3_BARE_HEADER_SIZE + 8;
+        header.compression_type = 2;
+        header.incompatible_features |= INCOMPATIBLE_FEATURES_COMPRESSION_TYPE;
+        let mut disk_file = tempfile().expect("failed to create temp file");
+        header.write_to(&mut disk_file).unwrap();
+        let read_header = QcowHeader::new(&mut disk_file).unwrap();
+        assert_eq!(read_header.compression_type, 2);
+        QcowFile::from(disk_file, MAX_NESTING_DEPTH).expect_err("Invalid compression type worked.");
+    }
+
+    // Returns the live refcounts of the L2 tables and data clusters referenced by the active L1
+    // table and the L1 tables of the snapshots.
+    fn table_refcounts(qcow: &mut QcowFile) -> std::collections::BTreeMap<u64, u16> {
+        let mut l1_tables = vec![qcow.l1_table.get_values().to_vec()];
+        for snapshot in qcow.snapshots.clone() {
+            l1_tables.push(
+                qcow.raw_file
+                    .read_pointer_table(
+                        snapshot.l1_table_offset,
+                        u64::from(snapshot.l1_size),
+                        Some(L1_TABLE_OFFSET_MASK),
+                    )
+                    .unwrap(),
+            );
+        }
+        let mut clusters = Vec::new();
+        for l2_addr in l1_tables.into_iter().flatten().filter(|addr| *addr != 0) {
+            clusters.push(l2_addr);
+            let l2_table = QcowFile::read_l2_cluster(&mut qcow.raw_file, l2_addr).unwrap();
+            clusters.extend(l2_table.into_iter().filter(|entry| *entry != 0));
+        }
+        clusters
+            .into_iter()
+            .map(|cluster| (cluster, qcow.cluster_refcount(cluster).unwrap()))
+            .collect()
+    }
+
+    #[test]
+    fn snapshot_create_apply() {
+        with_default_file(0x10_0000, |mut qcow_file| {
+            let original = [0x55u8; 0x2_0000];
+            write_all_at(&mut qcow_file, &original, 0x1_0000).unwrap();
+            qcow_file.create_snapshot("base").unwrap();
+
+            // The clusters are shared with the snapshot and must not be flagged as used on disk.
+            let l2_addr = qcow_file.l1_table[0];
+            let l2_table = qcow_file
+                .raw_file
+                .read_pointer_cluster(l2_addr, None)
+                .unwrap();
+            assert!(l2_table.iter().all(|entry| entry & CLUSTER_USED_FLAG == 0));
+
+            // Modify part of one shared cluster and deallocate the other.
+            let modified = [0xaau8; 0x1000];
+            write_all_at(&mut qcow_file, &modified, 0x1_8000).unwrap();
+            qcow_file.punch_hole(0x2_0000, 0x1_0000).unwrap();
+            let mut readback = [0u8; 0x1000];
+            read_exact_at(&mut qcow_file, &mut readback, 0x1_8000).unwrap();
+            assert!(readback == modified);
+            read_exact_at(&mut qcow_file, &mut readback, 0x2_0000).unwrap();
+            assert!(readback.iter().all(|b| *b == 0));
+
+            qcow_file.apply_snapshot("base").unwrap();
+            let mut readback = [0u8; 0x2_0000];
+            read_exact_at(&mut qcow_file, &mut readback, 0x1_0000).unwrap();
+            assert!(readback == original);
+        });
+    }
+
+    #[test]
+    fn snapshot_reopen() {
+        let file = tempfile().unwrap();
+        let mut qcow_file = QcowFile::new(file.try_clone().unwrap(), 0x10_0000).unwrap();
+        let original = [0x55u8; 0x1000];
+        write_all_at(&mut qcow_file, &original, 0).unwrap();
+        qcow_file.create_snapshot("first").unwrap();
+        qcow_file.create_snapshot("second").unwrap();
+        write_all_at(&mut qcow_file, &[0xaau8; 0x1000], 0).unwrap();
+        drop(qcow_file);
+
+        let mut qcow_file = QcowFile::from(file, MAX_NESTING_DEPTH).unwrap();
+        let snapshots = qcow_file.snapshots();
+        assert_eq!(snapshots.len(), 2);
+        assert_eq!(snapshots[0].name, "first");
+        assert_eq!(snapshots[0].id, "1");
+        assert_eq!(snapshots[1].name, "second");
+        assert_eq!(snapshots[1].id, "2");
+        assert_eq!(snapshots[1].disk_size, 0x10_0000);
+
+        // Snapshots can be selected by ID as well as by name.
+        qcow_file.apply_snapshot("1").unwrap();
+        let mut readback = [0u8; 0x1000];
+        read_exact_at(&mut qcow_file, &mut readback, 0).unwrap();
+        assert!(readback == original);
+    }
+
+    #[test]
+    fn snapshot_delete() {
+        with_default_file(0x10_0000, |mut qcow_file| {
+            write_all_at(&mut qcow_file, &[0x55u8; 0x2_0000], 0).unwrap();
+            qcow_file.create_snapshot("base").unwrap();
+            let old_cluster = match qcow_file.cluster_location(0).unwrap() {
+                ClusterLocation::Standard(offset) => offset,
+                _ => panic!("cluster not allocated"),
+            };
+            write_all_at(&mut qcow_file, &[0xaau8; 0x1000], 0).unwrap();
+            assert_eq!(qcow_file.cluster_refcount(old_cluster).unwrap(), 1);
+
+            qcow_file.delete_snapshot("base").unwrap();
+            assert!(qcow_file.snapshots().is_empty());
+            assert_eq!(qcow_file.header.snapshots_offset, 0);
+            assert_eq!(qcow_file.cluster_refcount(old_cluster).unwrap(), 0);
+
+            // The second cluster was never copied and is no longer shared.
+            let l2_table = qcow_file
+                .raw_file
+                .read_pointer_cluster(qcow_file.l1_table[0], None)
+                .unwrap();
+            assert!(l2_table[..2]
+                .iter()
+                .all(|entry| entry & CLUSTER_USED_FLAG != 0));
+            let unshared_cluster = match qcow_file.cluster_location(0x1_0000).unwrap() {
+                ClusterLocation::Standard(offset) => offset,
+                _ => panic!("cluster not allocated"),
+            };
+            write_all_at(&mut qcow_file, &[0xaau8; 0x1000], 0x1_0000).unwrap();
+            assert!(matches!(
+                qcow_file.cluster_location(0x1_0000).unwrap(),
+                ClusterLocation::Standard(offset) if offset == unshared_cluster
+            ));
+            assert!(table_refcounts(&mut qcow_file)
+                .values()
+                .all(|refcount| *refcount == 1));
+            qcow_file
+                .apply_snapshot("base")
+                .expect_err("Deleted snapshot was applied.");
+        });
+    }
+
+    #[test]
+    fn snapshot_rebuild_refcounts() {
+        let cluster_size = 0x01u64 << DEFAULT_CLUSTER_BITS;
+        let file = tempfile().unwrap();
+        let mut qcow_file = QcowFile::new(file.try_clone().unwrap(), 0x40_0000).unwrap();
+        write_all_at(&mut qcow_file, &[0x55u8; 0x3_0000], 0).unwrap();
+        qcow_file.create_snapshot("first").unwrap();
+        write_all_at(&mut qcow_file, &[0xaau8; 0x1000], 0x1_0000).unwrap();
+        write_all_at(&mut qcow_file, &[0xaau8; 0x1000], 0x30_0000).unwrap();
+        qcow_file.create_snapshot("second").unwrap();
+        qcow_file.punch_hole(0, 0x1_0000).unwrap();
+        qcow_file.apply_snapshot("first").unwrap();
+        qcow_file.delete_snapshot("second").unwrap();
+        let expected_refcounts = table_refcounts(&mut qcow_file);
+        assert!(expected_refcounts.values().any(|refcount| *refcount > 1));
+        drop(qcow_file);
+
+        let mut file = file;
+        let header = QcowHeader::new(&mut file).unwrap();
+        let mut raw_file = QcowRawFile::from(file.try_clone().unwrap(), cluster_size).unwrap();
+        QcowFile::rebuild_refcounts(&mut raw_file, header).expect("Failed to rebuild refcounts.");
+
+        let mut qcow_file = QcowFile::from(file, MAX_NESTING_DEPTH).unwrap();
+        assert_eq!(table_refcounts(&mut qcow_file), expected_refcounts);
+    }
+
+    #[test]
+    fn snapshot_invalid_names() {
+        with_default_file(0x10_0000, |mut qcow_file| {
+            qcow_file
+                .create_snapshot("")
+                .expect_err("Created snapshot with empty name.");
+            qcow_file.create_snapshot("base").unwrap();
+            qcow_file
+                .create_snapshot("base")
+                .expect_err("Created snapshot with duplicate name.");
+            qcow_file
+                .delete_snapshot("missing")
+                .expect_err("Deleted missing snapshot.");
+        });
+    }
 }
@@ -0,0 +1,281 @@
+// Copyright 2023 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Reading and writing of the qcow2 internal snapshot table.
+
+use std::io;
+use std::io::Read;
+use std::io::Seek;
+use std::io::SeekFrom;
+use std::string::FromUtf8Error;
+
+use remain::sorted;
+use thiserror::Error;
+
+// Limits on the snapshot table, the same as used by qemu.
+pub const MAX_SNAPSHOTS: usize = 65536;
+const MAX_SNAPSHOT_TABLE_SIZE: u64 = 64 * 1024 * 1024;
+const MAX_EXTRA_DATA_SIZE: u32 = 1024;
+
+// Size of the fixed part of a snapshot table entry.
+const SNAPSHOT_ENTRY_HEADER_SIZE: usize = 40;
+// Size of the extra data written for new snapshots: the 64 bit VM state size and the disk size.
+const SNAPSHOT_EXTRA_DATA_SIZE: usize = 16;
+
+#[sorted]
+#[derive(Error, Debug)]
+pub enum Error {
+    /// `InvalidExtraDataSize` - The extra data of a snapshot is larger than allowed.
+    #[error("invalid snapshot extra data size: {0}")]
+    InvalidExtraDataSize(u32),
+    /// `InvalidName` - The ID or name of a snapshot isn't valid UTF-8.
+    #[error("invalid snapshot id or name: {0}")]
+    InvalidName(FromUtf8Error),
+    /// `ReadingTable` - Error reading the snapshot table from the file.
+    #[error("failed to read the snapshot table: {0}")]
+    ReadingTable(io::Error),
+    /// `TableTooLarge` - The snapshot table is larger than allowed.
+    #[error("snapshot table is too large")]
+    TableTooLarge,
+    /// `TooManySnapshots` - The header specifies more snapshots than allowed.
+    #[error("too many snapshots: {0}")]
+    TooManySnapshots(u32),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// An internal snapshot of a qcow2 image.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct QcowSnapshot {
+    /// Unique ID of the snapshot, assigned when the snapshot is created.
+    pub id: String,
+    /// Name of the snapshot.
+    pub name: String,
+    /// Time the snapshot was created, in seconds since the epoch.
+    pub date_sec: u32,
+    /// Sub-second part of the time the snapshot was created.
+    pub date_nsec: u32,
+    /// Virtual size of the disk when the snapshot was created.
+    pub disk_size: u64,
+    pub(super) l1_table_offset: u64,
+    pub(super) l1_size: u32,
+    // Fields written by other implementations that are preserved when the table is rewritten.
+    vm_clock_nsec: u64,
+    vm_state_size: u64,
+    extra_data_tail: Vec<u8>,
+}
+
+impl QcowSnapshot {
+    /// Creates a snapshot entry for a copy of the L1 table stored at `l1_table_offset`.
+    pub fn new(
+        id: String,
+        name: String,
+        date_sec: u32,
+        date_nsec: u32,
+        disk_size: u64,
+        l1_table_offset: u64,
+        l1_size: u32,
+    ) -> QcowSnapshot {
+        QcowSnapshot {
+            id,
+            name,
+            date_sec,
+            date_nsec,
+            disk_size,
+            l1_table_offset,
+            l1_size,
+            vm_clock_nsec: 0,
+            vm_state_size: 0,
+            extra_data_tail: Vec::new(),
+        }
+    }
+
+    // Reads one snapshot table entry. `default_disk_size` is used for entries written without the
+    // disk size in their extra data.
+    fn read_from<F: Read>(f: &mut F, default_disk_size: u64) -> Result<QcowSnapshot> {
+        let mut header = [0u8; SNAPSHOT_ENTRY_HEADER_SIZE];
+        f.read_exact(&mut header).map_err(Error::ReadingTable)?;
+        let l1_table_offset = u64::from_be_bytes(header[0..8].try_into().unwrap());
+        let l1_size = u32::from_be_bytes(header[8..12].try_into().unwrap());
+        let id_size = u16::from_be_bytes(header[12..14].try_into().unwrap()) as usize;
+        let name_size = u16::from_be_bytes(header[14..16].try_into().unwrap()) as usize;
+        let date_sec = u32::from_be_bytes(header[16..20].try_into().unwrap());
+        let date_nsec = u32::from_be_bytes(header[20..24].try_into().unwrap());
+        let vm_clock_nsec = u64::from_be_bytes(header[24..32].try_into().unwrap());
+        let vm_state_size = u32::from_be_bytes(header[32..36].try_into().unwrap());
+        let extra_data_size = u32::from_be_bytes(header[36..40].try_into().unwrap());
+        if extra_data_size > MAX_EXTRA_DATA_SIZE {
+            return Err(Error::InvalidExtraDataSize(extra_data_size));
+        }
+
+        let mut extra_data = vec![0u8; extra_data_size as usize];
+        f.read_exact(&mut extra_data).map_err(Error::ReadingTable)?;
+        let vm_state_size = extra_data.get(0..8).map_or(u64::from(vm_state_size), |b| {
+            u64::from_be_bytes(b.try_into().unwrap())
+        });
+        let disk_size = extra_data.get(8..16).map_or(default_disk_size, |b| {
+            u64::from_be_bytes(b.try_into().unwrap())
+        });
+        let extra_data_tail = extra_data
+            .get(SNAPSHOT_EXTRA_DATA_SIZE..)
+            .unwrap_or_default()
+            .to_vec();
+
+        let mut id = vec![0u8; id_size];
+        f.read_exact(&mut id).map_err(Error::ReadingTable)?;
+        let mut name = vec![0u8; name_size];
+        f.read_exact(&mut name).map_err(Error::ReadingTable)?;
+
+        // Entries are padded to a multiple of 8 bytes.
+        let entry_size = SNAPSHOT_ENTRY_HEADER_SIZE + extra_data.len() + id_size + name_size;
+        let mut padding = vec![0u8; padding_size(entry_size)];
+        f.read_exact(&mut padding).map_err(Error::ReadingTable)?;
+
+        Ok(QcowSnapshot {
+            id: String::from_utf8(id).map_err(Error::InvalidName)?,
+            name: String::from_utf8(name).map_err(Error::InvalidName)?,
+            date_sec,
+            date_nsec,
+            disk_size,
+            l1_table_offset,
+            l1_size,
+            vm_clock_nsec,
+            vm_state_size,
+            extra_data_tail,
+        })
+    }
+
+    // Returns the size of the table entry for this snapshot, including padding.
+    fn entry_size(&self) -> usize {
+        let size = SNAPSHOT_ENTRY_HEADER_SIZE
+            + SNAPSHOT_EXTRA_DATA_SIZE
+            + self.extra_data_tail.len()
+            + self.id.len()
+            + self.name.len();
+        size + padding_size(size)
+    }
+
+    // Appends the table entry for this snapshot to `buf`.
+    fn write_to(&self, buf: &mut Vec<u8>) {
+        let start = buf.len();
+        let extra_data_size = SNAPSHOT_EXTRA_DATA_SIZE + self.extra_data_tail.len();
+        buf.extend_from_slice(&self.l1_table_offset.to_be_bytes());
+        buf.extend_from_slice(&self.l1_size.to_be_bytes());
+        buf.extend_from_slice(&(self.id.len() as u16).to_be_bytes());
+        buf.extend_from_slice(&(self.name.len() as u16).to_be_bytes());
+        buf.extend_from_slice(&self.date_sec.to_be_bytes());
+        buf.extend_from_slice(&self.date_nsec.to_be_bytes());
+        buf.extend_from_slice(&self.vm_clock_nsec.to_be_bytes());
+        // The 32 bit field is truncated, the full size is in the extra data.
+        buf.extend_from_slice(&(self.vm_state_size as u32).to_be_bytes());
+        buf.extend_from_slice(&(extra_data_size as u32).to_be_bytes());
+        buf.extend_from_slice(&self.vm_state_size.to_be_bytes());
+        buf.extend_from_slice(&self.disk_size.to_be_bytes());
+        buf.extend_from_slice(&self.extra_data_tail);
+        buf.extend_from_slice(self.id.as_bytes());
+        buf.extend_from_slice(self.name.as_bytes());
+        buf.resize(start + self.entry_size(), 0);
+    }
+}
+
+// Returns the number of bytes needed to pad an entry of `size` bytes to a multiple of 8.
+fn padding_size(size: usize) -> usize {
+    (8 - size % 8) % 8
+}
+
+/// Reads the `count` entries of the snapshot table starting at `offset`.
+pub fn read_snapshot_table<F: Read + Seek>(
+    f: &mut F,
+    offset: u64,
+    count: u32,
+    default_disk_size: u64,
+) -> Result<Vec<QcowSnapshot>> {
+    if count as usize > MAX_SNAPSHOTS {
+        return Err(Error::TooManySnapshots(count));
+    }
+    if count == 0 {
+        return Ok(Vec::new());
+    }
+    f.seek(SeekFrom::Start(offset))
+        .map_err(Error::ReadingTable)?;
+    let mut snapshots = Vec::with_capacity(count as usize);
+    let mut table_size = 0;
+    for _ in 0..count {
+        let snapshot = QcowSnapshot::read_from(f, default_disk_size)?;
+        table_size += snapshot.entry_size() as u64;
+        if table_size > MAX_SNAPSHOT_TABLE_SIZE {
+            return Err(Error::TableTooLarge);
+        }
+        snapshots.push(snapshot);
+    }
+    Ok(snapshots)
+}
+
+/// Returns the on-disk representation of a snapshot table holding `snapshots`.
+pub fn snapshot_table_bytes(snapshots: &[QcowSnapshot]) -> Vec<u8> {
+    let mut buf = Vec::new();
+    for snapshot in snapshots {
+        snapshot.write_to(&mut buf);
+    }
+    buf
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    #[test]
+    fn table_round_trip() {
+        let mut snapshots = vec![
+            QcowSnapshot::new(
+                "1".to_string(),
+                "a".to_string(),
+                1,
+                2,
+                0x10_0000,
+                0x3_0000,
+                1,
+            ),
+            QcowSnapshot::new(
+                "2".to_string(),
+                "longer name".to_string(),
+                3,
+                4,
+                0x20_0000,
+                0x5_0000,
+                2,
+            ),
+        ];
+        // Data added by other implementations must be kept.
+        snapshots[1].vm_state_size = 0x1_0000_0000;
+        snapshots[1].extra_data_tail = vec![0xff; 8];
+
+        let table = snapshot_table_bytes(&snapshots);
+        assert_eq!(table.len() % 8, 0);
+        let mut file = Cursor::new(table);
+        let read = read_snapshot_table(&mut file, 0, 2, 0).unwrap();
+        assert_eq!(read, snapshots);
+    }
+
+    #[test]
+    fn default_disk_size() {
+        let mut table = snapshot_table_bytes(&[QcowSnapshot::new(
+            "1".to_string(),
+            "a".to_string(),
+            0,
+            0,
+            0x10_0000,
+            0x3_0000,
+            1,
+        )]);
+        // Remove the extra data, which holds the disk size.
+        table[36..40].copy_from_slice(&0u32.to_be_bytes());
+        table.drain(40..56);
+        let mut file = Cursor::new(table);
+        let read = read_snapshot_table(&mut file, 0, 1, 0x40_0000).unwrap();
+        assert_eq!(read[0].disk_size, 0x40_0000);
+    }
+}
@@ -13,3 +13,4 @@ cfg_if::cfg_if! {
 }
 
 pub(crate) use platform::apply_raw_disk_file_options;
+pub(crate) use platform::is_same_file;
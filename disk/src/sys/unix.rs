@@ -3,7 +3,13 @@
 // found in the LICENSE file.
 
 use std::fs::File;
+use std::io;
+use std::mem::MaybeUninit;
+use std::os::unix::fs::MetadataExt;
 
+use base::RawDescriptor;
+
+use crate::Error;
 use crate::Result;
 
 pub fn apply_raw_disk_file_options(_raw_image: &File, _is_sparse_file: bool) -> Result<()> {
@@ -11,6 +17,20 @@ pub fn apply_raw_disk_file_options(_raw_image: &File, _is_sparse_file: bool) ->
     Ok(())
 }
 
+/// Returns true if `file` and `descriptor` refer to the same file, i.e. the same inode.
+pub fn is_same_file(file: &File, descriptor: RawDescriptor) -> Result<bool> {
+    let metadata = file.metadata().map_err(Error::CheckingFile)?;
+    let mut stat = MaybeUninit::<libc::stat64>::uninit();
+    // Safe because the kernel only writes to `stat`, whose size is given by its type.
+    let ret = unsafe { libc::fstat64(descriptor, stat.as_mut_ptr()) };
+    if ret < 0 {
+        return Err(Error::CheckingFile(io::Error::last_os_error()));
+    }
+    // Safe because fstat64 succeeded and initialized `stat`.
+    let stat = unsafe { stat.assume_init() };
+    Ok(metadata.dev() == stat.st_dev && metadata.ino() == stat.st_ino)
+}
+
 #[cfg(test)]
 mod tests {
     use std::fs::File;
@@ -115,4 +135,48 @@ mod tests {
         let image_type = detect_image_type(&t).expect("failed to detect image type");
         assert_eq!(image_type, ImageType::Raw);
     }
+
+    #[test]
+    #[cfg(feature = "qcow")]
+    fn switch_to_overlay() {
+        async fn switch_to_overlay_async(ex: &Executor) {
+            let mut backing = tempfile::tempfile().unwrap();
+            backing.write_all(&[0x55; 4096]).unwrap();
+            let mut disk: Box<dyn AsyncDisk> =
+                Box::new(SingleFileDisk::new(backing.try_clone().unwrap(), ex).unwrap());
+            // The backing file must be the current image.
+            let other = tempfile::tempfile().unwrap();
+            let overlay = tempfile::tempfile().unwrap();
+            assert!(matches!(
+                crate::switch_to_overlay(&mut disk, overlay, &other, "/other.img", ex),
+                Err(Error::BackingFileMismatch)
+            ));
+            // The path of the backing file must not depend on the current directory.
+            let overlay = tempfile::tempfile().unwrap();
+            assert!(matches!(
+                crate::switch_to_overlay(&mut disk, overlay, &backing, "backing.img", ex),
+                Err(Error::RelativeBackingFile(_))
+            ));
+            assert_eq!(disk.get_len().unwrap(), 4096);
+
+            let overlay = tempfile::tempfile().unwrap();
+            crate::switch_to_overlay(&mut disk, overlay, &backing, "/backing.img", ex).unwrap();
+            assert_eq!(disk.get_len().unwrap(), 4096);
+
+            // Writes go to the overlay, unwritten data is read from the previous image.
+            disk.write_double_buffered(0, &[0xaa; 512]).await.unwrap();
+            let mut buf = [0u8; 1024];
+            disk.read_double_buffered(0, &mut buf).await.unwrap();
+            assert!(buf[..512].iter().all(|b| *b == 0xaa));
+            assert!(buf[512..].iter().all(|b| *b == 0x55));
+
+            let mut backing_data = Vec::new();
+            backing.seek(SeekFrom::Start(0)).unwrap();
+            backing.read_to_end(&mut backing_data).unwrap();
+            assert!(backing_data.iter().all(|b| *b == 0x55));
+        }
+
+        let ex = Executor::new().unwrap();
+        ex.run_until(switch_to_overlay_async(&ex)).unwrap();
+    }
 }
@@ -21,6 +21,11 @@ impl SingleFileDisk {
     }
 }
 
+/// Checking whether two handles refer to the same file isn't supported on Windows.
+pub fn is_same_file(_file: &File, _descriptor: base::RawDescriptor) -> Result<bool> {
+    Err(Error::UnsupportedOperation)
+}
+
 /// On Windows, if the file is sparse, we set the option. On Linux this is not needed.
 pub fn apply_raw_disk_file_options(raw_image: &File, is_sparse_file: bool) -> Result<()> {
     if is_sparse_file {
@@ -11,6 +11,7 @@ use std::time::Duration;
 
 use crate::server::Mapper;
 use crate::sys;
+pub use crate::sys::FileLock;
 pub use crate::sys::FsOptions;
 pub use crate::sys::IoctlFlags;
 pub use crate::sys::IoctlIovec;
@@ -1096,18 +1097,66 @@ pub trait FileSystem {
         Err(io::Error::from_raw_os_error(libc::ENOSYS))
     }
 
-    /// TODO: support this
-    fn getlk(&self) -> io::Result<()> {
+    /// Test for a POSIX file lock.
+    ///
+    /// Returns a lock that conflicts with `lock`, or `lock` with its type changed to `F_UNLCK` if
+    /// `owner` could acquire it. `lock.end` is inclusive and is `i64::MAX` for a lock extending to
+    /// the end of the file.
+    ///
+    /// `handle` is the `Handle` returned by the file system from the `open` method, if any. If the
+    /// file system did not return a `Handle` from `open` then the contents of `handle` are
+    /// undefined.
+    ///
+    /// This method is only called when the `FsOptions::POSIX_LOCKS` feature is enabled.
+    fn getlk(
+        &self,
+        ctx: Context,
+        inode: Self::Inode,
+        handle: Self::Handle,
+        owner: u64,
+        lock: FileLock,
+        flags: u32,
+    ) -> io::Result<FileLock> {
         Err(io::Error::from_raw_os_error(libc::ENOSYS))
     }
 
-    /// TODO: support this
-    fn setlk(&self) -> io::Result<()> {
+    /// Acquire, modify, or release a POSIX file lock or, if `flags` contains `LK_FLOCK`, a BSD
+    /// file lock.
+    ///
+    /// Fails with `EAGAIN` if `lock` conflicts with a lock held by another owner. `lock.end` is
+    /// inclusive and is `i64::MAX` for a lock extending to the end of the file.
+    ///
+    /// `handle` is the `Handle` returned by the file system from the `open` method, if any. If the
+    /// file system did not return a `Handle` from `open` then the contents of `handle` are
+    /// undefined.
+    ///
+    /// This method is only called when the `FsOptions::POSIX_LOCKS` feature (or the
+    /// `FsOptions::FLOCK_LOCKS` feature for BSD locks) is enabled.
+    fn setlk(
+        &self,
+        ctx: Context,
+        inode: Self::Inode,
+        handle: Self::Handle,
+        owner: u64,
+        lock: FileLock,
+        flags: u32,
+    ) -> io::Result<()> {
         Err(io::Error::from_raw_os_error(libc::ENOSYS))
     }
 
-    /// TODO: support this
-    fn setlkw(&self) -> io::Result<()> {
+    /// Like `setlk` but waits for conflicting locks to be released instead of failing.
+    ///
+    /// The calling thread is blocked until the lock is acquired, so requests that would release
+    /// the conflicting lock must be able to reach the file system from another thread.
+    fn setlkw(
+        &self,
+        ctx: Context,
+        inode: Self::Inode,
+        handle: Self::Handle,
+        owner: u64,
+        lock: FileLock,
+        flags: u32,
+    ) -> io::Result<()> {
         Err(io::Error::from_raw_os_error(libc::ENOSYS))
     }
 
@@ -1126,8 +1175,26 @@ pub trait FileSystem {
         Err(io::Error::from_raw_os_error(libc::ENOSYS))
     }
 
-    /// TODO: support this
-    fn lseek(&self) -> io::Result<()> {
+    /// Find the next data or hole in a file.
+    ///
+    /// Returns the offset of the first data (for `SEEK_DATA`) or hole (for `SEEK_HOLE`) at or after
+    /// `offset`. The end of the file is an implicit hole.
+    ///
+    /// `handle` is the `Handle` returned by the file system from the `open` method, if any. If the
+    /// file system did not return a `Handle` from `open` then the contents of `handle` are
+    /// undefined.
+    ///
+    /// If this method returns an `ENOSYS` error then the kernel will treat that as a permanent
+    /// failure: all future `SEEK_DATA` and `SEEK_HOLE` seeks will be handled by the kernel as if
+    /// the file had no holes without being forwarded to the file system.
+    fn lseek(
+        &self,
+        ctx: Context,
+        inode: Self::Inode,
+        handle: Self::Handle,
+        offset: u64,
+        whence: u32,
+    ) -> io::Result<u64> {
         Err(io::Error::from_raw_os_error(libc::ENOSYS))
     }
 
@@ -1246,27 +1246,69 @@ impl<F: FileSystem + Sync> Server<F> {
         }
     }
 
-    fn getlk<R: Reader, W: Writer>(&self, in_header: InHeader, mut _r: R, w: W) -> Result<usize> {
-        if let Err(e) = self.fs.getlk() {
-            reply_error(e, in_header.unique, w)
-        } else {
-            Ok(0)
+    fn getlk<R: Reader, W: Writer>(&self, in_header: InHeader, mut r: R, w: W) -> Result<usize> {
+        let LkIn {
+            fh,
+            owner,
+            lk,
+            lk_flags,
+            ..
+        } = zerocopy_from_reader(&mut r).map_err(Error::DecodeMessage)?;
+
+        match self.fs.getlk(
+            Context::from(in_header),
+            in_header.nodeid.into(),
+            fh.into(),
+            owner,
+            lk,
+            lk_flags,
+        ) {
+            Ok(lk) => reply_ok(Some(LkOut { lk }), None, in_header.unique, w),
+            Err(e) => reply_error(e, in_header.unique, w),
         }
     }
 
-    fn setlk<R: Reader, W: Writer>(&self, in_header: InHeader, mut _r: R, w: W) -> Result<usize> {
-        if let Err(e) = self.fs.setlk() {
-            reply_error(e, in_header.unique, w)
-        } else {
-            Ok(0)
+    fn setlk<R: Reader, W: Writer>(&self, in_header: InHeader, mut r: R, w: W) -> Result<usize> {
+        let LkIn {
+            fh,
+            owner,
+            lk,
+            lk_flags,
+            ..
+        } = zerocopy_from_reader(&mut r).map_err(Error::DecodeMessage)?;
+
+        match self.fs.setlk(
+            Context::from(in_header),
+            in_header.nodeid.into(),
+            fh.into(),
+            owner,
+            lk,
+            lk_flags,
+        ) {
+            Ok(()) => reply_ok(None::<u8>, None, in_header.unique, w),
+            Err(e) => reply_error(e, in_header.unique, w),
         }
     }
 
-    fn setlkw<R: Reader, W: Writer>(&self, in_header: InHeader, mut _r: R, w: W) -> Result<usize> {
-        if let Err(e) = self.fs.setlkw() {
-            reply_error(e, in_header.unique, w)
-        } else {
-            Ok(0)
+    fn setlkw<R: Reader, W: Writer>(&self, in_header: InHeader, mut r: R, w: W) -> Result<usize> {
+        let LkIn {
+            fh,
+            owner,
+            lk,
+            lk_flags,
+            ..
+        } = zerocopy_from_reader(&mut r).map_err(Error::DecodeMessage)?;
+
+        match self.fs.setlkw(
+            Context::from(in_header),
+            in_header.nodeid.into(),
+            fh.into(),
+            owner,
+            lk,
+            lk_flags,
+        ) {
+            Ok(()) => reply_ok(None::<u8>, None, in_header.unique, w),
+            Err(e) => reply_error(e, in_header.unique, w),
         }
     }
 
@@ -1480,11 +1522,20 @@ impl<F: FileSystem + Sync> Server<F> {
         }
     }
 
-    fn lseek<R: Reader, W: Writer>(&self, in_header: InHeader, mut _r: R, w: W) -> Result<usize> {
-        if let Err(e) = self.fs.lseek() {
-            reply_error(e, in_header.unique, w)
-        } else {
-            Ok(0)
+    fn lseek<R: Reader, W: Writer>(&self, in_header: InHeader, mut r: R, w: W) -> Result<usize> {
+        let LseekIn {
+            fh, offset, whence, ..
+        } = zerocopy_from_reader(&mut r).map_err(Error::DecodeMessage)?;
+
+        match self.fs.lseek(
+            Context::from(in_header),
+            in_header.nodeid.into(),
+            fh.into(),
+            offset,
+            whence,
+        ) {
+            Ok(offset) => reply_ok(Some(LseekOut { offset }), None, in_header.unique, w),
+            Err(e) => reply_error(e, in_header.unique, w),
         }
     }
 
@@ -0,0 +1,1314 @@
+// Copyright 2023 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Display backend serving the scanout to VNC clients, over version 3.8 of the RFB protocol
+//! (RFC 6143).
+//!
+//! The first scanout surface is shared with every client, without any authentication, using the
+//! raw encoding and the DesktopSize pseudo-encoding to follow mode changes. The keyboard events of
+//! the clients go to the keyboard event devices, and their first pointer button to the
+//! touchscreen event devices. Both the framebuffers written by the 2D path and the imported
+//! buffers are served, the latter being copied out of their mapping at each flip.
+
+use std::cell::Cell;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+use std::collections::VecDeque;
+use std::io;
+use std::io::BufWriter;
+use std::io::Read;
+use std::io::Write;
+use std::net::Shutdown;
+use std::net::TcpListener;
+use std::net::TcpStream;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::thread;
+
+use base::error;
+use base::info;
+use base::AsRawDescriptor;
+use base::Event;
+use base::EventToken;
+use base::MemoryMapping;
+use base::MemoryMappingBuilder;
+use base::MemoryMappingBuilderUnix;
+use base::Protection;
+use base::RawDescriptor;
+use base::WaitContext;
+use data_model::VolatileMemory;
+use data_model::VolatileSlice;
+use linux_input_sys::virtio_input_event;
+use sync::Condvar;
+use sync::Mutex;
+
+use crate::DisplayT;
+use crate::EventDeviceKind;
+use crate::GpuDisplayError;
+use crate::GpuDisplayEvents;
+use crate::GpuDisplayFramebuffer;
+use crate::GpuDisplayImport;
+use crate::GpuDisplayResult;
+use crate::GpuDisplaySurface;
+use crate::SurfaceType;
+use crate::SysDisplayT;
+
+// Size of the screen until the guest sets a mode.
+const DEFAULT_WIDTH: u32 = 640;
+const DEFAULT_HEIGHT: u32 = 480;
+// Updates are sent as the tiles of this size that changed since the last update.
+const TILE_SIZE: u32 = 64;
+const DESKTOP_NAME: &[u8] = b"crosvm";
+
+const DRM_FORMAT_ABGR8888: u32 = 0x34324241;
+const DRM_FORMAT_ARGB8888: u32 = 0x34325241;
+const DRM_FORMAT_XBGR8888: u32 = 0x34324258;
+const DRM_FORMAT_XRGB8888: u32 = 0x34325258;
+const DRM_FORMAT_MOD_LINEAR: u64 = 0;
+
+const SECURITY_NONE: u8 = 1;
+
+const CLIENT_SET_PIXEL_FORMAT: u8 = 0;
+const CLIENT_SET_ENCODINGS: u8 = 2;
+const CLIENT_FRAMEBUFFER_UPDATE_REQUEST: u8 = 3;
+const CLIENT_KEY_EVENT: u8 = 4;
+const CLIENT_POINTER_EVENT: u8 = 5;
+const CLIENT_CUT_TEXT: u8 = 6;
+
+const SERVER_FRAMEBUFFER_UPDATE: u8 = 0;
+
+const ENCODING_RAW: i32 = 0;
+const ENCODING_DESKTOP_SIZE: i32 = -223;
+
+/// Format of the pixels sent to a client.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct PixelFormat {
+    bits_per_pixel: u8,
+    depth: u8,
+    big_endian: bool,
+    true_color: bool,
+    red_max: u16,
+    green_max: u16,
+    blue_max: u16,
+    red_shift: u8,
+    green_shift: u8,
+    blue_shift: u8,
+}
+
+impl PixelFormat {
+    /// Little-endian XRGB8888, the format of the screen and the one offered to the clients.
+    const SERVER: PixelFormat = PixelFormat {
+        bits_per_pixel: 32,
+        depth: 24,
+        big_endian: false,
+        true_color: true,
+        red_max: 255,
+        green_max: 255,
+        blue_max: 255,
+        red_shift: 16,
+        green_shift: 8,
+        blue_shift: 0,
+    };
+
+    fn from_bytes(bytes: &[u8; 16]) -> PixelFormat {
+        PixelFormat {
+            bits_per_pixel: bytes[0],
+            depth: bytes[1],
+            big_endian: bytes[2] != 0,
+            true_color: bytes[3] != 0,
+            red_max: u16::from_be_bytes([bytes[4], bytes[5]]),
+            green_max: u16::from_be_bytes([bytes[6], bytes[7]]),
+            blue_max: u16::from_be_bytes([bytes[8], bytes[9]]),
+            red_shift: bytes[10],
+            green_shift: bytes[11],
+            blue_shift: bytes[12],
+        }
+    }
+
+    fn to_bytes(self) -> [u8; 16] {
+        let mut bytes = [0u8; 16];
+        bytes[0] = self.bits_per_pixel;
+        bytes[1] = self.depth;
+        bytes[2] = self.big_endian as u8;
+        bytes[3] = self.true_color as u8;
+        bytes[4..6].copy_from_slice(&self.red_max.to_be_bytes());
+        bytes[6..8].copy_from_slice(&self.green_max.to_be_bytes());
+        bytes[8..10].copy_from_slice(&self.blue_max.to_be_bytes());
+        bytes[10] = self.red_shift;
+        bytes[11] = self.green_shift;
+        bytes[12] = self.blue_shift;
+        bytes
+    }
+
+    /// Returns true if pixels can be converted to this format. Color maps aren't supported.
+    fn is_supported(&self) -> bool {
+        self.true_color
+            && matches!(self.bits_per_pixel, 8 | 16 | 32)
+            && [self.red_shift, self.green_shift, self.blue_shift]
+                .iter()
+                .all(|&shift| shift < self.bits_per_pixel)
+    }
+
+    /// Appends `pixel`, in the XRGB8888 format of the screen, to `out` in this format.
+    fn write_pixel(&self, pixel: u32, out: &mut Vec<u8>) {
+        if *self == PixelFormat::SERVER {
+            out.extend_from_slice(&pixel.to_le_bytes());
+            return;
+        }
+        let scale = |component: u32, max: u16| (component * max as u32 + 127) / 255;
+        let value = scale((pixel >> 16) & 0xff, self.red_max) << self.red_shift
+            | scale((pixel >> 8) & 0xff, self.green_max) << self.green_shift
+            | scale(pixel & 0xff, self.blue_max) << self.blue_shift;
+        match (self.bits_per_pixel, self.big_endian) {
+            (8, _) => out.push(value as u8),
+            (16, false) => out.extend_from_slice(&(value as u16).to_le_bytes()),
+            (16, true) => out.extend_from_slice(&(value as u16).to_be_bytes()),
+            (_, false) => out.extend_from_slice(&value.to_le_bytes()),
+            (_, true) => out.extend_from_slice(&value.to_be_bytes()),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Rect {
+    x: u32,
+    y: u32,
+    width: u32,
+    height: u32,
+}
+
+impl Rect {
+    fn intersect(&self, other: &Rect) -> Option<Rect> {
+        let x = self.x.max(other.x);
+        let y = self.y.max(other.y);
+        let right = (self.x + self.width).min(other.x + other.width);
+        let bottom = (self.y + self.height).min(other.y + other.height);
+        if right <= x || bottom <= y {
+            return None;
+        }
+        Some(Rect {
+            x,
+            y,
+            width: right - x,
+            height: bottom - y,
+        })
+    }
+}
+
+/// Contents of the served scanout.
+struct Screen {
+    width: u32,
+    height: u32,
+    /// XRGB8888 pixels, row after row.
+    pixels: Vec<u32>,
+    /// Incremented on each change of the contents or of the size.
+    frame: u64,
+}
+
+impl Screen {
+    fn resize(&mut self, width: u32, height: u32) {
+        self.width = width;
+        self.height = height;
+        self.pixels = vec![0; width as usize * height as usize];
+    }
+
+    /// Copies the start of the row `y` of the screen from `row`, which holds little-endian 32-bit
+    /// pixels whose red component is at `red_shift`, either 16 or 0.
+    fn copy_row(&mut self, y: u32, row: &[u8], red_shift: u32) {
+        let start = y as usize * self.width as usize;
+        let pixels = &mut self.pixels[start..start + self.width as usize];
+        for (pixel, bytes) in pixels.iter_mut().zip(row.chunks_exact(4)) {
+            let value = u32::from_le_bytes(bytes.try_into().unwrap());
+            *pixel = if red_shift == 16 {
+                value & 0xff_ffff
+            } else {
+                (value & 0xff) << 16 | (value & 0xff00) | (value >> 16) & 0xff
+            };
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct UpdateRequest {
+    incremental: bool,
+    area: Rect,
+}
+
+struct Client {
+    /// Clone of the connection, to shut it down when the display is dropped.
+    stream: TcpStream,
+    pixel_format: PixelFormat,
+    /// Whether the client supports the DesktopSize pseudo-encoding.
+    desktop_size: bool,
+    request: Option<UpdateRequest>,
+}
+
+struct State {
+    screen: Screen,
+    clients: BTreeMap<u64, Client>,
+    next_client_id: u64,
+    /// Set when the display is dropped, to stop the client threads.
+    closed: bool,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum InputEvent {
+    Key { keysym: u32, down: bool },
+    Pointer { x: u16, y: u16, buttons: u8 },
+}
+
+/// State shared between the display and the threads serving the clients.
+struct Shared {
+    state: Mutex<State>,
+    /// Notified on each change of the screen and of the update requests of the clients.
+    changed: Condvar,
+    input: Mutex<VecDeque<InputEvent>>,
+    /// Signaled when input events are queued.
+    input_evt: Event,
+}
+
+impl Shared {
+    fn update_screen<F: FnOnce(&mut Screen)>(&self, f: F) {
+        let mut state = self.state.lock();
+        f(&mut state.screen);
+        state.screen.frame += 1;
+        drop(state);
+        self.changed.notify_all();
+    }
+
+    fn push_input(&self, event: InputEvent) {
+        self.input.lock().push_back(event);
+        if let Err(e) = self.input_evt.signal() {
+            error!("failed to signal vnc input event: {}", e);
+        }
+    }
+}
+
+/// Buffer imported from the guest, mapped to be copied to the screen.
+struct ImportedBuffer {
+    mapping: MemoryMapping,
+    offset: usize,
+    stride: usize,
+    width: u32,
+    height: u32,
+    red_shift: u32,
+}
+
+type Imports = Rc<RefCell<BTreeMap<u32, ImportedBuffer>>>;
+
+struct VncImport {
+    import_id: u32,
+    imports: Imports,
+}
+
+impl GpuDisplayImport for VncImport {}
+
+impl Drop for VncImport {
+    fn drop(&mut self) {
+        self.imports.borrow_mut().remove(&self.import_id);
+    }
+}
+
+struct VncSurface {
+    surface_id: u32,
+    width: u32,
+    height: u32,
+    buffer: Vec<u8>,
+    shared: Arc<Shared>,
+    /// Id of the surface shown to the clients.
+    scanout_surface: Rc<Cell<Option<u32>>>,
+    imports: Imports,
+}
+
+impl VncSurface {
+    fn is_shown(&self) -> bool {
+        self.scanout_surface.get() == Some(self.surface_id)
+    }
+}
+
+impl GpuDisplaySurface for VncSurface {
+    fn surface_descriptor(&self) -> u64 {
+        self.surface_id as u64
+    }
+
+    fn framebuffer(&mut self) -> Option<GpuDisplayFramebuffer> {
+        // XRGB8888
+        if self.buffer.is_empty() {
+            self.buffer = vec![0; self.width as usize * self.height as usize * 4];
+        }
+        Some(GpuDisplayFramebuffer::new(
+            VolatileSlice::new(self.buffer.as_mut_slice()),
+            self.width * 4,
+            4,
+        ))
+    }
+
+    fn flip(&mut self) {
+        if !self.is_shown() || self.buffer.is_empty() {
+            return;
+        }
+        let buffer = &self.buffer;
+        let row_len = self.width as usize * 4;
+        self.shared.update_screen(|screen| {
+            for (y, row) in buffer.chunks_exact(row_len).enumerate() {
+                screen.copy_row(y as u32, row, 16);
+            }
+        });
+    }
+
+    fn flip_to(&mut self, import_id: u32) {
+        if !self.is_shown() {
+            return;
+        }
+        let imports = self.imports.borrow();
+        let import = match imports.get(&import_id) {
+            Some(import) => import,
+            None => return,
+        };
+        let width = import.width.min(self.width);
+        let height = import.height.min(self.height);
+        let mut row = vec![0u8; width as usize * 4];
+        self.shared.update_screen(|screen| {
+            for y in 0..height {
+                let offset = import.offset + y as usize * import.stride;
+                match import.mapping.get_slice(offset, row.len()) {
+                    Ok(slice) => slice.copy_to(&mut row),
+                    Err(e) => {
+                        error!("failed to read imported buffer: {}", e);
+                        return;
+                    }
+                }
+                screen.copy_row(y, &row, import.red_shift);
+            }
+        });
+    }
+}
+
+impl Drop for VncSurface {
+    fn drop(&mut self) {
+        if self.is_shown() {
+            self.scanout_surface.set(None);
+        }
+    }
+}
+
+#[derive(EventToken)]
+enum Token {
+    Listener,
+    Kill,
+}
+
+pub struct DisplayVnc {
+    shared: Arc<Shared>,
+    kill_evt: Event,
+    server_thread: Option<thread::JoinHandle<()>>,
+    scanout_surface: Rc<Cell<Option<u32>>>,
+    imports: Imports,
+    /// Input event taken by `next_event`, to be handled by `handle_next_event`.
+    current_event: Option<InputEvent>,
+    tracking_id: Option<i32>,
+    next_tracking_id: u16,
+}
+
+impl DisplayVnc {
+    /// Starts serving the clients connecting to `listener`, which must be nonblocking.
+    pub fn new(listener: TcpListener) -> GpuDisplayResult<DisplayVnc> {
+        let kill_evt = Event::new().map_err(|_| GpuDisplayError::CreateEvent)?;
+        let input_evt = Event::new().map_err(|_| GpuDisplayError::CreateEvent)?;
+        let shared = Arc::new(Shared {
+            state: Mutex::new(State {
+                screen: Screen {
+                    width: DEFAULT_WIDTH,
+                    height: DEFAULT_HEIGHT,
+                    pixels: vec![0; (DEFAULT_WIDTH * DEFAULT_HEIGHT) as usize],
+                    frame: 0,
+                },
+                clients: BTreeMap::new(),
+                next_client_id: 0,
+                closed: false,
+            }),
+            changed: Condvar::new(),
+            input: Mutex::new(VecDeque::new()),
+            input_evt,
+        });
+
+        let wait_ctx =
+            WaitContext::build_with(&[(&listener, Token::Listener), (&kill_evt, Token::Kill)])?;
+        let server_shared = shared.clone();
+        let server_thread = thread::Builder::new()
+            .name("vnc_server".to_string())
+            .spawn(move || run_server(listener, wait_ctx, server_shared))?;
+
+        Ok(DisplayVnc {
+            shared,
+            kill_evt,
+            server_thread: Some(server_thread),
+            scanout_surface: Default::default(),
+            imports: Default::default(),
+            current_event: None,
+            tracking_id: None,
+            next_tracking_id: 0,
+        })
+    }
+}
+
+impl Drop for DisplayVnc {
+    fn drop(&mut self) {
+        let mut state = self.shared.state.lock();
+        state.closed = true;
+        for client in state.clients.values() {
+            let _ = client.stream.shutdown(Shutdown::Both);
+        }
+        drop(state);
+        self.shared.changed.notify_all();
+
+        let _ = self.kill_evt.signal();
+        if let Some(server_thread) = self.server_thread.take() {
+            let _ = server_thread.join();
+        }
+    }
+}
+
+impl DisplayT for DisplayVnc {
+    fn pending_events(&self) -> bool {
+        !self.shared.input.lock().is_empty()
+    }
+
+    fn flush(&self) {
+        // Events queued after this are signaled again, so none of them can be missed.
+        let _ = self.shared.input_evt.reset();
+    }
+
+    fn next_event(&mut self) -> GpuDisplayResult<u64> {
+        self.current_event = self.shared.input.lock().pop_front();
+        // Surface ids start at 1, so events are dropped when nothing is shown.
+        Ok(self.scanout_surface.get().unwrap_or(0) as u64)
+    }
+
+    fn handle_next_event(
+        &mut self,
+        _surface: &mut Box<dyn GpuDisplaySurface>,
+    ) -> Option<GpuDisplayEvents> {
+        match self.current_event.take()? {
+            InputEvent::Key { keysym, down } => Some(GpuDisplayEvents {
+                events: vec![virtio_input_event::key(
+                    keysym_to_linux_keycode(keysym)?,
+                    down,
+                )],
+                device_type: EventDeviceKind::Keyboard,
+            }),
+            InputEvent::Pointer { x, y, buttons } => {
+                // Only the first button is forwarded, as a single finger touching the screen.
+                let pressed = buttons & 1 != 0;
+                let mut events = vec![virtio_input_event::multitouch_slot(0)];
+                match (self.tracking_id, pressed) {
+                    (None, false) => return None,
+                    (Some(_), false) => {
+                        self.tracking_id = None;
+                        events.push(virtio_input_event::multitouch_tracking_id(-1));
+                    }
+                    (tracking_id, true) => {
+                        let tracking_id = tracking_id.unwrap_or_else(|| {
+                            let id = self.next_tracking_id as i32;
+                            self.next_tracking_id = self.next_tracking_id.wrapping_add(1);
+                            id
+                        });
+                        self.tracking_id = Some(tracking_id);
+                        events.push(virtio_input_event::multitouch_tracking_id(tracking_id));
+                        events.push(virtio_input_event::multitouch_absolute_x(x as i32));
+                        events.push(virtio_input_event::multitouch_absolute_y(y as i32));
+                    }
+                }
+                Some(GpuDisplayEvents {
+                    events,
+                    device_type: EventDeviceKind::Touchscreen,
+                })
+            }
+        }
+    }
+
+    fn create_surface(
+        &mut self,
+        parent_surface_id: Option<u32>,
+        surface_id: u32,
+        width: u32,
+        height: u32,
+        surf_type: SurfaceType,
+    ) -> GpuDisplayResult<Box<dyn GpuDisplaySurface>> {
+        if width > u16::MAX as u32 || height > u16::MAX as u32 {
+            return Err(GpuDisplayError::CreateSurface);
+        }
+
+        if parent_surface_id.is_none()
+            && surf_type == SurfaceType::Scanout
+            && self.scanout_surface.get().is_none()
+        {
+            self.scanout_surface.set(Some(surface_id));
+            self.shared
+                .update_screen(|screen| screen.resize(width, height));
+        }
+
+        Ok(Box::new(VncSurface {
+            surface_id,
+            width,
+            height,
+            buffer: Vec::new(),
+            shared: self.shared.clone(),
+            scanout_surface: self.scanout_surface.clone(),
+            imports: self.imports.clone(),
+        }))
+    }
+
+    fn import_memory(
+        &mut self,
+        import_id: u32,
+        descriptor: &dyn AsRawDescriptor,
+        offset: u32,
+        stride: u32,
+        modifiers: u64,
+        width: u32,
+        height: u32,
+        fourcc: u32,
+    ) -> GpuDisplayResult<Box<dyn GpuDisplayImport>> {
+        let red_shift = match fourcc {
+            DRM_FORMAT_ARGB8888 | DRM_FORMAT_XRGB8888 => 16,
+            DRM_FORMAT_ABGR8888 | DRM_FORMAT_XBGR8888 => 0,
+            _ => return Err(GpuDisplayError::FailedImport),
+        };
+        if modifiers != DRM_FORMAT_MOD_LINEAR || height == 0 || (stride as u64) < width as u64 * 4 {
+            return Err(GpuDisplayError::FailedImport);
+        }
+        let size = offset as usize + stride as usize * height as usize;
+        let mapping = MemoryMappingBuilder::new(size)
+            .from_descriptor(descriptor)
+            .protection(Protection::read())
+            .build()
+            .map_err(|_| GpuDisplayError::FailedImport)?;
+
+        self.imports.borrow_mut().insert(
+            import_id,
+            ImportedBuffer {
+                mapping,
+                offset: offset as usize,
+                stride: stride as usize,
+                width,
+                height,
+                red_shift,
+            },
+        );
+        Ok(Box::new(VncImport {
+            import_id,
+            imports: self.imports.clone(),
+        }))
+    }
+}
+
+impl SysDisplayT for DisplayVnc {}
+
+impl AsRawDescriptor for DisplayVnc {
+    fn as_raw_descriptor(&self) -> RawDescriptor {
+        self.shared.input_evt.as_raw_descriptor()
+    }
+}
+
+fn run_server(listener: TcpListener, wait_ctx: WaitContext<Token>, shared: Arc<Shared>) {
+    loop {
+        let events = match wait_ctx.wait() {
+            Ok(events) => events,
+            Err(e) => {
+                error!("failed to wait for vnc clients: {}", e);
+                return;
+            }
+        };
+        for event in events.iter().filter(|e| e.is_readable) {
+            match event.token {
+                Token::Listener => loop {
+                    match listener.accept() {
+                        Ok((stream, addr)) => {
+                            info!("vnc client connected from {}", addr);
+                            let client_shared = shared.clone();
+                            if let Err(e) = thread::Builder::new()
+                                .name("vnc_client".to_string())
+                                .spawn(move || serve_client(stream, client_shared))
+                            {
+                                error!("failed to spawn vnc client thread: {}", e);
+                            }
+                        }
+                        Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                        Err(e) => {
+                            error!("failed to accept vnc client: {}", e);
+                            break;
+                        }
+                    }
+                },
+                Token::Kill => return,
+            }
+        }
+    }
+}
+
+fn serve_client(mut stream: TcpStream, shared: Arc<Shared>) {
+    let (id, width, height) = match handshake(&mut stream, &shared) {
+        Ok(Some(client)) => client,
+        Ok(None) => return,
+        Err(e) => {
+            error!("vnc handshake failed: {}", e);
+            return;
+        }
+    };
+
+    let writer_stream = match stream.try_clone() {
+        Ok(writer_stream) => writer_stream,
+        Err(e) => {
+            error!("failed to clone vnc client connection: {}", e);
+            shared.state.lock().clients.remove(&id);
+            return;
+        }
+    };
+    let writer_shared = shared.clone();
+    let writer = thread::Builder::new()
+        .name("vnc_client_writer".to_string())
+        .spawn(move || {
+            if let Err(e) = write_updates(id, writer_stream, &writer_shared, width, height) {
+                info!("vnc client disconnected: {}", e);
+            }
+            // Stop the reader.
+            if let Some(client) = writer_shared.state.lock().clients.get(&id) {
+                let _ = client.stream.shutdown(Shutdown::Both);
+            }
+        });
+    if let Err(e) = writer {
+        error!("failed to spawn vnc client writer thread: {}", e);
+        shared.state.lock().clients.remove(&id);
+        return;
+    }
+
+    let mut input = ClientInput::default();
+    if let Err(e) = read_messages(id, &mut stream, &shared, &mut input) {
+        if e.kind() != io::ErrorKind::UnexpectedEof {
+            info!("vnc client disconnected: {}", e);
+        }
+    }
+    input.release(&shared);
+
+    let _ = stream.shutdown(Shutdown::Both);
+    shared.state.lock().clients.remove(&id);
+    // Stop the writer.
+    shared.changed.notify_all();
+}
+
+/// Runs the handshake up to the initialization messages, and returns the id of the client and
+/// the size of the screen that it was told, or `None` if the display was dropped.
+fn handshake(stream: &mut TcpStream, shared: &Shared) -> io::Result<Option<(u64, u32, u32)>> {
+    stream.write_all(b"RFB 003.008\n")?;
+    let mut version = [0u8; 12];
+    stream.read_exact(&mut version)?;
+    let minor = parse_version(&version).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "unsupported protocol version {:?}",
+                String::from_utf8_lossy(&version)
+            ),
+        )
+    })?;
+
+    if minor >= 7 {
+        stream.write_all(&[1, SECURITY_NONE])?;
+        let mut security_type = [0u8; 1];
+        stream.read_exact(&mut security_type)?;
+        if security_type[0] != SECURITY_NONE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unsupported security type {}", security_type[0]),
+            ));
+        }
+        if minor >= 8 {
+            // SecurityResult: OK.
+            stream.write_all(&0u32.to_be_bytes())?;
+        }
+    } else {
+        stream.write_all(&(SECURITY_NONE as u32).to_be_bytes())?;
+    }
+
+    // ClientInit: the shared flag is ignored, all clients share the display.
+    let mut shared_flag = [0u8; 1];
+    stream.read_exact(&mut shared_flag)?;
+
+    let mut state = shared.state.lock();
+    if state.closed {
+        return Ok(None);
+    }
+    let id = state.next_client_id;
+    state.next_client_id += 1;
+    state.clients.insert(
+        id,
+        Client {
+            stream: stream.try_clone()?,
+            pixel_format: PixelFormat::SERVER,
+            desktop_size: false,
+            request: None,
+        },
+    );
+    let (width, height) = (state.screen.width, state.screen.height);
+    drop(state);
+
+    let mut server_init = Vec::new();
+    server_init.extend_from_slice(&(width as u16).to_be_bytes());
+    server_init.extend_from_slice(&(height as u16).to_be_bytes());
+    server_init.extend_from_slice(&PixelFormat::SERVER.to_bytes());
+    server_init.extend_from_slice(&(DESKTOP_NAME.len() as u32).to_be_bytes());
+    server_init.extend_from_slice(DESKTOP_NAME);
+    if let Err(e) = stream.write_all(&server_init) {
+        shared.state.lock().clients.remove(&id);
+        return Err(e);
+    }
+    Ok(Some((id, width, height)))
+}
+
+/// Returns the minor version of a ProtocolVersion message, as understood by the server.
+fn parse_version(version: &[u8; 12]) -> Option<u32> {
+    if &version[0..8] != b"RFB 003." || version[11] != b'\n' {
+        return None;
+    }
+    let minor: u32 = std::str::from_utf8(&version[8..11]).ok()?.parse().ok()?;
+    // Unknown versions are treated as the closest earlier one.
+    Some(match minor {
+        0..=2 => return None,
+        3..=6 => 3,
+        7 => 7,
+        _ => 8,
+    })
+}
+
+/// Keys and button held by a client, released when it disconnects.
+#[derive(Default)]
+struct ClientInput {
+    keysyms: BTreeSet<u32>,
+    pointer: Option<(u16, u16)>,
+}
+
+impl ClientInput {
+    fn release(&mut self, shared: &Shared) {
+        for keysym in std::mem::take(&mut self.keysyms) {
+            shared.push_input(InputEvent::Key {
+                keysym,
+                down: false,
+            });
+        }
+        if let Some((x, y)) = self.pointer.take() {
+            shared.push_input(InputEvent::Pointer { x, y, buttons: 0 });
+        }
+    }
+}
+
+fn read_messages(
+    id: u64,
+    stream: &mut TcpStream,
+    shared: &Shared,
+    input: &mut ClientInput,
+) -> io::Result<()> {
+    loop {
+        let mut message_type = [0u8; 1];
+        stream.read_exact(&mut message_type)?;
+        match message_type[0] {
+            CLIENT_SET_PIXEL_FORMAT => {
+                let mut message = [0u8; 19];
+                stream.read_exact(&mut message)?;
+                let pixel_format = PixelFormat::from_bytes(message[3..].try_into().unwrap());
+                if !pixel_format.is_supported() {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("unsupported pixel format {:?}", pixel_format),
+                    ));
+                }
+                if let Some(client) = shared.state.lock().clients.get_mut(&id) {
+                    client.pixel_format = pixel_format;
+                }
+            }
+            CLIENT_SET_ENCODINGS => {
+                let mut message = [0u8; 3];
+                stream.read_exact(&mut message)?;
+                let count = u16::from_be_bytes([message[1], message[2]]);
+                let mut desktop_size = false;
+                for _ in 0..count {
+                    let mut encoding = [0u8; 4];
+                    stream.read_exact(&mut encoding)?;
+                    desktop_size |= i32::from_be_bytes(encoding) == ENCODING_DESKTOP_SIZE;
+                }
+                if let Some(client) = shared.state.lock().clients.get_mut(&id) {
+                    client.desktop_size = desktop_size;
+                }
+            }
+            CLIENT_FRAMEBUFFER_UPDATE_REQUEST => {
+                let mut message = [0u8; 9];
+                stream.read_exact(&mut message)?;
+                let field = |i: usize| u16::from_be_bytes([message[i], message[i + 1]]) as u32;
+                let request = UpdateRequest {
+                    incremental: message[0] != 0,
+                    area: Rect {
+                        x: field(1),
+                        y: field(3),
+                        width: field(5),
+                        height: field(7),
+                    },
+                };
+                if let Some(client) = shared.state.lock().clients.get_mut(&id) {
+                    client.request = Some(request);
+                }
+                shared.changed.notify_all();
+            }
+            CLIENT_KEY_EVENT => {
+                let mut message = [0u8; 7];
+                stream.read_exact(&mut message)?;
+                let down = message[0] != 0;
+                let keysym = u32::from_be_bytes(message[3..7].try_into().unwrap());
+                if down {
+                    input.keysyms.insert(keysym);
+                } else {
+                    input.keysyms.remove(&keysym);
+                }
+                shared.push_input(InputEvent::Key { keysym, down });
+            }
+            CLIENT_POINTER_EVENT => {
+                let mut message = [0u8; 5];
+                stream.read_exact(&mut message)?;
+                let buttons = message[0];
+                let x = u16::from_be_bytes([message[1], message[2]]);
+                let y = u16::from_be_bytes([message[3], message[4]]);
+                input.pointer = if buttons & 1 != 0 { Some((x, y)) } else { None };
+                shared.push_input(InputEvent::Pointer { x, y, buttons });
+            }
+            CLIENT_CUT_TEXT => {
+                let mut message = [0u8; 7];
+                stream.read_exact(&mut message)?;
+                let len = u32::from_be_bytes(message[3..7].try_into().unwrap());
+                // The clipboard isn't shared with the guest.
+                io::copy(&mut (&mut *stream).take(len as u64), &mut io::sink())?;
+            }
+            t => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("unknown message type {}", t),
+                ))
+            }
+        }
+    }
+}
+
+/// Sends the updates requested by the client `id`, until it disconnects or the display is
+/// dropped. The client was told that the screen is `width`x`height`.
+fn write_updates(
+    id: u64,
+    stream: TcpStream,
+    shared: &Shared,
+    mut width: u32,
+    mut height: u32,
+) -> io::Result<()> {
+    let mut stream = BufWriter::new(stream);
+    // Contents of the screen as last sent to the client.
+    let mut sent: Vec<u32> = Vec::new();
+    let mut sent_frame = None;
+
+    loop {
+        let mut state = shared.state.lock();
+        let (mut request, pixel_format, desktop_size) = loop {
+            if state.closed {
+                return Ok(());
+            }
+            let frame = state.screen.frame;
+            let client = match state.clients.get_mut(&id) {
+                Some(client) => client,
+                None => return Ok(()),
+            };
+            match client.request {
+                Some(request) if !request.incremental || sent_frame != Some(frame) => {
+                    client.request = None;
+                    break (request, client.pixel_format, client.desktop_size);
+                }
+                _ => state = shared.changed.wait(state),
+            }
+        };
+        sent_frame = Some(state.screen.frame);
+        let screen = &state.screen;
+
+        let mut resized = false;
+        if (screen.width, screen.height) != (width, height) {
+            if !desktop_size {
+                return Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    "screen resized but the client doesn't support it",
+                ));
+            }
+            width = screen.width;
+            height = screen.height;
+            resized = true;
+        }
+        if resized || sent.len() != screen.pixels.len() {
+            // Everything has to be sent again.
+            sent = vec![0; screen.pixels.len()];
+            request = UpdateRequest {
+                incremental: false,
+                area: Rect {
+                    x: 0,
+                    y: 0,
+                    width,
+                    height,
+                },
+            };
+        }
+
+        let screen_area = Rect {
+            x: 0,
+            y: 0,
+            width,
+            height,
+        };
+        let mut rects = Vec::new();
+        if let Some(area) = request.area.intersect(&screen_area) {
+            if request.incremental {
+                rects = changed_tiles(&screen.pixels, &sent, width, &area);
+            } else {
+                rects.push(area);
+            }
+            // The number of rectangles of an update is 16-bit.
+            if rects.len() >= u16::MAX as usize {
+                rects = vec![area];
+            }
+            for rect in &rects {
+                for y in rect.y..rect.y + rect.height {
+                    let start = (y * width + rect.x) as usize;
+                    let end = start + rect.width as usize;
+                    sent[start..end].copy_from_slice(&screen.pixels[start..end]);
+                }
+            }
+        }
+        if rects.is_empty() && !resized {
+            // Nothing changed in the requested area: wait for the next change.
+            if let Some(client) = state.clients.get_mut(&id) {
+                client.request.get_or_insert(request);
+            }
+            continue;
+        }
+        drop(state);
+
+        let mut message = vec![SERVER_FRAMEBUFFER_UPDATE, 0];
+        message.extend_from_slice(&((rects.len() + resized as usize) as u16).to_be_bytes());
+        if resized {
+            write_rect_header(&mut message, &screen_area, ENCODING_DESKTOP_SIZE);
+        }
+        for rect in &rects {
+            write_rect_header(&mut message, rect, ENCODING_RAW);
+            for y in rect.y..rect.y + rect.height {
+                let start = (y * width + rect.x) as usize;
+                for &pixel in &sent[start..start + rect.width as usize] {
+                    pixel_format.write_pixel(pixel, &mut message);
+                }
+            }
+        }
+        stream.write_all(&message)?;
+        stream.flush()?;
+    }
+}
+
+fn write_rect_header(message: &mut Vec<u8>, rect: &Rect, encoding: i32) {
+    message.extend_from_slice(&(rect.x as u16).to_be_bytes());
+    message.extend_from_slice(&(rect.y as u16).to_be_bytes());
+    message.extend_from_slice(&(rect.width as u16).to_be_bytes());
+    message.extend_from_slice(&(rect.height as u16).to_be_bytes());
+    message.extend_from_slice(&encoding.to_be_bytes());
+}
+
+/// Returns the tiles of `area` whose pixels differ between `current` and `previous`, two screens
+/// of the given width.
+fn changed_tiles(current: &[u32], previous: &[u32], width: u32, area: &Rect) -> Vec<Rect> {
+    let mut tiles = Vec::new();
+    let mut tile_y = area.y;
+    while tile_y < area.y + area.height {
+        let tile_height = TILE_SIZE.min(area.y + area.height - tile_y);
+        let mut tile_x = area.x;
+        while tile_x < area.x + area.width {
+            let tile_width = TILE_SIZE.min(area.x + area.width - tile_x);
+            let changed = (tile_y..tile_y + tile_height).any(|y| {
+                let start = (y * width + tile_x) as usize;
+                let end = start + tile_width as usize;
+                current[start..end] != previous[start..end]
+            });
+            if changed {
+                tiles.push(Rect {
+                    x: tile_x,
+                    y: tile_y,
+                    width: tile_width,
+                    height: tile_height,
+                });
+            }
+            tile_x += tile_width;
+        }
+        tile_y += tile_height;
+    }
+    tiles
+}
+
+/// Converts an X11 keysym to the Linux keycode of the key producing it on a US keyboard.
+fn keysym_to_linux_keycode(keysym: u32) -> Option<u16> {
+    // Letters and symbols are sent with the keysym of their case or shift level, so both map to
+    // the same key.
+    let keycode = match keysym {
+        0x61..=0x7a | 0x41..=0x5a => {
+            const LETTERS: [u16; 26] = [
+                30, 48, 46, 32, 18, 33, 34, 35, 23, 36, 37, 38, 50, 49, 24, 25, 16, 19, 31, 20, 22,
+                47, 17, 45, 21, 44,
+            ];
+            LETTERS[((keysym | 0x20) - 0x61) as usize]
+        }
+        0x31..=0x39 => (keysym - 0x31 + 2) as u16,
+        0x30 | 0x29 => 11,     // 0 )
+        0x21 => 2,             // !
+        0x40 => 3,             // @
+        0x23 => 4,             // #
+        0x24 => 5,             // $
+        0x25 => 6,             // %
+        0x5e => 7,             // ^
+        0x26 => 8,             // &
+        0x2a => 9,             // *
+        0x28 => 10,            // (
+        0x2d | 0x5f => 12,     // - _
+        0x3d | 0x2b => 13,     // = +
+        0x5b | 0x7b => 26,     // [ {
+        0x5d | 0x7d => 27,     // ] }
+        0x3b | 0x3a => 39,     // ; :
+        0x27 | 0x22 => 40,     // ' "
+        0x60 | 0x7e => 41,     // ` ~
+        0x5c | 0x7c => 43,     // \ |
+        0x2c | 0x3c => 51,     // , <
+        0x2e | 0x3e => 52,     // . >
+        0x2f | 0x3f => 53,     // / ?
+        0x20 => 57,            // space
+        0xff08 => 14,          // BackSpace
+        0xff09 | 0xfe20 => 15, // Tab, ISO_Left_Tab
+        0xff0d => 28,          // Return
+        0xff13 => 119,         // Pause
+        0xff14 => 70,          // Scroll_Lock
+        0xff15 | 0xff61 => 99, // Sys_Req, Print
+        0xff1b => 1,           // Escape
+        0xff50 => 102,         // Home
+        0xff51 => 105,         // Left
+        0xff52 => 103,         // Up
+        0xff53 => 106,         // Right
+        0xff54 => 108,         // Down
+        0xff55 => 104,         // Page_Up
+        0xff56 => 109,         // Page_Down
+        0xff57 => 107,         // End
+        0xff63 => 110,         // Insert
+        0xff67 => 127,         // Menu
+        0xff7f => 69,          // Num_Lock
+        0xff8d => 96,          // KP_Enter
+        0xff95 | 0xffb7 => 71, // KP_Home, KP_7
+        0xff97 | 0xffb8 => 72, // KP_Up, KP_8
+        0xff9a | 0xffb9 => 73, // KP_Page_Up, KP_9
+        0xffad => 74,          // KP_Subtract
+        0xff96 | 0xffb4 => 75, // KP_Left, KP_4
+        0xff9d | 0xffb5 => 76, // KP_Begin, KP_5
+        0xff98 | 0xffb6 => 77, // KP_Right, KP_6
+        0xffab => 78,          // KP_Add
+        0xff9c | 0xffb1 => 79, // KP_End, KP_1
+        0xff99 | 0xffb2 => 80, // KP_Down, KP_2
+        0xff9b | 0xffb3 => 81, // KP_Page_Down, KP_3
+        0xff9e | 0xffb0 => 82, // KP_Insert, KP_0
+        0xff9f | 0xffae => 83, // KP_Delete, KP_Decimal
+        0xffaa => 55,          // KP_Multiply
+        0xffaf => 98,          // KP_Divide
+        0xffbd => 117,         // KP_Equal
+        // F1 to F10
+        0xffbe..=0xffc7 => (keysym - 0xffbe + 59) as u16,
+        0xffc8 => 87,           // F11
+        0xffc9 => 88,           // F12
+        0xffe1 => 42,           // Shift_L
+        0xffe2 => 54,           // Shift_R
+        0xffe3 => 29,           // Control_L
+        0xffe4 => 97,           // Control_R
+        0xffe5 => 58,           // Caps_Lock
+        0xffe7 | 0xffeb => 125, // Meta_L, Super_L
+        0xffe8 | 0xffec => 126, // Meta_R, Super_R
+        0xffe9 => 56,           // Alt_L
+        0xffea | 0xfe03 => 100, // Alt_R, ISO_Level3_Shift
+        0xffff => 111,          // Delete
+        _ => return None,
+    };
+    Some(keycode)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+
+    #[test]
+    fn convert_pixels() {
+        let pixel = 0x00_12_80_ff;
+
+        let mut out = Vec::new();
+        PixelFormat::SERVER.write_pixel(pixel, &mut out);
+        assert_eq!(out, [0xff, 0x80, 0x12, 0]);
+
+        // Big-endian RGB565.
+        let rgb565 = PixelFormat {
+            bits_per_pixel: 16,
+            depth: 16,
+            big_endian: true,
+            true_color: true,
+            red_max: 31,
+            green_max: 63,
+            blue_max: 31,
+            red_shift: 11,
+            green_shift: 5,
+            blue_shift: 0,
+        };
+        assert!(rgb565.is_supported());
+        let mut out = Vec::new();
+        rgb565.write_pixel(pixel, &mut out);
+        let value = (2 << 11) | (32 << 5) | 31;
+        assert_eq!(out, (value as u16).to_be_bytes());
+
+        assert_eq!(PixelFormat::from_bytes(&rgb565.to_bytes()), rgb565);
+        assert!(!PixelFormat {
+            true_color: false,
+            ..PixelFormat::SERVER
+        }
+        .is_supported());
+        assert!(!PixelFormat {
+            red_shift: 32,
+            ..PixelFormat::SERVER
+        }
+        .is_supported());
+    }
+
+    #[test]
+    fn keysyms() {
+        assert_eq!(keysym_to_linux_keycode(b'a' as u32), Some(30));
+        assert_eq!(keysym_to_linux_keycode(b'A' as u32), Some(30));
+        assert_eq!(keysym_to_linux_keycode(b'z' as u32), Some(44));
+        assert_eq!(keysym_to_linux_keycode(b'1' as u32), Some(2));
+        assert_eq!(keysym_to_linux_keycode(b'!' as u32), Some(2));
+        assert_eq!(keysym_to_linux_keycode(b'0' as u32), Some(11));
+        assert_eq!(keysym_to_linux_keycode(0xffc7), Some(68)); // F10
+        assert_eq!(keysym_to_linux_keycode(0xff0d), Some(28)); // Return
+        assert_eq!(keysym_to_linux_keycode(0x20ac), None); // EuroSign
+    }
+
+    #[test]
+    fn versions() {
+        assert_eq!(parse_version(b"RFB 003.008\n"), Some(8));
+        assert_eq!(parse_version(b"RFB 003.007\n"), Some(7));
+        assert_eq!(parse_version(b"RFB 003.003\n"), Some(3));
+        // Apple clients announce 3.889.
+        assert_eq!(parse_version(b"RFB 003.889\n"), Some(8));
+        assert_eq!(parse_version(b"RFB 004.000\n"), None);
+        assert_eq!(parse_version(b"HTTP/1.1 200"), None);
+    }
+
+    #[test]
+    fn tiles() {
+        let width = 100;
+        let current = vec![0u32; 100 * 70];
+        let mut previous = current.clone();
+        let area = Rect {
+            x: 0,
+            y: 0,
+            width: 100,
+            height: 70,
+        };
+        assert!(changed_tiles(&current, &previous, width, &area).is_empty());
+
+        previous[65 * 100 + 70] = 1;
+        assert_eq!(
+            changed_tiles(&current, &previous, width, &area),
+            [Rect {
+                x: 64,
+                y: 64,
+                width: 36,
+                height: 6,
+            }]
+        );
+    }
+
+    fn read_array<const N: usize>(stream: &mut TcpStream) -> [u8; N] {
+        let mut buf = [0u8; N];
+        stream.read_exact(&mut buf).unwrap();
+        buf
+    }
+
+    #[test]
+    fn serve_client() {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        listener.set_nonblocking(true).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut display = DisplayVnc::new(listener).unwrap();
+
+        let mut surface = display
+            .create_surface(None, 1, 2, 2, SurfaceType::Scanout)
+            .unwrap();
+        let framebuffer = surface.framebuffer().unwrap();
+        framebuffer.as_volatile_slice().copy_from(&[
+            0x00_ff_00_00u32,
+            0x00_00_ff_00,
+            0x00_00_00_ff,
+            0x00_12_34_56,
+        ]);
+        surface.flip();
+
+        let mut client = TcpStream::connect(addr).unwrap();
+        assert_eq!(&read_array::<12>(&mut client), b"RFB 003.008\n");
+        client.write_all(b"RFB 003.008\n").unwrap();
+        assert_eq!(read_array::<2>(&mut client), [1, SECURITY_NONE]);
+        client.write_all(&[SECURITY_NONE]).unwrap();
+        assert_eq!(read_array::<4>(&mut client), [0; 4]);
+        client.write_all(&[1]).unwrap();
+        let server_init = read_array::<24>(&mut client);
+        assert_eq!(server_init[0..4], [0, 2, 0, 2]);
+        assert_eq!(server_init[4..20], PixelFormat::SERVER.to_bytes());
+        assert_eq!(
+            read_array::<{ DESKTOP_NAME.len() }>(&mut client),
+            DESKTOP_NAME
+        );
+
+        client
+            .write_all(&[CLIENT_FRAMEBUFFER_UPDATE_REQUEST, 0, 0, 0, 0, 0, 0, 2, 0, 2])
+            .unwrap();
+        assert_eq!(
+            read_array::<4>(&mut client),
+            [SERVER_FRAMEBUFFER_UPDATE, 0, 0, 1]
+        );
+        assert_eq!(
+            read_array::<12>(&mut client),
+            [0, 0, 0, 0, 0, 2, 0, 2, 0, 0, 0, 0]
+        );
+        assert_eq!(
+            read_array::<16>(&mut client),
+            [0, 0, 0xff, 0, 0, 0xff, 0, 0, 0xff, 0, 0, 0, 0x56, 0x34, 0x12, 0]
+        );
+
+        // Key press of 'a'.
+        client
+            .write_all(&[CLIENT_KEY_EVENT, 1, 0, 0, 0, 0, 0, b'a'])
+            .unwrap();
+        display.shared.input_evt.wait().unwrap();
+        display.flush();
+        assert!(display.pending_events());
+        assert_eq!(display.next_event().unwrap(), 1);
+        let events = display.handle_next_event(&mut surface).unwrap();
+        assert_eq!(events.device_type, EventDeviceKind::Keyboard);
+        assert_eq!(events.events, [virtio_input_event::key(30, true)]);
+        assert!(!display.pending_events());
+
+        // The key is released when the client disconnects.
+        drop(client);
+        display.shared.input_evt.wait().unwrap();
+        assert_eq!(display.next_event().unwrap(), 1);
+        let events = display.handle_next_event(&mut surface).unwrap();
+        assert_eq!(events.events, [virtio_input_event::key(30, false)]);
+    }
+}
@@ -3,7 +3,7 @@
 // found in the LICENSE file.
 
 //! Crate for displaying simple surfaces and GPU buffers over a low-level display backend such as
-//! Wayland or X.
+//! Wayland, X or VNC.
 
 use std::collections::BTreeMap;
 use std::io::Error as IoError;
@@ -20,6 +20,8 @@ use thiserror::Error;
 
 mod event_device;
 mod gpu_display_stub;
+#[cfg(unix)]
+mod gpu_display_vnc;
 #[cfg(windows)]
 mod gpu_display_win;
 #[cfg(unix)]
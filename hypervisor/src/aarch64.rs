@@ -15,6 +15,8 @@ use gdbstub_arch::aarch64::AArch64 as GdbArch;
 use libc::EINVAL;
 use vm_memory::GuestAddress;
 
+#[cfg(feature = "gdb")]
+use crate::HwWatchpoint;
 use crate::Hypervisor;
 use crate::IrqRoute;
 use crate::IrqSource;
@@ -101,8 +103,14 @@ pub trait VcpuAArch64: Vcpu {
     fn get_psci_version(&self) -> Result<PsciVersion>;
 
     #[cfg(feature = "gdb")]
-    /// Sets up debug registers and configure vcpu for handling guest debug events.
-    fn set_guest_debug(&self, addrs: &[GuestAddress], enable_singlestep: bool) -> Result<()>;
+    /// Sets up debug registers and configure vcpu for handling guest debug events, with hardware
+    /// breakpoints at `addrs` and the hardware `watchpoints`.
+    fn set_guest_debug(
+        &self,
+        addrs: &[GuestAddress],
+        watchpoints: &[HwWatchpoint],
+        enable_singlestep: bool,
+    ) -> Result<()>;
 
     #[cfg(feature = "gdb")]
     /// Sets the VCPU general registers used by GDB 'G' packets.
@@ -116,6 +124,10 @@ pub trait VcpuAArch64: Vcpu {
     /// Gets the max number of hardware breakpoints.
     fn get_max_hw_bps(&self) -> Result<usize>;
 
+    #[cfg(feature = "gdb")]
+    /// Gets the max number of hardware watchpoints.
+    fn get_max_hw_wps(&self) -> Result<usize>;
+
     #[cfg(feature = "gdb")]
     /// Sets the value of a single register on this VCPU.
     fn set_gdb_register(&self, reg: <GdbArch as Arch>::RegId, data: &[u8]) -> Result<()>;
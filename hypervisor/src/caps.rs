@@ -45,6 +45,8 @@ pub enum VmCap {
     Protected,
     /// VM completes initialization of CPUID at creation time, not required after.
     EarlyInitCpuid,
+    /// Track dirty pages with per-vcpu rings instead of bitmaps
+    DirtyRing,
     /// VM can detect the bus lock
     #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
     BusLockDetect,
@@ -33,9 +33,11 @@ use crate::get_tsc_offset_from_msr;
 use crate::set_tsc_offset_via_msr;
 use crate::CpuId;
 use crate::CpuIdEntry;
+use crate::DebugExit;
 use crate::DebugRegs;
 use crate::DescriptorTable;
 use crate::Fpu;
+use crate::HwWatchpoint;
 use crate::HypervHypercall;
 use crate::IoOperation;
 use crate::IoParams;
@@ -44,9 +46,11 @@ use crate::Regs;
 use crate::Segment;
 use crate::Sregs;
 use crate::Vcpu;
+use crate::VcpuEvents;
 use crate::VcpuExit;
 use crate::VcpuRunHandle;
 use crate::VcpuX86_64;
+use crate::Xsave;
 
 // HAXM exit reasons
 // IO port request
@@ -367,7 +371,7 @@ impl Vcpu for HaxmVcpu {
             HAX_EXIT_STATECHANGE => Ok(VcpuExit::Shutdown),
             HAX_EXIT_FAST_MMIO => Ok(VcpuExit::Mmio),
             HAX_EXIT_PAGEFAULT => Ok(VcpuExit::Exception),
-            HAX_EXIT_DEBUG => Ok(VcpuExit::Debug),
+            HAX_EXIT_DEBUG => Ok(VcpuExit::Debug(DebugExit::Unknown)),
             HAX_EXIT_PAUSED => Ok(VcpuExit::Exception),
             r => panic!("unknown exit reason: {}", r),
         }
@@ -494,6 +498,26 @@ impl VcpuX86_64 for HaxmVcpu {
         Err(Error::new(libc::ENXIO))
     }
 
+    fn get_xsave(&self) -> Result<Xsave> {
+        // Haxm support for the XSAVE area isn't implemented.
+        Err(Error::new(libc::ENXIO))
+    }
+
+    fn set_xsave(&self, _xsave: &Xsave) -> Result<()> {
+        // Haxm support for the XSAVE area isn't implemented.
+        Err(Error::new(libc::ENXIO))
+    }
+
+    fn get_vcpu_events(&self) -> Result<VcpuEvents> {
+        // Haxm support for the VCPU events isn't implemented.
+        Err(Error::new(libc::ENXIO))
+    }
+
+    fn set_vcpu_events(&self, _events: &VcpuEvents) -> Result<()> {
+        // Haxm support for the VCPU events isn't implemented.
+        Err(Error::new(libc::ENXIO))
+    }
+
     /// Gets the model-specific registers.  `msrs` specifies the MSR indexes to be queried, and
     /// on success contains their indexes and values.
     fn get_msrs(&self, msrs: &mut Vec<Register>) -> Result<()> {
@@ -591,7 +615,12 @@ impl VcpuX86_64 for HaxmVcpu {
         Err(Error::new(libc::ENXIO))
     }
 
-    fn set_guest_debug(&self, _addrs: &[GuestAddress], _enable_singlestep: bool) -> Result<()> {
+    fn set_guest_debug(
+        &self,
+        _addrs: &[GuestAddress],
+        _watchpoints: &[HwWatchpoint],
+        _enable_singlestep: bool,
+    ) -> Result<()> {
         // TODO(b/173807302): Implement this
         Err(Error::new(ENOENT))
     }
@@ -41,7 +41,10 @@ use super::KvmCap;
 use super::KvmVcpu;
 use super::KvmVm;
 use crate::ClockState;
+use crate::DebugExit;
 use crate::DeviceKind;
+#[cfg(feature = "gdb")]
+use crate::HwWatchpoint;
 use crate::Hypervisor;
 use crate::IrqSourceChip;
 use crate::ProtectionType;
@@ -52,6 +55,8 @@ use crate::VcpuFeature;
 use crate::VcpuRegAArch64;
 use crate::VmAArch64;
 use crate::VmCap;
+#[cfg(feature = "gdb")]
+use crate::WatchpointKind;
 use crate::PSCI_0_2;
 
 impl Kvm {
@@ -232,6 +237,22 @@ impl KvmVcpu {
         Ok(VcpuExit::SystemEventReset)
     }
 
+    /// Decodes the cause of a `KVM_EXIT_DEBUG` from the exception syndrome.
+    pub fn debug_exit(&self, arch: &kvm_debug_exit_arch) -> Result<DebugExit> {
+        // ESR_ELx.EC, bits [31:26]: Exception class
+        Ok(match arch.hsr >> 26 {
+            // Software step from a lower exception level.
+            0x32 => DebugExit::SingleStep,
+            // Breakpoint from a lower exception level, or BRK instruction.
+            0x30 | 0x3c => DebugExit::Breakpoint,
+            // Watchpoint from a lower exception level, FAR holds the accessed address.
+            0x34 => DebugExit::Watchpoint {
+                addr: GuestAddress(arch.far),
+            },
+            _ => DebugExit::Unknown,
+        })
+    }
+
     fn set_one_kvm_reg_u64(&self, kvm_reg_id: KvmVcpuRegister, data: u64) -> Result<()> {
         self.set_one_kvm_reg(kvm_reg_id, data.to_ne_bytes().as_slice())
     }
@@ -685,9 +706,32 @@ impl VcpuAArch64 for KvmVcpu {
         }
     }
 
+    #[cfg(feature = "gdb")]
+    fn get_max_hw_wps(&self) -> Result<usize> {
+        // Safe because the kernel will only return the result of the ioctl.
+        let max_hw_wps = unsafe {
+            ioctl_with_val(
+                &self.vm,
+                KVM_CHECK_EXTENSION(),
+                KVM_CAP_GUEST_DEBUG_HW_WPS.into(),
+            )
+        };
+
+        if max_hw_wps < 0 {
+            errno_result()
+        } else {
+            Ok(max_hw_wps.try_into().expect("can't represent u64 as usize"))
+        }
+    }
+
     #[cfg(feature = "gdb")]
     #[allow(clippy::unusual_byte_groupings)]
-    fn set_guest_debug(&self, addrs: &[GuestAddress], enable_singlestep: bool) -> Result<()> {
+    fn set_guest_debug(
+        &self,
+        addrs: &[GuestAddress],
+        watchpoints: &[HwWatchpoint],
+        enable_singlestep: bool,
+    ) -> Result<()> {
         let mut dbg = kvm_guest_debug {
             control: KVM_GUESTDBG_ENABLE,
             ..Default::default()
@@ -696,9 +740,12 @@ impl VcpuAArch64 for KvmVcpu {
         if enable_singlestep {
             dbg.control |= KVM_GUESTDBG_SINGLESTEP;
         }
-        if !addrs.is_empty() {
+        if !addrs.is_empty() || !watchpoints.is_empty() {
             dbg.control |= KVM_GUESTDBG_USE_HW;
         }
+        if addrs.len() > dbg.arch.dbg_bvr.len() || watchpoints.len() > dbg.arch.dbg_wvr.len() {
+            return Err(Error::new(EINVAL));
+        }
 
         for (i, guest_addr) in addrs.iter().enumerate() {
             // From the ARMv8 Architecture Reference Manual (DDI0487H.a) D31.3.{2,3}:
@@ -722,6 +769,35 @@ impl VcpuAArch64 for KvmVcpu {
             dbg.arch.dbg_bcr[i] = 0b1111_11_1;
         }
 
+        for (i, watchpoint) in watchpoints.iter().enumerate() {
+            // A watchpoint covers bytes of a single aligned doubleword.
+            let offset = watchpoint.addr.0 & 0b111;
+            if watchpoint.len == 0 || offset + watchpoint.len > 8 {
+                return Err(Error::new(EINVAL));
+            }
+            let sign_ext = 15;
+            // From the ARMv8 Architecture Reference Manual (DDI0487H.a) D13.3.{11,12}:
+            //      DBGWVR<n>_EL1, Bits [2:0]: Reserved, RES0
+            //      DBGWVR<n>_EL1.RESS[14:4], bits [63:53]: Reserved, Sign extended
+            dbg.arch.dbg_wvr[i] =
+                ((((watchpoint.addr.0 & !0b111) << sign_ext) as i64) >> sign_ext) as u64;
+            // DBGWCR<n>_EL1.BAS, bits [12:5]: Byte address select
+            //      One bit per watched byte of the doubleword.
+            let bas = ((1u64 << watchpoint.len) - 1) << offset;
+            // DBGWCR<n>_EL1.LSC, bits [4:3]: Load/store control
+            //      0b01: Loads, 0b10: Stores, 0b11: Loads and stores
+            let lsc = match watchpoint.kind {
+                WatchpointKind::Read => 0b01,
+                WatchpointKind::Write => 0b10,
+                WatchpointKind::ReadWrite => 0b11,
+            };
+            // DBGWCR<n>_EL1.PAC, bits [2:1]: Privilege of access control
+            //      0b11: EL1 & EL0
+            // DBGWCR<n>_EL1.E, bit [0]: Enable watchpoint
+            //      0b1: Enabled
+            dbg.arch.dbg_wcr[i] = bas << 5 | lsc << 3 | 0b11_1;
+        }
+
         // Safe because the kernel won't read past the end of the kvm_guest_debug struct.
         let ret = unsafe { ioctl_with_ref(self, KVM_SET_GUEST_DEBUG(), &dbg) };
         if ret == 0 {
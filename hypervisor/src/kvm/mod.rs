@@ -30,7 +30,9 @@ use std::os::unix::prelude::OsStrExt;
 use std::path::Path;
 use std::path::PathBuf;
 use std::ptr::copy_nonoverlapping;
+use std::sync::atomic::AtomicU32;
 use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
 
 use base::errno_result;
@@ -59,6 +61,7 @@ use kvm_sys::*;
 use libc::open64;
 use libc::sigset_t;
 use libc::EBUSY;
+use libc::EEXIST;
 use libc::EFAULT;
 use libc::EINVAL;
 use libc::EIO;
@@ -139,6 +142,79 @@ pub fn dirty_log_bitmap_size(size: usize) -> usize {
     (((size + page_size - 1) / page_size) + 7) / 8
 }
 
+// Flags of a `kvm_dirty_gfn`, which KVM sets to DIRTY when it publishes the entry and expects to be
+// set to RESET once the entry was harvested.
+const KVM_DIRTY_GFN_F_DIRTY: u32 = 1 << 0;
+const KVM_DIRTY_GFN_F_RESET: u32 = 1 << 1;
+
+/// An entry of a vcpu dirty ring, only defined in the bindings of some architectures.
+#[repr(C)]
+struct kvm_dirty_gfn {
+    flags: u32,
+    slot: u32,
+    offset: u64,
+}
+
+/// The dirty rings of the vcpus of a VM, along with the dirty pages harvested from them and not yet
+/// returned by `get_dirty_log`.
+#[derive(Default)]
+struct DirtyRings {
+    /// Number of entries of each ring, 0 when the VM tracks dirty pages with bitmaps.
+    entries: u32,
+    rings: Vec<DirtyRing>,
+    /// Harvested dirty pages of each slot, as bitmaps of 64-page words.
+    pending: BTreeMap<MemSlot, Vec<u64>>,
+}
+
+struct DirtyRing {
+    mmap: MemoryMapping,
+    /// Count of harvested entries, the index of the next one modulo the ring size.
+    next: u32,
+}
+
+impl DirtyRings {
+    /// Moves the entries published by KVM in every ring to `pending`, then lets KVM reuse them.
+    fn harvest(&mut self, vm: &SafeDescriptor) -> Result<()> {
+        let mut harvested = false;
+        for ring in &mut self.rings {
+            loop {
+                let index = (ring.next % self.entries) as usize;
+                // Safe because the ring mapping holds `entries` entries, which are aligned since
+                // the mapping is page aligned.
+                #[allow(clippy::cast_ptr_alignment)]
+                let gfn = unsafe { &*(ring.mmap.as_ptr() as *const kvm_dirty_gfn).add(index) };
+                // Safe because `flags` is the first field of the aligned entry, and KVM only
+                // accesses it atomically.
+                let flags = unsafe { &*(&gfn.flags as *const u32 as *const AtomicU32) };
+                // Acquire orders the reads of the entry after KVM published it.
+                if flags.load(Ordering::Acquire) & KVM_DIRTY_GFN_F_DIRTY == 0 {
+                    break;
+                }
+                // The upper 16 bits of the slot are the address space, always 0 for guest memory.
+                let slot = gfn.slot & 0xffff;
+                let page = gfn.offset as usize;
+                let bitmap = self.pending.entry(slot).or_default();
+                if bitmap.len() <= page / 64 {
+                    bitmap.resize(page / 64 + 1, 0);
+                }
+                bitmap[page / 64] |= 1 << (page % 64);
+                // Release orders the reads of the entry before KVM may reuse it.
+                flags.store(KVM_DIRTY_GFN_F_RESET, Ordering::Release);
+                ring.next = ring.next.wrapping_add(1);
+                harvested = true;
+            }
+        }
+        if harvested {
+            // Safe because we know that our file is a VM fd and we verify the return result.
+            let ret = unsafe { ioctl(vm, KVM_RESET_DIRTY_RINGS()) };
+            if ret < 0 {
+                return errno_result();
+            }
+        }
+        Ok(())
+    }
+}
+
 pub struct Kvm {
     kvm: SafeDescriptor,
 }
@@ -209,6 +285,7 @@ pub struct KvmVm {
     mem_regions: Arc<Mutex<BTreeMap<MemSlot, Box<dyn MappedRegion>>>>,
     /// A min heap of MemSlot numbers that were used and then removed and can now be re-used
     mem_slot_gaps: Arc<Mutex<BinaryHeap<Reverse<MemSlot>>>>,
+    dirty_rings: Arc<Mutex<DirtyRings>>,
 }
 
 impl KvmVm {
@@ -249,6 +326,7 @@ impl KvmVm {
             guest_mem,
             mem_regions: Arc::new(Mutex::new(BTreeMap::new())),
             mem_slot_gaps: Arc::new(Mutex::new(BinaryHeap::new())),
+            dirty_rings: Default::default(),
         };
         vm.init_arch(&cfg)?;
         Ok(vm)
@@ -272,12 +350,27 @@ impl KvmVm {
             .build()
             .map_err(|_| Error::new(ENOSPC))?;
 
+        let mut dirty_rings = self.dirty_rings.lock();
+        if dirty_rings.entries > 0 {
+            let mmap = MemoryMappingBuilder::new(
+                dirty_rings.entries as usize * size_of::<kvm_dirty_gfn>(),
+            )
+            .from_descriptor(&vcpu)
+            .offset(KVM_DIRTY_LOG_PAGE_OFFSET as u64 * pagesize() as u64)
+            .build()
+            .map_err(|_| Error::new(ENOSPC))?;
+            dirty_rings.rings.push(DirtyRing { mmap, next: 0 });
+        }
+
         Ok(KvmVcpu {
             vm: self.vm.try_clone()?,
             vcpu,
             id,
             run_mmap,
             vcpu_run_handle_fingerprint: Default::default(),
+            dirty_rings: self.dirty_rings.clone(),
+            #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+            guest_debug: Default::default(),
         })
     }
 
@@ -454,6 +547,8 @@ impl KvmVm {
                     false
                 }
             }
+            // The extension returns the maximum size of the rings in bytes.
+            KvmCap::DirtyLogRing => ret > 0,
             _ => ret == 1,
         }
     }
@@ -497,6 +592,7 @@ impl Vm for KvmVm {
             guest_mem: self.guest_mem.clone(),
             mem_regions: self.mem_regions.clone(),
             mem_slot_gaps: self.mem_slot_gaps.clone(),
+            dirty_rings: self.dirty_rings.clone(),
         })
     }
 
@@ -510,6 +606,7 @@ impl Vm for KvmVm {
             VmCap::PvClockSuspend => self.check_raw_capability(KvmCap::KvmclockCtrl),
             VmCap::Protected => self.check_raw_capability(KvmCap::ArmProtectedVm),
             VmCap::EarlyInitCpuid => false,
+            VmCap::DirtyRing => self.check_raw_capability(KvmCap::DirtyLogRing),
             #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
             VmCap::BusLockDetect => self.check_raw_capability(KvmCap::BusLockDetect),
         }
@@ -641,12 +738,31 @@ impl Vm for KvmVm {
 
     fn get_dirty_log(&self, slot: MemSlot, dirty_log: &mut [u8]) -> Result<()> {
         let regions = self.mem_regions.lock();
-        let mmap = regions.get(&slot).ok_or_else(|| Error::new(ENOENT))?;
+        let size = match regions.get(&slot) {
+            Some(mmap) => mmap.size(),
+            None => self
+                .guest_mem
+                .guest_memory_regions()
+                .get(slot as usize)
+                .map(|&(_, size)| size)
+                .ok_or_else(|| Error::new(ENOENT))?,
+        };
         // Ensures that there are as many bytes in dirty_log as there are pages in the mmap.
-        if dirty_log_bitmap_size(mmap.size()) > dirty_log.len() {
+        if dirty_log_bitmap_size(size) > dirty_log.len() {
             return Err(Error::new(EINVAL));
         }
 
+        let mut dirty_rings = self.dirty_rings.lock();
+        if dirty_rings.entries > 0 {
+            dirty_rings.harvest(&self.vm)?;
+            let bitmap = dirty_rings.pending.remove(&slot).unwrap_or_default();
+            dirty_log.fill(0);
+            for (chunk, word) in dirty_log.chunks_mut(8).zip(bitmap) {
+                chunk.copy_from_slice(&word.to_le_bytes()[..chunk.len()]);
+            }
+            return Ok(());
+        }
+
         let mut dirty_log_kvm = kvm_dirty_log {
             slot,
             ..Default::default()
@@ -662,6 +778,46 @@ impl Vm for KvmVm {
         }
     }
 
+    fn set_memory_dirty_log(&mut self, enable: bool) -> Result<()> {
+        self.guest_mem
+            .with_regions(|index, guest_addr, size, host_addr, _, _| {
+                // Safe because the guest regions are the ones the VM was created with, only with
+                // different flags.
+                unsafe {
+                    set_user_memory_region(
+                        &self.vm,
+                        index as MemSlot,
+                        false,
+                        enable,
+                        guest_addr.offset(),
+                        size as u64,
+                        host_addr as *mut u8,
+                    )
+                }
+            })?;
+
+        // Start the next log from a clean state, as KVM does with the bitmaps.
+        let mut dirty_rings = self.dirty_rings.lock();
+        if dirty_rings.entries > 0 {
+            dirty_rings.harvest(&self.vm)?;
+            dirty_rings.pending.clear();
+        }
+        Ok(())
+    }
+
+    fn enable_dirty_ring(&mut self, entries: u32) -> Result<()> {
+        let mut dirty_rings = self.dirty_rings.lock();
+        if dirty_rings.entries > 0 {
+            return Err(Error::new(EEXIST));
+        }
+        let args = [entries as u64 * size_of::<kvm_dirty_gfn>() as u64, 0, 0, 0];
+        // Safe because the argument of KVM_CAP_DIRTY_LOG_RING is the size of the rings, not a
+        // pointer.
+        unsafe { self.enable_raw_capability(KvmCap::DirtyLogRing, 0, &args) }?;
+        dirty_rings.entries = entries;
+        Ok(())
+    }
+
     fn register_ioevent(
         &mut self,
         evt: &Event,
@@ -750,6 +906,10 @@ pub struct KvmVcpu {
     id: usize,
     run_mmap: MemoryMapping,
     vcpu_run_handle_fingerprint: Arc<AtomicU64>,
+    dirty_rings: Arc<Mutex<DirtyRings>>,
+    /// Debug registers set by the last `set_guest_debug`, which tell the causes of debug exits.
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    guest_debug: Arc<Mutex<kvm_guest_debug_arch>>,
 }
 
 pub(super) struct VcpuThread {
@@ -775,6 +935,9 @@ impl Vcpu for KvmVcpu {
             id: self.id,
             run_mmap,
             vcpu_run_handle_fingerprint,
+            dirty_rings: self.dirty_rings.clone(),
+            #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+            guest_debug: self.guest_debug.clone(),
         })
     }
 
@@ -955,7 +1118,12 @@ impl Vcpu for KvmVcpu {
             KVM_EXIT_UNKNOWN => Ok(VcpuExit::Unknown),
             KVM_EXIT_EXCEPTION => Ok(VcpuExit::Exception),
             KVM_EXIT_HYPERCALL => Ok(VcpuExit::Hypercall),
-            KVM_EXIT_DEBUG => Ok(VcpuExit::Debug),
+            KVM_EXIT_DEBUG => {
+                // Safe because the exit_reason (which comes from the kernel) told us which
+                // union field to use.
+                let arch = unsafe { run.__bindgen_anon_1.debug.arch };
+                Ok(VcpuExit::Debug(self.debug_exit(&arch)?))
+            }
             KVM_EXIT_HLT => Ok(VcpuExit::Hlt),
             KVM_EXIT_IRQ_WINDOW_OPEN => Ok(VcpuExit::IrqWindowOpen),
             KVM_EXIT_SHUTDOWN => Ok(VcpuExit::Shutdown),
@@ -1024,6 +1192,10 @@ impl Vcpu for KvmVcpu {
                 Ok(VcpuExit::WrMsr { index, data })
             }
             KVM_EXIT_X86_BUS_LOCK => Ok(VcpuExit::BusLock),
+            KVM_EXIT_DIRTY_RING_FULL => {
+                self.dirty_rings.lock().harvest(&self.vm)?;
+                Ok(VcpuExit::DirtyRingFull)
+            }
             #[cfg(target_arch = "riscv64")]
             KVM_EXIT_RISCV_SBI => {
                 // Safe because we trust the kernel to correctly fill in the union
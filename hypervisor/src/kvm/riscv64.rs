@@ -15,6 +15,7 @@ use super::Kvm;
 use super::KvmVcpu;
 use super::KvmVm;
 use crate::ClockState;
+use crate::DebugExit;
 use crate::DeviceKind;
 use crate::Hypervisor;
 use crate::IrqSourceChip;
@@ -110,6 +111,11 @@ impl KvmVcpu {
     pub fn system_event_reset(&self, _event_flags: u64) -> Result<VcpuExit> {
         Ok(VcpuExit::SystemEventReset)
     }
+
+    /// Decodes the cause of a `KVM_EXIT_DEBUG`, which carries no details on RISC-V.
+    pub fn debug_exit(&self, _arch: &kvm_debug_exit_arch) -> Result<DebugExit> {
+        Ok(DebugExit::Unknown)
+    }
 }
 
 impl VcpuRiscv64 for KvmVcpu {
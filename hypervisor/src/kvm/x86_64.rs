@@ -33,10 +33,12 @@ use crate::set_tsc_offset_via_msr;
 use crate::ClockState;
 use crate::CpuId;
 use crate::CpuIdEntry;
+use crate::DebugExit;
 use crate::DebugRegs;
 use crate::DescriptorTable;
 use crate::DeviceKind;
 use crate::Fpu;
+use crate::HwWatchpoint;
 use crate::HypervisorX86_64;
 use crate::IoapicRedirectionTableEntry;
 use crate::IoapicState;
@@ -51,10 +53,17 @@ use crate::Register;
 use crate::Regs;
 use crate::Segment;
 use crate::Sregs;
+use crate::VcpuEvents;
+use crate::VcpuExceptionState;
 use crate::VcpuExit;
+use crate::VcpuInterruptState;
+use crate::VcpuNmiState;
+use crate::VcpuSmiState;
 use crate::VcpuX86_64;
 use crate::VmCap;
 use crate::VmX86_64;
+use crate::WatchpointKind;
+use crate::Xsave;
 use crate::MAX_IOAPIC_PINS;
 use crate::NUM_IOAPIC_PINS;
 
@@ -527,6 +536,29 @@ impl KvmVcpu {
     pub fn system_event_reset(&self, _event_flags: u64) -> Result<VcpuExit> {
         Ok(VcpuExit::SystemEventReset)
     }
+
+    /// Decodes the cause of a `KVM_EXIT_DEBUG` from the debug status register.
+    pub fn debug_exit(&self, arch: &kvm_debug_exit_arch) -> Result<DebugExit> {
+        // DR6.BS: single step.
+        if arch.dr6 & (1 << 14) != 0 {
+            return Ok(DebugExit::SingleStep);
+        }
+        // DR6.B0-B3: the debug registers that matched. A watchpoint trap can be reported along
+        // with a breakpoint on the next instruction, which will trap again once resumed.
+        let debugreg = self.guest_debug.lock().debugreg;
+        let (watchpoints, breakpoints): (Vec<usize>, Vec<usize>) = (0..4)
+            .filter(|i| arch.dr6 & (1 << i) != 0)
+            .partition(|i| (debugreg[7] >> (16 + i * 4)) & 0b11 != 0);
+        if let Some(&i) = watchpoints.first() {
+            Ok(DebugExit::Watchpoint {
+                addr: GuestAddress(debugreg[i]),
+            })
+        } else if !breakpoints.is_empty() {
+            Ok(DebugExit::Breakpoint)
+        } else {
+            Ok(DebugExit::Unknown)
+        }
+    }
 }
 
 impl VcpuX86_64 for KvmVcpu {
@@ -723,6 +755,60 @@ impl VcpuX86_64 for KvmVcpu {
         }
     }
 
+    fn get_xsave(&self) -> Result<Xsave> {
+        // Safe because we know that our file is a VCPU fd, we know the kernel will only write the
+        // correct amount of memory to our pointer, and we verify the return result.
+        let mut xsave: kvm_xsave = Default::default();
+        let ret = unsafe { ioctl_with_mut_ref(self, KVM_GET_XSAVE(), &mut xsave) };
+        if ret == 0 {
+            Ok(Xsave {
+                region: xsave.region,
+            })
+        } else {
+            errno_result()
+        }
+    }
+
+    fn set_xsave(&self, xsave: &Xsave) -> Result<()> {
+        let xsave = kvm_xsave {
+            region: xsave.region,
+        };
+        let ret = unsafe {
+            // Here we trust the kernel not to read past the end of the kvm_xsave struct.
+            ioctl_with_ref(self, KVM_SET_XSAVE(), &xsave)
+        };
+        if ret == 0 {
+            Ok(())
+        } else {
+            errno_result()
+        }
+    }
+
+    fn get_vcpu_events(&self) -> Result<VcpuEvents> {
+        // Safe because we know that our file is a VCPU fd, we know the kernel will only write the
+        // correct amount of memory to our pointer, and we verify the return result.
+        let mut events: kvm_vcpu_events = Default::default();
+        let ret = unsafe { ioctl_with_mut_ref(self, KVM_GET_VCPU_EVENTS(), &mut events) };
+        if ret == 0 {
+            Ok(VcpuEvents::from(&events))
+        } else {
+            errno_result()
+        }
+    }
+
+    fn set_vcpu_events(&self, events: &VcpuEvents) -> Result<()> {
+        let events = kvm_vcpu_events::from(events);
+        let ret = unsafe {
+            // Here we trust the kernel not to read past the end of the kvm_vcpu_events struct.
+            ioctl_with_ref(self, KVM_SET_VCPU_EVENTS(), &events)
+        };
+        if ret == 0 {
+            Ok(())
+        } else {
+            errno_result()
+        }
+    }
+
     fn get_msrs(&self, vec: &mut Vec<Register>) -> Result<()> {
         let msrs = to_kvm_msrs(vec);
         let ret = unsafe {
@@ -779,14 +865,20 @@ impl VcpuX86_64 for KvmVcpu {
         get_cpuid_with_initial_capacity(self, KVM_GET_SUPPORTED_HV_CPUID(), KVM_MAX_ENTRIES)
     }
 
-    fn set_guest_debug(&self, addrs: &[GuestAddress], enable_singlestep: bool) -> Result<()> {
+    fn set_guest_debug(
+        &self,
+        addrs: &[GuestAddress],
+        watchpoints: &[HwWatchpoint],
+        enable_singlestep: bool,
+    ) -> Result<()> {
         use kvm_sys::*;
         let mut dbg: kvm_guest_debug = Default::default();
 
-        if addrs.len() > 4 {
+        // Breakpoints and watchpoints share the 4 debug address registers.
+        if addrs.len() + watchpoints.len() > 4 {
             error!(
-                "Support 4 breakpoints at most but {} addresses are passed",
-                addrs.len()
+                "Support 4 breakpoints and watchpoints at most but {} are passed",
+                addrs.len() + watchpoints.len()
             );
             return Err(base::Error::new(libc::EINVAL));
         }
@@ -807,11 +899,37 @@ impl VcpuX86_64 for KvmVcpu {
             dbg.arch.debugreg[7] |= 2 << (i * 2);
         }
 
+        for (i, watchpoint) in watchpoints
+            .iter()
+            .enumerate()
+            .map(|(i, w)| (i + addrs.len(), w))
+        {
+            // The watched bytes must be naturally aligned.
+            let len = match watchpoint.len {
+                1 => 0b00,
+                2 => 0b01,
+                8 => 0b10,
+                4 => 0b11,
+                _ => return Err(base::Error::new(libc::EINVAL)),
+            };
+            if watchpoint.addr.0 % watchpoint.len != 0 {
+                return Err(base::Error::new(libc::EINVAL));
+            }
+            // x86 can't break on reads only, so read watchpoints break on writes too.
+            let rw = match watchpoint.kind {
+                WatchpointKind::Write => 0b01,
+                WatchpointKind::Read | WatchpointKind::ReadWrite => 0b11,
+            };
+            dbg.arch.debugreg[i] = watchpoint.addr.0;
+            dbg.arch.debugreg[7] |= 2 << (i * 2) | (rw | len << 2) << (16 + i * 4);
+        }
+
         let ret = unsafe {
             // Here we trust the kernel not to read past the end of the kvm_guest_debug struct.
             ioctl_with_ref(self, KVM_SET_GUEST_DEBUG(), &dbg)
         };
         if ret == 0 {
+            *self.guest_debug.lock() = dbg.arch;
             Ok(())
         } else {
             errno_result()
@@ -1337,6 +1455,101 @@ impl From<&DebugRegs> for kvm_debugregs {
     }
 }
 
+impl From<&kvm_vcpu_events> for VcpuEvents {
+    fn from(e: &kvm_vcpu_events) -> Self {
+        let valid = |flag: u32| e.flags & flag != 0;
+        VcpuEvents {
+            exception: VcpuExceptionState {
+                injected: e.exception.injected != 0,
+                pending: e.exception.pending != 0,
+                nr: e.exception.nr,
+                has_error_code: e.exception.has_error_code != 0,
+                error_code: e.exception.error_code,
+                payload: if valid(KVM_VCPUEVENT_VALID_PAYLOAD) && e.exception_has_payload != 0 {
+                    Some(e.exception_payload)
+                } else {
+                    None
+                },
+            },
+            interrupt: VcpuInterruptState {
+                injected: e.interrupt.injected != 0,
+                nr: e.interrupt.nr,
+                soft: e.interrupt.soft != 0,
+                shadow: if valid(KVM_VCPUEVENT_VALID_SHADOW) {
+                    Some(e.interrupt.shadow)
+                } else {
+                    None
+                },
+            },
+            nmi: VcpuNmiState {
+                injected: e.nmi.injected != 0,
+                pending: if valid(KVM_VCPUEVENT_VALID_NMI_PENDING) {
+                    Some(e.nmi.pending != 0)
+                } else {
+                    None
+                },
+                masked: e.nmi.masked != 0,
+            },
+            sipi_vector: if valid(KVM_VCPUEVENT_VALID_SIPI_VECTOR) {
+                Some(e.sipi_vector)
+            } else {
+                None
+            },
+            smi: if valid(KVM_VCPUEVENT_VALID_SMM) {
+                Some(VcpuSmiState {
+                    smm: e.smi.smm != 0,
+                    pending: e.smi.pending != 0,
+                    smm_inside_nmi: e.smi.smm_inside_nmi != 0,
+                    latched_init: e.smi.latched_init,
+                })
+            } else {
+                None
+            },
+        }
+    }
+}
+
+impl From<&VcpuEvents> for kvm_vcpu_events {
+    fn from(e: &VcpuEvents) -> Self {
+        let mut kvm = kvm_vcpu_events::default();
+        kvm.exception.injected = e.exception.injected as u8;
+        kvm.exception.pending = e.exception.pending as u8;
+        kvm.exception.nr = e.exception.nr;
+        kvm.exception.has_error_code = e.exception.has_error_code as u8;
+        kvm.exception.error_code = e.exception.error_code;
+        if let Some(payload) = e.exception.payload {
+            kvm.flags |= KVM_VCPUEVENT_VALID_PAYLOAD;
+            kvm.exception_has_payload = 1;
+            kvm.exception_payload = payload;
+        }
+        kvm.interrupt.injected = e.interrupt.injected as u8;
+        kvm.interrupt.nr = e.interrupt.nr;
+        kvm.interrupt.soft = e.interrupt.soft as u8;
+        if let Some(shadow) = e.interrupt.shadow {
+            kvm.flags |= KVM_VCPUEVENT_VALID_SHADOW;
+            kvm.interrupt.shadow = shadow;
+        }
+        kvm.nmi.injected = e.nmi.injected as u8;
+        kvm.nmi.masked = e.nmi.masked as u8;
+        if let Some(pending) = e.nmi.pending {
+            kvm.flags |= KVM_VCPUEVENT_VALID_NMI_PENDING;
+            kvm.nmi.pending = pending as u8;
+        }
+        if let Some(sipi_vector) = e.sipi_vector {
+            kvm.flags |= KVM_VCPUEVENT_VALID_SIPI_VECTOR;
+            kvm.sipi_vector = sipi_vector;
+        }
+        if let Some(smi) = &e.smi {
+            kvm.flags |= KVM_VCPUEVENT_VALID_SMM;
+            kvm.smi.smm = smi.smm as u8;
+            kvm.smi.pending = smi.pending as u8;
+            kvm.smi.smm_inside_nmi = smi.smm_inside_nmi as u8;
+            kvm.smi.latched_init = smi.latched_init;
+        }
+        kvm
+    }
+}
+
 fn from_kvm_xcrs(r: &kvm_xcrs) -> Vec<Register> {
     r.xcrs
         .iter()
@@ -118,6 +118,26 @@ pub trait Vm: Send {
     /// be 2 bytes or greater.
     fn get_dirty_log(&self, slot: MemSlot, dirty_log: &mut [u8]) -> Result<()>;
 
+    /// Enables or disables dirty page logging for the guest memory returned by `get_memory`.
+    ///
+    /// While enabled, `get_dirty_log` can be called on the guest memory regions, whose slots are
+    /// their indices in the `GuestMemory`. Only works on VMs that support `VmCap::DirtyLog`.
+    fn set_memory_dirty_log(&mut self, _enable: bool) -> Result<()> {
+        Err(std::io::Error::from(std::io::ErrorKind::Unsupported).into())
+    }
+
+    /// Makes the dirty log use a ring of `entries` dirty pages per vcpu instead of a bitmap per
+    /// memory slot. Only works on VMs that support `VmCap::DirtyRing`, and must be called before
+    /// any vcpu is created. `entries` must be a power of two, and the hypervisor may require the
+    /// rings to fill at least a page.
+    ///
+    /// The rings are transparently harvested by `get_dirty_log` and when they fill up, so callers
+    /// keep using `get_dirty_log`, which then costs the number of dirty pages rather than the size
+    /// of the memory.
+    fn enable_dirty_ring(&mut self, _entries: u32) -> Result<()> {
+        Err(std::io::Error::from(std::io::ErrorKind::Unsupported).into())
+    }
+
     /// Registers an event to be signaled whenever a certain address is written to.
     ///
     /// The `datamatch` parameter can be used to limit signaling `evt` to only the cases where the
@@ -398,6 +418,43 @@ pub enum Datamatch {
     U64(Option<u64>),
 }
 
+/// Kind of memory access that triggers a hardware watchpoint.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum WatchpointKind {
+    Write,
+    /// Architectures that can't watch only reads watch all the accesses instead.
+    Read,
+    ReadWrite,
+}
+
+/// A hardware watchpoint on `len` bytes of guest virtual memory starting at `addr`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct HwWatchpoint {
+    pub addr: GuestAddress,
+    pub len: u64,
+    pub kind: WatchpointKind,
+}
+
+impl HwWatchpoint {
+    /// Returns true if the watched memory contains `addr`.
+    pub fn contains(&self, addr: GuestAddress) -> bool {
+        addr >= self.addr && addr.offset() - self.addr.offset() < self.len
+    }
+}
+
+/// The reason of a `VcpuExit::Debug`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum DebugExit {
+    /// The vcpu executed the instruction it was single-stepping.
+    SingleStep,
+    /// The vcpu reached a breakpoint.
+    Breakpoint,
+    /// The vcpu accessed `addr`, watched by a hardware watchpoint.
+    Watchpoint { addr: GuestAddress },
+    /// The hypervisor didn't tell the reason.
+    Unknown,
+}
+
 /// A reason why a VCPU exited. One of these returns every time `Vcpu::run` is called.
 #[derive(Debug, Clone, Copy)]
 pub enum VcpuExit {
@@ -414,7 +471,7 @@ pub enum VcpuExit {
     Unknown,
     Exception,
     Hypercall,
-    Debug,
+    Debug(DebugExit),
     Hlt,
     IrqWindowOpen,
     Shutdown,
@@ -469,6 +526,8 @@ pub enum VcpuExit {
     ApicInitSipiTrap,
     /// vcpu stoppted due to bus lock
     BusLock,
+    /// vcpu stopped because its dirty ring was full, the ring was harvested before returning
+    DirtyRingFull,
     /// Riscv supervisor call.
     Sbi {
         extension_id: u64,
@@ -553,7 +612,7 @@ pub struct ClockState {
 
 /// The MPState represents the state of a processor.
 #[repr(C)]
-#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub enum MPState {
     /// the vcpu is currently running (x86/x86_64,arm/arm64)
     Runnable,
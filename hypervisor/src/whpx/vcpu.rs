@@ -28,6 +28,7 @@ use crate::CpuId;
 use crate::CpuIdEntry;
 use crate::DebugRegs;
 use crate::Fpu;
+use crate::HwWatchpoint;
 use crate::HypervHypercall;
 use crate::IoOperation;
 use crate::IoParams;
@@ -35,9 +36,11 @@ use crate::Register;
 use crate::Regs;
 use crate::Sregs;
 use crate::Vcpu;
+use crate::VcpuEvents;
 use crate::VcpuExit;
 use crate::VcpuRunHandle;
 use crate::VcpuX86_64;
+use crate::Xsave;
 
 const WHPX_EXIT_DIRECTION_MMIO_READ: u8 = 0;
 const WHPX_EXIT_DIRECTION_MMIO_WRITE: u8 = 1;
@@ -1130,6 +1133,26 @@ impl VcpuX86_64 for WhpxVcpu {
         }
     }
 
+    fn get_xsave(&self) -> Result<Xsave> {
+        // WHPX support for the XSAVE area isn't implemented.
+        Err(Error::new(ENXIO))
+    }
+
+    fn set_xsave(&self, _xsave: &Xsave) -> Result<()> {
+        // WHPX support for the XSAVE area isn't implemented.
+        Err(Error::new(ENXIO))
+    }
+
+    fn get_vcpu_events(&self) -> Result<VcpuEvents> {
+        // WHPX support for the VCPU events isn't implemented.
+        Err(Error::new(ENXIO))
+    }
+
+    fn set_vcpu_events(&self, _events: &VcpuEvents) -> Result<()> {
+        // WHPX support for the VCPU events isn't implemented.
+        Err(Error::new(ENXIO))
+    }
+
     /// Gets the model-specific registers.  `msrs` specifies the MSR indexes to be queried, and
     /// on success contains their indexes and values.
     fn get_msrs(&self, msrs: &mut Vec<Register>) -> Result<()> {
@@ -1245,7 +1268,12 @@ impl VcpuX86_64 for WhpxVcpu {
     }
 
     /// Sets up debug registers and configure vcpu for handling guest debug events.
-    fn set_guest_debug(&self, _addrs: &[GuestAddress], _enable_singlestep: bool) -> Result<()> {
+    fn set_guest_debug(
+        &self,
+        _addrs: &[GuestAddress],
+        _watchpoints: &[HwWatchpoint],
+        _enable_singlestep: bool,
+    ) -> Result<()> {
         // TODO(b/173807302): Implement this
         Err(Error::new(ENOENT))
     }
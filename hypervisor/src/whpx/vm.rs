@@ -417,6 +417,7 @@ impl Vm for WhpxVm {
             VmCap::Protected => false,
             // whpx initializes cpuid early during VM creation.
             VmCap::EarlyInitCpuid => true,
+            VmCap::DirtyRing => false,
             #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
             VmCap::BusLockDetect => false,
         }
@@ -8,6 +8,8 @@ use std::arch::x86_64::__cpuid;
 #[cfg(any(unix, feature = "haxm", feature = "whpx"))]
 use std::arch::x86_64::_rdtsc;
 
+use base::custom_serde::deserialize_seq_to_arr;
+use base::custom_serde::serialize_arr;
 use base::error;
 use base::Result;
 use bit_field::*;
@@ -16,6 +18,7 @@ use serde::Deserialize;
 use serde::Serialize;
 use vm_memory::GuestAddress;
 
+use crate::HwWatchpoint;
 use crate::Hypervisor;
 use crate::IrqRoute;
 use crate::IrqSource;
@@ -95,6 +98,18 @@ pub trait VcpuX86_64: Vcpu {
     /// Sets the VCPU extended control registers.
     fn set_xcrs(&self, xcrs: &[Register]) -> Result<()>;
 
+    /// Gets the VCPU XSAVE area.
+    fn get_xsave(&self) -> Result<Xsave>;
+
+    /// Sets the VCPU XSAVE area.
+    fn set_xsave(&self, xsave: &Xsave) -> Result<()>;
+
+    /// Gets the exceptions, interrupts and NMIs pending or being injected in the VCPU.
+    fn get_vcpu_events(&self) -> Result<VcpuEvents>;
+
+    /// Sets the exceptions, interrupts and NMIs pending or being injected in the VCPU.
+    fn set_vcpu_events(&self, events: &VcpuEvents) -> Result<()>;
+
     /// Gets the model-specific registers.  `msrs` specifies the MSR indexes to be queried, and
     /// on success contains their indexes and values.
     fn get_msrs(&self, msrs: &mut Vec<Register>) -> Result<()>;
@@ -108,8 +123,14 @@ pub trait VcpuX86_64: Vcpu {
     /// Gets the system emulated hyper-v CPUID values.
     fn get_hyperv_cpuid(&self) -> Result<CpuId>;
 
-    /// Sets up debug registers and configure vcpu for handling guest debug events.
-    fn set_guest_debug(&self, addrs: &[GuestAddress], enable_singlestep: bool) -> Result<()>;
+    /// Sets up debug registers and configure vcpu for handling guest debug events, with hardware
+    /// breakpoints at `addrs` and the hardware `watchpoints`.
+    fn set_guest_debug(
+        &self,
+        addrs: &[GuestAddress],
+        watchpoints: &[HwWatchpoint],
+        enable_singlestep: bool,
+    ) -> Result<()>;
 
     /// This function should be called after `Vcpu::run` returns `VcpuExit::Cpuid`, and `entry`
     /// should represent the result of emulating the CPUID instruction. The `handle_cpuid` function
@@ -121,6 +142,41 @@ pub trait VcpuX86_64: Vcpu {
 
     /// Set the guest->host TSC offset
     fn set_tsc_offset(&self, offset: u64) -> Result<()>;
+
+    /// Gets the architectural state of the VCPU, to be saved in a VM snapshot.
+    fn snapshot(&self) -> Result<VcpuSnapshot> {
+        let mut msrs = SNAPSHOT_MSRS
+            .iter()
+            .map(|&id| Register { id, value: 0 })
+            .collect();
+        // MSRs the hypervisor doesn't support are left out.
+        self.get_msrs(&mut msrs)?;
+        Ok(VcpuSnapshot {
+            regs: self.get_regs()?,
+            sregs: self.get_sregs()?,
+            fpu: self.get_fpu()?,
+            debugregs: self.get_debugregs()?,
+            xcrs: self.get_xcrs()?,
+            xsave: self.get_xsave()?,
+            msrs,
+            events: self.get_vcpu_events()?,
+        })
+    }
+
+    /// Restores the state of the VCPU from a snapshot taken with `snapshot`.
+    ///
+    /// The local APIC must be restored first for the TSC deadline to be set.
+    fn restore(&mut self, snapshot: &VcpuSnapshot) -> Result<()> {
+        self.set_sregs(&snapshot.sregs)?;
+        self.set_regs(&snapshot.regs)?;
+        self.set_fpu(&snapshot.fpu)?;
+        self.set_debugregs(&snapshot.debugregs)?;
+        self.set_xcrs(&snapshot.xcrs)?;
+        // The XSAVE area supersedes the FPU registers, and needs XCR0 to enable its components.
+        self.set_xsave(&snapshot.xsave)?;
+        self.set_msrs(&snapshot.msrs)?;
+        self.set_vcpu_events(&snapshot.events)
+    }
 }
 
 impl_downcast!(VcpuX86_64);
@@ -128,6 +184,29 @@ impl_downcast!(VcpuX86_64);
 // TSC MSR
 pub const MSR_IA32_TSC: u32 = 0x00000010;
 
+// MSRs saved in VCPU snapshots, besides the ones of the special registers. The TSC comes first so
+// that it's restored before the MSRs depending on it.
+const SNAPSHOT_MSRS: [u32; 18] = [
+    MSR_IA32_TSC,
+    0x00000174, // IA32_SYSENTER_CS
+    0x00000175, // IA32_SYSENTER_ESP
+    0x00000176, // IA32_SYSENTER_EIP
+    0x000001a0, // IA32_MISC_ENABLE
+    0x00000277, // IA32_PAT
+    0xc0000081, // STAR
+    0xc0000082, // LSTAR
+    0xc0000083, // CSTAR
+    0xc0000084, // SYSCALL_MASK
+    0xc0000102, // KERNEL_GS_BASE
+    0xc0000103, // TSC_AUX
+    0x4b564d00, // KVM_WALL_CLOCK_NEW
+    0x4b564d01, // KVM_SYSTEM_TIME_NEW
+    0x4b564d02, // KVM_ASYNC_PF_EN
+    0x4b564d03, // KVM_STEAL_TIME
+    0x4b564d04, // KVM_PV_EOI_EN
+    0x000006e0, // IA32_TSC_DEADLINE
+];
+
 /// Implementation of get_tsc_offset that uses VcpuX86_64::get_msrs.
 #[cfg(any(unix, feature = "haxm", feature = "whpx"))]
 pub(crate) fn get_tsc_offset_from_msr(vcpu: &impl VcpuX86_64) -> Result<u64> {
@@ -365,6 +444,26 @@ pub struct IoapicRedirectionTableEntry {
     dest_id: BitField8,
 }
 
+impl Serialize for IoapicRedirectionTableEntry {
+    fn serialize<S>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        self.get(0, 64).serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for IoapicRedirectionTableEntry {
+    fn deserialize<D>(deserializer: D) -> std::result::Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let mut entry = IoapicRedirectionTableEntry::default();
+        entry.set(0, 64, u64::deserialize(deserializer)?);
+        Ok(entry)
+    }
+}
+
 /// Number of pins on the standard KVM/IOAPIC.
 pub const NUM_IOAPIC_PINS: usize = 24;
 
@@ -373,7 +472,7 @@ pub const MAX_IOAPIC_PINS: usize = 120;
 
 /// Represents the state of the IOAPIC.
 #[repr(C)]
-#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub struct IoapicState {
     /// base_address is the memory base address for this IOAPIC. It cannot be changed.
     pub base_address: u64,
@@ -384,6 +483,10 @@ pub struct IoapicState {
     /// current_interrupt_level_bitmap represents a bitmap of the state of all of the irq lines
     pub current_interrupt_level_bitmap: u32,
     /// redirect_table contains the irq settings for each irq line
+    #[serde(
+        serialize_with = "serialize_arr",
+        deserialize_with = "deserialize_seq_to_arr"
+    )]
     pub redirect_table: [IoapicRedirectionTableEntry; 120],
 }
 
@@ -401,7 +504,7 @@ pub enum PicSelect {
 }
 
 #[repr(C)]
-#[derive(enumn::N, Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(enumn::N, Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 pub enum PicInitState {
     Icw1 = 0,
     Icw2 = 1,
@@ -427,7 +530,7 @@ impl Default for PicInitState {
 
 /// Represents the state of the PIC.
 #[repr(C)]
-#[derive(Clone, Copy, Default, Debug, PartialEq, Eq)]
+#[derive(Clone, Copy, Default, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub struct PicState {
     /// Edge detection.
     pub last_irr: u8,
@@ -461,8 +564,12 @@ pub struct PicState {
 /// The Local APIC consists of 64 128-bit registers, but only the first 32-bits of each register
 /// can be used, so this structure only stores the first 32-bits of each register.
 #[repr(C)]
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Serialize, Deserialize)]
 pub struct LapicState {
+    #[serde(
+        serialize_with = "serialize_arr",
+        deserialize_with = "deserialize_seq_to_arr"
+    )]
     pub regs: [LapicRegister; 64],
 }
 
@@ -488,7 +595,7 @@ impl Eq for LapicState {}
 /// The PitState represents the state of the PIT (aka the Programmable Interval Timer).
 /// The state is simply the state of it's three channels.
 #[repr(C)]
-#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub struct PitState {
     pub channels: [PitChannelState; 3],
     /// Hypervisor-specific flags for setting the pit state.
@@ -500,7 +607,7 @@ pub struct PitState {
 /// but the count values and latch values are two bytes. So the access mode controls which of the
 /// two bytes will be read when.
 #[repr(C)]
-#[derive(enumn::N, Clone, Copy, Debug, PartialEq, Eq)]
+#[derive(enumn::N, Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub enum PitRWMode {
     /// None mode means that no access mode has been set.
     None = 0,
@@ -527,7 +634,7 @@ impl From<u8> for PitRWMode {
 /// This is related to the PitRWMode, it mainly gives more detail about the state of the channel
 /// with respect to PitRWMode::Both.
 #[repr(C)]
-#[derive(enumn::N, Clone, Copy, Debug, PartialEq, Eq)]
+#[derive(enumn::N, Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub enum PitRWState {
     /// None mode means that no access mode has been set.
     None = 0,
@@ -556,7 +663,7 @@ impl From<u8> for PitRWState {
 
 /// The PitChannelState represents the state of one of the PIT's three counters.
 #[repr(C)]
-#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub struct PitChannelState {
     /// The starting value for the counter.
     pub count: u32,
@@ -614,7 +721,7 @@ impl IrqRoute {
 
 /// State of a VCPU's general purpose registers.
 #[repr(C)]
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, Serialize, Deserialize)]
 pub struct Regs {
     pub rax: u64,
     pub rbx: u64,
@@ -663,7 +770,7 @@ impl Default for Regs {
 
 /// State of a memory segment.
 #[repr(C)]
-#[derive(Debug, Default, Copy, Clone)]
+#[derive(Debug, Default, Copy, Clone, Serialize, Deserialize)]
 pub struct Segment {
     pub base: u64,
     pub limit: u32,
@@ -680,7 +787,7 @@ pub struct Segment {
 
 /// State of a global descriptor table or interrupt descriptor table.
 #[repr(C)]
-#[derive(Debug, Default, Copy, Clone)]
+#[derive(Debug, Default, Copy, Clone, Serialize, Deserialize)]
 pub struct DescriptorTable {
     pub base: u64,
     pub limit: u16,
@@ -688,7 +795,7 @@ pub struct DescriptorTable {
 
 /// State of a VCPU's special registers.
 #[repr(C)]
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, Serialize, Deserialize)]
 pub struct Sregs {
     pub cs: Segment,
     pub ds: Segment,
@@ -805,7 +912,7 @@ impl Default for Sregs {
 
 /// State of a VCPU's floating point unit.
 #[repr(C)]
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, Serialize, Deserialize)]
 pub struct Fpu {
     pub fpr: [[u8; 16usize]; 8usize],
     pub fcw: u16,
@@ -836,13 +943,89 @@ impl Default for Fpu {
 
 /// State of a VCPU's debug registers.
 #[repr(C)]
-#[derive(Debug, Default, Copy, Clone)]
+#[derive(Debug, Default, Copy, Clone, Serialize, Deserialize)]
 pub struct DebugRegs {
     pub db: [u64; 4usize],
     pub dr6: u64,
     pub dr7: u64,
 }
 
+/// State of a VCPU's XSAVE area, in the standard format of the XSAVE instruction.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Xsave {
+    #[serde(
+        serialize_with = "serialize_arr",
+        deserialize_with = "deserialize_seq_to_arr"
+    )]
+    pub region: [u32; 1024],
+}
+
+/// An exception being injected into a VCPU, or pending.
+#[derive(Debug, Default, Copy, Clone, Serialize, Deserialize)]
+pub struct VcpuExceptionState {
+    pub injected: bool,
+    pub pending: bool,
+    pub nr: u8,
+    pub has_error_code: bool,
+    pub error_code: u32,
+    /// Payload of the exception, e.g. the faulting address of a page fault, if the hypervisor
+    /// keeps it apart from the registers until the exception is delivered.
+    pub payload: Option<u64>,
+}
+
+/// An interrupt being injected into a VCPU.
+#[derive(Debug, Default, Copy, Clone, Serialize, Deserialize)]
+pub struct VcpuInterruptState {
+    pub injected: bool,
+    pub nr: u8,
+    pub soft: bool,
+    /// Interrupt shadow after STI or MOV SS, if the hypervisor reports it.
+    pub shadow: Option<u8>,
+}
+
+/// The NMI state of a VCPU.
+#[derive(Debug, Default, Copy, Clone, Serialize, Deserialize)]
+pub struct VcpuNmiState {
+    pub injected: bool,
+    /// Whether an NMI is pending, if the hypervisor reports it.
+    pub pending: Option<bool>,
+    pub masked: bool,
+}
+
+/// The system management mode state of a VCPU.
+#[derive(Debug, Default, Copy, Clone, Serialize, Deserialize)]
+pub struct VcpuSmiState {
+    pub smm: bool,
+    pub pending: bool,
+    pub smm_inside_nmi: bool,
+    pub latched_init: u8,
+}
+
+/// Events pending or being injected in a VCPU.
+#[derive(Debug, Default, Copy, Clone, Serialize, Deserialize)]
+pub struct VcpuEvents {
+    pub exception: VcpuExceptionState,
+    pub interrupt: VcpuInterruptState,
+    pub nmi: VcpuNmiState,
+    /// Vector of the SIPI received by an AP waiting for one, if the hypervisor reports it.
+    pub sipi_vector: Option<u32>,
+    /// State of system management mode, if the hypervisor reports it.
+    pub smi: Option<VcpuSmiState>,
+}
+
+/// Architectural state of a VCPU saved in VM snapshots.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct VcpuSnapshot {
+    pub regs: Regs,
+    pub sregs: Sregs,
+    pub fpu: Fpu,
+    pub debugregs: DebugRegs,
+    pub xcrs: Vec<Register>,
+    pub xsave: Xsave,
+    pub msrs: Vec<Register>,
+    pub events: VcpuEvents,
+}
+
 /// State of one VCPU register.  Currently used for MSRs and XCRs.
 #[derive(Debug, Default, Copy, Clone, Serialize, Deserialize)]
 pub struct Register {
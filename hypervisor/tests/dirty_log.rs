@@ -24,6 +24,24 @@ fn test_kvm_dirty_log() {
     });
 }
 
+#[test]
+#[cfg(unix)]
+fn test_kvm_dirty_ring() {
+    use hypervisor::kvm::*;
+    let kvm = Kvm::new().expect("failed to create kvm");
+    let guest_mem = GuestMemory::new(&[(GuestAddress(0x20000), 0x1000)]).unwrap();
+    let vm = KvmVm::new(&kvm, guest_mem, Default::default()).expect("failed to create vm");
+    if !vm.check_capability(VmCap::DirtyRing) {
+        return;
+    }
+    test_dirty_log(|guest_mem| {
+        let mut vm = KvmVm::new(&kvm, guest_mem, Default::default()).expect("failed to create vm");
+        vm.enable_dirty_ring(256)
+            .expect("failed to enable dirty ring");
+        (kvm, vm)
+    });
+}
+
 #[test]
 #[cfg(feature = "haxm")]
 fn test_haxm_dirty_log_not_supported() {
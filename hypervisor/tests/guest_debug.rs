@@ -0,0 +1,139 @@
+// Copyright 2023 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+// TODO(b/237714823): Currently, only kvm is enabled for this test once LUCI can run windows.
+#![cfg(unix)]
+#![cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+
+use base::MemoryMappingBuilder;
+use base::SharedMemory;
+use hypervisor::*;
+use vm_memory::GuestAddress;
+use vm_memory::GuestMemory;
+
+#[test]
+fn test_kvm_guest_debug() {
+    use hypervisor::kvm::*;
+    test_guest_debug(|guest_mem| {
+        let kvm = Kvm::new().expect("failed to create kvm");
+        let vm = KvmVm::new(&kvm, guest_mem, Default::default()).expect("failed to create vm");
+        (kvm, vm)
+    });
+}
+
+fn test_guest_debug<CreateVm, HypervisorT, VmT>(create_vm: CreateVm)
+where
+    CreateVm: FnOnce(GuestMemory) -> (HypervisorT, VmT),
+    HypervisorT: Hypervisor,
+    VmT: VmX86_64,
+{
+    // Data breakpoints don't trigger while KVM emulates real mode, so run in flat 32-bit
+    // protected mode.
+    /*
+    0000  B042        mov al,0x42
+    0002  A200200000  mov [0x2000],al
+    0007  F4          hlt
+    */
+    let code = [0xb0, 0x42, 0xa2, 0x00, 0x20, 0x00, 0x00, 0xf4];
+    let mem_size = 0x3000;
+    let load_addr = GuestAddress(0x1000);
+    let data_addr = GuestAddress(0x2000);
+    let hlt_addr = GuestAddress(0x1007);
+
+    let guest_mem =
+        GuestMemory::new(&[(GuestAddress(0x8000), 0x1000)]).expect("failed to create guest mem");
+    let mem = SharedMemory::new("test", mem_size).expect("failed to create shared memory");
+    let mmap = MemoryMappingBuilder::new(mem_size as usize)
+        .from_shared_memory(&mem)
+        .build()
+        .expect("failed to create memory mapping");
+    mmap.write_slice(&code[..], load_addr.offset() as usize)
+        .expect("Writing code to memory failed.");
+
+    let (_, mut vm) = create_vm(guest_mem);
+    vm.add_memory_region(GuestAddress(0), Box::new(mmap), false, false)
+        .expect("failed to register memory");
+
+    let mut vcpu = vm.create_vcpu(0).expect("new vcpu failed");
+    let mut vcpu_sregs = vcpu.get_sregs().expect("get sregs failed");
+    let data_segment = Segment {
+        base: 0,
+        limit: 0xffffffff,
+        selector: 0x10,
+        type_: 0b0011,
+        present: 1,
+        dpl: 0,
+        db: 1,
+        s: 1,
+        l: 0,
+        g: 1,
+        avl: 0,
+    };
+    vcpu_sregs.cs = Segment {
+        selector: 0x8,
+        type_: 0b1011,
+        ..data_segment
+    };
+    vcpu_sregs.ds = data_segment;
+    vcpu_sregs.es = data_segment;
+    vcpu_sregs.ss = data_segment;
+    vcpu_sregs.cr0 |= 1; // PE
+    vcpu.set_sregs(&vcpu_sregs).expect("set sregs failed");
+    let vcpu_regs = Regs {
+        rip: load_addr.offset(),
+        rflags: 2,
+        ..Default::default()
+    };
+    vcpu.set_regs(&vcpu_regs).expect("set regs failed");
+
+    let run_handle = vcpu.take_run_handle(None).unwrap();
+    let run = |vcpu: &mut Box<dyn VcpuX86_64>| loop {
+        match vcpu.run(&run_handle).expect("run failed") {
+            VcpuExit::Intr => continue,
+            exit => break exit,
+        }
+    };
+
+    // Step over `mov al,0x42`.
+    vcpu.set_guest_debug(&[], &[], true)
+        .expect("failed to enable single step");
+    assert!(matches!(
+        run(&mut vcpu),
+        VcpuExit::Debug(DebugExit::SingleStep)
+    ));
+    assert_eq!(vcpu.get_regs().expect("failed to get regs").rip, 0x1002);
+
+    // The watchpoint traps after the write, before the breakpoint on `hlt`. Some nested
+    // hypervisors don't deliver data breakpoints, in which case only the breakpoint traps.
+    let watchpoint = HwWatchpoint {
+        addr: data_addr,
+        len: 1,
+        kind: WatchpointKind::Write,
+    };
+    vcpu.set_guest_debug(&[hlt_addr], &[watchpoint], false)
+        .expect("failed to set the breakpoint and the watchpoint");
+    let mut exit = run(&mut vcpu);
+    if let VcpuExit::Debug(DebugExit::Watchpoint { addr }) = exit {
+        assert_eq!(addr, data_addr);
+        assert_eq!(vcpu.get_regs().expect("failed to get regs").rip, 0x1007);
+        exit = run(&mut vcpu);
+    }
+    assert!(matches!(exit, VcpuExit::Debug(DebugExit::Breakpoint)));
+    assert_eq!(vcpu.get_regs().expect("failed to get regs").rip, 0x1007);
+
+    vcpu.set_guest_debug(&[], &[], false)
+        .expect("failed to clear the guest debug state");
+    assert!(matches!(run(&mut vcpu), VcpuExit::Hlt));
+
+    // Unaligned and oversized watchpoints are refused.
+    let unaligned = HwWatchpoint {
+        addr: GuestAddress(0x2001),
+        len: 2,
+        kind: WatchpointKind::ReadWrite,
+    };
+    assert!(vcpu.set_guest_debug(&[], &[unaligned], false).is_err());
+    assert!(vcpu
+        .set_guest_debug(&[hlt_addr; 4], &[watchpoint], false)
+        .is_err());
+}
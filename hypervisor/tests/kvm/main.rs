@@ -190,6 +190,25 @@ fn sync_memory() {
     assert!(vm.msync_memory_region(slot + 1, mem_size, 0).is_err());
 }
 
+#[test]
+fn memory_dirty_log() {
+    let kvm = Kvm::new().unwrap();
+    let gm =
+        GuestMemory::new(&[(GuestAddress(0), 0x1000), (GuestAddress(0x5000), 0x5000)]).unwrap();
+    let mut vm = KvmVm::new(&kvm, gm, Default::default()).unwrap();
+    let mut dirty_log = [0u8; 1];
+    assert!(vm.get_dirty_log(1, &mut dirty_log).is_err());
+
+    vm.set_memory_dirty_log(true).unwrap();
+    vm.get_dirty_log(0, &mut dirty_log).unwrap();
+    vm.get_dirty_log(1, &mut dirty_log).unwrap();
+    assert_eq!(dirty_log, [0]);
+    assert!(vm.get_dirty_log(2, &mut dirty_log).is_err());
+
+    vm.set_memory_dirty_log(false).unwrap();
+    assert!(vm.get_dirty_log(1, &mut dirty_log).is_err());
+}
+
 #[test]
 fn register_irqfd() {
     let kvm = Kvm::new().unwrap();
@@ -365,6 +365,35 @@ fn xcrs() {
     assert_eq!(xcrs[0].value, xcrs2[0].value);
 }
 
+#[test]
+fn xsave() {
+    let kvm = Kvm::new().unwrap();
+    let gm = GuestMemory::new(&[(GuestAddress(0), 0x10000)]).unwrap();
+    let vm = KvmVm::new(&kvm, gm, Default::default()).unwrap();
+    let vcpu = vm.create_vcpu(0).unwrap();
+    let mut xsave = vcpu.get_xsave().unwrap();
+    // The FPU control word is the first field of the legacy region, which is only loaded if its
+    // bit is set in XSTATE_BV, the first field of the XSAVE header at offset 512.
+    xsave.region[0] = (xsave.region[0] & !0xffff) | 0x27f;
+    xsave.region[128] |= 1;
+    vcpu.set_xsave(&xsave).unwrap();
+    let xsave2 = vcpu.get_xsave().unwrap();
+    assert_eq!(xsave2.region[0] & 0xffff, 0x27f);
+}
+
+#[test]
+fn vcpu_events() {
+    let kvm = Kvm::new().unwrap();
+    let gm = GuestMemory::new(&[(GuestAddress(0), 0x10000)]).unwrap();
+    let vm = KvmVm::new(&kvm, gm, Default::default()).unwrap();
+    let vcpu = vm.create_vcpu(0).unwrap();
+    let mut events = vcpu.get_vcpu_events().unwrap();
+    events.nmi.masked = true;
+    vcpu.set_vcpu_events(&events).unwrap();
+    let events2 = vcpu.get_vcpu_events().unwrap();
+    assert!(events2.nmi.masked);
+}
+
 #[test]
 fn get_msrs() {
     let kvm = Kvm::new().unwrap();
@@ -70,6 +70,10 @@ pub enum Error {
     InvalidProgramHeaderOffset,
     #[error("invalid program header size")]
     InvalidProgramHeaderSize,
+    #[error("invalid PVH entry point")]
+    InvalidPvhEntryPoint,
+    #[error("invalid PVH entry point note")]
+    InvalidPvhNote,
     #[error("no loadable program headers found")]
     NoLoadableProgramHeaders,
     #[error("program header address out of allowed address range")]
@@ -78,12 +82,16 @@ pub enum Error {
     ReadHeader,
     #[error("unable to read kernel image")]
     ReadKernelImage,
+    #[error("unable to read note")]
+    ReadNote,
     #[error("unable to read program header")]
     ReadProgramHeader,
     #[error("unable to seek to kernel end")]
     SeekKernelEnd,
     #[error("unable to seek to kernel start")]
     SeekKernelStart,
+    #[error("unable to seek to note")]
+    SeekNote,
     #[error("unable to seek to program header")]
     SeekProgramHeader,
 }
@@ -102,8 +110,14 @@ pub struct LoadedKernel {
 
     /// Entry point address of the kernel.
     pub entry: GuestAddress,
+
+    /// Entry point address of the PVH boot protocol, if the kernel supports it.
+    pub pvh_entry: Option<GuestAddress>,
 }
 
+/// Type of the Xen ELF note holding the 32-bit entry point of the PVH boot protocol.
+const XEN_ELFNOTE_PHYS32_ENTRY: u32 = 18;
+
 /// Loads a kernel from a 32-bit ELF image into memory.
 ///
 /// The ELF file will be loaded at the physical address specified by the `p_paddr` fields of its
@@ -255,13 +269,88 @@ where
         return Err(Error::InvalidEntryPoint);
     }
 
+    // Like the entry point, the PVH entry point must fall within the loaded sections.
+    let pvh_entry = match read_pvh_entry(kernel_image, &elf)? {
+        Some(pvh_entry) => {
+            let pvh_entry = pvh_entry
+                .checked_add(phys_offset)
+                .ok_or(Error::InvalidPvhEntryPoint)?;
+            if !address_range.contains(pvh_entry) {
+                return Err(Error::InvalidPvhEntryPoint);
+            }
+            Some(GuestAddress(pvh_entry))
+        }
+        None => None,
+    };
+
     Ok(LoadedKernel {
         address_range,
         size,
         entry: GuestAddress(entry),
+        pvh_entry,
     })
 }
 
+/// Returns the entry point of the PVH boot protocol, given by the `XEN_ELFNOTE_PHYS32_ENTRY` note
+/// of the PT_NOTE program headers, if any. The rest of a PT_NOTE segment whose notes overflow it
+/// is skipped.
+fn read_pvh_entry<F>(file: &mut F, elf: &Elf64) -> Result<Option<u64>>
+where
+    F: Read + Seek + AsRawDescriptor,
+{
+    const NOTE_HEADER_SIZE: usize = 12;
+    // The name and the descriptor of the notes are padded to 4 bytes.
+    fn align(size: usize) -> usize {
+        (size + 3) & !3
+    }
+    fn read_u32(bytes: &[u8]) -> u32 {
+        u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+    }
+
+    for phdr in &elf.program_headers {
+        if phdr.p_type != elf::PT_NOTE {
+            continue;
+        }
+
+        let mut notes = Vec::new();
+        file.seek(SeekFrom::Start(phdr.p_offset))
+            .map_err(|_| Error::SeekNote)?;
+        file.by_ref()
+            .take(phdr.p_filesz)
+            .read_to_end(&mut notes)
+            .map_err(|_| Error::ReadNote)?;
+
+        let mut offset = 0;
+        while let Some(header) = notes.get(offset..offset + NOTE_HEADER_SIZE) {
+            let name_size = read_u32(&header[0..4]) as usize;
+            let desc_size = read_u32(&header[4..8]) as usize;
+            let note_type = read_u32(&header[8..12]);
+            let name_start = offset + NOTE_HEADER_SIZE;
+            let desc_start = name_start + align(name_size);
+            let name = match notes.get(name_start..name_start + name_size) {
+                Some(name) => name,
+                None => break,
+            };
+            let desc = notes.get(desc_start..desc_start + desc_size);
+            if name == b"Xen\0" && note_type == XEN_ELFNOTE_PHYS32_ENTRY {
+                // The entry point is a 32-bit address, which some kernels store in 64 bits.
+                return match desc {
+                    Some(&[a, b, c, d]) => Ok(Some(u32::from_le_bytes([a, b, c, d]) as u64)),
+                    Some(&[a, b, c, d, e, f, g, h]) => {
+                        Ok(Some(u64::from_le_bytes([a, b, c, d, e, f, g, h])))
+                    }
+                    _ => Err(Error::InvalidPvhNote),
+                };
+            }
+            if desc.is_none() {
+                break;
+            }
+            offset = desc_start + align(desc_size);
+        }
+    }
+    Ok(None)
+}
+
 /// Writes the command line string to the given memory slice.
 ///
 /// # Arguments
@@ -490,6 +579,62 @@ mod test {
         file
     }
 
+    // Elf64 image loading 16 bytes at 0x20_0000, with a PVH entry point note whose descriptor is
+    // `pvh_entry`.
+    fn make_pvh_elf64_bin(pvh_entry: &[u8]) -> File {
+        const PHDRS_OFFSET: usize = 0x40;
+        const NOTE_OFFSET: usize = 0xb0;
+        const CODE_OFFSET: usize = 0x100;
+
+        let mut ident = [0u8; 16];
+        ident[..4].copy_from_slice(b"\x7fELF");
+        ident[elf::EI_CLASS as usize] = elf::ELFCLASS64 as u8;
+        ident[elf::EI_DATA as usize] = elf::ELFDATA2LSB as u8;
+        ident[elf::EI_VERSION as usize] = elf::EV_CURRENT as u8;
+        let ehdr = elf::Elf64_Ehdr {
+            e_ident: ident,
+            e_entry: 0x20_0000,
+            e_phoff: PHDRS_OFFSET as u64,
+            e_phentsize: mem::size_of::<elf::Elf64_Phdr>() as u16,
+            e_phnum: 2,
+            ..Default::default()
+        };
+
+        let mut note = Vec::new();
+        note.extend_from_slice(&4u32.to_le_bytes());
+        note.extend_from_slice(&(pvh_entry.len() as u32).to_le_bytes());
+        note.extend_from_slice(&XEN_ELFNOTE_PHYS32_ENTRY.to_le_bytes());
+        note.extend_from_slice(b"Xen\0");
+        note.extend_from_slice(pvh_entry);
+
+        let phdrs = [
+            elf::Elf64_Phdr {
+                p_type: elf::PT_LOAD,
+                p_offset: CODE_OFFSET as u64,
+                p_paddr: 0x20_0000,
+                p_filesz: 0x10,
+                p_memsz: 0x10,
+                ..Default::default()
+            },
+            elf::Elf64_Phdr {
+                p_type: elf::PT_NOTE,
+                p_offset: NOTE_OFFSET as u64,
+                p_filesz: note.len() as u64,
+                ..Default::default()
+            },
+        ];
+
+        let mut bytes = vec![0u8; CODE_OFFSET + 0x10];
+        bytes[..PHDRS_OFFSET].copy_from_slice(ehdr.as_slice());
+        for (i, phdr) in phdrs.iter().enumerate() {
+            let offset = PHDRS_OFFSET + i * mem::size_of::<elf::Elf64_Phdr>();
+            bytes[offset..offset + mem::size_of::<elf::Elf64_Phdr>()]
+                .copy_from_slice(phdr.as_slice());
+        }
+        bytes[NOTE_OFFSET..NOTE_OFFSET + note.len()].copy_from_slice(&note);
+        make_elf_bin(&bytes)
+    }
+
     fn mutate_elf_bin(mut f: &File, offset: u64, val: u8) {
         f.seek(SeekFrom::Start(offset))
             .expect("failed to seek file");
@@ -519,6 +664,33 @@ mod test {
         assert_eq!(kernel.address_range.end, 0x20_0035);
         assert_eq!(kernel.size, 0x35);
         assert_eq!(kernel.entry, GuestAddress(0x20_000e));
+        assert_eq!(kernel.pvh_entry, None);
+    }
+
+    #[test]
+    fn load_pvh_elf64() {
+        let gm = create_guest_mem();
+        let kernel_addr = GuestAddress(0x0);
+        let mut image = make_pvh_elf64_bin(&0x20_0004u32.to_le_bytes());
+        let kernel = load_elf(&gm, kernel_addr, &mut image, 0).expect("failed to load ELF");
+        assert_eq!(kernel.entry, GuestAddress(0x20_0000));
+        assert_eq!(kernel.pvh_entry, Some(GuestAddress(0x20_0004)));
+
+        let mut image = make_pvh_elf64_bin(&0x20_0008u64.to_le_bytes());
+        let kernel = load_elf(&gm, kernel_addr, &mut image, 0).expect("failed to load ELF");
+        assert_eq!(kernel.pvh_entry, Some(GuestAddress(0x20_0008)));
+
+        let mut image = make_pvh_elf64_bin(&0x30_0000u32.to_le_bytes());
+        assert_eq!(
+            Err(Error::InvalidPvhEntryPoint),
+            load_elf(&gm, kernel_addr, &mut image, 0)
+        );
+
+        let mut image = make_pvh_elf64_bin(&[0; 2]);
+        assert_eq!(
+            Err(Error::InvalidPvhNote),
+            load_elf(&gm, kernel_addr, &mut image, 0)
+        );
     }
 
     #[test]
@@ -135,6 +135,7 @@ pub enum Cap {
     IoapicNumPins = KVM_CAP_IOAPIC_NUM_PINS,
     ArmProtectedVm = KVM_CAP_ARM_PROTECTED_VM,
     ArmMte = KVM_CAP_ARM_MTE,
+    DirtyLogRing = KVM_CAP_DIRTY_LOG_RING,
     #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
     BusLockDetect = KVM_CAP_X86_BUS_LOCK_EXIT,
 }
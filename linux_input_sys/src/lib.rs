@@ -17,6 +17,7 @@ pub const EV_ABS: u16 = 0x03;
 pub const SYN_REPORT: u16 = 0;
 pub const REL_X: u16 = 0x00;
 pub const REL_Y: u16 = 0x01;
+pub const REL_WHEEL: u16 = 0x08;
 pub const ABS_X: u16 = 0x00;
 pub const ABS_Y: u16 = 0x01;
 pub const ABS_PRESSURE: u16 = 0x18;
@@ -2,7 +2,8 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the LICENSE file.
 
-//! Network API wrappers for TAP interfaces.
+//! Network API wrappers for TAP interfaces, and a user-mode network stack on Linux hosts without
+//! one.
 //! # Slirp specific crate features
 //! * **guest-to-host-net-loopback** -
 //!     Enables the guest to reach the host at a well known IP address on the
@@ -52,6 +53,13 @@ pub enum Error {
     /// Unable to create tap interface.
     #[error("failed to create tap interface: {0}")]
     CreateTap(SysError),
+    /// Failed to create the wait context of the user-mode network stack.
+    #[error("failed to create wait context: {0}")]
+    CreateWaitContext(SysError),
+    /// Failed to listen on a forwarded host port.
+    #[cfg(unix)]
+    #[error("failed to set up host forward {0}: {1}")]
+    HostForward(sys::unix::HostForward, std::io::Error),
     /// ioctl failed.
     #[error("ioctl failed: {0}")]
     IoctlError(SysError),
@@ -61,6 +69,9 @@ pub enum Error {
     #[cfg(all(feature = "slirp", windows))]
     #[error("slirp related error")]
     Slirp(slirp::SlirpError),
+    /// Failed to start the user-mode network stack thread.
+    #[error("failed to spawn user-mode network thread: {0}")]
+    SpawnUserNet(std::io::Error),
 }
 
 pub type Result<T> = std::result::Result<T, Error>;
@@ -73,6 +84,10 @@ impl Error {
             Error::CreateTap(e) => *e,
             Error::CloneTap(e) => *e,
             Error::IoctlError(e) => *e,
+            Error::CreateWaitContext(e) => *e,
+            #[cfg(unix)]
+            Error::HostForward(_, e) => SysError::new(e.raw_os_error().unwrap_or_default()),
+            Error::SpawnUserNet(e) => SysError::new(e.raw_os_error().unwrap_or_default()),
             #[cfg(all(feature = "slirp", windows))]
             Error::Slirp(e) => e.sys_error(),
         }
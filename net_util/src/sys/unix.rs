@@ -3,8 +3,13 @@
 // found in the LICENSE file.
 
 pub mod tap;
+pub mod usernet;
 use base::FileReadWriteVolatile;
 pub use tap::Tap;
+pub use usernet::HostForward;
+pub use usernet::HostForwardProtocol;
+pub use usernet::UserNet;
+pub use usernet::UserNetStack;
 
 use crate::TapTCommon;
 
@@ -0,0 +1,403 @@
+// Copyright 2023 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! User-mode networking: an unprivileged alternative to TAP devices.
+//!
+//! The guest is placed on a virtual IPv4 network, by default 10.0.2.0/24 with the gateway at
+//! 10.0.2.2, a DNS forwarder at 10.0.2.3 and the guest at 10.0.2.15, which it can learn through
+//! DHCP. Its TCP connections and UDP flows to other hosts are relayed through host sockets of the
+//! stack, and the DNS requests are forwarded to the first nameserver of the host. Host ports can be
+//! forwarded to guest ports, and with the `guest-to-host-net-loopback` feature the loopback
+//! interface of the host can be reached at the gateway address. Only ICMP echo requests to the
+//! gateway and DNS addresses are answered.
+//!
+//! The stack runs in a thread or a process of its own and exchanges the frames of the guest through
+//! a `SOCK_SEQPACKET` socket pair, so that the device side behaves like a TAP device with
+//! `IFF_VNET_HDR`.
+
+mod dhcp;
+mod packet;
+mod stack;
+mod tcp;
+
+use std::fmt;
+use std::fmt::Display;
+use std::io;
+use std::io::Read;
+use std::io::Write;
+use std::net;
+use std::net::Ipv4Addr;
+use std::os::raw::c_int;
+use std::os::raw::c_uint;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::io::RawFd;
+use std::str::FromStr;
+use std::thread;
+
+use base::volatile_impl;
+use base::AsRawDescriptor;
+use base::Error as SysError;
+use base::FileReadWriteVolatile;
+use base::FromRawDescriptor;
+use base::RawDescriptor;
+use base::ReadNotifier;
+use base::UnixSeqpacket;
+use cros_async::IntoAsync;
+use serde::Deserialize;
+use serde::Deserializer;
+use serde::Serialize;
+use serde::Serializer;
+use virtio_sys::virtio_net::virtio_net_hdr_v1;
+
+use self::stack::Stack;
+use crate::Error;
+use crate::MacAddress;
+use crate::Result;
+use crate::TapT;
+use crate::TapTCommon;
+
+const MTU: u16 = 1500;
+
+/// Addresses of the virtual network of the guest.
+#[derive(Clone, Copy, Debug)]
+struct VirtualNetwork {
+    netmask: Ipv4Addr,
+    gateway: Ipv4Addr,
+    dns: Ipv4Addr,
+    guest: Ipv4Addr,
+}
+
+impl Default for VirtualNetwork {
+    fn default() -> Self {
+        VirtualNetwork {
+            netmask: Ipv4Addr::new(255, 255, 255, 0),
+            gateway: Ipv4Addr::new(10, 0, 2, 2),
+            dns: Ipv4Addr::new(10, 0, 2, 3),
+            guest: Ipv4Addr::new(10, 0, 2, 15),
+        }
+    }
+}
+
+impl VirtualNetwork {
+    fn contains(&self, ip: Ipv4Addr) -> bool {
+        let mask = u32::from(self.netmask);
+        u32::from(ip) & mask == u32::from(self.gateway) & mask
+    }
+}
+
+/// Transport protocol of a host port forward.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HostForwardProtocol {
+    Tcp,
+    Udp,
+}
+
+/// Forwarding of a host port to a port of the guest, written as
+/// `PROTOCOL:[HOST_ADDRESS:]HOST_PORT:GUEST_PORT`, e.g. `tcp:2222:22`.
+///
+/// The host port listens on localhost unless another address is given. The guest sees the
+/// forwarded connections coming from the gateway address.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct HostForward {
+    pub protocol: HostForwardProtocol,
+    pub host_addr: Ipv4Addr,
+    pub host_port: u16,
+    pub guest_port: u16,
+}
+
+impl FromStr for HostForward {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let invalid = || {
+            format!(
+                "invalid host forward '{}', expected PROTOCOL:[HOST_ADDRESS:]HOST_PORT:GUEST_PORT",
+                s
+            )
+        };
+        let parts: Vec<&str> = s.split(':').collect();
+        let (protocol, host_addr, host_port, guest_port) = match parts[..] {
+            [protocol, host_port, guest_port] => (protocol, None, host_port, guest_port),
+            [protocol, host_addr, host_port, guest_port] => {
+                (protocol, Some(host_addr), host_port, guest_port)
+            }
+            _ => return Err(invalid()),
+        };
+        let protocol = match protocol {
+            "tcp" => HostForwardProtocol::Tcp,
+            "udp" => HostForwardProtocol::Udp,
+            _ => return Err(format!("invalid host forward protocol '{}'", protocol)),
+        };
+        let host_addr = match host_addr {
+            Some(addr) => addr.parse().map_err(|_| invalid())?,
+            None => Ipv4Addr::LOCALHOST,
+        };
+        Ok(HostForward {
+            protocol,
+            host_addr,
+            host_port: host_port.parse().map_err(|_| invalid())?,
+            guest_port: guest_port.parse().map_err(|_| invalid())?,
+        })
+    }
+}
+
+impl Display for HostForward {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let protocol = match self.protocol {
+            HostForwardProtocol::Tcp => "tcp",
+            HostForwardProtocol::Udp => "udp",
+        };
+        write!(
+            f,
+            "{}:{}:{}:{}",
+            protocol, self.host_addr, self.host_port, self.guest_port
+        )
+    }
+}
+
+impl<'de> Deserialize<'de> for HostForward {
+    fn deserialize<D>(deserializer: D) -> std::result::Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let s = String::deserialize(deserializer)?;
+        FromStr::from_str(&s).map_err(serde::de::Error::custom)
+    }
+}
+
+impl Serialize for HostForward {
+    fn serialize<S>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.collect_str(&self)
+    }
+}
+
+/// Handle for a pseudo-tap interface connected to a user-mode network stack.
+pub struct UserNet {
+    socket: UnixSeqpacket,
+}
+
+impl UserNet {
+    /// Creates a user-mode network stack forwarding the host ports of `host_forwards` to the
+    /// guest, and returns the interface connected to it along with the stack, which must then be
+    /// run.
+    ///
+    /// The host ports are bound and the nameserver of the host is read here, so that the stack
+    /// itself can run in a sandbox.
+    pub fn new(host_forwards: &[HostForward]) -> Result<(UserNet, UserNetStack)> {
+        let (socket, stack_socket) = UnixSeqpacket::pair()
+            .map_err(SysError::from)
+            .map_err(Error::CreateSocket)?;
+        socket
+            .set_nonblocking(true)
+            .map_err(SysError::from)
+            .map_err(Error::CreateSocket)?;
+        let stack = Stack::new(stack_socket, VirtualNetwork::default(), host_forwards)?;
+        Ok((UserNet { socket }, UserNetStack { stack }))
+    }
+
+    fn try_clone(&self) -> Result<UserNet> {
+        Ok(UserNet {
+            socket: self
+                .socket
+                .try_clone()
+                .map_err(SysError::from)
+                .map_err(Error::CloneTap)?,
+        })
+    }
+}
+
+/// User-mode network stack serving a `UserNet` interface, which stops once the interface and all
+/// its clones are dropped.
+pub struct UserNetStack {
+    stack: Stack,
+}
+
+impl UserNetStack {
+    /// Returns the descriptors used by the stack, which must be kept to run it in a child process.
+    pub fn keep_rds(&self) -> Vec<RawDescriptor> {
+        self.stack.keep_rds()
+    }
+
+    /// Runs the stack in the current thread.
+    pub fn run(self) {
+        self.stack.run()
+    }
+
+    /// Runs the stack in a new thread of the current process.
+    pub fn spawn(self) -> Result<()> {
+        thread::Builder::new()
+            .name("usernet".to_string())
+            .spawn(move || self.run())
+            .map_err(Error::SpawnUserNet)?;
+        Ok(())
+    }
+}
+
+fn unsupported<T>() -> Result<T> {
+    Err(Error::IoctlError(SysError::new(libc::EOPNOTSUPP)))
+}
+
+impl TapT for UserNet {}
+
+impl TapTCommon for UserNet {
+    fn new_with_name(_name: &[u8], _vnet_hdr: bool, _multi_vq: bool) -> Result<Self> {
+        unsupported()
+    }
+
+    fn new(_vnet_hdr: bool, _multi_vq: bool) -> Result<Self> {
+        unsupported()
+    }
+
+    fn into_mq_taps(self, vq_pairs: u16) -> Result<Vec<Self>> {
+        // Every frame is a single message on the socket, so queues can share it.
+        let mut taps = Vec::new();
+        for _ in 1..vq_pairs {
+            taps.push(self.try_clone()?);
+        }
+        taps.insert(0, self);
+        Ok(taps)
+    }
+
+    fn ip_addr(&self) -> Result<net::Ipv4Addr> {
+        unsupported()
+    }
+
+    fn set_ip_addr(&self, _ip_addr: net::Ipv4Addr) -> Result<()> {
+        unsupported()
+    }
+
+    fn netmask(&self) -> Result<net::Ipv4Addr> {
+        unsupported()
+    }
+
+    fn set_netmask(&self, _netmask: net::Ipv4Addr) -> Result<()> {
+        unsupported()
+    }
+
+    fn mtu(&self) -> Result<u16> {
+        Ok(MTU)
+    }
+
+    fn set_mtu(&self, _mtu: u16) -> Result<()> {
+        unsupported()
+    }
+
+    fn mac_address(&self) -> Result<MacAddress> {
+        unsupported()
+    }
+
+    fn set_mac_address(&self, _mac_addr: MacAddress) -> Result<()> {
+        unsupported()
+    }
+
+    fn set_offload(&self, _flags: c_uint) -> Result<()> {
+        // The stack ignores checksums and segmentation offload of the guest, and only sends it
+        // complete packets of at most one MSS, so any offload is fine.
+        Ok(())
+    }
+
+    fn enable(&self) -> Result<()> {
+        Ok(())
+    }
+
+    fn set_vnet_hdr_size(&self, size: c_int) -> Result<()> {
+        if size as usize != std::mem::size_of::<virtio_net_hdr_v1>() {
+            return Err(Error::IoctlError(SysError::new(libc::EINVAL)));
+        }
+        Ok(())
+    }
+
+    fn get_ifreq(&self) -> net_sys::ifreq {
+        Default::default()
+    }
+
+    fn if_flags(&self) -> u32 {
+        net_sys::IFF_TAP | net_sys::IFF_NO_PI | net_sys::IFF_VNET_HDR | net_sys::IFF_MULTI_QUEUE
+    }
+
+    fn try_clone(&self) -> Result<Self> {
+        self.try_clone()
+    }
+
+    // Safe if caller provides a valid descriptor.
+    unsafe fn from_raw_descriptor(descriptor: RawDescriptor) -> Result<Self> {
+        Ok(UserNet {
+            socket: UnixSeqpacket::from_raw_descriptor(descriptor),
+        })
+    }
+}
+
+impl Read for UserNet {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.socket.read(buf)
+    }
+}
+
+impl Write for UserNet {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.socket.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl AsRawFd for UserNet {
+    fn as_raw_fd(&self) -> RawFd {
+        self.socket.as_raw_fd()
+    }
+}
+
+impl AsRawDescriptor for UserNet {
+    fn as_raw_descriptor(&self) -> RawDescriptor {
+        self.socket.as_raw_descriptor()
+    }
+}
+
+impl ReadNotifier for UserNet {
+    fn get_read_notifier(&self) -> &dyn AsRawDescriptor {
+        self
+    }
+}
+
+impl IntoAsync for UserNet {}
+volatile_impl!(UserNet);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_host_forward() {
+        assert_eq!(
+            "tcp:2222:22".parse::<HostForward>().unwrap(),
+            HostForward {
+                protocol: HostForwardProtocol::Tcp,
+                host_addr: Ipv4Addr::LOCALHOST,
+                host_port: 2222,
+                guest_port: 22,
+            }
+        );
+        let forward = "udp:0.0.0.0:5353:53".parse::<HostForward>().unwrap();
+        assert_eq!(
+            forward,
+            HostForward {
+                protocol: HostForwardProtocol::Udp,
+                host_addr: Ipv4Addr::UNSPECIFIED,
+                host_port: 5353,
+                guest_port: 53,
+            }
+        );
+        assert_eq!(forward.to_string().parse::<HostForward>(), Ok(forward));
+
+        assert!("sctp:1:2".parse::<HostForward>().is_err());
+        assert!("tcp:22".parse::<HostForward>().is_err());
+        assert!("tcp:65536:22".parse::<HostForward>().is_err());
+        assert!("tcp:localhost:2222:22".parse::<HostForward>().is_err());
+    }
+}
@@ -0,0 +1,197 @@
+// Copyright 2023 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! A DHCP server leasing the only guest address of the virtual network.
+
+use std::net::Ipv4Addr;
+
+use super::VirtualNetwork;
+
+pub const DHCP_SERVER_PORT: u16 = 67;
+pub const DHCP_CLIENT_PORT: u16 = 68;
+
+const BOOTREQUEST: u8 = 1;
+const BOOTREPLY: u8 = 2;
+const HTYPE_ETHERNET: u8 = 1;
+// Length of the fixed part of a message, up to the magic cookie.
+const FIXED_LEN: usize = 236;
+const MAGIC_COOKIE: [u8; 4] = [99, 130, 83, 99];
+
+const OPT_PAD: u8 = 0;
+const OPT_SUBNET_MASK: u8 = 1;
+const OPT_ROUTER: u8 = 3;
+const OPT_DNS_SERVER: u8 = 6;
+const OPT_REQUESTED_IP: u8 = 50;
+const OPT_LEASE_TIME: u8 = 51;
+const OPT_MESSAGE_TYPE: u8 = 53;
+const OPT_SERVER_ID: u8 = 54;
+const OPT_END: u8 = 255;
+
+const DHCPDISCOVER: u8 = 1;
+const DHCPOFFER: u8 = 2;
+const DHCPREQUEST: u8 = 3;
+const DHCPACK: u8 = 5;
+const DHCPNAK: u8 = 6;
+
+const LEASE_TIME_SECS: u32 = 24 * 60 * 60;
+
+fn ipv4(buf: &[u8]) -> Option<Ipv4Addr> {
+    let octets: [u8; 4] = buf.try_into().ok()?;
+    Some(Ipv4Addr::from(octets))
+}
+
+/// Returns the reply to the DHCP message `request` sent by the guest, if it needs one.
+pub fn handle_request(request: &[u8], net: &VirtualNetwork) -> Option<Vec<u8>> {
+    if request.len() < FIXED_LEN + MAGIC_COOKIE.len()
+        || request[0] != BOOTREQUEST
+        || request[1] != HTYPE_ETHERNET
+        || request[2] != 6
+        || request[FIXED_LEN..FIXED_LEN + 4] != MAGIC_COOKIE
+    {
+        return None;
+    }
+
+    let mut message_type = None;
+    let mut requested_ip = None;
+    let mut server_id = None;
+    let mut options = &request[FIXED_LEN + MAGIC_COOKIE.len()..];
+    while let Some(&code) = options.first() {
+        match code {
+            OPT_PAD => options = &options[1..],
+            OPT_END => break,
+            _ => {
+                let len = usize::from(*options.get(1)?);
+                let value = options.get(2..2 + len)?;
+                match code {
+                    OPT_MESSAGE_TYPE => message_type = value.first().copied(),
+                    OPT_REQUESTED_IP => requested_ip = ipv4(value),
+                    OPT_SERVER_ID => server_id = ipv4(value),
+                    _ => {}
+                }
+                options = &options[2 + len..];
+            }
+        }
+    }
+
+    let reply_type = match message_type? {
+        DHCPDISCOVER => DHCPOFFER,
+        DHCPREQUEST => {
+            // The guest picked the offer of another server.
+            if server_id.map_or(false, |id| id != net.gateway) {
+                return None;
+            }
+            // A renewing client has its address in `ciaddr` instead of an option.
+            let ciaddr = ipv4(&request[12..16])?;
+            match requested_ip.unwrap_or(ciaddr) {
+                ip if ip == net.guest => DHCPACK,
+                _ => DHCPNAK,
+            }
+        }
+        _ => return None,
+    };
+
+    let mut reply = vec![0u8; FIXED_LEN];
+    reply[0] = BOOTREPLY;
+    reply[1] = HTYPE_ETHERNET;
+    reply[2] = 6;
+    // Transaction ID.
+    reply[4..8].copy_from_slice(&request[4..8]);
+    // Flags.
+    reply[10..12].copy_from_slice(&request[10..12]);
+    if reply_type != DHCPNAK {
+        reply[16..20].copy_from_slice(&net.guest.octets());
+        reply[20..24].copy_from_slice(&net.gateway.octets());
+    }
+    // Relay agent and client hardware address.
+    reply[24..44].copy_from_slice(&request[24..44]);
+    reply.extend_from_slice(&MAGIC_COOKIE);
+
+    reply.extend_from_slice(&[OPT_MESSAGE_TYPE, 1, reply_type]);
+    reply.extend_from_slice(&[OPT_SERVER_ID, 4]);
+    reply.extend_from_slice(&net.gateway.octets());
+    if reply_type != DHCPNAK {
+        reply.extend_from_slice(&[OPT_LEASE_TIME, 4]);
+        reply.extend_from_slice(&LEASE_TIME_SECS.to_be_bytes());
+        reply.extend_from_slice(&[OPT_SUBNET_MASK, 4]);
+        reply.extend_from_slice(&net.netmask.octets());
+        reply.extend_from_slice(&[OPT_ROUTER, 4]);
+        reply.extend_from_slice(&net.gateway.octets());
+        reply.extend_from_slice(&[OPT_DNS_SERVER, 4]);
+        reply.extend_from_slice(&net.dns.octets());
+    }
+    reply.push(OPT_END);
+    Some(reply)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAC: [u8; 6] = [0x52, 0x54, 0x00, 0x12, 0x34, 0x56];
+
+    fn request(message_type: u8, requested_ip: Option<Ipv4Addr>) -> Vec<u8> {
+        let mut request = vec![0u8; FIXED_LEN];
+        request[0] = BOOTREQUEST;
+        request[1] = HTYPE_ETHERNET;
+        request[2] = 6;
+        request[4..8].copy_from_slice(&[1, 2, 3, 4]);
+        request[28..34].copy_from_slice(&MAC);
+        request.extend_from_slice(&MAGIC_COOKIE);
+        request.extend_from_slice(&[OPT_MESSAGE_TYPE, 1, message_type]);
+        if let Some(ip) = requested_ip {
+            request.extend_from_slice(&[OPT_REQUESTED_IP, 4]);
+            request.extend_from_slice(&ip.octets());
+        }
+        request.extend_from_slice(&[OPT_PAD, OPT_END]);
+        request
+    }
+
+    // Returns the value of option `code` in `reply`.
+    fn option(reply: &[u8], code: u8) -> Option<&[u8]> {
+        let mut options = &reply[FIXED_LEN + 4..];
+        while options[0] != OPT_END {
+            let len = usize::from(options[1]);
+            if options[0] == code {
+                return Some(&options[2..2 + len]);
+            }
+            options = &options[2 + len..];
+        }
+        None
+    }
+
+    #[test]
+    fn discover_request() {
+        let net = VirtualNetwork::default();
+
+        let offer = handle_request(&request(DHCPDISCOVER, None), &net).unwrap();
+        assert_eq!(offer[0], BOOTREPLY);
+        assert_eq!(&offer[4..8], &[1, 2, 3, 4]);
+        assert_eq!(&offer[16..20], &net.guest.octets());
+        assert_eq!(&offer[28..34], &MAC);
+        assert_eq!(option(&offer, OPT_MESSAGE_TYPE), Some(&[DHCPOFFER][..]));
+        assert_eq!(option(&offer, OPT_ROUTER), Some(&net.gateway.octets()[..]));
+        assert_eq!(option(&offer, OPT_DNS_SERVER), Some(&net.dns.octets()[..]));
+
+        let ack = handle_request(&request(DHCPREQUEST, Some(net.guest)), &net).unwrap();
+        assert_eq!(option(&ack, OPT_MESSAGE_TYPE), Some(&[DHCPACK][..]));
+        assert_eq!(&ack[16..20], &net.guest.octets());
+
+        let nak = handle_request(
+            &request(DHCPREQUEST, Some(Ipv4Addr::new(192, 168, 0, 2))),
+            &net,
+        )
+        .unwrap();
+        assert_eq!(option(&nak, OPT_MESSAGE_TYPE), Some(&[DHCPNAK][..]));
+        assert_eq!(&nak[16..20], &[0; 4]);
+    }
+
+    #[test]
+    fn other_messages_ignored() {
+        let net = VirtualNetwork::default();
+        // DHCPRELEASE.
+        assert!(handle_request(&request(7, None), &net).is_none());
+        // Truncated message.
+        assert!(handle_request(&request(DHCPDISCOVER, None)[..FIXED_LEN], &net).is_none());
+    }
+}
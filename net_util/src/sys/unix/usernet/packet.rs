@@ -0,0 +1,525 @@
+// Copyright 2023 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Parsing and building of the Ethernet frames exchanged with the guest, and of the ARP, IPv4,
+//! ICMP, UDP and TCP packets they carry.
+
+use std::convert::TryInto;
+use std::net::Ipv4Addr;
+use std::net::SocketAddrV4;
+
+pub const BROADCAST_MAC: [u8; 6] = [0xff; 6];
+pub const ETHERTYPE_ARP: u16 = 0x0806;
+pub const ETHERTYPE_IPV4: u16 = 0x0800;
+const ETH_HDR_LEN: usize = 14;
+
+const ARP_LEN: usize = 28;
+const ARP_HTYPE_ETHERNET: u16 = 1;
+pub const ARP_REQUEST: u16 = 1;
+const ARP_REPLY: u16 = 2;
+
+pub const IPPROTO_ICMP: u8 = 1;
+pub const IPPROTO_TCP: u8 = 6;
+pub const IPPROTO_UDP: u8 = 17;
+const IPV4_HDR_LEN: usize = 20;
+const IPV4_FLAG_MF: u16 = 0x2000;
+const IPV4_FRAGMENT_OFFSET_MASK: u16 = 0x1fff;
+const IPV4_TTL: u8 = 64;
+
+pub const ICMP_ECHO_REPLY: u8 = 0;
+pub const ICMP_ECHO_REQUEST: u8 = 8;
+
+const UDP_HDR_LEN: usize = 8;
+
+pub const TCP_FIN: u8 = 0x01;
+pub const TCP_SYN: u8 = 0x02;
+pub const TCP_RST: u8 = 0x04;
+pub const TCP_PSH: u8 = 0x08;
+pub const TCP_ACK: u8 = 0x10;
+const TCP_HDR_LEN: usize = 20;
+const TCP_OPT_END: u8 = 0;
+const TCP_OPT_NOP: u8 = 1;
+const TCP_OPT_MSS: u8 = 2;
+
+fn be16(buf: &[u8], offset: usize) -> u16 {
+    u16::from_be_bytes([buf[offset], buf[offset + 1]])
+}
+
+fn be32(buf: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes(buf[offset..offset + 4].try_into().unwrap())
+}
+
+fn ipv4(buf: &[u8], offset: usize) -> Ipv4Addr {
+    Ipv4Addr::new(
+        buf[offset],
+        buf[offset + 1],
+        buf[offset + 2],
+        buf[offset + 3],
+    )
+}
+
+// Adds `data` to the running one's complement sum `sum`.
+fn checksum_add(mut sum: u64, data: &[u8]) -> u64 {
+    let mut chunks = data.chunks_exact(2);
+    for chunk in &mut chunks {
+        sum += u64::from(u16::from_be_bytes([chunk[0], chunk[1]]));
+    }
+    if let [last] = chunks.remainder() {
+        sum += u64::from(*last) << 8;
+    }
+    sum
+}
+
+fn checksum_finish(mut sum: u64) -> u16 {
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+/// Returns the Internet checksum of `data`.
+pub fn checksum(data: &[u8]) -> u16 {
+    checksum_finish(checksum_add(0, data))
+}
+
+/// Source and destination MAC addresses of a frame.
+#[derive(Clone, Copy, Debug)]
+pub struct MacAddrs {
+    pub src: [u8; 6],
+    pub dst: [u8; 6],
+}
+
+/// An Ethernet frame, without the frame check sequence.
+pub struct EthernetFrame<'a> {
+    pub src: [u8; 6],
+    pub ethertype: u16,
+    pub payload: &'a [u8],
+}
+
+impl<'a> EthernetFrame<'a> {
+    pub fn parse(buf: &'a [u8]) -> Option<EthernetFrame<'a>> {
+        if buf.len() < ETH_HDR_LEN {
+            return None;
+        }
+        Some(EthernetFrame {
+            src: buf[6..12].try_into().unwrap(),
+            ethertype: be16(buf, 12),
+            payload: &buf[ETH_HDR_LEN..],
+        })
+    }
+}
+
+fn write_ethernet_header(out: &mut Vec<u8>, macs: MacAddrs, ethertype: u16) {
+    out.extend_from_slice(&macs.dst);
+    out.extend_from_slice(&macs.src);
+    out.extend_from_slice(&ethertype.to_be_bytes());
+}
+
+/// An ARP packet for IPv4 over Ethernet.
+pub struct ArpPacket {
+    pub op: u16,
+    pub sender_mac: [u8; 6],
+    pub sender_ip: Ipv4Addr,
+    pub target_ip: Ipv4Addr,
+}
+
+impl ArpPacket {
+    pub fn parse(buf: &[u8]) -> Option<ArpPacket> {
+        if buf.len() < ARP_LEN
+            || be16(buf, 0) != ARP_HTYPE_ETHERNET
+            || be16(buf, 2) != ETHERTYPE_IPV4
+            || buf[4] != 6
+            || buf[5] != 4
+        {
+            return None;
+        }
+        Some(ArpPacket {
+            op: be16(buf, 6),
+            sender_mac: buf[8..14].try_into().unwrap(),
+            sender_ip: ipv4(buf, 14),
+            target_ip: ipv4(buf, 24),
+        })
+    }
+
+    /// Appends to `out` the frame answering this request with `mac` as the address of the
+    /// target.
+    pub fn write_reply(&self, out: &mut Vec<u8>, mac: [u8; 6]) {
+        write_ethernet_header(
+            out,
+            MacAddrs {
+                src: mac,
+                dst: self.sender_mac,
+            },
+            ETHERTYPE_ARP,
+        );
+        out.extend_from_slice(&ARP_HTYPE_ETHERNET.to_be_bytes());
+        out.extend_from_slice(&ETHERTYPE_IPV4.to_be_bytes());
+        out.extend_from_slice(&[6, 4]);
+        out.extend_from_slice(&ARP_REPLY.to_be_bytes());
+        out.extend_from_slice(&mac);
+        out.extend_from_slice(&self.target_ip.octets());
+        out.extend_from_slice(&self.sender_mac);
+        out.extend_from_slice(&self.sender_ip.octets());
+    }
+}
+
+/// An IPv4 packet. Fragments aren't supported.
+pub struct Ipv4Packet<'a> {
+    pub src: Ipv4Addr,
+    pub dst: Ipv4Addr,
+    pub protocol: u8,
+    pub payload: &'a [u8],
+}
+
+impl<'a> Ipv4Packet<'a> {
+    pub fn parse(buf: &'a [u8]) -> Option<Ipv4Packet<'a>> {
+        if buf.len() < IPV4_HDR_LEN || buf[0] >> 4 != 4 {
+            return None;
+        }
+        let hdr_len = usize::from(buf[0] & 0xf) * 4;
+        // The total length is 0 in the packets sent with TCP segmentation offload that are larger
+        // than the field can hold.
+        let total_len = match usize::from(be16(buf, 2)) {
+            0 => buf.len(),
+            len => len,
+        };
+        if hdr_len < IPV4_HDR_LEN || total_len < hdr_len || total_len > buf.len() {
+            return None;
+        }
+        let frag = be16(buf, 6);
+        if frag & IPV4_FLAG_MF != 0 || frag & IPV4_FRAGMENT_OFFSET_MASK != 0 {
+            return None;
+        }
+        Some(Ipv4Packet {
+            src: ipv4(buf, 12),
+            dst: ipv4(buf, 16),
+            protocol: buf[9],
+            payload: &buf[hdr_len..total_len],
+        })
+    }
+}
+
+// Appends to `out` a frame carrying an IPv4 packet with the transport header and payload `l4`.
+// If given, the checksum field at `l4_checksum_offset` in `l4` is filled in, including the
+// pseudo-header of TCP and UDP when `pseudo_header` is true.
+fn write_ipv4_frame(
+    out: &mut Vec<u8>,
+    macs: MacAddrs,
+    src: Ipv4Addr,
+    dst: Ipv4Addr,
+    protocol: u8,
+    l4: &[u8],
+    l4_checksum_offset: usize,
+    pseudo_header: bool,
+) {
+    write_ethernet_header(out, macs, ETHERTYPE_IPV4);
+
+    let ip_start = out.len();
+    let total_len = (IPV4_HDR_LEN + l4.len()) as u16;
+    out.extend_from_slice(&[0x45, 0]);
+    out.extend_from_slice(&total_len.to_be_bytes());
+    // Identification, and the Don't Fragment flag.
+    out.extend_from_slice(&[0, 0, 0x40, 0]);
+    out.extend_from_slice(&[IPV4_TTL, protocol, 0, 0]);
+    out.extend_from_slice(&src.octets());
+    out.extend_from_slice(&dst.octets());
+    let ip_checksum = checksum(&out[ip_start..]);
+    out[ip_start + 10..ip_start + 12].copy_from_slice(&ip_checksum.to_be_bytes());
+
+    let l4_start = out.len();
+    out.extend_from_slice(l4);
+    let mut sum = 0;
+    if pseudo_header {
+        sum = checksum_add(sum, &src.octets());
+        sum = checksum_add(sum, &dst.octets());
+        sum += u64::from(protocol) + l4.len() as u64;
+    }
+    let mut l4_checksum = checksum_finish(checksum_add(sum, l4));
+    // A zero UDP checksum means that there is none.
+    if protocol == IPPROTO_UDP && l4_checksum == 0 {
+        l4_checksum = 0xffff;
+    }
+    let offset = l4_start + l4_checksum_offset;
+    out[offset..offset + 2].copy_from_slice(&l4_checksum.to_be_bytes());
+}
+
+/// Appends to `out` a frame carrying the ICMP message `icmp`, whose checksum is filled in.
+pub fn write_icmp_frame(
+    out: &mut Vec<u8>,
+    macs: MacAddrs,
+    src: Ipv4Addr,
+    dst: Ipv4Addr,
+    icmp: &[u8],
+) {
+    let mut icmp = icmp.to_vec();
+    icmp[2..4].copy_from_slice(&[0, 0]);
+    write_ipv4_frame(out, macs, src, dst, IPPROTO_ICMP, &icmp, 2, false);
+}
+
+/// A UDP datagram.
+pub struct UdpDatagram<'a> {
+    pub src_port: u16,
+    pub dst_port: u16,
+    pub payload: &'a [u8],
+}
+
+impl<'a> UdpDatagram<'a> {
+    pub fn parse(buf: &'a [u8]) -> Option<UdpDatagram<'a>> {
+        if buf.len() < UDP_HDR_LEN {
+            return None;
+        }
+        let len = usize::from(be16(buf, 4));
+        if len < UDP_HDR_LEN || len > buf.len() {
+            return None;
+        }
+        Some(UdpDatagram {
+            src_port: be16(buf, 0),
+            dst_port: be16(buf, 2),
+            payload: &buf[UDP_HDR_LEN..len],
+        })
+    }
+}
+
+/// Appends to `out` a frame carrying a UDP datagram from `src` to `dst`.
+pub fn write_udp_frame(
+    out: &mut Vec<u8>,
+    macs: MacAddrs,
+    src: SocketAddrV4,
+    dst: SocketAddrV4,
+    payload: &[u8],
+) {
+    let mut udp = Vec::with_capacity(UDP_HDR_LEN + payload.len());
+    udp.extend_from_slice(&src.port().to_be_bytes());
+    udp.extend_from_slice(&dst.port().to_be_bytes());
+    udp.extend_from_slice(&((UDP_HDR_LEN + payload.len()) as u16).to_be_bytes());
+    udp.extend_from_slice(&[0, 0]);
+    udp.extend_from_slice(payload);
+    write_ipv4_frame(out, macs, *src.ip(), *dst.ip(), IPPROTO_UDP, &udp, 6, true);
+}
+
+/// The fields of a TCP header that are used by the stack.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct TcpHeader {
+    pub seq: u32,
+    pub ack: u32,
+    pub flags: u8,
+    pub window: u16,
+    /// Maximum segment size option, only valid in SYN segments.
+    pub mss: Option<u16>,
+}
+
+/// A TCP segment.
+pub struct TcpSegment<'a> {
+    pub src_port: u16,
+    pub dst_port: u16,
+    pub header: TcpHeader,
+    pub payload: &'a [u8],
+}
+
+impl<'a> TcpSegment<'a> {
+    pub fn parse(buf: &'a [u8]) -> Option<TcpSegment<'a>> {
+        if buf.len() < TCP_HDR_LEN {
+            return None;
+        }
+        let hdr_len = usize::from(buf[12] >> 4) * 4;
+        if hdr_len < TCP_HDR_LEN || hdr_len > buf.len() {
+            return None;
+        }
+
+        let mut mss = None;
+        let mut options = &buf[TCP_HDR_LEN..hdr_len];
+        while let Some(&kind) = options.first() {
+            match kind {
+                TCP_OPT_END => break,
+                TCP_OPT_NOP => options = &options[1..],
+                _ => {
+                    let len = usize::from(*options.get(1)?);
+                    if len < 2 || len > options.len() {
+                        return None;
+                    }
+                    if kind == TCP_OPT_MSS && len == 4 {
+                        mss = Some(be16(options, 2));
+                    }
+                    options = &options[len..];
+                }
+            }
+        }
+
+        Some(TcpSegment {
+            src_port: be16(buf, 0),
+            dst_port: be16(buf, 2),
+            header: TcpHeader {
+                seq: be32(buf, 4),
+                ack: be32(buf, 8),
+                flags: buf[13],
+                window: be16(buf, 14),
+                mss,
+            },
+            payload: &buf[hdr_len..],
+        })
+    }
+
+    /// Returns the amount of sequence space used by the segment.
+    pub fn seq_len(&self) -> u32 {
+        let mut len = self.payload.len() as u32;
+        if self.header.flags & TCP_SYN != 0 {
+            len += 1;
+        }
+        if self.header.flags & TCP_FIN != 0 {
+            len += 1;
+        }
+        len
+    }
+}
+
+/// Appends to `out` a frame carrying a TCP segment from `src` to `dst`.
+pub fn write_tcp_frame(
+    out: &mut Vec<u8>,
+    macs: MacAddrs,
+    src: SocketAddrV4,
+    dst: SocketAddrV4,
+    header: &TcpHeader,
+    payload: &[u8],
+) {
+    let options_len = if header.mss.is_some() { 4 } else { 0 };
+    let hdr_len = TCP_HDR_LEN + options_len;
+    let mut tcp = Vec::with_capacity(hdr_len + payload.len());
+    tcp.extend_from_slice(&src.port().to_be_bytes());
+    tcp.extend_from_slice(&dst.port().to_be_bytes());
+    tcp.extend_from_slice(&header.seq.to_be_bytes());
+    tcp.extend_from_slice(&header.ack.to_be_bytes());
+    tcp.extend_from_slice(&[(hdr_len as u8 / 4) << 4, header.flags]);
+    tcp.extend_from_slice(&header.window.to_be_bytes());
+    // Checksum and urgent pointer.
+    tcp.extend_from_slice(&[0, 0, 0, 0]);
+    if let Some(mss) = header.mss {
+        tcp.extend_from_slice(&[TCP_OPT_MSS, 4]);
+        tcp.extend_from_slice(&mss.to_be_bytes());
+    }
+    tcp.extend_from_slice(payload);
+    write_ipv4_frame(out, macs, *src.ip(), *dst.ip(), IPPROTO_TCP, &tcp, 16, true);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MACS: MacAddrs = MacAddrs {
+        src: [0x52, 0x55, 0x0a, 0x00, 0x02, 0x02],
+        dst: [0x52, 0x54, 0x00, 0x12, 0x34, 0x56],
+    };
+
+    fn parse_ipv4(frame: &[u8]) -> Ipv4Packet {
+        let eth = EthernetFrame::parse(frame).unwrap();
+        assert_eq!(eth.src, MACS.src);
+        assert_eq!(eth.ethertype, ETHERTYPE_IPV4);
+        // A valid header sums up to 0.
+        assert_eq!(checksum(&eth.payload[..IPV4_HDR_LEN]), 0);
+        Ipv4Packet::parse(eth.payload).unwrap()
+    }
+
+    fn pseudo_header_checksum(ip: &Ipv4Packet) -> u16 {
+        let mut sum = checksum_add(0, &ip.src.octets());
+        sum = checksum_add(sum, &ip.dst.octets());
+        sum += u64::from(ip.protocol) + ip.payload.len() as u64;
+        checksum_finish(checksum_add(sum, ip.payload))
+    }
+
+    #[test]
+    fn checksum_odd_length() {
+        // Example from RFC 1071, with an odd trailing byte.
+        assert_eq!(
+            checksum(&[0x00, 0x01, 0xf2, 0x03, 0xf4, 0xf5, 0xf6, 0xf7]),
+            !0xddf2
+        );
+        assert_eq!(checksum(&[0x01]), !0x0100);
+    }
+
+    #[test]
+    fn udp_round_trip() {
+        let src = SocketAddrV4::new(Ipv4Addr::new(10, 0, 2, 3), 53);
+        let dst = SocketAddrV4::new(Ipv4Addr::new(10, 0, 2, 15), 40000);
+        let mut frame = Vec::new();
+        write_udp_frame(&mut frame, MACS, src, dst, b"hello");
+
+        let ip = parse_ipv4(&frame);
+        assert_eq!(ip.src, *src.ip());
+        assert_eq!(ip.dst, *dst.ip());
+        assert_eq!(ip.protocol, IPPROTO_UDP);
+        assert_eq!(pseudo_header_checksum(&ip), 0);
+        let udp = UdpDatagram::parse(ip.payload).unwrap();
+        assert_eq!(udp.src_port, 53);
+        assert_eq!(udp.dst_port, 40000);
+        assert_eq!(udp.payload, b"hello");
+    }
+
+    #[test]
+    fn tcp_round_trip() {
+        let src = SocketAddrV4::new(Ipv4Addr::new(93, 184, 216, 34), 80);
+        let dst = SocketAddrV4::new(Ipv4Addr::new(10, 0, 2, 15), 40000);
+        let header = TcpHeader {
+            seq: 0x12345678,
+            ack: 0x9abcdef0,
+            flags: TCP_SYN | TCP_ACK,
+            window: 65535,
+            mss: Some(1460),
+        };
+        let mut frame = Vec::new();
+        write_tcp_frame(&mut frame, MACS, src, dst, &header, b"data");
+
+        let ip = parse_ipv4(&frame);
+        assert_eq!(ip.protocol, IPPROTO_TCP);
+        assert_eq!(pseudo_header_checksum(&ip), 0);
+        let tcp = TcpSegment::parse(ip.payload).unwrap();
+        assert_eq!(tcp.src_port, 80);
+        assert_eq!(tcp.dst_port, 40000);
+        assert_eq!(tcp.header, header);
+        assert_eq!(tcp.payload, b"data");
+        assert_eq!(tcp.seq_len(), 5);
+    }
+
+    #[test]
+    fn ipv4_fragments_rejected() {
+        let mut frame = Vec::new();
+        write_udp_frame(
+            &mut frame,
+            MACS,
+            SocketAddrV4::new(Ipv4Addr::new(10, 0, 2, 15), 1),
+            SocketAddrV4::new(Ipv4Addr::new(10, 0, 2, 2), 2),
+            &[0; 16],
+        );
+        let ip = &mut frame[ETH_HDR_LEN..];
+        assert!(Ipv4Packet::parse(ip).is_some());
+        // More fragments flag.
+        ip[6] |= 0x20;
+        assert!(Ipv4Packet::parse(ip).is_none());
+    }
+
+    #[test]
+    fn arp_reply() {
+        let mut request = Vec::new();
+        request.extend_from_slice(&ARP_HTYPE_ETHERNET.to_be_bytes());
+        request.extend_from_slice(&ETHERTYPE_IPV4.to_be_bytes());
+        request.extend_from_slice(&[6, 4]);
+        request.extend_from_slice(&ARP_REQUEST.to_be_bytes());
+        request.extend_from_slice(&MACS.dst);
+        request.extend_from_slice(&[10, 0, 2, 15]);
+        request.extend_from_slice(&[0; 6]);
+        request.extend_from_slice(&[10, 0, 2, 2]);
+        let request = ArpPacket::parse(&request).unwrap();
+        assert_eq!(request.op, ARP_REQUEST);
+        assert_eq!(request.target_ip, Ipv4Addr::new(10, 0, 2, 2));
+
+        let mut frame = Vec::new();
+        request.write_reply(&mut frame, MACS.src);
+        let eth = EthernetFrame::parse(&frame).unwrap();
+        assert_eq!(eth.ethertype, ETHERTYPE_ARP);
+        assert_eq!(&frame[0..6], &MACS.dst);
+        let reply = ArpPacket::parse(eth.payload).unwrap();
+        assert_eq!(reply.op, ARP_REPLY);
+        assert_eq!(reply.sender_mac, MACS.src);
+        assert_eq!(reply.sender_ip, Ipv4Addr::new(10, 0, 2, 2));
+        assert_eq!(reply.target_ip, Ipv4Addr::new(10, 0, 2, 15));
+    }
+}
@@ -0,0 +1,936 @@
+// Copyright 2023 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! The event loop of the user-mode network stack, relaying the traffic of the guest to host
+//! sockets.
+
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::mem;
+use std::net::Ipv4Addr;
+use std::net::SocketAddr;
+use std::net::SocketAddrV4;
+use std::net::TcpListener;
+use std::net::TcpStream;
+use std::net::UdpSocket;
+use std::os::unix::io::FromRawFd;
+use std::time::Duration;
+use std::time::Instant;
+use std::time::SystemTime;
+
+use base::error;
+use base::warn;
+use base::AsRawDescriptor;
+use base::EventToken;
+use base::EventType;
+use base::RawDescriptor;
+use base::UnixSeqpacket;
+use base::WaitContext;
+use virtio_sys::virtio_net::virtio_net_hdr_v1;
+
+use super::dhcp;
+use super::dhcp::DHCP_CLIENT_PORT;
+use super::dhcp::DHCP_SERVER_PORT;
+use super::packet::write_icmp_frame;
+use super::packet::write_tcp_frame;
+use super::packet::write_udp_frame;
+use super::packet::ArpPacket;
+use super::packet::EthernetFrame;
+use super::packet::Ipv4Packet;
+use super::packet::MacAddrs;
+use super::packet::TcpHeader;
+use super::packet::TcpSegment;
+use super::packet::UdpDatagram;
+use super::packet::ARP_REQUEST;
+use super::packet::BROADCAST_MAC;
+use super::packet::ETHERTYPE_ARP;
+use super::packet::ETHERTYPE_IPV4;
+use super::packet::ICMP_ECHO_REPLY;
+use super::packet::ICMP_ECHO_REQUEST;
+use super::packet::IPPROTO_ICMP;
+use super::packet::IPPROTO_TCP;
+use super::packet::IPPROTO_UDP;
+use super::packet::TCP_ACK;
+use super::packet::TCP_RST;
+use super::packet::TCP_SYN;
+use super::tcp;
+use super::tcp::TcpConnection;
+use super::HostForward;
+use super::HostForwardProtocol;
+use super::VirtualNetwork;
+use crate::Error;
+use crate::Result;
+
+// MAC address of the gateway and DNS addresses.
+const GATEWAY_MAC: [u8; 6] = [0x52, 0x55, 0x0a, 0x00, 0x02, 0x02];
+const DNS_PORT: u16 = 53;
+const RESOLV_CONF_PATH: &str = "/etc/resolv.conf";
+// Largest frame sent by the guest, along with its virtio-net header.
+const MAX_FRAME_SIZE: usize = 65562;
+// Ports of the gateway address that forwarded connections appear to come from.
+const FIRST_FORWARD_PORT: u16 = 49152;
+const UDP_FLOW_TIMEOUT: Duration = Duration::from_secs(60);
+// Largest number of TCP connections and UDP flows relayed at once. Each of them takes a host
+// socket, so these keep the guest from using up the descriptors of the stack.
+const MAX_TCP_CONNECTIONS: usize = 512;
+const MAX_UDP_FLOWS: usize = 256;
+// Longest time between two checks for expired UDP flows.
+const MAX_WAIT: Duration = Duration::from_secs(1);
+
+#[derive(EventToken)]
+enum Token {
+    Guest,
+    TcpListener { index: usize },
+    TcpStream { id: u32 },
+    UdpFlow { id: u32 },
+    UdpForward { index: usize },
+}
+
+// Returns the address of the first IPv4 nameserver of the host.
+fn host_dns_server() -> Option<SocketAddrV4> {
+    let resolv_conf = fs::read_to_string(RESOLV_CONF_PATH).ok()?;
+    resolv_conf.lines().find_map(|line| {
+        let mut words = line.split_whitespace();
+        if words.next() != Some("nameserver") {
+            return None;
+        }
+        let ip: Ipv4Addr = words.next()?.parse().ok()?;
+        Some(SocketAddrV4::new(ip, DNS_PORT))
+    })
+}
+
+// Starts connecting a non-blocking TCP socket to `addr`.
+fn connect_nonblocking(addr: SocketAddrV4) -> io::Result<TcpStream> {
+    // Safe because this doesn't modify any memory and the return value is checked.
+    let fd = unsafe {
+        libc::socket(
+            libc::AF_INET,
+            libc::SOCK_STREAM | libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC,
+            0,
+        )
+    };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // Safe because `fd` is a valid socket that nothing else owns.
+    let stream = unsafe { TcpStream::from_raw_fd(fd) };
+    let sockaddr = libc::sockaddr_in {
+        sin_family: libc::AF_INET as libc::sa_family_t,
+        sin_port: addr.port().to_be(),
+        sin_addr: libc::in_addr {
+            s_addr: u32::from(*addr.ip()).to_be(),
+        },
+        sin_zero: [0; 8],
+    };
+    // Safe because `sockaddr` is a valid address of the given size and the return value is
+    // checked.
+    let ret = unsafe {
+        libc::connect(
+            fd,
+            &sockaddr as *const libc::sockaddr_in as *const libc::sockaddr,
+            mem::size_of::<libc::sockaddr_in>() as libc::socklen_t,
+        )
+    };
+    if ret < 0 {
+        let err = io::Error::last_os_error();
+        if err.raw_os_error() != Some(libc::EINPROGRESS) {
+            return Err(err);
+        }
+    }
+    Ok(stream)
+}
+
+// Returns an initial sequence number for a new connection.
+fn new_iss() -> u32 {
+    let nanos = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .subsec_nanos();
+    nanos.wrapping_mul(2654435761)
+}
+
+struct TcpEntry {
+    conn: TcpConnection,
+    guest: SocketAddrV4,
+    // Address the guest is connected to, as seen by the guest.
+    remote: SocketAddrV4,
+    events: EventType,
+}
+
+struct UdpFlow {
+    socket: UdpSocket,
+    guest: SocketAddrV4,
+    remote: SocketAddrV4,
+    last_used: Instant,
+}
+
+// A host peer sending datagrams to a forwarded UDP port.
+struct UdpForwardPeer {
+    index: usize,
+    peer: SocketAddrV4,
+    last_used: Instant,
+}
+
+pub struct Stack {
+    guest_socket: UnixSeqpacket,
+    wait_ctx: WaitContext<Token>,
+    net: VirtualNetwork,
+    dns_server: Option<SocketAddrV4>,
+    // Learned from the frames of the guest.
+    guest_mac: Option<[u8; 6]>,
+    tcp_forwards: Vec<(TcpListener, HostForward)>,
+    udp_forwards: Vec<(UdpSocket, HostForward)>,
+    tcp: HashMap<u32, TcpEntry>,
+    tcp_ids: HashMap<(SocketAddrV4, SocketAddrV4), u32>,
+    udp: HashMap<u32, UdpFlow>,
+    udp_ids: HashMap<(SocketAddrV4, SocketAddrV4), u32>,
+    // Peers of the forwarded UDP ports, by gateway port.
+    udp_forward_peers: HashMap<u16, UdpForwardPeer>,
+    next_id: u32,
+    next_forward_port: u16,
+}
+
+impl Stack {
+    /// Creates a stack for the guest at the other end of `guest_socket`, and sets up the host
+    /// forwards.
+    pub fn new(
+        guest_socket: UnixSeqpacket,
+        net: VirtualNetwork,
+        host_forwards: &[HostForward],
+    ) -> Result<Stack> {
+        guest_socket
+            .set_nonblocking(true)
+            .map_err(base::Error::from)
+            .map_err(Error::CreateSocket)?;
+        let wait_ctx = WaitContext::build_with(&[(&guest_socket, Token::Guest)])
+            .map_err(Error::CreateWaitContext)?;
+
+        let mut tcp_forwards = Vec::new();
+        let mut udp_forwards = Vec::new();
+        for forward in host_forwards {
+            let addr = SocketAddrV4::new(forward.host_addr, forward.host_port);
+            let map_err = |e| Error::HostForward(*forward, e);
+            match forward.protocol {
+                HostForwardProtocol::Tcp => {
+                    let listener = TcpListener::bind(addr).map_err(map_err)?;
+                    listener.set_nonblocking(true).map_err(map_err)?;
+                    wait_ctx
+                        .add(
+                            &listener,
+                            Token::TcpListener {
+                                index: tcp_forwards.len(),
+                            },
+                        )
+                        .map_err(Error::CreateWaitContext)?;
+                    tcp_forwards.push((listener, *forward));
+                }
+                HostForwardProtocol::Udp => {
+                    let socket = UdpSocket::bind(addr).map_err(map_err)?;
+                    socket.set_nonblocking(true).map_err(map_err)?;
+                    wait_ctx
+                        .add(
+                            &socket,
+                            Token::UdpForward {
+                                index: udp_forwards.len(),
+                            },
+                        )
+                        .map_err(Error::CreateWaitContext)?;
+                    udp_forwards.push((socket, *forward));
+                }
+            }
+        }
+
+        Ok(Stack {
+            guest_socket,
+            wait_ctx,
+            net,
+            dns_server: host_dns_server(),
+            guest_mac: None,
+            tcp_forwards,
+            udp_forwards,
+            tcp: HashMap::new(),
+            tcp_ids: HashMap::new(),
+            udp: HashMap::new(),
+            udp_ids: HashMap::new(),
+            udp_forward_peers: HashMap::new(),
+            next_id: 0,
+            next_forward_port: FIRST_FORWARD_PORT,
+        })
+    }
+
+    /// Returns the descriptors that the stack uses before relaying any connection.
+    pub fn keep_rds(&self) -> Vec<RawDescriptor> {
+        let mut keep_rds = vec![
+            self.guest_socket.as_raw_descriptor(),
+            self.wait_ctx.as_raw_descriptor(),
+        ];
+        keep_rds.extend(
+            self.tcp_forwards
+                .iter()
+                .map(|(listener, _)| listener.as_raw_descriptor()),
+        );
+        keep_rds.extend(
+            self.udp_forwards
+                .iter()
+                .map(|(socket, _)| socket.as_raw_descriptor()),
+        );
+        keep_rds
+    }
+
+    /// Runs the stack until the guest side of the socket pair is closed.
+    pub fn run(mut self) {
+        let mut frame = vec![0u8; MAX_FRAME_SIZE];
+        loop {
+            let now = Instant::now();
+            let timeout = self
+                .tcp
+                .values()
+                .filter_map(|entry| entry.conn.timeout())
+                .min()
+                .map_or(MAX_WAIT, |at| {
+                    at.saturating_duration_since(now).min(MAX_WAIT)
+                });
+            let events = match self.wait_ctx.wait_timeout(timeout) {
+                Ok(events) => events,
+                Err(e) => {
+                    error!("usernet: failed to wait for events: {}", e);
+                    return;
+                }
+            };
+
+            let now = Instant::now();
+            for event in events.iter() {
+                match event.token {
+                    Token::Guest => {
+                        if !self.handle_guest_frames(&mut frame, now) {
+                            return;
+                        }
+                    }
+                    Token::TcpListener { index } => self.accept_tcp_forward(index, now),
+                    Token::TcpStream { id } => {
+                        let mut out = Vec::new();
+                        if let Some(entry) = self.tcp.get_mut(&id) {
+                            entry.conn.handle_host_events(
+                                event.is_readable,
+                                event.is_writable,
+                                event.is_hungup,
+                                now,
+                                &mut out,
+                            );
+                        }
+                        self.send_tcp_segments(id, out);
+                    }
+                    Token::UdpFlow { id } => self.handle_udp_flow(id, now),
+                    Token::UdpForward { index } => self.handle_udp_forward(index, now),
+                }
+            }
+
+            let ids: Vec<u32> = self.tcp.keys().copied().collect();
+            for id in ids {
+                let mut out = Vec::new();
+                if let Some(entry) = self.tcp.get_mut(&id) {
+                    entry.conn.handle_timeout(now, &mut out);
+                }
+                self.send_tcp_segments(id, out);
+            }
+            self.expire_udp_flows(now);
+        }
+    }
+
+    fn alloc_id(&mut self) -> u32 {
+        loop {
+            let id = self.next_id;
+            self.next_id = self.next_id.wrapping_add(1);
+            if !self.tcp.contains_key(&id) && !self.udp.contains_key(&id) {
+                return id;
+            }
+        }
+    }
+
+    // Returns a port of the gateway address that isn't used by any forwarded connection to the
+    // guest port `guest`.
+    fn alloc_forward_port(&mut self, guest: SocketAddrV4) -> u16 {
+        loop {
+            let port = self.next_forward_port;
+            self.next_forward_port = self
+                .next_forward_port
+                .checked_add(1)
+                .unwrap_or(FIRST_FORWARD_PORT);
+            let remote = SocketAddrV4::new(self.net.gateway, port);
+            if !self.tcp_ids.contains_key(&(guest, remote))
+                && !self.udp_forward_peers.contains_key(&port)
+            {
+                return port;
+            }
+        }
+    }
+
+    // Returns the host address to relay the traffic for the virtual address `remote` to, if
+    // there is one.
+    fn host_addr(&self, remote: SocketAddrV4) -> Option<SocketAddrV4> {
+        let ip = *remote.ip();
+        if ip == self.net.dns {
+            return self.dns_server.filter(|_| remote.port() == DNS_PORT);
+        }
+        if ip == self.net.gateway {
+            return if cfg!(feature = "guest-to-host-net-loopback") {
+                Some(SocketAddrV4::new(Ipv4Addr::LOCALHOST, remote.port()))
+            } else {
+                None
+            };
+        }
+        if self.net.contains(ip)
+            || ip.is_broadcast()
+            || ip.is_multicast()
+            || ip.is_unspecified()
+            || ip.is_loopback()
+        {
+            return None;
+        }
+        Some(remote)
+    }
+
+    fn send_to_guest(&self, build: impl FnOnce(&mut Vec<u8>, MacAddrs), dst_mac: [u8; 6]) {
+        let hdr_len = mem::size_of::<virtio_net_hdr_v1>();
+        let mut buf = vec![0u8; hdr_len];
+        // The num_buffers field, which is always 1 without mergeable receive buffers.
+        buf[hdr_len - 2] = 1;
+        build(
+            &mut buf,
+            MacAddrs {
+                src: GATEWAY_MAC,
+                dst: dst_mac,
+            },
+        );
+        match self.guest_socket.send(&buf) {
+            Ok(_) => {}
+            // The guest isn't receiving fast enough, the frame is dropped.
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+            Err(e) => warn!("usernet: failed to send frame to guest: {}", e),
+        }
+    }
+
+    fn send_udp(&self, src: SocketAddrV4, dst: SocketAddrV4, payload: &[u8]) {
+        if let Some(mac) = self.guest_mac {
+            self.send_to_guest(
+                |buf, macs| write_udp_frame(buf, macs, src, dst, payload),
+                mac,
+            );
+        }
+    }
+
+    fn send_tcp(&self, src: SocketAddrV4, dst: SocketAddrV4, header: &TcpHeader, payload: &[u8]) {
+        if let Some(mac) = self.guest_mac {
+            self.send_to_guest(
+                |buf, macs| write_tcp_frame(buf, macs, src, dst, header, payload),
+                mac,
+            );
+        }
+    }
+
+    // Sends the segments of connection `id` to the guest, and updates the events it waits for or
+    // drops it if it's closed.
+    fn send_tcp_segments(&mut self, id: u32, segments: Vec<tcp::Segment>) {
+        let entry = match self.tcp.get_mut(&id) {
+            Some(entry) => entry,
+            None => return,
+        };
+        let (guest, remote) = (entry.guest, entry.remote);
+
+        let events = entry.conn.host_events();
+        let old_events = entry.events;
+        entry.events = events;
+        let closed = entry.conn.is_closed();
+        let res = if closed || events == EventType::None {
+            // Nothing is waited for once the host socket is done with, not even hangups.
+            if old_events != EventType::None {
+                self.wait_ctx.delete(entry.conn.stream())
+            } else {
+                Ok(())
+            }
+        } else if old_events == EventType::None {
+            self.wait_ctx
+                .add_for_event(entry.conn.stream(), events, Token::TcpStream { id })
+        } else if events != old_events {
+            self.wait_ctx
+                .modify(entry.conn.stream(), events, Token::TcpStream { id })
+        } else {
+            Ok(())
+        };
+        if let Err(e) = res {
+            error!("usernet: failed to wait for TCP socket events: {}", e);
+        }
+
+        for segment in segments {
+            self.send_tcp(remote, guest, &segment.header, &segment.payload);
+        }
+        if closed {
+            self.tcp.remove(&id);
+            self.tcp_ids.remove(&(guest, remote));
+        }
+    }
+
+    // Handles the frames sent by the guest. Returns false once the guest side is closed.
+    fn handle_guest_frames(&mut self, frame: &mut [u8], now: Instant) -> bool {
+        loop {
+            match self.guest_socket.recv(frame) {
+                Ok(0) => return false,
+                Ok(len) => self.handle_frame(&frame[..len], now),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return true,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => {
+                    error!("usernet: failed to receive frame from guest: {}", e);
+                    return false;
+                }
+            }
+        }
+    }
+
+    fn handle_frame(&mut self, frame: &[u8], now: Instant) {
+        let eth = match frame
+            .get(mem::size_of::<virtio_net_hdr_v1>()..)
+            .and_then(EthernetFrame::parse)
+        {
+            Some(eth) => eth,
+            None => return,
+        };
+        if eth.src[0] & 1 == 0 {
+            self.guest_mac = Some(eth.src);
+        }
+        match eth.ethertype {
+            ETHERTYPE_ARP => self.handle_arp(eth.payload),
+            ETHERTYPE_IPV4 => {
+                let ip = match Ipv4Packet::parse(eth.payload) {
+                    Some(ip) => ip,
+                    None => return,
+                };
+                match ip.protocol {
+                    IPPROTO_ICMP => self.handle_icmp(&ip),
+                    IPPROTO_TCP => self.handle_tcp(&ip, now),
+                    IPPROTO_UDP => self.handle_udp(&ip, now),
+                    _ => {}
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn handle_arp(&self, payload: &[u8]) {
+        let arp = match ArpPacket::parse(payload) {
+            Some(arp) => arp,
+            None => return,
+        };
+        if arp.op == ARP_REQUEST
+            && arp.sender_ip != arp.target_ip
+            && (arp.target_ip == self.net.gateway || arp.target_ip == self.net.dns)
+        {
+            self.send_to_guest(|buf, _| arp.write_reply(buf, GATEWAY_MAC), arp.sender_mac);
+        }
+    }
+
+    fn handle_icmp(&self, ip: &Ipv4Packet) {
+        let mac = match self.guest_mac {
+            Some(mac) => mac,
+            None => return,
+        };
+        if ip.payload.len() < 8
+            || ip.payload[0] != ICMP_ECHO_REQUEST
+            || (ip.dst != self.net.gateway && ip.dst != self.net.dns)
+        {
+            return;
+        }
+        let mut reply = ip.payload.to_vec();
+        reply[0] = ICMP_ECHO_REPLY;
+        self.send_to_guest(
+            |buf, macs| write_icmp_frame(buf, macs, ip.dst, ip.src, &reply),
+            mac,
+        );
+    }
+
+    fn handle_tcp(&mut self, ip: &Ipv4Packet, now: Instant) {
+        let segment = match TcpSegment::parse(ip.payload) {
+            Some(segment) => segment,
+            None => return,
+        };
+        let guest = SocketAddrV4::new(ip.src, segment.src_port);
+        let remote = SocketAddrV4::new(ip.dst, segment.dst_port);
+
+        if let Some(&id) = self.tcp_ids.get(&(guest, remote)) {
+            let mut out = Vec::new();
+            if let Some(entry) = self.tcp.get_mut(&id) {
+                entry.conn.handle_segment(&segment, now, &mut out);
+            }
+            self.send_tcp_segments(id, out);
+            return;
+        }
+
+        if segment.header.flags & (TCP_SYN | TCP_ACK | TCP_RST) == TCP_SYN
+            && self.tcp.len() < MAX_TCP_CONNECTIONS
+        {
+            if let Some(Ok(stream)) = self.host_addr(remote).map(connect_nonblocking) {
+                let id = self.alloc_id();
+                let conn = TcpConnection::connect(stream, &segment.header, new_iss());
+                self.tcp.insert(
+                    id,
+                    TcpEntry {
+                        conn,
+                        guest,
+                        remote,
+                        events: EventType::None,
+                    },
+                );
+                self.tcp_ids.insert((guest, remote), id);
+                self.send_tcp_segments(id, Vec::new());
+                return;
+            }
+        }
+        if let Some(reset) = tcp::reset_for(&segment) {
+            self.send_tcp(remote, guest, &reset, &[]);
+        }
+    }
+
+    fn accept_tcp_forward(&mut self, index: usize, now: Instant) {
+        loop {
+            let (listener, forward) = &self.tcp_forwards[index];
+            let guest = SocketAddrV4::new(self.net.guest, forward.guest_port);
+            let stream = match listener.accept() {
+                Ok((stream, _)) => stream,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    warn!("usernet: failed to accept forwarded connection: {}", e);
+                    return;
+                }
+            };
+            if self.tcp.len() >= MAX_TCP_CONNECTIONS {
+                warn!("usernet: too many connections, dropping forwarded connection");
+                continue;
+            }
+            if let Err(e) = stream.set_nonblocking(true) {
+                warn!("usernet: failed to set up forwarded connection: {}", e);
+                continue;
+            }
+
+            let remote = SocketAddrV4::new(self.net.gateway, self.alloc_forward_port(guest));
+            let id = self.alloc_id();
+            let mut out = Vec::new();
+            let conn = TcpConnection::accept(stream, new_iss(), now, &mut out);
+            self.tcp.insert(
+                id,
+                TcpEntry {
+                    conn,
+                    guest,
+                    remote,
+                    events: EventType::None,
+                },
+            );
+            self.tcp_ids.insert((guest, remote), id);
+            self.send_tcp_segments(id, out);
+        }
+    }
+
+    fn handle_udp(&mut self, ip: &Ipv4Packet, now: Instant) {
+        let datagram = match UdpDatagram::parse(ip.payload) {
+            Some(datagram) => datagram,
+            None => return,
+        };
+        let guest = SocketAddrV4::new(ip.src, datagram.src_port);
+        let remote = SocketAddrV4::new(ip.dst, datagram.dst_port);
+
+        if datagram.dst_port == DHCP_SERVER_PORT
+            && (ip.dst.is_broadcast() || ip.dst == self.net.gateway)
+        {
+            if let Some(reply) = dhcp::handle_request(datagram.payload, &self.net) {
+                self.send_to_guest(
+                    |buf, macs| {
+                        write_udp_frame(
+                            buf,
+                            macs,
+                            SocketAddrV4::new(self.net.gateway, DHCP_SERVER_PORT),
+                            SocketAddrV4::new(Ipv4Addr::BROADCAST, DHCP_CLIENT_PORT),
+                            &reply,
+                        )
+                    },
+                    BROADCAST_MAC,
+                );
+            }
+            return;
+        }
+
+        if ip.dst == self.net.gateway {
+            if let Some(peer) = self.udp_forward_peers.get_mut(&datagram.dst_port) {
+                peer.last_used = now;
+                let socket = &self.udp_forwards[peer.index].0;
+                if let Err(e) = socket.send_to(datagram.payload, peer.peer) {
+                    warn!("usernet: failed to send forwarded UDP datagram: {}", e);
+                }
+                return;
+            }
+        }
+
+        let id = match self.udp_ids.get(&(guest, remote)) {
+            Some(&id) => id,
+            None => match self.open_udp_flow(guest, remote) {
+                Some(id) => id,
+                None => return,
+            },
+        };
+        if let Some(flow) = self.udp.get_mut(&id) {
+            flow.last_used = now;
+            // Errors are reported by the next receive, like ICMP errors would be.
+            let _ = flow.socket.send(datagram.payload);
+        }
+    }
+
+    fn open_udp_flow(&mut self, guest: SocketAddrV4, remote: SocketAddrV4) -> Option<u32> {
+        // The datagrams of new flows are dropped until others expire.
+        if self.udp.len() >= MAX_UDP_FLOWS {
+            return None;
+        }
+        let host_addr = self.host_addr(remote)?;
+        let socket = match UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0))
+            .and_then(|socket| socket.connect(host_addr).map(|_| socket))
+            .and_then(|socket| socket.set_nonblocking(true).map(|_| socket))
+        {
+            Ok(socket) => socket,
+            Err(e) => {
+                warn!("usernet: failed to open UDP socket to {}: {}", host_addr, e);
+                return None;
+            }
+        };
+        let id = self.alloc_id();
+        if let Err(e) = self.wait_ctx.add(&socket, Token::UdpFlow { id }) {
+            error!("usernet: failed to wait for UDP socket events: {}", e);
+            return None;
+        }
+        self.udp.insert(
+            id,
+            UdpFlow {
+                socket,
+                guest,
+                remote,
+                last_used: Instant::now(),
+            },
+        );
+        self.udp_ids.insert((guest, remote), id);
+        Some(id)
+    }
+
+    fn handle_udp_flow(&mut self, id: u32, now: Instant) {
+        let mut buf = [0u8; u16::MAX as usize];
+        let flow = match self.udp.get_mut(&id) {
+            Some(flow) => flow,
+            None => return,
+        };
+        flow.last_used = now;
+        let (guest, remote) = (flow.guest, flow.remote);
+        loop {
+            let len = match self.udp[&id].socket.recv(&mut buf) {
+                Ok(len) => len,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                // Including errors of previous sends, like refused connections.
+                Err(_) => return,
+            };
+            self.send_udp(remote, guest, &buf[..len]);
+        }
+    }
+
+    fn handle_udp_forward(&mut self, index: usize, now: Instant) {
+        let mut buf = [0u8; u16::MAX as usize];
+        loop {
+            let (socket, forward) = &self.udp_forwards[index];
+            let guest = SocketAddrV4::new(self.net.guest, forward.guest_port);
+            let (len, peer) = match socket.recv_from(&mut buf) {
+                Ok((len, SocketAddr::V4(peer))) => (len, peer),
+                Ok(_) => continue,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(_) => return,
+            };
+            let port = match self
+                .udp_forward_peers
+                .iter()
+                .find(|(_, p)| p.index == index && p.peer == peer)
+            {
+                Some((&port, _)) => port,
+                None => {
+                    let port = self.alloc_forward_port(guest);
+                    self.udp_forward_peers.insert(
+                        port,
+                        UdpForwardPeer {
+                            index,
+                            peer,
+                            last_used: now,
+                        },
+                    );
+                    port
+                }
+            };
+            if let Some(peer) = self.udp_forward_peers.get_mut(&port) {
+                peer.last_used = now;
+            }
+            self.send_udp(
+                SocketAddrV4::new(self.net.gateway, port),
+                guest,
+                &buf[..len],
+            );
+        }
+    }
+
+    fn expire_udp_flows(&mut self, now: Instant) {
+        let expired: Vec<u32> = self
+            .udp
+            .iter()
+            .filter(|(_, flow)| now.saturating_duration_since(flow.last_used) > UDP_FLOW_TIMEOUT)
+            .map(|(&id, _)| id)
+            .collect();
+        for id in expired {
+            if let Some(flow) = self.udp.remove(&id) {
+                let _ = self.wait_ctx.delete(&flow.socket);
+                self.udp_ids.remove(&(flow.guest, flow.remote));
+            }
+        }
+        self.udp_forward_peers
+            .retain(|_, peer| now.saturating_duration_since(peer.last_used) <= UDP_FLOW_TIMEOUT);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::packet::TCP_FIN;
+    use super::super::packet::TCP_PSH;
+    use super::*;
+
+    const GUEST_MAC: [u8; 6] = [0x52, 0x54, 0x00, 0x12, 0x34, 0x56];
+
+    // Runs a stack in a thread and returns the guest side of its socket.
+    fn start_stack(host_forwards: &[HostForward]) -> UnixSeqpacket {
+        let (guest, stack_socket) = UnixSeqpacket::pair().unwrap();
+        let stack = Stack::new(stack_socket, VirtualNetwork::default(), host_forwards).unwrap();
+        std::thread::spawn(move || stack.run());
+        guest
+            .set_read_timeout(Some(Duration::from_secs(10)))
+            .unwrap();
+        guest
+    }
+
+    fn send_frame(guest: &UnixSeqpacket, build: impl FnOnce(&mut Vec<u8>, MacAddrs)) {
+        let mut buf = vec![0u8; mem::size_of::<virtio_net_hdr_v1>()];
+        build(
+            &mut buf,
+            MacAddrs {
+                src: GUEST_MAC,
+                dst: GATEWAY_MAC,
+            },
+        );
+        guest.send(&buf).unwrap();
+    }
+
+    // Receives the next IPv4 packet sent to the guest and passes it to `check`.
+    fn recv_ipv4(guest: &UnixSeqpacket, check: impl FnOnce(&Ipv4Packet)) {
+        let frame = guest.recv_as_vec().unwrap();
+        let eth = EthernetFrame::parse(&frame[mem::size_of::<virtio_net_hdr_v1>()..]).unwrap();
+        assert_eq!(eth.src, GATEWAY_MAC);
+        assert_eq!(eth.ethertype, ETHERTYPE_IPV4);
+        check(&Ipv4Packet::parse(eth.payload).unwrap());
+    }
+
+    #[test]
+    fn icmp_echo() {
+        let net = VirtualNetwork::default();
+        let guest = start_stack(&[]);
+        let request = [ICMP_ECHO_REQUEST, 0, 0, 0, 0, 1, 0, 1, 0xaa, 0xbb];
+        send_frame(&guest, |buf, macs| {
+            write_icmp_frame(buf, macs, net.guest, net.gateway, &request)
+        });
+        recv_ipv4(&guest, |ip| {
+            assert_eq!(ip.src, net.gateway);
+            assert_eq!(ip.dst, net.guest);
+            assert_eq!(ip.protocol, IPPROTO_ICMP);
+            assert_eq!(ip.payload[0], ICMP_ECHO_REPLY);
+            assert_eq!(&ip.payload[4..], &request[4..]);
+        });
+    }
+
+    #[test]
+    fn tcp_forward() {
+        let net = VirtualNetwork::default();
+        // Find a free port to forward.
+        let host_port = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let forward = HostForward {
+            protocol: HostForwardProtocol::Tcp,
+            host_addr: Ipv4Addr::LOCALHOST,
+            host_port,
+            guest_port: 22,
+        };
+        let guest = start_stack(&[forward]);
+        // Let the stack learn the address of the guest.
+        send_frame(&guest, |buf, macs| {
+            write_icmp_frame(
+                buf,
+                macs,
+                net.guest,
+                net.gateway,
+                &[ICMP_ECHO_REQUEST, 0, 0, 0, 0, 0, 0, 0],
+            )
+        });
+        recv_ipv4(&guest, |_| {});
+
+        let mut host = TcpStream::connect(("127.0.0.1", host_port)).unwrap();
+        let mut syn = None;
+        recv_ipv4(&guest, |ip| {
+            let segment = TcpSegment::parse(ip.payload).unwrap();
+            assert_eq!(ip.src, net.gateway);
+            assert_eq!(segment.dst_port, 22);
+            assert_eq!(segment.header.flags, TCP_SYN);
+            syn = Some((segment.src_port, segment.header.seq));
+        });
+        let (port, iss) = syn.unwrap();
+        let remote = SocketAddrV4::new(net.gateway, port);
+        let local = SocketAddrV4::new(net.guest, 22);
+
+        let send_segment = |seq: u32, ack: u32, flags: u8, payload: &[u8]| {
+            let header = TcpHeader {
+                seq,
+                ack,
+                flags,
+                window: 65535,
+                mss: None,
+            };
+            send_frame(&guest, |buf, macs| {
+                write_tcp_frame(buf, macs, local, remote, &header, payload)
+            });
+        };
+        send_segment(100, iss.wrapping_add(1), TCP_SYN | TCP_ACK, &[]);
+        recv_ipv4(&guest, |ip| {
+            let segment = TcpSegment::parse(ip.payload).unwrap();
+            assert_eq!(segment.header.flags, TCP_ACK);
+            assert_eq!(segment.header.ack, 101);
+        });
+
+        send_segment(101, iss.wrapping_add(1), TCP_ACK | TCP_PSH, b"SSH-2.0");
+        recv_ipv4(&guest, |ip| {
+            let segment = TcpSegment::parse(ip.payload).unwrap();
+            assert_eq!(segment.header.ack, 108);
+        });
+        let mut buf = [0u8; 7];
+        io::Read::read_exact(&mut host, &mut buf).unwrap();
+        assert_eq!(&buf, b"SSH-2.0");
+
+        io::Write::write_all(&mut host, b"hi").unwrap();
+        recv_ipv4(&guest, |ip| {
+            let segment = TcpSegment::parse(ip.payload).unwrap();
+            assert_eq!(segment.header.seq, iss.wrapping_add(1));
+            assert_eq!(segment.payload, b"hi");
+        });
+
+        // The guest resets the connection, which closes the host side.
+        send_segment(108, iss.wrapping_add(3), TCP_RST | TCP_FIN, &[]);
+        assert_eq!(io::Read::read(&mut host, &mut buf).unwrap_or(0), 0);
+    }
+}
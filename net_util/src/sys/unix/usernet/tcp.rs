@@ -0,0 +1,744 @@
+// Copyright 2023 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! TCP connections of the guest, terminated by the stack and relayed to host sockets.
+//!
+//! The data received from either side is buffered until the other side accepts it, and the
+//! windows advertised to the guest follow the space left in those buffers. Frames sent to the
+//! guest can be dropped if it doesn't receive them fast enough, so unacknowledged data is
+//! retransmitted after a timeout. Segments received out of order are dropped and left for the
+//! guest to retransmit.
+
+use std::cmp::min;
+use std::collections::VecDeque;
+use std::io;
+use std::io::Read;
+use std::io::Write;
+use std::net::Shutdown;
+use std::net::TcpStream;
+use std::time::Duration;
+use std::time::Instant;
+
+use base::EventType;
+
+use super::packet::TcpHeader;
+use super::packet::TcpSegment;
+use super::packet::TCP_ACK;
+use super::packet::TCP_FIN;
+use super::packet::TCP_PSH;
+use super::packet::TCP_RST;
+use super::packet::TCP_SYN;
+
+/// Maximum segment size of the virtual network, for an MTU of 1500.
+pub const MSS: u16 = 1460;
+// MSS to assume if the guest doesn't announce one.
+const DEFAULT_GUEST_MSS: u16 = 536;
+// Largest amount of data read from the host and not yet acknowledged by the guest.
+const SEND_BUFFER_SIZE: usize = 256 * 1024;
+// Largest amount of data received from the guest and not yet written to the host. Window scaling
+// isn't used so this is also the largest window that can be advertised.
+const RECV_BUFFER_SIZE: usize = 65535;
+const READ_CHUNK_SIZE: usize = 16 * 1024;
+const INITIAL_RTO: Duration = Duration::from_millis(500);
+const MAX_RTO: Duration = Duration::from_secs(8);
+const MAX_RETRANSMISSIONS: u32 = 10;
+
+// Returns whether sequence number `a` comes before `b`.
+fn seq_lt(a: u32, b: u32) -> bool {
+    (a.wrapping_sub(b) as i32) < 0
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum State {
+    /// The guest sent a SYN and the host socket is connecting.
+    Connecting,
+    /// The host socket connected and a SYN-ACK was sent to the guest.
+    SynReceived,
+    /// A host connection to a forwarded port was accepted and a SYN was sent to the guest.
+    SynSent,
+    Established,
+    /// The connection was reset or both sides closed it.
+    Closed,
+}
+
+/// A segment to send to the guest.
+#[derive(Debug)]
+pub struct Segment {
+    pub header: TcpHeader,
+    pub payload: Vec<u8>,
+}
+
+/// Returns the header of the reset to send in response to `segment`, which doesn't belong to any
+/// connection, if it needs one.
+pub fn reset_for(segment: &TcpSegment) -> Option<TcpHeader> {
+    let header = &segment.header;
+    if header.flags & TCP_RST != 0 {
+        return None;
+    }
+    Some(if header.flags & TCP_ACK != 0 {
+        TcpHeader {
+            seq: header.ack,
+            flags: TCP_RST,
+            ..Default::default()
+        }
+    } else {
+        TcpHeader {
+            ack: header.seq.wrapping_add(segment.seq_len()),
+            flags: TCP_RST | TCP_ACK,
+            ..Default::default()
+        }
+    })
+}
+
+/// A TCP connection between the guest and a host socket.
+pub struct TcpConnection {
+    stream: TcpStream,
+    state: State,
+    // Initial sequence number of the stack.
+    iss: u32,
+    // Oldest sequence number not acknowledged by the guest, the one of the first byte of
+    // `send_buf`.
+    snd_una: u32,
+    // Next sequence number to send, which is lower than `snd_max` while retransmitting.
+    snd_nxt: u32,
+    snd_max: u32,
+    // Window and maximum segment size of the guest.
+    snd_wnd: u32,
+    guest_mss: usize,
+    send_buf: VecDeque<u8>,
+    // Next sequence number expected from the guest.
+    rcv_nxt: u32,
+    recv_buf: VecDeque<u8>,
+    // Whether the host closed its side of the connection, which is passed on to the guest after
+    // the buffered data.
+    host_eof: bool,
+    fin_seq: Option<u32>,
+    fin_acked: bool,
+    // Whether the guest closed its side of the connection, which is passed on to the host after
+    // the buffered data.
+    guest_fin: bool,
+    host_shutdown: bool,
+    rto: Duration,
+    retransmit_at: Option<Instant>,
+    retransmissions: u32,
+}
+
+impl TcpConnection {
+    fn new(stream: TcpStream, state: State, iss: u32) -> TcpConnection {
+        TcpConnection {
+            stream,
+            state,
+            iss,
+            snd_una: iss,
+            snd_nxt: iss.wrapping_add(1),
+            snd_max: iss.wrapping_add(1),
+            snd_wnd: 0,
+            guest_mss: usize::from(DEFAULT_GUEST_MSS),
+            send_buf: VecDeque::new(),
+            rcv_nxt: 0,
+            recv_buf: VecDeque::new(),
+            host_eof: false,
+            fin_seq: None,
+            fin_acked: false,
+            guest_fin: false,
+            host_shutdown: false,
+            rto: INITIAL_RTO,
+            retransmit_at: None,
+            retransmissions: 0,
+        }
+    }
+
+    /// Creates the connection requested by the SYN `syn` of the guest. `stream` is the host
+    /// socket connecting to the destination, and the SYN is answered once it is connected.
+    pub fn connect(stream: TcpStream, syn: &TcpHeader, iss: u32) -> TcpConnection {
+        let mut conn = TcpConnection::new(stream, State::Connecting, iss);
+        conn.rcv_nxt = syn.seq.wrapping_add(1);
+        conn.set_guest_params(syn);
+        conn
+    }
+
+    /// Creates a connection to the guest for the accepted host connection `stream`, and sends
+    /// the SYN to `out`.
+    pub fn accept(stream: TcpStream, iss: u32, now: Instant, out: &mut Vec<Segment>) -> Self {
+        let mut conn = TcpConnection::new(stream, State::SynSent, iss);
+        conn.send_syn(now, out);
+        conn
+    }
+
+    /// Returns the host socket of the connection.
+    pub fn stream(&self) -> &TcpStream {
+        &self.stream
+    }
+
+    /// Returns the events of the host socket that the connection waits for.
+    pub fn host_events(&self) -> EventType {
+        match self.state {
+            State::Connecting => EventType::Write,
+            State::Established => {
+                let read = !self.host_eof && self.send_buf.len() < SEND_BUFFER_SIZE;
+                let write = !self.recv_buf.is_empty();
+                match (read, write) {
+                    (true, true) => EventType::ReadWrite,
+                    (true, false) => EventType::Read,
+                    (false, true) => EventType::Write,
+                    (false, false) => EventType::None,
+                }
+            }
+            _ => EventType::None,
+        }
+    }
+
+    /// Returns whether the connection is over and can be dropped.
+    pub fn is_closed(&self) -> bool {
+        self.state == State::Closed || (self.fin_acked && self.guest_fin && self.host_shutdown)
+    }
+
+    /// Returns when `handle_timeout()` should be called next.
+    pub fn timeout(&self) -> Option<Instant> {
+        self.retransmit_at
+    }
+
+    fn set_guest_params(&mut self, syn: &TcpHeader) {
+        self.snd_wnd = u32::from(syn.window);
+        self.guest_mss = usize::from(min(syn.mss.unwrap_or(DEFAULT_GUEST_MSS), MSS));
+    }
+
+    fn window(&self) -> u16 {
+        (RECV_BUFFER_SIZE - self.recv_buf.len()) as u16
+    }
+
+    fn arm_timer(&mut self, now: Instant) {
+        if self.retransmit_at.is_none() {
+            self.retransmit_at = Some(now + self.rto);
+        }
+    }
+
+    fn send_syn(&mut self, now: Instant, out: &mut Vec<Segment>) {
+        let mut flags = TCP_SYN;
+        if self.state == State::SynReceived {
+            flags |= TCP_ACK;
+        }
+        out.push(Segment {
+            header: TcpHeader {
+                seq: self.iss,
+                ack: self.rcv_nxt,
+                flags,
+                window: self.window(),
+                mss: Some(MSS),
+            },
+            payload: Vec::new(),
+        });
+        self.arm_timer(now);
+    }
+
+    fn send_ack(&self, out: &mut Vec<Segment>) {
+        out.push(Segment {
+            header: TcpHeader {
+                seq: self.snd_nxt,
+                ack: self.rcv_nxt,
+                flags: TCP_ACK,
+                window: self.window(),
+                mss: None,
+            },
+            payload: Vec::new(),
+        });
+    }
+
+    fn reset(&mut self, out: &mut Vec<Segment>) {
+        out.push(Segment {
+            header: TcpHeader {
+                seq: self.snd_nxt,
+                ack: self.rcv_nxt,
+                flags: TCP_RST | TCP_ACK,
+                ..Default::default()
+            },
+            payload: Vec::new(),
+        });
+        self.state = State::Closed;
+    }
+
+    /// Handles the segment `segment` sent by the guest, and adds the segments to send in response
+    /// to `out`.
+    pub fn handle_segment(&mut self, segment: &TcpSegment, now: Instant, out: &mut Vec<Segment>) {
+        let header = &segment.header;
+        if header.flags & TCP_RST != 0 {
+            self.state = State::Closed;
+            return;
+        }
+
+        match self.state {
+            State::Connecting | State::Closed => return,
+            State::SynSent => {
+                if header.flags & (TCP_SYN | TCP_ACK) == TCP_SYN | TCP_ACK
+                    && header.ack == self.iss.wrapping_add(1)
+                {
+                    self.rcv_nxt = header.seq.wrapping_add(1);
+                    self.set_guest_params(header);
+                    self.establish();
+                    self.send_ack(out);
+                    self.transmit(now, false, out);
+                } else if header.flags & TCP_ACK != 0 {
+                    out.push(Segment {
+                        header: reset_for(segment).unwrap(),
+                        payload: Vec::new(),
+                    });
+                }
+                return;
+            }
+            State::SynReceived => {
+                if header.flags & TCP_SYN != 0 {
+                    // The SYN-ACK was lost.
+                    self.send_syn(now, out);
+                    return;
+                }
+                if header.flags & TCP_ACK == 0 || header.ack != self.iss.wrapping_add(1) {
+                    return;
+                }
+                self.establish();
+            }
+            State::Established => {
+                if header.flags & TCP_SYN != 0 {
+                    self.send_ack(out);
+                    return;
+                }
+            }
+        }
+
+        if header.flags & TCP_ACK != 0 {
+            self.handle_ack(header, now);
+        }
+        self.handle_data(segment, out);
+        self.transmit(now, false, out);
+    }
+
+    fn establish(&mut self) {
+        self.state = State::Established;
+        self.snd_una = self.iss.wrapping_add(1);
+        self.retransmit_at = None;
+        self.retransmissions = 0;
+        self.rto = INITIAL_RTO;
+    }
+
+    fn handle_ack(&mut self, header: &TcpHeader, now: Instant) {
+        let acked = header.ack.wrapping_sub(self.snd_una);
+        if acked > self.snd_max.wrapping_sub(self.snd_una) {
+            // Acknowledges data that was never sent.
+            return;
+        }
+        self.snd_wnd = u32::from(header.window);
+        if acked == 0 {
+            return;
+        }
+
+        let data_acked = min(acked as usize, self.send_buf.len());
+        self.send_buf.drain(..data_acked);
+        if self
+            .fin_seq
+            .map_or(false, |seq| header.ack == seq.wrapping_add(1))
+        {
+            self.fin_acked = true;
+        }
+        self.snd_una = header.ack;
+        if seq_lt(self.snd_nxt, self.snd_una) {
+            self.snd_nxt = self.snd_una;
+        }
+
+        self.retransmissions = 0;
+        self.rto = INITIAL_RTO;
+        self.retransmit_at = None;
+        if self.snd_una != self.snd_max {
+            self.arm_timer(now);
+        }
+    }
+
+    fn handle_data(&mut self, segment: &TcpSegment, out: &mut Vec<Segment>) {
+        let fin = segment.header.flags & TCP_FIN != 0;
+        if segment.payload.is_empty() && !fin {
+            return;
+        }
+
+        let mut payload = segment.payload;
+        let mut seq = segment.header.seq;
+        if seq_lt(seq, self.rcv_nxt) {
+            let duplicate = self.rcv_nxt.wrapping_sub(seq) as usize;
+            if duplicate > payload.len() || (duplicate == payload.len() && !fin) {
+                // A retransmission of what was already received.
+                self.send_ack(out);
+                return;
+            }
+            payload = &payload[duplicate..];
+            seq = self.rcv_nxt;
+        }
+        if seq != self.rcv_nxt || self.guest_fin {
+            self.send_ack(out);
+            return;
+        }
+
+        let len = min(payload.len(), RECV_BUFFER_SIZE - self.recv_buf.len());
+        self.recv_buf.extend(&payload[..len]);
+        self.rcv_nxt = self.rcv_nxt.wrapping_add(len as u32);
+        if fin && len == payload.len() {
+            self.rcv_nxt = self.rcv_nxt.wrapping_add(1);
+            self.guest_fin = true;
+        }
+        if self.flush_to_host().is_err() {
+            self.reset(out);
+            return;
+        }
+        self.send_ack(out);
+    }
+
+    // Writes as much of the data received from the guest as possible to the host socket, and
+    // shuts its write side down after the last of it when the guest closed the connection.
+    // Returns the amount of data written.
+    fn flush_to_host(&mut self) -> io::Result<usize> {
+        let mut written = 0;
+        while !self.recv_buf.is_empty() {
+            let (data, _) = self.recv_buf.as_slices();
+            match self.stream.write(data) {
+                Ok(len) => {
+                    self.recv_buf.drain(..len);
+                    written += len;
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        if self.guest_fin && self.recv_buf.is_empty() && !self.host_shutdown {
+            // The host may have closed the connection already.
+            let _ = self.stream.shutdown(Shutdown::Write);
+            self.host_shutdown = true;
+        }
+        Ok(written)
+    }
+
+    fn read_from_host(&mut self) -> io::Result<()> {
+        let mut buf = [0u8; READ_CHUNK_SIZE];
+        while !self.host_eof && self.send_buf.len() < SEND_BUFFER_SIZE {
+            let len = min(buf.len(), SEND_BUFFER_SIZE - self.send_buf.len());
+            match self.stream.read(&mut buf[..len]) {
+                Ok(0) => self.host_eof = true,
+                Ok(len) => self.send_buf.extend(&buf[..len]),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    // Sends the data and FIN that the window of the guest allows. When `probe` is true, at least
+    // one byte is sent even if the window is closed.
+    fn transmit(&mut self, now: Instant, probe: bool, out: &mut Vec<Segment>) {
+        if self.state != State::Established {
+            return;
+        }
+
+        let mut window = self.snd_wnd as usize;
+        if probe {
+            window = window.max(1);
+        }
+        loop {
+            let offset = self.snd_nxt.wrapping_sub(self.snd_una) as usize;
+            if offset >= self.send_buf.len() {
+                break;
+            }
+            let len = min(
+                min(self.send_buf.len() - offset, self.guest_mss),
+                window.saturating_sub(offset),
+            );
+            if len == 0 {
+                break;
+            }
+            let mut flags = TCP_ACK;
+            if offset + len == self.send_buf.len() {
+                flags |= TCP_PSH;
+            }
+            out.push(Segment {
+                header: TcpHeader {
+                    seq: self.snd_nxt,
+                    ack: self.rcv_nxt,
+                    flags,
+                    window: self.window(),
+                    mss: None,
+                },
+                payload: self.send_buf.range(offset..offset + len).copied().collect(),
+            });
+            self.snd_nxt = self.snd_nxt.wrapping_add(len as u32);
+        }
+
+        // The FIN follows the data, and may have to be sent again along with it.
+        let offset = self.snd_nxt.wrapping_sub(self.snd_una) as usize;
+        if self.host_eof
+            && offset == self.send_buf.len()
+            && self.fin_seq.map_or(true, |seq| seq == self.snd_nxt)
+        {
+            out.push(Segment {
+                header: TcpHeader {
+                    seq: self.snd_nxt,
+                    ack: self.rcv_nxt,
+                    flags: TCP_FIN | TCP_ACK,
+                    window: self.window(),
+                    mss: None,
+                },
+                payload: Vec::new(),
+            });
+            self.fin_seq = Some(self.snd_nxt);
+            self.snd_nxt = self.snd_nxt.wrapping_add(1);
+        }
+
+        if seq_lt(self.snd_max, self.snd_nxt) {
+            self.snd_max = self.snd_nxt;
+        }
+        // Also covers data held back by a closed window, which is probed on timeouts.
+        if self.snd_una != self.snd_max || !self.send_buf.is_empty() {
+            self.arm_timer(now);
+        }
+    }
+
+    /// Handles the events of the host socket, and adds the segments to send in response to
+    /// `out`.
+    pub fn handle_host_events(
+        &mut self,
+        readable: bool,
+        writable: bool,
+        hungup: bool,
+        now: Instant,
+        out: &mut Vec<Segment>,
+    ) {
+        match self.state {
+            State::Connecting => {
+                if !writable && !hungup {
+                    return;
+                }
+                match self.stream.take_error() {
+                    Ok(None) if self.stream.peer_addr().is_ok() => {
+                        self.state = State::SynReceived;
+                        self.send_syn(now, out);
+                    }
+                    _ => self.reset(out),
+                }
+                return;
+            }
+            State::Established => {}
+            _ => return,
+        }
+
+        if writable {
+            let old_window = self.window();
+            match self.flush_to_host() {
+                // Let the guest know that it can send more if its window was getting small.
+                Ok(written) if written > 0 && usize::from(old_window) < RECV_BUFFER_SIZE / 2 => {
+                    self.send_ack(out)
+                }
+                Ok(_) => {}
+                Err(_) => {
+                    self.reset(out);
+                    return;
+                }
+            }
+        }
+        if (readable || hungup) && self.read_from_host().is_err() {
+            self.reset(out);
+            return;
+        }
+        self.transmit(now, false, out);
+    }
+
+    /// Retransmits what the guest didn't acknowledge in time, and adds the segments to send to
+    /// `out`. Does nothing if `timeout()` isn't reached yet.
+    pub fn handle_timeout(&mut self, now: Instant, out: &mut Vec<Segment>) {
+        match self.retransmit_at {
+            Some(at) if at <= now => {}
+            _ => return,
+        }
+        self.retransmit_at = None;
+        self.retransmissions += 1;
+        if self.retransmissions > MAX_RETRANSMISSIONS {
+            self.reset(out);
+            return;
+        }
+        self.rto = min(self.rto * 2, MAX_RTO);
+
+        match self.state {
+            State::SynSent | State::SynReceived => self.send_syn(now, out),
+            State::Established => {
+                // Go back to the oldest unacknowledged data.
+                self.snd_nxt = self.snd_una;
+                self.transmit(now, true, out);
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+
+    use super::*;
+
+    const GUEST_ISS: u32 = 1000;
+    const ISS: u32 = 0xffff_fff0;
+
+    fn guest_segment(seq: u32, ack: u32, flags: u8, payload: &[u8]) -> TcpSegment {
+        TcpSegment {
+            src_port: 40000,
+            dst_port: 80,
+            header: TcpHeader {
+                seq,
+                ack,
+                flags,
+                window: 65535,
+                mss: if flags & TCP_SYN != 0 {
+                    Some(MSS)
+                } else {
+                    None
+                },
+            },
+            payload,
+        }
+    }
+
+    // Returns an established connection to a host socket, along with the host end of it.
+    fn establish(now: Instant) -> (TcpConnection, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        stream.set_nonblocking(true).unwrap();
+        let (host, _) = listener.accept().unwrap();
+
+        let syn = guest_segment(GUEST_ISS, 0, TCP_SYN, &[]);
+        let mut conn = TcpConnection::connect(stream, &syn.header, ISS);
+        assert_eq!(conn.host_events(), EventType::Write);
+
+        let mut out = Vec::new();
+        conn.handle_host_events(false, true, false, now, &mut out);
+        assert_eq!(out.len(), 1);
+        let syn_ack = &out[0].header;
+        assert_eq!(syn_ack.flags, TCP_SYN | TCP_ACK);
+        assert_eq!(syn_ack.seq, ISS);
+        assert_eq!(syn_ack.ack, GUEST_ISS + 1);
+        assert_eq!(syn_ack.mss, Some(MSS));
+
+        out.clear();
+        conn.handle_segment(
+            &guest_segment(GUEST_ISS + 1, ISS.wrapping_add(1), TCP_ACK, &[]),
+            now,
+            &mut out,
+        );
+        assert!(out.is_empty());
+        assert_eq!(conn.host_events(), EventType::Read);
+        (conn, host)
+    }
+
+    #[test]
+    fn guest_to_host() {
+        let now = Instant::now();
+        let (mut conn, mut host) = establish(now);
+        let mut out = Vec::new();
+
+        conn.handle_segment(
+            &guest_segment(GUEST_ISS + 1, ISS.wrapping_add(1), TCP_ACK, b"hello"),
+            now,
+            &mut out,
+        );
+        assert_eq!(out.len(), 1);
+        assert_eq!(out[0].header.ack, GUEST_ISS + 6);
+        let mut buf = [0u8; 5];
+        host.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"hello");
+
+        // A retransmission is acknowledged but not written again.
+        out.clear();
+        conn.handle_segment(
+            &guest_segment(GUEST_ISS + 1, ISS.wrapping_add(1), TCP_ACK, b"hello"),
+            now,
+            &mut out,
+        );
+        assert_eq!(out.len(), 1);
+        assert_eq!(out[0].header.ack, GUEST_ISS + 6);
+
+        // Segments after a gap are dropped.
+        out.clear();
+        conn.handle_segment(
+            &guest_segment(GUEST_ISS + 10, ISS.wrapping_add(1), TCP_ACK, b"later"),
+            now,
+            &mut out,
+        );
+        assert_eq!(out[0].header.ack, GUEST_ISS + 6);
+
+        // The guest closes its side.
+        out.clear();
+        conn.handle_segment(
+            &guest_segment(GUEST_ISS + 6, ISS.wrapping_add(1), TCP_FIN | TCP_ACK, &[]),
+            now,
+            &mut out,
+        );
+        assert_eq!(out[0].header.ack, GUEST_ISS + 7);
+        assert_eq!(host.read(&mut buf).unwrap(), 0);
+        assert!(!conn.is_closed());
+    }
+
+    #[test]
+    fn host_to_guest() {
+        let now = Instant::now();
+        let (mut conn, mut host) = establish(now);
+        let mut out = Vec::new();
+
+        let data = vec![0x5a; 3000];
+        host.write_all(&data).unwrap();
+        drop(host);
+        // Wait for the data and the FIN to arrive, so that they are sent at once.
+        while !conn.host_eof {
+            conn.read_from_host().unwrap();
+        }
+        conn.handle_host_events(true, false, false, now, &mut out);
+        // Two full segments, a partial one and the FIN.
+        assert_eq!(out.len(), 4);
+        assert_eq!(out[0].header.seq, ISS.wrapping_add(1));
+        assert_eq!(out[0].payload.len(), usize::from(MSS));
+        assert_eq!(out[2].payload.len(), 3000 - 2 * usize::from(MSS));
+        assert_eq!(out[3].header.flags, TCP_FIN | TCP_ACK);
+        let fin_seq = out[3].header.seq;
+        assert_eq!(fin_seq, ISS.wrapping_add(3001));
+        assert!(conn.timeout().is_some());
+
+        // Nothing is acknowledged in time, so everything is sent again.
+        out.clear();
+        let later = now + INITIAL_RTO;
+        conn.handle_timeout(later, &mut out);
+        assert_eq!(out.len(), 4);
+        assert_eq!(out[0].header.seq, ISS.wrapping_add(1));
+
+        out.clear();
+        conn.handle_segment(
+            &guest_segment(
+                GUEST_ISS + 1,
+                fin_seq.wrapping_add(1),
+                TCP_FIN | TCP_ACK,
+                &[],
+            ),
+            later,
+            &mut out,
+        );
+        assert!(conn.timeout().is_none());
+        assert!(conn.is_closed());
+    }
+
+    #[test]
+    fn reset_unknown_segments() {
+        let syn = guest_segment(GUEST_ISS, 0, TCP_SYN, &[]);
+        let reset = reset_for(&syn).unwrap();
+        assert_eq!(reset.flags, TCP_RST | TCP_ACK);
+        assert_eq!(reset.ack, GUEST_ISS + 1);
+
+        let ack = guest_segment(GUEST_ISS, 1234, TCP_ACK, b"data");
+        let reset = reset_for(&ack).unwrap();
+        assert_eq!(reset.flags, TCP_RST);
+        assert_eq!(reset.seq, 1234);
+
+        assert!(reset_for(&guest_segment(GUEST_ISS, 0, TCP_RST, &[])).is_none());
+    }
+}
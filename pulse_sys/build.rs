@@ -0,0 +1,22 @@
+// Copyright 2023 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+fn main() {
+    // Skip installing dependencies when generating documents.
+    if std::env::var("CARGO_DOC").is_ok() {
+        return;
+    }
+
+    // libpulse is unix only
+    if std::env::var("CARGO_CFG_UNIX").is_err() {
+        return;
+    }
+
+    // The simple API, along with pa_strerror() from libpulse.
+    for library in ["libpulse-simple", "libpulse"] {
+        if let Err(e) = pkg_config::probe_library(library) {
+            panic!("{} not found: {}", library, e);
+        }
+    }
+}
@@ -0,0 +1,69 @@
+// Copyright 2023 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Bindings to the simple API of libpulse, the PulseAudio client library.
+
+#![cfg(unix)]
+#![allow(non_camel_case_types)]
+
+use std::os::raw::c_char;
+use std::os::raw::c_int;
+use std::os::raw::c_void;
+
+pub const PA_STREAM_PLAYBACK: c_int = 1;
+pub const PA_STREAM_RECORD: c_int = 2;
+
+pub const PA_SAMPLE_U8: c_int = 0;
+pub const PA_SAMPLE_S16LE: c_int = 3;
+pub const PA_SAMPLE_S32LE: c_int = 7;
+pub const PA_SAMPLE_S24_32LE: c_int = 11;
+
+#[repr(C)]
+pub struct pa_sample_spec {
+    pub format: c_int,
+    pub rate: u32,
+    pub channels: u8,
+}
+
+#[repr(C)]
+pub struct pa_buffer_attr {
+    pub maxlength: u32,
+    pub tlength: u32,
+    pub prebuf: u32,
+    pub minreq: u32,
+    pub fragsize: u32,
+}
+
+#[repr(C)]
+pub struct pa_simple {
+    _private: [u8; 0],
+}
+
+extern "C" {
+    pub fn pa_simple_new(
+        server: *const c_char,
+        name: *const c_char,
+        dir: c_int,
+        dev: *const c_char,
+        stream_name: *const c_char,
+        ss: *const pa_sample_spec,
+        map: *const c_void,
+        attr: *const pa_buffer_attr,
+        error: *mut c_int,
+    ) -> *mut pa_simple;
+    pub fn pa_simple_free(s: *mut pa_simple);
+    pub fn pa_simple_write(
+        s: *mut pa_simple,
+        data: *const c_void,
+        bytes: usize,
+        error: *mut c_int,
+    ) -> c_int;
+    pub fn pa_simple_read(
+        s: *mut pa_simple,
+        data: *mut c_void,
+        bytes: usize,
+        error: *mut c_int,
+    ) -> c_int;
+    pub fn pa_strerror(error: c_int) -> *const c_char;
+}
@@ -51,6 +51,8 @@ use devices::PflashParameters;
 use devices::SerialHardware;
 use devices::SerialParameters;
 use devices::StubPciParameters;
+#[cfg(feature = "qcow")]
+use disk::CompressionType;
 #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
 use hypervisor::CpuHybridType;
 use hypervisor::ProtectionType;
@@ -60,6 +62,8 @@ use resources::AddressRange;
 use serde::Deserialize;
 #[cfg(feature = "gpu")]
 use serde_keyvalue::FromKeyValues;
+use vm_control::input::FingerPath;
+use vm_control::DiskThrottleConfig;
 
 #[cfg(feature = "gpu")]
 use super::gpu_config::fixup_gpu_display_options;
@@ -132,7 +136,11 @@ pub enum CrossPlatformCommands {
     Balloon(BalloonCommand),
     #[cfg(feature = "balloon")]
     BalloonStats(BalloonStatsCommand),
+    #[cfg(feature = "balloon")]
+    BalloonWs(BalloonWsCommand),
     Battery(BatteryCommand),
+    #[cfg(feature = "qcow")]
+    CompressQcow2(CompressQcow2Command),
     #[cfg(feature = "composite-disk")]
     CreateComposite(CreateCompositeCommand),
     #[cfg(feature = "qcow")]
@@ -141,7 +149,10 @@ pub enum CrossPlatformCommands {
     Disk(DiskCommand),
     #[cfg(feature = "gpu")]
     Gpu(GpuCommand),
+    Input(InputCommand),
     MakeRT(MakeRTCommand),
+    Mem(MemCommand),
+    Net(NetCommand),
     Resume(ResumeCommand),
     Run(RunCommand),
     Stop(StopCommand),
@@ -184,6 +195,15 @@ pub struct BalloonStatsCommand {
     pub socket_path: String,
 }
 
+#[derive(argh::FromArgs)]
+#[argh(subcommand, name = "balloon_ws")]
+/// Prints virtio balloon working set for a `VM_SOCKET`
+pub struct BalloonWsCommand {
+    #[argh(positional, arg_name = "VM_SOCKET")]
+    /// VM Socket path
+    pub socket_path: String,
+}
+
 #[derive(FromArgs)]
 #[argh(subcommand, name = "battery")]
 /// Modify battery
@@ -204,6 +224,22 @@ pub struct BatteryCommand {
     pub socket_path: String,
 }
 
+#[cfg(feature = "qcow")]
+#[derive(FromArgs)]
+#[argh(subcommand, name = "compress_qcow2")]
+/// Write a compressed copy of a disk image to a new Qcow2 image
+pub struct CompressQcow2Command {
+    #[argh(positional, arg_name = "SOURCE")]
+    /// path to the disk image to copy; backing files of the source are merged into the copy
+    pub source_path: String,
+    #[argh(positional, arg_name = "PATH")]
+    /// path to the new qcow2 file to create
+    pub file_path: String,
+    #[argh(option, default = "CompressionType::Deflate")]
+    /// compression algorithm to use for clusters: deflate (default) or zstd
+    pub compression: CompressionType,
+}
+
 #[cfg(feature = "composite-disk")]
 #[derive(FromArgs)]
 #[argh(subcommand, name = "create_composite")]
@@ -238,6 +274,10 @@ pub struct CreateQcow2Command {
 #[argh(subcommand)]
 pub enum DiskSubcommand {
     Resize(ResizeDiskSubcommand),
+    Snapshot(SnapshotDiskSubcommand),
+    DeleteSnapshot(DeleteSnapshotDiskSubcommand),
+    ExternalSnapshot(ExternalSnapshotDiskSubcommand),
+    Throttle(ThrottleDiskSubcommand),
 }
 
 #[derive(FromArgs)]
@@ -255,6 +295,71 @@ pub struct ResizeDiskSubcommand {
     pub socket_path: String,
 }
 
+#[derive(FromArgs)]
+/// save a snapshot of a disk inside its image
+#[argh(subcommand, name = "snapshot")]
+pub struct SnapshotDiskSubcommand {
+    #[argh(positional, arg_name = "DISK_INDEX")]
+    /// disk index
+    pub disk_index: usize,
+    #[argh(positional, arg_name = "NAME")]
+    /// snapshot name
+    pub name: String,
+    #[argh(positional, arg_name = "VM_SOCKET")]
+    /// VM Socket path
+    pub socket_path: String,
+}
+
+#[derive(FromArgs)]
+/// delete a snapshot from the image of a disk
+#[argh(subcommand, name = "delete_snapshot")]
+pub struct DeleteSnapshotDiskSubcommand {
+    #[argh(positional, arg_name = "DISK_INDEX")]
+    /// disk index
+    pub disk_index: usize,
+    #[argh(positional, arg_name = "NAME")]
+    /// snapshot name or ID
+    pub name: String,
+    #[argh(positional, arg_name = "VM_SOCKET")]
+    /// VM Socket path
+    pub socket_path: String,
+}
+
+#[derive(FromArgs)]
+/// switch a disk to a new qcow2 overlay on top of its current image
+#[argh(subcommand, name = "external_snapshot")]
+pub struct ExternalSnapshotDiskSubcommand {
+    #[argh(positional, arg_name = "DISK_INDEX")]
+    /// disk index
+    pub disk_index: usize,
+    #[argh(positional, arg_name = "IMAGE")]
+    /// path of the current disk image, recorded as the backing file of the overlay, which must be
+    /// the image used by the disk
+    pub image_path: String,
+    #[argh(positional, arg_name = "OVERLAY")]
+    /// path of the overlay to create
+    pub overlay_path: String,
+    #[argh(positional, arg_name = "VM_SOCKET")]
+    /// VM Socket path
+    pub socket_path: String,
+}
+
+#[derive(FromArgs)]
+/// set the I/O rate limits of a disk
+#[argh(subcommand, name = "throttle")]
+pub struct ThrottleDiskSubcommand {
+    #[argh(positional, arg_name = "DISK_INDEX")]
+    /// disk index
+    pub disk_index: usize,
+    #[argh(positional, arg_name = "LIMITS")]
+    /// rate limits in the format of the throttle option of --block, without brackets. Limits that
+    /// aren't given are removed.
+    pub limits: DiskThrottleConfig,
+    #[argh(positional, arg_name = "VM_SOCKET")]
+    /// VM Socket path
+    pub socket_path: String,
+}
+
 #[derive(FromArgs)]
 #[argh(subcommand, name = "disk")]
 /// Manage attached virtual disk devices
@@ -263,6 +368,218 @@ pub struct DiskCommand {
     pub command: DiskSubcommand,
 }
 
+fn parse_link_state(s: &str) -> Result<bool, String> {
+    match s {
+        "up" => Ok(true),
+        "down" => Ok(false),
+        _ => Err(format!(
+            "invalid link state '{}', expected 'up' or 'down'",
+            s
+        )),
+    }
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand)]
+pub enum NetSubcommand {
+    Link(LinkNetSubcommand),
+}
+
+#[derive(FromArgs)]
+/// bring the link of a network device up or down
+#[argh(subcommand, name = "link")]
+pub struct LinkNetSubcommand {
+    #[argh(positional, arg_name = "NET_INDEX")]
+    /// net device index, in the order the devices were given on the command line
+    pub net_index: usize,
+    #[argh(positional, arg_name = "STATE", from_str_fn(parse_link_state))]
+    /// up | down
+    pub up: bool,
+    #[argh(positional, arg_name = "VM_SOCKET")]
+    /// VM Socket path
+    pub socket_path: String,
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand, name = "net")]
+/// Manage attached virtual network devices
+pub struct NetCommand {
+    #[argh(subcommand)]
+    pub command: NetSubcommand,
+}
+
+fn parse_finger(s: &str) -> Result<FingerPath, String> {
+    let parse_position = |p: &str| -> Option<(i32, i32)> {
+        let (x, y) = p.split_once(',')?;
+        Some((x.trim().parse().ok()?, y.trim().parse().ok()?))
+    };
+    let positions = match s.split_once(':') {
+        Some((from, to)) => parse_position(from).zip(parse_position(to)),
+        None => parse_position(s).map(|p| (p, p)),
+    };
+    positions.ok_or_else(|| format!("invalid finger '{}', expected X,Y or X,Y:X,Y", s))
+}
+
+fn parse_button(s: &str) -> Result<u16, String> {
+    match s {
+        "left" => Ok(vm_control::input::BTN_LEFT),
+        "right" => Ok(vm_control::input::BTN_RIGHT),
+        "middle" => Ok(vm_control::input::BTN_MIDDLE),
+        _ => Err(format!(
+            "invalid button '{}', expected 'left', 'right' or 'middle'",
+            s
+        )),
+    }
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand)]
+pub enum InputSubcommand {
+    Click(ClickInputSubcommand),
+    Key(KeyInputSubcommand),
+    Move(MoveInputSubcommand),
+    Text(TextInputSubcommand),
+    Touch(TouchInputSubcommand),
+}
+
+#[derive(FromArgs)]
+/// press keys together then release them, e.g. `leftctrl+c`
+#[argh(subcommand, name = "key")]
+pub struct KeyInputSubcommand {
+    #[argh(option)]
+    /// name of the input device, e.g. keyboard0
+    pub device: String,
+    #[argh(positional, arg_name = "KEYS")]
+    /// keys joined by '+', each either a key code, a KEY_* name of linux/input-event-codes.h in
+    /// lower case without the prefix, or a character of a US keyboard
+    pub keys: String,
+    #[argh(positional, arg_name = "VM_SOCKET")]
+    /// VM Socket path
+    pub socket_path: String,
+}
+
+#[derive(FromArgs)]
+/// type text with a US keyboard layout
+#[argh(subcommand, name = "text")]
+pub struct TextInputSubcommand {
+    #[argh(option)]
+    /// name of the input device, e.g. keyboard0
+    pub device: String,
+    #[argh(positional, arg_name = "TEXT")]
+    /// text to type
+    pub text: String,
+    #[argh(positional, arg_name = "VM_SOCKET")]
+    /// VM Socket path
+    pub socket_path: String,
+}
+
+#[derive(FromArgs)]
+/// move a mouse pointer by a relative offset
+#[argh(subcommand, name = "move")]
+pub struct MoveInputSubcommand {
+    #[argh(option)]
+    /// name of the input device, e.g. mouse0
+    pub device: String,
+    #[argh(option, default = "0")]
+    /// horizontal offset
+    pub dx: i32,
+    #[argh(option, default = "0")]
+    /// vertical offset
+    pub dy: i32,
+    #[argh(positional, arg_name = "VM_SOCKET")]
+    /// VM Socket path
+    pub socket_path: String,
+}
+
+#[derive(FromArgs)]
+/// press and release a mouse button
+#[argh(subcommand, name = "click")]
+pub struct ClickInputSubcommand {
+    #[argh(option)]
+    /// name of the input device, e.g. mouse0
+    pub device: String,
+    #[argh(
+        option,
+        default = "vm_control::input::BTN_LEFT",
+        from_str_fn(parse_button)
+    )]
+    /// left | right | middle (default: left)
+    pub button: u16,
+    #[argh(positional, arg_name = "VM_SOCKET")]
+    /// VM Socket path
+    pub socket_path: String,
+}
+
+#[derive(FromArgs)]
+/// tap, or swipe fingers on a touch device
+#[argh(subcommand, name = "touch")]
+pub struct TouchInputSubcommand {
+    #[argh(option)]
+    /// name of the input device, e.g. multi-touch0
+    pub device: String,
+    #[argh(option, arg_name = "X,Y[:X,Y]", from_str_fn(parse_finger))]
+    /// position of a finger, or the start and end positions of a swipe. Can be given several
+    /// times for multi-touch gestures.
+    pub finger: Vec<FingerPath>,
+    #[argh(option, default = "100")]
+    /// duration of the touch in milliseconds (default: 100)
+    pub duration_ms: u64,
+    #[argh(option, default = "10")]
+    /// number of intermediate positions of the fingers (default: 10)
+    pub steps: u32,
+    #[argh(positional, arg_name = "VM_SOCKET")]
+    /// VM Socket path
+    pub socket_path: String,
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand, name = "input")]
+/// Inject input events into virtio input devices. The devices are named after their option and
+/// index on the command line: single-touch0, multi-touch0, trackpad0, mouse0, keyboard0,
+/// switches0 and evdev0, or display-touch and display-keyboard for those of the display window.
+/// The emulated usb keyboards and tablets are named after their port, e.g. usb1.
+pub struct InputCommand {
+    #[argh(subcommand)]
+    pub command: InputSubcommand,
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand)]
+pub enum MemSubcommand {
+    Resize(ResizeMemSubcommand),
+    Stats(StatsMemSubcommand),
+}
+
+#[derive(FromArgs)]
+/// ask the guest to plug or unplug hotplug memory until `SIZE` bytes of it are plugged
+#[argh(subcommand, name = "resize")]
+pub struct ResizeMemSubcommand {
+    #[argh(positional, arg_name = "SIZE")]
+    /// amount of bytes
+    pub size: u64,
+    #[argh(positional, arg_name = "VM_SOCKET")]
+    /// VM Socket path
+    pub socket_path: String,
+}
+
+#[derive(FromArgs)]
+/// prints the sizes of the hotplug memory region and of its plugged memory
+#[argh(subcommand, name = "stats")]
+pub struct StatsMemSubcommand {
+    #[argh(positional, arg_name = "VM_SOCKET")]
+    /// VM Socket path
+    pub socket_path: String,
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand, name = "mem")]
+/// Manage the memory plugged by the virtio-mem device in the region set up by the hotplug-size
+/// of --mem
+pub struct MemCommand {
+    #[argh(subcommand)]
+    pub command: MemSubcommand,
+}
+
 #[derive(FromArgs)]
 #[argh(subcommand, name = "make_rt")]
 /// Enables real-time vcpu priority for crosvm instances started with `--delay-rt`
@@ -489,6 +806,7 @@ pub enum GpuSubCommand {
     AddDisplays(GpuAddDisplaysCommand),
     ListDisplays(GpuListDisplaysCommand),
     RemoveDisplays(GpuRemoveDisplaysCommand),
+    Screenshot(GpuScreenshotCommand),
 }
 
 #[cfg(feature = "gpu")]
@@ -528,10 +846,31 @@ pub struct GpuRemoveDisplaysCommand {
     pub socket_path: String,
 }
 
+#[cfg(feature = "gpu")]
+#[derive(FromArgs)]
+/// Save the current contents of a display of the GPU device as a PNG image.
+#[argh(subcommand, name = "screenshot")]
+pub struct GpuScreenshotCommand {
+    #[argh(option, default = "0")]
+    /// display id (default: 0)
+    pub display_id: u32,
+    #[argh(positional, arg_name = "PATH")]
+    /// path of the PNG image to write
+    pub path: PathBuf,
+    #[argh(positional, arg_name = "VM_SOCKET")]
+    /// VM Socket path
+    pub socket_path: String,
+}
+
 #[derive(FromArgs)]
 #[argh(subcommand)]
 pub enum UsbSubCommand {
     Attach(UsbAttachCommand),
+    AttachStorage(UsbAttachStorageCommand),
+    AttachKeyboard(UsbAttachKeyboardCommand),
+    AttachTablet(UsbAttachTabletCommand),
+    #[cfg(unix)]
+    AttachUsbip(UsbAttachUsbipCommand),
     Detach(UsbDetachCommand),
     List(UsbListCommand),
 }
@@ -554,6 +893,63 @@ pub struct UsbAttachCommand {
     pub socket_path: String,
 }
 
+#[derive(FromArgs)]
+/// Attach an emulated usb mass storage device exposing a disk image
+#[argh(subcommand, name = "attach-storage")]
+pub struct UsbAttachStorageCommand {
+    #[argh(switch)]
+    /// prevent the guest from writing the disk image
+    pub read_only: bool,
+    #[argh(positional, arg_name = "IMAGE")]
+    /// disk image path
+    pub image_path: PathBuf,
+    #[argh(positional, arg_name = "VM_SOCKET")]
+    /// VM Socket path
+    pub socket_path: String,
+}
+
+#[derive(FromArgs)]
+/// Attach an emulated usb keyboard, which receives the events of `crosvm input` for the device
+/// `usb<PORT>`
+#[argh(subcommand, name = "attach-keyboard")]
+pub struct UsbAttachKeyboardCommand {
+    #[argh(positional, arg_name = "VM_SOCKET")]
+    /// VM Socket path
+    pub socket_path: String,
+}
+
+#[derive(FromArgs)]
+/// Attach an emulated usb tablet, which receives the events of `crosvm input` for the device
+/// `usb<PORT>`
+#[argh(subcommand, name = "attach-tablet")]
+pub struct UsbAttachTabletCommand {
+    #[argh(option, default = "1280")]
+    /// width of the coordinates of the input events (default: 1280)
+    pub width: u32,
+    #[argh(option, default = "1024")]
+    /// height of the coordinates of the input events (default: 1024)
+    pub height: u32,
+    #[argh(positional, arg_name = "VM_SOCKET")]
+    /// VM Socket path
+    pub socket_path: String,
+}
+
+#[cfg(unix)]
+#[derive(FromArgs)]
+/// Attach a usb device exported by a USB/IP server
+#[argh(subcommand, name = "attach-usbip")]
+pub struct UsbAttachUsbipCommand {
+    #[argh(positional, arg_name = "SERVER")]
+    /// HOST[:PORT] of the server (default port: 3240), or the path of its Unix socket
+    pub server: String,
+    #[argh(positional, arg_name = "BUSID")]
+    /// bus id of the device on the server, e.g. 1-1.2
+    pub busid: String,
+    #[argh(positional, arg_name = "VM_SOCKET")]
+    /// VM Socket path
+    pub socket_path: String,
+}
+
 #[derive(FromArgs)]
 /// Detach usb device
 #[argh(subcommand, name = "detach")]
@@ -817,6 +1213,19 @@ pub struct RunCommand {
     /// enable page reporting in balloon.
     pub balloon_page_reporting: bool,
 
+    #[argh(switch)]
+    #[serde(skip)] // TODO(b/255223604)
+    #[merge(strategy = overwrite_false)]
+    /// enable free page hinting in balloon.
+    pub balloon_free_page_hint: bool,
+
+    #[argh(option, arg_name = "N")]
+    #[serde(skip)] // TODO(b/255223604)
+    #[merge(strategy = overwrite_option)]
+    /// enable working set reporting in balloon, with N bins
+    /// (2-16) in the working set histograms.
+    pub balloon_ws_num_bins: Option<u8>,
+
     #[argh(option)]
     /// comma separated key=value pairs for setting up battery
     /// device
@@ -856,6 +1265,21 @@ pub struct RunCommand {
     ///     async-executor=epoll|uring - set the async executor kind
     ///         to simulate the block device with. This takes
     ///         precedence over the global --async-executor option.
+    ///     throttle=[KEY=VALUE,...] - limit the rate of reads and
+    ///         writes. Valid keys are read-iops, read-bps, write-iops
+    ///         and write-bps for the requests or bytes per second,
+    ///         and the same keys with a -burst suffix for the amount
+    ///         that can be used at once after the disk has been idle.
+    ///         (default: no limits, bursts of one second)
+    ///     zoned=[KEY=VALUE,...] - expose the disk as a host-managed
+    ///         zoned device, emulating zones on the image. Valid keys
+    ///         are zone-size (in bytes, default 256 MiB),
+    ///         conventional-zones (default 0), max-open-zones and
+    ///         max-active-zones (default 0, no limit), and
+    ///         state-file, a file keeping the write pointers of the
+    ///         zones across restarts, which emulated zones require.
+    ///         It is created if it doesn't exist. Zoned host block
+    ///         devices are always exposed with their own zones.
     block: Vec<DiskOptionWithId>,
 
     /// ratelimit enforced on detected bus locks in guest.
@@ -1253,6 +1677,14 @@ pub struct RunCommand {
     #[merge(strategy = overwrite_option)]
     pub hypervisor: Option<HypervisorKind>,
 
+    #[cfg(unix)]
+    #[argh(option, arg_name = "PATH")]
+    #[serde(skip)] // TODO(b/255223604)
+    #[merge(strategy = overwrite_option)]
+    /// path of a socket to listen on for a VM migrated with `crosvm migrate send`, instead of
+    /// booting. The VM must be configured the same way as the source one.
+    pub incoming: Option<PathBuf>,
+
     #[argh(option, arg_name = "N")]
     #[serde(skip)] // TODO(b/255223604)
     #[merge(strategy = overwrite_option)]
@@ -1335,6 +1767,9 @@ pub struct RunCommand {
     /// memory parameters.
     /// Possible key values:
     ///     size=NUM - amount of guest memory in MiB. (default: 256)
+    ///     hotplug-size=NUM - size in MiB of the region in which
+    ///         a virtio-mem device can plug more memory after
+    ///         boot. (default: 0)
     pub mem: Option<MemOptions>,
 
     #[argh(option, from_str_fn(parse_mmio_address_range))]
@@ -1365,7 +1800,7 @@ pub struct RunCommand {
     #[cfg(unix)]
     #[argh(
         option,
-        arg_name = "(tap-name=TAP_NAME,mac=MAC_ADDRESS|tap-fd=TAP_FD,mac=MAC_ADDRESS|host-ip=IP,netmask=NETMASK,mac=MAC_ADDRESS),vhost-net=VHOST_NET"
+        arg_name = "(tap-name=TAP_NAME,mac=MAC_ADDRESS|tap-fd=TAP_FD,mac=MAC_ADDRESS|host-ip=IP,netmask=NETMASK,mac=MAC_ADDRESS|user,mac=MAC_ADDRESS,host-fwd=[FORWARD,...]),vhost-net=VHOST_NET"
     )]
     #[serde(default)]
     #[merge(strategy = append)]
@@ -1389,13 +1824,26 @@ pub struct RunCommand {
     ///       AND
     ///         mac=STRING      - MAC address for VM.
     ///      )
+    ///    OR
+    ///      user            - use a user-mode network stack, in a
+    ///                          sandboxed process, instead of a TAP
+    ///                          device. The guest gets 10.0.2.15
+    ///                          through DHCP, with the gateway at
+    ///                          10.0.2.2 and a DNS forwarder at
+    ///                          10.0.2.3.
+    ///      mac=STRING      - MAC address for VM. [Optional]
+    ///      host-fwd=[PROTOCOL:[HOST_IP:]HOST_PORT:GUEST_PORT,...]
+    ///                      - host ports to forward to the guest,
+    ///                          with PROTOCOL tcp or udp and HOST_IP
+    ///                          127.0.0.1 by default. [Optional]
     ///   )
     /// AND
     ///   vhost-net=BOOL  - whether enable vhost_net or not.
     ///                       Default: false.  [Optional]
     ///
-    /// Either one tap_name, one tap_fd or a triplet of host_ip,
-    /// netmask and mac must be specified.
+    /// Either one tap_name, one tap_fd, a triplet of host_ip,
+    /// netmask and mac, or user must be specified. vhost-net
+    /// cannot be used with user.
     pub net: Vec<NetParameters>,
 
     #[cfg(unix)]
@@ -1642,7 +2090,8 @@ pub struct RunCommand {
     #[argh(option, long = "restore", arg_name = "PATH")]
     #[serde(skip)] // TODO(b/255223604)
     #[merge(strategy = overwrite_option)]
-    /// path of the snapshot that is used to restore the VM on startup.
+    /// path of the snapshot that is used to restore the VM on startup. The VM must be
+    /// configured the same way as the one the snapshot was taken from.
     pub restore: Option<PathBuf>,
 
     #[argh(option, arg_name = "PATH[,key=value[,key=value[,...]]]", short = 'r')]
@@ -1726,7 +2175,7 @@ pub struct RunCommand {
 
     #[argh(
         option,
-        arg_name = "type=TYPE,[hardware=HW,num=NUM,path=PATH,input=PATH,console,earlycon,stdin]",
+        arg_name = "type=TYPE,[hardware=HW,num=NUM,path=PATH,addr=ADDR,input=PATH,log=PATH,console,earlycon,stdin]",
         from_str_fn(parse_serial_options)
     )]
     #[serde(default)]
@@ -1734,8 +2183,10 @@ pub struct RunCommand {
     /// comma separated key=value pairs for setting up serial
     /// devices. Can be given more than once.
     /// Possible key values:
-    ///     type=(stdout,syslog,sink,file) - Where to route the
-    ///        serial device
+    ///     type=(stdout,syslog,sink,file,pty,tcp) - Where to route
+    ///        the serial device. pty allocates a PTY, whose path
+    ///        is logged, and tcp listens on addr for a client;
+    ///        both take the input of the device from the console.
     ///     hardware=(serial,virtio-console,debugcon) - Which type
     ///        of serial hardware to emulate. Defaults to 8250 UART
     ///        (serial).
@@ -1745,9 +2196,18 @@ pub struct RunCommand {
     ///        listen to. Defaults to 0x402, which is what OVMF
     ///        expects.
     ///     path=PATH - The path to the file to write to when
-    ///        type=file
+    ///        type=file, or of a symlink to the PTY when type=pty
+    ///     addr=ADDR - The address to listen on when type=tcp,
+    ///        e.g. 127.0.0.1:4444. A new client replaces the
+    ///        previous one, and the output is kept while no
+    ///        client is connected.
     ///     input=PATH - The path to the file to read from when not
     ///        stdin
+    ///     log=PATH - Also write the output to this file
+    ///     log-size=BYTES - Rotate the log file once it reaches
+    ///        this size. Defaults to no rotation.
+    ///     log-count=N - Number of rotated log files to keep.
+    ///        Defaults to 4.
     ///     console - Use this serial device as the guest console.
     ///        Can only be given once. Will default to first
     ///        serial port if not provided.
@@ -1768,7 +2228,7 @@ pub struct RunCommand {
     #[cfg(unix)]
     #[argh(
         option,
-        arg_name = "PATH:TAG[:type=TYPE:writeback=BOOL:timeout=SECONDS:uidmap=UIDMAP:gidmap=GIDMAP:cache=CACHE:dax=BOOL,posix_acl=BOOL]"
+        arg_name = "PATH:TAG[:type=TYPE:writeback=BOOL:timeout=SECONDS:uidmap=UIDMAP:gidmap=GIDMAP:cache=CACHE:dax=BOOL,posix_acl=BOOL,locks=BOOL]"
     )]
     // TODO(b/218223240) add Deserialize implementation for SharedDir so it can be supported by the
     // config file.
@@ -1820,6 +2280,9 @@ pub struct RunCommand {
     ///        supports POSIX ACLs.  This should only be enabled
     ///        when the underlying file system supports POSIX ACLs.
     ///        The default value for this option is "true".
+    ///     locks=BOOL - Forwards the POSIX and flock locks of the
+    ///        VM to the host, where blocking lock requests fail.
+    ///        The default value for this option is "false".
     pub shared_dir: Vec<SharedDir>,
 
     #[argh(option, arg_name = "PATH:WIDTH:HEIGHT")]
@@ -2115,12 +2578,16 @@ pub struct RunCommand {
     /// Possible key values:
     ///     capture=(false,true) - Disable/enable audio capture.
     ///         Default is false.
-    ///     backend=(null,[cras]) - Which backend to use for
-    ///         virtio-snd.
+    ///     backend=(null,file,[cras],[pulse]) - Which backend to use
+    ///         for virtio-snd.
     ///     client_type=(crosvm,arcvm,borealis) - Set specific
     ///         client type for cras backend. Default is crosvm.
     ///     socket_type=(legacy,unified) Set specific socket type
     ///         for cras backend. Default is unified.
+    ///     playback_path=PATH - Directory where the file backend
+    ///         records playback stream N to streamN.wav.
+    ///     capture_path=PATH - WAV or raw file played to the
+    ///         capture streams by the file backend.
     ///     num_output_devices=INT - Set number of output PCM
     ///         devices.
     ///     num_input_devices=INT - Set number of input PCM devices.
@@ -2137,6 +2604,29 @@ pub struct RunCommand {
     /// enable the virtio-tpm connection to vtpm daemon
     pub vtpm_proxy: bool,
 
+    #[cfg(all(unix, feature = "gpu"))]
+    #[argh(option, arg_name = "ADDRESS:PORT")]
+    #[serde(skip)] // TODO(b/255223604)
+    #[merge(strategy = overwrite_option)]
+    /// serve the GPU display to VNC clients on the given TCP
+    ///     address, e.g. 127.0.0.1:5900. There is no
+    ///     authentication, so the address should not be reachable
+    ///     by untrusted hosts. Implies --display-window-keyboard and
+    ///     --display-window-mouse for the input of the clients.
+    pub vnc: Option<net::SocketAddr>,
+
+    #[cfg(unix)]
+    #[argh(option, arg_name = "PATH")]
+    #[serde(skip)] // TODO(b/255223604)
+    #[merge(strategy = overwrite_option)]
+    /// use the userspace vsock device instead of vhost-vsock, with
+    ///     the host side of the connections on Unix sockets.
+    ///     Host processes connect to the socket at PATH and send
+    ///     "CONNECT <port>\n" to reach a guest port, and guest
+    ///     connections to port N go to the socket at PATH_N.
+    ///     Requires --cid.
+    pub vsock_uds: Option<PathBuf>,
+
     #[argh(
         option,
         arg_name = "SOCKET_PATH[,addr=DOMAIN:BUS:DEVICE.FUNCTION,uuid=UUID]"
@@ -2294,6 +2784,7 @@ impl TryFrom<RunCommand> for super::config::Config {
 
         let mem = cmd.mem.unwrap_or_default();
         cfg.memory = mem.size;
+        cfg.hotplug_memory = mem.hotplug_size;
 
         #[cfg(target_arch = "aarch64")]
         {
@@ -2493,6 +2984,13 @@ impl TryFrom<RunCommand> for super::config::Config {
 
         cfg.swap_dir = cmd.swap_dir;
         cfg.restore_path = cmd.restore;
+        #[cfg(unix)]
+        {
+            cfg.incoming = cmd.incoming;
+            if cfg.incoming.is_some() && cfg.restore_path.is_some() {
+                return Err("unable to use incoming and restore together".to_string());
+            }
+        }
 
         if let Some(mut socket_path) = cmd.socket {
             if socket_path.is_dir() {
@@ -2607,6 +3105,8 @@ impl TryFrom<RunCommand> for super::config::Config {
         cfg.rng = !cmd.no_rng;
         cfg.balloon = !cmd.no_balloon;
         cfg.balloon_page_reporting = cmd.balloon_page_reporting;
+        cfg.balloon_free_page_hint = cmd.balloon_free_page_hint;
+        cfg.balloon_ws_num_bins = cmd.balloon_ws_num_bins;
         #[cfg(feature = "audio")]
         {
             cfg.virtio_snds = cmd.virtio_snd;
@@ -2626,6 +3126,17 @@ impl TryFrom<RunCommand> for super::config::Config {
                     .extend(cmd.gpu_display.into_iter().map(|p| p.0));
             }
 
+            #[cfg(unix)]
+            if cmd.vnc.is_some() {
+                if cfg.gpu_parameters.is_none() {
+                    return Err("vnc requires gpu".to_string());
+                }
+                cfg.vnc = cmd.vnc;
+                // The input of the VNC clients goes to the devices of the display window.
+                cfg.display_window_keyboard = true;
+                cfg.display_window_mouse = true;
+            }
+
             #[cfg(windows)]
             if let Some(gpu_parameters) = &cfg.gpu_parameters {
                 let num_displays = gpu_parameters.display_params.len();
@@ -2652,6 +3163,19 @@ impl TryFrom<RunCommand> for super::config::Config {
                 cfg.vhost_vsock_device = Some(PathBuf::from(format!("/proc/self/fd/{}", fd)));
             }
 
+            if cmd.vsock_uds.is_some() {
+                if cmd.cid.is_none() {
+                    return Err("vsock-uds requires cid".to_string());
+                }
+                if cfg.vhost_vsock_device.is_some() {
+                    return Err(
+                        "vsock-uds cannot be used with vhost-vsock-device or vhost-vsock-fd"
+                            .to_string(),
+                    );
+                }
+            }
+            cfg.vsock_uds = cmd.vsock_uds;
+
             cfg.shared_dirs = cmd.shared_dir;
 
             cfg.net = cmd.net;
@@ -29,6 +29,8 @@ use base::pagesize;
 use cros_async::ExecutorKind;
 use devices::serial_device::SerialHardware;
 use devices::serial_device::SerialParameters;
+#[cfg(unix)]
+use devices::serial_device::SerialType;
 use devices::virtio::block::block::DiskOption;
 #[cfg(any(feature = "video-decoder", feature = "video-encoder"))]
 use devices::virtio::device_constants::video::VideoDeviceConfig;
@@ -59,6 +61,8 @@ use serde::Serialize;
 use serde_keyvalue::FromKeyValues;
 use uuid::Uuid;
 use vm_control::BatteryType;
+use vm_control::VIRTIO_BALLOON_WS_MAX_NUM_BINS;
+use vm_control::VIRTIO_BALLOON_WS_MIN_NUM_BINS;
 #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
 use x86_64::check_host_hybrid_support;
 #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
@@ -137,6 +141,9 @@ pub struct MemOptions {
     /// Amount of guest memory in MiB.
     #[serde(default)]
     pub size: Option<u64>,
+    /// Size in MiB of the region in which a virtio-mem device can plug memory after boot.
+    #[serde(default)]
+    pub hotplug_size: Option<u64>,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -533,6 +540,10 @@ impl FromStr for SharedDir {
                     let posix_acl = value.parse().map_err(|_| "`posix_acl` must be a boolean")?;
                     shared_dir.fs_cfg.posix_acl = posix_acl;
                 }
+                "locks" => {
+                    let forward_locks = value.parse().map_err(|_| "`locks` must be a boolean")?;
+                    shared_dir.fs_cfg.forward_locks = forward_locks;
+                }
                 _ => return Err("unrecognized option for `shared-dir`"),
             }
         }
@@ -667,6 +678,10 @@ pub fn validate_serial_parameters(params: &SerialParameters) -> Result<(), Strin
     if params.stdin && params.input.is_some() {
         return Err("Cannot specify both stdin and input options".to_string());
     }
+    #[cfg(unix)]
+    if params.type_ == SerialType::Tcp && params.addr.is_none() {
+        return Err("Serial device type tcp requires an addr".to_string());
+    }
     if params.num < 1 {
         return Err(invalid_value_err(
             params.num.to_string(),
@@ -1058,6 +1073,8 @@ pub struct Config {
     pub balloon_bias: i64,
     pub balloon_control: Option<PathBuf>,
     pub balloon_page_reporting: bool,
+    pub balloon_free_page_hint: bool,
+    pub balloon_ws_num_bins: Option<u8>,
     pub battery_config: Option<BatteryConfig>,
     #[cfg(windows)]
     pub block_control_tube: Vec<Tube>,
@@ -1117,8 +1134,11 @@ pub struct Config {
     #[cfg(windows)]
     pub host_guid: Option<String>,
     pub host_ip: Option<net::Ipv4Addr>,
+    pub hotplug_memory: Option<u64>,
     pub hugepages: bool,
     pub hypervisor: Option<HypervisorKind>,
+    #[cfg(unix)]
+    pub incoming: Option<PathBuf>,
     pub init_memory: Option<u64>,
     pub initrd_path: Option<PathBuf>,
     #[cfg(windows)]
@@ -1253,6 +1273,10 @@ pub struct Config {
     pub virtio_trackpad: Vec<TouchDeviceOption>,
     #[cfg(all(feature = "vtpm", target_arch = "x86_64"))]
     pub vtpm_proxy: bool,
+    #[cfg(all(unix, feature = "gpu"))]
+    pub vnc: Option<net::SocketAddr>,
+    #[cfg(unix)]
+    pub vsock_uds: Option<PathBuf>,
     pub vvu_proxy: Vec<VvuOption>,
     pub wayland_socket_paths: BTreeMap<String, PathBuf>,
     pub x_display: Option<String>,
@@ -1272,6 +1296,8 @@ impl Default for Config {
             balloon_bias: 0,
             balloon_control: None,
             balloon_page_reporting: false,
+            balloon_free_page_hint: false,
+            balloon_ws_num_bins: None,
             battery_config: None,
             #[cfg(windows)]
             block_control_tube: Vec::new(),
@@ -1331,12 +1357,15 @@ impl Default for Config {
             #[cfg(windows)]
             host_guid: None,
             host_ip: None,
+            hotplug_memory: None,
             #[cfg(windows)]
             product_version: None,
             #[cfg(windows)]
             product_channel: None,
             hugepages: false,
             hypervisor: None,
+            #[cfg(unix)]
+            incoming: None,
             init_memory: None,
             initrd_path: None,
             #[cfg(windows)]
@@ -1467,6 +1496,10 @@ impl Default for Config {
             virtio_trackpad: Vec::new(),
             #[cfg(all(feature = "vtpm", target_arch = "x86_64"))]
             vtpm_proxy: false,
+            #[cfg(all(unix, feature = "gpu"))]
+            vnc: None,
+            #[cfg(unix)]
+            vsock_uds: None,
             vvu_proxy: Vec::new(),
             wayland_socket_paths: BTreeMap::new(),
             x_display: None,
@@ -1487,10 +1520,6 @@ pub fn validate_config(cfg: &mut Config) -> std::result::Result<(), String> {
     {
         crate::crosvm::gpu_config::validate_gpu_config(cfg)?;
     }
-    #[cfg(feature = "gdb")]
-    if cfg.gdb.is_some() && cfg.vcpu_count.unwrap_or(1) != 1 {
-        return Err("`gdb` requires the number of vCPU to be 1".to_string());
-    }
     if cfg.host_cpu_topology {
         if cfg.no_smt {
             return Err(
@@ -1623,6 +1652,36 @@ pub fn validate_config(cfg: &mut Config) -> std::result::Result<(), String> {
         return Err("'balloon_page_reporting' requires enabled balloon".to_string());
     }
 
+    if !cfg.balloon && cfg.balloon_free_page_hint {
+        return Err("'balloon-free-page-hint' requires enabled balloon".to_string());
+    }
+
+    if let Some(num_bins) = cfg.balloon_ws_num_bins {
+        if !cfg.balloon {
+            return Err("'balloon-ws-num-bins' requires enabled balloon".to_string());
+        }
+        if !(VIRTIO_BALLOON_WS_MIN_NUM_BINS..=VIRTIO_BALLOON_WS_MAX_NUM_BINS)
+            .contains(&(num_bins as usize))
+        {
+            return Err(format!(
+                "'balloon-ws-num-bins' must be between {} and {}",
+                VIRTIO_BALLOON_WS_MIN_NUM_BINS, VIRTIO_BALLOON_WS_MAX_NUM_BINS
+            ));
+        }
+    }
+
+    if cfg.hotplug_memory.is_some() {
+        if !cfg!(all(unix, target_arch = "x86_64")) {
+            return Err("'hotplug-size' is only supported on x86_64 Linux".to_string());
+        }
+        #[cfg(unix)]
+        if cfg.lock_guest_memory {
+            return Err(
+                "'hotplug-size' and 'lock-guest-memory' are mutually exclusive".to_string(),
+            );
+        }
+    }
+
     #[cfg(unix)]
     if cfg.lock_guest_memory && cfg.jail_config.is_none() {
         return Err("'lock-guest-memory' and 'disable-sandbox' are mutually exclusive".to_string());
@@ -1872,6 +1931,11 @@ mod tests {
 
         let res: MemOptions = from_key_values("size=0x4000").unwrap();
         assert_eq!(res.size, Some(16384));
+        assert_eq!(res.hotplug_size, None);
+
+        let res: MemOptions = from_key_values("size=1024,hotplug-size=2048").unwrap();
+        assert_eq!(res.size, Some(1024));
+        assert_eq!(res.hotplug_size, Some(2048));
     }
 
     #[cfg(feature = "audio_cras")]
@@ -1958,6 +2022,13 @@ mod tests {
         parse_serial_options("type=syslog,speed=lightspeed").expect_err("parse should have failed");
     }
 
+    #[cfg(unix)]
+    #[test]
+    fn parse_serial_tcp() {
+        parse_serial_options("type=tcp,addr=127.0.0.1:4444").expect("parse should have succeded");
+        parse_serial_options("type=tcp").expect_err("parse should have failed");
+    }
+
     #[test]
     fn parse_serial_invalid_two_stdin() {
         assert!(TryInto::<Config>::try_into(
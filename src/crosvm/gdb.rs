@@ -2,9 +2,12 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the LICENSE file.
 
+use std::collections::BTreeSet;
+use std::collections::VecDeque;
 use std::net::TcpListener;
 use std::sync::mpsc;
 use std::time::Duration;
+use std::time::Instant;
 
 #[cfg(any(target_arch = "arm", target_arch = "aarch64"))]
 use aarch64::AArch64 as CrosvmArch;
@@ -15,24 +18,29 @@ use base::Tube;
 use base::TubeError;
 use gdbstub::arch::Arch;
 use gdbstub::common::Signal;
+use gdbstub::common::Tid;
 use gdbstub::conn::Connection;
 use gdbstub::conn::ConnectionExt;
 use gdbstub::stub::run_blocking;
 use gdbstub::stub::run_blocking::BlockingEventLoop;
-use gdbstub::stub::SingleThreadStopReason;
+use gdbstub::stub::MultiThreadStopReason;
+use gdbstub::target::ext::base::multithread::MultiThreadBase;
+use gdbstub::target::ext::base::multithread::MultiThreadResume;
+use gdbstub::target::ext::base::multithread::MultiThreadResumeOps;
+use gdbstub::target::ext::base::multithread::MultiThreadSingleStep;
+use gdbstub::target::ext::base::multithread::MultiThreadSingleStepOps;
 use gdbstub::target::ext::base::single_register_access::SingleRegisterAccess;
 #[cfg(target_arch = "aarch64")]
 use gdbstub::target::ext::base::single_register_access::SingleRegisterAccessOps;
-use gdbstub::target::ext::base::singlethread::SingleThreadBase;
-use gdbstub::target::ext::base::singlethread::SingleThreadResume;
-use gdbstub::target::ext::base::singlethread::SingleThreadResumeOps;
-use gdbstub::target::ext::base::singlethread::SingleThreadSingleStep;
-use gdbstub::target::ext::base::singlethread::SingleThreadSingleStepOps;
 use gdbstub::target::ext::base::BaseOps;
 use gdbstub::target::ext::breakpoints::Breakpoints;
 use gdbstub::target::ext::breakpoints::BreakpointsOps;
 use gdbstub::target::ext::breakpoints::HwBreakpoint;
 use gdbstub::target::ext::breakpoints::HwBreakpointOps;
+use gdbstub::target::ext::breakpoints::HwWatchpointOps;
+use gdbstub::target::ext::breakpoints::WatchKind;
+use gdbstub::target::ext::thread_extra_info::ThreadExtraInfo;
+use gdbstub::target::ext::thread_extra_info::ThreadExtraInfoOps;
 use gdbstub::target::Target;
 use gdbstub::target::TargetError::NonFatal;
 use gdbstub::target::TargetResult;
@@ -40,10 +48,13 @@ use gdbstub::target::TargetResult;
 use gdbstub_arch::aarch64::AArch64 as GdbArch;
 #[cfg(target_arch = "x86_64")]
 use gdbstub_arch::x86::X86_64_SSE as GdbArch;
+use hypervisor::DebugExit;
+use hypervisor::HwWatchpoint;
 #[cfg(any(target_arch = "arm", target_arch = "aarch64"))]
 use hypervisor::VcpuAArch64 as VcpuArch;
 #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
 use hypervisor::VcpuX86_64 as VcpuArch;
+use hypervisor::WatchpointKind;
 use remain::sorted;
 use sync::Mutex;
 use thiserror::Error as ThisError;
@@ -90,7 +101,14 @@ pub fn gdb_thread(mut gdbstub: GdbStub, port: u32) {
         }
     }
 
-    // Resume the VM when GDB session is disconnected.
+    // Remove the breakpoints and watchpoints, then resume the VM when GDB session is
+    // disconnected.
+    gdbstub.hw_breakpoints.clear();
+    gdbstub.hw_watchpoints.clear();
+    gdbstub.single_step.clear();
+    if let Err(e) = gdbstub.set_guest_debug() {
+        error!("Failed to clear the guest debug state: {}", e);
+    }
     if let Err(e) = gdbstub.vm_request(VmRequest::Resume) {
         error!("Failed to resume the VM after GDB disconnected: {}", e);
     }
@@ -99,9 +117,18 @@ pub fn gdb_thread(mut gdbstub: GdbStub, port: u32) {
 #[sorted]
 #[derive(ThisError, Debug)]
 enum Error {
+    /// The vCPU doesn't exist.
+    #[error("no vCPU {0}")]
+    InvalidVcpu(usize),
+    /// Got an unexpected vCPU response.
+    #[error("Got an unexpected response from vCPU {0}: {1:?}")]
+    UnexpectedVcpuResponse(usize, Box<VcpuDebugStatus>),
     /// Got an unexpected VM response.
     #[error("Got an unexpected VM response: {0}")]
     UnexpectedVmResponse(VmResponse),
+    /// The vCPU failed to handle a request.
+    #[error("vCPU {0} failed to handle the request")]
+    VcpuCommandFailed(usize),
     /// Failed to send a vCPU request.
     #[error("failed to send a vCPU request: {0}")]
     VcpuRequest(mpsc::SendError<VcpuControl>),
@@ -117,14 +144,28 @@ enum Error {
 }
 type GdbResult<T> = std::result::Result<T, Error>;
 
+/// GDB thread of a vCPU. Thread IDs start at 1.
+fn cpu_to_tid(cpu: usize) -> Tid {
+    Tid::new(cpu + 1).expect("thread ID overflow")
+}
+
+fn tid_to_cpu(tid: Tid) -> usize {
+    tid.get() - 1
+}
+
 pub struct GdbStub {
     vm_tube: Mutex<Tube>,
     vcpu_com: Vec<mpsc::Sender<VcpuControl>>,
     from_vcpu: mpsc::Receiver<VcpuDebugStatusMessage>,
 
-    single_step: bool,
+    /// Debug exits of vCPUs not reported to GDB yet.
+    pending_stops: VecDeque<(usize, DebugExit)>,
+    /// vCPUs to single-step on the next resume.
+    single_step: BTreeSet<usize>,
     max_hw_breakpoints: Option<usize>,
+    max_hw_watchpoints: Option<usize>,
     hw_breakpoints: Vec<GuestAddress>,
+    hw_watchpoints: Vec<HwWatchpoint>,
 }
 
 impl GdbStub {
@@ -137,19 +178,36 @@ impl GdbStub {
             vm_tube: Mutex::new(vm_tube),
             vcpu_com,
             from_vcpu,
-            single_step: false,
+            pending_stops: VecDeque::new(),
+            single_step: BTreeSet::new(),
             max_hw_breakpoints: None,
+            max_hw_watchpoints: None,
             hw_breakpoints: Default::default(),
+            hw_watchpoints: Default::default(),
         }
     }
 
-    fn vcpu_request(&self, request: VcpuControl) -> GdbResult<VcpuDebugStatus> {
-        // We use the only one vCPU when GDB is enabled.
-        self.vcpu_com[0].send(request).map_err(Error::VcpuRequest)?;
+    fn vcpu_request(&mut self, cpu: usize, request: VcpuControl) -> GdbResult<VcpuDebugStatus> {
+        self.vcpu_com
+            .get(cpu)
+            .ok_or(Error::InvalidVcpu(cpu))?
+            .send(request)
+            .map_err(Error::VcpuRequest)?;
 
-        match self.from_vcpu.recv_timeout(Duration::from_millis(500)) {
-            Ok(msg) => Ok(msg.msg),
-            Err(e) => Err(Error::VcpuResponse(e)),
+        let deadline = Instant::now() + Duration::from_millis(500);
+        loop {
+            let msg = self
+                .from_vcpu
+                .recv_timeout(deadline.saturating_duration_since(Instant::now()))
+                .map_err(Error::VcpuResponse)?;
+            match msg.msg {
+                // Other vCPUs may stop while this one handles the request.
+                VcpuDebugStatus::HitBreakPoint(exit) => {
+                    self.pending_stops.push_back((msg.cpu, exit));
+                }
+                status if msg.cpu == cpu => return Ok(status),
+                status => error!("Unexpected status from vCPU {}: {:?}", msg.cpu, status),
+            }
         }
     }
 
@@ -163,9 +221,46 @@ impl GdbStub {
         }
     }
 
-    fn max_hw_breakpoints_request(&self) -> TargetResult<usize, Self> {
-        match self.vcpu_request(VcpuControl::Debug(VcpuDebug::GetHwBreakPointCount)) {
-            Ok(VcpuDebugStatus::HwBreakPointCount(n)) => Ok(n),
+    /// Applies the hardware breakpoints and watchpoints to every vCPU, and makes the vCPUs in
+    /// `single_step` single-step.
+    fn set_guest_debug(&mut self) -> GdbResult<()> {
+        for cpu in 0..self.vcpu_com.len() {
+            let request = VcpuDebug::SetGuestDebug {
+                breakpoints: self.hw_breakpoints.clone(),
+                watchpoints: self.hw_watchpoints.clone(),
+                single_step: self.single_step.contains(&cpu),
+            };
+            match self.vcpu_request(cpu, VcpuControl::Debug(request))? {
+                VcpuDebugStatus::CommandComplete => {}
+                VcpuDebugStatus::CommandFailed => return Err(Error::VcpuCommandFailed(cpu)),
+                status => return Err(Error::UnexpectedVcpuResponse(cpu, Box::new(status))),
+            }
+        }
+        Ok(())
+    }
+
+    /// Applies a change of the hardware breakpoints or watchpoints, undoing it with `revert` if
+    /// a vCPU refuses it.
+    fn update_guest_debug(&mut self, revert: impl FnOnce(&mut Self)) -> bool {
+        match self.set_guest_debug() {
+            Ok(()) => true,
+            Err(e) => {
+                error!("Failed to set the guest debug state: {}", e);
+                revert(self);
+                if let Err(e) = self.set_guest_debug() {
+                    error!("Failed to restore the guest debug state: {}", e);
+                }
+                false
+            }
+        }
+    }
+
+    fn max_hw_breakpoints(&mut self) -> TargetResult<usize, Self> {
+        if let Some(n) = self.max_hw_breakpoints {
+            return Ok(n);
+        }
+        match self.vcpu_request(0, VcpuControl::Debug(VcpuDebug::GetHwBreakPointCount)) {
+            Ok(VcpuDebugStatus::HwBreakPointCount(n)) => Ok(*self.max_hw_breakpoints.insert(n)),
             Ok(s) => {
                 error!("Unexpected vCPU response for GetHwBreakPointCount: {:?}", s);
                 Err(NonFatal)
@@ -176,6 +271,63 @@ impl GdbStub {
             }
         }
     }
+
+    fn max_hw_watchpoints(&mut self) -> TargetResult<usize, Self> {
+        if let Some(n) = self.max_hw_watchpoints {
+            return Ok(n);
+        }
+        match self.vcpu_request(0, VcpuControl::Debug(VcpuDebug::GetHwWatchPointCount)) {
+            Ok(VcpuDebugStatus::HwWatchPointCount(n)) => Ok(*self.max_hw_watchpoints.insert(n)),
+            Ok(s) => {
+                error!("Unexpected vCPU response for GetHwWatchPointCount: {:?}", s);
+                Err(NonFatal)
+            }
+            Err(e) => {
+                error!("Failed to request GetHwWatchPointCount: {}", e);
+                Err(NonFatal)
+            }
+        }
+    }
+
+    /// Stops the whole VM after `cpu` stopped because of `exit`, and returns what to report.
+    fn stop_reason(
+        &mut self,
+        cpu: usize,
+        exit: DebugExit,
+    ) -> Result<MultiThreadStopReason<<GdbArch as Arch>::Usize>, &'static str> {
+        // GDB expects all the threads to be stopped when one of them is.
+        self.vm_request(VmRequest::Suspend).map_err(|e| {
+            error!("Failed to suspend the target: {}", e);
+            "Failed to suspend the target"
+        })?;
+
+        let tid = cpu_to_tid(cpu);
+        let sigtrap = MultiThreadStopReason::SignalWithThread {
+            tid,
+            signal: Signal::SIGTRAP,
+        };
+        Ok(match exit {
+            DebugExit::SingleStep => sigtrap,
+            DebugExit::Breakpoint => MultiThreadStopReason::HwBreak(tid),
+            DebugExit::Watchpoint { addr } => {
+                match self.hw_watchpoints.iter().find(|w| w.contains(addr)) {
+                    Some(w) => MultiThreadStopReason::Watch {
+                        tid,
+                        kind: match w.kind {
+                            WatchpointKind::Write => WatchKind::Write,
+                            WatchpointKind::Read => WatchKind::Read,
+                            WatchpointKind::ReadWrite => WatchKind::ReadWrite,
+                        },
+                        addr: w.addr.0,
+                    },
+                    None => sigtrap,
+                }
+            }
+            // Without details, guess from what was asked to the vCPU.
+            DebugExit::Unknown if self.single_step.contains(&cpu) => sigtrap,
+            DebugExit::Unknown => MultiThreadStopReason::HwBreak(tid),
+        })
+    }
 }
 
 impl Target for GdbStub {
@@ -183,10 +335,10 @@ impl Target for GdbStub {
     type Error = &'static str;
 
     fn base_ops(&mut self) -> BaseOps<Self::Arch, Self::Error> {
-        BaseOps::SingleThread(self)
+        BaseOps::MultiThread(self)
     }
 
-    // TODO(keiichiw): sw_breakpoint, hw_watchpoint, extended_mode, monitor_cmd, section_offsets
+    // TODO(keiichiw): sw_breakpoint, extended_mode, monitor_cmd, section_offsets
     fn support_breakpoints(&mut self) -> Option<BreakpointsOps<Self>> {
         Some(self)
     }
@@ -204,12 +356,13 @@ impl Target for GdbStub {
     }
 }
 
-impl SingleThreadBase for GdbStub {
+impl MultiThreadBase for GdbStub {
     fn read_registers(
         &mut self,
         regs: &mut <Self::Arch as Arch>::Registers,
+        tid: Tid,
     ) -> TargetResult<(), Self> {
-        match self.vcpu_request(VcpuControl::Debug(VcpuDebug::ReadRegs)) {
+        match self.vcpu_request(tid_to_cpu(tid), VcpuControl::Debug(VcpuDebug::ReadRegs)) {
             Ok(VcpuDebugStatus::RegValues(r)) => {
                 *regs = r;
                 Ok(())
@@ -228,10 +381,12 @@ impl SingleThreadBase for GdbStub {
     fn write_registers(
         &mut self,
         regs: &<Self::Arch as Arch>::Registers,
+        tid: Tid,
     ) -> TargetResult<(), Self> {
-        match self.vcpu_request(VcpuControl::Debug(VcpuDebug::WriteRegs(Box::new(
-            regs.clone(),
-        )))) {
+        match self.vcpu_request(
+            tid_to_cpu(tid),
+            VcpuControl::Debug(VcpuDebug::WriteRegs(Box::new(regs.clone()))),
+        ) {
             Ok(VcpuDebugStatus::CommandComplete) => Ok(()),
             Ok(s) => {
                 error!("Unexpected vCPU response for WriteRegs: {:?}", s);
@@ -248,11 +403,12 @@ impl SingleThreadBase for GdbStub {
         &mut self,
         start_addr: <Self::Arch as Arch>::Usize,
         data: &mut [u8],
+        tid: Tid,
     ) -> TargetResult<(), Self> {
-        match self.vcpu_request(VcpuControl::Debug(VcpuDebug::ReadMem(
-            GuestAddress(start_addr),
-            data.len(),
-        ))) {
+        match self.vcpu_request(
+            tid_to_cpu(tid),
+            VcpuControl::Debug(VcpuDebug::ReadMem(GuestAddress(start_addr), data.len())),
+        ) {
             Ok(VcpuDebugStatus::MemoryRegion(r)) => {
                 for (dst, v) in data.iter_mut().zip(r.iter()) {
                     *dst = *v;
@@ -274,11 +430,15 @@ impl SingleThreadBase for GdbStub {
         &mut self,
         start_addr: <Self::Arch as Arch>::Usize,
         data: &[u8],
+        tid: Tid,
     ) -> TargetResult<(), Self> {
-        match self.vcpu_request(VcpuControl::Debug(VcpuDebug::WriteMem(
-            GuestAddress(start_addr),
-            data.to_owned(),
-        ))) {
+        match self.vcpu_request(
+            tid_to_cpu(tid),
+            VcpuControl::Debug(VcpuDebug::WriteMem(
+                GuestAddress(start_addr),
+                data.to_owned(),
+            )),
+        ) {
             Ok(VcpuDebugStatus::CommandComplete) => Ok(()),
             Ok(s) => {
                 error!("Unexpected vCPU response for WriteMem: {:?}", s);
@@ -291,21 +451,40 @@ impl SingleThreadBase for GdbStub {
         }
     }
 
+    fn list_active_threads(
+        &mut self,
+        thread_is_active: &mut dyn FnMut(Tid),
+    ) -> Result<(), Self::Error> {
+        for cpu in 0..self.vcpu_com.len() {
+            thread_is_active(cpu_to_tid(cpu));
+        }
+        Ok(())
+    }
+
     #[inline(always)]
-    fn support_resume(&mut self) -> Option<SingleThreadResumeOps<Self>> {
+    fn support_resume(&mut self) -> Option<MultiThreadResumeOps<Self>> {
         Some(self)
     }
 
     #[cfg(target_arch = "aarch64")]
     #[inline(always)]
-    fn support_single_register_access(&mut self) -> Option<SingleRegisterAccessOps<(), Self>> {
+    fn support_single_register_access(&mut self) -> Option<SingleRegisterAccessOps<Tid, Self>> {
+        Some(self)
+    }
+
+    #[inline(always)]
+    fn support_thread_extra_info(&mut self) -> Option<ThreadExtraInfoOps<Self>> {
         Some(self)
     }
 }
 
-impl SingleThreadResume for GdbStub {
-    fn resume(&mut self, _signal: Option<Signal>) -> Result<(), Self::Error> {
-        // TODO: Handle any incoming signal.
+impl MultiThreadResume for GdbStub {
+    fn resume(&mut self) -> Result<(), Self::Error> {
+        // The vCPUs stepping on the previous resume must stop doing so.
+        self.set_guest_debug().map_err(|e| {
+            error!("Failed to set the single-step state: {}", e);
+            "Failed to set the single-step state"
+        })?;
 
         self.vm_request(VmRequest::Resume).map_err(|e| {
             error!("Failed to resume the target: {}", e);
@@ -313,43 +492,58 @@ impl SingleThreadResume for GdbStub {
         })
     }
 
+    fn clear_resume_actions(&mut self) -> Result<(), Self::Error> {
+        self.single_step.clear();
+        Ok(())
+    }
+
+    fn set_resume_action_continue(
+        &mut self,
+        _tid: Tid,
+        _signal: Option<Signal>,
+    ) -> Result<(), Self::Error> {
+        // TODO: Handle any incoming signal.
+
+        // All the vCPUs without a step action continue when the VM resumes.
+        Ok(())
+    }
+
     #[inline(always)]
-    fn support_single_step(&mut self) -> Option<SingleThreadSingleStepOps<'_, Self>> {
+    fn support_single_step(&mut self) -> Option<MultiThreadSingleStepOps<'_, Self>> {
         Some(self)
     }
 }
 
-impl SingleThreadSingleStep for GdbStub {
-    fn step(&mut self, _signal: Option<Signal>) -> Result<(), Self::Error> {
+impl MultiThreadSingleStep for GdbStub {
+    fn set_resume_action_step(
+        &mut self,
+        tid: Tid,
+        _signal: Option<Signal>,
+    ) -> Result<(), Self::Error> {
         // TODO: Handle any incoming signal.
 
-        match self.vcpu_request(VcpuControl::Debug(VcpuDebug::EnableSinglestep)) {
-            Ok(VcpuDebugStatus::CommandComplete) => {
-                self.single_step = true;
-            }
-            Ok(s) => {
-                error!("Unexpected vCPU response for EnableSinglestep: {:?}", s);
-                return Err("Unexpected vCPU response for EnableSinglestep");
-            }
-            Err(e) => {
-                error!("Failed to request EnableSinglestep: {}", e);
-                return Err("Failed to request EnableSinglestep");
-            }
-        };
-
-        self.vm_request(VmRequest::Resume).map_err(|e| {
-            error!("Failed to resume the target: {}", e);
-            "Failed to resume the target"
-        })?;
-
+        self.single_step.insert(tid_to_cpu(tid));
         Ok(())
     }
 }
 
+impl ThreadExtraInfo for GdbStub {
+    fn thread_extra_info(&self, tid: Tid, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        let info = format!("vCPU {}", tid_to_cpu(tid));
+        let len = info.len().min(buf.len());
+        buf[..len].copy_from_slice(&info.as_bytes()[..len]);
+        Ok(len)
+    }
+}
+
 impl Breakpoints for GdbStub {
     fn support_hw_breakpoint(&mut self) -> Option<HwBreakpointOps<Self>> {
         Some(self)
     }
+
+    fn support_hw_watchpoint(&mut self) -> Option<HwWatchpointOps<Self>> {
+        Some(self)
+    }
 }
 
 impl HwBreakpoint for GdbStub {
@@ -360,31 +554,16 @@ impl HwBreakpoint for GdbStub {
         addr: <Self::Arch as Arch>::Usize,
         _kind: <Self::Arch as Arch>::BreakpointKind,
     ) -> TargetResult<bool, Self> {
-        let max_count = *(match &mut self.max_hw_breakpoints {
-            None => self
-                .max_hw_breakpoints
-                .insert(self.max_hw_breakpoints_request()?),
-            Some(c) => c,
-        });
+        let max_count = self.max_hw_breakpoints()?;
         if self.hw_breakpoints.len() >= max_count {
             error!("Not allowed to set more than {} HW breakpoints", max_count);
             return Err(NonFatal);
         }
         self.hw_breakpoints.push(GuestAddress(addr));
 
-        match self.vcpu_request(VcpuControl::Debug(VcpuDebug::SetHwBreakPoint(
-            self.hw_breakpoints.clone(),
-        ))) {
-            Ok(VcpuDebugStatus::CommandComplete) => Ok(true),
-            Ok(s) => {
-                error!("Unexpected vCPU response for SetHwBreakPoint: {:?}", s);
-                Err(NonFatal)
-            }
-            Err(e) => {
-                error!("Failed to request SetHwBreakPoint: {}", e);
-                Err(NonFatal)
-            }
-        }
+        Ok(self.update_guest_debug(|stub| {
+            stub.hw_breakpoints.pop();
+        }))
     }
 
     /// Remove an existing hardware breakpoint.
@@ -394,32 +573,69 @@ impl HwBreakpoint for GdbStub {
         addr: <Self::Arch as Arch>::Usize,
         _kind: <Self::Arch as Arch>::BreakpointKind,
     ) -> TargetResult<bool, Self> {
+        let old_breakpoints = self.hw_breakpoints.clone();
         self.hw_breakpoints.retain(|&b| b.0 != addr);
 
-        match self.vcpu_request(VcpuControl::Debug(VcpuDebug::SetHwBreakPoint(
-            self.hw_breakpoints.clone(),
-        ))) {
-            Ok(VcpuDebugStatus::CommandComplete) => Ok(true),
-            Ok(s) => {
-                error!("Unexpected vCPU response for SetHwBreakPoint: {:?}", s);
-                Err(NonFatal)
-            }
-            Err(e) => {
-                error!("Failed to request SetHwBreakPoint: {}", e);
-                Err(NonFatal)
-            }
+        Ok(self.update_guest_debug(|stub| stub.hw_breakpoints = old_breakpoints))
+    }
+}
+
+impl gdbstub::target::ext::breakpoints::HwWatchpoint for GdbStub {
+    /// Add a new hardware watchpoint.
+    /// Return `Ok(false)` if the operation could not be completed.
+    fn add_hw_watchpoint(
+        &mut self,
+        addr: <Self::Arch as Arch>::Usize,
+        len: <Self::Arch as Arch>::Usize,
+        kind: WatchKind,
+    ) -> TargetResult<bool, Self> {
+        let max_count = self.max_hw_watchpoints()?;
+        if self.hw_watchpoints.len() >= max_count {
+            error!("Not allowed to set more than {} HW watchpoints", max_count);
+            return Err(NonFatal);
         }
+        self.hw_watchpoints.push(HwWatchpoint {
+            addr: GuestAddress(addr),
+            len,
+            kind: match kind {
+                WatchKind::Write => WatchpointKind::Write,
+                WatchKind::Read => WatchpointKind::Read,
+                WatchKind::ReadWrite => WatchpointKind::ReadWrite,
+            },
+        });
+
+        Ok(self.update_guest_debug(|stub| {
+            stub.hw_watchpoints.pop();
+        }))
+    }
+
+    /// Remove an existing hardware watchpoint.
+    /// Return `Ok(false)` if the operation could not be completed.
+    fn remove_hw_watchpoint(
+        &mut self,
+        addr: <Self::Arch as Arch>::Usize,
+        len: <Self::Arch as Arch>::Usize,
+        _kind: WatchKind,
+    ) -> TargetResult<bool, Self> {
+        let old_watchpoints = self.hw_watchpoints.clone();
+        self.hw_watchpoints
+            .retain(|w| w.addr.0 != addr || w.len != len);
+
+        Ok(self.update_guest_debug(|stub| stub.hw_watchpoints = old_watchpoints))
     }
 }
 
-impl SingleRegisterAccess<()> for GdbStub {
+impl SingleRegisterAccess<Tid> for GdbStub {
     fn read_register(
         &mut self,
-        _tid: (),
+        tid: Tid,
         reg_id: <Self::Arch as Arch>::RegId,
         buf: &mut [u8],
     ) -> TargetResult<usize, Self> {
-        match self.vcpu_request(VcpuControl::Debug(VcpuDebug::ReadReg(reg_id))) {
+        match self.vcpu_request(
+            tid_to_cpu(tid),
+            VcpuControl::Debug(VcpuDebug::ReadReg(reg_id)),
+        ) {
             Ok(VcpuDebugStatus::RegValue(r)) => {
                 if buf.len() != r.len() {
                     error!(
@@ -447,14 +663,14 @@ impl SingleRegisterAccess<()> for GdbStub {
 
     fn write_register(
         &mut self,
-        _tid: (),
+        tid: Tid,
         reg_id: <Self::Arch as Arch>::RegId,
         val: &[u8],
     ) -> TargetResult<(), Self> {
-        match self.vcpu_request(VcpuControl::Debug(VcpuDebug::WriteReg(
-            reg_id,
-            val.to_owned(),
-        ))) {
+        match self.vcpu_request(
+            tid_to_cpu(tid),
+            VcpuControl::Debug(VcpuDebug::WriteReg(reg_id, val.to_owned())),
+        ) {
             Ok(VcpuDebugStatus::CommandComplete) => Ok(()),
             Ok(s) => {
                 error!("Unexpected vCPU response for WriteReg: {:?}", s);
@@ -473,7 +689,7 @@ struct GdbStubEventLoop;
 impl BlockingEventLoop for GdbStubEventLoop {
     type Target = GdbStub;
     type Connection = Box<dyn ConnectionExt<Error = std::io::Error>>;
-    type StopReason = SingleThreadStopReason<<GdbArch as Arch>::Usize>;
+    type StopReason = MultiThreadStopReason<<GdbArch as Arch>::Usize>;
 
     fn wait_for_stop_reason(
         target: &mut Self::Target,
@@ -486,23 +702,25 @@ impl BlockingEventLoop for GdbStubEventLoop {
         >,
     > {
         loop {
+            // Report the vCPUs that stopped while the VM was suspended first.
+            if let Some((cpu, exit)) = target.pending_stops.pop_front() {
+                return target
+                    .stop_reason(cpu, exit)
+                    .map(run_blocking::Event::TargetStopped)
+                    .map_err(run_blocking::WaitForStopReasonError::Target);
+            }
+
             // TODO(keiichiw): handle error?
             if let Ok(msg) = target
                 .from_vcpu
                 .recv_timeout(std::time::Duration::from_millis(100))
             {
                 match msg.msg {
-                    VcpuDebugStatus::HitBreakPoint => {
-                        if target.single_step {
-                            target.single_step = false;
-                            return Ok(run_blocking::Event::TargetStopped(
-                                SingleThreadStopReason::DoneStep,
-                            ));
-                        } else {
-                            return Ok(run_blocking::Event::TargetStopped(
-                                SingleThreadStopReason::HwBreak(()),
-                            ));
-                        }
+                    VcpuDebugStatus::HitBreakPoint(exit) => {
+                        return target
+                            .stop_reason(msg.cpu, exit)
+                            .map(run_blocking::Event::TargetStopped)
+                            .map_err(run_blocking::WaitForStopReasonError::Target);
                     }
                     status => {
                         error!("Unexpected VcpuDebugStatus: {:?}", status);
@@ -533,19 +751,20 @@ impl BlockingEventLoop for GdbStubEventLoop {
             "Failed to suspend the target"
         })?;
 
-        Ok(Some(SingleThreadStopReason::Signal(Signal::SIGINT)))
+        Ok(Some(MultiThreadStopReason::Signal(Signal::SIGINT)))
     }
 }
 
-/// Notify the GDB thread that a VCPU has stopped because of a breakpoint.
+/// Notify the GDB thread that a VCPU has stopped because of a debug event.
 pub fn vcpu_exit_debug(
     cpu: usize,
+    exit: DebugExit,
     to_gdb_tube: Option<&mpsc::Sender<VcpuDebugStatusMessage>>,
 ) -> anyhow::Result<()> {
     if let Some(ch) = to_gdb_tube.as_ref() {
         ch.send(VcpuDebugStatusMessage {
             cpu,
-            msg: VcpuDebugStatus::HitBreakPoint,
+            msg: VcpuDebugStatus::HitBreakPoint(exit),
         })
         .context("failed to send breakpoint status to gdb thread")?;
     }
@@ -567,7 +786,29 @@ where
         .as_ref()
         .context("VcpuControl::Debug received while debugger not connected")?;
 
-    let debug_status = match d {
+    // Always reply so that the GDB thread doesn't wait for a failed command.
+    let debug_status = handle_vcpu_debug(vcpu, guest_mem, d).unwrap_or_else(|e| {
+        error!("vCPU {} failed to handle a gdb command: {:#}", cpu_id, e);
+        VcpuDebugStatus::CommandFailed
+    });
+
+    reply_tube
+        .send(VcpuDebugStatusMessage {
+            cpu: cpu_id,
+            msg: debug_status,
+        })
+        .context("failed to send a debug status to GDB thread")
+}
+
+fn handle_vcpu_debug<V>(
+    vcpu: &V,
+    guest_mem: &GuestMemory,
+    d: VcpuDebug,
+) -> anyhow::Result<VcpuDebugStatus>
+where
+    V: VcpuArch + 'static,
+{
+    Ok(match d {
         VcpuDebug::ReadRegs => VcpuDebugStatus::RegValues(
             <CrosvmArch as arch::GdbOps<V>>::read_registers(vcpu as &V)
                 .context("failed to handle a gdb ReadRegs command")?,
@@ -595,26 +836,27 @@ where
                 .context("failed to handle a gdb WriteMem command")?;
             VcpuDebugStatus::CommandComplete
         }
-        VcpuDebug::EnableSinglestep => {
-            <CrosvmArch as arch::GdbOps<V>>::enable_singlestep(vcpu as &V)
-                .context("failed to handle a gdb EnableSingleStep command")?;
-            VcpuDebugStatus::CommandComplete
-        }
         VcpuDebug::GetHwBreakPointCount => VcpuDebugStatus::HwBreakPointCount(
             <CrosvmArch as arch::GdbOps<V>>::get_max_hw_breakpoints(vcpu as &V)
                 .context("failed to get max number of HW breakpoints")?,
         ),
-        VcpuDebug::SetHwBreakPoint(addrs) => {
-            <CrosvmArch as arch::GdbOps<V>>::set_hw_breakpoints(vcpu as &V, &addrs)
-                .context("failed to handle a gdb SetHwBreakPoint command")?;
+        VcpuDebug::GetHwWatchPointCount => VcpuDebugStatus::HwWatchPointCount(
+            <CrosvmArch as arch::GdbOps<V>>::get_max_hw_watchpoints(vcpu as &V)
+                .context("failed to get max number of HW watchpoints")?,
+        ),
+        VcpuDebug::SetGuestDebug {
+            breakpoints,
+            watchpoints,
+            single_step,
+        } => {
+            <CrosvmArch as arch::GdbOps<V>>::set_guest_debug(
+                vcpu as &V,
+                &breakpoints,
+                &watchpoints,
+                single_step,
+            )
+            .context("failed to handle a gdb SetGuestDebug command")?;
             VcpuDebugStatus::CommandComplete
         }
-    };
-
-    reply_tube
-        .send(VcpuDebugStatusMessage {
-            cpu: cpu_id,
-            msg: debug_status,
-        })
-        .context("failed to send a debug status to GDB thread")
+    })
 }
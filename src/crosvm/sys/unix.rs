@@ -153,6 +153,7 @@ use hypervisor::VmX86_64 as VmArch;
 use jail_helpers::*;
 use libc;
 use minijail::Minijail;
+use net_util::sys::unix::UserNet;
 use resources::AddressRange;
 use resources::Alloc;
 #[cfg(feature = "direct")]
@@ -165,6 +166,9 @@ use rutabaga_gfx::RutabagaGralloc;
 use swap::SwapController;
 use sync::Condvar;
 use sync::Mutex;
+use vm_control::dirty_log::DirtyMemoryTracker;
+use vm_control::dirty_log::DIRTY_RING_ENTRIES;
+use vm_control::migration::MigrationReceiver;
 use vm_control::*;
 use vm_memory::GuestAddress;
 use vm_memory::GuestMemory;
@@ -191,6 +195,17 @@ use crate::crosvm::gdb::GdbStub;
 use crate::crosvm::ratelimit::Ratelimit;
 use crate::crosvm::sys::cmdline::DevicesCommand;
 
+/// Creates the control socket of the input device named `name`, keeping its host end in
+/// `input_host_tubes`, and returns its device end.
+fn create_input_control_tube(
+    input_host_tubes: &mut BTreeMap<String, Tube>,
+    name: String,
+) -> DeviceResult<Tube> {
+    let (input_host_tube, input_device_tube) = Tube::pair().context("failed to create tube")?;
+    input_host_tubes.insert(name, input_host_tube);
+    Ok(input_device_tube)
+}
+
 fn create_virtio_devices(
     cfg: &Config,
     vm: &mut impl Vm,
@@ -200,7 +215,10 @@ fn create_virtio_devices(
     #[cfg(feature = "balloon")] balloon_inflate_tube: Option<Tube>,
     #[cfg(feature = "balloon")] init_balloon_size: u64,
     disk_device_tubes: &mut Vec<Tube>,
+    net_host_tubes: &mut Vec<Tube>,
+    input_host_tubes: &mut BTreeMap<String, Tube>,
     pmem_device_tubes: &mut Vec<Tube>,
+    mem_device: Option<(AddressRange, Tube)>,
     fs_device_tubes: &mut Vec<Tube>,
     #[cfg(feature = "gpu")] gpu_control_tube: Tube,
     #[cfg(all(feature = "gpu", feature = "virgl_renderer_next"))] render_server_fd: Option<
@@ -301,6 +319,10 @@ fn create_virtio_devices(
                     virtio_dev_socket,
                     multi_touch_width,
                     multi_touch_height,
+                    Some(create_input_control_tube(
+                        input_host_tubes,
+                        "display-touch".to_string(),
+                    )?),
                     virtio::base_features(cfg.protection_type),
                 )
                 .context("failed to set up mouse device")?;
@@ -319,6 +341,10 @@ fn create_virtio_devices(
                     // the multi_touch options, which begin at 0.
                     u32::MAX,
                     virtio_dev_socket,
+                    Some(create_input_control_tube(
+                        input_host_tubes,
+                        "display-keyboard".to_string(),
+                    )?),
                     virtio::base_features(cfg.protection_type),
                 )
                 .context("failed to set up keyboard device")?;
@@ -354,7 +380,11 @@ fn create_virtio_devices(
     }
 
     for disk in &cfg.disks {
-        let disk_config = DiskConfig::new(disk, Some(disk_device_tubes.remove(0)));
+        let mut disk_config = DiskConfig::new(disk, Some(disk_device_tubes.remove(0)));
+        // The source of a migration holds the lock until the VM is migrated.
+        if cfg.incoming.is_some() {
+            disk_config = disk_config.without_lock();
+        }
         devs.push(
             disk_config.create_virtio_device_and_jail(cfg.protection_type, &cfg.jail_config)?,
         );
@@ -384,6 +414,15 @@ fn create_virtio_devices(
         )?);
     }
 
+    if let Some((region, mem_device_tube)) = mem_device {
+        devs.push(create_mem_device(
+            cfg.protection_type,
+            &cfg.jail_config,
+            region,
+            mem_device_tube,
+        )?);
+    }
+
     if cfg.rng {
         devs.push(create_rng_device(cfg.protection_type, &cfg.jail_config)?);
     }
@@ -414,6 +453,7 @@ fn create_virtio_devices(
             &cfg.jail_config,
             single_touch_spec,
             idx as u32,
+            create_input_control_tube(input_host_tubes, format!("single-touch{}", idx))?,
         )?);
     }
 
@@ -423,6 +463,7 @@ fn create_virtio_devices(
             &cfg.jail_config,
             multi_touch_spec,
             idx as u32,
+            create_input_control_tube(input_host_tubes, format!("multi-touch{}", idx))?,
         )?);
     }
 
@@ -432,6 +473,7 @@ fn create_virtio_devices(
             &cfg.jail_config,
             trackpad_spec,
             idx as u32,
+            create_input_control_tube(input_host_tubes, format!("trackpad{}", idx))?,
         )?);
     }
 
@@ -441,6 +483,7 @@ fn create_virtio_devices(
             &cfg.jail_config,
             mouse_socket,
             idx as u32,
+            create_input_control_tube(input_host_tubes, format!("mouse{}", idx))?,
         )?);
     }
 
@@ -450,6 +493,7 @@ fn create_virtio_devices(
             &cfg.jail_config,
             keyboard_socket,
             idx as u32,
+            create_input_control_tube(input_host_tubes, format!("keyboard{}", idx))?,
         )?);
     }
 
@@ -459,21 +503,25 @@ fn create_virtio_devices(
             &cfg.jail_config,
             switches_socket,
             idx as u32,
+            create_input_control_tube(input_host_tubes, format!("switches{}", idx))?,
         )?);
     }
 
-    for dev_path in &cfg.virtio_input_evdevs {
+    for (idx, dev_path) in cfg.virtio_input_evdevs.iter().enumerate() {
         devs.push(create_vinput_device(
             cfg.protection_type,
             &cfg.jail_config,
             dev_path,
+            create_input_control_tube(input_host_tubes, format!("evdev{}", idx))?,
         )?);
     }
 
     #[cfg(feature = "balloon")]
     if let Some(balloon_device_tube) = balloon_device_tube {
-        let balloon_features =
-            (cfg.balloon_page_reporting as u64) << BalloonFeatures::PageReporting as u64;
+        let balloon_features = (cfg.balloon_page_reporting as u64)
+            << BalloonFeatures::PageReporting as u64
+            | (cfg.balloon_free_page_hint as u64) << BalloonFeatures::FreePageHint as u64
+            | (cfg.balloon_ws_num_bins.is_some() as u64) << BalloonFeatures::WSReporting as u64;
         devs.push(create_balloon_device(
             cfg.protection_type,
             &cfg.jail_config,
@@ -486,6 +534,7 @@ fn create_virtio_devices(
             balloon_inflate_tube,
             init_balloon_size,
             balloon_features,
+            cfg.balloon_ws_num_bins.unwrap_or(0),
         )?);
     }
 
@@ -527,6 +576,28 @@ fn create_virtio_devices(
         let vq_pairs = cfg.net_vq_pairs.unwrap_or(1);
         let vcpu_count = cfg.vcpu_count.unwrap_or(1);
         let multi_vq = vq_pairs > 1 && !opt.vhost_net;
+        // One control socket per net device, so `net_index` counts them all, even though
+        // vhost-net devices don't handle any commands.
+        let (net_host_tube, net_device_tube) = Tube::pair().context("failed to create tube")?;
+        net_host_tubes.push(net_host_tube);
+        if let NetParametersMode::User { mac, host_fwd, .. } = &opt.mode {
+            if opt.vhost_net {
+                bail!("vhost-net cannot be used with user-mode networking");
+            }
+            let (user_net, stack) =
+                UserNet::new(host_fwd).context("failed to create user-mode network")?;
+            start_user_net_stack(&cfg.jail_config, stack)?;
+            devs.push(create_virtio_net_device_from_tap(
+                cfg.protection_type,
+                &cfg.jail_config,
+                vq_pairs,
+                vcpu_count,
+                user_net,
+                *mac,
+                net_device_tube,
+            )?);
+            continue;
+        }
         let (tap, mac) = create_tap_for_net_device(&opt.mode, multi_vq)?;
         let dev = if opt.vhost_net {
             create_virtio_vhost_net_device_from_tap(
@@ -545,6 +616,7 @@ fn create_virtio_devices(
                 vcpu_count,
                 tap,
                 mac,
+                net_device_tube,
             )
         }?;
         devs.push(dev);
@@ -609,15 +681,24 @@ fn create_virtio_devices(
     }
 
     if let Some(cid) = cfg.cid {
-        let vhost_config = VhostVsockConfig {
-            device: cfg.vhost_vsock_device.clone(),
-            cid,
-        };
-        devs.push(create_vhost_vsock_device(
-            cfg.protection_type,
-            &cfg.jail_config,
-            &vhost_config,
-        )?);
+        if let Some(uds_path) = &cfg.vsock_uds {
+            devs.push(create_vsock_device(
+                cfg.protection_type,
+                &cfg.jail_config,
+                cid,
+                uds_path,
+            )?);
+        } else {
+            let vhost_config = VhostVsockConfig {
+                device: cfg.vhost_vsock_device.clone(),
+                cid,
+            };
+            devs.push(create_vhost_vsock_device(
+                cfg.protection_type,
+                &cfg.jail_config,
+                &vhost_config,
+            )?);
+        }
     }
 
     for vhost_user_fs in &cfg.vhost_user_fs {
@@ -701,7 +782,10 @@ fn create_devices(
     #[cfg(feature = "balloon")] balloon_device_tube: Option<Tube>,
     #[cfg(feature = "balloon")] init_balloon_size: u64,
     disk_device_tubes: &mut Vec<Tube>,
+    net_host_tubes: &mut Vec<Tube>,
+    input_host_tubes: &mut BTreeMap<String, Tube>,
     pmem_device_tubes: &mut Vec<Tube>,
+    mem_device: Option<(AddressRange, Tube)>,
     fs_device_tubes: &mut Vec<Tube>,
     #[cfg(feature = "usb")] usb_provider: HostBackendDeviceProvider,
     #[cfg(feature = "gpu")] gpu_control_tube: Tube,
@@ -832,7 +916,10 @@ fn create_devices(
         #[cfg(feature = "balloon")]
         init_balloon_size,
         disk_device_tubes,
+        net_host_tubes,
+        input_host_tubes,
         pmem_device_tubes,
+        mem_device,
         fs_device_tubes,
         #[cfg(feature = "gpu")]
         gpu_control_tube,
@@ -1219,6 +1306,11 @@ fn setup_vm_components(cfg: &Config) -> Result<VmComponents> {
         #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
         oem_strings: cfg.oem_strings.clone(),
         host_cpu_topology: cfg.host_cpu_topology,
+        hotplug_memory_size: cfg
+            .hotplug_memory
+            .unwrap_or(0)
+            .checked_mul(1024 * 1024)
+            .ok_or_else(|| anyhow!("requested hotplug memory size too large"))?,
         itmt: cfg.itmt,
         #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
         force_s2idle: cfg.force_s2idle,
@@ -1339,7 +1431,16 @@ fn run_kvm(cfg: Config, components: VmComponents) -> Result<ExitState> {
         swap_controller,
     } = create_guest_memory(&cfg, &components, &kvm)?;
 
-    let vm = KvmVm::new(&kvm, guest_mem, components.hv_cfg).context("failed to create vm")?;
+    let mut vm = KvmVm::new(&kvm, guest_mem, components.hv_cfg).context("failed to create vm")?;
+
+    // The rings make harvesting the dirty log cost the number of dirty pages instead of the size of
+    // guest memory, they must be set up before the vcpus are created.
+    if vm.check_capability(VmCap::DirtyRing) {
+        match vm.enable_dirty_ring(DIRTY_RING_ENTRIES) {
+            Ok(()) => info!("tracking dirty pages with the KVM dirty ring"),
+            Err(e) => warn!("failed to enable the KVM dirty ring: {}", e),
+        }
+    }
 
     #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
     if cfg.itmt {
@@ -1526,6 +1627,12 @@ where
         disk_device_tubes.push(disk_device_tube);
     }
 
+    // Filled with one control socket per net device when they are created.
+    let mut net_host_tubes = Vec::new();
+
+    // Filled with one control socket per input device, keyed by the name of the device.
+    let mut input_host_tubes = BTreeMap::new();
+
     let mut pmem_device_tubes = Vec::new();
     let pmem_count = cfg.pmem_devices.len();
     for _ in 0..pmem_count {
@@ -1534,6 +1641,16 @@ where
         control_tubes.push(TaggedControlTube::VmMsync(pmem_host_tube));
     }
 
+    // The virtio-mem device gets its own socket so resize requests can be forwarded from the main
+    // process.
+    let (mem_host_tube, mem_device) = match Arch::hotplug_memory_region(&components) {
+        Some(region) => {
+            let (host, device) = Tube::pair().context("failed to create tube")?;
+            (Some(host), Some((region, device)))
+        }
+        None => (None, None),
+    };
+
     if let Some(ioapic_host_tube) = ioapic_host_tube {
         control_tubes.push(TaggedControlTube::VmIrq(ioapic_host_tube));
     }
@@ -1714,7 +1831,10 @@ where
         #[cfg(feature = "balloon")]
         init_balloon_size,
         &mut disk_device_tubes,
+        &mut net_host_tubes,
+        &mut input_host_tubes,
         &mut pmem_device_tubes,
+        mem_device,
         &mut fs_device_tubes,
         #[cfg(feature = "usb")]
         usb_provider,
@@ -1903,6 +2023,9 @@ where
         #[cfg(feature = "balloon")]
         balloon_host_tube,
         &disk_host_tubes,
+        &net_host_tubes,
+        &input_host_tubes,
+        mem_host_tube,
         #[cfg(feature = "gpu")]
         gpu_control_host_tube,
         #[cfg(feature = "usb")]
@@ -2332,6 +2455,44 @@ fn handle_hotplug_command<V: VmArch, Vcpu: VcpuArch>(
     }
 }
 
+/// Saves the state of the interrupt controllers for a snapshot of the VM.
+fn snapshot_irq_chip(irq_chip: &dyn IrqChipArch, num_vcpus: usize) -> Result<serde_json::Value> {
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    {
+        irq_chip.snapshot(num_vcpus)
+    }
+    #[cfg(not(any(target_arch = "x86", target_arch = "x86_64")))]
+    {
+        let _ = (irq_chip, num_vcpus);
+        Err(anyhow!(
+            "irqchip snapshot is not supported on this architecture"
+        ))
+    }
+}
+
+/// Restores the state of the interrupt controllers saved by `snapshot_irq_chip`.
+fn restore_irq_chip(
+    irq_chip: &dyn IrqChipArch,
+    data: serde_json::Value,
+    num_vcpus: usize,
+) -> Result<()> {
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    {
+        // Clones of the irqchip share its state.
+        irq_chip
+            .try_box_clone()
+            .context("failed to clone irqchip")?
+            .restore(data, num_vcpus)
+    }
+    #[cfg(not(any(target_arch = "x86", target_arch = "x86_64")))]
+    {
+        let _ = (irq_chip, data, num_vcpus);
+        Err(anyhow!(
+            "irqchip restore is not supported on this architecture"
+        ))
+    }
+}
+
 fn run_control<V: VmArch + 'static, Vcpu: VcpuArch + 'static>(
     mut linux: RunnableLinuxVm<V, Vcpu>,
     mut sys_allocator: SystemAllocator,
@@ -2340,6 +2501,9 @@ fn run_control<V: VmArch + 'static, Vcpu: VcpuArch + 'static>(
     mut control_tubes: Vec<TaggedControlTube>,
     #[cfg(feature = "balloon")] balloon_host_tube: Option<Tube>,
     disk_host_tubes: &[Tube],
+    net_host_tubes: &[Tube],
+    input_host_tubes: &BTreeMap<String, Tube>,
+    mem_host_tube: Option<Tube>,
     #[cfg(feature = "gpu")] gpu_control_tube: Tube,
     #[cfg(feature = "usb")] usb_control_tube: Tube,
     vm_evt_rdtube: RecvTube,
@@ -2423,7 +2587,7 @@ fn run_control<V: VmArch + 'static, Vcpu: VcpuArch + 'static>(
     };
 
     let (device_ctrl_tube, device_ctrl_resp) = Tube::pair().context("failed to create tube")?;
-    // Create devices thread, and restore if a restore file exists.
+    // Create devices thread.
     linux.devices_thread = match create_devices_worker_thread(
         linux.vm.get_memory().clone(),
         linux.io_bus.clone(),
@@ -2435,14 +2599,6 @@ fn run_control<V: VmArch + 'static, Vcpu: VcpuArch + 'static>(
             return Err(anyhow!("Failed to start devices thread: {}", e));
         }
     };
-    if let Some(path) = cfg.restore_path.clone() {
-        if let Err(e) =
-            device_ctrl_tube.send(&DeviceControlCommand::RestoreDevices { restore_path: path })
-        {
-            error!("fail to send command to devices control socket: {}", e);
-        };
-    }
-
     let mut vcpu_handles = Vec::with_capacity(linux.vcpu_count);
     let vcpu_thread_barrier = Arc::new(Barrier::new(linux.vcpu_count + 1));
     let use_hypervisor_signals = !linux
@@ -2583,6 +2739,7 @@ fn run_control<V: VmArch + 'static, Vcpu: VcpuArch + 'static>(
             #[cfg(all(any(target_arch = "x86", target_arch = "x86_64"), unix))]
             bus_lock_ratelimit_ctrl,
             to_vm_control.clone(),
+            cfg.restore_path.is_some() || cfg.incoming.is_some(),
         )?;
         vcpu_handles.push((handle, to_vcpu_channel));
     }
@@ -2607,10 +2764,64 @@ fn run_control<V: VmArch + 'static, Vcpu: VcpuArch + 'static>(
 
     vcpu_thread_barrier.wait();
 
+    // The vCPUs start suspended when restoring, and only run once the whole VM is restored.
+    if let Some(path) = &cfg.restore_path {
+        let kick_vcpus =
+            |msg| vcpu::kick_all_vcpus(&vcpu_handles, linux.irq_chip.as_irq_chip(), msg);
+        let result = match restore_vm(
+            path,
+            &kick_vcpus,
+            &state_from_vcpu_channel,
+            vcpu_handles.len(),
+            |data| restore_irq_chip(linux.irq_chip.as_ref(), data, vcpu_handles.len()),
+            &device_ctrl_tube,
+        ) {
+            VmResponse::RestoreResponse(RestoreControlResult::Ok) => Ok(()),
+            VmResponse::RestoreResponse(RestoreControlResult::Failed(e)) => Err(anyhow!(e)),
+            response => Err(anyhow!("unexpected response: {}", response)),
+        };
+        if let Err(e) = result {
+            kick_vcpus(VcpuControl::RunState(VmRunMode::Exiting));
+            return Err(e).with_context(|| format!("failed to restore from {}", path.display()));
+        }
+        kick_vcpus(VcpuControl::RunState(VmRunMode::Running));
+    }
+
+    // Likewise when migrating in, where the vCPUs only run once the source released the disks.
+    let mut disk_locks = Vec::new();
+    if let Some(path) = &cfg.incoming {
+        let kick_vcpus =
+            |msg| vcpu::kick_all_vcpus(&vcpu_handles, linux.irq_chip.as_irq_chip(), msg);
+        let result: Result<()> = (|| {
+            info!("waiting for a migration on {}", path.display());
+            let mut receiver = MigrationReceiver::listen(path)?;
+            receive_migrated_vm(
+                &mut receiver,
+                linux.vm.get_memory(),
+                &kick_vcpus,
+                vcpu_handles.len(),
+                |data| restore_irq_chip(linux.irq_chip.as_ref(), data, vcpu_handles.len()),
+                &device_ctrl_tube,
+            )?;
+            receiver.ack()?;
+            info!("waiting for the migration source to release the disks");
+            for disk in &cfg.disks {
+                disk_locks.push(disk.lock()?);
+            }
+            Ok(())
+        })();
+        if let Err(e) = result {
+            kick_vcpus(VcpuControl::RunState(VmRunMode::Exiting));
+            return Err(e).context("failed to receive the migrated VM");
+        }
+        kick_vcpus(VcpuControl::RunState(VmRunMode::Running));
+    }
+
     let mut exit_state = ExitState::Stop;
     let mut pvpanic_code = PvPanicCode::Unknown;
     #[cfg(feature = "balloon")]
     let mut balloon_stats_id: u64 = 0;
+    let mut dirty_memory_tracker = DirtyMemoryTracker::new();
 
     'wait: loop {
         let events = {
@@ -2775,6 +2986,48 @@ fn run_control<V: VmArch + 'static, Vcpu: VcpuArch + 'static>(
                                                 VmResponse::Ok
                                             }
                                         }
+                                        VmRequest::Migrate(_)
+                                            if dirty_memory_tracker.is_logging() =>
+                                        {
+                                            VmResponse::MigrateResponse(
+                                                MigrateControlResult::Failed(
+                                                    "dirty memory logging must be stopped first"
+                                                        .to_string(),
+                                                ),
+                                            )
+                                        }
+                                        VmRequest::Migrate(MigrateCommand::Send { socket }) => {
+                                            let response = migrate_vm(
+                                                &socket,
+                                                &mut linux.vm,
+                                                |msg| {
+                                                    vcpu::kick_all_vcpus(
+                                                        &vcpu_handles,
+                                                        linux.irq_chip.as_irq_chip(),
+                                                        msg,
+                                                    )
+                                                },
+                                                &state_from_vcpu_channel,
+                                                vcpu_handles.len(),
+                                                || {
+                                                    snapshot_irq_chip(
+                                                        linux.irq_chip.as_ref(),
+                                                        vcpu_handles.len(),
+                                                    )
+                                                },
+                                                &device_ctrl_tube,
+                                            );
+                                            // The destination runs the VM from now on.
+                                            if let VmResponse::MigrateResponse(
+                                                MigrateControlResult::Ok,
+                                            ) = response
+                                            {
+                                                run_mode_opt = Some(VmRunMode::Exiting);
+                                            }
+                                            response
+                                        }
+                                        VmRequest::DirtyLog(command) => dirty_memory_tracker
+                                            .handle_command(&mut linux.vm, &command),
                                         _ => {
                                             let response = request.execute(
                                                 &mut run_mode_opt,
@@ -2783,6 +3036,9 @@ fn run_control<V: VmArch + 'static, Vcpu: VcpuArch + 'static>(
                                                 #[cfg(feature = "balloon")]
                                                 &mut balloon_stats_id,
                                                 disk_host_tubes,
+                                                net_host_tubes,
+                                                input_host_tubes,
+                                                mem_host_tube.as_ref(),
                                                 &mut linux.pm,
                                                 #[cfg(feature = "gpu")]
                                                 &gpu_control_tube,
@@ -2804,6 +3060,19 @@ fn run_control<V: VmArch + 'static, Vcpu: VcpuArch + 'static>(
                                                 &device_ctrl_tube,
                                                 &state_from_vcpu_channel,
                                                 vcpu_handles.len(),
+                                                || {
+                                                    snapshot_irq_chip(
+                                                        linux.irq_chip.as_ref(),
+                                                        vcpu_handles.len(),
+                                                    )
+                                                },
+                                                |data| {
+                                                    restore_irq_chip(
+                                                        linux.irq_chip.as_ref(),
+                                                        data,
+                                                        vcpu_handles.len(),
+                                                    )
+                                                },
                                             );
 
                                             // For non s2idle guest suspension we are done
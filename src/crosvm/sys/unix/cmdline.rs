@@ -108,10 +108,86 @@ pub struct DevicesCommand {
     pub control_socket: Option<PathBuf>,
 }
 
+#[derive(FromArgs)]
+#[argh(subcommand, name = "migrate")]
+/// Live migration commands
+pub struct MigrateCommand {
+    #[argh(subcommand)]
+    pub migrate_command: MigrateSubCommands,
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand, name = "send")]
+/// Migrate the running VM to a crosvm instance started with `--incoming`
+pub struct MigrateSendCommand {
+    #[argh(positional, arg_name = "DESTINATION")]
+    /// socket path given to `--incoming`
+    pub destination: PathBuf,
+    #[argh(positional, arg_name = "VM_SOCKET")]
+    /// VM Socket path
+    pub socket_path: String,
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand)]
+/// Live migration commands
+pub enum MigrateSubCommands {
+    Send(MigrateSendCommand),
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand, name = "dirty-log")]
+/// Track the pages written by the guest
+pub struct DirtyLogCommand {
+    #[argh(subcommand)]
+    pub dirty_log_command: DirtyLogSubCommands,
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand, name = "start")]
+/// Start logging the pages written by the guest
+pub struct DirtyLogStartCommand {
+    #[argh(positional, arg_name = "VM_SOCKET")]
+    /// VM Socket path
+    pub socket_path: String,
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand, name = "stop")]
+/// Stop logging the pages written by the guest
+pub struct DirtyLogStopCommand {
+    #[argh(positional, arg_name = "VM_SOCKET")]
+    /// VM Socket path
+    pub socket_path: String,
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand, name = "report")]
+/// Report the pages written since logging started or since the previous report
+pub struct DirtyLogReportCommand {
+    #[argh(option, arg_name = "PATH")]
+    /// file to write the bitmaps of the dirty pages of each memory region to
+    pub bitmap: Option<PathBuf>,
+    #[argh(positional, arg_name = "VM_SOCKET")]
+    /// VM Socket path
+    pub socket_path: String,
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand)]
+/// Dirty log commands
+pub enum DirtyLogSubCommands {
+    Start(DirtyLogStartCommand),
+    Stop(DirtyLogStopCommand),
+    Report(DirtyLogReportCommand),
+}
+
 #[derive(FromArgs)]
 #[argh(subcommand)]
 /// Unix Commands
 pub enum Commands {
     #[cfg(unix)]
     Devices(DevicesCommand),
+    Migrate(MigrateCommand),
+    DirtyLog(DirtyLogCommand),
 }
@@ -4,6 +4,7 @@
 
 use std::collections::BTreeMap;
 use std::convert::TryFrom;
+use std::ffi::CString;
 use std::fs::OpenOptions;
 use std::ops::RangeInclusive;
 use std::os::unix::net::UnixListener;
@@ -62,9 +63,11 @@ use hypervisor::ProtectionType;
 use hypervisor::Vm;
 use minijail::Minijail;
 use net_util::sys::unix::Tap;
+use net_util::sys::unix::UserNetStack;
 use net_util::MacAddress;
 use net_util::TapT;
 use net_util::TapTCommon;
+use resources::AddressRange;
 use resources::Alloc;
 use resources::AllocOptions;
 use resources::SystemAllocator;
@@ -196,11 +199,27 @@ pub struct DiskConfig<'a> {
     disk: &'a DiskOption,
     /// Optional control tube for the device.
     device_tube: Option<Tube>,
+    /// Whether to lock the disk image when opening it.
+    lock: bool,
 }
 
 impl<'a> DiskConfig<'a> {
     pub fn new(disk: &'a DiskOption, device_tube: Option<Tube>) -> Self {
-        Self { disk, device_tube }
+        Self {
+            disk,
+            device_tube,
+            lock: true,
+        }
+    }
+
+    /// Opens the disk without locking it, for a VM migrated from another crosvm instance that
+    /// still holds the lock. The caller must lock the disk with `DiskOption::lock` before the VM
+    /// runs.
+    pub fn without_lock(self) -> Self {
+        Self {
+            lock: false,
+            ..self
+        }
     }
 }
 
@@ -215,7 +234,11 @@ impl<'a> VirtioDeviceBuilder for DiskConfig<'a> {
             "Trying to attach block device: {}",
             self.disk.path.display(),
         );
-        let disk_image = self.disk.open()?;
+        let disk_image = if self.lock {
+            self.disk.open()?
+        } else {
+            self.disk.open_unlocked()?
+        };
 
         Ok(Box::new(
             virtio::BlockAsync::new(
@@ -229,6 +252,8 @@ impl<'a> VirtioDeviceBuilder for DiskConfig<'a> {
                 None,
                 self.disk.async_executor,
                 None,
+                self.disk.throttle,
+                self.disk.zoned.clone(),
             )
             .context("failed to create block device")?,
         ))
@@ -252,6 +277,8 @@ impl<'a> VirtioDeviceBuilder for DiskConfig<'a> {
                 None,
                 disk.async_executor,
                 None,
+                disk.throttle,
+                disk.zoned.clone(),
             )
             .context("failed to create block device")?,
         );
@@ -414,6 +441,25 @@ pub fn create_rng_device(
     })
 }
 
+pub fn create_mem_device(
+    protection_type: ProtectionType,
+    jail_config: &Option<JailConfig>,
+    region: AddressRange,
+    mem_device_tube: Tube,
+) -> DeviceResult {
+    let dev = virtio::Mem::new(
+        virtio::base_features(protection_type),
+        region,
+        mem_device_tube,
+    )
+    .context("failed to set up virtio-mem device")?;
+
+    Ok(VirtioDeviceStub {
+        dev: Box::new(dev),
+        jail: simple_jail(jail_config, "mem_device")?,
+    })
+}
+
 #[cfg(feature = "audio")]
 pub fn create_virtio_snd_device(
     protection_type: ProtectionType,
@@ -421,6 +467,8 @@ pub fn create_virtio_snd_device(
     snd_params: SndParameters,
 ) -> DeviceResult {
     let backend = snd_params.backend;
+    let playback_path = snd_params.playback_path.clone();
+    let capture_path = snd_params.capture_path.clone();
     let dev = virtio::snd::common_backend::VirtioSnd::new(
         virtio::base_features(protection_type),
         snd_params,
@@ -433,19 +481,26 @@ pub fn create_virtio_snd_device(
         Backend::NULL => "snd_null_device",
         #[cfg(feature = "audio_cras")]
         Backend::Sys(virtio::snd::sys::StreamSourceBackend::CRAS) => "snd_cras_device",
-        #[cfg(not(feature = "audio_cras"))]
-        _ => unreachable!(),
+        Backend::Sys(virtio::snd::sys::StreamSourceBackend::FILE) => "snd_file_device",
+        #[cfg(feature = "audio_pulse")]
+        Backend::Sys(virtio::snd::sys::StreamSourceBackend::PULSE) => "snd_pulse_device",
     };
 
     let jail = if let Some(jail_config) = jail_config {
         let mut config = SandboxConfig::new(jail_config, policy);
+        if backend == Backend::Sys(virtio::snd::sys::StreamSourceBackend::FILE) {
+            config.bind_mounts = true;
+        }
         #[cfg(feature = "audio_cras")]
         if backend == Backend::Sys(virtio::snd::sys::StreamSourceBackend::CRAS) {
             config.bind_mounts = true;
         }
+        #[cfg(feature = "audio_pulse")]
+        if backend == Backend::Sys(virtio::snd::sys::StreamSourceBackend::PULSE) {
+            config.bind_mounts = true;
+        }
         // TODO(b/267574679): running as current_user may not be required for snd device.
         config.run_as = RunAsUser::CurrentUser;
-        #[allow(unused_mut)]
         let mut jail =
             create_sandbox_minijail(&jail_config.pivot_root, MAX_OPEN_FILES_DEFAULT, &config)?;
         #[cfg(feature = "audio_cras")]
@@ -453,6 +508,29 @@ pub fn create_virtio_snd_device(
             let run_cras_path = Path::new("/run/cras");
             jail.mount_bind(run_cras_path, run_cras_path, true)?;
         }
+        if backend == Backend::Sys(virtio::snd::sys::StreamSourceBackend::FILE) {
+            if let Some(playback_path) = &playback_path {
+                jail.mount_bind(playback_path, playback_path, true)?;
+            }
+            if let Some(capture_path) = &capture_path {
+                jail.mount_bind(capture_path, capture_path, false)?;
+            }
+        }
+        #[cfg(feature = "audio_pulse")]
+        if backend == Backend::Sys(virtio::snd::sys::StreamSourceBackend::PULSE) {
+            // libpulse connects to the socket of the server in the runtime directory of the user,
+            // with the client configuration and the cookie of the user.
+            let mut dirs = vec![PathBuf::from("/etc/pulse")];
+            if let Some(runtime_dir) = std::env::var_os("PULSE_RUNTIME_PATH") {
+                dirs.push(runtime_dir.into());
+            } else if let Some(runtime_dir) = std::env::var_os("XDG_RUNTIME_DIR") {
+                dirs.push(Path::new(&runtime_dir).join("pulse"));
+            }
+            if let Some(home) = std::env::var_os("HOME") {
+                dirs.push(Path::new(&home).join(".config/pulse"));
+            }
+            jail_mount_bind_if_exists(&mut jail, &dirs)?;
+        }
         Some(jail)
     } else {
         None
@@ -538,6 +616,7 @@ pub fn create_single_touch_device(
     jail_config: &Option<JailConfig>,
     single_touch_spec: &TouchDeviceOption,
     idx: u32,
+    control_tube: Tube,
 ) -> DeviceResult {
     let socket = single_touch_spec
         .get_path()
@@ -550,6 +629,7 @@ pub fn create_single_touch_device(
         socket,
         width,
         height,
+        Some(control_tube),
         virtio::base_features(protection_type),
     )
     .context("failed to set up input device")?;
@@ -564,6 +644,7 @@ pub fn create_multi_touch_device(
     jail_config: &Option<JailConfig>,
     multi_touch_spec: &TouchDeviceOption,
     idx: u32,
+    control_tube: Tube,
 ) -> DeviceResult {
     let socket = multi_touch_spec
         .get_path()
@@ -576,6 +657,7 @@ pub fn create_multi_touch_device(
         socket,
         width,
         height,
+        Some(control_tube),
         virtio::base_features(protection_type),
     )
     .context("failed to set up input device")?;
@@ -591,6 +673,7 @@ pub fn create_trackpad_device(
     jail_config: &Option<JailConfig>,
     trackpad_spec: &TouchDeviceOption,
     idx: u32,
+    control_tube: Tube,
 ) -> DeviceResult {
     let socket = trackpad_spec
         .get_path()
@@ -603,6 +686,7 @@ pub fn create_trackpad_device(
         socket,
         width,
         height,
+        Some(control_tube),
         virtio::base_features(protection_type),
     )
     .context("failed to set up input device")?;
@@ -618,13 +702,19 @@ pub fn create_mouse_device<T: IntoUnixStream>(
     jail_config: &Option<JailConfig>,
     mouse_socket: T,
     idx: u32,
+    control_tube: Tube,
 ) -> DeviceResult {
     let socket = mouse_socket
         .into_unix_stream()
         .context("failed configuring virtio mouse")?;
 
-    let dev = virtio::new_mouse(idx, socket, virtio::base_features(protection_type))
-        .context("failed to set up input device")?;
+    let dev = virtio::new_mouse(
+        idx,
+        socket,
+        Some(control_tube),
+        virtio::base_features(protection_type),
+    )
+    .context("failed to set up input device")?;
 
     Ok(VirtioDeviceStub {
         dev: Box::new(dev),
@@ -637,13 +727,19 @@ pub fn create_keyboard_device<T: IntoUnixStream>(
     jail_config: &Option<JailConfig>,
     keyboard_socket: T,
     idx: u32,
+    control_tube: Tube,
 ) -> DeviceResult {
     let socket = keyboard_socket
         .into_unix_stream()
         .context("failed configuring virtio keyboard")?;
 
-    let dev = virtio::new_keyboard(idx, socket, virtio::base_features(protection_type))
-        .context("failed to set up input device")?;
+    let dev = virtio::new_keyboard(
+        idx,
+        socket,
+        Some(control_tube),
+        virtio::base_features(protection_type),
+    )
+    .context("failed to set up input device")?;
 
     Ok(VirtioDeviceStub {
         dev: Box::new(dev),
@@ -656,13 +752,19 @@ pub fn create_switches_device<T: IntoUnixStream>(
     jail_config: &Option<JailConfig>,
     switches_socket: T,
     idx: u32,
+    control_tube: Tube,
 ) -> DeviceResult {
     let socket = switches_socket
         .into_unix_stream()
         .context("failed configuring virtio switches")?;
 
-    let dev = virtio::new_switches(idx, socket, virtio::base_features(protection_type))
-        .context("failed to set up input device")?;
+    let dev = virtio::new_switches(
+        idx,
+        socket,
+        Some(control_tube),
+        virtio::base_features(protection_type),
+    )
+    .context("failed to set up input device")?;
 
     Ok(VirtioDeviceStub {
         dev: Box::new(dev),
@@ -674,6 +776,7 @@ pub fn create_vinput_device(
     protection_type: ProtectionType,
     jail_config: &Option<JailConfig>,
     dev_path: &Path,
+    control_tube: Tube,
 ) -> DeviceResult {
     let dev_file = OpenOptions::new()
         .read(true)
@@ -681,8 +784,12 @@ pub fn create_vinput_device(
         .open(dev_path)
         .with_context(|| format!("failed to open vinput device {}", dev_path.display()))?;
 
-    let dev = virtio::new_evdev(dev_file, virtio::base_features(protection_type))
-        .context("failed to set up input device")?;
+    let dev = virtio::new_evdev(
+        dev_file,
+        Some(control_tube),
+        virtio::base_features(protection_type),
+    )
+    .context("failed to set up input device")?;
 
     Ok(VirtioDeviceStub {
         dev: Box::new(dev),
@@ -699,6 +806,7 @@ pub fn create_balloon_device(
     inflate_tube: Option<Tube>,
     init_balloon_size: u64,
     enabled_features: u64,
+    ws_num_bins: u8,
 ) -> DeviceResult {
     let dev = virtio::Balloon::new(
         virtio::base_features(protection_type),
@@ -707,6 +815,7 @@ pub fn create_balloon_device(
         init_balloon_size,
         mode,
         enabled_features,
+        ws_num_bins,
     )
     .context("failed to create balloon")?;
 
@@ -780,6 +889,49 @@ pub fn create_tap_for_net_device(
             tap.enable().map_err(NetError::TapEnable)?;
             Ok((tap, None))
         }
+        NetParametersMode::User { .. } => bail!("user-mode networking has no tap device"),
+    }
+}
+
+/// Runs a user-mode network stack in a jailed child process, or in a thread of the main process
+/// if the sandbox is disabled.
+pub fn start_user_net_stack(jail_config: &Option<JailConfig>, stack: UserNetStack) -> Result<()> {
+    let jail_config = match jail_config {
+        Some(jail_config) => jail_config,
+        None => return stack.spawn().context("failed to start user-mode network"),
+    };
+    let mut config = SandboxConfig::new(jail_config, "usernet");
+    // The connections of the guest are relayed through sockets of the host network.
+    config.namespace_net = false;
+    let jail = create_sandbox_minijail(&jail_config.pivot_root, MAX_OPEN_FILES_DEFAULT, &config)?;
+
+    let mut keep_rds = stack.keep_rds();
+    syslog::push_descriptors(&mut keep_rds);
+    cros_tracing::push_descriptors!(&mut keep_rds);
+    // Deduplicate the FDs since minijail expects them to be unique.
+    keep_rds.sort_unstable();
+    keep_rds.dedup();
+
+    // Safe because we are keeping all the descriptors needed for the child to function.
+    match unsafe { jail.fork(Some(&keep_rds)) }.context("failed to fork user-mode network")? {
+        0 => {
+            // In the child process. Make sure it does not survive its parent.
+            if unsafe { libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGKILL) } < 0 {
+                panic!("call to prctl(PR_SET_DEATHSIG, SIGKILL) failed. Aborting child process.");
+            }
+            let thread_name = CString::new("usernet").unwrap();
+            // Safe because the name is shorter than 16 bytes.
+            let _ = unsafe { libc::pthread_setname_np(libc::pthread_self(), thread_name.as_ptr()) };
+
+            stack.run();
+            // Exit without running the destructors of the parent resources, whose descriptors
+            // aren't kept in the child.
+            std::process::exit(0);
+        }
+        pid => {
+            info!("user-mode network started in process {}", pid);
+            Ok(())
+        }
     }
 }
 
@@ -791,6 +943,7 @@ pub fn create_virtio_net_device_from_tap<T: TapT + ReadNotifier + 'static>(
     vcpu_count: usize,
     tap: T,
     mac: Option<MacAddress>,
+    control_tube: Tube,
 ) -> DeviceResult {
     create_net_device(
         protection_type,
@@ -799,7 +952,7 @@ pub fn create_virtio_net_device_from_tap<T: TapT + ReadNotifier + 'static>(
         vcpu_count,
         "net_device",
         move |features, vq_pairs| {
-            virtio::Net::new(features, tap, vq_pairs, mac)
+            virtio::Net::new(features, tap, vq_pairs, mac, Some(control_tube))
                 .context("failed to set up virtio networking")
         },
     )
@@ -1056,6 +1209,41 @@ pub fn create_vhost_vsock_device(
     })
 }
 
+pub fn create_vsock_device(
+    protection_type: ProtectionType,
+    jail_config: &Option<JailConfig>,
+    cid: u64,
+    uds_path: &Path,
+) -> DeviceResult {
+    let features = virtio::base_features(protection_type);
+
+    // The device may run in a jail with another root, where relative paths won't resolve.
+    let uds_path = std::env::current_dir()
+        .context("failed to get the current directory")?
+        .join(uds_path);
+    let dev = virtio::Vsock::new(cid, &uds_path, features)
+        .context("failed to set up userspace vsock device")?;
+
+    let jail = if let Some(jail_config) = jail_config {
+        let mut config = SandboxConfig::new(jail_config, "vsock_device");
+        config.bind_mounts = true;
+        let mut jail =
+            create_sandbox_minijail(&jail_config.pivot_root, MAX_OPEN_FILES_DEFAULT, &config)?;
+        // Guest connections are made to sockets next to the listening one.
+        if let Some(dir) = uds_path.parent() {
+            jail.mount_bind(dir, dir, true)?;
+        }
+        Some(jail)
+    } else {
+        None
+    };
+
+    Ok(VirtioDeviceStub {
+        dev: Box::new(dev),
+        jail,
+    })
+}
+
 pub fn create_fs_device(
     protection_type: ProtectionType,
     jail_config: &Option<JailConfig>,
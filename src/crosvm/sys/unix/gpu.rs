@@ -8,7 +8,9 @@
 #[cfg(feature = "virgl_renderer_next")]
 use std::collections::HashMap;
 use std::env;
+use std::net::TcpListener;
 use std::path::PathBuf;
+use std::sync::Arc;
 
 use serde::Deserialize;
 use serde::Serialize;
@@ -109,6 +111,16 @@ pub fn create_gpu_device(
         );
     }
 
+    if let Some(addr) = cfg.vnc {
+        // The listener is bound here as the jail of the device has no network access.
+        let listener = TcpListener::bind(addr)
+            .with_context(|| format!("failed to listen for vnc clients on {}", addr))?;
+        listener
+            .set_nonblocking(true)
+            .context("failed to set vnc listener nonblocking")?;
+        display_backends.insert(0, virtio::DisplayBackend::Vnc(Arc::new(listener)));
+    }
+
     let dev = virtio::Gpu::new(
         exit_evt_wrtube
             .try_clone()
@@ -56,6 +56,8 @@ pub(super) struct SandboxConfig<'a> {
     pub(super) bind_mounts: bool,
     /// Specify the user in the jail to run as.
     pub(super) run_as: RunAsUser,
+    /// Whether or not to run in an empty network namespace instead of the one of crosvm.
+    pub(super) namespace_net: bool,
 }
 
 impl<'a> SandboxConfig<'a> {
@@ -74,6 +76,7 @@ impl<'a> SandboxConfig<'a> {
             remount_mode: None,
             bind_mounts: false,
             run_as: RunAsUser::Unspecified,
+            namespace_net: true,
         }
     }
 }
@@ -181,8 +184,10 @@ pub(super) fn create_sandbox_minijail(
     // Run in a new mount namespace.
     jail.namespace_vfs();
 
-    // Run in an empty network namespace.
-    jail.namespace_net();
+    if config.namespace_net {
+        // Run in an empty network namespace.
+        jail.namespace_net();
+    }
 
     // Don't allow the device to gain new privileges.
     jail.no_new_privs();
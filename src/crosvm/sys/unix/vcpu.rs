@@ -19,6 +19,7 @@ use std::time::Duration;
 use aarch64::AArch64 as Arch;
 #[cfg(any(target_arch = "arm", target_arch = "aarch64"))]
 use aarch64::MsrHandlers;
+use anyhow::anyhow;
 use anyhow::Context;
 use anyhow::Result;
 use arch::CpuSet;
@@ -275,6 +276,39 @@ fn handle_s2idle_request(privileged_vm: bool, guest_suspended_cvar: &Arc<(Mutex<
     }
 }
 
+/// Saves the architectural state of `vcpu` for a snapshot of the VM.
+fn snapshot_vcpu<V: VcpuArch>(vcpu: &V) -> Result<serde_json::Value> {
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    {
+        let snapshot = vcpu.snapshot().context("failed to get vcpu state")?;
+        serde_json::to_value(snapshot).context("failed to serialize vcpu state")
+    }
+    #[cfg(not(any(target_arch = "x86", target_arch = "x86_64")))]
+    {
+        let _ = vcpu;
+        Err(anyhow!(
+            "vcpu snapshot is not supported on this architecture"
+        ))
+    }
+}
+
+/// Restores the state of `vcpu` saved by `snapshot_vcpu`.
+fn restore_vcpu<V: VcpuArch>(vcpu: &mut V, snapshot: &serde_json::Value) -> Result<()> {
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    {
+        let snapshot: hypervisor::VcpuSnapshot =
+            serde_json::from_value(snapshot.clone()).context("failed to parse vcpu state")?;
+        vcpu.restore(&snapshot).context("failed to set vcpu state")
+    }
+    #[cfg(not(any(target_arch = "x86", target_arch = "x86_64")))]
+    {
+        let _ = (vcpu, snapshot);
+        Err(anyhow!(
+            "vcpu restore is not supported on this architecture"
+        ))
+    }
+}
+
 fn vcpu_loop<V>(
     mut run_mode: VmRunMode,
     cpu_id: usize,
@@ -399,6 +433,21 @@ where
                                 error!("Failed to send GetState: {}", e);
                             };
                         }
+                        VcpuControl::Snapshot(result_sender) => {
+                            let result = snapshot_vcpu(&vcpu);
+                            if let Err(e) = result_sender.send((cpu_id, result)) {
+                                error!("Failed to send vcpu {} snapshot: {}", cpu_id, e);
+                            }
+                        }
+                        VcpuControl::Restore(request) => {
+                            let result = match request.snapshots.get(cpu_id) {
+                                Some(snapshot) => restore_vcpu(&mut vcpu, snapshot),
+                                None => Err(anyhow!("no snapshot for vcpu {}", cpu_id)),
+                            };
+                            if let Err(e) = request.result_sender.send((cpu_id, result)) {
+                                error!("Failed to send vcpu {} restore result: {}", cpu_id, e);
+                            }
+                        }
                     }
                 }
             }
@@ -475,13 +524,14 @@ where
                 Ok(VcpuExit::SystemEventS2Idle) => {
                     handle_s2idle_request(privileged_vm, &guest_suspended_cvar);
                 }
-                Ok(VcpuExit::Debug) => {
+                #[allow(unused_variables)]
+                Ok(VcpuExit::Debug(exit)) => {
                     #[cfg(all(
                         any(target_arch = "x86_64", target_arch = "aarch64"),
                         feature = "gdb"
                     ))]
                     if let Err(e) =
-                        crate::crosvm::gdb::vcpu_exit_debug(cpu_id, to_gdb_tube.as_ref())
+                        crate::crosvm::gdb::vcpu_exit_debug(cpu_id, exit, to_gdb_tube.as_ref())
                     {
                         error!("Failed to handle VcpuExit::Debug: {:#}", e);
                         return ExitState::Crash;
@@ -494,6 +544,9 @@ where
                     let delay_ns: u64 = bus_lock_ratelimit_ctrl.lock().ratelimit_calculate_delay(1);
                     thread::sleep(Duration::from_nanos(delay_ns));
                 }
+                // The hypervisor already harvested the ring, only the pages dirtied since then are
+                // left to log.
+                Ok(VcpuExit::DirtyRingFull) => {}
                 Ok(VcpuExit::Sbi {
                     extension_id: _,
                     function_id: _,
@@ -577,6 +630,7 @@ pub fn run_vcpu<V>(
     #[cfg(all(any(target_arch = "x86", target_arch = "x86_64"), unix))]
     bus_lock_ratelimit_ctrl: Arc<Mutex<Ratelimit>>,
     to_vm_control: mpsc::Sender<VmRunMode>,
+    start_suspended: bool,
 ) -> Result<JoinHandle<()>>
 where
     V: VcpuArch + 'static,
@@ -636,8 +690,13 @@ where
                     }
                 };
 
+                // A VM being restored only runs once all of its state is restored.
                 #[allow(unused_mut)]
-                let mut run_mode = VmRunMode::Running;
+                let mut run_mode = if start_suspended {
+                    VmRunMode::Suspending
+                } else {
+                    VmRunMode::Running
+                };
                 #[cfg(all(any(target_arch = "x86_64", target_arch = "aarch64"), feature = "gdb"))]
                 if to_gdb_tube.is_some() {
                     // Wait until a GDB client attaches
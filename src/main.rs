@@ -7,9 +7,10 @@
 //! ## Feature flags
 #![cfg_attr(feature = "document-features", doc = document_features::document_features!())]
 
-#[cfg(any(feature = "composite-disk", feature = "qcow"))]
 use std::fs::OpenOptions;
 use std::path::Path;
+use std::thread;
+use std::time::Duration;
 
 use anyhow::anyhow;
 use anyhow::Context;
@@ -32,7 +33,7 @@ use devices::virtio::vhost::user::device::run_gpu_device;
 use devices::virtio::vhost::user::device::run_net_device;
 #[cfg(feature = "composite-disk")]
 use disk::create_composite_disk;
-#[cfg(feature = "composite-disk")]
+#[cfg(any(feature = "composite-disk", feature = "qcow"))]
 use disk::create_disk_file;
 #[cfg(feature = "composite-disk")]
 use disk::create_zero_filler;
@@ -54,9 +55,16 @@ use vm_control::client::do_gpu_display_add;
 use vm_control::client::do_gpu_display_list;
 #[cfg(feature = "gpu")]
 use vm_control::client::do_gpu_display_remove;
+#[cfg(feature = "gpu")]
+use vm_control::client::do_gpu_screenshot;
+use vm_control::client::do_input_inject;
 use vm_control::client::do_modify_battery;
 use vm_control::client::do_swap_status;
 use vm_control::client::do_usb_attach;
+use vm_control::client::do_usb_attach_emulated;
+use vm_control::client::do_usb_attach_storage;
+#[cfg(unix)]
+use vm_control::client::do_usb_attach_usbip;
 use vm_control::client::do_usb_detach;
 use vm_control::client::do_usb_list;
 use vm_control::client::handle_request;
@@ -64,17 +72,24 @@ use vm_control::client::vms_request;
 #[cfg(feature = "gpu")]
 use vm_control::client::ModifyGpuResult;
 use vm_control::client::ModifyUsbResult;
+use vm_control::input::key_chord_events;
+use vm_control::input::key_code;
+use vm_control::input::pointer_move_events;
+use vm_control::input::text_events;
+use vm_control::input::touch_frames;
 #[cfg(feature = "balloon")]
 use vm_control::BalloonControlCommand;
 use vm_control::DiskControlCommand;
+use vm_control::EmulatedUsbDevice;
 use vm_control::HotPlugDeviceInfo;
 use vm_control::HotPlugDeviceType;
+use vm_control::MemControlCommand;
+use vm_control::NetControlCommand;
 use vm_control::RestoreCommand;
 use vm_control::SnapshotCommand;
 use vm_control::SwapCommand;
 use vm_control::UsbControlResult;
 use vm_control::VmRequest;
-#[cfg(feature = "balloon")]
 use vm_control::VmResponse;
 
 use crate::sys::error_to_exit_code;
@@ -235,6 +250,24 @@ fn balloon_stats(cmd: cmdline::BalloonStatsCommand) -> std::result::Result<(), (
     }
 }
 
+#[cfg(feature = "balloon")]
+fn balloon_ws(cmd: cmdline::BalloonWsCommand) -> std::result::Result<(), ()> {
+    let command = BalloonControlCommand::WorkingSet {};
+    let request = &VmRequest::BalloonCommand(command);
+    let response = handle_request(request, cmd.socket_path)?;
+    match serde_json::to_string_pretty(&response) {
+        Ok(response_json) => println!("{}", response_json),
+        Err(e) => {
+            error!("Failed to serialize into JSON: {}", e);
+            return Err(());
+        }
+    }
+    match response {
+        VmResponse::BalloonWS { .. } => Ok(()),
+        _ => Err(()),
+    }
+}
+
 fn modify_battery(cmd: cmdline::BatteryCommand) -> std::result::Result<(), ()> {
     do_modify_battery(
         cmd.socket_path,
@@ -386,6 +419,37 @@ fn create_composite(cmd: cmdline::CreateCompositeCommand) -> std::result::Result
     Ok(())
 }
 
+#[cfg(feature = "qcow")]
+fn compress_qcow2(cmd: cmdline::CompressQcow2Command) -> Result<()> {
+    let source_file = OpenOptions::new()
+        .read(true)
+        .open(&cmd.source_path)
+        .with_context(|| format!("failed opening source image at '{}'", cmd.source_path))?;
+    let mut source = create_disk_file(
+        source_file,
+        /* is_sparse_file= */ false,
+        disk::MAX_NESTING_DEPTH,
+        Path::new(&cmd.source_path),
+    )
+    .with_context(|| format!("failed to open source image '{}'", cmd.source_path))?;
+
+    let file = OpenOptions::new()
+        .create(true)
+        .read(true)
+        .write(true)
+        .truncate(true)
+        .open(&cmd.file_path)
+        .with_context(|| format!("failed opening qcow file at '{}'", cmd.file_path))?;
+
+    QcowFile::new_compressed_from(file, source.as_mut(), cmd.compression).with_context(|| {
+        format!(
+            "failed to create compressed qcow file at '{}'",
+            cmd.file_path
+        )
+    })?;
+    Ok(())
+}
+
 #[cfg(feature = "qcow")]
 fn create_qcow2(cmd: cmdline::CreateQcow2Command) -> std::result::Result<(), ()> {
     if !(cmd.size.is_some() ^ cmd.backing_file.is_some()) {
@@ -454,6 +518,155 @@ fn disk_cmd(cmd: cmdline::DiskCommand) -> std::result::Result<(), ()> {
             };
             vms_request(&request, cmd.socket_path)
         }
+        cmdline::DiskSubcommand::Snapshot(cmd) => {
+            let request = VmRequest::DiskCommand {
+                disk_index: cmd.disk_index,
+                command: DiskControlCommand::Snapshot { name: cmd.name },
+            };
+            vms_request(&request, cmd.socket_path)
+        }
+        cmdline::DiskSubcommand::DeleteSnapshot(cmd) => {
+            let request = VmRequest::DiskCommand {
+                disk_index: cmd.disk_index,
+                command: DiskControlCommand::DeleteSnapshot { name: cmd.name },
+            };
+            vms_request(&request, cmd.socket_path)
+        }
+        cmdline::DiskSubcommand::Throttle(cmd) => {
+            let request = VmRequest::DiskCommand {
+                disk_index: cmd.disk_index,
+                command: DiskControlCommand::Throttle { limits: cmd.limits },
+            };
+            vms_request(&request, cmd.socket_path)
+        }
+        cmdline::DiskSubcommand::ExternalSnapshot(cmd) => {
+            // The overlay records the path of the image, which must not depend on the current
+            // directory.
+            let image_path = std::fs::canonicalize(&cmd.image_path)
+                .map_err(|e| {
+                    error!(
+                        "Failed resolving disk image path '{}': {}",
+                        cmd.image_path, e
+                    );
+                })?
+                .into_os_string()
+                .into_string()
+                .map_err(|path| {
+                    error!("Disk image path {:?} is not valid UTF-8", path);
+                })?;
+            // The device checks that this is the image it uses, so that the overlay doesn't record
+            // the path of another image as its backing file.
+            let backing_file = std::fs::File::open(&image_path).map_err(|e| {
+                error!("Failed opening disk image at '{}': {}", image_path, e);
+            })?;
+            // The overlay is created here because the block device may not be able to create
+            // files on the host.
+            let overlay = OpenOptions::new()
+                .create_new(true)
+                .read(true)
+                .write(true)
+                .open(&cmd.overlay_path)
+                .map_err(|e| {
+                    error!("Failed creating overlay at '{}': {}", cmd.overlay_path, e);
+                })?;
+            let request = VmRequest::DiskCommand {
+                disk_index: cmd.disk_index,
+                command: DiskControlCommand::ExternalSnapshot {
+                    overlay,
+                    backing_file,
+                    backing_file_path: image_path,
+                },
+            };
+            match handle_request(&request, &cmd.socket_path) {
+                Ok(VmResponse::Ok) => Ok(()),
+                response => {
+                    if let Ok(response) = response {
+                        error!("Failed to switch disk to the overlay: {}", response);
+                    }
+                    // Don't leave the unused overlay behind.
+                    let _ = std::fs::remove_file(&cmd.overlay_path);
+                    Err(())
+                }
+            }
+        }
+    }
+}
+
+fn net_cmd(cmd: cmdline::NetCommand) -> std::result::Result<(), ()> {
+    match cmd.command {
+        cmdline::NetSubcommand::Link(cmd) => {
+            let request = VmRequest::NetCommand {
+                net_index: cmd.net_index,
+                command: NetControlCommand::SetLinkState { up: cmd.up },
+            };
+            vms_request(&request, cmd.socket_path)
+        }
+    }
+}
+
+fn mem_cmd(cmd: cmdline::MemCommand) -> std::result::Result<(), ()> {
+    match cmd.command {
+        cmdline::MemSubcommand::Resize(cmd) => vms_request(
+            &VmRequest::MemCommand(MemControlCommand::Resize { size: cmd.size }),
+            cmd.socket_path,
+        ),
+        cmdline::MemSubcommand::Stats(cmd) => {
+            let request = &VmRequest::MemCommand(MemControlCommand::Stats);
+            let response = handle_request(request, cmd.socket_path)?;
+            match serde_json::to_string_pretty(&response) {
+                Ok(response_json) => println!("{}", response_json),
+                Err(e) => {
+                    error!("Failed to serialize into JSON: {}", e);
+                    return Err(());
+                }
+            }
+            match response {
+                VmResponse::MemStats(_) => Ok(()),
+                _ => Err(()),
+            }
+        }
+    }
+}
+
+fn input_cmd(cmd: cmdline::InputCommand) -> std::result::Result<(), ()> {
+    match cmd.command {
+        cmdline::InputSubcommand::Click(cmd) => {
+            do_input_inject(cmd.socket_path, cmd.device, key_chord_events(&[cmd.button]))
+        }
+        cmdline::InputSubcommand::Key(cmd) => {
+            let codes = cmd
+                .keys
+                .split('+')
+                .map(|key| key_code(key).ok_or_else(|| error!("unknown key '{}'", key)))
+                .collect::<std::result::Result<Vec<_>, _>>()?;
+            do_input_inject(cmd.socket_path, cmd.device, key_chord_events(&codes))
+        }
+        cmdline::InputSubcommand::Move(cmd) => do_input_inject(
+            cmd.socket_path,
+            cmd.device,
+            pointer_move_events(cmd.dx, cmd.dy),
+        ),
+        cmdline::InputSubcommand::Text(cmd) => {
+            let events = text_events(&cmd.text)
+                .map_err(|c| error!("'{}' can't be typed on a US keyboard", c))?;
+            do_input_inject(cmd.socket_path, cmd.device, events)
+        }
+        cmdline::InputSubcommand::Touch(cmd) => {
+            if cmd.finger.is_empty() {
+                error!("at least one --finger is required");
+                return Err(());
+            }
+            // Each frame is sent separately so that the guest sees the fingers move over time.
+            let frames = touch_frames(&cmd.finger, cmd.steps);
+            let interval = Duration::from_millis(cmd.duration_ms) / (frames.len() as u32 - 1);
+            for (i, frame) in frames.into_iter().enumerate() {
+                if i > 0 {
+                    thread::sleep(interval);
+                }
+                do_input_inject(&cmd.socket_path, cmd.device.clone(), frame)?;
+            }
+            Ok(())
+        }
     }
 }
 
@@ -476,12 +689,18 @@ fn gpu_display_remove(cmd: cmdline::GpuRemoveDisplaysCommand) -> ModifyGpuResult
     do_gpu_display_remove(cmd.socket_path, cmd.display_id)
 }
 
+#[cfg(feature = "gpu")]
+fn gpu_screenshot(cmd: cmdline::GpuScreenshotCommand) -> ModifyGpuResult {
+    do_gpu_screenshot(cmd.socket_path, cmd.display_id, &cmd.path)
+}
+
 #[cfg(feature = "gpu")]
 fn modify_gpu(cmd: cmdline::GpuCommand) -> std::result::Result<(), ()> {
     let result = match cmd.command {
         cmdline::GpuSubCommand::AddDisplays(cmd) => gpu_display_add(cmd),
         cmdline::GpuSubCommand::ListDisplays(cmd) => gpu_display_list(cmd),
         cmdline::GpuSubCommand::RemoveDisplays(cmd) => gpu_display_remove(cmd),
+        cmdline::GpuSubCommand::Screenshot(cmd) => gpu_screenshot(cmd),
     };
     match result {
         Ok(response) => {
@@ -501,6 +720,31 @@ fn usb_attach(cmd: UsbAttachCommand) -> ModifyUsbResult<UsbControlResult> {
     do_usb_attach(cmd.socket_path, dev_path)
 }
 
+fn usb_attach_storage(cmd: cmdline::UsbAttachStorageCommand) -> ModifyUsbResult<UsbControlResult> {
+    do_usb_attach_storage(cmd.socket_path, &cmd.image_path, cmd.read_only)
+}
+
+fn usb_attach_keyboard(
+    cmd: cmdline::UsbAttachKeyboardCommand,
+) -> ModifyUsbResult<UsbControlResult> {
+    do_usb_attach_emulated(cmd.socket_path, EmulatedUsbDevice::Keyboard)
+}
+
+fn usb_attach_tablet(cmd: cmdline::UsbAttachTabletCommand) -> ModifyUsbResult<UsbControlResult> {
+    do_usb_attach_emulated(
+        cmd.socket_path,
+        EmulatedUsbDevice::Tablet {
+            width: cmd.width,
+            height: cmd.height,
+        },
+    )
+}
+
+#[cfg(unix)]
+fn usb_attach_usbip(cmd: cmdline::UsbAttachUsbipCommand) -> ModifyUsbResult<UsbControlResult> {
+    do_usb_attach_usbip(cmd.socket_path, &cmd.server, &cmd.busid)
+}
+
 fn usb_detach(cmd: cmdline::UsbDetachCommand) -> ModifyUsbResult<UsbControlResult> {
     do_usb_detach(cmd.socket_path, cmd.port)
 }
@@ -512,6 +756,11 @@ fn usb_list(cmd: cmdline::UsbListCommand) -> ModifyUsbResult<UsbControlResult> {
 fn modify_usb(cmd: cmdline::UsbCommand) -> std::result::Result<(), ()> {
     let result = match cmd.command {
         cmdline::UsbSubCommand::Attach(cmd) => usb_attach(cmd),
+        cmdline::UsbSubCommand::AttachStorage(cmd) => usb_attach_storage(cmd),
+        cmdline::UsbSubCommand::AttachKeyboard(cmd) => usb_attach_keyboard(cmd),
+        cmdline::UsbSubCommand::AttachTablet(cmd) => usb_attach_tablet(cmd),
+        #[cfg(unix)]
+        cmdline::UsbSubCommand::AttachUsbip(cmd) => usb_attach_usbip(cmd),
         cmdline::UsbSubCommand::Detach(cmd) => usb_detach(cmd),
         cmdline::UsbSubCommand::List(cmd) => usb_list(cmd),
     };
@@ -670,9 +919,17 @@ fn crosvm_main<I: IntoIterator<Item = String>>(args: I) -> Result<CommandStatus>
                     CrossPlatformCommands::BalloonStats(cmd) => {
                         balloon_stats(cmd).map_err(|_| anyhow!("balloon_stats subcommand failed"))
                     }
+                    #[cfg(feature = "balloon")]
+                    CrossPlatformCommands::BalloonWs(cmd) => {
+                        balloon_ws(cmd).map_err(|_| anyhow!("balloon_ws subcommand failed"))
+                    }
                     CrossPlatformCommands::Battery(cmd) => {
                         modify_battery(cmd).map_err(|_| anyhow!("battery subcommand failed"))
                     }
+                    #[cfg(feature = "qcow")]
+                    CrossPlatformCommands::CompressQcow2(cmd) => {
+                        compress_qcow2(cmd).context("compress_qcow2 subcommand failed")
+                    }
                     #[cfg(feature = "composite-disk")]
                     CrossPlatformCommands::CreateComposite(cmd) => create_composite(cmd)
                         .map_err(|_| anyhow!("create_composite subcommand failed")),
@@ -688,9 +945,18 @@ fn crosvm_main<I: IntoIterator<Item = String>>(args: I) -> Result<CommandStatus>
                     CrossPlatformCommands::Gpu(cmd) => {
                         modify_gpu(cmd).map_err(|_| anyhow!("gpu subcommand failed"))
                     }
+                    CrossPlatformCommands::Input(cmd) => {
+                        input_cmd(cmd).map_err(|_| anyhow!("input subcommand failed"))
+                    }
+                    CrossPlatformCommands::Mem(cmd) => {
+                        mem_cmd(cmd).map_err(|_| anyhow!("mem subcommand failed"))
+                    }
                     CrossPlatformCommands::MakeRT(cmd) => {
                         make_rt(cmd).map_err(|_| anyhow!("make_rt subcommand failed"))
                     }
+                    CrossPlatformCommands::Net(cmd) => {
+                        net_cmd(cmd).map_err(|_| anyhow!("net subcommand failed"))
+                    }
                     CrossPlatformCommands::Resume(cmd) => {
                         resume_vms(cmd).map_err(|_| anyhow!("resume subcommand failed"))
                     }
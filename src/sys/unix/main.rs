@@ -18,9 +18,18 @@ use devices::virtio::vhost::user::device::run_fs_device;
 use devices::virtio::vhost::user::device::run_snd_device;
 use devices::virtio::vhost::user::device::run_vsock_device;
 use devices::virtio::vhost::user::device::run_wl_device;
+use vm_control::client::handle_request;
+use vm_control::dirty_log::DirtyLogControlResult;
+use vm_control::MigrateControlResult;
+use vm_control::VmRequest;
+use vm_control::VmResponse;
 
 use crate::crosvm::sys::cmdline::Commands;
 use crate::crosvm::sys::cmdline::DeviceSubcommand;
+use crate::crosvm::sys::cmdline::DirtyLogCommand;
+use crate::crosvm::sys::cmdline::DirtyLogSubCommands;
+use crate::crosvm::sys::cmdline::MigrateCommand;
+use crate::crosvm::sys::cmdline::MigrateSubCommands;
 use crate::crosvm::sys::unix::start_devices;
 use crate::CommandStatus;
 use crate::Config;
@@ -82,9 +91,64 @@ pub fn get_library_watcher() -> std::io::Result<()> {
     Ok(())
 }
 
+fn migrate_vm(cmd: MigrateCommand) -> anyhow::Result<()> {
+    let MigrateSubCommands::Send(cmd) = cmd.migrate_command;
+    // The path is resolved by the VM process, which may run in another directory.
+    let destination = std::env::current_dir()
+        .context("failed to get the current directory")?
+        .join(cmd.destination);
+    let request = VmRequest::Migrate(vm_control::MigrateCommand::Send {
+        socket: destination,
+    });
+    match handle_request(&request, &cmd.socket_path) {
+        Ok(VmResponse::MigrateResponse(MigrateControlResult::Ok)) => Ok(()),
+        Ok(response) => Err(anyhow!("{}", response)),
+        Err(()) => Err(anyhow!("failed to send the migrate request")),
+    }
+}
+
+fn dirty_log(cmd: DirtyLogCommand) -> anyhow::Result<()> {
+    let (command, socket_path) = match cmd.dirty_log_command {
+        DirtyLogSubCommands::Start(cmd) => (
+            vm_control::dirty_log::DirtyLogCommand::Start,
+            cmd.socket_path,
+        ),
+        DirtyLogSubCommands::Stop(cmd) => (
+            vm_control::dirty_log::DirtyLogCommand::Stop,
+            cmd.socket_path,
+        ),
+        DirtyLogSubCommands::Report(cmd) => {
+            // The path is resolved by the VM process, which may run in another directory.
+            let bitmap_path = match cmd.bitmap {
+                Some(path) => Some(
+                    std::env::current_dir()
+                        .context("failed to get the current directory")?
+                        .join(path),
+                ),
+                None => None,
+            };
+            (
+                vm_control::dirty_log::DirtyLogCommand::Report { bitmap_path },
+                cmd.socket_path,
+            )
+        }
+    };
+    match handle_request(&VmRequest::DirtyLog(command), socket_path) {
+        Ok(VmResponse::DirtyLogResponse(DirtyLogControlResult::Ok)) => Ok(()),
+        Ok(response @ VmResponse::DirtyLogResponse(DirtyLogControlResult::Report(_))) => {
+            println!("{}", response);
+            Ok(())
+        }
+        Ok(response) => Err(anyhow!("{}", response)),
+        Err(()) => Err(anyhow!("failed to send the dirty log request")),
+    }
+}
+
 pub(crate) fn run_command(command: Commands) -> anyhow::Result<()> {
     match command {
         Commands::Devices(cmd) => start_devices(cmd).context("start_devices subcommand failed"),
+        Commands::Migrate(cmd) => migrate_vm(cmd).context("migrate subcommand failed"),
+        Commands::DirtyLog(cmd) => dirty_log(cmd).context("dirty-log subcommand failed"),
     }
 }
 
@@ -257,6 +257,8 @@ fn create_block_device(cfg: &Config, disk: &DiskOption, disk_device_tube: Tube)
         None,
         None,
         None,
+        disk.throttle,
+        disk.zoned.clone(),
     )
     .exit_context(Exit::BlockDeviceNew, "failed to create block device")?;
 
@@ -323,6 +325,7 @@ fn create_multi_touch_device(
         event_pipe,
         width,
         height,
+        None,
         virtio::base_features(cfg.protection_type),
     )
     .exit_context(Exit::InputDeviceNew, "failed to set up input device")?;
@@ -334,8 +337,13 @@ fn create_multi_touch_device(
 
 #[cfg(feature = "gpu")]
 fn create_mouse_device(cfg: &Config, event_pipe: StreamChannel, idx: u32) -> DeviceResult {
-    let dev = virtio::new_mouse(idx, event_pipe, virtio::base_features(cfg.protection_type))
-        .exit_context(Exit::InputDeviceNew, "failed to set up input device")?;
+    let dev = virtio::new_mouse(
+        idx,
+        event_pipe,
+        None,
+        virtio::base_features(cfg.protection_type),
+    )
+    .exit_context(Exit::InputDeviceNew, "failed to set up input device")?;
     Ok(VirtioDeviceStub {
         dev: Box::new(dev),
         jail: None,
@@ -388,8 +396,10 @@ fn create_balloon_device(
     inflate_tube: Option<Tube>,
     init_balloon_size: u64,
 ) -> DeviceResult {
-    let balloon_features =
-        (cfg.balloon_page_reporting as u64) << BalloonFeatures::PageReporting as u64;
+    let balloon_features = (cfg.balloon_page_reporting as u64)
+        << BalloonFeatures::PageReporting as u64
+        | (cfg.balloon_free_page_hint as u64) << BalloonFeatures::FreePageHint as u64
+        | (cfg.balloon_ws_num_bins.is_some() as u64) << BalloonFeatures::WSReporting as u64;
     let dev = virtio::Balloon::new(
         virtio::base_features(cfg.protection_type),
         balloon_device_tube,
@@ -402,6 +412,7 @@ fn create_balloon_device(
             BalloonMode::Relaxed
         },
         balloon_features,
+        cfg.balloon_ws_num_bins.unwrap_or(0),
     )
     .exit_context(Exit::BalloonDeviceNew, "failed to create balloon")?;
 
@@ -564,6 +575,7 @@ fn create_virtio_gpu_and_input_devices(
     let dev = virtio::new_keyboard(
         /* idx= */ 0,
         keyboard_pipe,
+        None,
         virtio::base_features(cfg.protection_type),
     )
     .exit_context(Exit::InputDeviceNew, "failed to set up input device")?;
@@ -1395,6 +1407,7 @@ fn setup_vm_components(cfg: &Config) -> Result<VmComponents> {
         no_i8042: cfg.no_i8042,
         no_rtc: cfg.no_rtc,
         host_cpu_topology: cfg.host_cpu_topology,
+        hotplug_memory_size: 0,
         #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
         force_s2idle: cfg.force_s2idle,
         itmt: false,
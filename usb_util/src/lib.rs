@@ -27,6 +27,7 @@ pub use self::types::ConfigDescriptor;
 pub use self::types::ControlRequestDataPhaseTransferDirection;
 pub use self::types::ControlRequestRecipient;
 pub use self::types::ControlRequestType;
+pub use self::types::Descriptor;
 pub use self::types::DescriptorHeader;
 pub use self::types::DescriptorType;
 pub use self::types::DeviceDescriptor;
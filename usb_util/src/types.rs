@@ -12,6 +12,7 @@ use zerocopy::FromBytes;
 pub enum DescriptorType {
     Device = 0x01,
     Configuration = 0x02,
+    String = 0x03,
     Interface = 0x04,
     Endpoint = 0x05,
 }
@@ -2,16 +2,28 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the LICENSE file.
 
+#[cfg(unix)]
+use std::fs::File;
 use std::fs::OpenOptions;
+#[cfg(unix)]
+use std::net::TcpStream;
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
 use std::path::Path;
 use std::path::PathBuf;
 
 use base::open_file;
+#[cfg(unix)]
+use base::AsRawDescriptor;
+#[cfg(unix)]
+use base::SafeDescriptor;
 use remain::sorted;
 use thiserror::Error;
 
 #[cfg(feature = "gpu")]
 pub use crate::gpu::*;
+use crate::input::InputControlCommand;
+use crate::input::InputEvent;
 pub use crate::sys::handle_request;
 pub use crate::*;
 
@@ -25,6 +37,8 @@ enum ModifyBatError {
 #[sorted]
 #[derive(Error, Debug)]
 pub enum ModifyUsbError {
+    #[error("failed to connect to {0}: {1}")]
+    FailedToConnect(String, std::io::Error),
     #[error("failed to open device {0}: {1}")]
     FailedToOpenDevice(PathBuf, base::Error),
     #[error("socket failed")]
@@ -47,6 +61,24 @@ pub fn vms_request<T: AsRef<Path> + std::fmt::Debug>(
     Ok(())
 }
 
+pub fn do_input_inject<T: AsRef<Path> + std::fmt::Debug>(
+    socket_path: T,
+    device: String,
+    events: Vec<InputEvent>,
+) -> VmsRequestResult {
+    let request = VmRequest::InputCommand {
+        device,
+        command: InputControlCommand::InjectEvents { events },
+    };
+    match handle_request(&request, socket_path)? {
+        VmResponse::Ok => Ok(()),
+        response => {
+            println!("error {}", response);
+            Err(())
+        }
+    }
+}
+
 pub fn do_usb_attach<T: AsRef<Path> + std::fmt::Debug>(
     socket_path: T,
     dev_path: &Path,
@@ -63,6 +95,77 @@ pub fn do_usb_attach<T: AsRef<Path> + std::fmt::Debug>(
     }
 }
 
+pub fn do_usb_attach_emulated<T: AsRef<Path> + std::fmt::Debug>(
+    socket_path: T,
+    device: EmulatedUsbDevice,
+) -> ModifyUsbResult<UsbControlResult> {
+    let request = VmRequest::UsbCommand(UsbControlCommand::AttachEmulatedDevice { device });
+    let response =
+        handle_request(&request, socket_path).map_err(|_| ModifyUsbError::SocketFailed)?;
+    match response {
+        VmResponse::UsbResponse(usb_resp) => Ok(usb_resp),
+        r => Err(ModifyUsbError::UnexpectedResponse(r)),
+    }
+}
+
+pub fn do_usb_attach_storage<T: AsRef<Path> + std::fmt::Debug>(
+    socket_path: T,
+    image_path: &Path,
+    read_only: bool,
+) -> ModifyUsbResult<UsbControlResult> {
+    let file = open_file(image_path, OpenOptions::new().read(true).write(!read_only))
+        .map_err(|e| ModifyUsbError::FailedToOpenDevice(image_path.into(), e))?;
+    do_usb_attach_emulated(
+        socket_path,
+        EmulatedUsbDevice::MassStorage { file, read_only },
+    )
+}
+
+/// Port of the USB/IP servers.
+#[cfg(unix)]
+const USBIP_PORT: u16 = 3240;
+
+/// Connects to the USB/IP server `server`, either `HOST[:PORT]` or the path of a Unix socket, and
+/// attaches its device `busid`.
+#[cfg(unix)]
+pub fn do_usb_attach_usbip<T: AsRef<Path> + std::fmt::Debug>(
+    socket_path: T,
+    server: &str,
+    busid: &str,
+) -> ModifyUsbResult<UsbControlResult> {
+    let stream: Box<dyn AsRawDescriptor> = if server.contains('/') {
+        Box::new(
+            UnixStream::connect(server)
+                .map_err(|e| ModifyUsbError::FailedToConnect(server.to_owned(), e))?,
+        )
+    } else {
+        let has_port =
+            matches!(server.rsplit_once(':'), Some((_, port)) if port.parse::<u16>().is_ok());
+        let address = if has_port {
+            server.to_owned()
+        } else {
+            format!("{}:{}", server, USBIP_PORT)
+        };
+        Box::new(
+            TcpStream::connect(&address)
+                .map_err(|e| ModifyUsbError::FailedToConnect(address.clone(), e))?,
+        )
+    };
+    let socket = SafeDescriptor::try_from(&*stream)
+        .map_err(|e| ModifyUsbError::FailedToConnect(server.to_owned(), e))?;
+
+    let request = VmRequest::UsbCommand(UsbControlCommand::AttachUsbipDevice {
+        socket: File::from(socket),
+        busid: busid.to_owned(),
+    });
+    let response =
+        handle_request(&request, socket_path).map_err(|_| ModifyUsbError::SocketFailed)?;
+    match response {
+        VmResponse::UsbResponse(usb_resp) => Ok(usb_resp),
+        r => Err(ModifyUsbError::UnexpectedResponse(r)),
+    }
+}
+
 pub fn do_usb_detach<T: AsRef<Path> + std::fmt::Debug>(
     socket_path: T,
     port: u8,
@@ -0,0 +1,191 @@
+// Copyright 2023 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Guest-wide tracking of the pages written by the guest, built on the dirty log of the hypervisor.
+//!
+//! The tracker is meant to measure how fast the guest dirties its memory, for example to size live
+//! migrations, and to build incremental memory snapshots from the bitmaps of the dirty pages.
+
+use std::fs::File;
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+use std::time::Instant;
+
+use anyhow::bail;
+use anyhow::Context;
+use base::pagesize;
+use hypervisor::MemSlot;
+use hypervisor::Vm;
+use serde::Deserialize;
+use serde::Serialize;
+
+use crate::VmResponse;
+
+/// Number of entries of the dirty ring of each vcpu, when the hypervisor supports dirty rings.
+pub const DIRTY_RING_ENTRIES: u32 = 4096;
+
+/// Commands for the dirty memory tracker.
+#[derive(Serialize, Deserialize, Debug)]
+pub enum DirtyLogCommand {
+    /// Starts logging the pages written by the guest.
+    Start,
+    /// Stops logging the pages written by the guest.
+    Stop,
+    /// Reports the pages written since logging started or since the previous report. The bitmaps
+    /// of the regions are written one after the other to `bitmap_path` if given, with bit `n % 8`
+    /// of byte `n / 8` set when page `n` of the region was written.
+    Report { bitmap_path: Option<PathBuf> },
+}
+
+/// Pages of a guest memory region written during a [DirtyLogReport] interval.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DirtyRegion {
+    pub guest_base: u64,
+    pub size: u64,
+    pub dirty_pages: u64,
+    /// Offset of the bitmap of the region in the bitmap file.
+    pub bitmap_offset: u64,
+}
+
+/// Pages written by the guest during an interval.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DirtyLogReport {
+    pub page_size: u64,
+    /// Duration of the interval in milliseconds.
+    pub interval_ms: u64,
+    pub regions: Vec<DirtyRegion>,
+}
+
+impl DirtyLogReport {
+    /// Returns the number of pages written during the interval.
+    pub fn dirty_pages(&self) -> u64 {
+        self.regions.iter().map(|r| r.dirty_pages).sum()
+    }
+
+    /// Returns the rate at which the guest dirtied its memory during the interval, in pages per
+    /// second. Pages written several times only count once.
+    pub fn dirty_pages_per_second(&self) -> f64 {
+        if self.interval_ms == 0 {
+            return 0.0;
+        }
+        self.dirty_pages() as f64 * 1000.0 / self.interval_ms as f64
+    }
+}
+
+/// Response for [DirtyLogCommand].
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum DirtyLogControlResult {
+    /// The command succeeded.
+    Ok,
+    /// Result of `DirtyLogCommand::Report`.
+    Report(DirtyLogReport),
+    /// The command fails.
+    Failed(String),
+}
+
+/// Switches the dirty log of the guest memory on and off, and reports the dirty pages.
+#[derive(Default)]
+pub struct DirtyMemoryTracker {
+    /// Start of the current interval, while logging.
+    since: Option<Instant>,
+}
+
+impl DirtyMemoryTracker {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Returns true while the tracker owns the dirty log of `vm`, which other users such as live
+    /// migration must then leave alone.
+    pub fn is_logging(&self) -> bool {
+        self.since.is_some()
+    }
+
+    pub fn handle_command(&mut self, vm: &mut dyn Vm, command: &DirtyLogCommand) -> VmResponse {
+        let result = match command {
+            DirtyLogCommand::Start => self.start(vm).map(|_| DirtyLogControlResult::Ok),
+            DirtyLogCommand::Stop => self.stop(vm).map(|_| DirtyLogControlResult::Ok),
+            DirtyLogCommand::Report { bitmap_path } => self
+                .report(vm, bitmap_path.as_deref())
+                .map(DirtyLogControlResult::Report),
+        };
+        VmResponse::DirtyLogResponse(
+            result.unwrap_or_else(|e| DirtyLogControlResult::Failed(format!("{:#}", e))),
+        )
+    }
+
+    /// Starts logging the pages written to every guest memory region of `vm`.
+    pub fn start(&mut self, vm: &mut dyn Vm) -> anyhow::Result<()> {
+        if self.is_logging() {
+            bail!("dirty memory logging is already started");
+        }
+        vm.set_memory_dirty_log(true)
+            .context("failed to enable the dirty log")?;
+        self.since = Some(Instant::now());
+        Ok(())
+    }
+
+    pub fn stop(&mut self, vm: &mut dyn Vm) -> anyhow::Result<()> {
+        if !self.is_logging() {
+            bail!("dirty memory logging is not started");
+        }
+        vm.set_memory_dirty_log(false)
+            .context("failed to disable the dirty log")?;
+        self.since = None;
+        Ok(())
+    }
+
+    /// Collects the pages written since the previous report, and writes their bitmaps to
+    /// `bitmap_path` if given.
+    pub fn report(
+        &mut self,
+        vm: &dyn Vm,
+        bitmap_path: Option<&Path>,
+    ) -> anyhow::Result<DirtyLogReport> {
+        let since = match self.since {
+            Some(since) => since,
+            None => bail!("dirty memory logging is not started"),
+        };
+        let mut bitmap_file = bitmap_path
+            .map(|path| {
+                File::create(path).with_context(|| format!("failed to create {}", path.display()))
+            })
+            .transpose()?;
+
+        let page_size = pagesize() as u64;
+        let now = Instant::now();
+        let mut regions = Vec::new();
+        let mut bitmap_offset = 0;
+        for (slot, (guest_base, size)) in vm
+            .get_memory()
+            .guest_memory_regions()
+            .into_iter()
+            .enumerate()
+        {
+            let pages = (size as u64 + page_size - 1) / page_size;
+            let mut bitmap = vec![0u8; ((pages + 7) / 8) as usize];
+            vm.get_dirty_log(slot as MemSlot, &mut bitmap)
+                .with_context(|| format!("failed to get the dirty log of slot {}", slot))?;
+            if let Some(file) = &mut bitmap_file {
+                file.write_all(&bitmap)
+                    .context("failed to write the dirty bitmap")?;
+            }
+            regions.push(DirtyRegion {
+                guest_base: guest_base.offset(),
+                size: size as u64,
+                dirty_pages: bitmap.iter().map(|b| b.count_ones() as u64).sum(),
+                bitmap_offset,
+            });
+            bitmap_offset += bitmap.len() as u64;
+        }
+        self.since = Some(now);
+
+        Ok(DirtyLogReport {
+            page_size,
+            interval_ms: now.duration_since(since).as_millis() as u64,
+            regions,
+        })
+    }
+}
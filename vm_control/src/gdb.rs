@@ -7,6 +7,8 @@ use gdbstub::arch::Arch;
 use gdbstub_arch::aarch64::AArch64 as GdbArch;
 #[cfg(target_arch = "x86_64")]
 use gdbstub_arch::x86::X86_64_SSE as GdbArch;
+use hypervisor::DebugExit;
+use hypervisor::HwWatchpoint;
 use vm_memory::GuestAddress;
 
 /// Messages that can be sent to a vCPU to set/get its state from the debugger.
@@ -18,9 +20,14 @@ pub enum VcpuDebug {
     WriteRegs(Box<<GdbArch as Arch>::Registers>),
     WriteReg(<GdbArch as Arch>::RegId, Vec<u8>),
     WriteMem(GuestAddress, Vec<u8>),
-    EnableSinglestep,
     GetHwBreakPointCount,
-    SetHwBreakPoint(Vec<GuestAddress>),
+    GetHwWatchPointCount,
+    /// Sets the hardware breakpoints and watchpoints, and whether the next runs single-step.
+    SetGuestDebug {
+        breakpoints: Vec<GuestAddress>,
+        watchpoints: Vec<HwWatchpoint>,
+        single_step: bool,
+    },
 }
 
 /// Messages that can be sent from a vCPU to update the state to the debugger.
@@ -32,7 +39,10 @@ pub enum VcpuDebugStatus {
     MemoryRegion(Vec<u8>),
     CommandComplete,
     HwBreakPointCount(usize),
-    HitBreakPoint,
+    HwWatchPointCount(usize),
+    HitBreakPoint(DebugExit),
+    /// The vCPU failed to handle the `VcpuDebug` request.
+    CommandFailed,
 }
 
 /// Pair of a vCPU ID and messages that can be sent from the vCPU to update the state to the
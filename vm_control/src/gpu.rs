@@ -5,8 +5,12 @@
 use std::collections::BTreeMap as Map;
 use std::fmt;
 use std::fmt::Display;
+use std::fs::OpenOptions;
+use std::io;
 use std::path::Path;
+use std::path::PathBuf;
 
+use base::SafeDescriptor;
 use serde::Deserialize;
 use serde::Serialize;
 use serde_keyvalue::FromKeyValues;
@@ -111,6 +115,7 @@ pub enum GpuControlCommand {
     AddDisplays { displays: Vec<DisplayParameters> },
     ListDisplays,
     RemoveDisplays { display_ids: Vec<u32> },
+    Screenshot { display_id: u32, fd: SafeDescriptor },
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -123,6 +128,14 @@ pub enum GpuControlResult {
     NoSuchDisplay {
         display_id: u32,
     },
+    NoScanoutResource {
+        display_id: u32,
+    },
+    ScreenshotFailed(String),
+    ScreenshotSaved {
+        width: u32,
+        height: u32,
+    },
 }
 
 impl Display for GpuControlResult {
@@ -141,11 +154,18 @@ impl Display for GpuControlResult {
             }
             TooManyDisplays(n) => write!(f, "too_many_displays {}", n),
             NoSuchDisplay { display_id } => write!(f, "no_such_display {}", display_id),
+            NoScanoutResource { display_id } => {
+                write!(f, "no_scanout_resource {}", display_id)
+            }
+            ScreenshotFailed(e) => write!(f, "screenshot_failed {}", e),
+            ScreenshotSaved { width, height } => write!(f, "screenshot_saved {}x{}", width, height),
         }
     }
 }
 
 pub enum ModifyGpuError {
+    FailedToMoveFile(PathBuf, io::Error),
+    FailedToOpenFile(PathBuf, io::Error),
     SocketFailed,
     UnexpectedResponse(VmResponse),
     UnknownCommand(String),
@@ -157,6 +177,8 @@ impl fmt::Display for ModifyGpuError {
         use self::ModifyGpuError::*;
 
         match self {
+            FailedToMoveFile(path, e) => write!(f, "failed to move to {}: {}", path.display(), e),
+            FailedToOpenFile(path, e) => write!(f, "failed to open {}: {}", path.display(), e),
             SocketFailed => write!(f, "socket failed"),
             UnexpectedResponse(r) => write!(f, "unexpected response: {}", r),
             UnknownCommand(c) => write!(f, "unknown display command: `{}`", c),
@@ -204,3 +226,40 @@ pub fn do_gpu_display_remove<T: AsRef<Path> + std::fmt::Debug>(
         .map_err(|_| ModifyGpuError::SocketFailed)?
         .into()
 }
+
+/// Writes the current contents of a display to `path` as a PNG image. The image is written to a
+/// temporary file that is only moved to `path` once complete.
+pub fn do_gpu_screenshot<T: AsRef<Path> + std::fmt::Debug>(
+    control_socket_path: T,
+    display_id: u32,
+    path: &Path,
+) -> ModifyGpuResult {
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+    let tmp_path = PathBuf::from(tmp_path);
+    // The file is opened here as the GPU device may not have access to `path`.
+    let file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(&tmp_path)
+        .map_err(|e| ModifyGpuError::FailedToOpenFile(tmp_path.clone(), e))?;
+
+    let request = VmRequest::GpuCommand(GpuControlCommand::Screenshot {
+        display_id,
+        fd: file.into(),
+    });
+    let result = handle_request(&request, control_socket_path)
+        .map_err(|_| ModifyGpuError::SocketFailed)
+        .and_then(ModifyGpuResult::from);
+    let result = match result {
+        Ok(saved @ GpuControlResult::ScreenshotSaved { .. }) => std::fs::rename(&tmp_path, path)
+            .map(|_| saved)
+            .map_err(|e| ModifyGpuError::FailedToMoveFile(path.into(), e)),
+        result => result,
+    };
+    if !matches!(result, Ok(GpuControlResult::ScreenshotSaved { .. })) {
+        let _ = std::fs::remove_file(&tmp_path);
+    }
+    result
+}
@@ -0,0 +1,403 @@
+// Copyright 2023 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Control messages of the virtio input devices, and helpers to build the events for common
+//! interactions with them.
+
+use std::fmt;
+use std::fmt::Display;
+
+use base::Error as SysError;
+use linux_input_sys::virtio_input_event;
+use linux_input_sys::ABS_MT_POSITION_X;
+use linux_input_sys::ABS_MT_POSITION_Y;
+use linux_input_sys::ABS_MT_SLOT;
+use linux_input_sys::ABS_MT_TRACKING_ID;
+use linux_input_sys::ABS_X;
+use linux_input_sys::ABS_Y;
+use linux_input_sys::BTN_TOUCH;
+use linux_input_sys::EV_ABS;
+use linux_input_sys::EV_KEY;
+use linux_input_sys::EV_REL;
+use linux_input_sys::EV_SYN;
+use linux_input_sys::REL_X;
+use linux_input_sys::REL_Y;
+use linux_input_sys::SYN_REPORT;
+use serde::Deserialize;
+use serde::Serialize;
+
+pub const BTN_LEFT: u16 = 0x110;
+pub const BTN_RIGHT: u16 = 0x111;
+pub const BTN_MIDDLE: u16 = 0x112;
+const KEY_LEFTSHIFT: u16 = 42;
+
+/// Names of the keys that don't produce a character, after the `KEY_` and `BTN_` constants of
+/// `linux/input-event-codes.h`.
+const KEY_NAMES: &[(&str, u16)] = &[
+    ("esc", 1),
+    ("backspace", 14),
+    ("tab", 15),
+    ("enter", 28),
+    ("leftctrl", 29),
+    ("leftshift", KEY_LEFTSHIFT),
+    ("rightshift", 54),
+    ("leftalt", 56),
+    ("space", 57),
+    ("capslock", 58),
+    ("f1", 59),
+    ("f2", 60),
+    ("f3", 61),
+    ("f4", 62),
+    ("f5", 63),
+    ("f6", 64),
+    ("f7", 65),
+    ("f8", 66),
+    ("f9", 67),
+    ("f10", 68),
+    ("f11", 87),
+    ("f12", 88),
+    ("rightctrl", 97),
+    ("rightalt", 100),
+    ("home", 102),
+    ("up", 103),
+    ("pageup", 104),
+    ("left", 105),
+    ("right", 106),
+    ("end", 107),
+    ("down", 108),
+    ("pagedown", 109),
+    ("insert", 110),
+    ("delete", 111),
+    ("mute", 113),
+    ("volumedown", 114),
+    ("volumeup", 115),
+    ("power", 116),
+    ("leftmeta", 125),
+    ("rightmeta", 126),
+    ("menu", 139),
+    ("back", 158),
+    ("homepage", 172),
+    ("btn_left", BTN_LEFT),
+    ("btn_right", BTN_RIGHT),
+    ("btn_middle", BTN_MIDDLE),
+];
+
+/// Rows of a US keyboard layout, without and with shift, and the key codes of their keys.
+const US_LAYOUT: &[(&str, &str, &[u16])] = &[
+    (
+        "`1234567890-=",
+        "~!@#$%^&*()_+",
+        &[41, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13],
+    ),
+    (
+        "qwertyuiop[]\\",
+        "QWERTYUIOP{}|",
+        &[16, 17, 18, 19, 20, 21, 22, 23, 24, 25, 26, 27, 43],
+    ),
+    (
+        "asdfghjkl;'",
+        "ASDFGHJKL:\"",
+        &[30, 31, 32, 33, 34, 35, 36, 37, 38, 39, 40],
+    ),
+    (
+        "zxcvbnm,./",
+        "ZXCVBNM<>?",
+        &[44, 45, 46, 47, 48, 49, 50, 51, 52, 53],
+    ),
+];
+
+/// Start and end positions of a finger on a touch device.
+pub type FingerPath = ((i32, i32), (i32, i32));
+
+/// An input event, as found in the `virtio_input_event` and `input_event` structures.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InputEvent {
+    pub type_: u16,
+    pub code: u16,
+    pub value: i32,
+}
+
+impl InputEvent {
+    pub fn syn() -> InputEvent {
+        InputEvent {
+            type_: EV_SYN,
+            code: SYN_REPORT,
+            value: 0,
+        }
+    }
+
+    pub fn key(code: u16, pressed: bool) -> InputEvent {
+        InputEvent {
+            type_: EV_KEY,
+            code,
+            value: pressed.into(),
+        }
+    }
+
+    pub fn absolute(code: u16, value: i32) -> InputEvent {
+        InputEvent {
+            type_: EV_ABS,
+            code,
+            value,
+        }
+    }
+
+    pub fn relative(code: u16, value: i32) -> InputEvent {
+        InputEvent {
+            type_: EV_REL,
+            code,
+            value,
+        }
+    }
+}
+
+impl From<InputEvent> for virtio_input_event {
+    fn from(event: InputEvent) -> Self {
+        virtio_input_event {
+            type_: event.type_.into(),
+            code: event.code.into(),
+            value: event.value.into(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub enum InputControlCommand {
+    /// Send `events` to the guest as if they had been read from the source of the input device.
+    InjectEvents { events: Vec<InputEvent> },
+}
+
+impl Display for InputControlCommand {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::InputControlCommand::*;
+
+        match self {
+            InjectEvents { events } => write!(f, "input_inject {} events", events.len()),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum InputControlResult {
+    Ok,
+    Err(SysError),
+}
+
+/// Returns the code of the key named `name`, either its number, a name from
+/// `linux/input-event-codes.h` in lower case without the `KEY_` prefix, or the character the key
+/// produces on a US keyboard.
+pub fn key_code(name: &str) -> Option<u16> {
+    if let Ok(code) = name.parse() {
+        return Some(code);
+    }
+    let name = name.to_lowercase();
+    if let Some((_, code)) = KEY_NAMES.iter().find(|(key_name, _)| *key_name == name) {
+        return Some(*code);
+    }
+    let mut chars = name.chars();
+    match (chars.next(), chars.next()) {
+        (Some(c), None) => char_key(c).map(|(code, _shift)| code),
+        _ => None,
+    }
+}
+
+/// Returns the code of the key producing `c` on a US keyboard, and whether shift must be held.
+fn char_key(c: char) -> Option<(u16, bool)> {
+    match c {
+        ' ' => return Some((57, false)),
+        '\t' => return Some((15, false)),
+        '\n' => return Some((28, false)),
+        _ => {}
+    }
+    US_LAYOUT.iter().find_map(|(plain, shifted, codes)| {
+        if let Some(i) = plain.chars().position(|p| p == c) {
+            Some((codes[i], false))
+        } else {
+            shifted
+                .chars()
+                .position(|s| s == c)
+                .map(|i| (codes[i], true))
+        }
+    })
+}
+
+/// Returns the events pressing the keys of `codes` in order, then releasing them in reverse
+/// order, e.g. to send a shortcut.
+pub fn key_chord_events(codes: &[u16]) -> Vec<InputEvent> {
+    let mut events = Vec::with_capacity(codes.len() * 4);
+    for code in codes {
+        events.push(InputEvent::key(*code, true));
+        events.push(InputEvent::syn());
+    }
+    for code in codes.iter().rev() {
+        events.push(InputEvent::key(*code, false));
+        events.push(InputEvent::syn());
+    }
+    events
+}
+
+/// Returns the events typing `text` on a US keyboard, or the first character that can't be
+/// typed.
+pub fn text_events(text: &str) -> std::result::Result<Vec<InputEvent>, char> {
+    let mut events = Vec::new();
+    for c in text.chars() {
+        let (code, shift) = char_key(c).ok_or(c)?;
+        if shift {
+            events.extend(key_chord_events(&[KEY_LEFTSHIFT, code]));
+        } else {
+            events.extend(key_chord_events(&[code]));
+        }
+    }
+    Ok(events)
+}
+
+/// Returns the events moving a relative pointing device by `dx`, `dy`.
+pub fn pointer_move_events(dx: i32, dy: i32) -> Vec<InputEvent> {
+    vec![
+        InputEvent::relative(REL_X, dx),
+        InputEvent::relative(REL_Y, dy),
+        InputEvent::syn(),
+    ]
+}
+
+/// Returns the frames of events of touches by one or more fingers, each moving in a straight line
+/// from its first to its second position in `steps` frames. The first frame puts the fingers
+/// down and the last one lifts them.
+///
+/// Both the multi-touch events and the single touch ones of the first finger are sent, the guest
+/// ignores those not supported by the device.
+pub fn touch_frames(fingers: &[FingerPath], steps: u32) -> Vec<Vec<InputEvent>> {
+    let position = |from: i32, to: i32, step: u32| -> i32 {
+        if steps == 0 {
+            return to;
+        }
+        (from as i64 + (to as i64 - from as i64) * step as i64 / steps as i64) as i32
+    };
+
+    let mut frames = Vec::with_capacity(steps as usize + 2);
+    for step in 0..=steps {
+        let mut frame = Vec::new();
+        for (slot, ((x0, y0), (x1, y1))) in fingers.iter().enumerate() {
+            let (x, y) = if step == 0 {
+                (*x0, *y0)
+            } else {
+                (position(*x0, *x1, step), position(*y0, *y1, step))
+            };
+            frame.push(InputEvent::absolute(ABS_MT_SLOT, slot as i32));
+            if step == 0 {
+                frame.push(InputEvent::absolute(ABS_MT_TRACKING_ID, slot as i32));
+            }
+            frame.push(InputEvent::absolute(ABS_MT_POSITION_X, x));
+            frame.push(InputEvent::absolute(ABS_MT_POSITION_Y, y));
+            if slot == 0 {
+                frame.push(InputEvent::absolute(ABS_X, x));
+                frame.push(InputEvent::absolute(ABS_Y, y));
+            }
+        }
+        if step == 0 {
+            frame.push(InputEvent::key(BTN_TOUCH, true));
+        }
+        frame.push(InputEvent::syn());
+        frames.push(frame);
+    }
+
+    let mut frame = Vec::new();
+    for slot in 0..fingers.len() {
+        frame.push(InputEvent::absolute(ABS_MT_SLOT, slot as i32));
+        frame.push(InputEvent::absolute(ABS_MT_TRACKING_ID, -1));
+    }
+    frame.push(InputEvent::key(BTN_TOUCH, false));
+    frame.push(InputEvent::syn());
+    frames.push(frame);
+    frames
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn key_codes() {
+        assert_eq!(key_code("enter"), Some(28));
+        assert_eq!(key_code("LeftCtrl"), Some(29));
+        assert_eq!(key_code("a"), Some(30));
+        assert_eq!(key_code("/"), Some(53));
+        assert_eq!(key_code("?"), Some(53));
+        assert_eq!(key_code("30"), Some(30));
+        assert_eq!(key_code("btn_left"), Some(BTN_LEFT));
+        assert_eq!(key_code("notakey"), None);
+        assert_eq!(key_code("é"), None);
+    }
+
+    #[test]
+    fn text() {
+        assert_eq!(
+            text_events("a!").unwrap(),
+            vec![
+                InputEvent::key(30, true),
+                InputEvent::syn(),
+                InputEvent::key(30, false),
+                InputEvent::syn(),
+                InputEvent::key(KEY_LEFTSHIFT, true),
+                InputEvent::syn(),
+                InputEvent::key(2, true),
+                InputEvent::syn(),
+                InputEvent::key(2, false),
+                InputEvent::syn(),
+                InputEvent::key(KEY_LEFTSHIFT, false),
+                InputEvent::syn(),
+            ]
+        );
+        assert_eq!(text_events("ok€"), Err('€'));
+    }
+
+    #[test]
+    fn swipe() {
+        let frames = touch_frames(&[((0, 100), (100, 0))], 2);
+        assert_eq!(frames.len(), 4);
+        assert_eq!(
+            frames[0],
+            vec![
+                InputEvent::absolute(ABS_MT_SLOT, 0),
+                InputEvent::absolute(ABS_MT_TRACKING_ID, 0),
+                InputEvent::absolute(ABS_MT_POSITION_X, 0),
+                InputEvent::absolute(ABS_MT_POSITION_Y, 100),
+                InputEvent::absolute(ABS_X, 0),
+                InputEvent::absolute(ABS_Y, 100),
+                InputEvent::key(BTN_TOUCH, true),
+                InputEvent::syn(),
+            ]
+        );
+        assert_eq!(
+            frames[1],
+            vec![
+                InputEvent::absolute(ABS_MT_SLOT, 0),
+                InputEvent::absolute(ABS_MT_POSITION_X, 50),
+                InputEvent::absolute(ABS_MT_POSITION_Y, 50),
+                InputEvent::absolute(ABS_X, 50),
+                InputEvent::absolute(ABS_Y, 50),
+                InputEvent::syn(),
+            ]
+        );
+        assert_eq!(
+            frames[3],
+            vec![
+                InputEvent::absolute(ABS_MT_SLOT, 0),
+                InputEvent::absolute(ABS_MT_TRACKING_ID, -1),
+                InputEvent::key(BTN_TOUCH, false),
+                InputEvent::syn(),
+            ]
+        );
+
+        // A tap puts the fingers down and lifts them.
+        let frames = touch_frames(&[((10, 20), (10, 20)), ((30, 40), (30, 40))], 0);
+        assert_eq!(frames.len(), 2);
+        assert!(frames[0].contains(&InputEvent::absolute(ABS_MT_TRACKING_ID, 1)));
+        assert!(frames[0].contains(&InputEvent::absolute(ABS_MT_POSITION_X, 30)));
+
+        // Positions far apart don't overflow.
+        let frames = touch_frames(&[((i32::MIN, 0), (i32::MAX, 0))], 2);
+        assert!(frames[1].contains(&InputEvent::absolute(ABS_MT_POSITION_X, -1)));
+    }
+}
@@ -21,26 +21,42 @@ use base::MemoryMappingBuilderUnix;
 use base::MemoryMappingBuilderWindows;
 
 pub mod client;
+pub mod dirty_log;
 pub mod display;
+pub mod input;
+#[cfg(unix)]
+pub mod migration;
+pub mod snapshot;
 pub mod sys;
 
+use std::collections::BTreeMap;
 use std::collections::BTreeSet;
 use std::convert::TryInto;
 use std::fmt;
 use std::fmt::Display;
 use std::fs::File;
+#[cfg(unix)]
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
 use std::path::PathBuf;
 use std::result::Result as StdResult;
 use std::str::FromStr;
 use std::sync::mpsc;
 use std::sync::Arc;
 
+use anyhow::Context;
 pub use balloon_control::BalloonStats;
 #[cfg(feature = "balloon")]
 use balloon_control::BalloonTubeCommand;
 #[cfg(feature = "balloon")]
 use balloon_control::BalloonTubeResult;
+pub use balloon_control::BalloonWS;
+pub use balloon_control::WSBucket;
+pub use balloon_control::VIRTIO_BALLOON_WS_MAX_NUM_BINS;
+pub use balloon_control::VIRTIO_BALLOON_WS_MIN_NUM_BINS;
 use base::error;
+#[cfg(unix)]
+use base::info;
 use base::with_as_descriptor;
 use base::AsRawDescriptor;
 use base::Error as SysError;
@@ -54,7 +70,6 @@ use base::Result;
 use base::SafeDescriptor;
 use base::SharedMemory;
 use base::Tube;
-use base::TubeError;
 use hypervisor::Datamatch;
 use hypervisor::IoEventAddress;
 use hypervisor::IrqRoute;
@@ -75,6 +90,7 @@ use rutabaga_gfx::RutabagaHandle;
 use rutabaga_gfx::VulkanInfo;
 use serde::Deserialize;
 use serde::Serialize;
+use serde_keyvalue::FromKeyValues;
 use sync::Mutex;
 #[cfg(unix)]
 pub use sys::FsMappingRequest;
@@ -84,7 +100,11 @@ pub use sys::VmMsyncRequest;
 pub use sys::VmMsyncResponse;
 use thiserror::Error;
 use vm_memory::GuestAddress;
+#[cfg(unix)]
+use vm_memory::GuestMemory;
 
+use crate::dirty_log::DirtyLogCommand;
+use crate::dirty_log::DirtyLogControlResult;
 use crate::display::AspectRatio;
 use crate::display::DisplaySize;
 use crate::display::GuestDisplayDensity;
@@ -102,6 +122,15 @@ pub use crate::gdb::VcpuDebugStatusMessage;
 use crate::gpu::GpuControlCommand;
 #[cfg(feature = "gpu")]
 use crate::gpu::GpuControlResult;
+use crate::input::InputControlCommand;
+use crate::input::InputControlResult;
+use crate::input::InputEvent;
+#[cfg(unix)]
+use crate::migration::MigrationReceiver;
+#[cfg(unix)]
+use crate::migration::MigrationSender;
+use crate::snapshot::SnapshotReader;
+use crate::snapshot::VmSnapshot;
 
 /// Control the state of a particular VM CPU.
 #[derive(Clone, Debug)]
@@ -111,6 +140,18 @@ pub enum VcpuControl {
     RunState(VmRunMode),
     MakeRT,
     GetStates,
+    /// Saves the state of the vCPU, which is sent back along with its id.
+    Snapshot(mpsc::Sender<(usize, anyhow::Result<serde_json::Value>)>),
+    /// Restores the state of the vCPU from a snapshot.
+    Restore(VcpuRestoreRequest),
+}
+
+/// Request for the vCPUs to restore their state, each from the snapshot at its index.
+#[derive(Clone, Debug)]
+pub struct VcpuRestoreRequest {
+    /// Receives the result of each vCPU, along with its id.
+    pub result_sender: mpsc::Sender<(usize, anyhow::Result<()>)>,
+    pub snapshots: Arc<Vec<serde_json::Value>>,
 }
 
 /// Mode of execution for the VM.
@@ -179,6 +220,17 @@ pub enum BalloonControlCommand {
         num_bytes: u64,
     },
     Stats,
+    /// Ask the guest to hint its free pages, which are then returned to the host.
+    FreePageHint,
+    /// Set the bins of the working set histograms, as idle ages in milliseconds, and the
+    /// thresholds at which the guest refreshes and reports its working set.
+    WorkingSetConfig {
+        bins: Vec<u64>,
+        refresh_threshold: u64,
+        report_threshold: u64,
+    },
+    /// Fetch the working set of the guest.
+    WorkingSet,
 }
 
 // BalloonControlResult holds results for BalloonControlCommand defined above.
@@ -188,12 +240,51 @@ pub enum BalloonControlResult {
         stats: BalloonStats,
         balloon_actual: u64,
     },
+    WorkingSet {
+        ws: BalloonWS,
+        balloon_actual: u64,
+    },
+}
+
+/// Limits on the rate of read and write requests to a disk. Limits that aren't set or are 0 aren't
+/// enforced. Each burst is the amount that can be used at once after the disk has been idle, and
+/// defaults to one second's worth of the matching limit.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq, FromKeyValues)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct DiskThrottleConfig {
+    /// Read requests per second.
+    pub read_iops: Option<u64>,
+    pub read_iops_burst: Option<u64>,
+    /// Bytes read per second.
+    pub read_bps: Option<u64>,
+    pub read_bps_burst: Option<u64>,
+    /// Write requests per second.
+    pub write_iops: Option<u64>,
+    pub write_iops_burst: Option<u64>,
+    /// Bytes written per second.
+    pub write_bps: Option<u64>,
+    pub write_bps_burst: Option<u64>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub enum DiskControlCommand {
     /// Resize a disk to `new_size` in bytes.
     Resize { new_size: u64 },
+    /// Save an internal snapshot named `name` inside the disk image.
+    Snapshot { name: String },
+    /// Delete the internal snapshot with the given name or ID from the disk image.
+    DeleteSnapshot { name: String },
+    /// Create a qcow2 image in the empty file `overlay` on top of the current disk image, found at
+    /// `backing_file_path` and opened as `backing_file`, and switch the disk over to it.
+    ExternalSnapshot {
+        #[serde(with = "with_as_descriptor")]
+        overlay: File,
+        #[serde(with = "with_as_descriptor")]
+        backing_file: File,
+        backing_file_path: String,
+    },
+    /// Replace the I/O rate limits of the disk with `limits`.
+    Throttle { limits: DiskThrottleConfig },
 }
 
 impl Display for DiskControlCommand {
@@ -202,6 +293,12 @@ impl Display for DiskControlCommand {
 
         match self {
             Resize { new_size } => write!(f, "disk_resize {}", new_size),
+            Snapshot { name } => write!(f, "disk_snapshot {}", name),
+            DeleteSnapshot { name } => write!(f, "disk_delete_snapshot {}", name),
+            ExternalSnapshot {
+                backing_file_path, ..
+            } => write!(f, "disk_external_snapshot {}", backing_file_path),
+            Throttle { limits } => write!(f, "disk_throttle {:?}", limits),
         }
     }
 }
@@ -212,18 +309,96 @@ pub enum DiskControlResult {
     Err(SysError),
 }
 
+#[derive(Serialize, Deserialize, Debug)]
+pub enum NetControlCommand {
+    /// Bring the link of the network device up or down, as if its cable was plugged or pulled.
+    SetLinkState { up: bool },
+}
+
+impl Display for NetControlCommand {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::NetControlCommand::*;
+
+        match self {
+            SetLinkState { up } => write!(f, "net_link {}", if *up { "up" } else { "down" }),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum NetControlResult {
+    Ok,
+    Err(SysError),
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub enum MemControlCommand {
+    /// Plug or unplug memory in the hotplug region until `size` bytes of it are plugged.
+    Resize { size: u64 },
+    /// Get the sizes of the hotplug region and of its plugged memory.
+    Stats,
+}
+
+/// Sizes, in bytes, of the memory hotplug region of a virtio-mem device.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub struct MemStats {
+    pub region_size: u64,
+    pub block_size: u64,
+    /// Memory currently plugged by the guest.
+    pub plugged_size: u64,
+    /// Memory that the guest was asked to plug.
+    pub requested_size: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub enum MemControlResult {
+    Ok,
+    Stats(MemStats),
+    Err(SysError),
+}
+
+/// A USB device emulated by crosvm.
+#[derive(Serialize, Deserialize, Debug)]
+pub enum EmulatedUsbDevice {
+    /// Mass storage device exposing the disk image `file`.
+    MassStorage {
+        #[serde(with = "with_as_descriptor")]
+        file: File,
+        read_only: bool,
+    },
+    /// Keyboard receiving the key events injected with `UsbControlCommand::InjectInput`.
+    Keyboard,
+    /// Tablet receiving the pointer events injected with `UsbControlCommand::InjectInput`, with
+    /// coordinates from 0 to `width` - 1 and `height` - 1.
+    Tablet { width: u32, height: u32 },
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub enum UsbControlCommand {
     AttachDevice {
         #[serde(with = "with_as_descriptor")]
         file: File,
     },
+    AttachEmulatedDevice {
+        device: EmulatedUsbDevice,
+    },
+    /// Imports the device `busid` of the USB/IP server connected to `socket`.
+    AttachUsbipDevice {
+        #[serde(with = "with_as_descriptor")]
+        socket: File,
+        busid: String,
+    },
     DetachDevice {
         port: u8,
     },
     ListDevice {
         ports: [u8; USB_CONTROL_MAX_PORTS],
     },
+    /// Sends `events` to the emulated HID device on `port`.
+    InjectInput {
+        port: u8,
+        events: Vec<InputEvent>,
+    },
 }
 
 #[derive(Serialize, Deserialize, Copy, Clone, Debug, Default)]
@@ -248,6 +423,7 @@ pub enum UsbControlResult {
     FailedToOpenDevice,
     Devices([UsbControlAttachedDevice; USB_CONTROL_MAX_PORTS]),
     FailedToInitHostDevice,
+    FailedToInjectInput,
 }
 
 impl Display for UsbControlResult {
@@ -268,6 +444,7 @@ impl Display for UsbControlResult {
                 std::result::Result::Ok(())
             }
             FailedToInitHostDevice => write!(f, "failed_to_init_host_device"),
+            FailedToInjectInput => write!(f, "failed_to_inject_input"),
         }
     }
 }
@@ -303,14 +480,53 @@ pub enum RestoreControlResult {
     Failed(String),
 }
 
+/// Commands for live migration
+#[derive(Serialize, Deserialize, Debug)]
+pub enum MigrateCommand {
+    /// Sends the running VM to the `crosvm run --incoming` process listening at `socket`.
+    Send { socket: PathBuf },
+}
+
 /// Commands for actions on devices and the devices control thread.
 #[derive(Serialize, Deserialize, Debug)]
 pub enum DeviceControlCommand {
-    SnapshotDevices { snapshot_path: PathBuf },
-    RestoreDevices { restore_path: PathBuf },
+    /// Writes a snapshot archive with the state of guest memory, the devices and `vm`.
+    SnapshotDevices {
+        snapshot_path: PathBuf,
+        vm: VmSnapshot,
+    },
+    /// Restores guest memory and the devices from a snapshot archive.
+    RestoreDevices {
+        restore_path: PathBuf,
+    },
+    /// Puts the devices to sleep so that they stop writing guest memory, ahead of a migration.
+    #[cfg(unix)]
+    SleepDevices,
+    /// Writes the states of the sleeping devices to the migration `stream`.
+    #[cfg(unix)]
+    SendDeviceStates {
+        stream: SafeDescriptor,
+    },
+    /// Wakes the devices up after a failed migration.
+    #[cfg(unix)]
+    WakeDevices,
+    /// Restores the devices from the states read from the migration `stream`.
+    #[cfg(unix)]
+    ReceiveDeviceStates {
+        stream: SafeDescriptor,
+    },
     Exit,
 }
 
+/// Response for the migration commands of [DeviceControlCommand].
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum MigrateControlResult {
+    /// The command succeeded.
+    Ok,
+    /// The command fails.
+    Failed(String),
+}
+
 /// Source of a `VmMemoryRequest::RegisterMemory` mapping.
 #[derive(Serialize, Deserialize)]
 pub enum VmMemorySource {
@@ -1003,6 +1219,22 @@ pub enum VmRequest {
         disk_index: usize,
         command: DiskControlCommand,
     },
+    /// Send a command to a network device chosen by `net_index`.
+    /// `net_index` is a 0-based count of the network devices backed by a tap device, in the order
+    /// of `--net` options followed by `--tap-fd`, `--host-ip` and `--tap-name`.
+    NetCommand {
+        net_index: usize,
+        command: NetControlCommand,
+    },
+    /// Send a command to an input device chosen by `device`, the name of the input device e.g.
+    /// `keyboard0` for the first `--keyboard` option, or `usb1` for the emulated USB HID device on
+    /// port 1, see `crosvm input --help`.
+    InputCommand {
+        device: String,
+        command: InputControlCommand,
+    },
+    /// Command for the virtio-mem device that plugs memory in the hotplug region.
+    MemCommand(MemControlCommand),
     /// Command to use controller.
     UsbCommand(UsbControlCommand),
     #[cfg(feature = "gpu")]
@@ -1019,6 +1251,10 @@ pub enum VmRequest {
     Snapshot(SnapshotCommand),
     /// Command to Restore devices
     Restore(RestoreCommand),
+    /// Command to migrate the VM to another crosvm process
+    Migrate(MigrateCommand),
+    /// Command to track the pages written by the guest
+    DirtyLog(DirtyLogCommand),
 }
 
 pub fn handle_disk_command(command: &DiskControlCommand, disk_host_tube: &Tube) -> VmResponse {
@@ -1039,6 +1275,89 @@ pub fn handle_disk_command(command: &DiskControlCommand, disk_host_tube: &Tube)
     }
 }
 
+pub fn handle_net_command(command: &NetControlCommand, net_host_tube: &Tube) -> VmResponse {
+    // Forward the request to the net device process via its control socket.
+    if let Err(e) = net_host_tube.send(command) {
+        error!("net socket send failed: {}", e);
+        return VmResponse::Err(SysError::new(EINVAL));
+    }
+
+    match net_host_tube.recv() {
+        Ok(NetControlResult::Ok) => VmResponse::Ok,
+        Ok(NetControlResult::Err(e)) => VmResponse::Err(e),
+        Err(e) => {
+            error!("net socket recv failed: {}", e);
+            VmResponse::Err(SysError::new(EINVAL))
+        }
+    }
+}
+
+pub fn handle_input_command(command: &InputControlCommand, input_host_tube: &Tube) -> VmResponse {
+    // Forward the request to the input device process via its control socket.
+    if let Err(e) = input_host_tube.send(command) {
+        error!("input socket send failed: {}", e);
+        return VmResponse::Err(SysError::new(EINVAL));
+    }
+
+    match input_host_tube.recv() {
+        Ok(InputControlResult::Ok) => VmResponse::Ok,
+        Ok(InputControlResult::Err(e)) => VmResponse::Err(e),
+        Err(e) => {
+            error!("input socket recv failed: {}", e);
+            VmResponse::Err(SysError::new(EINVAL))
+        }
+    }
+}
+
+/// Returns the port of the emulated USB HID device named `device`, e.g. 1 for `usb1`.
+fn usb_input_port(device: &str) -> Option<u8> {
+    device.strip_prefix("usb")?.parse().ok()
+}
+
+pub fn handle_usb_input_command(
+    port: u8,
+    command: &InputControlCommand,
+    usb_control_tube: &Tube,
+) -> VmResponse {
+    let InputControlCommand::InjectEvents { events } = command;
+    let command = UsbControlCommand::InjectInput {
+        port,
+        events: events.clone(),
+    };
+    if let Err(e) = usb_control_tube.send(&command) {
+        error!("fail to send command to usb control socket: {}", e);
+        return VmResponse::Err(SysError::new(EIO));
+    }
+
+    match usb_control_tube.recv() {
+        Ok(UsbControlResult::Ok { .. }) => VmResponse::Ok,
+        Ok(UsbControlResult::NoSuchDevice) => VmResponse::Err(SysError::new(ENODEV)),
+        Ok(_) => VmResponse::Err(SysError::new(EIO)),
+        Err(e) => {
+            error!("fail to recv command from usb control socket: {}", e);
+            VmResponse::Err(SysError::new(EIO))
+        }
+    }
+}
+
+pub fn handle_mem_command(command: &MemControlCommand, mem_host_tube: &Tube) -> VmResponse {
+    // Forward the request to the virtio-mem device process via its control socket.
+    if let Err(e) = mem_host_tube.send(command) {
+        error!("mem socket send failed: {}", e);
+        return VmResponse::Err(SysError::new(EINVAL));
+    }
+
+    match mem_host_tube.recv() {
+        Ok(MemControlResult::Ok) => VmResponse::Ok,
+        Ok(MemControlResult::Stats(stats)) => VmResponse::MemStats(stats),
+        Ok(MemControlResult::Err(e)) => VmResponse::Err(e),
+        Err(e) => {
+            error!("mem socket recv failed: {}", e);
+            VmResponse::Err(SysError::new(EINVAL))
+        }
+    }
+}
+
 /// WARNING: descriptor must be a mapping handle on Windows.
 fn map_descriptor(
     descriptor: &dyn AsRawDescriptor,
@@ -1096,7 +1415,7 @@ fn do_while_vcpus_suspended<T: for<'de> serde::Deserialize<'de>>(
     kick_vcpus: impl Fn(VcpuControl),
     state_from_vcpu_channel: &mpsc::Receiver<VmRunMode>,
     vcpu_num: usize,
-    run_action: impl Fn() -> std::result::Result<(), TubeError>,
+    run_action: impl FnOnce() -> anyhow::Result<()>,
     device_control_tube: &Tube,
 ) -> std::result::Result<T, SysError> {
     // get initial vcpu state
@@ -1115,7 +1434,7 @@ fn do_while_vcpus_suspended<T: for<'de> serde::Deserialize<'de>>(
         return Err(SysError::new(EIO));
     }
     if let Err(e) = run_action() {
-        error!("fail to send command to devices control socket: {}", e);
+        error!("failed to run action while vCPUs are suspended: {:#}", e);
         kick_vcpus(VcpuControl::RunState(saved_run_mode));
         return Err(SysError::new(EIO));
     };
@@ -1130,6 +1449,288 @@ fn do_while_vcpus_suspended<T: for<'de> serde::Deserialize<'de>>(
     Ok(response)
 }
 
+// Collects the result of each of the `vcpu_num` vCPUs, ordered by vCPU id.
+fn collect_vcpu_results<T>(
+    receiver: mpsc::Receiver<(usize, anyhow::Result<T>)>,
+    vcpu_num: usize,
+) -> anyhow::Result<Vec<T>> {
+    let mut results: Vec<Option<T>> = (0..vcpu_num).map(|_| None).collect();
+    for _ in 0..vcpu_num {
+        let (cpu_id, result) = receiver
+            .recv()
+            .context("failed to receive the result of a vCPU")?;
+        let result = result.with_context(|| format!("vCPU {}", cpu_id))?;
+        *results
+            .get_mut(cpu_id)
+            .with_context(|| format!("unexpected vCPU id {}", cpu_id))? = Some(result);
+    }
+    results
+        .into_iter()
+        .enumerate()
+        .map(|(cpu_id, result)| result.with_context(|| format!("no result from vCPU {}", cpu_id)))
+        .collect()
+}
+
+/// Takes a snapshot of the whole VM to the archive at `snapshot_path`.
+///
+/// The vCPUs are suspended while the state of the vCPUs and of `snapshot_irq_chip` is collected,
+/// then the device control thread writes it to the archive along with guest memory and the
+/// devices.
+pub fn snapshot_vm(
+    snapshot_path: &Path,
+    kick_vcpus: impl Fn(VcpuControl),
+    state_from_vcpu_channel: &mpsc::Receiver<VmRunMode>,
+    vcpu_num: usize,
+    snapshot_irq_chip: impl FnOnce() -> anyhow::Result<serde_json::Value>,
+    device_control_tube: &Tube,
+) -> VmResponse {
+    let response: SnapshotControlResult = match do_while_vcpus_suspended(
+        &kick_vcpus,
+        state_from_vcpu_channel,
+        vcpu_num,
+        || {
+            let (sender, receiver) = mpsc::channel();
+            kick_vcpus(VcpuControl::Snapshot(sender));
+            let vcpus =
+                collect_vcpu_results(receiver, vcpu_num).context("failed to snapshot vCPUs")?;
+            let irq_chip = snapshot_irq_chip().context("failed to snapshot irqchip")?;
+            device_control_tube
+                .send(&DeviceControlCommand::SnapshotDevices {
+                    snapshot_path: snapshot_path.to_owned(),
+                    vm: VmSnapshot { vcpus, irq_chip },
+                })
+                .context("failed to send command to device control socket")
+        },
+        device_control_tube,
+    ) {
+        Ok(res) => res,
+        Err(e) => return VmResponse::Err(e),
+    };
+    VmResponse::SnapshotResponse(response)
+}
+
+/// Restores the whole VM from the archive at `restore_path`.
+///
+/// The VM must have been started with the same configuration as the one the snapshot was taken
+/// from. The irqchip and the vCPUs are restored first, while the vCPUs are suspended, then the
+/// device control thread restores guest memory and the devices.
+pub fn restore_vm(
+    restore_path: &Path,
+    kick_vcpus: impl Fn(VcpuControl),
+    state_from_vcpu_channel: &mpsc::Receiver<VmRunMode>,
+    vcpu_num: usize,
+    restore_irq_chip: impl FnOnce(serde_json::Value) -> anyhow::Result<()>,
+    device_control_tube: &Tube,
+) -> VmResponse {
+    let vm = match SnapshotReader::open(restore_path) {
+        Ok(mut reader) => std::mem::take(&mut reader.metadata_mut().vm),
+        Err(e) => {
+            return VmResponse::RestoreResponse(RestoreControlResult::Failed(format!("{:#}", e)))
+        }
+    };
+    if vm.vcpus.len() != vcpu_num {
+        return VmResponse::RestoreResponse(RestoreControlResult::Failed(format!(
+            "snapshot has {} vCPUs, the VM has {}",
+            vm.vcpus.len(),
+            vcpu_num
+        )));
+    }
+    let response: RestoreControlResult = match do_while_vcpus_suspended(
+        &kick_vcpus,
+        state_from_vcpu_channel,
+        vcpu_num,
+        || {
+            restore_irq_chip(vm.irq_chip).context("failed to restore irqchip")?;
+            let (sender, receiver) = mpsc::channel();
+            kick_vcpus(VcpuControl::Restore(VcpuRestoreRequest {
+                result_sender: sender,
+                snapshots: Arc::new(vm.vcpus),
+            }));
+            collect_vcpu_results(receiver, vcpu_num).context("failed to restore vCPUs")?;
+            device_control_tube
+                .send(&DeviceControlCommand::RestoreDevices {
+                    restore_path: restore_path.to_owned(),
+                })
+                .context("failed to send command to device control socket")
+        },
+        device_control_tube,
+    ) {
+        Ok(res) => res,
+        Err(e) => return VmResponse::Err(e),
+    };
+    VmResponse::RestoreResponse(response)
+}
+
+// Pre-copy stops once a pass sends at most this many pages, or after `MAX_PRECOPY_PASSES` passes
+// if the guest keeps writing memory faster than it is sent.
+#[cfg(unix)]
+const PRECOPY_DONE_PAGES: usize = 256;
+#[cfg(unix)]
+const MAX_PRECOPY_PASSES: usize = 30;
+
+// Sends a migration command to the device control thread and waits for its result.
+#[cfg(unix)]
+fn device_migrate_command(
+    device_control_tube: &Tube,
+    command: DeviceControlCommand,
+) -> anyhow::Result<()> {
+    device_control_tube
+        .send(&command)
+        .context("failed to send command to device control socket")?;
+    match device_control_tube
+        .recv()
+        .context("failed to receive from device control socket")?
+    {
+        MigrateControlResult::Ok => Ok(()),
+        MigrateControlResult::Failed(e) => Err(anyhow::anyhow!(e)),
+    }
+}
+
+// Sends the rest of the VM once the vCPUs are suspended.
+#[cfg(unix)]
+fn migrate_suspended_vm(
+    sender: &mut MigrationSender,
+    kick_vcpus: impl Fn(VcpuControl),
+    vcpu_num: usize,
+    snapshot_irq_chip: impl FnOnce() -> anyhow::Result<serde_json::Value>,
+    device_control_tube: &Tube,
+) -> anyhow::Result<()> {
+    device_migrate_command(device_control_tube, DeviceControlCommand::SleepDevices)
+        .context("failed to put the devices to sleep")?;
+    let result = (|| {
+        // The devices don't go through the dirty log, so their writes are only found by comparing
+        // the pages to the ones sent.
+        let sent = sender.send_changed()?;
+        info!("migration: sent the last {} pages", sent);
+        let (result_sender, receiver) = mpsc::channel();
+        kick_vcpus(VcpuControl::Snapshot(result_sender));
+        let vcpus = collect_vcpu_results(receiver, vcpu_num).context("failed to snapshot vCPUs")?;
+        let irq_chip = snapshot_irq_chip().context("failed to snapshot irqchip")?;
+        sender.send_vm_state(&VmSnapshot { vcpus, irq_chip })?;
+        let stream = SafeDescriptor::try_from(sender.stream() as &dyn AsRawFd)
+            .context("failed to clone the migration stream")?;
+        device_migrate_command(
+            device_control_tube,
+            DeviceControlCommand::SendDeviceStates { stream },
+        )
+        .context("failed to send the device states")?;
+        sender.wait_ack()
+    })();
+    if result.is_err() {
+        if let Err(e) =
+            device_migrate_command(device_control_tube, DeviceControlCommand::WakeDevices)
+        {
+            error!("failed to wake the devices up: {:#}", e);
+        }
+    }
+    result
+}
+
+/// Migrates the running VM to the `crosvm run --incoming` process listening at `socket`.
+///
+/// Guest memory is first sent while the VM runs, in passes that send the pages written by the
+/// vCPUs since the previous pass according to the dirty log of `vm`. Once a pass is small enough,
+/// the vCPUs and the devices are stopped to send the last pages and the state of the vCPUs, of
+/// `snapshot_irq_chip` and of the devices. On success, the vCPUs stay suspended and the VM must
+/// exit since the destination took over. On failure, the VM resumes.
+#[cfg(unix)]
+pub fn migrate_vm(
+    socket: &Path,
+    vm: &mut dyn Vm,
+    kick_vcpus: impl Fn(VcpuControl),
+    state_from_vcpu_channel: &mpsc::Receiver<VmRunMode>,
+    vcpu_num: usize,
+    snapshot_irq_chip: impl FnOnce() -> anyhow::Result<serde_json::Value>,
+    device_control_tube: &Tube,
+) -> VmResponse {
+    let mut sender = match MigrationSender::connect(socket, vm.get_memory().clone()) {
+        Ok(sender) => sender,
+        Err(e) => {
+            return VmResponse::MigrateResponse(MigrateControlResult::Failed(format!("{:#}", e)))
+        }
+    };
+    if let Err(e) = vm.set_memory_dirty_log(true) {
+        error!("failed to enable the dirty log: {}", e);
+        return VmResponse::Err(e);
+    }
+    let result = (|| {
+        let mut sent = sender.send_all()?;
+        info!("migration: sent {} pages", sent);
+        for _ in 0..MAX_PRECOPY_PASSES {
+            if sent <= PRECOPY_DONE_PAGES {
+                break;
+            }
+            sent = sender.send_dirty(vm)?;
+            info!("migration: sent {} dirty pages", sent);
+        }
+
+        let saved_run_mode = get_vcpu_state(&kick_vcpus, state_from_vcpu_channel, vcpu_num)?;
+        if saved_run_mode != VmRunMode::Suspending {
+            kick_vcpus(VcpuControl::RunState(VmRunMode::Suspending));
+        }
+        let result = match get_vcpu_state(&kick_vcpus, state_from_vcpu_channel, vcpu_num)? {
+            VmRunMode::Suspending => migrate_suspended_vm(
+                &mut sender,
+                &kick_vcpus,
+                vcpu_num,
+                snapshot_irq_chip,
+                device_control_tube,
+            ),
+            _ => Err(anyhow::anyhow!("vCPUs failed to all suspend")),
+        };
+        if result.is_err() {
+            kick_vcpus(VcpuControl::RunState(saved_run_mode));
+        }
+        result
+    })();
+    match result {
+        Ok(()) => VmResponse::MigrateResponse(MigrateControlResult::Ok),
+        Err(e) => {
+            error!("failed to migrate: {:#}", e);
+            if let Err(e) = vm.set_memory_dirty_log(false) {
+                error!("failed to disable the dirty log: {}", e);
+            }
+            VmResponse::MigrateResponse(MigrateControlResult::Failed(format!("{:#}", e)))
+        }
+    }
+}
+
+/// Receives the VM sent by `migrate_vm` from `receiver` into this VM, whose vCPUs must be
+/// suspended. Guest memory is written to `guest_mem` and the state of the vCPUs, of
+/// `restore_irq_chip` and of the devices is restored from the source.
+#[cfg(unix)]
+pub fn receive_migrated_vm(
+    receiver: &mut MigrationReceiver,
+    guest_mem: &GuestMemory,
+    kick_vcpus: impl Fn(VcpuControl),
+    vcpu_num: usize,
+    restore_irq_chip: impl FnOnce(serde_json::Value) -> anyhow::Result<()>,
+    device_control_tube: &Tube,
+) -> anyhow::Result<()> {
+    let vm = receiver.receive_memory(guest_mem)?;
+    if vm.vcpus.len() != vcpu_num {
+        anyhow::bail!(
+            "the source has {} vCPUs, the VM has {}",
+            vm.vcpus.len(),
+            vcpu_num
+        );
+    }
+    restore_irq_chip(vm.irq_chip).context("failed to restore irqchip")?;
+    let (sender, result_receiver) = mpsc::channel();
+    kick_vcpus(VcpuControl::Restore(VcpuRestoreRequest {
+        result_sender: sender,
+        snapshots: Arc::new(vm.vcpus),
+    }));
+    collect_vcpu_results(result_receiver, vcpu_num).context("failed to restore vCPUs")?;
+    let stream = SafeDescriptor::try_from(receiver.stream() as &dyn AsRawFd)
+        .context("failed to clone the migration stream")?;
+    device_migrate_command(
+        device_control_tube,
+        DeviceControlCommand::ReceiveDeviceStates { stream },
+    )
+    .context("failed to restore the devices")
+}
+
 impl VmRequest {
     /// Executes this request on the given Vm and other mutable state.
     ///
@@ -1142,6 +1743,9 @@ impl VmRequest {
         #[cfg(feature = "balloon")] balloon_host_tube: Option<&Tube>,
         #[cfg(feature = "balloon")] balloon_stats_id: &mut u64,
         disk_host_tubes: &[Tube],
+        net_host_tubes: &[Tube],
+        input_host_tubes: &BTreeMap<String, Tube>,
+        mem_host_tube: Option<&Tube>,
         pm: &mut Option<Arc<Mutex<dyn PmResource + Send>>>,
         #[cfg(feature = "gpu")] gpu_control_tube: &Tube,
         usb_control_tube: Option<&Tube>,
@@ -1152,6 +1756,8 @@ impl VmRequest {
         device_control_tube: &Tube,
         state_from_vcpu_channel: &mpsc::Receiver<VmRunMode>,
         vcpu_size: usize,
+        snapshot_irq_chip: impl FnOnce() -> anyhow::Result<serde_json::Value>,
+        restore_irq_chip: impl FnOnce(serde_json::Value) -> anyhow::Result<()>,
     ) -> VmResponse {
         match *self {
             VmRequest::Exit => {
@@ -1331,6 +1937,8 @@ impl VmRequest {
                                             balloon_actual,
                                         };
                                     }
+                                    // A working set report for a request that timed out.
+                                    Ok(BalloonTubeResult::WorkingSet { .. }) => continue,
                                     Err(e) => {
                                         error!("balloon socket recv failed: {}", e);
                                         break VmResponse::Err(SysError::last());
@@ -1347,6 +1955,72 @@ impl VmRequest {
                     VmResponse::Err(SysError::new(ENOTSUP))
                 }
             }
+            #[cfg(feature = "balloon")]
+            VmRequest::BalloonCommand(BalloonControlCommand::FreePageHint) => {
+                if let Some(balloon_host_tube) = balloon_host_tube {
+                    match balloon_host_tube.send(&BalloonTubeCommand::FreePageHint) {
+                        Ok(_) => VmResponse::Ok,
+                        Err(_) => VmResponse::Err(SysError::last()),
+                    }
+                } else {
+                    VmResponse::Err(SysError::new(ENOTSUP))
+                }
+            }
+            #[cfg(feature = "balloon")]
+            VmRequest::BalloonCommand(BalloonControlCommand::WorkingSetConfig {
+                ref bins,
+                refresh_threshold,
+                report_threshold,
+            }) => {
+                if let Some(balloon_host_tube) = balloon_host_tube {
+                    match balloon_host_tube.send(&BalloonTubeCommand::WorkingSetConfig {
+                        bins: bins.clone(),
+                        refresh_threshold,
+                        report_threshold,
+                    }) {
+                        Ok(_) => VmResponse::Ok,
+                        Err(_) => VmResponse::Err(SysError::last()),
+                    }
+                } else {
+                    VmResponse::Err(SysError::new(ENOTSUP))
+                }
+            }
+            #[cfg(feature = "balloon")]
+            VmRequest::BalloonCommand(BalloonControlCommand::WorkingSet) => {
+                if let Some(balloon_host_tube) = balloon_host_tube {
+                    // Stale results are discarded by id like for the stats above, which share
+                    // the same id sequence.
+                    *balloon_stats_id = (*balloon_stats_id).wrapping_add(1);
+                    let sent_id = *balloon_stats_id;
+                    match balloon_host_tube.send(&BalloonTubeCommand::WorkingSet { id: sent_id }) {
+                        Ok(_) => loop {
+                            match balloon_host_tube.recv() {
+                                Ok(BalloonTubeResult::WorkingSet {
+                                    ws,
+                                    balloon_actual,
+                                    id,
+                                }) => {
+                                    if sent_id != id {
+                                        continue;
+                                    }
+                                    break VmResponse::BalloonWS { ws, balloon_actual };
+                                }
+                                Ok(BalloonTubeResult::Stats { .. }) => continue,
+                                Err(e) => {
+                                    error!("balloon socket recv failed: {}", e);
+                                    break VmResponse::Err(SysError::last());
+                                }
+                                Ok(BalloonTubeResult::Adjusted { .. }) => {
+                                    unreachable!("unexpected adjusted response")
+                                }
+                            }
+                        },
+                        Err(_) => VmResponse::Err(SysError::last()),
+                    }
+                } else {
+                    VmResponse::Err(SysError::new(ENOTSUP))
+                }
+            }
             #[cfg(not(feature = "balloon"))]
             VmRequest::BalloonCommand(_) => VmResponse::Err(SysError::new(ENOTSUP)),
             VmRequest::DiskCommand {
@@ -1356,6 +2030,27 @@ impl VmRequest {
                 Some(tube) => handle_disk_command(command, tube),
                 None => VmResponse::Err(SysError::new(ENODEV)),
             },
+            VmRequest::NetCommand {
+                net_index,
+                ref command,
+            } => match &net_host_tubes.get(net_index) {
+                Some(tube) => handle_net_command(command, tube),
+                None => VmResponse::Err(SysError::new(ENODEV)),
+            },
+            VmRequest::InputCommand {
+                ref device,
+                ref command,
+            } => match input_host_tubes.get(device) {
+                Some(tube) => handle_input_command(command, tube),
+                None => match (usb_input_port(device), usb_control_tube) {
+                    (Some(port), Some(tube)) => handle_usb_input_command(port, command, tube),
+                    _ => VmResponse::Err(SysError::new(ENODEV)),
+                },
+            },
+            VmRequest::MemCommand(ref command) => match mem_host_tube {
+                Some(tube) => handle_mem_command(command, tube),
+                None => VmResponse::Err(SysError::new(ENODEV)),
+            },
             #[cfg(feature = "gpu")]
             VmRequest::GpuCommand(ref cmd) => {
                 let res = gpu_control_tube.send(cmd);
@@ -1418,40 +2113,26 @@ impl VmRequest {
                 }
             }
             VmRequest::HotPlugCommand { device: _, add: _ } => VmResponse::Ok,
-            VmRequest::Snapshot(SnapshotCommand::Take { ref snapshot_path }) => {
-                let response: SnapshotControlResult = match do_while_vcpus_suspended(
-                    &kick_vcpus,
-                    state_from_vcpu_channel,
-                    vcpu_size,
-                    || {
-                        device_control_tube.send(&DeviceControlCommand::SnapshotDevices {
-                            snapshot_path: snapshot_path.clone(),
-                        })
-                    },
-                    device_control_tube,
-                ) {
-                    Ok(res) => res,
-                    Err(e) => return VmResponse::Err(e),
-                };
-                VmResponse::SnapshotResponse(response)
-            }
-            VmRequest::Restore(RestoreCommand::Apply { ref restore_path }) => {
-                let response: RestoreControlResult = match do_while_vcpus_suspended(
-                    &kick_vcpus,
-                    state_from_vcpu_channel,
-                    vcpu_size,
-                    || {
-                        device_control_tube.send(&DeviceControlCommand::RestoreDevices {
-                            restore_path: restore_path.clone(),
-                        })
-                    },
-                    device_control_tube,
-                ) {
-                    Ok(res) => res,
-                    Err(e) => return VmResponse::Err(e),
-                };
-                VmResponse::RestoreResponse(response)
+            // Migrating and dirty logging need the `Vm`, which is handled by the main loop.
+            VmRequest::Migrate(_) | VmRequest::DirtyLog(_) => {
+                VmResponse::Err(SysError::new(ENOTSUP))
             }
+            VmRequest::Snapshot(SnapshotCommand::Take { ref snapshot_path }) => snapshot_vm(
+                snapshot_path,
+                &kick_vcpus,
+                state_from_vcpu_channel,
+                vcpu_size,
+                snapshot_irq_chip,
+                device_control_tube,
+            ),
+            VmRequest::Restore(RestoreCommand::Apply { ref restore_path }) => restore_vm(
+                restore_path,
+                &kick_vcpus,
+                state_from_vcpu_channel,
+                vcpu_size,
+                restore_irq_chip,
+                device_control_tube,
+            ),
         }
     }
 }
@@ -1473,6 +2154,10 @@ pub enum VmResponse {
         stats: BalloonStats,
         balloon_actual: u64,
     },
+    /// Working set of the guest reported by the balloon.
+    BalloonWS { ws: BalloonWS, balloon_actual: u64 },
+    /// Results of the virtio-mem stats command.
+    MemStats(MemStats),
     /// Results of usb control commands.
     UsbResponse(UsbControlResult),
     #[cfg(feature = "gpu")]
@@ -1486,6 +2171,10 @@ pub enum VmResponse {
     SnapshotResponse(SnapshotControlResult),
     /// Results of restore commands.
     RestoreResponse(RestoreControlResult),
+    /// Results of migrate commands.
+    MigrateResponse(MigrateControlResult),
+    /// Results of dirty log commands.
+    DirtyLogResponse(DirtyLogControlResult),
 }
 
 impl Display for VmResponse {
@@ -1512,6 +2201,20 @@ impl Display for VmResponse {
                     balloon_actual
                 )
             }
+            VmResponse::BalloonWS { ws, balloon_actual } => {
+                write!(
+                    f,
+                    "ws: {}\nballoon_actual: {}",
+                    serde_json::to_string_pretty(&ws)
+                        .unwrap_or_else(|_| "invalid_response".to_string()),
+                    balloon_actual
+                )
+            }
+            MemStats(stats) => write!(
+                f,
+                "plugged {} of {} requested bytes in a {} bytes region of {} bytes blocks",
+                stats.plugged_size, stats.requested_size, stats.region_size, stats.block_size
+            ),
             UsbResponse(result) => write!(f, "usb control request get result {:?}", result),
             #[cfg(feature = "gpu")]
             GpuResponse(result) => write!(f, "gpu control request result {:?}", result),
@@ -1526,6 +2229,17 @@ impl Display for VmResponse {
             }
             SnapshotResponse(result) => write!(f, "snapshot control request result {:?}", result),
             RestoreResponse(result) => write!(f, "restore control request result {:?}", result),
+            MigrateResponse(result) => write!(f, "migrate control request result {:?}", result),
+            DirtyLogResponse(DirtyLogControlResult::Report(report)) => write!(
+                f,
+                "{} dirty pages in {} ms ({:.0} pages/s)\n{}",
+                report.dirty_pages(),
+                report.interval_ms,
+                report.dirty_pages_per_second(),
+                serde_json::to_string_pretty(&report.regions)
+                    .unwrap_or_else(|_| "invalid_response".to_string()),
+            ),
+            DirtyLogResponse(result) => write!(f, "dirty log control request result {:?}", result),
         }
     }
 }
@@ -0,0 +1,481 @@
+// Copyright 2023 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Stream carrying a running VM from `crosvm migrate` to `crosvm run --incoming`.
+//!
+//! The stream starts with the magic `CROSVMLM` and a little-endian u32 version, followed by
+//! records made of a little-endian u32 kind, a little-endian u64 payload length and the payload:
+//!
+//! | Kind | Payload                                                             |
+//! |------|---------------------------------------------------------------------|
+//! | 1    | JSON list of the `(base, size)` guest memory regions                |
+//! | 2    | guest address as a little-endian u64, followed by the page contents |
+//! | 3    | JSON `VmSnapshot`                                                   |
+//! | 4    | JSON list of the device states of each bus, by device id            |
+//!
+//! The layout comes first, then any number of page records, then the VM state and the device
+//! states. Once the destination restored the VM, it answers with a single byte so that the source
+//! can exit.
+
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::hash::BuildHasher;
+use std::hash::Hasher;
+use std::io::Read;
+use std::io::Write;
+use std::os::unix::net::UnixListener;
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+
+use anyhow::bail;
+use anyhow::Context;
+use base::pagesize;
+use hypervisor::MemSlot;
+use hypervisor::Vm;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use vm_memory::GuestAddress;
+use vm_memory::GuestMemory;
+
+use crate::snapshot::VmSnapshot;
+
+const MIGRATION_MAGIC: [u8; 8] = *b"CROSVMLM";
+/// Version of the stream format, bumped for incompatible changes.
+pub const MIGRATION_VERSION: u32 = 1;
+
+const RECORD_LAYOUT: u32 = 1;
+const RECORD_PAGES: u32 = 2;
+const RECORD_VM_STATE: u32 = 3;
+const RECORD_DEVICE_STATES: u32 = 4;
+const RECORD_HEADER_LEN: usize = 12;
+
+// Maximum number of bytes of contiguous pages sent in a single record.
+const MAX_PAGES_LEN: usize = 1 << 20;
+// Upper bound on the JSON records, to avoid allocating whatever a corrupted stream asks for.
+const MAX_JSON_LEN: u64 = 1 << 30;
+
+const ACK: u8 = 1;
+
+fn write_record(stream: &mut impl Write, kind: u32, payload: &[&[u8]]) -> anyhow::Result<()> {
+    let len: usize = payload.iter().map(|p| p.len()).sum();
+    let mut header = [0u8; RECORD_HEADER_LEN];
+    header[0..4].copy_from_slice(&kind.to_le_bytes());
+    header[4..12].copy_from_slice(&(len as u64).to_le_bytes());
+    stream.write_all(&header)?;
+    for p in payload {
+        stream.write_all(p)?;
+    }
+    Ok(())
+}
+
+fn write_json_record(
+    stream: &mut impl Write,
+    kind: u32,
+    value: &impl Serialize,
+) -> anyhow::Result<()> {
+    let json = serde_json::to_vec(value).context("failed to serialize migration record")?;
+    write_record(stream, kind, &[&json])
+}
+
+// Returns the kind and length of the next record.
+fn read_record_header(stream: &mut impl Read) -> anyhow::Result<(u32, u64)> {
+    let mut header = [0u8; RECORD_HEADER_LEN];
+    stream
+        .read_exact(&mut header)
+        .context("failed to read migration record")?;
+    Ok((
+        u32::from_le_bytes(header[0..4].try_into().unwrap()),
+        u64::from_le_bytes(header[4..12].try_into().unwrap()),
+    ))
+}
+
+fn read_json_payload<T: DeserializeOwned>(stream: &mut impl Read, len: u64) -> anyhow::Result<T> {
+    if len > MAX_JSON_LEN {
+        bail!("migration record of {} bytes is too large", len);
+    }
+    let mut json = vec![0u8; len as usize];
+    stream.read_exact(&mut json)?;
+    serde_json::from_slice(&json).context("failed to deserialize migration record")
+}
+
+/// Writes the states of the devices of each bus, by device id, to the migration stream.
+pub fn write_device_states(
+    stream: &mut impl Write,
+    devices: &[HashMap<u32, serde_json::Value>],
+) -> anyhow::Result<()> {
+    write_json_record(stream, RECORD_DEVICE_STATES, &devices)
+}
+
+/// Reads the device states written by `write_device_states`.
+pub fn read_device_states(
+    stream: &mut impl Read,
+) -> anyhow::Result<Vec<HashMap<u32, serde_json::Value>>> {
+    match read_record_header(stream)? {
+        (RECORD_DEVICE_STATES, len) => read_json_payload(stream, len),
+        (kind, _) => bail!("expected the device states, got migration record {}", kind),
+    }
+}
+
+// Number of pages in `size` bytes of guest memory, rounded up.
+fn pages(size: usize) -> usize {
+    (size + pagesize() - 1) / pagesize()
+}
+
+// Pages to send among the candidates of `MigrationSender::send_pages`.
+enum PageFilter {
+    All,
+    NonZero,
+    // The pages whose contents changed since they were last sent.
+    Changed,
+}
+
+/// Source side of a migration, sending guest memory while the VM keeps running.
+pub struct MigrationSender {
+    stream: UnixStream,
+    guest_mem: GuestMemory,
+    hasher: RandomState,
+    // Hash of the contents of each page of each region, as they were last sent.
+    hashes: Vec<Vec<u64>>,
+    // Pending run of contiguous pages, starting at `run_addr`.
+    run: Vec<u8>,
+    run_addr: GuestAddress,
+}
+
+impl MigrationSender {
+    /// Connects to the destination listening at `path` and sends it the memory layout.
+    pub fn connect(path: &Path, guest_mem: GuestMemory) -> anyhow::Result<MigrationSender> {
+        let stream = UnixStream::connect(path)
+            .with_context(|| format!("failed to connect to {}", path.display()))?;
+        MigrationSender::new(stream, guest_mem)
+    }
+
+    /// Starts a migration over `stream` and sends the memory layout.
+    pub fn new(mut stream: UnixStream, guest_mem: GuestMemory) -> anyhow::Result<MigrationSender> {
+        stream.write_all(&MIGRATION_MAGIC)?;
+        stream.write_all(&MIGRATION_VERSION.to_le_bytes())?;
+        let layout: Vec<(u64, usize)> = guest_mem
+            .guest_memory_regions()
+            .into_iter()
+            .map(|(base, size)| (base.offset(), size))
+            .collect();
+        write_json_record(&mut stream, RECORD_LAYOUT, &layout)?;
+        let hashes = layout
+            .iter()
+            .map(|&(_, size)| vec![0; pages(size)])
+            .collect();
+        Ok(MigrationSender {
+            stream,
+            guest_mem,
+            hasher: RandomState::new(),
+            hashes,
+            run: Vec::with_capacity(MAX_PAGES_LEN),
+            run_addr: GuestAddress(0),
+        })
+    }
+
+    /// Returns the stream to the destination, for the device states.
+    pub fn stream(&mut self) -> &mut UnixStream {
+        &mut self.stream
+    }
+
+    /// Sends all the pages of guest memory but the zero ones, which the destination clears.
+    /// Returns the number of pages sent.
+    pub fn send_all(&mut self) -> anyhow::Result<usize> {
+        self.send_pages(|_, _| true, PageFilter::NonZero)
+    }
+
+    /// Sends the pages that the vCPUs wrote since the last call, according to the dirty log of
+    /// `vm`, which must be enabled with `Vm::set_memory_dirty_log`. Returns the number of pages
+    /// sent.
+    pub fn send_dirty(&mut self, vm: &dyn Vm) -> anyhow::Result<usize> {
+        let dirty_logs = self
+            .hashes
+            .iter()
+            .enumerate()
+            .map(|(slot, hashes)| {
+                // KVM writes the bitmap in 64-bit words.
+                let mut dirty_log = vec![0u8; (hashes.len() + 63) / 64 * 8];
+                vm.get_dirty_log(slot as MemSlot, &mut dirty_log)
+                    .with_context(|| format!("failed to get the dirty log of slot {}", slot))?;
+                Ok(dirty_log)
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        self.send_pages(
+            |region, index| dirty_logs[region][index / 8] & (1 << (index % 8)) != 0,
+            PageFilter::All,
+        )
+    }
+
+    /// Sends the pages whose contents changed since they were last sent. Unlike `send_dirty`,
+    /// this also catches the writes of the devices, which the dirty log doesn't track, but it
+    /// reads the whole guest memory. Returns the number of pages sent.
+    pub fn send_changed(&mut self) -> anyhow::Result<usize> {
+        self.send_pages(|_, _| true, PageFilter::Changed)
+    }
+
+    /// Sends the state of the VM, once guest memory is final.
+    pub fn send_vm_state(&mut self, vm: &VmSnapshot) -> anyhow::Result<()> {
+        write_json_record(&mut self.stream, RECORD_VM_STATE, vm)
+    }
+
+    /// Waits for the destination to confirm that it took over the VM.
+    pub fn wait_ack(&mut self) -> anyhow::Result<()> {
+        let mut ack = [0u8; 1];
+        self.stream
+            .read_exact(&mut ack)
+            .context("the destination failed to restore the VM")?;
+        if ack[0] != ACK {
+            bail!("unexpected answer {} from the destination", ack[0]);
+        }
+        Ok(())
+    }
+
+    // Sends the pages that pass `filter` among the ones for which `wanted` returns true, given the
+    // region index and the page index in the region.
+    fn send_pages(
+        &mut self,
+        mut wanted: impl FnMut(usize, usize) -> bool,
+        filter: PageFilter,
+    ) -> anyhow::Result<usize> {
+        let page_size = pagesize();
+        let mut page = vec![0u8; page_size];
+        let mut sent = 0;
+        let guest_mem = self.guest_mem.clone();
+        for (region, (base, size)) in guest_mem.guest_memory_regions().into_iter().enumerate() {
+            let slice = guest_mem.get_slice_at_addr(base, size)?;
+            for index in 0..pages(size) {
+                if !wanted(region, index) {
+                    continue;
+                }
+                let offset = index * page_size;
+                let len = page_size.min(size - offset);
+                slice.sub_slice(offset, len)?.copy_to(&mut page[..len]);
+                let mut hasher = self.hasher.build_hasher();
+                hasher.write(&page[..len]);
+                let hash = hasher.finish();
+                let send = match filter {
+                    PageFilter::All => true,
+                    PageFilter::NonZero => page[..len].iter().any(|&b| b != 0),
+                    PageFilter::Changed => self.hashes[region][index] != hash,
+                };
+                // The destination has the zero pages that aren't sent.
+                self.hashes[region][index] = hash;
+                if !send {
+                    continue;
+                }
+                let addr = base.unchecked_add(offset as u64);
+                if self.run.len() + len > MAX_PAGES_LEN
+                    || self.run_addr.unchecked_add(self.run.len() as u64) != addr
+                {
+                    self.flush_run()?;
+                    self.run_addr = addr;
+                }
+                self.run.extend_from_slice(&page[..len]);
+                sent += 1;
+            }
+            self.flush_run()?;
+        }
+        Ok(sent)
+    }
+
+    fn flush_run(&mut self) -> anyhow::Result<()> {
+        if !self.run.is_empty() {
+            write_record(
+                &mut self.stream,
+                RECORD_PAGES,
+                &[&self.run_addr.offset().to_le_bytes(), &self.run],
+            )?;
+            self.run.clear();
+        }
+        Ok(())
+    }
+}
+
+/// Destination side of a migration.
+pub struct MigrationReceiver {
+    stream: UnixStream,
+}
+
+impl MigrationReceiver {
+    /// Listens on a socket at `path` and waits for the source to connect.
+    pub fn listen(path: &Path) -> anyhow::Result<MigrationReceiver> {
+        let listener = UnixListener::bind(path)
+            .with_context(|| format!("failed to listen on {}", path.display()))?;
+        let accepted = listener.accept();
+        // Only a single source can connect.
+        let _ = std::fs::remove_file(path);
+        let (stream, _) = accepted.context("failed to accept the migration connection")?;
+        MigrationReceiver::new(stream)
+    }
+
+    /// Starts receiving a migration over `stream`.
+    pub fn new(mut stream: UnixStream) -> anyhow::Result<MigrationReceiver> {
+        let mut header = [0u8; 12];
+        stream
+            .read_exact(&mut header)
+            .context("failed to read migration header")?;
+        if header[0..8] != MIGRATION_MAGIC {
+            bail!("not a crosvm migration stream");
+        }
+        let version = u32::from_le_bytes(header[8..12].try_into().unwrap());
+        if version != MIGRATION_VERSION {
+            bail!(
+                "unsupported migration version {}, expected {}",
+                version,
+                MIGRATION_VERSION
+            );
+        }
+        Ok(MigrationReceiver { stream })
+    }
+
+    /// Returns the stream from the source, for the device states.
+    pub fn stream(&mut self) -> &mut UnixStream {
+        &mut self.stream
+    }
+
+    /// Receives the guest memory into `guest_mem`, which must have the same layout as the one of
+    /// the source, then returns the state of the VM.
+    pub fn receive_memory(&mut self, guest_mem: &GuestMemory) -> anyhow::Result<VmSnapshot> {
+        let regions = guest_mem.guest_memory_regions();
+        match read_record_header(&mut self.stream)? {
+            (RECORD_LAYOUT, len) => {
+                let layout: Vec<(u64, usize)> = read_json_payload(&mut self.stream, len)?;
+                let expected: Vec<(u64, usize)> = regions
+                    .iter()
+                    .map(|&(base, size)| (base.offset(), size))
+                    .collect();
+                if layout != expected {
+                    bail!(
+                        "guest memory layout {:x?} doesn't match the source {:x?}",
+                        expected,
+                        layout
+                    );
+                }
+            }
+            (kind, _) => bail!("expected the memory layout, got migration record {}", kind),
+        }
+        // The source skips the zero pages.
+        for (base, size) in regions {
+            if guest_mem.remove_range(base, size as u64).is_err() {
+                guest_mem.get_slice_at_addr(base, size)?.write_bytes(0);
+            }
+        }
+
+        let mut data = Vec::new();
+        loop {
+            match read_record_header(&mut self.stream)? {
+                (RECORD_PAGES, len) => {
+                    let len = len
+                        .checked_sub(8)
+                        .filter(|&len| len <= MAX_PAGES_LEN as u64)
+                        .with_context(|| format!("invalid pages record of {} bytes", len))?;
+                    let mut addr = [0u8; 8];
+                    self.stream.read_exact(&mut addr)?;
+                    let addr = GuestAddress(u64::from_le_bytes(addr));
+                    data.resize(len as usize, 0);
+                    self.stream.read_exact(&mut data)?;
+                    guest_mem
+                        .get_slice_at_addr(addr, data.len())
+                        .with_context(|| format!("invalid pages at {}", addr))?
+                        .copy_from(&data);
+                }
+                (RECORD_VM_STATE, len) => return read_json_payload(&mut self.stream, len),
+                (kind, _) => bail!("unexpected migration record {}", kind),
+            }
+        }
+    }
+
+    /// Tells the source that the VM was restored.
+    pub fn ack(&mut self) -> anyhow::Result<()> {
+        self.stream
+            .write_all(&[ACK])
+            .context("failed to acknowledge the migration")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+
+    fn guest_mem() -> GuestMemory {
+        GuestMemory::new(&[(GuestAddress(0), 0x4000), (GuestAddress(0x10000), 0x2000)]).unwrap()
+    }
+
+    #[test]
+    fn migrate_memory() {
+        let (source_stream, dest_stream) = UnixStream::pair().unwrap();
+        let source_mem = guest_mem();
+        source_mem
+            .write_obj_at_addr(1u64, GuestAddress(0x1000))
+            .unwrap();
+        source_mem
+            .write_obj_at_addr(2u64, GuestAddress(0x11000))
+            .unwrap();
+        let dest_mem = guest_mem();
+        // Leftovers of the destination are cleared.
+        dest_mem
+            .write_obj_at_addr(3u64, GuestAddress(0x3000))
+            .unwrap();
+
+        let dest = {
+            let dest_mem = dest_mem.clone();
+            thread::spawn(move || {
+                let mut receiver = MigrationReceiver::new(dest_stream).unwrap();
+                let vm = receiver.receive_memory(&dest_mem).unwrap();
+                let devices = read_device_states(receiver.stream()).unwrap();
+                receiver.ack().unwrap();
+                (vm, devices)
+            })
+        };
+
+        let mut sender = MigrationSender::new(source_stream, source_mem.clone()).unwrap();
+        assert_eq!(sender.send_all().unwrap(), 2);
+        assert_eq!(sender.send_changed().unwrap(), 0);
+        source_mem
+            .write_obj_at_addr(4u64, GuestAddress(0x1008))
+            .unwrap();
+        source_mem
+            .write_obj_at_addr(5u64, GuestAddress(0x2000))
+            .unwrap();
+        assert_eq!(sender.send_changed().unwrap(), 2);
+        sender
+            .send_vm_state(&VmSnapshot {
+                vcpus: vec![serde_json::json!(6)],
+                irq_chip: serde_json::Value::Null,
+            })
+            .unwrap();
+        write_device_states(sender.stream(), &[[(7, serde_json::json!(8))].into()]).unwrap();
+        sender.wait_ack().unwrap();
+
+        let (vm, devices) = dest.join().unwrap();
+        assert_eq!(vm.vcpus, [6]);
+        assert_eq!(devices[0][&7], 8);
+        for addr in [0x1000, 0x1008, 0x2000, 0x3000, 0x11000] {
+            let addr = GuestAddress(addr);
+            assert_eq!(
+                dest_mem.read_obj_from_addr::<u64>(addr).unwrap(),
+                source_mem.read_obj_from_addr::<u64>(addr).unwrap()
+            );
+        }
+    }
+
+    #[test]
+    fn layout_mismatch() {
+        let (source_stream, dest_stream) = UnixStream::pair().unwrap();
+        MigrationSender::new(source_stream, guest_mem()).unwrap();
+        let mut receiver = MigrationReceiver::new(dest_stream).unwrap();
+        let dest_mem = GuestMemory::new(&[(GuestAddress(0), 0x4000)]).unwrap();
+        assert!(receiver.receive_memory(&dest_mem).is_err());
+    }
+
+    #[test]
+    fn failed_destination() {
+        let (source_stream, dest_stream) = UnixStream::pair().unwrap();
+        let mut sender = MigrationSender::new(source_stream, guest_mem()).unwrap();
+        drop(dest_stream);
+        assert!(sender.wait_ack().is_err());
+    }
+}
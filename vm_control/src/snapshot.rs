@@ -0,0 +1,274 @@
+// Copyright 2023 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Archive holding the full state of a VM, written by `crosvm snapshot take` and read back by
+//! `crosvm snapshot restore` or `crosvm run --restore`.
+//!
+//! The archive is a single file laid out as:
+//!
+//! | Offset          | Contents                                                       |
+//! |-----------------|----------------------------------------------------------------|
+//! | 0               | magic `CROSVMSS`                                               |
+//! | 8               | format version, little-endian u32                              |
+//! | 12              | reserved                                                       |
+//! | 16              | offset of the metadata, little-endian u64                      |
+//! | 24              | length of the metadata, little-endian u64                      |
+//! | 4096            | guest memory, without its zero pages                           |
+//! | metadata offset | JSON metadata: vCPUs, irqchip, memory layout and device states |
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::fs::OpenOptions;
+use std::io::Read;
+use std::io::Seek;
+use std::io::SeekFrom;
+use std::io::Write;
+use std::mem;
+use std::path::Path;
+use std::path::PathBuf;
+
+use anyhow::bail;
+use anyhow::Context;
+use serde::Deserialize;
+use serde::Serialize;
+
+const SNAPSHOT_MAGIC: [u8; 8] = *b"CROSVMSS";
+/// Version of the archive format, bumped for incompatible changes.
+pub const SNAPSHOT_VERSION: u32 = 1;
+const HEADER_LEN: usize = 32;
+// The guest memory starts page-aligned, after the header.
+const MEMORY_OFFSET: u64 = 4096;
+
+/// State of the VM kept by the main process, outside of guest memory and devices.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct VmSnapshot {
+    /// Architectural state of each vCPU, indexed by vCPU id.
+    pub vcpus: Vec<serde_json::Value>,
+    /// State of the interrupt controllers.
+    pub irq_chip: serde_json::Value,
+}
+
+/// Metadata of a snapshot archive.
+#[derive(Serialize, Deserialize)]
+pub struct SnapshotMetadata {
+    pub vm: VmSnapshot,
+    /// Layout of the guest memory, as returned by `GuestMemory::snapshot`.
+    pub guest_memory: serde_json::Value,
+    /// States of the devices of each bus, by device id.
+    pub devices: Vec<HashMap<u32, serde_json::Value>>,
+    memory_len: u64,
+}
+
+/// Writes a snapshot archive.
+///
+/// The archive is written to a temporary file that only replaces the one at the given path once
+/// complete, so that a failed snapshot doesn't leave a truncated archive behind.
+pub struct SnapshotWriter {
+    path: PathBuf,
+    tmp_path: PathBuf,
+    file: Option<File>,
+}
+
+impl SnapshotWriter {
+    /// Starts writing the archive at `path`.
+    pub fn create(path: &Path) -> anyhow::Result<SnapshotWriter> {
+        let mut tmp_path = path.as_os_str().to_owned();
+        tmp_path.push(".tmp");
+        let tmp_path = PathBuf::from(tmp_path);
+        let mut file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(&tmp_path)
+            .with_context(|| format!("failed to open {}", tmp_path.display()))?;
+        file.seek(SeekFrom::Start(MEMORY_OFFSET))?;
+        Ok(SnapshotWriter {
+            path: path.to_owned(),
+            tmp_path,
+            file: Some(file),
+        })
+    }
+
+    /// Returns the file to write the guest memory to, with `GuestMemory::snapshot`.
+    pub fn memory_file(&mut self) -> &mut File {
+        self.file.as_mut().expect("snapshot already finished")
+    }
+
+    /// Writes the metadata and moves the archive to its final path. The temporary file is removed
+    /// if this fails.
+    pub fn finish(
+        mut self,
+        vm: VmSnapshot,
+        guest_memory: serde_json::Value,
+        devices: Vec<HashMap<u32, serde_json::Value>>,
+    ) -> anyhow::Result<()> {
+        let file = self.file.as_mut().expect("snapshot already finished");
+        let metadata_offset = file.stream_position()?;
+        let metadata = SnapshotMetadata {
+            vm,
+            guest_memory,
+            devices,
+            memory_len: metadata_offset - MEMORY_OFFSET,
+        };
+        let metadata = serde_json::to_vec(&metadata).context("failed to serialize metadata")?;
+        file.write_all(&metadata)?;
+
+        let mut header = [0u8; HEADER_LEN];
+        header[0..8].copy_from_slice(&SNAPSHOT_MAGIC);
+        header[8..12].copy_from_slice(&SNAPSHOT_VERSION.to_le_bytes());
+        header[16..24].copy_from_slice(&metadata_offset.to_le_bytes());
+        header[24..32].copy_from_slice(&(metadata.len() as u64).to_le_bytes());
+        file.seek(SeekFrom::Start(0))?;
+        file.write_all(&header)?;
+        file.sync_all()?;
+
+        std::fs::rename(&self.tmp_path, &self.path)
+            .with_context(|| format!("failed to move snapshot to {}", self.path.display()))?;
+        // The archive is complete, so dropping the writer must not remove it.
+        self.file = None;
+        Ok(())
+    }
+}
+
+impl Drop for SnapshotWriter {
+    fn drop(&mut self) {
+        if self.file.take().is_some() {
+            let _ = std::fs::remove_file(&self.tmp_path);
+        }
+    }
+}
+
+/// Reads a snapshot archive.
+pub struct SnapshotReader {
+    file: File,
+    metadata: SnapshotMetadata,
+}
+
+impl SnapshotReader {
+    /// Opens the archive at `path` and reads its metadata.
+    pub fn open(path: &Path) -> anyhow::Result<SnapshotReader> {
+        let mut file =
+            File::open(path).with_context(|| format!("failed to open {}", path.display()))?;
+        let mut header = [0u8; HEADER_LEN];
+        file.read_exact(&mut header)
+            .context("failed to read snapshot header")?;
+        if header[0..8] != SNAPSHOT_MAGIC {
+            bail!("{} is not a crosvm snapshot", path.display());
+        }
+        let version = u32::from_le_bytes(header[8..12].try_into().unwrap());
+        if version != SNAPSHOT_VERSION {
+            bail!(
+                "unsupported snapshot version {}, expected {}",
+                version,
+                SNAPSHOT_VERSION
+            );
+        }
+        let metadata_offset = u64::from_le_bytes(header[16..24].try_into().unwrap());
+        let metadata_len = u64::from_le_bytes(header[24..32].try_into().unwrap());
+
+        file.seek(SeekFrom::Start(metadata_offset))?;
+        let metadata: SnapshotMetadata = serde_json::from_reader((&mut file).take(metadata_len))
+            .context("failed to read snapshot metadata")?;
+        if metadata.memory_len != metadata_offset.saturating_sub(MEMORY_OFFSET) {
+            bail!("corrupted snapshot: guest memory size doesn't match the metadata offset");
+        }
+        Ok(SnapshotReader { file, metadata })
+    }
+
+    pub fn metadata(&self) -> &SnapshotMetadata {
+        &self.metadata
+    }
+
+    pub fn metadata_mut(&mut self) -> &mut SnapshotMetadata {
+        &mut self.metadata
+    }
+
+    /// Reads the guest memory with `restore`, which is given its layout and the file positioned
+    /// at its data, like `GuestMemory::restore`.
+    pub fn read_memory(
+        &mut self,
+        restore: impl FnOnce(serde_json::Value, &mut File) -> anyhow::Result<()>,
+    ) -> anyhow::Result<()> {
+        self.file.seek(SeekFrom::Start(MEMORY_OFFSET))?;
+        restore(mem::take(&mut self.metadata.guest_memory), &mut self.file)?;
+        if self.file.stream_position()? != MEMORY_OFFSET + self.metadata.memory_len {
+            bail!("guest memory size doesn't match the snapshot");
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn write_read() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("snapshot");
+
+        let mut writer = SnapshotWriter::create(&path).unwrap();
+        writer.memory_file().write_all(&[1, 2, 3, 4]).unwrap();
+        // Nothing shows up at the path until the snapshot is complete.
+        assert!(!path.exists());
+        let vm = VmSnapshot {
+            vcpus: vec![serde_json::json!({ "rip": 1 })],
+            irq_chip: serde_json::Value::Null,
+        };
+        let devices = vec![[(3, serde_json::json!("device"))].into()];
+        writer
+            .finish(vm, serde_json::json!("memory"), devices)
+            .unwrap();
+
+        let mut reader = SnapshotReader::open(&path).unwrap();
+        assert_eq!(reader.metadata().vm.vcpus[0]["rip"], 1);
+        assert_eq!(reader.metadata().devices[0][&3], "device");
+        reader
+            .read_memory(|layout, file| {
+                assert_eq!(layout, "memory");
+                let mut data = [0u8; 4];
+                file.read_exact(&mut data)?;
+                assert_eq!(data, [1, 2, 3, 4]);
+                Ok(())
+            })
+            .unwrap();
+
+        // Reading too little guest memory fails.
+        let mut reader = SnapshotReader::open(&path).unwrap();
+        assert!(reader.read_memory(|_, _| Ok(())).is_err());
+    }
+
+    #[test]
+    fn abandoned_write() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("snapshot");
+        drop(SnapshotWriter::create(&path).unwrap());
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn failed_finish() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("snapshot");
+        let writer = SnapshotWriter::create(&path).unwrap();
+        // The archive can't replace a directory.
+        std::fs::create_dir(&path).unwrap();
+        let vm = VmSnapshot {
+            vcpus: Vec::new(),
+            irq_chip: serde_json::Value::Null,
+        };
+        assert!(writer
+            .finish(vm, serde_json::Value::Null, Vec::new())
+            .is_err());
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn not_a_snapshot() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("snapshot");
+        std::fs::write(&path, [0u8; 64]).unwrap();
+        assert!(SnapshotReader::open(&path).is_err());
+    }
+}
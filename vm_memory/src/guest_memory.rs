@@ -870,35 +870,56 @@ impl GuestMemory {
             .map(|region| region.obj_offset + guest_addr.offset_from(region.start()))
     }
 
-    /// Copy all guest memory into `w`.
+    /// Copy all guest memory into `w`, leaving out the pages that only contain zeroes.
     ///
     /// Assumes exclusive access to the guest memory for the duration of the call (e.g. all vCPUs
     /// and devices must be stopped).
     ///
-    /// Returns a JSON object that contains metadata about the underlying memory regions to allow
-    /// validation checks at restore time.
+    /// Returns a JSON object that contains metadata about the underlying memory regions and the
+    /// parts of them that were written, to allow validation checks and the restore itself.
     pub fn snapshot(&self, w: &mut std::fs::File) -> anyhow::Result<serde_json::Value> {
         let mut metadata = MemorySnapshotMetadata {
             regions: Vec::new(),
         };
 
+        let page_size = pagesize();
+        let mut page = vec![0u8; page_size];
         for region in self.regions.iter() {
-            metadata
-                .regions
-                .push((region.guest_base.0, region.mapping.size()));
-            self.write_from_memory(region.guest_base, w, region.mapping.size())?;
+            let size = region.mapping.size();
+            let mut data_ranges: Vec<(usize, usize)> = Vec::new();
+            for offset in (0..size).step_by(page_size) {
+                let len = page_size.min(size - offset);
+                let page = &mut page[..len];
+                self.read_exact_at_addr(page, region.guest_base.unchecked_add(offset as u64))?;
+                if page.iter().all(|&b| b == 0) {
+                    continue;
+                }
+                match data_ranges.last_mut() {
+                    Some((start, range_len)) if *start + *range_len == offset => *range_len += len,
+                    _ => data_ranges.push((offset, len)),
+                }
+            }
+            for &(offset, len) in &data_ranges {
+                self.write_from_memory(region.guest_base.unchecked_add(offset as u64), w, len)?;
+            }
+            metadata.regions.push(MemoryRegionSnapshot {
+                guest_base: region.guest_base.0,
+                size,
+                data_ranges,
+            });
         }
 
         Ok(serde_json::to_value(metadata)?)
     }
 
-    /// Restore the guest memory using the bytes from `r`.
+    /// Restore the guest memory using the bytes from `r`, and zero the parts of it that weren't
+    /// written by `snapshot`.
     ///
     /// Assumes exclusive access to the guest memory for the duration of the call (e.g. all vCPUs
     /// and devices must be stopped).
     ///
     /// Returns an error if `metadata` doesn't match the configuration of the `GuestMemory` or if
-    /// `r` doesn't produce exactly as many bytes as needed.
+    /// `r` doesn't produce as many bytes as needed.
     pub fn restore(
         &self,
         metadata: serde_json::Value,
@@ -912,22 +933,45 @@ impl GuestMemory {
                 self.regions.len()
             );
         }
-        for (region, (guest_base, size)) in self.regions.iter().zip(metadata.regions.iter()) {
-            if region.guest_base.0 != *guest_base || region.mapping.size() != *size {
+        for (region, snapshot) in self.regions.iter().zip(metadata.regions.iter()) {
+            if region.guest_base.0 != snapshot.guest_base || region.mapping.size() != snapshot.size
+            {
                 bail!("snapshot memory regions don't match VM memory regions");
             }
-        }
+            // Validate all the ranges before touching the memory.
+            let mut end = 0;
+            for &(offset, len) in &snapshot.data_ranges {
+                if offset < end || offset + len > snapshot.size {
+                    bail!("invalid memory snapshot data range");
+                }
+                end = offset + len;
+            }
 
-        for region in self.regions.iter() {
-            self.read_to_memory(region.guest_base, r, region.mapping.size())?;
+            let mut end = 0;
+            for &(offset, len) in &snapshot.data_ranges {
+                self.clear_range(region.guest_base.unchecked_add(end as u64), offset - end)?;
+                self.read_to_memory(region.guest_base.unchecked_add(offset as u64), r, len)?;
+                end = offset + len;
+            }
+            self.clear_range(
+                region.guest_base.unchecked_add(end as u64),
+                snapshot.size - end,
+            )?;
         }
 
-        // Should always be at EOF at this point.
-        let mut buf = [0];
-        if r.read(&mut buf)? != 0 {
-            bail!("too many bytes");
-        }
+        Ok(())
+    }
 
+    // Fills a range of guest memory with zeroes, giving the pages back to the host when possible.
+    fn clear_range(&self, addr: GuestAddress, len: usize) -> anyhow::Result<()> {
+        if len == 0 {
+            return Ok(());
+        }
+        #[cfg(unix)]
+        if self.remove_range(addr, len as u64).is_ok() {
+            return Ok(());
+        }
+        self.get_slice_at_addr(addr, len)?.write_bytes(0);
         Ok(())
     }
 }
@@ -935,8 +979,16 @@ impl GuestMemory {
 // TODO: Consider storing a hash of memory contents and validating it on restore.
 #[derive(serde::Serialize, serde::Deserialize)]
 struct MemorySnapshotMetadata {
-    // Guest base and size for each memory region.
-    regions: Vec<(u64, usize)>,
+    regions: Vec<MemoryRegionSnapshot>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+struct MemoryRegionSnapshot {
+    guest_base: u64,
+    size: usize,
+    // Offsets and lengths of the parts of the region stored in the snapshot, in order. The rest of
+    // the region is zero.
+    data_ranges: Vec<(usize, usize)>,
 }
 
 // It is safe to implement BackingMemory because GuestMemory can be mutated any time already.
@@ -952,6 +1004,9 @@ unsafe impl BackingMemory for GuestMemory {
 
 #[cfg(test)]
 mod tests {
+    use std::io::Seek;
+    use std::io::SeekFrom;
+
     use super::*;
 
     #[test]
@@ -1135,4 +1190,57 @@ mod tests {
             Ok(())
         });
     }
+
+    #[test]
+    fn snapshot_restore_sparse() {
+        let page_size = pagesize() as u64;
+        let gm = GuestMemory::new(&[
+            (GuestAddress(0x0), 4 * page_size),
+            (GuestAddress(0x100000), 4 * page_size),
+        ])
+        .unwrap();
+        gm.write_obj_at_addr(0x1337u16, GuestAddress(page_size))
+            .unwrap();
+        gm.write_obj_at_addr(0x0420u16, GuestAddress(0x100000 + 3 * page_size + 8))
+            .unwrap();
+
+        let mut file = tempfile::tempfile().unwrap();
+        let metadata = gm.snapshot(&mut file).unwrap();
+        // Only the two pages that aren't zero are stored.
+        assert_eq!(file.metadata().unwrap().len(), 2 * page_size);
+
+        let restored = GuestMemory::new(&[
+            (GuestAddress(0x0), 4 * page_size),
+            (GuestAddress(0x100000), 4 * page_size),
+        ])
+        .unwrap();
+        restored
+            .write_obj_at_addr(0xffffu16, GuestAddress(0x0))
+            .unwrap();
+        file.seek(SeekFrom::Start(0)).unwrap();
+        restored.restore(metadata.clone(), &mut file).unwrap();
+        assert_eq!(
+            restored
+                .read_obj_from_addr::<u16>(GuestAddress(0x0))
+                .unwrap(),
+            0
+        );
+        assert_eq!(
+            restored
+                .read_obj_from_addr::<u16>(GuestAddress(page_size))
+                .unwrap(),
+            0x1337
+        );
+        assert_eq!(
+            restored
+                .read_obj_from_addr::<u16>(GuestAddress(0x100000 + 3 * page_size + 8))
+                .unwrap(),
+            0x0420
+        );
+
+        // The memory layout must match.
+        let other = GuestMemory::new(&[(GuestAddress(0x0), 8 * page_size)]).unwrap();
+        file.seek(SeekFrom::Start(0)).unwrap();
+        assert!(other.restore(metadata, &mut file).is_err());
+    }
 }
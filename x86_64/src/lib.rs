@@ -45,6 +45,8 @@ pub mod cpuid;
 mod gdt;
 pub mod interrupts;
 pub mod mptable;
+#[allow(non_camel_case_types)]
+mod pvh;
 pub mod regs;
 pub mod smbios;
 
@@ -117,6 +119,8 @@ use hypervisor::x86_64::Regs;
 #[cfg(all(target_arch = "x86_64", feature = "gdb"))]
 use hypervisor::x86_64::Sregs;
 use hypervisor::CpuConfigX86_64;
+#[cfg(all(target_arch = "x86_64", feature = "gdb"))]
+use hypervisor::HwWatchpoint;
 use hypervisor::Hypervisor;
 use hypervisor::HypervisorX86_64;
 use hypervisor::ProtectionType;
@@ -208,8 +212,6 @@ pub enum Error {
     #[cfg(feature = "direct")]
     #[error("failed to enable ACPI event forwarding: {0}")]
     EnableAcpiEvent(devices::DirectIrqError),
-    #[error("failed to enable singlestep execution: {0}")]
-    EnableSinglestep(base::Error),
     #[error("failed to enable split irqchip: {0}")]
     EnableSplitIrqchip(base::Error),
     #[error("failed to get serial cmdline: {0}")]
@@ -232,6 +234,8 @@ pub enum Error {
     LoadKernel(kernel_loader::Error),
     #[error("error loading pflash: {0}")]
     LoadPflash(io::Error),
+    #[error("32-bit ELF kernels must have a PVH entry point")]
+    MissingPvhEntry,
     #[error("error translating address: Page not present")]
     PageNotPresent,
     #[error("error reading guest memory {0}")]
@@ -246,8 +250,8 @@ pub enum Error {
     RegisterVsock(arch::DeviceRegistrationError),
     #[error("error reserved pcie config mmio")]
     ReservePcieCfgMmio(resources::Error),
-    #[error("failed to set a hardware breakpoint: {0}")]
-    SetHwBreakpoint(base::Error),
+    #[error("failed to set the guest debug state: {0}")]
+    SetGuestDebug(base::Error),
     #[error("failed to set interrupts: {0}")]
     SetLint(interrupts::Error),
     #[error("failed to set tss addr: {0}")]
@@ -268,6 +272,8 @@ pub enum Error {
     SetupPageTables(regs::Error),
     #[error("failed to set up pflash: {0}")]
     SetupPflash(anyhow::Error),
+    #[error("failed to set up PVH start info: {0}")]
+    SetupPvh(pvh::Error),
     #[error("failed to set up registers: {0}")]
     SetupRegs(regs::Error),
     #[error("failed to set up SMBIOS: {0}")]
@@ -321,6 +327,16 @@ pub struct SetupData {
     pub type_: SetupDataType,
 }
 
+/// Entry point of a kernel, along with the boot protocol it expects.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum KernelEntry {
+    /// 64-bit entry point of the Linux/x86 boot protocol.
+    Linux64(GuestAddress),
+    /// 32-bit entry point of the PVH boot protocol.
+    Pvh(GuestAddress),
+}
+
+#[derive(Clone, Copy)]
 enum E820Type {
     Ram = 0x01,
     Reserved = 0x2,
@@ -446,9 +462,9 @@ fn configure_system(
     cmdline_size: usize,
     setup_data: Option<GuestAddress>,
     initrd: Option<(GuestAddress, usize)>,
+    hotplug_region: Option<AddressRange>,
     mut params: boot_params,
 ) -> Result<()> {
-    const EBDA_START: u64 = 0x0009_fc00;
     const KERNEL_BOOT_FLAG_MAGIC: u16 = 0xaa55;
     const KERNEL_HDR_MAGIC: u32 = 0x5372_6448;
     const KERNEL_LOADER_OTHER: u8 = 0xff;
@@ -468,18 +484,49 @@ fn configure_system(
         params.hdr.ramdisk_size = initrd_size as u32;
     }
 
-    add_e820_entry(
-        &mut params,
+    for (range, mem_type) in e820_entries(guest_mem, kernel_addr, hotplug_region) {
+        add_e820_entry(&mut params, range, mem_type)?;
+    }
+
+    let zero_page_addr = GuestAddress(ZERO_PAGE_OFFSET);
+    if !guest_mem.is_valid_range(zero_page_addr, mem::size_of::<boot_params>() as u64) {
+        return Err(Error::ZeroPagePastRamEnd);
+    }
+
+    guest_mem
+        .write_obj_at_addr(params, zero_page_addr)
+        .map_err(|_| Error::ZeroPageSetup)?;
+
+    Ok(())
+}
+
+/// Returns the memory map of the guest, as reported by the e820 table of the Linux boot protocol
+/// or the memory map of the PVH boot protocol.
+fn e820_entries(
+    guest_mem: &GuestMemory,
+    kernel_addr: GuestAddress,
+    hotplug_region: Option<AddressRange>,
+) -> Vec<(AddressRange, E820Type)> {
+    const EBDA_START: u64 = 0x0009_fc00;
+
+    let mut entries = vec![(
         AddressRange {
             start: START_OF_RAM_32BITS,
             end: EBDA_START - 1,
         },
         E820Type::Ram,
-    )?;
-
-    // GuestMemory::end_addr() returns the first address past the end, so subtract 1 to get the
-    // inclusive end.
-    let guest_mem_end = guest_mem.end_addr().offset() - 1;
+    )];
+
+    // The memory hotplug region is reported to the guest by its virtio-mem device, not as RAM.
+    // Subtract 1 from the first address past the end of RAM to get the inclusive end.
+    let guest_mem_end = guest_mem
+        .guest_memory_regions()
+        .iter()
+        .filter(|(addr, _)| !hotplug_region.map_or(false, |r| r.contains(addr.offset())))
+        .map(|(addr, size)| addr.offset() + *size as u64)
+        .max()
+        .unwrap_or_default()
+        .saturating_sub(1);
     let ram_below_4g = AddressRange {
         start: kernel_addr.offset(),
         end: guest_mem_end.min(read_pci_mmio_before_32bit().start - 1),
@@ -488,30 +535,18 @@ fn configure_system(
         start: FIRST_ADDR_PAST_32BITS,
         end: guest_mem_end,
     };
-    add_e820_entry(&mut params, ram_below_4g, E820Type::Ram)?;
+    entries.push((ram_below_4g, E820Type::Ram));
     if !ram_above_4g.is_empty() {
-        add_e820_entry(&mut params, ram_above_4g, E820Type::Ram)?
+        entries.push((ram_above_4g, E820Type::Ram));
     }
 
     let pcie_cfg_mmio_range = read_pcie_cfg_mmio();
-    add_e820_entry(&mut params, pcie_cfg_mmio_range, E820Type::Reserved)?;
-
-    add_e820_entry(
-        &mut params,
+    entries.push((pcie_cfg_mmio_range, E820Type::Reserved));
+    entries.push((
         X8664arch::get_pcie_vcfg_mmio_range(guest_mem, &pcie_cfg_mmio_range),
         E820Type::Reserved,
-    )?;
-
-    let zero_page_addr = GuestAddress(ZERO_PAGE_OFFSET);
-    if !guest_mem.is_valid_range(zero_page_addr, mem::size_of::<boot_params>() as u64) {
-        return Err(Error::ZeroPagePastRamEnd);
-    }
-
-    guest_mem
-        .write_obj_at_addr(params, zero_page_addr)
-        .map_err(|_| Error::ZeroPageSetup)?;
-
-    Ok(())
+    ));
+    entries
 }
 
 /// Write setup_data entries in guest memory and link them together with the `next` field.
@@ -606,6 +641,24 @@ fn add_e820_entry(params: &mut boot_params, range: AddressRange, mem_type: E820T
     Ok(())
 }
 
+/// Returns the region reserved for memory hotplug, which starts at the first address past RAM and
+/// the 32-bit address space that is aligned to the memory block size of Linux guests.
+pub fn hotplug_memory_region(memory_size: u64, hotplug_memory_size: u64) -> Option<AddressRange> {
+    const HOTPLUG_MEMORY_ALIGNMENT: u64 = 128 * MB;
+
+    if hotplug_memory_size == 0 {
+        return None;
+    }
+    let ram_end = arch_memory_regions(memory_size, None)
+        .iter()
+        .map(|(addr, size)| addr.offset() + size)
+        .max()?;
+    let start = ram_end.max(FIRST_ADDR_PAST_32BITS);
+    let start = (start + HOTPLUG_MEMORY_ALIGNMENT - 1) / HOTPLUG_MEMORY_ALIGNMENT
+        * HOTPLUG_MEMORY_ALIGNMENT;
+    AddressRange::from_start_and_size(start, hotplug_memory_size)
+}
+
 /// Returns a Vec of the valid memory addresses.
 /// These should be used to configure the GuestMemory structure for the platform.
 /// For x86_64 all addresses are valid from the start of the kernel except a
@@ -654,7 +707,18 @@ impl arch::LinuxArch for X8664arch {
             VmImage::Kernel(_) => None,
         };
 
-        Ok(arch_memory_regions(components.memory_size, bios_size))
+        let mut regions = arch_memory_regions(components.memory_size, bios_size);
+        if let Some(hotplug_region) = Self::hotplug_memory_region(components) {
+            regions.push((
+                GuestAddress(hotplug_region.start),
+                components.hotplug_memory_size,
+            ));
+        }
+        Ok(regions)
+    }
+
+    fn hotplug_memory_region(components: &VmComponents) -> Option<AddressRange> {
+        hotplug_memory_region(components.memory_size, components.hotplug_memory_size)
     }
 
     fn get_system_allocator_config<V: Vm>(vm: &V) -> SystemAllocatorConfig {
@@ -913,7 +977,7 @@ impl arch::LinuxArch for X8664arch {
         };
 
         // TODO (tjeznach) Write RSDP to bootconfig before writing to memory
-        acpi::create_acpi_tables(
+        let rsdp_addr = acpi::create_acpi_tables(
             &mem,
             vcpu_count as u8,
             sci_irq,
@@ -948,6 +1012,8 @@ impl arch::LinuxArch for X8664arch {
         }
 
         let pci_start = read_pci_mmio_before_32bit().start;
+        let hotplug_region =
+            hotplug_memory_region(components.memory_size, components.hotplug_memory_size);
 
         let mut vcpu_init = vec![VcpuInitX86_64::default(); vcpu_count];
 
@@ -968,7 +1034,7 @@ impl arch::LinuxArch for X8664arch {
             VmImage::Kernel(ref mut kernel_image) => {
                 let (params, kernel_end, kernel_entry) = Self::load_kernel(&mem, kernel_image)?;
 
-                Self::setup_system_memory(
+                let initrd = Self::setup_system_memory(
                     &mem,
                     &CString::new(cmdline).unwrap(),
                     components.initrd_image,
@@ -976,22 +1042,58 @@ impl arch::LinuxArch for X8664arch {
                     kernel_end,
                     params,
                     dump_device_tree_blob,
+                    hotplug_region,
                 )?;
 
-                // Configure the bootstrap VCPU for the Linux/x86 64-bit boot protocol.
-                // <https://www.kernel.org/doc/html/latest/x86/boot.html>
-                vcpu_init[0].regs.rip = kernel_entry.offset();
-                vcpu_init[0].regs.rsp = BOOT_STACK_POINTER;
-                vcpu_init[0].regs.rsi = ZERO_PAGE_OFFSET;
-
-                msrs = regs::long_mode_msrs();
-                msrs.append(&mut regs::mtrr_msrs(&vm, pci_start));
-
-                // Set up long mode and enable paging.
-                regs::configure_segments_and_sregs(&mem, &mut vcpu_init[0].sregs)
-                    .map_err(Error::ConfigureSegments)?;
-                regs::setup_page_tables(&mem, &mut vcpu_init[0].sregs)
-                    .map_err(Error::SetupPageTables)?;
+                match kernel_entry {
+                    KernelEntry::Linux64(entry) => {
+                        // Configure the bootstrap VCPU for the Linux/x86 64-bit boot protocol.
+                        // <https://www.kernel.org/doc/html/latest/x86/boot.html>
+                        vcpu_init[0].regs.rip = entry.offset();
+                        vcpu_init[0].regs.rsp = BOOT_STACK_POINTER;
+                        vcpu_init[0].regs.rsi = ZERO_PAGE_OFFSET;
+
+                        msrs = regs::long_mode_msrs();
+                        msrs.append(&mut regs::mtrr_msrs(&vm, pci_start));
+
+                        // Set up long mode and enable paging.
+                        regs::configure_segments_and_sregs(&mem, &mut vcpu_init[0].sregs)
+                            .map_err(Error::ConfigureSegments)?;
+                        regs::setup_page_tables(&mem, &mut vcpu_init[0].sregs)
+                            .map_err(Error::SetupPageTables)?;
+                    }
+                    KernelEntry::Pvh(entry) => {
+                        // Configure the bootstrap VCPU for the PVH boot protocol, which starts the
+                        // kernel in 32-bit protected mode without paging.
+                        // <https://xenbits.xen.org/docs/unstable/misc/pvh.html>
+                        let memmap: Vec<pvh::MemmapEntry> =
+                            e820_entries(&mem, GuestAddress(KERNEL_START_OFFSET), hotplug_region)
+                                .into_iter()
+                                .map(|(range, mem_type)| pvh::MemmapEntry {
+                                    addr: range.start,
+                                    size: range.len().unwrap_or_default(),
+                                    type_: mem_type as u32,
+                                })
+                                .collect();
+                        pvh::setup_start_info(
+                            &mem,
+                            GuestAddress(CMDLINE_OFFSET),
+                            initrd,
+                            Some(rsdp_addr),
+                            &memmap,
+                        )
+                        .map_err(Error::SetupPvh)?;
+
+                        vcpu_init[0].regs.rip = entry.offset();
+                        vcpu_init[0].regs.rbx = pvh::PVH_INFO_OFFSET;
+
+                        msrs = regs::default_msrs();
+                        msrs.append(&mut regs::mtrr_msrs(&vm, pci_start));
+
+                        regs::configure_segments_and_sregs_pvh(&mem, &mut vcpu_init[0].sregs)
+                            .map_err(Error::ConfigureSegments)?;
+                    }
+                }
             }
         }
 
@@ -1288,18 +1390,23 @@ impl<T: VcpuX86_64> arch::GdbOps<T> for X8664arch {
         Ok(())
     }
 
-    fn enable_singlestep(vcpu: &T) -> Result<()> {
-        vcpu.set_guest_debug(&[], true /* enable_singlestep */)
-            .map_err(Error::EnableSinglestep)
+    fn get_max_hw_breakpoints(_vcpu: &T) -> Result<usize> {
+        Ok(4usize)
     }
 
-    fn get_max_hw_breakpoints(_vcpu: &T) -> Result<usize> {
+    fn get_max_hw_watchpoints(_vcpu: &T) -> Result<usize> {
+        // Watchpoints use the same 4 debug registers as breakpoints.
         Ok(4usize)
     }
 
-    fn set_hw_breakpoints(vcpu: &T, breakpoints: &[GuestAddress]) -> Result<()> {
-        vcpu.set_guest_debug(breakpoints, false /* enable_singlestep */)
-            .map_err(Error::SetHwBreakpoint)
+    fn set_guest_debug(
+        vcpu: &T,
+        breakpoints: &[GuestAddress],
+        watchpoints: &[HwWatchpoint],
+        enable_singlestep: bool,
+    ) -> Result<()> {
+        vcpu.set_guest_debug(breakpoints, watchpoints, enable_singlestep)
+            .map_err(Error::SetGuestDebug)
     }
 }
 
@@ -1549,21 +1656,36 @@ impl X8664arch {
     /// # Returns
     ///
     /// On success, returns the Linux x86_64 boot protocol parameters, the first address past the
-    /// end of the kernel, and the entry point (initial `RIP` value).
+    /// end of the kernel, and the entry point (initial `RIP` value). ELF kernels with a PVH entry
+    /// point are booted with the PVH boot protocol.
     fn load_kernel(
         mem: &GuestMemory,
         kernel_image: &mut File,
-    ) -> Result<(boot_params, u64, GuestAddress)> {
+    ) -> Result<(boot_params, u64, KernelEntry)> {
         let kernel_start = GuestAddress(KERNEL_START_OFFSET);
-        match kernel_loader::load_elf64(mem, kernel_start, kernel_image, 0) {
+        let loaded_elf = match kernel_loader::load_elf64(mem, kernel_start, kernel_image, 0) {
             Ok(loaded_kernel) => {
+                // Like other VMMs, prefer the PVH entry point of kernels that have both.
+                let entry = match loaded_kernel.pvh_entry {
+                    Some(pvh_entry) => KernelEntry::Pvh(pvh_entry),
+                    None => KernelEntry::Linux64(loaded_kernel.entry),
+                };
+                Ok((loaded_kernel.address_range.end, entry))
+            }
+            Err(kernel_loader::Error::InvalidElfClass) => {
+                // 32-bit ELF kernels can only be started through their PVH entry point.
+                let loaded_kernel = kernel_loader::load_elf32(mem, kernel_start, kernel_image, 0)
+                    .map_err(Error::LoadKernel)?;
+                let pvh_entry = loaded_kernel.pvh_entry.ok_or(Error::MissingPvhEntry)?;
+                Ok((loaded_kernel.address_range.end, KernelEntry::Pvh(pvh_entry)))
+            }
+            Err(e) => Err(e),
+        };
+        match loaded_elf {
+            Ok((kernel_end, entry)) => {
                 // ELF kernels don't contain a `boot_params` structure, so synthesize a default one.
                 let boot_params = Default::default();
-                Ok((
-                    boot_params,
-                    loaded_kernel.address_range.end,
-                    loaded_kernel.entry,
-                ))
+                Ok((boot_params, kernel_end, entry))
             }
             Err(kernel_loader::Error::InvalidMagicNumber) => {
                 // The image failed to parse as ELF, so try to load it as a bzImage.
@@ -1573,7 +1695,11 @@ impl X8664arch {
                 let bzimage_entry = mem
                     .checked_offset(kernel_start, KERNEL_64BIT_ENTRY_OFFSET)
                     .ok_or(Error::KernelOffsetPastEnd)?;
-                Ok((boot_params, bzimage_end, bzimage_entry))
+                Ok((
+                    boot_params,
+                    bzimage_end,
+                    KernelEntry::Linux64(bzimage_entry),
+                ))
             }
             Err(e) => Err(Error::LoadKernel(e)),
         }
@@ -1587,6 +1713,9 @@ impl X8664arch {
     /// * `mem` - The memory to be used by the guest.
     /// * `cmdline` - the kernel commandline
     /// * `initrd_file` - an initial ramdisk image
+    /// * `hotplug_region` - the part of `mem` reserved for memory hotplug
+    ///
+    /// Returns the address and size of the initrd, if any.
     pub fn setup_system_memory(
         mem: &GuestMemory,
         cmdline: &CStr,
@@ -1595,7 +1724,8 @@ impl X8664arch {
         kernel_end: u64,
         params: boot_params,
         dump_device_tree_blob: Option<PathBuf>,
-    ) -> Result<()> {
+        hotplug_region: Option<AddressRange>,
+    ) -> Result<Option<(GuestAddress, usize)>> {
         kernel_loader::load_cmdline(mem, GuestAddress(CMDLINE_OFFSET), cmdline)
             .map_err(Error::LoadCmdline)?;
 
@@ -1646,9 +1776,10 @@ impl X8664arch {
             cmdline.to_bytes().len() + 1,
             setup_data,
             initrd,
+            hotplug_region,
             params,
         )?;
-        Ok(())
+        Ok(initrd)
     }
 
     fn get_pcie_vcfg_mmio_range(mem: &GuestMemory, pcie_cfg_mmio: &AddressRange) -> AddressRange {
@@ -2321,6 +2452,25 @@ mod tests {
         assert_eq!(read_pci_mmio_before_32bit().start % (256 * MB), 0);
     }
 
+    #[test]
+    fn hotplug_memory_region_layout() {
+        setup();
+        assert_eq!(hotplug_memory_region(GB, 0), None);
+        // The region stays past 4GB when all RAM is below the 32-bit gap.
+        assert_eq!(
+            hotplug_memory_region(GB, 2 * GB),
+            Some(AddressRange::from_start_and_end(4 * GB, 6 * GB - 1))
+        );
+        // 2GB of RAM fit below the gap and the rest ends at 5GB + 100MB.
+        assert_eq!(
+            hotplug_memory_region(3 * GB + 100 * MB, 512 * MB),
+            Some(AddressRange::from_start_and_end(
+                5 * GB + 128 * MB,
+                5 * GB + 640 * MB - 1
+            ))
+        );
+    }
+
     #[test]
     fn write_setup_data_empty() {
         let mem = GuestMemory::new(&[(GuestAddress(0), 0x2_0000)]).unwrap();
@@ -0,0 +1,207 @@
+// Copyright 2023 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+// Boot information of the PVH boot protocol, as described in
+// https://xenbits.xen.org/docs/unstable/misc/pvh.html and defined in
+// xen/include/public/arch-x86/hvm/start_info.h
+
+use std::mem;
+
+use remain::sorted;
+use thiserror::Error;
+use vm_memory::GuestAddress;
+use vm_memory::GuestMemory;
+use vm_memory::GuestMemoryError;
+use zerocopy::AsBytes;
+use zerocopy::FromBytes;
+
+#[sorted]
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("too many memory map entries: {0}")]
+    TooManyMemmapEntries(usize),
+    #[error("failed to write the PVH start info: {0}")]
+    WriteStartInfo(GuestMemoryError),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+const XEN_HVM_START_MAGIC_VALUE: u32 = 0x336e_c578;
+// Version 1 adds the memory map.
+const XEN_HVM_START_INFO_VERSION: u32 = 1;
+
+/// Address of the `hvm_start_info` structure, followed by the module list and the memory map.
+pub const PVH_INFO_OFFSET: u64 = 0x6000;
+/// Size of the page holding the `hvm_start_info` structure, the module list and the memory map.
+const PVH_INFO_SIZE: u64 = 0x1000;
+
+#[repr(C)]
+#[derive(Copy, Clone, Default, FromBytes, AsBytes)]
+struct hvm_start_info {
+    magic: u32,
+    version: u32,
+    flags: u32,
+    nr_modules: u32,
+    modlist_paddr: u64,
+    cmdline_paddr: u64,
+    rsdp_paddr: u64,
+    memmap_paddr: u64,
+    memmap_entries: u32,
+    reserved: u32,
+}
+
+// hvm_start_info is plain old data with no implicit padding.
+unsafe impl data_model::DataInit for hvm_start_info {}
+
+#[repr(C)]
+#[derive(Copy, Clone, Default, FromBytes, AsBytes)]
+struct hvm_modlist_entry {
+    paddr: u64,
+    size: u64,
+    cmdline_paddr: u64,
+    reserved: u64,
+}
+
+// hvm_modlist_entry is plain old data with no implicit padding.
+unsafe impl data_model::DataInit for hvm_modlist_entry {}
+
+#[repr(C)]
+#[derive(Copy, Clone, Default, FromBytes, AsBytes)]
+struct hvm_memmap_table_entry {
+    addr: u64,
+    size: u64,
+    type_: u32,
+    reserved: u32,
+}
+
+// hvm_memmap_table_entry is plain old data with no implicit padding.
+unsafe impl data_model::DataInit for hvm_memmap_table_entry {}
+
+/// A region of the memory map given to the kernel, with its e820 type.
+pub struct MemmapEntry {
+    pub addr: u64,
+    pub size: u64,
+    pub type_: u32,
+}
+
+/// Writes the `hvm_start_info` structure at `PVH_INFO_OFFSET`, whose address is passed to the
+/// kernel in EBX.
+///
+/// # Arguments
+///
+/// * `guest_mem` - The guest memory the structure is written to.
+/// * `cmdline_addr` - Address of the NUL-terminated kernel command line.
+/// * `initrd` - Address and size of the initrd, which is passed as the first module.
+/// * `rsdp_addr` - Address of the ACPI RSDP.
+/// * `memmap` - The memory map of the guest.
+pub fn setup_start_info(
+    guest_mem: &GuestMemory,
+    cmdline_addr: GuestAddress,
+    initrd: Option<(GuestAddress, usize)>,
+    rsdp_addr: Option<GuestAddress>,
+    memmap: &[MemmapEntry],
+) -> Result<()> {
+    let start_info_addr = GuestAddress(PVH_INFO_OFFSET);
+    let modlist_addr = start_info_addr.unchecked_add(mem::size_of::<hvm_start_info>() as u64);
+    let memmap_addr = modlist_addr.unchecked_add(mem::size_of::<hvm_modlist_entry>() as u64);
+    let memmap_size = (memmap.len() * mem::size_of::<hvm_memmap_table_entry>()) as u64;
+    if memmap_addr.offset() + memmap_size > PVH_INFO_OFFSET + PVH_INFO_SIZE {
+        return Err(Error::TooManyMemmapEntries(memmap.len()));
+    }
+
+    let mut start_info = hvm_start_info {
+        magic: XEN_HVM_START_MAGIC_VALUE,
+        version: XEN_HVM_START_INFO_VERSION,
+        cmdline_paddr: cmdline_addr.offset(),
+        rsdp_paddr: rsdp_addr.map_or(0, |addr| addr.offset()),
+        memmap_paddr: memmap_addr.offset(),
+        memmap_entries: memmap.len() as u32,
+        ..Default::default()
+    };
+
+    if let Some((initrd_addr, initrd_size)) = initrd {
+        let module = hvm_modlist_entry {
+            paddr: initrd_addr.offset(),
+            size: initrd_size as u64,
+            ..Default::default()
+        };
+        guest_mem
+            .write_obj_at_addr(module, modlist_addr)
+            .map_err(Error::WriteStartInfo)?;
+        start_info.nr_modules = 1;
+        start_info.modlist_paddr = modlist_addr.offset();
+    }
+
+    for (index, entry) in memmap.iter().enumerate() {
+        let table_entry = hvm_memmap_table_entry {
+            addr: entry.addr,
+            size: entry.size,
+            type_: entry.type_,
+            reserved: 0,
+        };
+        guest_mem
+            .write_obj_at_addr(
+                table_entry,
+                memmap_addr
+                    .unchecked_add((index * mem::size_of::<hvm_memmap_table_entry>()) as u64),
+            )
+            .map_err(Error::WriteStartInfo)?;
+    }
+
+    guest_mem
+        .write_obj_at_addr(start_info, start_info_addr)
+        .map_err(Error::WriteStartInfo)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn start_info() {
+        let gm = GuestMemory::new(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let memmap = [
+            MemmapEntry {
+                addr: 0,
+                size: 0x9fc00,
+                type_: 1,
+            },
+            MemmapEntry {
+                addr: 0x20_0000,
+                size: 0x100_0000,
+                type_: 1,
+            },
+        ];
+        setup_start_info(
+            &gm,
+            GuestAddress(0x2_0000),
+            Some((GuestAddress(0x40_0000), 0x1234)),
+            Some(GuestAddress(0xe_0000)),
+            &memmap,
+        )
+        .unwrap();
+
+        let start_info: hvm_start_info = gm.read_obj_from_addr(GuestAddress(0x6000)).unwrap();
+        assert_eq!(start_info.magic, XEN_HVM_START_MAGIC_VALUE);
+        assert_eq!(start_info.version, 1);
+        assert_eq!(start_info.cmdline_paddr, 0x2_0000);
+        assert_eq!(start_info.rsdp_paddr, 0xe_0000);
+        assert_eq!(start_info.nr_modules, 1);
+        assert_eq!(start_info.memmap_entries, 2);
+
+        let module: hvm_modlist_entry = gm
+            .read_obj_from_addr(GuestAddress(start_info.modlist_paddr))
+            .unwrap();
+        assert_eq!(module.paddr, 0x40_0000);
+        assert_eq!(module.size, 0x1234);
+
+        let entry: hvm_memmap_table_entry = gm
+            .read_obj_from_addr(GuestAddress(start_info.memmap_paddr + 24))
+            .unwrap();
+        assert_eq!(entry.addr, 0x20_0000);
+        assert_eq!(entry.size, 0x100_0000);
+        assert_eq!(entry.type_, 1);
+    }
+}
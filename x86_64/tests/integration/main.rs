@@ -230,6 +230,7 @@ where
         kernel_end,
         params,
         None,
+        None,
     )
     .expect("failed to setup system_memory");
 